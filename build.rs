@@ -0,0 +1,15 @@
+use std::process::Command;
+
+/// Records the commit the binary is built from for the output manifests (see `manifest.rs`).
+/// Builds outside of a git checkout (e.g. from crates.io) have none.
+fn main() {
+    let commit = Command::new("git").args(["rev-parse", "HEAD"]).output();
+    if let Ok(output) = commit {
+        if output.status.success() {
+            let commit = String::from_utf8_lossy(&output.stdout);
+            println!("cargo:rustc-env=CLEORA_GIT_COMMIT={}", commit.trim());
+        }
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
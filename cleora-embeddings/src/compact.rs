@@ -0,0 +1,132 @@
+use crate::{open_file, EmbeddingSet, Error};
+use std::convert::TryInto;
+use std::io::Read;
+
+const COMPACT_MAGIC: &[u8; 8] = b"CLEORACB";
+const COMPACT_VERSION: u32 = 1;
+const COMPACT_HEADER_SIZE: usize = 64;
+const COMPACT_LITTLE_ENDIAN: u8 = 0;
+const COMPACT_OCCURRENCES: u8 = 1;
+
+/// Reads embeddings written by the compact persistor: header, f32 rows padded to the row stride
+/// and the entity table (count, occurrences if flagged, length-prefixed entities). Rows of
+/// transient entities (past the entities) are cut off.
+pub fn read(path: &str) -> Result<EmbeddingSet, Error> {
+    let mut bytes = Vec::new();
+    open_file(path)?
+        .read_to_end(&mut bytes)
+        .map_err(|e| Error::io(path, e))?;
+    parse(&bytes).map_err(|e| Error::invalid(path, e))
+}
+
+fn parse(bytes: &[u8]) -> Result<EmbeddingSet, String> {
+    if bytes.len() < COMPACT_HEADER_SIZE || &bytes[0..8] != COMPACT_MAGIC {
+        return Err(String::from("not a compact embeddings file"));
+    }
+    let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    if version != COMPACT_VERSION {
+        return Err(format!("unsupported compact file version {}", version));
+    }
+    if bytes[12] != COMPACT_LITTLE_ENDIAN {
+        return Err(String::from("unsupported byte order"));
+    }
+    let with_occurrences = bytes[13] & COMPACT_OCCURRENCES != 0;
+    let dimension = u32::from_le_bytes(bytes[16..20].try_into().unwrap()) as usize;
+    let row_stride = u32::from_le_bytes(bytes[20..24].try_into().unwrap()) as usize;
+    let rows = u64::from_le_bytes(bytes[24..32].try_into().unwrap()) as usize;
+    if row_stride < dimension * 4 {
+        return Err(String::from("row stride shorter than the row"));
+    }
+
+    let table_start = rows
+        .checked_mul(row_stride)
+        .and_then(|len| len.checked_add(COMPACT_HEADER_SIZE))
+        .filter(|&start| start + 8 <= bytes.len())
+        .ok_or("truncated rows")?;
+    let count =
+        u64::from_le_bytes(bytes[table_start..table_start + 8].try_into().unwrap()) as usize;
+    if count > rows {
+        return Err(format!("{} rows for {} entities", rows, count));
+    }
+    let mut rest = &bytes[table_start + 8..];
+
+    let occurrences = if with_occurrences {
+        if rest.len() < count * 4 {
+            return Err(String::from("truncated occurrences"));
+        }
+        let (occurrences, tail) = rest.split_at(count * 4);
+        rest = tail;
+        Some(
+            occurrences
+                .chunks_exact(4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                .collect(),
+        )
+    } else {
+        None
+    };
+
+    let mut entities = Vec::with_capacity(count);
+    for _ in 0..count {
+        if rest.len() < 4 {
+            return Err(String::from("truncated entity length"));
+        }
+        let (len, tail) = rest.split_at(4);
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        if tail.len() < len {
+            return Err(String::from("truncated entity"));
+        }
+        let (entity, tail) = tail.split_at(len);
+        entities.push(
+            std::str::from_utf8(entity)
+                .map_err(|e| e.to_string())?
+                .to_string(),
+        );
+        rest = tail;
+    }
+
+    let mut values = Vec::with_capacity(count * dimension);
+    for row in 0..count {
+        let start = COMPACT_HEADER_SIZE + row * row_stride;
+        values.extend(
+            bytes[start..start + dimension * 4]
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap())),
+        );
+    }
+    EmbeddingSet::new(entities, occurrences, values, dimension)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compact::parse;
+
+    #[test]
+    fn parse_compact_file() {
+        let mut bytes = vec![0u8; 64];
+        bytes[0..8].copy_from_slice(b"CLEORACB");
+        bytes[8..12].copy_from_slice(&1u32.to_le_bytes());
+        bytes[13] = 1;
+        bytes[16..20].copy_from_slice(&2u32.to_le_bytes());
+        bytes[20..24].copy_from_slice(&64u32.to_le_bytes());
+        // the second row is of a transient entity
+        bytes[24..32].copy_from_slice(&2u64.to_le_bytes());
+        let mut row = vec![0u8; 64];
+        row[0..4].copy_from_slice(&0.5f32.to_le_bytes());
+        row[4..8].copy_from_slice(&(-1f32).to_le_bytes());
+        bytes.extend_from_slice(&row);
+        bytes.extend_from_slice(&[0u8; 64]);
+        bytes.extend_from_slice(&1u64.to_le_bytes());
+        bytes.extend_from_slice(&7u32.to_le_bytes());
+        bytes.extend_from_slice(&("żółw".len() as u32).to_le_bytes());
+        bytes.extend_from_slice("żółw".as_bytes());
+
+        let embeddings = parse(&bytes).unwrap();
+        assert_eq!(vec!["żółw"], embeddings.entities());
+        assert_eq!(Some(&[7u32][..]), embeddings.occurrences());
+        assert_eq!(&[0.5f32, -1.0][..], embeddings.values());
+
+        assert!(parse(&bytes[..bytes.len() - 1]).is_err());
+        assert!(parse(&bytes[..100]).is_err());
+    }
+}
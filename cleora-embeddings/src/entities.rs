@@ -0,0 +1,102 @@
+use crate::{open_file, Error};
+use std::convert::TryInto;
+use std::io::{BufRead, BufReader, Read};
+
+/// Magic bytes starting the binary entities file
+const ENTITIES_MAGIC: &[u8; 8] = b"CLEORAEN";
+
+/// Reads the `.entities` sidecar in any of the formats: JSON list, entity per line or binary
+/// (magic and u32 version followed by u32 length and UTF-8 bytes of every entity).
+pub fn read(path: &str) -> Result<Vec<String>, Error> {
+    let mut reader = BufReader::new(open_file(path)?);
+    let start = reader.fill_buf().map_err(|e| Error::io(path, e))?;
+    if start.starts_with(ENTITIES_MAGIC) {
+        read_binary(reader, path)
+    } else if is_json(start) {
+        serde_json::from_reader(reader).map_err(|e| Error::invalid(path, e))
+    } else {
+        reader
+            .lines()
+            .collect::<Result<_, _>>()
+            .map_err(|e| Error::invalid(path, e))
+    }
+}
+
+/// JSON list starts with the bracket followed by the first name or the closing bracket, which
+/// tells it apart from a name starting with the bracket
+fn is_json(start: &[u8]) -> bool {
+    match start.strip_prefix(b"[") {
+        Some(rest) => matches!(
+            rest.iter().find(|c| !c.is_ascii_whitespace()),
+            Some(b'"') | Some(b']')
+        ),
+        None => false,
+    }
+}
+
+fn read_binary<R: Read>(mut reader: R, path: &str) -> Result<Vec<String>, Error> {
+    let mut header = [0u8; 12];
+    reader
+        .read_exact(&mut header)
+        .map_err(|e| Error::io(path, e))?;
+    let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    if version != 1 {
+        return Err(Error::invalid(
+            path,
+            format!("unsupported entities version {}", version),
+        ));
+    }
+
+    let mut bytes = Vec::new();
+    reader
+        .read_to_end(&mut bytes)
+        .map_err(|e| Error::io(path, e))?;
+    let mut entities = Vec::new();
+    let mut rest = bytes.as_slice();
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(Error::invalid(path, "truncated entity length"));
+        }
+        let (len, tail) = rest.split_at(4);
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        if tail.len() < len {
+            return Err(Error::invalid(path, "truncated entity"));
+        }
+        let (entity, tail) = tail.split_at(len);
+        let entity = std::str::from_utf8(entity).map_err(|e| Error::invalid(path, e))?;
+        entities.push(entity.to_string());
+        rest = tail;
+    }
+    Ok(entities)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entities::{is_json, read};
+
+    #[test]
+    fn read_entities_formats() {
+        let path = std::env::temp_dir().join("cleora_embeddings_entities");
+        let path = path.to_str().unwrap();
+
+        std::fs::write(path, "[\n  \"a\",\n  \"[b\"\n]").unwrap();
+        assert_eq!(vec!["a", "[b"], read(path).unwrap());
+
+        std::fs::write(path, "[b\na\n").unwrap();
+        assert_eq!(vec!["[b", "a"], read(path).unwrap());
+
+        let mut binary = b"CLEORAEN\x01\x00\x00\x00".to_vec();
+        for entity in ["a", "żółw"] {
+            binary.extend_from_slice(&(entity.len() as u32).to_le_bytes());
+            binary.extend_from_slice(entity.as_bytes());
+        }
+        std::fs::write(path, &binary).unwrap();
+        assert_eq!(vec!["a", "żółw"], read(path).unwrap());
+        std::fs::write(path, &binary[..binary.len() - 1]).unwrap();
+        assert!(read(path).is_err());
+
+        assert!(is_json(b"[]"));
+        assert!(!is_json(b""));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,343 @@
+mod compact;
+mod entities;
+mod npy;
+mod npz;
+#[cfg(feature = "parquet")]
+mod parquet;
+mod raw;
+mod text;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+/// Errors of opening an embeddings file
+#[derive(Debug)]
+pub enum Error {
+    /// The file (or one of its sidecars) can't be read
+    Io { path: String, source: io::Error },
+    /// The file isn't valid output of one of the Cleora formats
+    Invalid { path: String, message: String },
+}
+
+impl Error {
+    pub(crate) fn io(path: &str, source: io::Error) -> Self {
+        Error::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    pub(crate) fn invalid<E: ToString>(path: &str, error: E) -> Self {
+        Error::Invalid {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "Unable to read file {}: {}", path, source),
+            Error::Invalid { path, message } => {
+                write!(f, "Invalid embeddings file {}: {}", path, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Invalid { .. } => None,
+        }
+    }
+}
+
+/// Embeddings read from any output format. Vectors are kept in a single row-major buffer, row `i`
+/// is the embedding of `entities()[i]`.
+#[derive(Debug, Clone)]
+pub struct EmbeddingSet {
+    entities: Vec<String>,
+    occurrences: Option<Vec<u32>>,
+    values: Vec<f32>,
+    dimension: usize,
+    positions: HashMap<String, usize>,
+}
+
+impl EmbeddingSet {
+    /// Opens embeddings written by one of the persistors (including the original upstream
+    /// Cleora, whose text and numpy outputs have the same layout). The format is recognized by the
+    /// magic bytes, so renamed files can be read: numpy and raw arrays are read with their
+    /// `.entities` (and, if present, `.occurences`) sidecars named after the array without its
+    /// `.npy` / `.bin` extension, `.npz` archives with `embeddings`, `entities` and
+    /// `occurrences` arrays, compact files with their entity table, parquet files with `entity`,
+    /// `occur_count` and `f0`..`fN` columns (requires the `parquet` feature), anything else is
+    /// read as text file. The output name without extension (as upstream logs it) is resolved to
+    /// the numpy array or npz archive.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_string_lossy();
+        let path = path.as_ref();
+        if !Path::new(path).exists() {
+            for extension in ["npy", "npz"] {
+                let full_path = format!("{}.{}", path, extension);
+                if Path::new(&full_path).exists() {
+                    return Self::open(full_path);
+                }
+            }
+        }
+
+        match detect_format(path)? {
+            Format::Npy => {
+                let (shape, values) = npy::read_f32(BufReader::new(open_file(path)?))
+                    .map_err(|e| Error::invalid(path, e))?;
+                match shape.as_slice() {
+                    [rows, dimension] => {
+                        let base = path.strip_suffix(".npy").unwrap_or(path);
+                        with_sidecars(path, base, values, *rows, *dimension)
+                    }
+                    _ => Err(Error::invalid(path, "expected two dimensional array")),
+                }
+            }
+            Format::Raw => {
+                let (rows, dimension, values) = raw::read_raw(BufReader::new(open_file(path)?))
+                    .map_err(|e| Error::invalid(path, e))?;
+                let base = path.strip_suffix(".bin").unwrap_or(path);
+                with_sidecars(path, base, values, rows, dimension)
+            }
+            Format::Npz => npz::read(path),
+            Format::Compact => compact::read(path),
+            #[cfg(feature = "parquet")]
+            Format::Parquet => parquet::read(path),
+            #[cfg(not(feature = "parquet"))]
+            Format::Parquet => Err(Error::invalid(
+                path,
+                "parquet support requires the parquet feature",
+            )),
+            Format::Text => text::read(path),
+        }
+    }
+
+    /// Builds the set from row-major `values`, `dimension` values per entity.
+    pub fn new(
+        entities: Vec<String>,
+        occurrences: Option<Vec<u32>>,
+        values: Vec<f32>,
+        dimension: usize,
+    ) -> Result<Self, String> {
+        if values.len() != entities.len() * dimension {
+            return Err(format!(
+                "{} values for {} entities of dimension {}",
+                values.len(),
+                entities.len(),
+                dimension
+            ));
+        }
+        if let Some(occurrences) = &occurrences {
+            if occurrences.len() != entities.len() {
+                return Err(format!(
+                    "{} occurrence counts for {} entities",
+                    occurrences.len(),
+                    entities.len()
+                ));
+            }
+        }
+        let positions = entities
+            .iter()
+            .enumerate()
+            .map(|(i, entity)| (entity.clone(), i))
+            .collect();
+        Ok(Self {
+            entities,
+            occurrences,
+            values,
+            dimension,
+            positions,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    pub fn entities(&self) -> &[String] {
+        &self.entities
+    }
+
+    /// Occurrence counts of the entities, `None` if the output was written without them
+    pub fn occurrences(&self) -> Option<&[u32]> {
+        self.occurrences.as_deref()
+    }
+
+    /// All vectors, row-major
+    pub fn values(&self) -> &[f32] {
+        &self.values
+    }
+
+    /// Embedding of the entity in the given row
+    pub fn vector(&self, row: usize) -> &[f32] {
+        &self.values[row * self.dimension..(row + 1) * self.dimension]
+    }
+
+    /// Row of the entity, `None` if there is no such entity
+    pub fn position(&self, entity: &str) -> Option<usize> {
+        self.positions.get(entity).copied()
+    }
+
+    /// Embedding of the entity, `None` if there is no such entity
+    pub fn get(&self, entity: &str) -> Option<&[f32]> {
+        self.position(entity).map(|row| self.vector(row))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[f32])> {
+        self.entities
+            .iter()
+            .enumerate()
+            .map(move |(row, entity)| (entity.as_str(), self.vector(row)))
+    }
+
+    /// Returns entities and row-major vectors
+    pub fn into_parts(self) -> (Vec<String>, Vec<f32>) {
+        (self.entities, self.values)
+    }
+}
+
+/// Formats of the embeddings files
+enum Format {
+    Npy,
+    Npz,
+    Raw,
+    Compact,
+    Parquet,
+    Text,
+}
+
+/// Recognizes format of the file by its first bytes
+fn detect_format(path: &str) -> Result<Format, Error> {
+    let mut magic = Vec::with_capacity(8);
+    open_file(path)?
+        .take(8)
+        .read_to_end(&mut magic)
+        .map_err(|e| Error::io(path, e))?;
+    let format = if magic.starts_with(b"\x93NUMPY") {
+        Format::Npy
+    } else if magic.starts_with(b"PK\x03\x04") {
+        Format::Npz
+    } else if magic.starts_with(b"CLEORAEM") {
+        Format::Raw
+    } else if magic.starts_with(b"CLEORACB") {
+        Format::Compact
+    } else if magic.starts_with(b"PAR1") {
+        Format::Parquet
+    } else {
+        Format::Text
+    };
+    Ok(format)
+}
+
+pub(crate) fn open_file(path: &str) -> Result<File, Error> {
+    File::open(path).map_err(|e| Error::io(path, e))
+}
+
+/// Pairs array rows with entities from the `.entities` file (in any of its formats) and occurrence
+/// counts from the `.occurences` file (if it exists). Rows of transient entities (without the
+/// name) are left at the end of the array, so they are cut off.
+fn with_sidecars(
+    path: &str,
+    base: &str,
+    mut values: Vec<f32>,
+    rows: usize,
+    dimension: usize,
+) -> Result<EmbeddingSet, Error> {
+    let entities_path = format!("{}.entities", base);
+    let entities = entities::read(&entities_path)?;
+    if entities.len() > rows {
+        return Err(Error::invalid(
+            path,
+            format!("{} rows for {} entities", rows, entities.len()),
+        ));
+    }
+    values.truncate(entities.len() * dimension);
+
+    let occurrences_path = format!("{}.occurences", base);
+    let occurrences = if Path::new(&occurrences_path).exists() {
+        let (_, mut occurrences) = npy::read_u32(BufReader::new(open_file(&occurrences_path)?))
+            .map_err(|e| Error::invalid(&occurrences_path, e))?;
+        occurrences.truncate(entities.len());
+        Some(occurrences)
+    } else {
+        None
+    };
+
+    EmbeddingSet::new(entities, occurrences, values, dimension).map_err(|e| Error::invalid(path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::EmbeddingSet;
+
+    #[test]
+    fn open_upstream_outputs() {
+        let dir = std::env::temp_dir();
+        // upstream text output: header counts transient entities too, no trailing new line
+        let text = dir.join("cleora_embeddings_upstream.vec");
+        std::fs::write(&text, "3 2\na 4 0.5 -1\nb 1 0.25 2").unwrap();
+        let embeddings = EmbeddingSet::open(&text).unwrap();
+        assert_eq!(vec!["a", "b"], embeddings.entities());
+        assert_eq!(Some(&[4u32, 1][..]), embeddings.occurrences());
+        assert_eq!(&[0.5f32, -1.0, 0.25, 2.0][..], embeddings.values());
+        std::fs::remove_file(text).unwrap();
+
+        // numpy output opened by the output name, the transient entity row is cut off
+        let base = dir.join("cleora_embeddings_upstream.out");
+        let base = base.to_str().unwrap();
+        let header = "{'descr': '<f4', 'fortran_order': False, 'shape': (3, 2), }\n";
+        let mut npy = b"\x93NUMPY\x01\x00".to_vec();
+        npy.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        npy.extend_from_slice(header.as_bytes());
+        for value in [0.5f32, -1.0, 0.25, 2.0, 1.0, 1.0] {
+            npy.extend_from_slice(&value.to_le_bytes());
+        }
+        std::fs::write(format!("{}.npy", base), npy).unwrap();
+        std::fs::write(format!("{}.entities", base), r#"["a", "b"]"#).unwrap();
+        let embeddings = EmbeddingSet::open(base).unwrap();
+        assert_eq!(Some(&[0.25f32, 2.0][..]), embeddings.get("b"));
+        assert_eq!(2, embeddings.len());
+        assert_eq!(None, embeddings.occurrences());
+        std::fs::remove_file(format!("{}.npy", base)).unwrap();
+        std::fs::remove_file(format!("{}.entities", base)).unwrap();
+    }
+
+    #[test]
+    fn look_up_entities() {
+        let embeddings = EmbeddingSet::new(
+            vec![String::from("a"), String::from("b")],
+            None,
+            vec![0.5, -1.0, 0.25, 2.0],
+            2,
+        )
+        .unwrap();
+        assert_eq!(2, embeddings.len());
+        assert_eq!(Some(&[0.25f32, 2.0][..]), embeddings.get("b"));
+        assert_eq!(None, embeddings.get("c"));
+        assert_eq!(
+            vec![("a", &[0.5f32, -1.0][..]), ("b", &[0.25f32, 2.0][..])],
+            embeddings.iter().collect::<Vec<_>>()
+        );
+
+        assert!(EmbeddingSet::new(vec![String::from("a")], None, vec![0.5], 2).is_err());
+        assert!(EmbeddingSet::new(vec![String::from("a")], Some(vec![]), vec![0.5], 1).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+use std::convert::TryInto;
+use std::io::Read;
+
+const NPY_MAGIC: &[u8; 6] = b"\x93NUMPY";
+
+/// Reads a little-endian f32 array, returns its shape and values in C order.
+pub fn read_f32<R: Read>(reader: R) -> Result<(Vec<usize>, Vec<f32>), String> {
+    read_array(reader, "<f4", f32::from_le_bytes)
+}
+
+/// Reads a little-endian u32 array, returns its shape and values in C order.
+pub fn read_u32<R: Read>(reader: R) -> Result<(Vec<usize>, Vec<u32>), String> {
+    read_array(reader, "<u4", u32::from_le_bytes)
+}
+
+/// Reads a little-endian unicode (`<U`) array, returns its shape and strings in C order.
+pub fn read_unicode<R: Read>(mut reader: R) -> Result<(Vec<usize>, Vec<String>), String> {
+    let (descr, fortran_order, shape) = read_header(&mut reader)?;
+    if fortran_order && shape.len() > 1 {
+        return Err(String::from("only C order unicode arrays are supported"));
+    }
+    let width: usize = descr
+        .strip_prefix("<U")
+        .and_then(|w| w.parse().ok())
+        .ok_or_else(|| format!("expected <U values, found {}", descr))?;
+
+    let count: usize = shape.iter().product();
+    let mut bytes = vec![0u8; count * width * 4];
+    reader.read_exact(&mut bytes).map_err(|e| e.to_string())?;
+    let values = bytes
+        .chunks_exact((width * 4).max(1))
+        .take(count)
+        .map(|value| {
+            // UTF-32 code points, shorter strings are padded with zeros
+            value
+                .chunks_exact(4)
+                .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+                .take_while(|&c| c != 0)
+                .map(|c| char::from_u32(c).ok_or_else(|| format!("invalid code point {}", c)))
+                .collect::<Result<String, _>>()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((shape, values))
+}
+
+/// Only the subset of the npy format written by the persistors is supported: arrays of 4-byte
+/// values, two dimensional arrays may be in Fortran order (embeddings moved from memory-mapped
+/// matrix).
+fn read_array<R: Read, T: Copy>(
+    mut reader: R,
+    descr: &str,
+    from_bytes: fn([u8; 4]) -> T,
+) -> Result<(Vec<usize>, Vec<T>), String> {
+    let (found_descr, fortran_order, shape) = read_header(&mut reader)?;
+    if found_descr != descr {
+        return Err(format!("expected {} values, found {}", descr, found_descr));
+    }
+
+    let count: usize = shape.iter().product();
+    let mut bytes = vec![0u8; count * 4];
+    reader.read_exact(&mut bytes).map_err(|e| e.to_string())?;
+    let values: Vec<T> = bytes
+        .chunks_exact(4)
+        .map(|b| from_bytes(b.try_into().unwrap()))
+        .collect();
+    match (fortran_order, shape.as_slice()) {
+        (false, _) | (true, [_]) => Ok((shape, values)),
+        (true, &[rows, cols]) => {
+            // columns one after another, transposed to rows
+            let values = (0..rows * cols)
+                .map(|i| values[(i % cols) * rows + i / cols])
+                .collect();
+            Ok((shape, values))
+        }
+        (true, _) => Err(String::from(
+            "only one and two dimensional Fortran order arrays are supported",
+        )),
+    }
+}
+
+/// Reads the preamble and the header of the array, returns type description of its values,
+/// whether it's in Fortran order and its shape
+fn read_header<R: Read>(reader: &mut R) -> Result<(String, bool, Vec<usize>), String> {
+    let mut preamble = [0u8; 8];
+    reader
+        .read_exact(&mut preamble)
+        .map_err(|e| e.to_string())?;
+    if &preamble[..6] != NPY_MAGIC {
+        return Err(String::from("not a npy file"));
+    }
+    // the header length follows the version: u16 in version 1, u32 in versions 2 and 3
+    let header_len = match preamble[6] {
+        1 => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len).map_err(|e| e.to_string())?;
+            u16::from_le_bytes(len) as usize
+        }
+        2 | 3 => {
+            let mut len = [0u8; 4];
+            reader.read_exact(&mut len).map_err(|e| e.to_string())?;
+            u32::from_le_bytes(len) as usize
+        }
+        version => return Err(format!("unsupported npy version {}", version)),
+    };
+    let mut header = vec![0u8; header_len];
+    reader.read_exact(&mut header).map_err(|e| e.to_string())?;
+    let header = String::from_utf8(header).map_err(|e| e.to_string())?;
+
+    let descr = header_value(&header, "descr")
+        .map(|d| d.trim_matches(|c| c == '\'' || c == '"'))
+        .ok_or("no descr in the header")?;
+    let fortran_order = match header_value(&header, "fortran_order") {
+        Some("False") => false,
+        Some("True") => true,
+        _ => return Err(String::from("no fortran_order in the header")),
+    };
+    let shape = header_value(&header, "shape")
+        .and_then(|s| s.strip_prefix('('))
+        .and_then(|s| s.strip_suffix(')'))
+        .ok_or("no shape in the header")?
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid shape: {}", e))?;
+    Ok((descr.to_string(), fortran_order, shape))
+}
+
+/// Value of the key in the header dictionary, e.g. `{'descr': '<f4', 'shape': (3, 2), }`
+fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header
+        .find(&format!("'{}'", key))
+        .or_else(|| header.find(&format!("\"{}\"", key)))?;
+    let value = header[start + key.len() + 2..]
+        .trim_start()
+        .strip_prefix(':')?;
+    let value = value.trim_start();
+    let end = if value.starts_with('(') {
+        value.find(')')? + 1
+    } else {
+        value.find([',', '}'])?
+    };
+    Some(value[..end].trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::npy::{read_f32, read_u32, read_unicode};
+
+    fn npy(header: &str, values: &[u8]) -> Vec<u8> {
+        let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+        bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(header.as_bytes());
+        bytes.extend_from_slice(values);
+        bytes
+    }
+
+    #[test]
+    fn read_arrays() {
+        let values: Vec<u8> = [0.5f32, -1.0, 0.25, 2.0, 1.0, 0.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let bytes = npy(
+            "{'descr': '<f4', 'fortran_order': False, 'shape': (3, 2), }\n",
+            &values,
+        );
+        let (shape, array) = read_f32(bytes.as_slice()).unwrap();
+        assert_eq!(vec![3, 2], shape);
+        assert_eq!(vec![0.5, -1.0, 0.25, 2.0, 1.0, 0.0], array);
+        // the same bytes aren't u32 values
+        assert!(read_u32(bytes.as_slice()).is_err());
+
+        let bytes = npy(
+            "{'descr': '<u4', 'fortran_order': False, 'shape': (2,), }\n",
+            &[4, 0, 0, 0, 1, 0, 0, 0],
+        );
+        assert_eq!((vec![2], vec![4, 1]), read_u32(bytes.as_slice()).unwrap());
+
+        // columns one after another
+        let bytes = npy(
+            "{'descr': '<f4', 'fortran_order': True, 'shape': (2, 3), }\n",
+            &values,
+        );
+        assert_eq!(
+            (vec![2, 3], vec![0.5, 0.25, 1.0, -1.0, 2.0, 0.0]),
+            read_f32(bytes.as_slice()).unwrap()
+        );
+        assert!(read_f32(&values[..]).is_err());
+
+        let strings: Vec<u8> = ['a', 'b', 'c', 'ż', '\0', '\0']
+            .iter()
+            .flat_map(|&c| (c as u32).to_le_bytes())
+            .collect();
+        let bytes = npy(
+            "{'descr': '<U3', 'fortran_order': False, 'shape': (2,), }\n",
+            &strings,
+        );
+        assert_eq!(
+            (vec![2], vec![String::from("abc"), String::from("ż")]),
+            read_unicode(bytes.as_slice()).unwrap()
+        );
+        assert!(read_f32(bytes.as_slice()).is_err());
+    }
+}
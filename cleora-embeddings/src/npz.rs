@@ -0,0 +1,61 @@
+use crate::npy;
+use crate::{open_file, EmbeddingSet, Error};
+use std::io::{BufReader, Read, Seek};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+/// Shape and values of the array
+type Array<T> = (Vec<usize>, Vec<T>);
+
+/// Reads embeddings from `embeddings`, `entities` and (optional) `occurrences` arrays of the
+/// `.npz` archive. Rows of transient entities (past the entities) are cut off.
+pub fn read(path: &str) -> Result<EmbeddingSet, Error> {
+    let mut archive =
+        ZipArchive::new(BufReader::new(open_file(path)?)).map_err(|e| Error::invalid(path, e))?;
+
+    let (shape, mut values) = read_array(&mut archive, path, "embeddings", |r| npy::read_f32(r))?
+        .ok_or_else(|| Error::invalid(path, "no embeddings array"))?;
+    let (rows, dimension) = match shape.as_slice() {
+        [rows, dimension] => (*rows, *dimension),
+        _ => return Err(Error::invalid(path, "expected two dimensional array")),
+    };
+    let (_, entities) = read_array(&mut archive, path, "entities", |r| npy::read_unicode(r))?
+        .ok_or_else(|| Error::invalid(path, "no entities array"))?;
+    if entities.len() > rows {
+        return Err(Error::invalid(
+            path,
+            format!("{} rows for {} entities", rows, entities.len()),
+        ));
+    }
+    values.truncate(entities.len() * dimension);
+
+    let occurrences = read_array(&mut archive, path, "occurrences", |r| npy::read_u32(r))?.map(
+        |(_, mut occurrences)| {
+            occurrences.truncate(entities.len());
+            occurrences
+        },
+    );
+
+    EmbeddingSet::new(entities, occurrences, values, dimension).map_err(|e| Error::invalid(path, e))
+}
+
+/// Reads the array of the archive, `None` if there is no such array
+fn read_array<R, T, F>(
+    archive: &mut ZipArchive<R>,
+    path: &str,
+    name: &str,
+    read: F,
+) -> Result<Option<Array<T>>, Error>
+where
+    R: Read + Seek,
+    F: FnOnce(&mut dyn Read) -> Result<Array<T>, String>,
+{
+    let mut file = match archive.by_name(&format!("{}.npy", name)) {
+        Ok(file) => file,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(Error::invalid(path, e)),
+    };
+    read(&mut file)
+        .map(Some)
+        .map_err(|e| Error::invalid(path, format!("{}: {}", name, e)))
+}
@@ -0,0 +1,57 @@
+use crate::{open_file, EmbeddingSet, Error};
+use arrow2::array::{Float32Array, UInt32Array, Utf8Array};
+use arrow2::io::parquet::read::{infer_schema, read_metadata, FileReader};
+
+/// Reads parquet output with `entity`, optional `occur_count` and `f0`..`fN` columns.
+pub fn read(path: &str) -> Result<EmbeddingSet, Error> {
+    let mut file = open_file(path)?;
+    let metadata = read_metadata(&mut file).map_err(|e| Error::invalid(path, e))?;
+    let schema = infer_schema(&metadata).map_err(|e| Error::invalid(path, e))?;
+
+    let column = |name: &str| schema.fields.iter().position(|f| f.name == name);
+    let entity_column = column("entity").ok_or_else(|| Error::invalid(path, "no entity column"))?;
+    let occurrence_column = column("occur_count");
+    let value_columns: Vec<usize> = (0..)
+        .map(|i| column(&format!("f{}", i)))
+        .take_while(|c| c.is_some())
+        .flatten()
+        .collect();
+
+    let mut entities = Vec::new();
+    let mut occurrences = Vec::new();
+    let mut values = Vec::new();
+    let reader = FileReader::new(file, metadata.row_groups, schema, None, None);
+    for chunk in reader {
+        let chunk = chunk.map_err(|e| Error::invalid(path, e))?;
+        let arrays = chunk.arrays();
+        let chunk_entities = arrays[entity_column]
+            .as_any()
+            .downcast_ref::<Utf8Array<i32>>()
+            .ok_or_else(|| Error::invalid(path, "entity column isn't utf8"))?;
+        let chunk_occurrences = occurrence_column
+            .map(|c| {
+                arrays[c]
+                    .as_any()
+                    .downcast_ref::<UInt32Array>()
+                    .ok_or_else(|| Error::invalid(path, "occur_count column isn't uint32"))
+            })
+            .transpose()?;
+        let chunk_values = value_columns
+            .iter()
+            .map(|&c| arrays[c].as_any().downcast_ref::<Float32Array>())
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| Error::invalid(path, "value column isn't float32"))?;
+
+        for row in 0..chunk_entities.len() {
+            entities.push(chunk_entities.value(row).to_string());
+            if let Some(chunk_occurrences) = chunk_occurrences {
+                occurrences.push(chunk_occurrences.value(row));
+            }
+            values.extend(chunk_values.iter().map(|column| column.value(row)));
+        }
+    }
+
+    let occurrences = occurrence_column.map(|_| occurrences);
+    EmbeddingSet::new(entities, occurrences, values, value_columns.len())
+        .map_err(|e| Error::invalid(path, e))
+}
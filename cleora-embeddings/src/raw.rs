@@ -0,0 +1,42 @@
+use std::convert::TryInto;
+use std::io::Read;
+
+const RAW_MAGIC: &[u8; 8] = b"CLEORAEM";
+const RAW_VERSION: u32 = 1;
+const RAW_HEADER_SIZE: usize = 64;
+const RAW_LITTLE_ENDIAN: u8 = 0;
+
+/// Reads embeddings written by the raw persistor (packed f32 rows padded to the row stride),
+/// returns the number of rows, dimension and row-major values.
+pub fn read_raw<R: Read>(mut reader: R) -> Result<(usize, usize, Vec<f32>), String> {
+    let mut header = [0u8; RAW_HEADER_SIZE];
+    reader.read_exact(&mut header).map_err(|e| e.to_string())?;
+    if &header[0..8] != RAW_MAGIC {
+        return Err(String::from("not a raw embeddings file"));
+    }
+    let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    if version != RAW_VERSION {
+        return Err(format!("unsupported raw file version {}", version));
+    }
+    if header[12] != RAW_LITTLE_ENDIAN {
+        return Err(String::from("unsupported byte order"));
+    }
+    let dimension = u32::from_le_bytes(header[16..20].try_into().unwrap()) as usize;
+    let row_stride = u32::from_le_bytes(header[20..24].try_into().unwrap()) as usize;
+    let count = u64::from_le_bytes(header[24..32].try_into().unwrap()) as usize;
+    if row_stride < dimension * 4 {
+        return Err(String::from("row stride shorter than the row"));
+    }
+
+    let mut values = Vec::with_capacity(count * dimension);
+    let mut row = vec![0u8; row_stride];
+    for _ in 0..count {
+        reader.read_exact(&mut row).map_err(|e| e.to_string())?;
+        values.extend(
+            row[..dimension * 4]
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap())),
+        );
+    }
+    Ok((count, dimension, values))
+}
@@ -0,0 +1,54 @@
+use crate::{open_file, EmbeddingSet, Error};
+use std::io::{BufRead, BufReader};
+
+/// Reads text output: `count dimension` header and `entity [occurrence] values...` lines. Whether
+/// occurrence counts are present is decided from the first line, so entities containing spaces
+/// can't be read.
+pub fn read(path: &str) -> Result<EmbeddingSet, Error> {
+    let mut lines = BufReader::new(open_file(path)?).lines();
+    let header = match lines.next() {
+        Some(header) => header.map_err(|e| Error::io(path, e))?,
+        None => return Err(Error::invalid(path, "empty file")),
+    };
+    let dimension: usize = header
+        .split(' ')
+        .nth(1)
+        .and_then(|d| d.trim().parse().ok())
+        .ok_or_else(|| Error::invalid(path, "invalid header"))?;
+
+    let mut entities = Vec::new();
+    let mut occurrences = Vec::new();
+    let mut values = Vec::new();
+    let mut with_occurrence = None;
+    for (line_number, line) in lines.enumerate() {
+        let line = line.map_err(|e| Error::io(path, e))?;
+        if line.is_empty() {
+            continue;
+        }
+        let tokens: Vec<&str> = line.split(' ').collect();
+        let with_occurrence = *with_occurrence.get_or_insert(tokens.len() == dimension + 2);
+        let skip = if with_occurrence { 2 } else { 1 };
+        if tokens.len() != dimension + skip {
+            return Err(Error::invalid(
+                path,
+                format!("line {} has {} values", line_number + 2, tokens.len() - 1),
+            ));
+        }
+        entities.push(tokens[0].to_string());
+        if with_occurrence {
+            let occurrence: u32 = tokens[1].parse().map_err(|_| {
+                Error::invalid(path, format!("invalid occurrence count {}", tokens[1]))
+            })?;
+            occurrences.push(occurrence);
+        }
+        for token in &tokens[skip..] {
+            let value: f32 = token
+                .parse()
+                .map_err(|_| Error::invalid(path, format!("invalid value {}", token)))?;
+            values.push(value);
+        }
+    }
+
+    let occurrences = with_occurrence.filter(|&w| w).map(|_| occurrences);
+    EmbeddingSet::new(entities, occurrences, values, dimension).map_err(|e| Error::invalid(path, e))
+}
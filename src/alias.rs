@@ -0,0 +1,135 @@
+use crate::error::CleoraError;
+use rustc_hash::FxHashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::str;
+
+/// Canonical IDs of entities known under several IDs (e.g. case variants, merged accounts).
+/// Entities of all columns are replaced with their canonical IDs while the input is parsed, so
+/// the aliases collapse into one node before the graph is built. Canonical IDs are stored in the
+/// entity mapping, so they have to be valid UTF-8.
+#[derive(Debug, Default)]
+pub struct Aliases {
+    canonical: FxHashMap<Box<[u8]>, Box<str>>,
+}
+
+impl Aliases {
+    /// Reads aliases from a file of `alias<TAB>canonical` lines, see `Aliases::parse`
+    pub fn load(path: &str) -> Result<Self, CleoraError> {
+        let file = File::open(path).map_err(|e| CleoraError::read_file(path, e))?;
+        Aliases::parse(BufReader::new(file)).map_err(|e| CleoraError::invalid_aliases(path, e))
+    }
+
+    /// Parses `alias<TAB>canonical` lines, empty lines are skipped. Chains of aliases are
+    /// resolved to their last canonical ID, an alias can't have two canonical IDs and cycles are
+    /// rejected.
+    pub fn parse<R: BufRead>(reader: R) -> Result<Self, String> {
+        let mut canonical: FxHashMap<Box<[u8]>, Box<str>> = FxHashMap::default();
+        for (i, line) in reader.split(b'\n').enumerate() {
+            let line = line.map_err(|e| e.to_string())?;
+            let line = line.strip_suffix(b"\r").unwrap_or(&line);
+            if line.is_empty() {
+                continue;
+            }
+            let (alias, id) = match line.iter().position(|&b| b == b'\t') {
+                Some(tab) if !line[tab + 1..].contains(&b'\t') => (&line[..tab], &line[tab + 1..]),
+                _ => return Err(format!("line {} isn't alias<TAB>canonical", i + 1)),
+            };
+            if alias.is_empty() || id.is_empty() {
+                return Err(format!("line {} has an empty ID", i + 1));
+            }
+            let id = str::from_utf8(id)
+                .map_err(|_| format!("canonical ID on line {} isn't valid UTF-8", i + 1))?;
+            if alias == id.as_bytes() {
+                continue;
+            }
+            match canonical.get(alias) {
+                Some(other) if &**other != id => {
+                    return Err(format!(
+                        "[{}] is an alias of both [{}] and [{}]",
+                        String::from_utf8_lossy(alias),
+                        other,
+                        id
+                    ))
+                }
+                _ => {
+                    canonical.insert(alias.into(), id.into());
+                }
+            }
+        }
+
+        // chain has at most as many links as there are aliases, a longer one is a cycle
+        let mut resolved = FxHashMap::default();
+        for (alias, id) in canonical.iter() {
+            let mut id = id;
+            let mut links = 0;
+            while let Some(next) = canonical.get(id.as_bytes()) {
+                links += 1;
+                if links > canonical.len() {
+                    return Err(format!(
+                        "aliases of [{}] form a cycle",
+                        String::from_utf8_lossy(alias)
+                    ));
+                }
+                id = next;
+            }
+            resolved.insert(alias.clone(), id.clone());
+        }
+        Ok(Self {
+            canonical: resolved,
+        })
+    }
+
+    /// Canonical ID of the entity, the entity itself if it has none
+    #[inline]
+    pub fn resolve<'a>(&'a self, entity: &'a [u8]) -> &'a [u8] {
+        self.canonical
+            .get(entity)
+            .map_or(entity, |id| id.as_bytes())
+    }
+
+    /// Canonical ID of the entity, `None` if it isn't an alias
+    #[inline]
+    pub fn canonical_id(&self, entity: &[u8]) -> Option<&str> {
+        self.canonical.get(entity).map(|id| &id[..])
+    }
+
+    pub fn len(&self) -> usize {
+        self.canonical.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.canonical.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::alias::Aliases;
+
+    #[test]
+    fn parse_aliases() {
+        let aliases =
+            Aliases::parse(&b"Alice\talice\r\nALICE\talice\n\nacc2\tacc1\nacc3\tacc2\nb\tb\n"[..])
+                .unwrap();
+        assert_eq!(4, aliases.len());
+        assert_eq!(b"alice", aliases.resolve(b"ALICE"));
+        assert_eq!(b"alice", aliases.resolve(b"alice"));
+        // chains are resolved
+        assert_eq!(b"acc1", aliases.resolve(b"acc3"));
+        assert_eq!(b"b", aliases.resolve(b"b"));
+        assert_eq!(Some("acc1"), aliases.canonical_id(b"acc3"));
+        assert_eq!(None, aliases.canonical_id(b"acc1"));
+
+        assert!(Aliases::parse(&b"a\tb\na\tc\n"[..]).is_err());
+        assert!(Aliases::parse(&b"a\tb\nb\ta\n"[..]).is_err());
+        assert!(Aliases::parse(&b"a b\n"[..]).is_err());
+        assert!(Aliases::parse(&b"a\tb\tc\n"[..]).is_err());
+        assert!(Aliases::parse(&b"\tb\n"[..]).is_err());
+        // aliases are matched as bytes, canonical IDs are stored as strings
+        assert!(Aliases::parse(&b"\xff\tb\n"[..]).is_ok());
+        assert!(Aliases::parse(&b"a\t\xff\n"[..]).is_err());
+        // the same alias given twice is fine
+        assert!(Aliases::parse(&b"a\tb\na\tb\n"[..]).is_ok());
+    }
+}
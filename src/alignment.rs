@@ -0,0 +1,96 @@
+use ndarray::{Array2, ArrayView2};
+
+/// Max number of Newton-Schulz iterations. Small singular values grow by 1.5 per iteration, so
+/// it's enough even for badly conditioned problems.
+const MAX_POLAR_ITERATIONS: usize = 100;
+const POLAR_TOLERANCE: f64 = 1e-10;
+
+/// Orthogonal matrix `R` minimizing `||source * R - target||` (Frobenius norm), i.e. the rotation
+/// (possibly with reflection) which maps rows of `source` onto the matching rows of `target`.
+///
+/// `R` is the orthogonal polar factor of `source^T * target`, computed with Newton-Schulz
+/// iterations, so only matrix products are needed. If there are fewer rows than dimensions the
+/// problem is underdetermined and `R` is orthogonal only on the subspace spanned by the rows.
+pub fn orthogonal_procrustes(source: ArrayView2<f32>, target: ArrayView2<f32>) -> Array2<f32> {
+    let source = source.mapv(|v| v as f64);
+    let target = target.mapv(|v| v as f64);
+    let m = source.t().dot(&target);
+    let dimension = m.nrows();
+
+    let norm = m.iter().map(|v| v * v).sum::<f64>().sqrt();
+    if norm == 0f64 {
+        return Array2::eye(dimension);
+    }
+    // singular values scaled into (0, 1], the iteration converges for values below sqrt(3)
+    let mut x = m / norm;
+    let identity = Array2::<f64>::eye(dimension);
+    for _ in 0..MAX_POLAR_ITERATIONS {
+        let xtx = x.t().dot(&x);
+        let error = (&xtx - &identity).iter().map(|v| v * v).sum::<f64>();
+        if error < POLAR_TOLERANCE {
+            break;
+        }
+        x = x.dot(&(&identity * 3f64 - &xtx)) * 0.5f64;
+    }
+    x.mapv(|v| v as f32)
+}
+
+/// Euclidean distance between every row of `source * rotation` and the matching row of `target`
+pub fn residuals(
+    source: ArrayView2<f32>,
+    target: ArrayView2<f32>,
+    rotation: Option<&Array2<f32>>,
+) -> Vec<f32> {
+    let source = match rotation {
+        Some(rotation) => source.dot(rotation),
+        None => source.to_owned(),
+    };
+    source
+        .outer_iter()
+        .zip(target.outer_iter())
+        .map(|(s, t)| {
+            s.iter()
+                .zip(t.iter())
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f32>()
+                .sqrt()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::alignment::{orthogonal_procrustes, residuals};
+    use ndarray::{arr2, Array2};
+
+    #[test]
+    fn recover_rotation() {
+        let source = arr2(&[
+            [1.0f32, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [0.0, 0.0, 1.0],
+            [0.6, 0.8, 0.0],
+        ]);
+        // rotation by 90 degrees in the first two dimensions
+        let rotation = arr2(&[[0.0f32, 1.0, 0.0], [-1.0, 0.0, 0.0], [0.0, 0.0, 1.0]]);
+        let target = source.dot(&rotation);
+
+        let found = orthogonal_procrustes(source.view(), target.view());
+        for (a, b) in found.iter().zip(rotation.iter()) {
+            assert!((a - b).abs() < 1e-4);
+        }
+        let max_residual = residuals(source.view(), target.view(), Some(&found))
+            .into_iter()
+            .fold(0f32, f32::max);
+        assert!(max_residual < 1e-4);
+    }
+
+    #[test]
+    fn identity_for_empty_input() {
+        let empty = Array2::<f32>::zeros((0, 2));
+        assert_eq!(
+            Array2::<f32>::eye(2),
+            orthogonal_procrustes(empty.view(), empty.view())
+        );
+    }
+}
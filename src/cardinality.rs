@@ -0,0 +1,295 @@
+use crate::configuration::{BudgetPolicy, Configuration, Precision};
+use crate::error::CleoraError;
+use crate::sketch::HyperLogLog;
+use crate::sparse_matrix::configured_sparse_matrices;
+use log::{info, warn};
+
+/// Precision of the per column sketches, 16 KiB each with ~0.8% standard error
+const SKETCH_PRECISION: u8 = 14;
+
+/// After how many rows the estimated memory usage is checked against the budget
+const CHECK_EVERY_N_ROWS: u64 = 100_000;
+
+/// Rough memory used by a sparse matrix per entity (hash to id map entry, id to hash entry,
+/// row sum), edges aren't included
+pub const SPARSE_MATRIX_BYTES_PER_ENTITY: u64 = 48;
+
+/// Rough memory used by a sparse matrix per entry (the entry and its pair index entry)
+pub const SPARSE_MATRIX_BYTES_PER_ENTRY: u64 = 24;
+
+/// Tracks the number of unique entities per column during ingestion with HyperLogLog sketches
+/// and checks the memory needed to train their embeddings against the memory budget. It's a lower
+/// bound (edges and entity mapping aren't counted), so exceeding it means the run can't succeed.
+pub struct CardinalityMonitor {
+    column_names: Vec<String>,
+    sketches: Vec<HyperLogLog>,
+    /// Columns (indices in the configuration) of every sparse matrix, `None` for the second
+    /// column of a reflexive one
+    matrices: Vec<(usize, Option<usize>)>,
+    embedding_bytes_per_entity: u64,
+    budget: u64,
+    policy: BudgetPolicy,
+    rows: u64,
+    next_check: u64,
+    exceeded: bool,
+}
+
+impl CardinalityMonitor {
+    /// Returns `None` if there is no memory budget to check.
+    pub fn new(config: &Configuration) -> Option<Self> {
+        config
+            .memory_budget
+            .map(|budget| Self::with_budget(config, budget))
+    }
+
+    /// Monitor checking the given budget (e.g. `u64::MAX` just to estimate the cardinalities)
+    pub fn with_budget(config: &Configuration, budget: u64) -> Self {
+        let columns_count = config.columns.len();
+        let column_index = |name: &str| {
+            config
+                .columns
+                .iter()
+                .position(|c| c.name == name)
+                .expect("Sparse matrix of declared column")
+        };
+        let matrices = configured_sparse_matrices(config)
+            .iter()
+            .map(|m| {
+                let col_a = column_index(&m.col_a_name);
+                let col_b = column_index(&m.col_b_name);
+                (col_a, Some(col_b).filter(|&c| c != col_a))
+            })
+            .collect();
+        Self {
+            column_names: config.columns.iter().map(|c| c.name.clone()).collect(),
+            sketches: vec![HyperLogLog::new(SKETCH_PRECISION); columns_count],
+            matrices,
+            embedding_bytes_per_entity: embedding_bytes_per_entity(config),
+            budget,
+            policy: config.on_budget_exceeded,
+            rows: 0,
+            next_check: CHECK_EVERY_N_ROWS,
+            exceeded: false,
+        }
+    }
+
+    #[inline(always)]
+    pub fn add(&mut self, column: usize, hash: u64) {
+        self.sketches[column].add_hash(hash);
+    }
+
+    /// Counts processed rows, the budget is checked by `check_every_n_rows`
+    pub fn row_processed(&mut self) {
+        self.rows += 1;
+    }
+
+    /// Checks the budget if the rows passed the next check, every `CHECK_EVERY_N_ROWS` rows
+    pub fn check_every_n_rows(&mut self) -> Result<(), CleoraError> {
+        if self.rows < self.next_check {
+            return Ok(());
+        }
+        self.next_check = (self.rows / CHECK_EVERY_N_ROWS + 1) * CHECK_EVERY_N_ROWS;
+        self.check()
+    }
+
+    /// Monitor with empty sketches for rows processed apart (e.g. a range of the input parsed in
+    /// parallel), which are added back with `merge`. It doesn't check the budget itself.
+    pub fn fork(&self) -> Self {
+        Self {
+            column_names: self.column_names.clone(),
+            sketches: vec![HyperLogLog::new(SKETCH_PRECISION); self.sketches.len()],
+            matrices: self.matrices.clone(),
+            embedding_bytes_per_entity: self.embedding_bytes_per_entity,
+            budget: u64::MAX,
+            policy: self.policy,
+            rows: 0,
+            next_check: u64::MAX,
+            exceeded: false,
+        }
+    }
+
+    /// Adds entities and rows of the forked monitor, the budget is checked if the rows passed
+    /// the next check.
+    pub fn merge(&mut self, other: &CardinalityMonitor) -> Result<(), CleoraError> {
+        for (sketch, other) in self.sketches.iter_mut().zip(other.sketches.iter()) {
+            sketch.merge(other);
+        }
+        self.rows += other.rows;
+        self.check_every_n_rows()
+    }
+
+    /// Number of rows processed so far
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Estimated number of unique entities in the column
+    pub fn estimated_entities(&self, column: usize) -> u64 {
+        self.sketches[column].estimate().round() as u64
+    }
+
+    /// Estimated number of unique entities of every sparse matrix (in the order of
+    /// `configured_sparse_matrices`)
+    pub fn estimated_matrix_entities(&self) -> Vec<u64> {
+        self.matrices
+            .iter()
+            .map(|&(col_a, col_b)| {
+                let entities = match col_b {
+                    Some(col_b) => {
+                        let mut union = self.sketches[col_a].clone();
+                        union.merge(&self.sketches[col_b]);
+                        union.estimate()
+                    }
+                    None => self.sketches[col_a].estimate(),
+                };
+                entities.round() as u64
+            })
+            .collect()
+    }
+
+    /// Estimated memory (in bytes) needed by sparse matrices and embeddings of the entities seen
+    /// so far.
+    pub fn estimated_bytes(&self) -> u64 {
+        let bytes_per_entity = SPARSE_MATRIX_BYTES_PER_ENTITY + self.embedding_bytes_per_entity;
+        self.estimated_matrix_entities()
+            .iter()
+            .map(|entities| entities * bytes_per_entity)
+            .sum()
+    }
+
+    /// Checks the estimated memory usage against the budget. Fails if it's exceeded and the run
+    /// should be aborted, otherwise warns once.
+    pub fn check(&mut self) -> Result<(), CleoraError> {
+        let estimated = self.estimated_bytes();
+        if estimated <= self.budget || self.exceeded {
+            return Ok(());
+        }
+        let cardinalities: Vec<String> = self
+            .column_names
+            .iter()
+            .enumerate()
+            .map(|(column, name)| format!("{}: {}", name, self.estimated_entities(column)))
+            .collect();
+        let message = format!(
+            "Estimated memory usage {} bytes exceeds the memory budget {} bytes after {} rows. Estimated unique entities per column: {}",
+            estimated,
+            self.budget,
+            self.rows,
+            cardinalities.join(", ")
+        );
+        match self.policy {
+            BudgetPolicy::Abort => Err(CleoraError::MemoryBudget { message }),
+            BudgetPolicy::Warn => {
+                warn!("{}", message);
+                self.exceeded = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Logs the final estimates
+    pub fn report(&self) {
+        for (column, name) in self.column_names.iter().enumerate() {
+            info!(
+                "Estimated unique entities in column {}: {}",
+                name,
+                self.estimated_entities(column)
+            );
+        }
+        info!(
+            "Estimated memory usage: {} bytes of {} bytes budget",
+            self.estimated_bytes(),
+            self.budget
+        );
+    }
+}
+
+/// Memory of embeddings per entity during the training: two matrices are kept in memory
+/// (current and next iteration), memory-mapped ones don't count
+pub fn embedding_bytes_per_entity(config: &Configuration) -> u64 {
+    let value_bytes = match config.precision {
+        Precision::F32 => 4,
+        Precision::F64 => 8,
+    };
+    if config.in_memory_embedding_calculation {
+        2 * value_bytes * config.embeddings_dimension as u64
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cardinality::CardinalityMonitor;
+    use crate::configuration::{extract_fields, BudgetPolicy, Configuration};
+    use crate::error::CleoraError;
+
+    fn config(budget: u64) -> Configuration {
+        let columns = extract_fields(vec!["a", "complex::reflexive::b"]).unwrap();
+        let mut config = Configuration::default(String::from("input"), columns);
+        config.embeddings_dimension = 8;
+        config.memory_budget = Some(budget);
+        config
+    }
+
+    #[test]
+    fn estimate_memory_of_sparse_matrices() {
+        assert!(CardinalityMonitor::new(&Configuration::default(String::new(), vec![])).is_none());
+
+        let mut monitor = CardinalityMonitor::new(&config(u64::MAX)).unwrap();
+        for i in 0..1000u64 {
+            monitor.add(0, i);
+            monitor.add(1, i + 1_000_000);
+        }
+        // (a, b) matrix with 2000 entities and reflexive (b, b) with 1000 entities
+        let bytes_per_entity = 48 + 2 * 4 * 8;
+        let expected = 3000f64 * bytes_per_entity as f64;
+        assert!((monitor.estimated_bytes() as f64 / expected - 1f64).abs() < 0.03);
+        monitor.check().unwrap();
+    }
+
+    #[test]
+    fn merge_forked_monitors() {
+        let mut monitor = CardinalityMonitor::new(&config(u64::MAX)).unwrap();
+        let mut forks = [monitor.fork(), monitor.fork()];
+        for (i, fork) in forks.iter_mut().enumerate() {
+            for j in 0..1000u64 {
+                fork.add(0, i as u64 * 500 + j);
+                fork.row_processed();
+            }
+        }
+        for fork in forks.iter() {
+            monitor.merge(fork).unwrap();
+        }
+        assert_eq!(2000, monitor.rows());
+        let estimated = monitor.estimated_entities(0) as f64;
+        assert!((estimated / 1500f64 - 1f64).abs() < 0.03);
+        assert_eq!(0, monitor.estimated_entities(1));
+    }
+
+    #[test]
+    fn abort_over_budget() {
+        let mut monitor = CardinalityMonitor::new(&config(1024)).unwrap();
+        for i in 0..1000u64 {
+            monitor.add(0, i);
+            monitor.row_processed();
+        }
+        // not checked before the next check
+        monitor.check_every_n_rows().unwrap();
+        let err = monitor.check().unwrap_err();
+        assert!(matches!(err, CleoraError::MemoryBudget { .. }));
+        assert!(err.to_string().contains("exceeds the memory budget"));
+    }
+
+    #[test]
+    fn warn_over_budget() {
+        let mut config = config(1024);
+        config.on_budget_exceeded = BudgetPolicy::Warn;
+        let mut monitor = CardinalityMonitor::new(&config).unwrap();
+        for i in 0..1000u64 {
+            monitor.add(0, i);
+        }
+        monitor.check().unwrap();
+        monitor.check().unwrap();
+    }
+}
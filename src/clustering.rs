@@ -0,0 +1,105 @@
+use rayon::prelude::*;
+
+/// Coarse k-means clustering of embeddings (Lloyd's algorithm). Meant for grouping similar
+/// vectors together, not for high quality clusters, so it's fitted on a sample in few iterations.
+#[derive(Debug, Clone)]
+pub struct KMeans {
+    centroids: Vec<Vec<f32>>,
+}
+
+impl KMeans {
+    /// Fits centroids to the sample. Initial centroids are evenly spaced rows of the sample, so
+    /// the result is deterministic. Centroids of empty clusters are left where they were.
+    pub fn fit(sample: &[Vec<f32>], clusters: usize, iterations: usize) -> Self {
+        let clusters = clusters.min(sample.len()).max(1);
+        let step = (sample.len() / clusters).max(1);
+        let mut kmeans = Self {
+            centroids: (0..clusters).map(|c| sample[c * step].clone()).collect(),
+        };
+        let dimension = kmeans.centroids[0].len();
+
+        for _ in 0..iterations {
+            let assignments: Vec<usize> = sample.par_iter().map(|v| kmeans.predict(v)).collect();
+
+            let mut sums = vec![vec![0f64; dimension]; clusters];
+            let mut counts = vec![0usize; clusters];
+            for (vector, &cluster) in sample.iter().zip(assignments.iter()) {
+                counts[cluster] += 1;
+                for (sum, &value) in sums[cluster].iter_mut().zip(vector.iter()) {
+                    *sum += value as f64;
+                }
+            }
+
+            let mut moved = false;
+            for (cluster, centroid) in kmeans.centroids.iter_mut().enumerate() {
+                if counts[cluster] == 0 {
+                    continue;
+                }
+                for (value, sum) in centroid.iter_mut().zip(sums[cluster].iter()) {
+                    let mean = (*sum / counts[cluster] as f64) as f32;
+                    moved |= *value != mean;
+                    *value = mean;
+                }
+            }
+            if !moved {
+                break;
+            }
+        }
+        kmeans
+    }
+
+    /// Returns the index of the nearest (squared euclidean distance) centroid
+    pub fn predict(&self, vector: &[f32]) -> usize {
+        let mut nearest = 0;
+        let mut min_distance = f32::MAX;
+        for (cluster, centroid) in self.centroids.iter().enumerate() {
+            let distance: f32 = centroid
+                .iter()
+                .zip(vector.iter())
+                .map(|(c, v)| (c - v) * (c - v))
+                .sum();
+            if distance < min_distance {
+                min_distance = distance;
+                nearest = cluster;
+            }
+        }
+        nearest
+    }
+
+    pub fn centroids(&self) -> &[Vec<f32>] {
+        &self.centroids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clustering::KMeans;
+
+    #[test]
+    fn cluster_separated_groups() {
+        let sample = vec![
+            vec![0.0, 1.0],
+            vec![10.0, 10.0],
+            vec![0.1, 0.9],
+            vec![9.9, 10.1],
+            vec![-0.1, 1.1],
+            vec![10.1, 9.9],
+        ];
+        let kmeans = KMeans::fit(&sample, 2, 10);
+        assert_eq!(2, kmeans.centroids().len());
+
+        let first = kmeans.predict(&[0.0, 1.0]);
+        let second = kmeans.predict(&[10.0, 10.0]);
+        assert_ne!(first, second);
+        for (i, vector) in sample.iter().enumerate() {
+            let expected = if i % 2 == 0 { first } else { second };
+            assert_eq!(expected, kmeans.predict(vector));
+        }
+    }
+
+    #[test]
+    fn limit_clusters_to_sample_size() {
+        let kmeans = KMeans::fit(&[vec![1.0], vec![2.0]], 16, 10);
+        assert_eq!(2, kmeans.centroids().len());
+    }
+}
@@ -0,0 +1,169 @@
+use crate::query::CosineIndex;
+use rustc_hash::FxHashSet;
+use std::cmp::Ordering;
+
+/// Sorted values of a per-entity metric
+#[derive(Debug, Clone, PartialEq)]
+pub struct Distribution {
+    values: Vec<f32>,
+}
+
+impl Distribution {
+    pub fn new(mut values: Vec<f32>) -> Self {
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        Self { values }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn mean(&self) -> f32 {
+        if self.values.is_empty() {
+            return 0f32;
+        }
+        self.values.iter().map(|&v| v as f64).sum::<f64>() as f32 / self.values.len() as f32
+    }
+
+    /// Value below which the `q` fraction of the values lie (nearest rank), 0 if there are none
+    pub fn quantile(&self, q: f32) -> f32 {
+        if self.values.is_empty() {
+            return 0f32;
+        }
+        let rank = (q * self.values.len() as f32).ceil() as usize;
+        self.values[rank.clamp(1, self.values.len()) - 1]
+    }
+}
+
+/// Differences between two sets of embeddings of (mostly) the same entities
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub entities_a: usize,
+    pub entities_b: usize,
+    /// Entities embedded in both sets
+    pub common: usize,
+    /// Cosine distances between the two vectors of every common entity, `None` if the dimensions
+    /// differ. Only meaningful when the spaces are aligned (e.g. the same seed and initialization).
+    pub distances: Option<Distribution>,
+    /// Jaccard overlap of the `k` nearest neighbors of the sampled common entities in both sets,
+    /// which doesn't depend on the spaces being aligned
+    pub neighborhoods: Distribution,
+}
+
+/// Compares the embeddings of the common entities. Nearest neighbors take a scan of all entities,
+/// so they are compared for at most `sample_size` common entities, evenly spaced in the rows of
+/// the first set.
+pub fn compare(a: &CosineIndex, b: &CosineIndex, k: usize, sample_size: usize) -> Comparison {
+    let common: Vec<&str> = a
+        .entities()
+        .iter()
+        .map(|e| e.as_str())
+        .filter(|e| b.vector(e).is_some())
+        .collect();
+
+    let distances = (a.dimension() == b.dimension()).then(|| {
+        let distances = common
+            .iter()
+            .map(|e| {
+                let similarity = a.vector(e).unwrap().dot(&b.vector(e).unwrap());
+                1f32 - similarity
+            })
+            .collect();
+        Distribution::new(distances)
+    });
+
+    let step = (common.len() / sample_size.max(1)).max(1);
+    let neighborhoods = common
+        .iter()
+        .step_by(step)
+        .take(sample_size)
+        .map(|e| {
+            let neighbors_a: FxHashSet<&str> = a
+                .nearest_to_entity(e, k)
+                .unwrap()
+                .into_iter()
+                .map(|(n, _)| n)
+                .collect();
+            let neighbors_b: FxHashSet<&str> = b
+                .nearest_to_entity(e, k)
+                .unwrap()
+                .into_iter()
+                .map(|(n, _)| n)
+                .collect();
+            let union = neighbors_a.union(&neighbors_b).count();
+            if union == 0 {
+                return 1f32;
+            }
+            neighbors_a.intersection(&neighbors_b).count() as f32 / union as f32
+        })
+        .collect();
+
+    Comparison {
+        entities_a: a.len(),
+        entities_b: b.len(),
+        common: common.len(),
+        distances,
+        neighborhoods: Distribution::new(neighborhoods),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compare::{compare, Distribution};
+    use crate::loader::Embeddings;
+    use crate::query::CosineIndex;
+    use ndarray::arr2;
+
+    fn index(entities: &[&str], vectors: [[f32; 2]; 4]) -> CosineIndex {
+        CosineIndex::new(Embeddings {
+            entities: entities.iter().map(|e| e.to_string()).collect(),
+            vectors: arr2(&vectors),
+        })
+    }
+
+    #[test]
+    fn compare_embeddings() {
+        let a = index(
+            &["a", "b", "c", "d"],
+            [[1.0, 0.0], [0.9, 0.1], [0.0, 1.0], [0.1, 0.9]],
+        );
+        // b moved next to c, x replaced d
+        let b = index(
+            &["a", "b", "c", "x"],
+            [[2.0, 0.0], [0.0, 1.0], [0.1, 0.9], [-1.0, -0.1]],
+        );
+
+        let comparison = compare(&a, &b, 2, 10);
+        assert_eq!(
+            (4, 4, 3),
+            (
+                comparison.entities_a,
+                comparison.entities_b,
+                comparison.common
+            )
+        );
+        let distances = comparison.distances.unwrap();
+        assert!(distances.quantile(0.0).abs() < 1e-6);
+        assert!((distances.quantile(1.0) - (1.0 - 0.1 / 0.82f32.sqrt())).abs() < 1e-6);
+        // every entity keeps one of its two nearest neighbors
+        assert_eq!(3, comparison.neighborhoods.len());
+        assert!((comparison.neighborhoods.mean() - 1.0 / 3.0).abs() < 1e-6);
+
+        let sampled = compare(&a, &b, 2, 2);
+        assert_eq!(2, sampled.neighborhoods.len());
+    }
+
+    #[test]
+    fn distribution_quantiles() {
+        let distribution = Distribution::new(vec![0.4, 0.1, 0.3, 0.2]);
+        assert_eq!(0.1, distribution.quantile(0.0));
+        assert_eq!(0.2, distribution.quantile(0.5));
+        assert_eq!(0.4, distribution.quantile(0.9));
+        assert!((distribution.mean() - 0.25).abs() < 1e-6);
+        assert_eq!(0.0, Distribution::new(vec![]).quantile(0.5));
+    }
+}
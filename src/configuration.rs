@@ -1,14 +1,332 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use std::time::Duration;
+#[cfg(not(feature = "wasm"))]
+use std::time::Instant;
+#[cfg(feature = "wasm")]
+use web_time::Instant;
+
 #[derive(Debug)]
 pub enum FileType {
     Json,
     Tsv,
+    /// GraphML graph, read as rows of the source and target of every edge, see `GraphMlEdges`
+    GraphMl,
+    /// SNAP edge list (`FromNodeId ToNodeId` lines), see `parse_edge_line`
+    Snap,
 }
 
+impl FileType {
+    /// Whether the input is a graph, read as rows of two columns: the source and target of edges
+    pub fn is_graph(&self) -> bool {
+        matches!(self, FileType::GraphMl | FileType::Snap)
+    }
+}
+
+/// Columns of graph inputs without `--columns`. Nodes at both ends of the edges share entity
+/// hashes, so all of them are embedded into one output (`source__target`).
+pub const GRAPH_COLUMNS: &str = "source target";
+
 #[derive(Debug)]
 pub enum OutputFormat {
     TextFile,
     Parquet,
     Numpy,
+    /// Packed little-endian f32 rows with a small header, see `RawPersistor`
+    Raw,
+    /// Single file of packed little-endian f32 rows followed by the entities and occurrences, see
+    /// `CompactPersistor`
+    Compact,
+    /// Single `.npz` archive of embeddings, entities and occurrences, see `NpzPersistor`
+    Npz,
+    /// ONNX model looking up rows of the embedding matrix, see `OnnxPersistor`
+    Onnx,
+    /// `vectors.tsv` and `metadata.tsv` of the TensorBoard Embedding Projector, subsampled to the
+    /// number of points, see `ProjectorPersistor`
+    Projector(Option<usize>),
+    /// Parquet data files of a Delta Lake table, see `DeltaPersistor`
+    Delta(DeltaMode),
+    /// Table of a DuckDB database, see `DuckDbPersistor` (requires the `duckdb` feature)
+    DuckDb(DuckDbLayout),
+    /// Compact SQLite database of packed vectors, see `SqlitePersistor` (requires the `sqlite`
+    /// feature)
+    Sqlite,
+    /// Arrow table kept in memory and served over Arrow Flight on the address when the run
+    /// completes, see `FlightPersistor` (requires the `flight` feature)
+    Flight(String),
+    /// Parquet file source and feature view definitions of Feast, rows are pushed to the online
+    /// store through the feature server at the URL if given, see `FeastPersistor` (pushing
+    /// requires the `feast` feature)
+    Feast(Option<String>),
+}
+
+/// How the embeddings of the run are committed to an existing Delta table
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeltaMode {
+    /// Added to the rows of the table
+    Append,
+    /// Replace the rows (and the schema) of the table
+    Overwrite,
+}
+
+/// Columns of the vectors in the DuckDB table
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuckDbLayout {
+    /// `f0`..`fN` float columns
+    Columns,
+    /// Single `embedding` column of `FLOAT[]` lists
+    Array,
+}
+
+/// Format of the `.entities` sidecar of numpy and raw outputs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntitiesFormat {
+    /// Pretty-printed JSON list
+    Json,
+    /// Entity per line
+    Lines,
+    /// Length-prefixed UTF-8 names, see `EntitiesWriter`
+    Binary,
+}
+
+/// Output format of the hash to entity mapping
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntityMappingFormat {
+    Tsv,
+    Parquet,
+    /// Front-coded, zstd compressed blocks with an index, see `write_dictionary`
+    Dictionary,
+}
+
+/// Initialization of the embedding matrix before propagation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InitMethod {
+    /// Dense values from (-1, 1) derived from entity hash
+    Uniform,
+    /// Sparse random projection. Every value is `+-sqrt(1 / density)` with probability `density`
+    /// and zero otherwise (derived from entity hash as well)
+    SparseRandomProjection { density: f32 },
+}
+
+/// Function hashing entities into sparse matrix keys
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashFunction {
+    XxHash64,
+    FxHash,
+    /// Entities are already numeric ids and are used directly, no hash to entity mapping is kept
+    Identity,
+}
+
+/// Postprocessing step applied to the embeddings before they are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PostprocessStep {
+    /// Zero mean and unit variance for every dimension
+    Standardize,
+    /// Clip every dimension to its `1 - p` and `p` quantiles
+    Winsorize { p: f32 },
+}
+
+/// Order of the rows in the embedding outputs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortOutput {
+    /// Rows grouped by coarse k-means cluster, so similar vectors are stored next to each other
+    /// (better locality for ANN index builds and compression)
+    Cluster { clusters: usize },
+    /// Rows sorted by entity (bytes of the name), so outputs of the same input are diffable
+    Entity,
+    /// Rows sorted by occurrence count, the most frequent entities first, ties by entity
+    Occurrence,
+}
+
+/// Combination of the embeddings trained for every edge type into one output per column pair
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeTypeCombination {
+    /// Embeddings of the edge types one after another, zeros where the entity has no edges of the
+    /// type
+    Concat,
+    /// Mean of the embeddings of the edge types the entity has edges of
+    Merge,
+}
+
+/// Decay of the row weights with the age of the rows, given by the timestamp column
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TemporalDecay {
+    /// Age (in seconds) halving the weight
+    pub half_life: f64,
+    /// Time (unix seconds) the age is measured to, rows after it aren't boosted
+    pub reference: i64,
+}
+
+impl TemporalDecay {
+    /// Weight multiplier of the row from the given time (unix seconds)
+    pub fn factor(&self, timestamp: i64) -> f32 {
+        let age = (self.reference - timestamp).max(0) as f64;
+        0.5f64.powf(age / self.half_life) as f32
+    }
+}
+
+/// Format of the exported sparse matrices
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatrixFormat {
+    /// Matrix Market coordinate file with the entity of every row in a sidecar
+    MatrixMarket,
+    /// CSR arrays and entities in a `.npz` archive, readable by `scipy.sparse.load_npz`
+    Npz,
+}
+
+/// Type of the values the embeddings are propagated in. Embeddings are written as f32 anyway
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Precision {
+    F32,
+    /// Less rounding error accumulated over many iterations, twice the memory of the matrices
+    F64,
+}
+
+/// Scaling of the final embeddings, applied after postprocessing steps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    None,
+    /// Unit L2 norm of every embedding, so dot product equals cosine similarity
+    L2,
+    /// Zero mean and unit variance for every dimension, the same as `standardize` step
+    ZScore,
+}
+
+/// k-nearest-neighbor graph of the final embeddings, see `knn::write_graph`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KnnGraph {
+    /// Neighbors of every entity
+    pub k: usize,
+    pub method: KnnMethod,
+}
+
+/// Search of the nearest neighbors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KnnMethod {
+    /// Exact for up to `knn::KNN_EXACT_MAX_ENTITIES` embeddings, approximate for more
+    Auto,
+    /// Every pair of embeddings is compared
+    Exact,
+    /// Embeddings are compared with candidates found by random hyperplane hashing
+    Approximate,
+}
+
+/// Normalization of the graph (sparse matrix) used for propagation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PropagationKernel {
+    /// Row-normalized D^-1 A (random walk transition matrix)
+    Markov,
+    /// Symmetrically normalized D^-1/2 A D^-1/2, high degree entities weigh less
+    Symmetric,
+}
+
+/// Split of the propagation work between threads
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PropagationPartitioning {
+    /// Every thread owns a contiguous block of dimensions of all entities
+    Dimensions,
+    /// Every thread owns a contiguous range of entities with all their dimensions
+    Entities,
+    /// Dimension blocks if there are at least as many dimensions as threads, entity ranges
+    /// otherwise
+    Auto,
+}
+
+/// Handling of self-loops (entity related to itself, e.g. every entity of a reflexive column is
+/// paired with itself)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelfLoops {
+    /// Self-loops from the input are kept
+    Keep,
+    /// Self-loops are removed, entities without other relationships get zero embeddings
+    Drop,
+    /// Self-loops from the input are replaced with a self-loop of the given weight for every
+    /// entity. The weight isn't scaled by column weights
+    Add(f32),
+}
+
+/// Handling of malformed input rows: wrong number of columns, invalid UTF-8 or JSON, invalid
+/// weights, counts or timestamps and unknown edge types. Malformed rows are skipped and their
+/// total is reported after reading, unless the run fails.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MalformedRows {
+    /// Nothing is logged for the skipped rows
+    Skip,
+    /// The first given number of the skipped rows are logged with their line numbers
+    Log(usize),
+    /// The run fails on the first malformed row
+    Fail,
+}
+
+/// Which entities are pooled together when postprocessing statistics are computed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatsScope {
+    /// Separate statistics for every entity type (column), so smaller entity classes aren't skewed
+    /// by the bigger ones
+    PerEntityType,
+    /// One set of statistics shared by all entities in the output
+    Shared,
+}
+
+/// What happens when the estimated memory usage exceeds the memory budget
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BudgetPolicy {
+    /// Stop reading the input and fail the run
+    Abort,
+    /// Log a warning once and continue
+    Warn,
+}
+
+/// Metadata column written next to the embedding of the entity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetadataColumn {
+    /// Occurrence count of the entity
+    OccurCount,
+    /// Time of the run, parquet output only
+    Datetime,
+}
+
+/// S3 storage class of the objects written by the run, prices of the cost estimate depend on it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageClass {
+    Standard,
+    IntelligentTiering,
+    StandardIa,
+    OneZoneIa,
+    GlacierIr,
+}
+
+/// Prices (USD) of the cost estimate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CostPrices {
+    pub storage_class: StorageClass,
+    /// Storage per GB-month
+    pub storage: f64,
+    /// PUT requests (multipart upload requests included) per 1000
+    pub put: f64,
+    /// GET requests per 1000
+    pub get: f64,
+    /// Transfer of S3 input and output per GB, zero if the run is in the region of the bucket
+    pub transfer: f64,
+}
+
+impl CostPrices {
+    /// S3 list prices in us-east-1 (June 2022), storage of the first 50 TB
+    pub fn list_prices(storage_class: StorageClass) -> Self {
+        let (storage, put, get) = match storage_class {
+            StorageClass::Standard => (0.023, 0.005, 0.0004),
+            StorageClass::IntelligentTiering => (0.023, 0.005, 0.0004),
+            StorageClass::StandardIa => (0.0125, 0.01, 0.001),
+            StorageClass::OneZoneIa => (0.01, 0.01, 0.001),
+            StorageClass::GlacierIr => (0.004, 0.02, 0.01),
+        };
+        Self {
+            storage_class,
+            storage,
+            put,
+            get,
+            transfer: 0.0,
+        }
+    }
 }
 
 /// Pipeline configuration
@@ -43,12 +361,27 @@ pub struct Configuration {
     /// Type of the input file
     pub file_type: FileType,
 
+    /// Separator of the fields (columns) of TSV input
+    pub field_delimiter: u8,
+
+    /// Separators of the values of complex columns (by name) of TSV input, values of other
+    /// columns are separated by spaces
+    pub value_delimiters: Vec<(String, u8)>,
+
+    /// File of `alias<TAB>canonical` lines, entities are replaced with their canonical IDs while
+    /// the input is parsed
+    pub aliases: Option<String>,
+
     /// Output directory for files with embeddings
     pub output_dir: Option<String>,
 
     /// Output format
     pub output_format: OutputFormat,
 
+    /// Formats the embeddings are also written in, from the same propagation. Every format
+    /// renders its own file names from the output template.
+    pub extra_output_formats: Vec<OutputFormat>,
+
     /// Name of the relation, for output filename generation
     pub relation_name: String,
 
@@ -57,6 +390,202 @@ pub struct Configuration {
 
     /// Chunk size used in write
     pub chunk_size: usize,
+
+    /// Initialization of the embedding matrix
+    pub init_method: InitMethod,
+
+    /// Write hash to entity mapping at the end of the run in the given format
+    pub entity_mapping_format: Option<EntityMappingFormat>,
+
+    /// Abort the run when two different entities map to the same hash
+    pub fail_on_collision: bool,
+
+    /// Abort the run when more than this many hashes are shared by different entities
+    pub collision_budget: Option<usize>,
+
+    /// Postprocessing steps applied in order to the embeddings before they are written
+    pub postprocess: Vec<PostprocessStep>,
+
+    /// Scope of the statistics computed by postprocessing steps
+    pub postprocess_stats: StatsScope,
+
+    /// Scaling of the embeddings right before they are written
+    pub normalize: Normalization,
+
+    /// Dimension of the embeddings reduced with PCA (see `pca::Projection`), written next to the
+    /// full ones together with the fitted projection
+    pub reduce_dimension: Option<u16>,
+
+    /// k-nearest-neighbor graph of the final embeddings, written next to them
+    pub knn_graph: Option<KnnGraph>,
+
+    /// Function hashing entities into sparse matrix keys
+    pub hash_function: HashFunction,
+
+    /// Width of the entity hashes, 32 or 64 bits. 32-bit hashes make the hash to id indices of
+    /// the sparse matrices smaller, at the cost of more collisions (see
+    /// `entity::expected_collisions`).
+    pub hash_bits: u32,
+
+    /// Replace existing output files instead of refusing to run
+    pub overwrite: bool,
+
+    /// Order of the output rows, hash order if not set
+    pub sort_output: Option<SortOutput>,
+
+    /// Template of the embedding file names (relative to the output directory), see
+    /// `OUTPUT_TEMPLATE_PLACEHOLDERS`. Default template of the output format is used if not set.
+    pub output_template: Option<String>,
+
+    /// Memory (in bytes) the run may use. Entity cardinality is estimated during ingestion and
+    /// checked against it, so too big inputs are detected before the training starts.
+    pub memory_budget: Option<u64>,
+
+    /// Action taken when the estimated memory usage exceeds `memory_budget`
+    pub on_budget_exceeded: BudgetPolicy,
+
+    /// Train only column pairs whose graphs have some structure (see `select_column_pairs`),
+    /// ignored columns and `excluded_pairs` aren't built
+    pub auto_pairs: bool,
+
+    /// Column pairs (by name, in any order) not trained in auto pairs mode
+    pub excluded_pairs: Vec<(String, String)>,
+
+    /// Column pairs (by name, in any order, `(a, a)` is reflexive matrix of `a`) trained and
+    /// persisted, other pairs are skipped. All configured pairs are trained if `None`
+    pub relations: Option<Vec<(String, String)>>,
+
+    /// Labels of the edge type column, a sparse matrix is trained for every label of every column
+    /// pair. Rows of other labels are skipped. Empty if there is no edge type column
+    pub edge_types: Vec<String>,
+
+    /// How embeddings of the edge types are combined into the output of the column pair
+    pub edge_type_combination: EdgeTypeCombination,
+
+    /// Decay of the row weights with their age, required by the timestamp column
+    pub decay: Option<TemporalDecay>,
+
+    /// Embeddings file (in any output format) with feature vectors of the entities, which replace
+    /// their initial vectors. Vectors are projected or padded to the dimension
+    pub feature_init: Option<String>,
+
+    /// Export the sparse matrices (as they are propagated) in the given format next to the
+    /// embeddings
+    pub matrix_export: Option<MatrixFormat>,
+
+    /// Output directory of a previous run (with the same output template) whose embeddings the
+    /// new ones are rotated onto
+    pub align_to: Option<String>,
+
+    /// Type of the values the embeddings are propagated in
+    pub precision: Precision,
+
+    /// Repeated edges of a matrix are counted once, with the value of their first occurrence
+    pub dedupe_edges: bool,
+
+    /// Fraction of the input rows (hyperedges) uniformly sampled with the seed, `None` reads all
+    pub sample_edges: Option<f64>,
+
+    /// File the vocabulary (entity mapping and built graph) is saved to
+    pub save_vocab: Option<String>,
+
+    /// Vocabulary file of a previous run, loaded instead of reading the input
+    pub load_vocab: Option<String>,
+
+    /// MLflow tracking server the run parameters, metrics and outputs are logged to
+    pub mlflow_uri: Option<String>,
+
+    /// Entities occurring fewer times (in a sparse matrix) are removed before training
+    pub min_entity_count: u32,
+
+    /// Max number of entities (the most frequent ones) kept for the column, by column name
+    pub max_entities: Vec<(String, usize)>,
+
+    /// Merge entities cut off by `max_entities` into one unknown entity instead of removing them
+    pub unknown_bucket: bool,
+
+    /// Tags columns of the entity columns (entity column, tags column), tags of the row are
+    /// attached to its entities of the column
+    pub entity_tags: Vec<(String, String)>,
+
+    /// Only entities with one of the tags get their embeddings written, all entities are still in
+    /// the graph. Empty writes every entity
+    pub output_tags: Vec<String>,
+
+    /// Write intermediate embeddings after every iteration, next to the final ones
+    pub snapshot_iterations: bool,
+
+    /// Stop iterating connected components once changes of their embeddings stabilize within
+    /// the tolerance
+    pub convergence_tolerance: Option<f32>,
+
+    /// Stop iterating once 95% of the embeddings change (1 - cosine similarity to the iteration
+    /// before the previous one) less than the threshold
+    pub early_stop: Option<f32>,
+    /// Normalization of the graph used for propagation
+    pub kernel: PropagationKernel,
+
+    /// Weight of the initial vectors mixed back after every iteration (PageRank-style damping)
+    pub alpha: f32,
+    /// Weights of the columns (by name) scaling their edges, missing columns weigh 1
+    pub column_weights: Vec<(String, f32)>,
+
+    /// Handling of self-loops in the graphs
+    pub self_loops: SelfLoops,
+
+    /// Propagation stops early if the next iteration wouldn't finish before it
+    pub deadline: Option<Instant>,
+
+    /// Parse TSV input as raw bytes (memchr field splitting, hashing without UTF-8 validation),
+    /// lines the fast parser can't handle go through the regular one
+    pub fast_parse: bool,
+
+    /// Handling of input rows which can't be parsed
+    pub malformed_rows: MalformedRows,
+
+    /// Split of the propagation work between threads
+    pub propagation_partitioning: PropagationPartitioning,
+
+    /// Only read the input and print graph statistics with projected memory, nothing is trained
+    pub dry_run: bool,
+
+    /// Record wall time, allocation peak and throughput of the stages of the run and write them
+    /// as a JSON report
+    pub profile: bool,
+
+    /// Checksum the local inputs for the output manifests (see `manifest::InputFile`), which
+    /// reads them once more
+    pub input_checksums: bool,
+
+    /// Log histograms of cosine similarities of linked and unlinked entity pairs of the trained
+    /// embeddings, which samples pairs of every graph
+    pub similarity_summary: bool,
+
+    /// Directory with embeddings of a previous run (written with the same output template) whose
+    /// vectors replace the initial ones, so the training continues from them
+    pub continue_from: Option<String>,
+
+    /// Clear the run lock of the output location left by another run
+    pub force: bool,
+
+    /// S3 storage class of the outputs (and other objects) written by the run
+    pub storage_class: StorageClass,
+
+    /// Prices of the cost estimate printed with the dry run report
+    pub estimate_cost: Option<CostPrices>,
+
+    /// Format of the entities sidecar of numpy and raw outputs
+    pub entities_format: EntitiesFormat,
+
+    /// Write time of the run (`datetime` column) to parquet output
+    pub output_datetime: bool,
+
+    /// Chunks queued for the background writer of every output, 0 writes on the training thread
+    pub write_queue_size: usize,
+
+    /// Threads of the pool parsing the input and propagating the embeddings, set up by the binary
+    /// before the run (see `resources::init_thread_pool`). 0 leaves the pool as it is.
+    pub threads: usize,
 }
 
 /// Column configuration
@@ -76,6 +605,50 @@ pub struct Column {
 
     /// The field is ignored, no output file is written for the field
     pub ignored: bool,
+
+    /// The field holds signed weight of the row instead of entities, negative weights subtract
+    /// previously observed relations. Weight columns are ignored otherwise
+    pub weight: bool,
+
+    /// The field holds edge type (relation type label) of the row instead of entities, edges of
+    /// every type are trained separately. Edge type columns are ignored otherwise
+    pub edge_type: bool,
+
+    /// The field holds time of the row instead of entities, weight of the row decays with its
+    /// age. Timestamp columns are ignored otherwise
+    pub timestamp: bool,
+
+    /// The field holds multiplicity of the row (pre-aggregated number of its repetitions) instead
+    /// of entities. Count columns are ignored otherwise
+    pub count: bool,
+
+    /// The field holds tags of the entities of another column (see `Configuration::entity_tags`)
+    /// instead of entities. Tags columns are ignored otherwise
+    pub tags: bool,
+}
+
+impl Column {
+    /// Column as given in the columns option, e.g. `transient::complex::items`
+    pub fn spec(&self) -> String {
+        let modifiers = [
+            (self.transient, "transient"),
+            (self.complex, "complex"),
+            (self.reflexive, "reflexive"),
+            (self.ignored, "ignore"),
+            (self.weight, "weight"),
+            (self.edge_type, "edge_type"),
+            (self.timestamp, "timestamp"),
+            (self.count, "count"),
+            (self.tags, "tags"),
+        ];
+        let mut parts: Vec<&str> = modifiers
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, modifier)| *modifier)
+            .collect();
+        parts.push(&self.name);
+        parts.join("::")
+    }
 }
 
 impl Configuration {
@@ -90,12 +663,75 @@ impl Configuration {
             log_every_n: 1000,
             in_memory_embedding_calculation: true,
             file_type: FileType::Tsv,
+            field_delimiter: b'\t',
+            value_delimiters: vec![],
+            aliases: None,
             input: vec![input],
             output_dir: None,
             output_format: OutputFormat::TextFile,
+            extra_output_formats: vec![],
             relation_name: String::from("emb"),
             columns,
             chunk_size: 1000,
+            init_method: InitMethod::Uniform,
+            entity_mapping_format: None,
+            fail_on_collision: false,
+            collision_budget: None,
+            postprocess: vec![],
+            postprocess_stats: StatsScope::PerEntityType,
+            normalize: Normalization::None,
+            reduce_dimension: None,
+            knn_graph: None,
+            hash_function: HashFunction::XxHash64,
+            hash_bits: 64,
+            overwrite: false,
+            sort_output: None,
+            output_template: None,
+            memory_budget: None,
+            on_budget_exceeded: BudgetPolicy::Abort,
+            auto_pairs: false,
+            excluded_pairs: vec![],
+            relations: None,
+            edge_types: vec![],
+            edge_type_combination: EdgeTypeCombination::Concat,
+            decay: None,
+            feature_init: None,
+            matrix_export: None,
+            align_to: None,
+            precision: Precision::F32,
+            dedupe_edges: false,
+            sample_edges: None,
+            save_vocab: None,
+            load_vocab: None,
+            mlflow_uri: None,
+            min_entity_count: 1,
+            max_entities: vec![],
+            unknown_bucket: false,
+            entity_tags: vec![],
+            output_tags: vec![],
+            snapshot_iterations: false,
+            convergence_tolerance: None,
+            early_stop: None,
+            kernel: PropagationKernel::Markov,
+            alpha: 0f32,
+            column_weights: vec![],
+            self_loops: SelfLoops::Keep,
+            deadline: None,
+            fast_parse: false,
+            malformed_rows: MalformedRows::Log(100),
+            propagation_partitioning: PropagationPartitioning::Auto,
+            dry_run: false,
+            profile: false,
+            input_checksums: true,
+            similarity_summary: true,
+            continue_from: None,
+            force: false,
+            storage_class: StorageClass::Standard,
+            estimate_cost: None,
+            entities_format: EntitiesFormat::Json,
+            output_datetime: true,
+            write_queue_size: 4,
+            threads: 0,
         }
     }
 
@@ -103,6 +739,11 @@ impl Configuration {
     pub fn not_ignored_columns(&self) -> Vec<&Column> {
         self.columns.iter().filter(|&c| !c.ignored).collect()
     }
+
+    /// Output formats of the run, `output_format` first
+    pub fn output_formats(&self) -> impl Iterator<Item = &OutputFormat> {
+        std::iter::once(&self.output_format).chain(self.extra_output_formats.iter())
+    }
 }
 
 /// Extract columns config based on raw strings.
@@ -117,6 +758,11 @@ pub fn extract_fields(cols: Vec<&str>) -> Result<Vec<Column>, String> {
         let mut complex = false;
         let mut reflexive = false;
         let mut ignored = false;
+        let mut weight = false;
+        let mut edge_type = false;
+        let mut timestamp = false;
+        let mut count = false;
+        let mut tags = false;
 
         let parts_len = parts.len();
         if parts_len > 1 {
@@ -131,6 +777,16 @@ pub fn extract_fields(cols: Vec<&str>) -> Result<Vec<Column>, String> {
                     reflexive = true;
                 } else if part.eq_ignore_ascii_case("ignore") {
                     ignored = true;
+                } else if part.eq_ignore_ascii_case("weight") {
+                    weight = true;
+                } else if part.eq_ignore_ascii_case("edge_type") {
+                    edge_type = true;
+                } else if part.eq_ignore_ascii_case("timestamp") {
+                    timestamp = true;
+                } else if part.eq_ignore_ascii_case("count") {
+                    count = true;
+                } else if part.eq_ignore_ascii_case("tags") {
+                    tags = true;
                 } else {
                     let message = format!("Unrecognized column field modifier: {}", part);
                     return Err(message);
@@ -139,18 +795,670 @@ pub fn extract_fields(cols: Vec<&str>) -> Result<Vec<Column>, String> {
         } else {
             column_name = col;
         }
+        if weight {
+            if transient || complex || reflexive {
+                return Err(format!(
+                    "Weight column {} can't have other modifiers",
+                    column_name
+                ));
+            }
+            if columns.iter().any(|c| c.weight) {
+                return Err(format!(
+                    "Only one weight column is allowed, got another: {}",
+                    column_name
+                ));
+            }
+        }
+        if edge_type {
+            if transient || complex || reflexive || weight {
+                return Err(format!(
+                    "Edge type column {} can't have other modifiers",
+                    column_name
+                ));
+            }
+            if columns.iter().any(|c| c.edge_type) {
+                return Err(format!(
+                    "Only one edge type column is allowed, got another: {}",
+                    column_name
+                ));
+            }
+        }
+        if timestamp {
+            if transient || complex || reflexive || weight || edge_type {
+                return Err(format!(
+                    "Timestamp column {} can't have other modifiers",
+                    column_name
+                ));
+            }
+            if columns.iter().any(|c| c.timestamp) {
+                return Err(format!(
+                    "Only one timestamp column is allowed, got another: {}",
+                    column_name
+                ));
+            }
+        }
+        if count {
+            if transient || complex || reflexive || weight || edge_type || timestamp {
+                return Err(format!(
+                    "Count column {} can't have other modifiers",
+                    column_name
+                ));
+            }
+            if columns.iter().any(|c| c.count) {
+                return Err(format!(
+                    "Only one count column is allowed, got another: {}",
+                    column_name
+                ));
+            }
+        }
+        // several tags of a row are given like values of complex columns
+        if tags && (transient || reflexive || weight || edge_type || timestamp || count) {
+            return Err(format!(
+                "Tags column {} can't have other modifiers than complex",
+                column_name
+            ));
+        }
         let column = Column {
             name: column_name.to_string(),
             transient,
             complex,
             reflexive,
-            ignored,
+            ignored: ignored || weight || edge_type || timestamp || count || tags,
+            weight,
+            edge_type,
+            timestamp,
+            count,
+            tags,
         };
         columns.push(column);
     }
     Ok(columns)
 }
 
+/// Extract initialization method based on raw string such as `uniform`, `sparse-rp` or
+/// `sparse-rp:density=0.01`.
+pub fn extract_init_method(init: &str) -> Result<InitMethod, String> {
+    let mut parts = init.split(':');
+    let name = parts.next().unwrap_or_default();
+    match name {
+        "uniform" => {
+            if let Some(param) = parts.next() {
+                return Err(format!("Unrecognized uniform init parameter: {}", param));
+            }
+            Ok(InitMethod::Uniform)
+        }
+        "sparse-rp" => {
+            // Achlioptas' database-friendly projection as a default
+            let mut density = 1f32 / 3f32;
+            for param in parts {
+                match param.split_once('=') {
+                    Some(("density", value)) => {
+                        density = value
+                            .parse()
+                            .map_err(|_| format!("Invalid density value: {}", value))?;
+                    }
+                    _ => return Err(format!("Unrecognized sparse-rp init parameter: {}", param)),
+                }
+            }
+            if !(density > 0f32 && density <= 1f32) {
+                return Err(format!("Density must be in (0, 1] range: {}", density));
+            }
+            Ok(InitMethod::SparseRandomProjection { density })
+        }
+        _ => Err(format!("Unrecognized init method: {}", init)),
+    }
+}
+
+/// Extract postprocessing step based on raw string such as `standardize` or
+/// `winsorize:p=0.999`.
+pub fn extract_postprocess_step(step: &str) -> Result<PostprocessStep, String> {
+    let mut parts = step.split(':');
+    let name = parts.next().unwrap_or_default();
+    match name {
+        "standardize" => {
+            if let Some(param) = parts.next() {
+                return Err(format!("Unrecognized standardize parameter: {}", param));
+            }
+            Ok(PostprocessStep::Standardize)
+        }
+        "winsorize" => {
+            let mut p = 0.999f32;
+            for param in parts {
+                match param.split_once('=') {
+                    Some(("p", value)) => {
+                        p = value
+                            .parse()
+                            .map_err(|_| format!("Invalid quantile value: {}", value))?;
+                    }
+                    _ => return Err(format!("Unrecognized winsorize parameter: {}", param)),
+                }
+            }
+            if !(p > 0.5f32 && p < 1f32) {
+                return Err(format!("Quantile must be in (0.5, 1) range: {}", p));
+            }
+            Ok(PostprocessStep::Winsorize { p })
+        }
+        _ => Err(format!("Unrecognized postprocessing step: {}", step)),
+    }
+}
+
+/// Placeholders which can be used in output file name templates:
+/// - `{relation}` - relation name
+/// - `{column}` - names of the sparse matrix columns, e.g. `users__products`
+/// - `{dim}` - embedding dimension
+/// - `{timestamp}` - start of the training, e.g. `20220901T120000` (UTC)
+/// - `{format}` - output format, e.g. `numpy`
+pub const OUTPUT_TEMPLATE_PLACEHOLDERS: [&str; 5] =
+    ["relation", "column", "dim", "timestamp", "format"];
+
+/// Embedding file name template. Numpy and raw outputs append extensions for every file they write.
+pub const DEFAULT_OUTPUT_TEMPLATE: &str = "{relation}__{column}.out";
+
+/// Parquet outputs are timestamped, so consecutive runs don't overwrite each other
+pub const DEFAULT_PARQUET_OUTPUT_TEMPLATE: &str = "{relation}__{column}_{timestamp}.parquet";
+
+/// Delta output is a table directory, written again by the next runs
+pub const DEFAULT_DELTA_OUTPUT_TEMPLATE: &str = "{relation}__{column}.delta";
+
+pub const DEFAULT_DUCKDB_OUTPUT_TEMPLATE: &str = "{relation}__{column}.duckdb";
+
+pub const DEFAULT_SQLITE_OUTPUT_TEMPLATE: &str = "{relation}__{column}.sqlite";
+
+/// Feast output is the parquet file source, the definitions are written next to it
+pub const DEFAULT_FEAST_OUTPUT_TEMPLATE: &str = "{relation}__{column}_feast.parquet";
+
+pub const DEFAULT_FLIGHT_OUTPUT_TEMPLATE: &str = "{relation}__{column}";
+
+/// Validate output file name template. Every placeholder must be known and `{column}` is required,
+/// otherwise embeddings of different column pairs would be written to the same file.
+pub fn validate_output_template(template: &str) -> Result<String, String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => return Err(format!("Unclosed placeholder in: {}", template)),
+        };
+        let placeholder = &rest[(start + 1)..end];
+        if !OUTPUT_TEMPLATE_PLACEHOLDERS.contains(&placeholder) {
+            return Err(format!(
+                "Unrecognized placeholder {{{}}}. Use one of: {:?}",
+                placeholder, OUTPUT_TEMPLATE_PLACEHOLDERS
+            ));
+        }
+        rest = &rest[(end + 1)..];
+    }
+    if !template.contains("{column}") {
+        return Err(format!("Template must contain {{column}}: {}", template));
+    }
+    Ok(template.to_string())
+}
+
+/// Extract output order based on raw string such as `cluster` or `cluster:k=256`.
+pub fn extract_sort_output(order: &str) -> Result<SortOutput, String> {
+    let mut parts = order.split(':');
+    let name = parts.next().unwrap_or_default();
+    match name {
+        "cluster" => {
+            let mut clusters = 256usize;
+            for param in parts {
+                match param.split_once('=') {
+                    Some(("k", value)) => {
+                        clusters = value
+                            .parse()
+                            .map_err(|_| format!("Invalid number of clusters: {}", value))?;
+                    }
+                    _ => return Err(format!("Unrecognized cluster parameter: {}", param)),
+                }
+            }
+            if clusters == 0 {
+                return Err(String::from("Number of clusters must be positive"));
+            }
+            Ok(SortOutput::Cluster { clusters })
+        }
+        "entity" | "occurrence" => {
+            if let Some(param) = parts.next() {
+                return Err(format!("Unrecognized {} parameter: {}", name, param));
+            }
+            if name == "entity" {
+                Ok(SortOutput::Entity)
+            } else {
+                Ok(SortOutput::Occurrence)
+            }
+        }
+        _ => Err(format!("Unrecognized output order: {}", order)),
+    }
+}
+
+/// Extract memory size based on raw string such as `512M` or `16G`. Suffixes (K, M, G, T) are
+/// binary multiples, a number without suffix is in bytes.
+pub fn extract_memory_size(size: &str) -> Result<u64, String> {
+    let upper = size.trim().to_ascii_uppercase();
+    let digits = upper.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match upper[digits.len()..]
+        .trim_end_matches("IB")
+        .trim_end_matches('B')
+    {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("Unrecognized memory size unit: {}", size)),
+    };
+    let value: u64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid memory size: {}", size))?;
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Memory size too big: {}", size))
+}
+
+/// Extract duration based on raw string such as `90s`, `30m`, `2h` or `1d`. A number without
+/// suffix is in seconds.
+pub fn extract_duration(duration: &str) -> Result<Duration, String> {
+    let lower = duration.trim().to_ascii_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match &lower[digits.len()..] {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("Unrecognized duration unit: {}", duration)),
+    };
+    let value: u64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid duration: {}", duration))?;
+    value
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("Duration too long: {}", duration))
+}
+
+/// Extract time based on raw string: unix seconds such as `1654041600`, RFC 3339 time such as
+/// `2022-06-01T00:00:00Z`, `2022-06-01 00:00:00` or `2022-06-01` (UTC). Returns unix seconds.
+pub fn extract_timestamp(timestamp: &str) -> Result<i64, String> {
+    let timestamp = timestamp.trim();
+    if let Ok(seconds) = timestamp.parse::<f64>() {
+        if seconds.is_finite() {
+            return Ok(seconds as i64);
+        }
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(timestamp) {
+        return Ok(time.timestamp());
+    }
+    let time = NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S").or_else(|_| {
+        NaiveDate::parse_from_str(timestamp, "%Y-%m-%d")
+            .map(|date| date.and_hms_opt(0, 0, 0).expect("Midnight is valid time"))
+    });
+    time.map(|time| Utc.from_utc_datetime(&time).timestamp())
+        .map_err(|_| format!("Unrecognized time: {}", timestamp))
+}
+
+/// Read run configuration file (TOML, YAML or JSON, recognized by the extension) with options
+/// named like the long CLI flags, e.g. `dimension: 128` or `number_of_iterations = 4`. Returns
+/// option names (underscores replaced by dashes) with their values: lists give multiple values,
+/// `true` marks a flag, `false` and empty values are left out.
+pub fn read_run_config(path: &str) -> Result<Vec<(String, Vec<String>)>, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Can't read config file {}: {}", path, e))?;
+    let extension = path.rsplit('.').next().unwrap_or_default();
+    let parsed: Result<serde_json::Value, String> = match extension.to_ascii_lowercase().as_str() {
+        "toml" => toml::from_str(&content).map_err(|e| e.to_string()),
+        "yaml" | "yml" => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+        "json" => serde_json::from_str(&content).map_err(|e| e.to_string()),
+        _ => return Err(format!("Unrecognized config file extension: {}", path)),
+    };
+    let options = match parsed.map_err(|e| format!("Invalid config file {}: {}", path, e))? {
+        serde_json::Value::Object(options) => options,
+        _ => {
+            return Err(format!(
+                "Config file {} must contain a table of options",
+                path
+            ))
+        }
+    };
+
+    let mut run_config = Vec::new();
+    for (name, value) in options {
+        let values = match value {
+            serde_json::Value::Array(values) => values,
+            value => vec![value],
+        };
+        let mut option_values = Vec::new();
+        for value in values {
+            match value {
+                serde_json::Value::Null | serde_json::Value::Bool(false) => {}
+                serde_json::Value::Bool(true) => option_values.push(String::from("true")),
+                serde_json::Value::Number(number) => option_values.push(number.to_string()),
+                serde_json::Value::String(value) => option_values.push(value),
+                _ => return Err(format!("Unsupported value of option {}", name)),
+            }
+        }
+        if !option_values.is_empty() {
+            run_config.push((name.replace('_', "-"), option_values));
+        }
+    }
+    Ok(run_config)
+}
+
+/// Extract column pair based on raw string such as `users:products`. Both names must be declared
+/// columns.
+pub fn extract_column_pair(pair: &str, columns: &[Column]) -> Result<(String, String), String> {
+    let (a, b) = pair
+        .split_once(':')
+        .ok_or_else(|| format!("Column pair must be given as a:b, got: {}", pair))?;
+    for name in [a, b] {
+        if !columns.iter().any(|c| c.name == name) {
+            return Err(format!("Unknown column {} in pair: {}", name, pair));
+        }
+    }
+    Ok((a.to_string(), b.to_string()))
+}
+
+/// Extract relations based on raw string such as `users<->products,products<->brands`. Every
+/// relation must give a sparse matrix: both columns are declared and not ignored, at least one of
+/// them isn't transient and `a<->a` is allowed for reflexive columns only.
+pub fn extract_relations(
+    relations: &str,
+    columns: &[Column],
+) -> Result<Vec<(String, String)>, String> {
+    let mut pairs: Vec<(String, String)> = Vec::new();
+    for relation in relations.split(',').map(str::trim) {
+        let (a, b) = relation
+            .split_once("<->")
+            .ok_or_else(|| format!("Relation must be given as a<->b, got: {}", relation))?;
+        let (a, b) = (a.trim(), b.trim());
+        let mut relation_columns = Vec::with_capacity(2);
+        for name in [a, b] {
+            match columns.iter().find(|c| c.name == name) {
+                Some(column) if column.ignored => {
+                    return Err(format!("Ignored column {} in relation: {}", name, relation))
+                }
+                Some(column) => relation_columns.push(column),
+                None => return Err(format!("Unknown column {} in relation: {}", name, relation)),
+            }
+        }
+        if a == b && !relation_columns[0].reflexive {
+            return Err(format!(
+                "Column {} isn't reflexive in relation: {}",
+                a, relation
+            ));
+        }
+        if a != b && relation_columns.iter().all(|c| c.transient) {
+            return Err(format!(
+                "Both columns are transient in relation: {}",
+                relation
+            ));
+        }
+        let exists = pairs
+            .iter()
+            .any(|(x, y)| (x == a && y == b) || (x == b && y == a));
+        if !exists {
+            pairs.push((a.to_string(), b.to_string()));
+        }
+    }
+    Ok(pairs)
+}
+
+/// Extract labels of the edge type column based on raw string such as `follows,purchased`.
+/// Requires the edge type column, labels must be unique.
+pub fn extract_edge_types(edge_types: &str, columns: &[Column]) -> Result<Vec<String>, String> {
+    if !columns.iter().any(|c| c.edge_type) {
+        return Err(String::from(
+            "Edge types require a column with edge_type modifier",
+        ));
+    }
+    let mut labels: Vec<String> = Vec::new();
+    for label in edge_types.split(',').map(str::trim) {
+        if label.is_empty() {
+            return Err(format!("Empty edge type in: {}", edge_types));
+        }
+        if labels.iter().any(|l| l == label) {
+            return Err(format!("Duplicated edge type: {}", label));
+        }
+        labels.push(label.to_string());
+    }
+    Ok(labels)
+}
+
+/// Extract metadata columns based on raw string such as `occur_count,datetime`, `none` selects
+/// no columns
+pub fn extract_metadata_columns(columns: &str) -> Result<Vec<MetadataColumn>, String> {
+    if columns.trim() == "none" {
+        return Ok(vec![]);
+    }
+    let mut metadata_columns = Vec::new();
+    for column in columns.split(',').map(str::trim) {
+        let metadata_column = match column {
+            "occur_count" => MetadataColumn::OccurCount,
+            "datetime" => MetadataColumn::Datetime,
+            _ => return Err(format!("Unrecognized metadata column: {}", column)),
+        };
+        if !metadata_columns.contains(&metadata_column) {
+            metadata_columns.push(metadata_column);
+        }
+    }
+    Ok(metadata_columns)
+}
+
+/// Extract storage class based on raw string such as `standard-ia`
+pub fn extract_storage_class(storage_class: &str) -> Result<StorageClass, String> {
+    match storage_class {
+        "standard" => Ok(StorageClass::Standard),
+        "intelligent-tiering" => Ok(StorageClass::IntelligentTiering),
+        "standard-ia" => Ok(StorageClass::StandardIa),
+        "one-zone-ia" => Ok(StorageClass::OneZoneIa),
+        "glacier-ir" => Ok(StorageClass::GlacierIr),
+        _ => Err(format!("Unrecognized storage class: {}", storage_class)),
+    }
+}
+
+/// Overrides list prices with raw string such as `storage=0.0125 transfer=0.02`, e.g. prices of
+/// another region or negotiated ones. Keys: storage (GB-month), put and get (1000 requests),
+/// transfer (GB).
+pub fn extract_cost_prices(mut prices: CostPrices, overrides: &str) -> Result<CostPrices, String> {
+    for price in overrides.split_whitespace() {
+        let (name, value) = price
+            .split_once('=')
+            .ok_or_else(|| format!("Price must be given as name=value, got: {}", price))?;
+        let value: f64 = value
+            .parse()
+            .ok()
+            .filter(|v: &f64| *v >= 0f64 && v.is_finite())
+            .ok_or_else(|| format!("Invalid price: {}", price))?;
+        match name {
+            "storage" => prices.storage = value,
+            "put" => prices.put = value,
+            "get" => prices.get = value,
+            "transfer" => prices.transfer = value,
+            _ => return Err(format!("Unrecognized price: {}", name)),
+        }
+    }
+    Ok(prices)
+}
+
+/// Extract a delimiter: a single ASCII character other than a new line, or `tab` (also `\t`) and
+/// `space`.
+pub fn extract_delimiter(delimiter: &str) -> Result<u8, String> {
+    match delimiter {
+        "tab" | "\\t" => Ok(b'\t'),
+        "space" => Ok(b' '),
+        _ => match delimiter.as_bytes() {
+            [byte] if byte.is_ascii() && *byte != b'\n' && *byte != b'\r' => Ok(*byte),
+            _ => Err(format!(
+                "Delimiter must be a single ASCII character, tab or space, got: {}",
+                delimiter
+            )),
+        },
+    }
+}
+
+/// Extract value delimiters of complex columns based on raw string such as `tags:| brands:,`,
+/// columns may be given only once.
+pub fn extract_value_delimiters(
+    delimiters: &str,
+    columns: &[Column],
+) -> Result<Vec<(String, u8)>, String> {
+    let mut value_delimiters: Vec<(String, u8)> = Vec::new();
+    for value_delimiter in delimiters.split_whitespace() {
+        let (name, value) = value_delimiter.split_once(':').ok_or_else(|| {
+            format!(
+                "Value delimiter must be given as column:delimiter, got: {}",
+                value_delimiter
+            )
+        })?;
+        match columns.iter().find(|c| c.name == name) {
+            Some(column) if column.complex => {}
+            Some(_) => {
+                return Err(format!(
+                    "Column {} isn't complex, its values aren't split: {}",
+                    name, value_delimiter
+                ))
+            }
+            None => {
+                return Err(format!(
+                    "Unknown column {} in value delimiter: {}",
+                    name, value_delimiter
+                ))
+            }
+        }
+        if value_delimiters.iter().any(|(n, _)| n == name) {
+            return Err(format!("Value delimiter of column {} given twice", name));
+        }
+        value_delimiters.push((name.to_string(), extract_delimiter(value)?));
+    }
+    Ok(value_delimiters)
+}
+
+/// Extract column weights based on raw string such as `user:1.0 product:1.0 brand:0.3`. Weights
+/// must be positive, columns may be given only once.
+pub fn extract_column_weights(
+    weights: &str,
+    columns: &[Column],
+) -> Result<Vec<(String, f32)>, String> {
+    let mut column_weights: Vec<(String, f32)> = Vec::new();
+    for column_weight in weights.split_whitespace() {
+        let (name, value) = column_weight.split_once(':').ok_or_else(|| {
+            format!(
+                "Column weight must be given as column:weight, got: {}",
+                column_weight
+            )
+        })?;
+        if !columns.iter().any(|c| c.name == name) {
+            return Err(format!(
+                "Unknown column {} in weight: {}",
+                name, column_weight
+            ));
+        }
+        if column_weights.iter().any(|(n, _)| n == name) {
+            return Err(format!("Weight of column {} given twice", name));
+        }
+        let weight: f32 = value
+            .parse()
+            .map_err(|_| format!("Invalid weight: {}", value))?;
+        if !(weight > 0f32 && weight.is_finite()) {
+            return Err(format!("Weight must be positive, got: {}", value));
+        }
+        column_weights.push((name.to_string(), weight));
+    }
+    Ok(column_weights)
+}
+
+/// Extract entity limit of a column based on raw string such as `session_id=100000`.
+pub fn extract_max_entities(limit: &str, columns: &[Column]) -> Result<(String, usize), String> {
+    let (name, value) = limit
+        .split_once('=')
+        .ok_or_else(|| format!("Entity limit must be given as column=K, got: {}", limit))?;
+    if !columns.iter().any(|c| c.name == name) {
+        return Err(format!(
+            "Unknown column {} in entity limit: {}",
+            name, limit
+        ));
+    }
+    let max_entities: usize = value
+        .parse()
+        .map_err(|_| format!("Invalid number of entities: {}", value))?;
+    if max_entities == 0 {
+        return Err(String::from("Number of entities must be positive"));
+    }
+    Ok((name.to_string(), max_entities))
+}
+
+/// Extract tags column of an entity column based on raw string such as `items=item_tags`. The
+/// entity column must have output, the tags column must be a `tags` column.
+pub fn extract_entity_tags(
+    entity_tags: &str,
+    columns: &[Column],
+) -> Result<(String, String), String> {
+    let (name, tags_name) = entity_tags.split_once('=').ok_or_else(|| {
+        format!(
+            "Entity tags must be given as column=tags_column, got: {}",
+            entity_tags
+        )
+    })?;
+    match columns.iter().find(|c| c.name == name) {
+        None => return Err(format!("Unknown column {} in entity tags", name)),
+        Some(column) if column.ignored => {
+            return Err(format!("Column {} is ignored, it has no entities", name))
+        }
+        Some(column) if column.transient => {
+            return Err(format!("Column {} is transient, it has no output", name))
+        }
+        Some(_) => {}
+    }
+    match columns.iter().find(|c| c.name == tags_name) {
+        Some(column) if column.tags => Ok((name.to_string(), tags_name.to_string())),
+        Some(_) => Err(format!(
+            "Column {} isn't a tags column, mark it with tags::",
+            tags_name
+        )),
+        None => Err(format!("Unknown tags column {} in entity tags", tags_name)),
+    }
+}
+
+/// Marks the columns missing from the output columns (comma separated names, e.g. `user,product`)
+/// as transient: their embeddings are computed but not written and pairs without an output
+/// column aren't trained. Such columns lose the reflexive modifier, as a transient column can't
+/// be reflexive, so their reflexive matrices aren't trained either.
+pub fn apply_output_columns(
+    output_columns: &str,
+    cols: Vec<Column>,
+) -> Result<Vec<Column>, String> {
+    let names: Vec<&str> = output_columns
+        .split(',')
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .collect();
+    if names.is_empty() {
+        return Err(String::from("No output columns given"));
+    }
+    for name in names.iter() {
+        match cols.iter().find(|c| c.name == *name) {
+            None => return Err(format!("Unknown output column: {}", name)),
+            Some(col) if col.ignored => {
+                return Err(format!("Column {} is ignored, it has no embeddings", name))
+            }
+            Some(col) if col.transient => {
+                return Err(format!("Column {} is transient, it has no output", name))
+            }
+            Some(_) => {}
+        }
+    }
+    let cols = cols
+        .into_iter()
+        .map(|mut col| {
+            if !col.ignored && !names.contains(&col.name.as_str()) {
+                col.transient = true;
+                col.reflexive = false;
+            }
+            col
+        })
+        .collect();
+    validate_fields(cols)
+}
+
 /// Validate column modifiers.
 pub fn validate_fields(cols: Vec<Column>) -> Result<Vec<Column>, String> {
     for col in &cols {
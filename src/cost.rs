@@ -0,0 +1,350 @@
+use crate::configuration::{Configuration, CostPrices, EntitiesFormat, OutputFormat, StorageClass};
+use crate::dry_run::GraphReport;
+use crate::error::CleoraError;
+use crate::io::S3File;
+use crate::pipeline::embedding_output_files;
+use std::fmt;
+use std::fs;
+
+/// Bytes of a value in text output, e.g. `-0.12345678` with the separator
+const TEXT_BYTES_PER_VALUE: f64 = 12.0;
+
+/// Entity name length assumed when names aren't stored (identity hashes)
+const DEFAULT_ENTITY_BYTES: f64 = 16.0;
+
+/// Bytes of an entity in the JSON list of the `.entities` sidecar besides its name
+const ENTITIES_SIDECAR_BYTES: f64 = 8.0;
+
+/// Bytes of an entity in the binary `.entities` sidecar besides its name (length)
+const BINARY_ENTITIES_SIDECAR_BYTES: f64 = 4.0;
+
+/// Bytes of the `datetime` column of parquet output in a row (plain encoded string and its length)
+const PARQUET_DATETIME_BYTES: f64 = 23.0;
+
+/// Bytes of an entity mapping entry besides the entity name (hash and separators)
+const ENTITY_MAPPING_BYTES: f64 = 22.0;
+
+/// Part size of S3 multipart uploads, see `S3File`
+const S3_PART_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Prices are given per GiB
+const GB: f64 = (1u64 << 30) as f64;
+
+/// Expected S3 costs of the run: reading the input, writing and storing the outputs. Output sizes
+/// are projected from the estimated entities of the dry run, so they are as good as the estimate.
+#[derive(Debug)]
+pub struct CostReport {
+    pub prices: CostPrices,
+    /// Input files and their total size
+    pub input_files: usize,
+    pub input_bytes: u64,
+    /// Part of the input read from S3
+    pub s3_input_files: usize,
+    pub s3_input_bytes: u64,
+    /// Output files (snapshots and entity mapping included) and their projected size
+    pub output_files: usize,
+    pub output_bytes: u64,
+    /// Outputs are written to S3
+    pub s3_output: bool,
+    pub put_requests: u64,
+}
+
+impl CostReport {
+    pub fn get_cost(&self) -> f64 {
+        self.s3_input_files as f64 * self.prices.get / 1000f64
+    }
+
+    /// Transfer of the S3 input
+    pub fn transfer_cost(&self) -> f64 {
+        self.s3_input_bytes as f64 / GB * self.prices.transfer
+    }
+
+    /// Transfer of the outputs uploaded to S3
+    pub fn output_transfer_cost(&self) -> f64 {
+        if self.s3_output {
+            self.output_bytes as f64 / GB * self.prices.transfer
+        } else {
+            0f64
+        }
+    }
+
+    pub fn put_cost(&self) -> f64 {
+        self.put_requests as f64 * self.prices.put / 1000f64
+    }
+
+    /// Storage of the outputs per month
+    pub fn storage_cost(&self) -> f64 {
+        if self.s3_output {
+            self.output_bytes as f64 / GB * self.prices.storage
+        } else {
+            0f64
+        }
+    }
+
+    /// One-off costs of the run
+    pub fn run_cost(&self) -> f64 {
+        self.get_cost() + self.transfer_cost() + self.put_cost() + self.output_transfer_cost()
+    }
+}
+
+/// Estimates S3 costs of the run from the input sizes and the outputs projected by the dry run.
+pub fn estimate_cost(
+    config: &Configuration,
+    report: &GraphReport,
+    prices: CostPrices,
+) -> Result<CostReport, CleoraError> {
+    let mut input_bytes = 0;
+    let mut s3_input_files = 0;
+    let mut s3_input_bytes = 0;
+    for input in config.input.iter() {
+        if input.starts_with("s3://") {
+            let size = S3File::size(input)?;
+            s3_input_files += 1;
+            s3_input_bytes += size;
+            input_bytes += size;
+        } else {
+            let metadata = fs::metadata(input).map_err(|e| CleoraError::read_file(input, e))?;
+            input_bytes += metadata.len();
+        }
+    }
+
+    let entity_bytes = report.mean_entity_bytes.unwrap_or(DEFAULT_ENTITY_BYTES);
+    let bytes_per_entity: f64 = config
+        .output_formats()
+        .map(|format| embedding_bytes_per_entity(config, format, entity_bytes))
+        .sum();
+    // every snapshot is a full copy of the embeddings
+    let copies = if config.snapshot_iterations {
+        1 + config.max_number_of_iteration as usize
+    } else {
+        1
+    };
+    let files_per_output = config
+        .output_formats()
+        .map(|format| {
+            embedding_output_files(format, "", config.produce_entity_occurrence_count).len()
+        })
+        .sum();
+
+    // (size, number of files) of every written output
+    let mut outputs: Vec<(u64, usize)> = Vec::new();
+    for matrix in report.matrices.iter() {
+        let size = (matrix.entities as f64 * bytes_per_entity).round() as u64;
+        outputs.extend(vec![(size, files_per_output); copies]);
+        if config.deadline.is_some() {
+            outputs.push((0, 1));
+        }
+    }
+    if config.entity_mapping_format.is_some() {
+        let entities: u64 = report.columns.iter().map(|(_, entities)| entities).sum();
+        let size = entities as f64 * (entity_bytes + ENTITY_MAPPING_BYTES);
+        outputs.push((size.round() as u64, 1));
+    }
+
+    // multipart uploads: create and complete requests with a request for every part
+    let put_requests = outputs
+        .iter()
+        .map(|&(size, files)| 2 * files as u64 + (size as f64 / S3_PART_SIZE as f64).ceil() as u64)
+        .sum();
+
+    Ok(CostReport {
+        prices,
+        input_files: config.input.len(),
+        input_bytes,
+        s3_input_files,
+        s3_input_bytes,
+        output_files: outputs.iter().map(|&(_, files)| files).sum(),
+        output_bytes: outputs.iter().map(|&(size, _)| size).sum(),
+        s3_output: matches!(&config.output_dir, Some(dir) if dir.starts_with("s3://")),
+        put_requests,
+    })
+}
+
+/// Projected bytes of an entity in the output files of the format
+fn embedding_bytes_per_entity(
+    config: &Configuration,
+    output_format: &OutputFormat,
+    entity_bytes: f64,
+) -> f64 {
+    let dimension = config.embeddings_dimension as f64;
+    let occurrence = config.produce_entity_occurrence_count;
+    match output_format {
+        // sampled projector output is smaller
+        OutputFormat::TextFile | OutputFormat::Projector(_) => {
+            let occurrence_bytes = if occurrence { 6f64 } else { 0f64 };
+            entity_bytes + occurrence_bytes + dimension * TEXT_BYTES_PER_VALUE + 1f64
+        }
+        OutputFormat::Numpy
+        | OutputFormat::Raw
+        | OutputFormat::Onnx
+        | OutputFormat::Parquet
+        | OutputFormat::Feast(_)
+        | OutputFormat::Delta(_)
+        | OutputFormat::DuckDb(_)
+        | OutputFormat::Sqlite => {
+            let vector_bytes = match output_format {
+                // rows are padded to 64 bytes
+                OutputFormat::Raw => (dimension * 4f64 / 64f64).ceil() * 64f64,
+                _ => dimension * 4f64,
+            };
+            let occurrence_bytes = if occurrence { 4f64 } else { 0f64 };
+            let sidecar_bytes = match (output_format, config.entities_format) {
+                (OutputFormat::Parquet | OutputFormat::Delta(_), _) if config.output_datetime => {
+                    PARQUET_DATETIME_BYTES
+                }
+                // microseconds of the run
+                (OutputFormat::Feast(_), _) => 8f64,
+                (
+                    OutputFormat::Parquet
+                    | OutputFormat::Delta(_)
+                    | OutputFormat::DuckDb(_)
+                    | OutputFormat::Sqlite,
+                    _,
+                ) => 0f64,
+                (_, EntitiesFormat::Json) => ENTITIES_SIDECAR_BYTES,
+                (_, EntitiesFormat::Lines) => 1f64,
+                (_, EntitiesFormat::Binary) => BINARY_ENTITIES_SIDECAR_BYTES,
+            };
+            vector_bytes + entity_bytes + sidecar_bytes + occurrence_bytes
+        }
+        // served from memory, nothing is written
+        OutputFormat::Flight(_) => 0f64,
+        OutputFormat::Compact => {
+            let occurrence_bytes = if occurrence { 4f64 } else { 0f64 };
+            // rows are padded to 64 bytes, entities are prefixed with their length
+            (dimension * 4f64 / 64f64).ceil() * 64f64
+                + entity_bytes
+                + BINARY_ENTITIES_SIDECAR_BYTES
+                + occurrence_bytes
+        }
+        OutputFormat::Npz => {
+            let occurrence_bytes = if occurrence { 4f64 } else { 0f64 };
+            // entities are UTF-32, padding to the longest name isn't known
+            dimension * 4f64 + entity_bytes * 4f64 + occurrence_bytes
+        }
+    }
+}
+
+fn storage_class_name(storage_class: StorageClass) -> &'static str {
+    match storage_class {
+        StorageClass::Standard => "standard",
+        StorageClass::IntelligentTiering => "intelligent-tiering",
+        StorageClass::StandardIa => "standard-ia",
+        StorageClass::OneZoneIa => "one-zone-ia",
+        StorageClass::GlacierIr => "glacier-ir",
+    }
+}
+
+impl fmt::Display for CostReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Cost estimate (USD, {} storage class):",
+            storage_class_name(self.prices.storage_class)
+        )?;
+        writeln!(
+            f,
+            "  Input: {} files, {:.3} GB, {} from S3: {} GET requests ${:.4}, transfer ${:.4}",
+            self.input_files,
+            self.input_bytes as f64 / GB,
+            self.s3_input_files,
+            self.s3_input_files,
+            self.get_cost(),
+            self.transfer_cost()
+        )?;
+        if self.s3_output {
+            writeln!(
+                f,
+                "  Output: {} files, {:.3} GB to S3: {} PUT requests ${:.4}, transfer ${:.4}, storage ${:.4} per month",
+                self.output_files,
+                self.output_bytes as f64 / GB,
+                self.put_requests,
+                self.put_cost(),
+                self.output_transfer_cost(),
+                self.storage_cost()
+            )?;
+        } else {
+            writeln!(
+                f,
+                "  Output: {} files, {:.3} GB written locally, no S3 costs",
+                self.output_files,
+                self.output_bytes as f64 / GB
+            )?;
+        }
+        write!(
+            f,
+            "  Total: ${:.4} for the run, ${:.4} per month of storage",
+            self.run_cost(),
+            self.storage_cost()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::{
+        extract_cost_prices, extract_fields, Configuration, CostPrices, EntityMappingFormat,
+        OutputFormat, StorageClass,
+    };
+    use crate::cost::estimate_cost;
+    use crate::dry_run::{GraphReport, MatrixReport};
+
+    #[test]
+    fn estimate_output_costs() {
+        let path = std::env::temp_dir().join("cleora_cost_input.tsv");
+        std::fs::write(&path, "u1\ti1 i2\n").unwrap();
+        let columns = extract_fields(vec!["users", "complex::items"]).unwrap();
+        let mut config = Configuration::default(path.to_str().unwrap().to_string(), columns);
+        config.embeddings_dimension = 4;
+        config.output_format = OutputFormat::Numpy;
+        config.produce_entity_occurrence_count = true;
+        config.entity_mapping_format = Some(EntityMappingFormat::Tsv);
+        config.output_dir = Some(String::from("s3://bucket/embeddings"));
+        let report = GraphReport {
+            rows: 1,
+            columns: vec![(String::from("users"), 1000), (String::from("items"), 3000)],
+            matrices: vec![MatrixReport {
+                col_a_name: String::from("users"),
+                col_b_name: String::from("items"),
+                entities: 4000,
+                edges: 2,
+                entries: 4,
+            }],
+            projected_bytes: 0,
+            mean_entity_bytes: Some(10.0),
+        };
+        let prices = extract_cost_prices(
+            CostPrices::list_prices(StorageClass::StandardIa),
+            "storage=1 put=1000 transfer=2",
+        )
+        .unwrap();
+
+        let cost = estimate_cost(&config, &report, prices).unwrap();
+        assert_eq!(1, cost.input_files);
+        assert_eq!(9, cost.input_bytes);
+        assert_eq!(0, cost.s3_input_files);
+        // npy, .entities and .occurences files with the entity mapping
+        assert_eq!(4, cost.output_files);
+        let embedding_bytes = 4000 * (4 * 4 + 10 + 8 + 4);
+        let mapping_bytes = 4000 * (10 + 22);
+        assert_eq!(embedding_bytes + mapping_bytes, cost.output_bytes);
+        // two requests for every file and a part for every output
+        assert_eq!(2 * 4 + 2, cost.put_requests);
+        assert_eq!(10.0, cost.put_cost());
+        assert_eq!(
+            cost.output_bytes as f64 / (1u64 << 30) as f64,
+            cost.storage_cost()
+        );
+        // nothing is read from S3, the outputs are uploaded
+        assert_eq!(0.0, cost.transfer_cost());
+        assert_eq!(2.0 * cost.storage_cost(), cost.output_transfer_cost());
+        assert_eq!(
+            cost.put_cost() + cost.output_transfer_cost(),
+            cost.run_cost()
+        );
+
+        assert!(extract_cost_prices(prices, "storage=-1").is_err());
+        assert!(extract_cost_prices(prices, "delete=1").is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,314 @@
+use crate::configuration::Configuration;
+use crate::embedding::{calculate_embeddings, calculate_embeddings_mmap, PriorEmbeddings};
+use crate::entity::create_entity_mapping_persistor;
+use crate::error::CleoraError;
+use crate::persistence::embedding::{EmbeddingBatch, EmbeddingPersistor};
+use crate::persistence::entity::InMemoryEntityMappingPersistor;
+use crate::pipeline::build_graphs;
+use crate::sparse_matrix::{SparseMatrix, SparseMatrixReader};
+use log::info;
+use rustc_hash::FxHashMap;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::Arc;
+
+/// Pipeline stage after which the debug snapshot is taken.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DumpStage {
+    /// Input parsed, entity mapping created
+    PostParse,
+    /// Sparse matrices constructed
+    PostMatrix,
+    /// Embeddings propagated the given number of iterations
+    PostIteration(u8),
+}
+
+impl DumpStage {
+    fn name(&self) -> String {
+        match self {
+            DumpStage::PostParse => String::from("post-parse"),
+            DumpStage::PostMatrix => String::from("post-matrix"),
+            DumpStage::PostIteration(n) => format!("post-iteration-{}", n),
+        }
+    }
+}
+
+/// Extract dump stage based on raw string such as `post-parse`, `post-matrix` or `post-iteration-N`.
+pub fn extract_stage(stage: &str) -> Result<DumpStage, String> {
+    match stage {
+        "post-parse" => Ok(DumpStage::PostParse),
+        "post-matrix" => Ok(DumpStage::PostMatrix),
+        _ => match stage.strip_prefix("post-iteration-") {
+            Some(iteration) => iteration
+                .parse()
+                .map(DumpStage::PostIteration)
+                .map_err(|_| format!("Invalid iteration number in stage: {}", stage)),
+            None => Err(format!("Unrecognized stage: {}", stage)),
+        },
+    }
+}
+
+/// Runs the pipeline up to the requested stage and writes a compact JSON snapshot of internal
+/// structures (entity mapping stats, matrix nnz histograms, sample rows). It's meant to be attached
+/// to bug reports instead of the raw input data. Returns path of the written file.
+pub fn dump(
+    mut config: Configuration,
+    stage: DumpStage,
+    sample_size: usize,
+) -> Result<String, CleoraError> {
+    let directory = match config.output_dir.as_ref() {
+        Some(out) => format!("{}/", out),
+        None => String::from(""),
+    };
+    let file_name = format!(
+        "{}{}__debug_dump__{}.json",
+        directory,
+        config.relation_name,
+        stage.name()
+    );
+
+    let entity_mapping_persistor = Arc::new(create_entity_mapping_persistor(&config));
+    let sparse_matrices = build_graphs(&config, entity_mapping_persistor.clone())?;
+
+    let mut snapshot = json!({
+        "stage": stage.name(),
+        "config": format!("{:?}", config),
+        "entity_mapping": entity_mapping_snapshot(&entity_mapping_persistor, sample_size),
+    });
+
+    if stage != DumpStage::PostParse {
+        let matrices: Vec<Value> = sparse_matrices
+            .iter()
+            .map(|sparse_matrix| sparse_matrix_snapshot(sparse_matrix, sample_size))
+            .collect();
+        snapshot["sparse_matrices"] = Value::Array(matrices);
+    }
+
+    if let DumpStage::PostIteration(iteration) = stage {
+        config.max_number_of_iteration = iteration;
+        let config = Arc::new(config);
+        let mut embeddings = Vec::new();
+        for sparse_matrix in sparse_matrices {
+            let sparse_matrix = Arc::new(sparse_matrix);
+            let mut persistor = SnapshotPersistor::new(sample_size);
+            if config.in_memory_embedding_calculation {
+                calculate_embeddings(
+                    config.clone(),
+                    sparse_matrix.clone(),
+                    entity_mapping_persistor.clone(),
+                    &mut persistor,
+                    None,
+                    PriorEmbeddings::default(),
+                )?;
+            } else {
+                calculate_embeddings_mmap(
+                    config.clone(),
+                    sparse_matrix.clone(),
+                    entity_mapping_persistor.clone(),
+                    &mut persistor,
+                    None,
+                    PriorEmbeddings::default(),
+                )?;
+            }
+            let mut embedding = persistor.snapshot();
+            embedding["id"] = json!(sparse_matrix.get_id());
+            embeddings.push(embedding);
+        }
+        snapshot["embeddings"] = Value::Array(embeddings);
+    }
+
+    let file = File::create(&file_name).map_err(|e| CleoraError::create_file(&file_name, e))?;
+    serde_json::to_writer_pretty(BufWriter::new(file), &snapshot)
+        .map_err(|e| CleoraError::write_file(&file_name, e.into()))?;
+
+    info!("Debug dump for stage {} written", stage.name());
+    Ok(file_name)
+}
+
+fn entity_mapping_snapshot(
+    persistor: &InMemoryEntityMappingPersistor,
+    sample_size: usize,
+) -> Value {
+    let samples: Vec<Value> = persistor
+        .sample(sample_size)
+        .into_iter()
+        .map(|(hash, entity)| json!({ "hash": hash, "entity": &*entity }))
+        .collect();
+    json!({
+        "number_of_entities": persistor.len(),
+        "samples": samples,
+    })
+}
+
+fn sparse_matrix_snapshot(sparse_matrix: &SparseMatrix, sample_size: usize) -> Value {
+    let mut row_nnz: FxHashMap<u32, u32> = FxHashMap::default();
+    for entry in sparse_matrix.iter_entries() {
+        *row_nnz.entry(entry.row).or_insert(0) += 1;
+    }
+    let occurrences = sparse_matrix.iter_hashes().map(|hash| hash.occurrence);
+
+    let samples: Vec<Value> = sparse_matrix
+        .iter_entries()
+        .take(sample_size)
+        .map(|entry| json!([entry.row, entry.col, entry.value]))
+        .collect();
+
+    json!({
+        "id": sparse_matrix.get_id(),
+        "col_a_name": sparse_matrix.col_a_name,
+        "col_b_name": sparse_matrix.col_b_name,
+        "number_of_entities": sparse_matrix.get_number_of_entities(),
+        "number_of_edges": sparse_matrix.get_number_of_edges(),
+        "number_of_entries": sparse_matrix.get_number_of_entries(),
+        "row_nnz_histogram": log2_histogram(row_nnz.into_values()),
+        "occurrence_histogram": log2_histogram(occurrences),
+        "sample_entries": samples,
+    })
+}
+
+/// Buckets positive values by powers of two: [1, 2), [2, 4), [4, 8) etc.
+fn log2_histogram<I: Iterator<Item = u32>>(values: I) -> Value {
+    let mut buckets = [0u64; 33];
+    for value in values {
+        let bucket = 32 - value.leading_zeros() as usize;
+        buckets[bucket] += 1;
+    }
+    let histogram: Vec<Value> = buckets
+        .iter()
+        .enumerate()
+        .filter(|(_, &count)| count > 0)
+        .map(|(bucket, &count)| {
+            let (min, max) = if bucket == 0 {
+                (0u64, 0u64)
+            } else {
+                (1u64 << (bucket - 1), (1u64 << bucket) - 1)
+            };
+            json!({ "min": min, "max": max, "count": count })
+        })
+        .collect();
+    Value::Array(histogram)
+}
+
+/// Embedding persistor which keeps only sample rows and vector stats.
+struct SnapshotPersistor {
+    sample_size: usize,
+    entity_count: u32,
+    dimension: u16,
+    rows: Vec<Value>,
+    norm_min: f32,
+    norm_max: f32,
+    norm_sum: f64,
+    norm_count: u64,
+    non_finite_count: u64,
+}
+
+impl SnapshotPersistor {
+    fn new(sample_size: usize) -> Self {
+        SnapshotPersistor {
+            sample_size,
+            entity_count: 0,
+            dimension: 0,
+            rows: Vec::new(),
+            norm_min: f32::MAX,
+            norm_max: 0f32,
+            norm_sum: 0f64,
+            norm_count: 0,
+            non_finite_count: 0,
+        }
+    }
+
+    fn snapshot(&self) -> Value {
+        let norm_mean = if self.norm_count > 0 {
+            self.norm_sum / self.norm_count as f64
+        } else {
+            0f64
+        };
+        json!({
+            "number_of_entities": self.entity_count,
+            "dimension": self.dimension,
+            "norm_min": self.norm_min,
+            "norm_max": self.norm_max,
+            "norm_mean": norm_mean,
+            "non_finite_vectors": self.non_finite_count,
+            "samples": self.rows,
+        })
+    }
+}
+
+impl EmbeddingPersistor for SnapshotPersistor {
+    fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), CleoraError> {
+        self.entity_count = entity_count;
+        self.dimension = dimension;
+        Ok(())
+    }
+
+    fn put_data(
+        &mut self,
+        entity: &str,
+        occur_count: u32,
+        vector: Vec<f32>,
+    ) -> Result<(), CleoraError> {
+        if vector.iter().any(|v| !v.is_finite()) {
+            self.non_finite_count += 1;
+        } else {
+            let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+            self.norm_min = self.norm_min.min(norm);
+            self.norm_max = self.norm_max.max(norm);
+            self.norm_sum += norm as f64;
+            self.norm_count += 1;
+        }
+
+        if self.rows.len() < self.sample_size {
+            self.rows.push(json!({
+                "entity": entity,
+                "occur_count": occur_count,
+                "vector": vector,
+            }));
+        }
+        Ok(())
+    }
+
+    fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+        for (entity, occur_count, vector) in chunk.rows() {
+            self.put_data(entity, occur_count, vector.to_vec())?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), CleoraError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debug::{extract_stage, log2_histogram, DumpStage};
+    use serde_json::json;
+
+    #[test]
+    fn extract_dump_stages() {
+        assert_eq!(Ok(DumpStage::PostParse), extract_stage("post-parse"));
+        assert_eq!(Ok(DumpStage::PostMatrix), extract_stage("post-matrix"));
+        assert_eq!(
+            Ok(DumpStage::PostIteration(3)),
+            extract_stage("post-iteration-3")
+        );
+        assert!(extract_stage("post-iteration-x").is_err());
+        assert!(extract_stage("pre-parse").is_err());
+    }
+
+    #[test]
+    fn bucket_values_by_powers_of_two() {
+        let histogram = log2_histogram(vec![1, 2, 3, 4, 7, 8].into_iter());
+        assert_eq!(
+            json!([
+                { "min": 1, "max": 1, "count": 1 },
+                { "min": 2, "max": 3, "count": 2 },
+                { "min": 4, "max": 7, "count": 2 },
+                { "min": 8, "max": 15, "count": 1 },
+            ]),
+            histogram
+        );
+    }
+}
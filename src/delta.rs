@@ -0,0 +1,403 @@
+use crate::configuration::DeltaMode;
+use crate::error::CleoraError;
+use crate::io::{runtime, S3File};
+use crate::persistence::embedding::{EmbeddingBatch, EmbeddingPersistor, ParquetVectorPersistor};
+use arrow2::datatypes::{DataType, Field};
+use chrono::Utc;
+use deltalake::kernel::Action;
+use deltalake::operations::transaction::commit;
+use deltalake::protocol::{DeltaOperation, SaveMode};
+use deltalake::table::state::DeltaTableState;
+use deltalake::{DeltaTable, DeltaTableBuilder, DeltaTableError};
+use log::info;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Once;
+use uuid::Uuid;
+
+/// Protocol versions of new tables. Writers of existing tables are checked by delta-rs.
+const MIN_READER_VERSION: u64 = 1;
+const MIN_WRITER_VERSION: u64 = 2;
+
+/// Writes embeddings as a Delta Lake table, the output name is the table directory (local or S3).
+/// Every run writes a parquet data file (see `ParquetVectorPersistor`) and commits it with delta-rs
+/// as the next version of the table: added to its rows or replacing them, as the mode says.
+/// Columns of another dimension (or metadata columns) are merged into the table schema on append,
+/// rows without them read as nulls. The table state is read from the latest checkpoint and the
+/// commits after it, concurrent commits are detected by delta-rs, which creates commit files
+/// atomically (S3 tables need its locking provider, e.g. `AWS_S3_LOCKING_PROVIDER=dynamodb`).
+pub struct DeltaPersistor {
+    table: String,
+    data_file: String,
+    mode: DeltaMode,
+    parquet: ParquetVectorPersistor,
+}
+
+impl DeltaPersistor {
+    pub fn new(
+        table: String,
+        dimension: u16,
+        produce_entity_occurrence_count: bool,
+        output_datetime: bool,
+        mode: DeltaMode,
+    ) -> Result<Self, CleoraError> {
+        let table = table.trim_end_matches('/').to_string();
+        if !table.starts_with("s3://") {
+            fs::create_dir_all(&table).map_err(|e| CleoraError::create_file(&table, e))?;
+        }
+        // data files are never overwritten, the same way Spark names them
+        let data_file = format!("part-00000-{}-c000.snappy.parquet", Uuid::new_v4());
+        let parquet = ParquetVectorPersistor::with_signed_counts(
+            format!("{}/{}", table, data_file),
+            dimension,
+            produce_entity_occurrence_count,
+            output_datetime,
+        )?;
+        Ok(Self {
+            table,
+            data_file,
+            mode,
+            parquet,
+        })
+    }
+
+    /// Commits the written data file as the next version of the table
+    fn commit(&self) -> Result<(), CleoraError> {
+        let delta_table = load_table(&self.table)?;
+        let snapshot = delta_table.snapshot().ok();
+        let table_fields = snapshot
+            .map(|snapshot| table_fields(&self.table, snapshot))
+            .transpose()?;
+        let now = Utc::now().timestamp_millis();
+        let fields = delta_fields(&self.table, self.parquet.fields())?;
+        let schema = match (self.mode, table_fields.as_ref()) {
+            (DeltaMode::Append, Some(table_fields)) => {
+                merge_fields(&self.table, table_fields, fields)?
+            }
+            _ => fields,
+        };
+
+        let mut actions = Vec::new();
+        if snapshot.is_none() {
+            actions.push(json!({"protocol": {
+                "minReaderVersion": MIN_READER_VERSION,
+                "minWriterVersion": MIN_WRITER_VERSION,
+            }}));
+        }
+        if table_fields.as_ref() != Some(&schema) {
+            let metadata = snapshot.map(|snapshot| snapshot.metadata());
+            let schema_string = json!({"type": "struct", "fields": schema}).to_string();
+            actions.push(json!({"metaData": {
+                "id": metadata
+                    .map(|metadata| metadata.id.clone())
+                    .unwrap_or_else(|| Uuid::new_v4().to_string()),
+                "format": {"provider": "parquet", "options": {}},
+                "schemaString": schema_string,
+                "partitionColumns": [],
+                "configuration": {},
+                "createdTime": metadata
+                    .and_then(|metadata| metadata.created_time)
+                    .unwrap_or(now),
+            }}));
+        }
+        if let (DeltaMode::Overwrite, Some(snapshot)) = (self.mode, snapshot) {
+            let files = snapshot
+                .file_actions()
+                .map_err(|e| CleoraError::delta_table(&self.table, e))?;
+            for file in files {
+                actions.push(json!({"remove": {
+                    "path": file.path,
+                    "deletionTimestamp": now,
+                    "dataChange": true,
+                }}));
+            }
+        }
+        actions.push(json!({"add": {
+            "path": self.data_file,
+            "partitionValues": {},
+            "size": file_size(&format!("{}/{}", self.table, self.data_file))?,
+            "modificationTime": now,
+            "dataChange": true,
+        }}));
+        let actions = actions
+            .into_iter()
+            .map(serde_json::from_value)
+            .collect::<Result<Vec<Action>, _>>()
+            .map_err(|e| CleoraError::delta_table(&self.table, e))?;
+
+        let mode = match self.mode {
+            DeltaMode::Append => SaveMode::Append,
+            DeltaMode::Overwrite => SaveMode::Overwrite,
+        };
+        let operation = DeltaOperation::Write {
+            mode,
+            partition_by: None,
+            predicate: None,
+        };
+        let app_metadata = HashMap::from([(
+            "engineInfo".to_string(),
+            json!(format!("cleora/{}", env!("CARGO_PKG_VERSION"))),
+        )]);
+        let version = runtime()
+            .block_on(commit(
+                delta_table.log_store().as_ref(),
+                &actions,
+                operation,
+                snapshot,
+                Some(app_metadata),
+            ))
+            .map_err(|e| CleoraError::delta_table(&self.table, e))?;
+        info!(
+            "{} committed to Delta table {} as version {} ({:?})",
+            self.data_file, self.table, version, mode
+        );
+        Ok(())
+    }
+}
+
+impl EmbeddingPersistor for DeltaPersistor {
+    fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), CleoraError> {
+        self.parquet.put_metadata(entity_count, dimension)
+    }
+
+    fn put_data(
+        &mut self,
+        entity: &str,
+        occur_count: u32,
+        vector: Vec<f32>,
+    ) -> Result<(), CleoraError> {
+        self.parquet.put_data(entity, occur_count, vector)
+    }
+
+    fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+        self.parquet.put_data_batch(chunk)
+    }
+
+    fn finish(&mut self) -> Result<(), CleoraError> {
+        self.parquet.finish()?;
+        self.commit()
+    }
+}
+
+/// Fields of the table schema. Only the entity is required, other columns may be missing in the
+/// data files of other runs. Delta has no unsigned integers, occurrence counts are written as
+/// long.
+fn delta_fields(table: &str, fields: &[Field]) -> Result<Vec<Value>, CleoraError> {
+    fields
+        .iter()
+        .map(|field| {
+            let data_type = match &field.data_type {
+                DataType::Utf8 => "string",
+                DataType::Int64 => "long",
+                DataType::Float32 => "float",
+                data_type => {
+                    return Err(CleoraError::delta_table(
+                        table,
+                        format!(
+                            "column {} of type {:?} isn't supported",
+                            field.name, data_type
+                        ),
+                    ))
+                }
+            };
+            Ok(json!({
+                "name": field.name,
+                "type": data_type,
+                "nullable": field.name != "entity",
+                "metadata": {},
+            }))
+        })
+        .collect()
+}
+
+/// Schema of the table with columns of the run added
+fn merge_fields(
+    table: &str,
+    table_fields: &[Value],
+    fields: Vec<Value>,
+) -> Result<Vec<Value>, CleoraError> {
+    let mut merged = table_fields.to_vec();
+    for field in fields {
+        match table_fields.iter().find(|f| f["name"] == field["name"]) {
+            Some(table_field) if table_field["type"] != field["type"] => {
+                return Err(CleoraError::delta_table(
+                    table,
+                    format!(
+                        "column {} is {} in the table, can't append {}",
+                        field["name"], table_field["type"], field["type"]
+                    ),
+                ));
+            }
+            Some(_) => {}
+            None => merged.push(field),
+        }
+    }
+    Ok(merged)
+}
+
+/// Table at its latest version, a table without commits yet isn't loaded (it has no snapshot)
+fn load_table(table: &str) -> Result<DeltaTable, CleoraError> {
+    static REGISTER_S3: Once = Once::new();
+    REGISTER_S3.call_once(|| deltalake::aws::register_handlers(None));
+    let mut delta_table = DeltaTableBuilder::from_uri(table)
+        .build()
+        .map_err(|e| CleoraError::delta_table(table, e))?;
+    match runtime().block_on(delta_table.load()) {
+        Ok(()) | Err(DeltaTableError::NotATable(_)) => Ok(delta_table),
+        Err(e) => Err(CleoraError::delta_table(table, e)),
+    }
+}
+
+/// Schema fields of the existing table, which has to be unpartitioned (data files are added
+/// without partition values)
+fn table_fields(table: &str, snapshot: &DeltaTableState) -> Result<Vec<Value>, CleoraError> {
+    let metadata = snapshot.metadata();
+    if !metadata.partition_columns.is_empty() {
+        return Err(CleoraError::delta_table(
+            table,
+            "partitioned tables aren't supported",
+        ));
+    }
+    let schema: Value = serde_json::from_str(&metadata.schema_string)
+        .map_err(|e| CleoraError::delta_table(table, e))?;
+    Ok(schema["fields"].as_array().cloned().unwrap_or_default())
+}
+
+fn file_size(path: &str) -> Result<u64, CleoraError> {
+    if path.starts_with("s3://") {
+        S3File::size(path)
+    } else {
+        fs::metadata(path)
+            .map(|metadata| metadata.len())
+            .map_err(|e| CleoraError::read_file(path, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::DeltaMode;
+    use crate::delta::{delta_fields, load_table, table_fields, DeltaPersistor};
+    use crate::io::runtime;
+    use crate::persistence::embedding::{EmbeddingBatch, EmbeddingPersistor};
+    use arrow2::datatypes::{DataType, Field};
+    use serde_json::Value;
+
+    fn write(table: &str, dimension: u16, mode: DeltaMode) {
+        let mut persistor =
+            DeltaPersistor::new(table.to_string(), dimension, true, false, mode).unwrap();
+        persistor.put_metadata(2, dimension).unwrap();
+        persistor
+            .put_data_batch(EmbeddingBatch::new(
+                &["a".into(), "b".into()],
+                &[1, 2],
+                &vec![0.5; 2 * dimension as usize],
+                dimension as usize,
+            ))
+            .unwrap();
+        persistor.finish().unwrap();
+    }
+
+    /// Version, number of data files and schema fields of the table
+    fn read(table: &str) -> (i64, usize, Vec<Value>) {
+        let delta_table = load_table(table).unwrap();
+        let snapshot = delta_table.snapshot().unwrap();
+        (
+            delta_table.version(),
+            snapshot.file_actions().unwrap().len(),
+            table_fields(table, snapshot).unwrap(),
+        )
+    }
+
+    fn field_names(fields: &[Value]) -> Vec<&str> {
+        fields.iter().map(|f| f["name"].as_str().unwrap()).collect()
+    }
+
+    fn commit_file(table: &str, version: u64) -> String {
+        format!("{}/_delta_log/{:020}.json", table, version)
+    }
+
+    #[test]
+    fn append_and_overwrite_table() {
+        let table = std::env::temp_dir().join("cleora_delta_table.delta");
+        let _ = std::fs::remove_dir_all(&table);
+        let table = table.to_str().unwrap();
+
+        write(table, 2, DeltaMode::Append);
+        write(table, 2, DeltaMode::Append);
+        let (version, files, fields) = read(table);
+        assert_eq!(1, version);
+        assert_eq!(2, files);
+        assert_eq!(
+            vec!["entity", "occur_count", "f0", "f1"],
+            field_names(&fields)
+        );
+        assert_eq!("long", fields[1]["type"]);
+        // the schema is committed only when it changes
+        let commit = std::fs::read_to_string(commit_file(table, 1)).unwrap();
+        assert!(!commit.contains("metaData"));
+
+        // columns of the higher dimension are merged into the schema
+        write(table, 3, DeltaMode::Append);
+        let (_, files, fields) = read(table);
+        assert_eq!(3, files);
+        assert_eq!(
+            vec!["entity", "occur_count", "f0", "f1", "f2"],
+            field_names(&fields)
+        );
+
+        // the rows and the schema are replaced
+        write(table, 1, DeltaMode::Overwrite);
+        let (version, files, fields) = read(table);
+        assert_eq!(3, version);
+        assert_eq!(1, files);
+        assert_eq!(vec!["entity", "occur_count", "f0"], field_names(&fields));
+        let commit = std::fs::read_to_string(commit_file(table, 3)).unwrap();
+        assert_eq!(3, commit.matches("\"remove\"").count());
+        std::fs::remove_dir_all(table).unwrap();
+    }
+
+    #[test]
+    fn append_to_checkpointed_table() {
+        let table = std::env::temp_dir().join("cleora_delta_checkpoint.delta");
+        let _ = std::fs::remove_dir_all(&table);
+        let table = table.to_str().unwrap();
+
+        write(table, 2, DeltaMode::Append);
+        write(table, 2, DeltaMode::Append);
+        let delta_table = load_table(table).unwrap();
+        runtime()
+            .block_on(deltalake::checkpoints::create_checkpoint(&delta_table))
+            .unwrap();
+        // commits before the checkpoint are cleaned up by log retention
+        std::fs::remove_file(commit_file(table, 0)).unwrap();
+        std::fs::remove_file(commit_file(table, 1)).unwrap();
+
+        write(table, 3, DeltaMode::Append);
+        let (version, files, fields) = read(table);
+        assert_eq!(2, version);
+        assert_eq!(3, files);
+        assert_eq!(
+            vec!["entity", "occur_count", "f0", "f1", "f2"],
+            field_names(&fields)
+        );
+        std::fs::remove_dir_all(table).unwrap();
+    }
+
+    #[test]
+    fn map_delta_column_types() {
+        let fields = vec![
+            Field::new("entity", DataType::Utf8, false),
+            Field::new("occur_count", DataType::Int64, false),
+            Field::new("f0", DataType::Float32, false),
+        ];
+        let fields = delta_fields("table", &fields).unwrap();
+        let types: Vec<&str> = fields.iter().map(|f| f["type"].as_str().unwrap()).collect();
+        assert_eq!(vec!["string", "long", "float"], types);
+        assert_eq!(false, fields[0]["nullable"]);
+
+        // unsigned columns would be read as another type than declared
+        let unsigned = vec![Field::new("occur_count", DataType::UInt32, false)];
+        assert!(delta_fields("table", &unsigned).is_err());
+    }
+}
@@ -0,0 +1,320 @@
+use crate::error::CleoraError;
+use memmap::Mmap;
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Write};
+
+pub const DICTIONARY_MAGIC: &[u8; 8] = b"CLEORADC";
+pub const DICTIONARY_VERSION: u32 = 2;
+pub const DICTIONARY_BLOCK_ENTRIES: usize = 128;
+
+const HEADER_SIZE: usize = 32;
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Writes compact hash to entity dictionary, meant for mappings too big for TSV or parquet files.
+/// Entries are sorted by entity (in place, hashes have to be unique) and grouped into blocks of
+/// `DICTIONARY_BLOCK_ENTRIES`. Inside a block every entity is front-coded (length of the prefix
+/// shared with the previous entity and the remaining suffix), which sorted entities share most
+/// of, and the whole block is compressed with zstd. Hashes are kept in a sorted index pointing to
+/// the entries, so a lookup decompresses a single block.
+///
+/// Layout (little-endian):
+/// * header (32 bytes): magic `CLEORADC`, version u32, entries per block u32, number of entries
+///   u64, number of blocks u64
+/// * compressed blocks, every entry is: shared prefix length (LEB128), suffix length (LEB128),
+///   suffix bytes
+/// * index: hashes in ascending order (u64 per entry), then the numbers of their entries in the
+///   order of entities (u32 per entry), then offsets of the blocks relative to the end of the
+///   header (u64 per block and the end offset)
+pub fn write_dictionary<W: Write>(writer: &mut W, entries: &mut [(u64, String)]) -> io::Result<()> {
+    if entries.len() > u32::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "too many entries for a dictionary",
+        ));
+    }
+    entries.sort_unstable_by(|(_, a), (_, b)| a.cmp(b));
+    let mut hashes: Vec<(u64, u32)> = entries
+        .iter()
+        .enumerate()
+        .map(|(entry, (hash, _))| (*hash, entry as u32))
+        .collect();
+    hashes.sort_unstable();
+    let blocks: Vec<&[(u64, String)]> = entries.chunks(DICTIONARY_BLOCK_ENTRIES).collect();
+
+    writer.write_all(DICTIONARY_MAGIC)?;
+    writer.write_all(&DICTIONARY_VERSION.to_le_bytes())?;
+    writer.write_all(&(DICTIONARY_BLOCK_ENTRIES as u32).to_le_bytes())?;
+    writer.write_all(&(entries.len() as u64).to_le_bytes())?;
+    writer.write_all(&(blocks.len() as u64).to_le_bytes())?;
+
+    let mut offsets = Vec::with_capacity(blocks.len() + 1);
+    let mut offset = 0u64;
+    let mut raw = Vec::new();
+    for block in &blocks {
+        raw.clear();
+        let mut previous: &[u8] = &[];
+        for (_, entity) in block.iter() {
+            let entity = entity.as_bytes();
+            let shared = previous
+                .iter()
+                .zip(entity.iter())
+                .take_while(|(a, b)| a == b)
+                .count();
+            write_varint(&mut raw, shared as u64);
+            write_varint(&mut raw, (entity.len() - shared) as u64);
+            raw.extend_from_slice(&entity[shared..]);
+            previous = entity;
+        }
+        let compressed = zstd::stream::encode_all(raw.as_slice(), COMPRESSION_LEVEL)?;
+        writer.write_all(&compressed)?;
+        offsets.push(offset);
+        offset += compressed.len() as u64;
+    }
+    offsets.push(offset);
+
+    for (hash, _) in &hashes {
+        writer.write_all(&hash.to_le_bytes())?;
+    }
+    for (_, entry) in &hashes {
+        writer.write_all(&entry.to_le_bytes())?;
+    }
+    for offset in offsets {
+        writer.write_all(&offset.to_le_bytes())?;
+    }
+    writer.flush()
+}
+
+/// Memory-mapped dictionary written by `write_dictionary`. Only the index and looked up blocks
+/// are paged in, so opening is instant regardless of the size.
+pub struct EntityDictionary {
+    path: String,
+    mmap: Mmap,
+    entries: usize,
+    block_entries: usize,
+    blocks: usize,
+    /// Position of the hashes in the index, numbers of their entries and offsets follow them
+    index_start: usize,
+}
+
+impl EntityDictionary {
+    pub fn open(path: &str) -> Result<Self, CleoraError> {
+        let file = File::open(path).map_err(|e| CleoraError::read_file(path, e))?;
+        let mmap = unsafe { Mmap::map(&file) }.map_err(|e| CleoraError::read_file(path, e))?;
+        if mmap.len() < HEADER_SIZE || &mmap[..8] != DICTIONARY_MAGIC {
+            return Err(CleoraError::invalid_dictionary(
+                path,
+                "not a dictionary file",
+            ));
+        }
+        let version = u32::from_le_bytes(mmap[8..12].try_into().unwrap());
+        if version != DICTIONARY_VERSION {
+            return Err(CleoraError::invalid_dictionary(
+                path,
+                format!("unsupported version {}", version),
+            ));
+        }
+        let block_entries = u32::from_le_bytes(mmap[12..16].try_into().unwrap()) as usize;
+        let entries = u64::from_le_bytes(mmap[16..24].try_into().unwrap()) as usize;
+        let blocks = u64::from_le_bytes(mmap[24..32].try_into().unwrap()) as usize;
+        if block_entries == 0 {
+            return Err(CleoraError::invalid_dictionary(path, "invalid header"));
+        }
+        let index_size = entries
+            .checked_mul(12)
+            .and_then(|size| size.checked_add((blocks + 1) * 8))
+            .unwrap_or(usize::MAX);
+        if mmap.len().saturating_sub(HEADER_SIZE) < index_size {
+            return Err(CleoraError::invalid_dictionary(path, "truncated file"));
+        }
+        let index_start = mmap.len() - index_size;
+        Ok(Self {
+            path: path.to_string(),
+            mmap,
+            entries,
+            block_entries,
+            blocks,
+            index_start,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Returns entity of the hash, `None` if there is no such hash in the dictionary.
+    pub fn get(&self, hash: u64) -> Result<Option<String>, CleoraError> {
+        let (mut low, mut high) = (0, self.entries);
+        while low < high {
+            let middle = (low + high) / 2;
+            match self.read_u64(self.index_start + middle * 8).cmp(&hash) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => {
+                    let position = self.index_start + self.entries * 8 + middle * 4;
+                    let entry =
+                        u32::from_le_bytes(self.mmap[position..position + 4].try_into().unwrap())
+                            as usize;
+                    let block = self.read_block(entry / self.block_entries)?;
+                    return match block.into_iter().nth(entry % self.block_entries) {
+                        Some(entity) => Ok(Some(entity)),
+                        None => Err(CleoraError::invalid_dictionary(
+                            &self.path,
+                            "invalid entry number",
+                        )),
+                    };
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_u64(&self, position: usize) -> u64 {
+        u64::from_le_bytes(self.mmap[position..position + 8].try_into().unwrap())
+    }
+
+    /// Entities of the block, in order
+    fn read_block(&self, block: usize) -> Result<Vec<String>, CleoraError> {
+        if block >= self.blocks {
+            return Err(CleoraError::invalid_dictionary(
+                &self.path,
+                "invalid entry number",
+            ));
+        }
+        let offsets = self.index_start + self.entries * 12;
+        let start = HEADER_SIZE + self.read_u64(offsets + block * 8) as usize;
+        let end = HEADER_SIZE + self.read_u64(offsets + (block + 1) * 8) as usize;
+        if start > end || end > self.index_start {
+            return Err(CleoraError::invalid_dictionary(
+                &self.path,
+                "invalid block offsets",
+            ));
+        }
+        let raw = zstd::stream::decode_all(&self.mmap[start..end])
+            .map_err(|e| CleoraError::invalid_dictionary(&self.path, e))?;
+
+        let truncated = || CleoraError::invalid_dictionary(&self.path, "truncated block");
+        let mut entities = Vec::new();
+        let mut previous: Vec<u8> = Vec::new();
+        let mut position = 0;
+        while position < raw.len() {
+            let shared = read_varint(&raw, &mut position).ok_or_else(truncated)? as usize;
+            let suffix = read_varint(&raw, &mut position).ok_or_else(truncated)? as usize;
+            let suffix = raw.get(position..position + suffix).ok_or_else(truncated)?;
+            position += suffix.len();
+            if shared > previous.len() {
+                return Err(truncated());
+            }
+            previous.truncate(shared);
+            previous.extend_from_slice(suffix);
+            let entity = String::from_utf8(previous.clone())
+                .map_err(|e| CleoraError::invalid_dictionary(&self.path, e))?;
+            entities.push(entity);
+        }
+        Ok(entities)
+    }
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn read_varint(buffer: &[u8], position: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *buffer.get(*position)?;
+        *position += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte < 0x80 {
+            return Some(value);
+        }
+        shift += 7;
+        if shift >= 64 {
+            return None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dictionary::{write_dictionary, EntityDictionary, DICTIONARY_BLOCK_ENTRIES};
+
+    #[test]
+    fn write_and_lookup_entities() {
+        let count = DICTIONARY_BLOCK_ENTRIES as u64 * 3 + 5;
+        // hashes with gaps, so lookups between them find nothing
+        let mut entries: Vec<(u64, String)> = (0..count)
+            .rev()
+            .map(|i| (i * 10 + 5, format!("users__{}", i)))
+            .collect();
+
+        let path = std::env::temp_dir().join("cleora_dictionary_test.dict");
+        let path = path.to_str().unwrap();
+        let mut file = std::fs::File::create(path).unwrap();
+        write_dictionary(&mut file, &mut entries).unwrap();
+        drop(file);
+        assert!(entries.windows(2).all(|pair| pair[0].1 < pair[1].1));
+
+        let dictionary = EntityDictionary::open(path).unwrap();
+        assert_eq!(count as usize, dictionary.len());
+        for i in [0, 1, 127, 128, 129, 300, count - 1] {
+            assert_eq!(
+                Some(format!("users__{}", i)),
+                dictionary.get(i * 10 + 5).unwrap()
+            );
+        }
+        assert_eq!(None, dictionary.get(0).unwrap());
+        assert_eq!(None, dictionary.get(1280).unwrap());
+        assert_eq!(None, dictionary.get(u64::MAX).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn front_code_sorted_entities() {
+        // hashes in the opposite order than entities
+        let mut entries: Vec<(u64, String)> = ["items__b", "users__a", "items__a", "users__ab"]
+            .iter()
+            .enumerate()
+            .map(|(i, entity)| (u64::MAX - i as u64, entity.to_string()))
+            .collect();
+        let mut raw = Vec::new();
+        write_dictionary(&mut raw, &mut entries).unwrap();
+
+        let path = std::env::temp_dir().join("cleora_dictionary_sorted.dict");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, raw).unwrap();
+        let dictionary = EntityDictionary::open(path).unwrap();
+        assert_eq!(
+            vec!["items__a", "items__b", "users__a", "users__ab"],
+            dictionary.read_block(0).unwrap()
+        );
+        assert_eq!(
+            Some("users__ab".to_string()),
+            dictionary.get(u64::MAX - 3).unwrap()
+        );
+        assert_eq!(
+            Some("items__b".to_string()),
+            dictionary.get(u64::MAX).unwrap()
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reject_other_files() {
+        let path = std::env::temp_dir().join("cleora_dictionary_invalid.dict");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "hash\tentity\n1\tusers__a\n12345678901234567890").unwrap();
+        assert!(EntityDictionary::open(path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+use crate::cardinality::{
+    embedding_bytes_per_entity, CardinalityMonitor, SPARSE_MATRIX_BYTES_PER_ENTITY,
+    SPARSE_MATRIX_BYTES_PER_ENTRY,
+};
+use crate::configuration::{Configuration, SelfLoops};
+use crate::error::CleoraError;
+use crate::persistence::entity::CountingEntityMappingPersistor;
+use crate::pipeline::read_inputs;
+use crate::sketch::{mix, HyperLogLog};
+use crate::sparse_matrix::configured_sparse_matrices;
+use std::fmt;
+use std::sync::Arc;
+
+/// Precision of the edge sketches, 16 KiB each with ~0.8% standard error
+const SKETCH_PRECISION: u8 = 14;
+
+/// Number of entities the mean length of entity names is computed from
+const ENTITY_SAMPLE_SIZE: usize = 10_000;
+
+/// Statistics of the graph collected by a single parsing pass, without building sparse matrices.
+/// Counts of unique entities and entries are HyperLogLog estimates made before pruning
+/// (`--min-entity-count`, `--max-entities`) and cancelling of negatively weighted relations.
+#[derive(Debug)]
+pub struct GraphReport {
+    /// Number of input rows (hyperedges)
+    pub rows: u64,
+    /// Estimated unique entities per column
+    pub columns: Vec<(String, u64)>,
+    pub matrices: Vec<MatrixReport>,
+    /// Projected memory of sparse matrices and embeddings trained in parallel, entity mapping
+    /// isn't included
+    pub projected_bytes: u64,
+    /// Mean length of entity names (sampled), `None` if there are no names (identity hashes)
+    pub mean_entity_bytes: Option<f64>,
+}
+
+#[derive(Debug)]
+pub struct MatrixReport {
+    pub col_a_name: String,
+    pub col_b_name: String,
+    /// Estimated unique entities
+    pub entities: u64,
+    /// Number of expanded edges (combinations) read from the rows
+    pub edges: u64,
+    /// Estimated non-zero entries of the sparse matrix
+    pub entries: u64,
+}
+
+/// Counts expanded edges of a sparse matrix and estimates its unique entries
+struct EdgeCounter {
+    col_a: usize,
+    col_b: usize,
+    edges: u64,
+    pairs: HyperLogLog,
+    self_loops: HyperLogLog,
+}
+
+impl EdgeCounter {
+    fn add(&mut self, hashes: &[u64]) {
+        let a = hashes[self.col_a + 1];
+        let b = hashes[self.col_b + 1];
+        self.edges += 1;
+        if a == b {
+            self.self_loops.add_hash(a);
+        } else {
+            // the same key for both directions, entries are symmetric
+            self.pairs.add_hash(mix(a.min(b) ^ mix(a.max(b))));
+        }
+    }
+
+    /// Estimated entries of the matrix: both directions of every pair and the diagonal
+    fn entries(&self, self_loops: SelfLoops, entities: u64) -> u64 {
+        let pairs = 2 * self.pairs.estimate().round() as u64;
+        match self_loops {
+            SelfLoops::Keep => pairs + self.self_loops.estimate().round() as u64,
+            SelfLoops::Drop => pairs,
+            SelfLoops::Add(_) => pairs + entities,
+        }
+    }
+}
+
+/// Reads the input once (parsing and entity counting only) and estimates the size of the graph
+/// and the memory needed to train it, so machines can be sized before the full run. Entities
+/// aren't kept, only a sample of their names is measured.
+pub fn dry_run(config: &Configuration) -> Result<GraphReport, CleoraError> {
+    let persistor = Arc::new(CountingEntityMappingPersistor::new(ENTITY_SAMPLE_SIZE));
+    let mut monitor = CardinalityMonitor::with_budget(config, u64::MAX);
+    let mut counters: Vec<EdgeCounter> = configured_sparse_matrices(config)
+        .iter()
+        .map(|m| EdgeCounter {
+            col_a: m.col_a_id as usize,
+            col_b: m.col_b_id as usize,
+            edges: 0,
+            pairs: HyperLogLog::new(SKETCH_PRECISION),
+            self_loops: HyperLogLog::new(SKETCH_PRECISION),
+        })
+        .collect();
+
+    read_inputs(config, persistor.clone(), Some(&mut monitor), |hashes| {
+        for counter in counters.iter_mut() {
+            counter.add(&hashes);
+        }
+    })?;
+
+    let columns = config
+        .columns
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| !c.ignored)
+        .map(|(i, c)| (c.name.clone(), monitor.estimated_entities(i)))
+        .collect();
+
+    let bytes_per_entity = SPARSE_MATRIX_BYTES_PER_ENTITY + embedding_bytes_per_entity(config);
+    let mut projected_bytes = 0;
+    let matrices = configured_sparse_matrices(config)
+        .into_iter()
+        .zip(counters)
+        .zip(monitor.estimated_matrix_entities())
+        .map(|((matrix, counter), entities)| {
+            let entries = counter.entries(config.self_loops, entities);
+            projected_bytes +=
+                entities * bytes_per_entity + entries * SPARSE_MATRIX_BYTES_PER_ENTRY;
+            MatrixReport {
+                col_a_name: matrix.col_a_name,
+                col_b_name: matrix.col_b_name,
+                entities,
+                edges: counter.edges,
+                entries,
+            }
+        })
+        .collect();
+
+    let mean_entity_bytes = persistor.mean_entity_bytes();
+
+    Ok(GraphReport {
+        rows: monitor.rows(),
+        columns,
+        matrices,
+        projected_bytes,
+        mean_entity_bytes,
+    })
+}
+
+impl fmt::Display for GraphReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Hyperedges (rows): {}", self.rows)?;
+        writeln!(f, "Estimated unique entities per column:")?;
+        for (name, entities) in &self.columns {
+            writeln!(f, "  {}: {}", name, entities)?;
+        }
+        writeln!(f, "Sparse matrices:")?;
+        for matrix in &self.matrices {
+            writeln!(
+                f,
+                "  {} - {}: entities {}, edges {}, estimated entries {}",
+                matrix.col_a_name, matrix.col_b_name, matrix.entities, matrix.edges, matrix.entries
+            )?;
+        }
+        write!(
+            f,
+            "Projected memory: {} bytes ({:.2} GiB), entity mapping not included",
+            self.projected_bytes,
+            self.projected_bytes as f64 / (1u64 << 30) as f64
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::{extract_fields, Configuration, SelfLoops};
+    use crate::dry_run::dry_run;
+
+    #[test]
+    fn report_graph_statistics() {
+        let path = std::env::temp_dir().join("cleora_dry_run.tsv");
+        std::fs::write(&path, "u1\ti1 i2\nu2\ti1\nu1\ti1 i2\n").unwrap();
+        let columns = extract_fields(vec!["users", "complex::items"]).unwrap();
+        let mut config = Configuration::default(path.to_str().unwrap().to_string(), columns);
+        config.embeddings_dimension = 4;
+        config.self_loops = SelfLoops::Keep;
+
+        let report = dry_run(&config).unwrap();
+        assert_eq!(3, report.rows);
+        assert_eq!(
+            vec![(String::from("users"), 2), (String::from("items"), 2)],
+            report.columns
+        );
+        assert_eq!(1, report.matrices.len());
+        let matrix = &report.matrices[0];
+        assert_eq!(4, matrix.entities);
+        assert_eq!(5, matrix.edges);
+        // pairs u1-i1, u1-i2, u2-i1 in both directions
+        assert_eq!(6, matrix.entries);
+        assert_eq!(4 * (48 + 2 * 4 * 4) + 6 * 24, report.projected_bytes);
+        // users__u1, users__u2, items__i1, items__i2
+        assert_eq!(Some(9.0), report.mean_entity_bytes);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+use crate::configuration::DuckDbLayout;
+use crate::error::CleoraError;
+use crate::io::{commit_file, partial_path};
+use crate::persistence::embedding::{EmbeddingBatch, EmbeddingPersistor};
+use duckdb::{params_from_iter, Connection, ToSql};
+use std::fs;
+
+/// Table of the embeddings in the database
+pub const DUCKDB_TABLE: &str = "embeddings";
+
+/// Rows of the array layout are appended as vector columns, the lists are built when finished
+const DUCKDB_STAGING_TABLE: &str = "embeddings_columns";
+
+/// Writes embeddings into the `embeddings` table of a DuckDB database file, so they can be
+/// queried with SQL right after the run. The table has `entity` and optional `occur_count`
+/// columns, vectors are `f0`..`fN` float columns or a single `embedding` column of `FLOAT[]`
+/// lists (see `DuckDbLayout`). Rows of a chunk are appended at once. The database is written under
+/// its temporary name (staged locally for S3 outputs) and committed when it's checkpointed.
+pub struct DuckDbPersistor {
+    filename: String,
+    connection: Option<Connection>,
+    layout: DuckDbLayout,
+    produce_entity_occurrence_count: bool,
+    dimension: usize,
+}
+
+impl DuckDbPersistor {
+    pub fn new(
+        filename: String,
+        produce_entity_occurrence_count: bool,
+        layout: DuckDbLayout,
+    ) -> Result<Self, CleoraError> {
+        let path = partial_path(&filename);
+        // leftovers of a failed run would be opened as the database
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(format!("{}.wal", path));
+        let connection = Connection::open(&path).map_err(|e| CleoraError::duckdb(&filename, e))?;
+        Ok(Self {
+            filename,
+            connection: Some(connection),
+            layout,
+            produce_entity_occurrence_count,
+            dimension: 0,
+        })
+    }
+
+    fn connection(&self) -> &Connection {
+        self.connection
+            .as_ref()
+            .expect("Should be defined. Was finish called already?")
+    }
+
+    /// Table the rows are appended to
+    fn append_table(&self) -> &'static str {
+        match self.layout {
+            DuckDbLayout::Columns => DUCKDB_TABLE,
+            DuckDbLayout::Array => DUCKDB_STAGING_TABLE,
+        }
+    }
+
+    fn execute(&self, sql: &str) -> Result<(), CleoraError> {
+        self.connection()
+            .execute_batch(sql)
+            .map_err(|e| CleoraError::duckdb(&self.filename, e))
+    }
+
+    /// Builds the table of the array layout from the appended vector columns
+    fn create_array_table(&self) -> Result<(), CleoraError> {
+        let occur_count = if self.produce_entity_occurrence_count {
+            "occur_count, "
+        } else {
+            ""
+        };
+        let values: Vec<String> = (0..self.dimension).map(|i| format!("f{}", i)).collect();
+        self.execute(&format!(
+            "CREATE TABLE {} AS SELECT entity, {}[{}]::FLOAT[] AS embedding FROM {}; DROP TABLE {};",
+            DUCKDB_TABLE,
+            occur_count,
+            values.join(", "),
+            DUCKDB_STAGING_TABLE,
+            DUCKDB_STAGING_TABLE
+        ))
+    }
+}
+
+impl EmbeddingPersistor for DuckDbPersistor {
+    fn put_metadata(&mut self, _entity_count: u32, dimension: u16) -> Result<(), CleoraError> {
+        self.dimension = dimension as usize;
+        let mut columns = vec![String::from("entity VARCHAR NOT NULL")];
+        if self.produce_entity_occurrence_count {
+            columns.push(String::from("occur_count UINTEGER NOT NULL"));
+        }
+        columns.extend((0..self.dimension).map(|i| format!("f{} FLOAT NOT NULL", i)));
+        self.execute(&format!(
+            "CREATE TABLE {} ({});",
+            self.append_table(),
+            columns.join(", ")
+        ))
+    }
+
+    fn put_data(
+        &mut self,
+        entity: &str,
+        occur_count: u32,
+        vector: Vec<f32>,
+    ) -> Result<(), CleoraError> {
+        let dimension = vector.len();
+        self.put_data_batch(EmbeddingBatch::new(
+            &[entity.into()],
+            &[occur_count],
+            &vector,
+            dimension,
+        ))
+    }
+
+    fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+        let mut appender = self
+            .connection()
+            .appender(self.append_table())
+            .map_err(|e| CleoraError::duckdb(&self.filename, e))?;
+        for (entity, occur_count, vector) in chunk.rows() {
+            let mut row: Vec<&dyn ToSql> = Vec::with_capacity(vector.len() + 2);
+            row.push(&entity);
+            if self.produce_entity_occurrence_count {
+                row.push(&occur_count);
+            }
+            row.extend(vector.iter().map(|v| v as &dyn ToSql));
+            appender
+                .append_row(params_from_iter(row))
+                .map_err(|e| CleoraError::duckdb(&self.filename, e))?;
+        }
+        appender
+            .flush()
+            .map_err(|e| CleoraError::duckdb(&self.filename, e))
+    }
+
+    fn finish(&mut self) -> Result<(), CleoraError> {
+        if self.layout == DuckDbLayout::Array {
+            self.create_array_table()?;
+        }
+        // the write-ahead log is merged into the database file, so it's the only file to commit
+        self.execute("CHECKPOINT;")?;
+        if let Some(connection) = self.connection.take() {
+            connection
+                .close()
+                .map_err(|(_, e)| CleoraError::duckdb(&self.filename, e))?;
+        }
+        commit_file(&self.filename)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::DuckDbLayout;
+    use crate::duckdb_output::DuckDbPersistor;
+    use crate::persistence::embedding::{EmbeddingBatch, EmbeddingPersistor};
+    use duckdb::Connection;
+
+    fn write(filename: &str, layout: DuckDbLayout) -> Connection {
+        let _ = std::fs::remove_file(filename);
+        let mut persistor = DuckDbPersistor::new(filename.to_string(), true, layout).unwrap();
+        persistor.put_metadata(3, 2).unwrap();
+        persistor.put_data("a", 3, vec![1.0, 0.0]).unwrap();
+        persistor
+            .put_data_batch(EmbeddingBatch::new(
+                &["b".into(), "c".into()],
+                &[2, 1],
+                &[0.0, 1.0, 0.5, 0.5],
+                2,
+            ))
+            .unwrap();
+        persistor.finish().unwrap();
+        Connection::open(filename).unwrap()
+    }
+
+    #[test]
+    fn write_duckdb_tables() {
+        let filename = std::env::temp_dir().join("cleora_duckdb_columns.duckdb");
+        let filename = filename.to_str().unwrap();
+        let connection = write(filename, DuckDbLayout::Columns);
+        let (occur_count, f1): (u32, f32) = connection
+            .query_row(
+                "SELECT occur_count, f1 FROM embeddings WHERE entity = 'b'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((2, 1.0), (occur_count, f1));
+        drop(connection);
+        std::fs::remove_file(filename).unwrap();
+
+        let filename = std::env::temp_dir().join("cleora_duckdb_array.duckdb");
+        let filename = filename.to_str().unwrap();
+        let connection = write(filename, DuckDbLayout::Array);
+        let (rows, length): (i64, i64) = connection
+            .query_row(
+                "SELECT count(*), max(len(embedding)) FROM embeddings",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((3, 2), (rows, length));
+        drop(connection);
+        std::fs::remove_file(filename).unwrap();
+    }
+}
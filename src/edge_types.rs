@@ -0,0 +1,247 @@
+use crate::configuration::{EdgeTypeCombination, SortOutput};
+use crate::error::CleoraError;
+use crate::persistence::embedding::{EmbeddingBatch, EmbeddingPersistor};
+use rustc_hash::FxHashMap;
+use std::sync::Arc;
+
+/// Embeddings kept in memory instead of being written, so embeddings trained for the edge types
+/// can be combined. Values are row-major, row `i` is the embedding of `entities[i]`.
+#[derive(Debug, Default)]
+pub struct EmbeddingCollector {
+    pub entities: Vec<Arc<str>>,
+    pub occur_counts: Vec<u32>,
+    pub values: Vec<f32>,
+    pub dimension: usize,
+}
+
+impl EmbeddingCollector {
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    fn vector(&self, row: usize) -> &[f32] {
+        &self.values[row * self.dimension..(row + 1) * self.dimension]
+    }
+
+    /// Sorts the rows by entity or occurrence count, see `SortOutput`. Clustered rows of the
+    /// edge types are kept in the order they're combined in.
+    pub fn sort(&mut self, sort_output: SortOutput) {
+        let mut order: Vec<usize> = (0..self.len()).collect();
+        let by_entity =
+            |&a: &usize, &b: &usize| self.entities[a].as_bytes().cmp(self.entities[b].as_bytes());
+        match sort_output {
+            SortOutput::Cluster { .. } => return,
+            SortOutput::Entity => order.sort_by(by_entity),
+            SortOutput::Occurrence => order.sort_by(|a, b| {
+                self.occur_counts[*b]
+                    .cmp(&self.occur_counts[*a])
+                    .then_with(|| by_entity(a, b))
+            }),
+        }
+        let mut values = Vec::with_capacity(self.values.len());
+        for &row in order.iter() {
+            values.extend_from_slice(self.vector(row));
+        }
+        self.values = values;
+        self.entities = order
+            .iter()
+            .map(|&row| self.entities[row].clone())
+            .collect();
+        self.occur_counts = order.iter().map(|&row| self.occur_counts[row]).collect();
+    }
+}
+
+impl EmbeddingPersistor for EmbeddingCollector {
+    fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), CleoraError> {
+        // the count includes transient entities, which aren't written
+        self.dimension = dimension as usize;
+        self.entities.reserve(entity_count as usize);
+        self.occur_counts.reserve(entity_count as usize);
+        Ok(())
+    }
+
+    fn put_data(
+        &mut self,
+        entity: &str,
+        occur_count: u32,
+        vector: Vec<f32>,
+    ) -> Result<(), CleoraError> {
+        self.entities.push(entity.into());
+        self.occur_counts.push(occur_count);
+        self.values.extend(vector);
+        Ok(())
+    }
+
+    fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+        self.values.extend(chunk.vectors.iter());
+        self.entities.extend_from_slice(chunk.entities);
+        self.occur_counts.extend_from_slice(chunk.occur_counts);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), CleoraError> {
+        Ok(())
+    }
+}
+
+/// Combines embeddings of the edge types (in the order of the labels) of one column pair into
+/// embeddings of every entity found in any of them. Entities are in the order they are first
+/// found, their occurrence counts are summed.
+pub fn combine(
+    embeddings: Vec<EmbeddingCollector>,
+    combination: EdgeTypeCombination,
+    dimension: usize,
+) -> EmbeddingCollector {
+    let mut positions: FxHashMap<&str, usize> = FxHashMap::default();
+    let mut entities = Vec::new();
+    let mut occur_counts: Vec<u32> = Vec::new();
+    for edge_type in embeddings.iter() {
+        for (entity, &occur_count) in edge_type.entities.iter().zip(&edge_type.occur_counts) {
+            match positions.get(&**entity) {
+                Some(&row) => occur_counts[row] = occur_counts[row].saturating_add(occur_count),
+                None => {
+                    positions.insert(entity, entities.len());
+                    entities.push(Arc::clone(entity));
+                    occur_counts.push(occur_count);
+                }
+            }
+        }
+    }
+
+    let (values, dimension) = match combination {
+        EdgeTypeCombination::Concat => {
+            let combined_dimension = dimension * embeddings.len();
+            let mut values = vec![0f32; entities.len() * combined_dimension];
+            for (i, edge_type) in embeddings.iter().enumerate() {
+                for (row, entity) in edge_type.entities.iter().enumerate() {
+                    let start = positions[&**entity] * combined_dimension + i * dimension;
+                    values[start..start + dimension].copy_from_slice(edge_type.vector(row));
+                }
+            }
+            (values, combined_dimension)
+        }
+        EdgeTypeCombination::Merge => {
+            let mut values = vec![0f32; entities.len() * dimension];
+            let mut edge_types = vec![0u32; entities.len()];
+            for edge_type in embeddings.iter() {
+                for (row, entity) in edge_type.entities.iter().enumerate() {
+                    let position = positions[&**entity];
+                    edge_types[position] += 1;
+                    let combined = &mut values[position * dimension..(position + 1) * dimension];
+                    for (value, v) in combined.iter_mut().zip(edge_type.vector(row)) {
+                        *value += v;
+                    }
+                }
+            }
+            for (vector, &count) in values.chunks_exact_mut(dimension).zip(&edge_types) {
+                for value in vector.iter_mut() {
+                    *value /= count as f32;
+                }
+            }
+            (values, dimension)
+        }
+    };
+
+    EmbeddingCollector {
+        entities,
+        occur_counts,
+        values,
+        dimension,
+    }
+}
+
+/// Writes the embeddings to the persistor in chunks of `chunk_size` rows
+pub fn write(
+    embeddings: EmbeddingCollector,
+    persistor: &mut dyn EmbeddingPersistor,
+    chunk_size: usize,
+) -> Result<(), CleoraError> {
+    let dimension = embeddings.dimension;
+    persistor.put_metadata(embeddings.len() as u32, dimension as u16)?;
+    let chunk_size = chunk_size.max(1);
+    for start in (0..embeddings.len()).step_by(chunk_size) {
+        let end = (start + chunk_size).min(embeddings.len());
+        persistor.put_data_batch(EmbeddingBatch::new(
+            &embeddings.entities[start..end],
+            &embeddings.occur_counts[start..end],
+            &embeddings.values[start * dimension..end * dimension],
+            dimension,
+        ))?;
+    }
+    persistor.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::{EdgeTypeCombination, SortOutput};
+    use crate::edge_types::{combine, write, EmbeddingCollector};
+    use crate::persistence::embedding::EmbeddingPersistor;
+    use std::sync::Arc;
+
+    fn names(entities: &[Arc<str>]) -> Vec<&str> {
+        entities.iter().map(|entity| &**entity).collect()
+    }
+
+    fn collect(entities: &[&str], values: Vec<f32>) -> EmbeddingCollector {
+        let mut collector = EmbeddingCollector::default();
+        collector.put_metadata(entities.len() as u32, 2).unwrap();
+        for (entity, vector) in entities.iter().zip(values.chunks(2)) {
+            collector.put_data(entity, 1, vector.to_vec()).unwrap();
+        }
+        collector
+    }
+
+    #[test]
+    fn combine_edge_types() {
+        let edge_types = || {
+            vec![
+                collect(&["a", "b"], vec![1.0, 0.0, 0.0, 1.0]),
+                collect(&["c", "a"], vec![0.5, 0.5, 0.0, -1.0]),
+            ]
+        };
+
+        let concat = combine(edge_types(), EdgeTypeCombination::Concat, 2);
+        assert_eq!(vec!["a", "b", "c"], names(&concat.entities));
+        assert_eq!(vec![2, 1, 1], concat.occur_counts);
+        assert_eq!(4, concat.dimension);
+        assert_eq!(
+            vec![1.0, 0.0, 0.0, -1.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.5, 0.5],
+            concat.values
+        );
+
+        let merge = combine(edge_types(), EdgeTypeCombination::Merge, 2);
+        assert_eq!(vec!["a", "b", "c"], names(&merge.entities));
+        assert_eq!(2, merge.dimension);
+        assert_eq!(vec![0.5, -0.5, 0.0, 1.0, 0.5, 0.5], merge.values);
+
+        // written in chunks
+        let mut written = EmbeddingCollector::default();
+        write(merge, &mut written, 2).unwrap();
+        assert_eq!(vec!["a", "b", "c"], names(&written.entities));
+        assert_eq!(vec![0.5, -0.5, 0.0, 1.0, 0.5, 0.5], written.values);
+    }
+
+    #[test]
+    fn sort_combined_embeddings() {
+        let mut embeddings = combine(
+            vec![
+                collect(&["c", "a"], vec![1.0, 0.0, 0.0, 1.0]),
+                collect(&["b", "c"], vec![0.5, 0.5, 0.0, -1.0]),
+            ],
+            EdgeTypeCombination::Merge,
+            2,
+        );
+        embeddings.sort(SortOutput::Entity);
+        assert_eq!(vec!["a", "b", "c"], names(&embeddings.entities));
+        assert_eq!(vec![1, 1, 2], embeddings.occur_counts);
+        assert_eq!(vec![0.0, 1.0, 0.5, 0.5, 0.5, -0.5], embeddings.values);
+
+        embeddings.sort(SortOutput::Occurrence);
+        assert_eq!(vec!["c", "a", "b"], names(&embeddings.entities));
+        assert_eq!(vec![0.5, -0.5, 0.0, 1.0, 0.5, 0.5], embeddings.values);
+    }
+}
@@ -1,71 +1,387 @@
-use crate::configuration::Configuration;
-use crate::persistence::embedding::EmbeddingPersistor;
+use crate::alignment::{orthogonal_procrustes, residuals};
+use crate::clustering::KMeans;
+use crate::configuration::{
+    Configuration, InitMethod, Normalization, PostprocessStep, Precision, PropagationPartitioning,
+    SortOutput, StatsScope,
+};
+use crate::error::CleoraError;
+use crate::interrupt;
+use crate::loader::Embeddings;
+#[cfg(feature = "fs")]
+use crate::persistence::embedding::NPY_HEADER_LEN;
+use crate::persistence::embedding::{ColumnMajorFile, EmbeddingBatch, EmbeddingPersistor};
 use crate::persistence::entity::EntityMappingPersistor;
-use crate::sparse_matrix::SparseMatrixReader;
+use crate::profile;
+use crate::sketch::{mix, Histogram, QuantileSketch};
+use crate::sparse_matrix::{connected_components, Entry, SparseMatrixReader};
 use log::{info, warn};
-use memmap::MmapMut;
+#[cfg(feature = "fs")]
+use memmap::{MmapMut, MmapOptions};
+use ndarray::{s, Array2};
 use rayon::prelude::*;
+use rustc_hash::FxHashSet;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+#[cfg(feature = "fs")]
 use std::fs;
+#[cfg(feature = "fs")]
 use std::fs::OpenOptions;
 use std::hash::Hasher;
 use std::marker::PhantomData;
+use std::ops::{Add, AddAssign, DivAssign, Mul, Range, Sub};
+#[cfg(feature = "fs")]
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
+#[cfg(not(feature = "wasm"))]
+use std::time::Instant;
+#[cfg(feature = "fs")]
 use uuid::Uuid;
-
-/// Number of broken entities (those with errors during writing to the file) which are logged.
-/// There can be much more but log the first few.
-const LOGGED_NUMBER_OF_BROKEN_ENTITIES: usize = 20;
+#[cfg(feature = "wasm")]
+use web_time::Instant;
 
 /// Used during matrix initialization. No specific requirement (ca be lower as well).
 const MAX_HASH_I64: i64 = 8 * 1024 * 1024;
 const MAX_HASH_F32: f32 = MAX_HASH_I64 as f32;
 
+/// Number of rows k-means is fitted on when output is sorted by cluster
+const CLUSTER_SAMPLE_SIZE: usize = 50_000;
+const CLUSTER_ITERATIONS: usize = 10;
+
+/// Number of linked (and unlinked) pairs sampled for the cosine similarity histogram
+const SIMILARITY_SAMPLE_SIZE: usize = 10_000;
+const SIMILARITY_HISTOGRAM_BINS: usize = 10;
+/// Minimal number of unlinked pairs to warn about embeddings without structure
+const SIMILARITY_MIN_PAIRS: u64 = 100;
+
+/// Number of rows whose changes between iterations are measured
+const CHANGE_SAMPLE_SIZE: usize = 10_000;
+
+/// Dimension blocks are limited, so columns written for every entry stay in the cache
+const MAX_DIMENSION_BLOCK_SIZE: usize = 16;
+/// Entity blocks per thread, more blocks than threads balance the work better
+const ENTITY_BLOCKS_PER_THREAD: usize = 4;
+
+/// Embeddings read before the training, which the calculated embeddings start from or are aligned
+/// to. None of them is required.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PriorEmbeddings<'a> {
+    /// Feature vectors of the entities replacing their initial vectors (`--feature-init`)
+    pub features: Option<&'a Embeddings>,
+    /// Embeddings of the previous run the propagation continues from (`--continue-from`)
+    pub previous: Option<&'a Embeddings>,
+    /// Embeddings the calculated ones are rotated onto (`--align-to`)
+    pub reference: Option<&'a Embeddings>,
+}
+
+/// Creates persistor of the intermediate embeddings after given iteration (counted from 1)
+pub type SnapshotPersistorFactory<'a> =
+    dyn Fn(u8) -> Result<Box<dyn EmbeddingPersistor>, CleoraError> + 'a;
+
+/// Type of the matrix values the embeddings are propagated in, see `Precision`
+trait Float:
+    Copy
+    + Debug
+    + Default
+    + PartialOrd
+    + Send
+    + Sync
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + AddAssign
+    + DivAssign
+    + 'static
+{
+    fn from_f32(value: f32) -> Self;
+    fn to_f32(self) -> f32;
+    fn sqrt(self) -> Self;
+}
+
+impl Float for f32 {
+    #[inline]
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+
+    #[inline]
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    #[inline]
+    fn sqrt(self) -> Self {
+        f32::sqrt(self)
+    }
+}
+
+impl Float for f64 {
+    #[inline]
+    fn from_f32(value: f32) -> Self {
+        value as f64
+    }
+
+    #[inline]
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    #[inline]
+    fn sqrt(self) -> Self {
+        f64::sqrt(self)
+    }
+}
+
 /// Wrapper for different types of matrix structures such as 2-dim vectors or memory-mapped files
 trait MatrixWrapper {
+    type Value: Float;
+
     /// Initializing a matrix with values from its dimensions and the hash values from the sparse matrix
     fn init_with_hashes<T: SparseMatrixReader + Sync + Send>(
         rows: usize,
         cols: usize,
         fixed_random_value: i64,
+        init_method: InitMethod,
+        sparse_matrix_reader: Arc<T>,
+    ) -> Self;
+
+    /// Matrix of zeros of the given dimensions
+    fn zeros<T: SparseMatrixReader + Sync + Send>(
+        rows: usize,
+        cols: usize,
         sparse_matrix_reader: Arc<T>,
     ) -> Self;
 
     /// Returns value for specific coordinates
-    fn get_value(&self, row: usize, col: usize) -> f32;
+    fn get_value(&self, row: usize, col: usize) -> Self::Value;
 
     /// Normalizing a matrix by rows sum
     fn normalize(&mut self);
 
-    /// Multiplies sparse matrix by the matrix
+    /// Updates the matrix column by column (every column is one dimension of all embeddings)
+    fn update_columns<F>(&mut self, func: F)
+    where
+        F: Fn(usize, &mut [Self::Value]) + Sync + Send;
+
+    /// Mixes the other matrix in: `(1 - alpha) * self + alpha * other`. Rows marked in
+    /// `frozen_rows` are left as they are.
+    fn mix_in(&mut self, other: &InputMatrix<Self>, alpha: f32, frozen_rows: Option<&[bool]>)
+    where
+        Self: Sync + Sized,
+    {
+        let alpha = Self::Value::from_f32(alpha);
+        let one = Self::Value::from_f32(1f32);
+        self.update_columns(|i, column| {
+            for (row, value) in column.iter_mut().enumerate() {
+                if matches!(frozen_rows, Some(frozen) if frozen[row]) {
+                    continue;
+                }
+                *value = (one - alpha) * *value + alpha * other.get_value(row, i);
+            }
+        });
+    }
+
+    /// Multiplies sparse matrix by the matrix. Rows marked in `frozen_rows` are copied from the
+    /// matrix instead. The work is split between threads according to `partitioning`.
     fn multiply<T: SparseMatrixReader + Sync + Send>(
         sparse_matrix_reader: Arc<T>,
-        other: Self,
+        other: &Self,
+        frozen_rows: Option<&[bool]>,
+        partitioning: &Partitioning,
     ) -> Self;
+
+    /// Multiplies sparse matrix by the non-zero values of sparse random projection initialization.
+    /// Every output value sums its entries in the same order as `multiply`.
+    fn multiply_sparse<T: SparseMatrixReader + Sync + Send>(
+        sparse_matrix_reader: Arc<T>,
+        other: &SparseInit,
+        frozen_rows: Option<&[bool]>,
+    ) -> Self
+    where
+        Self: Sized,
+    {
+        let mut res = Self::zeros(other.rows, other.cols, sparse_matrix_reader.clone());
+        res.update_columns(|i, column| {
+            for entry in sparse_matrix_reader.iter_entries() {
+                if matches!(frozen_rows, Some(frozen) if frozen[entry.row as usize]) {
+                    continue;
+                }
+                let value = other.get_value(entry.col as usize, i);
+                if value != 0f32 {
+                    column[entry.row as usize] +=
+                        Self::Value::from_f32(value) * Self::Value::from_f32(entry.value);
+                }
+            }
+            if let Some(frozen_rows) = frozen_rows {
+                for (row, _) in frozen_rows.iter().enumerate().filter(|(_, &f)| f) {
+                    column[row] = Self::Value::from_f32(other.get_value(row, i));
+                }
+            }
+        });
+        res
+    }
+
+    /// File the matrix is computed in, `None` if it's kept in memory
+    fn file(&self) -> Option<ColumnMajorFile> {
+        None
+    }
+}
+
+/// Matrix of the same structure with f32 values, the propagated matrix is converted to it before
+/// postprocessing and writing
+trait IntoOutput: MatrixWrapper {
+    type Output: MatrixWrapper<Value = f32>;
+
+    fn into_output(self) -> Self::Output;
+}
+
+/// Split of the multiplication between parallel tasks. Every output value sums its entries in
+/// the same order, so all of them give the same results.
+#[derive(Debug)]
+enum Partitioning {
+    /// Every task owns a contiguous block of dimensions (of the given size) of all entities, the
+    /// entries are read once per block
+    DimensionBlocks(usize),
+    /// Every task owns a contiguous range of entities with all their dimensions
+    EntityBlocks(EntityBlocks),
+}
+
+impl Partitioning {
+    fn new<T: SparseMatrixReader>(
+        partitioning: PropagationPartitioning,
+        dimension: usize,
+        sparse_matrix_reader: &T,
+    ) -> Self {
+        let threads = rayon::current_num_threads();
+        let partitioning = match partitioning {
+            PropagationPartitioning::Auto if dimension >= threads => {
+                PropagationPartitioning::Dimensions
+            }
+            PropagationPartitioning::Auto => PropagationPartitioning::Entities,
+            partitioning => partitioning,
+        };
+        match partitioning {
+            PropagationPartitioning::Entities => {
+                let blocks =
+                    EntityBlocks::new(sparse_matrix_reader, threads * ENTITY_BLOCKS_PER_THREAD);
+                info!(
+                    "Propagation partitioned into {} entity blocks.",
+                    blocks.blocks.len()
+                );
+                Partitioning::EntityBlocks(blocks)
+            }
+            _ => {
+                let block_size = dimension
+                    .div_ceil(threads)
+                    .clamp(1, MAX_DIMENSION_BLOCK_SIZE);
+                info!(
+                    "Propagation partitioned into blocks of {} dimensions.",
+                    block_size
+                );
+                Partitioning::DimensionBlocks(block_size)
+            }
+        }
+    }
+}
+
+/// Entries sorted by row (keeping their order within rows), split into blocks of rows with
+/// similar number of entries
+#[derive(Debug)]
+struct EntityBlocks {
+    entries: Vec<Entry>,
+    /// Row range and entry range of every block, the row ranges cover all entities
+    blocks: Vec<(Range<usize>, Range<usize>)>,
+}
+
+impl EntityBlocks {
+    fn new<T: SparseMatrixReader>(sparse_matrix_reader: &T, max_blocks: usize) -> Self {
+        let rows = sparse_matrix_reader.get_number_of_entities() as usize;
+        let mut entries: Vec<Entry> = sparse_matrix_reader.iter_entries().collect();
+        entries.sort_by_key(|entry| entry.row);
+
+        let block_entries = entries.len().div_ceil(max_blocks).max(1);
+        let mut blocks = Vec::new();
+        let (mut row_start, mut entry_start) = (0, 0);
+        while entry_start < entries.len() {
+            // blocks end with the last entry of a row
+            let mut entry_end = (entry_start + block_entries).min(entries.len());
+            while entry_end < entries.len() && entries[entry_end].row == entries[entry_end - 1].row
+            {
+                entry_end += 1;
+            }
+            let row_end = match entries.get(entry_end) {
+                Some(entry) => entry.row as usize,
+                None => rows,
+            };
+            blocks.push((row_start..row_end, entry_start..entry_end));
+            row_start = row_end;
+            entry_start = entry_end;
+        }
+        if blocks.is_empty() {
+            blocks.push((0..rows, 0..0));
+        }
+        Self { entries, blocks }
+    }
+
+    /// Multiplies the blocks in parallel, every block writes its rows of all output columns
+    fn multiply<M: MatrixWrapper + Sync>(
+        &self,
+        input: &M,
+        output: &OutputColumns<M::Value>,
+        frozen_rows: Option<&[bool]>,
+    ) {
+        self.blocks.par_iter().for_each(|(rows, entries)| {
+            let entries = &self.entries[entries.clone()];
+            for (i, &column) in output.0.iter().enumerate() {
+                // blocks own disjoint rows, so their writes don't overlap
+                for entry in entries {
+                    if matches!(frozen_rows, Some(frozen) if frozen[entry.row as usize]) {
+                        continue;
+                    }
+                    let input_value = input.get_value(entry.col as usize, i);
+                    let value = input_value * M::Value::from_f32(entry.value);
+                    unsafe { *column.add(entry.row as usize) += value };
+                }
+                if let Some(frozen_rows) = frozen_rows {
+                    for row in rows.clone().filter(|&row| frozen_rows[row]) {
+                        unsafe { *column.add(row) = input.get_value(row, i) };
+                    }
+                }
+            }
+        });
+    }
 }
 
+/// Pointers to the zeroed output columns (one per dimension), written by entity blocks
+struct OutputColumns<F>(Vec<*mut F>);
+
+unsafe impl<F> Sync for OutputColumns<F> {}
+
 /// Two dimensional vectors as matrix representation
-struct TwoDimVectorMatrix {
+struct TwoDimVectorMatrix<F: Float = f32> {
     rows: usize,
     cols: usize,
-    matrix: Vec<Vec<f32>>,
+    matrix: Vec<Vec<F>>,
 }
 
-impl MatrixWrapper for TwoDimVectorMatrix {
+impl<F: Float> MatrixWrapper for TwoDimVectorMatrix<F> {
+    type Value = F;
+
     fn init_with_hashes<T: SparseMatrixReader + Sync + Send>(
         rows: usize,
         cols: usize,
         fixed_random_value: i64,
+        init_method: InitMethod,
         sparse_matrix_reader: Arc<T>,
     ) -> Self {
-        let result: Vec<Vec<f32>> = (0..cols)
+        let result: Vec<Vec<F>> = (0..cols)
             .into_par_iter()
             .map(|i| {
-                let mut col: Vec<f32> = Vec::with_capacity(rows);
+                let mut col: Vec<F> = Vec::with_capacity(rows);
                 for hsh in sparse_matrix_reader.iter_hashes() {
-                    let col_value = init_value(i, hsh.value, fixed_random_value);
-                    col.push(col_value);
+                    let col_value = init_value(i, hsh.value, fixed_random_value, init_method);
+                    col.push(F::from_f32(col_value));
                 }
                 col
             })
@@ -77,19 +393,31 @@ impl MatrixWrapper for TwoDimVectorMatrix {
         }
     }
 
+    fn zeros<T: SparseMatrixReader + Sync + Send>(
+        rows: usize,
+        cols: usize,
+        _sparse_matrix_reader: Arc<T>,
+    ) -> Self {
+        Self {
+            rows,
+            cols,
+            matrix: zero_2d(rows, cols),
+        }
+    }
+
     #[inline]
-    fn get_value(&self, row: usize, col: usize) -> f32 {
-        let column: &Vec<f32> = self.matrix.get(col).unwrap();
+    fn get_value(&self, row: usize, col: usize) -> F {
+        let column: &Vec<F> = self.matrix.get(col).unwrap();
         column[row]
     }
 
     fn normalize(&mut self) {
-        let mut row_sum = vec![0f32; self.rows];
+        let mut row_sum = vec![F::default(); self.rows];
 
         for col in self.matrix.iter() {
             for (j, sum) in row_sum.iter_mut().enumerate() {
                 let value = col[j];
-                *sum += value.powi(2)
+                *sum += value * value
             }
         }
 
@@ -97,42 +425,290 @@ impl MatrixWrapper for TwoDimVectorMatrix {
         self.matrix.par_iter_mut().for_each(|col| {
             for (j, value) in col.iter_mut().enumerate() {
                 let sum = row_sum[j];
-                *value /= sum.sqrt();
+                // rows without any value (possible with sparse init) stay zero
+                if sum > F::default() {
+                    *value /= sum.sqrt();
+                }
             }
         });
     }
 
+    fn update_columns<U>(&mut self, func: U)
+    where
+        U: Fn(usize, &mut [F]) + Sync + Send,
+    {
+        self.matrix
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(i, col)| func(i, col));
+    }
+
     fn multiply<T: SparseMatrixReader + Sync + Send>(
         sparse_matrix_reader: Arc<T>,
-        other: Self,
+        other: &Self,
+        frozen_rows: Option<&[bool]>,
+        partitioning: &Partitioning,
     ) -> Self {
-        let rnew = zero_2d(other.rows, other.cols);
+        let mut rnew = zero_2d(other.rows, other.cols);
 
-        let result: Vec<Vec<f32>> = other
+        match partitioning {
+            Partitioning::DimensionBlocks(block_size) => other
+                .matrix
+                .par_chunks(*block_size)
+                .zip(rnew.par_chunks_mut(*block_size))
+                .for_each(|(res_cols, rnew_cols)| {
+                    for entry in sparse_matrix_reader.iter_entries() {
+                        if matches!(frozen_rows, Some(frozen) if frozen[entry.row as usize]) {
+                            continue;
+                        }
+                        let entry_value = F::from_f32(entry.value);
+                        for (res_col, rnew_col) in res_cols.iter().zip(rnew_cols.iter_mut()) {
+                            let elem = rnew_col.get_mut(entry.row as usize).unwrap();
+                            let value = res_col[entry.col as usize];
+                            *elem += value * entry_value;
+                        }
+                    }
+                    if let Some(frozen_rows) = frozen_rows {
+                        for (row, _) in frozen_rows.iter().enumerate().filter(|(_, &f)| f) {
+                            for (res_col, rnew_col) in res_cols.iter().zip(rnew_cols.iter_mut()) {
+                                rnew_col[row] = res_col[row];
+                            }
+                        }
+                    }
+                }),
+            Partitioning::EntityBlocks(blocks) => {
+                let output = OutputColumns(rnew.iter_mut().map(|col| col.as_mut_ptr()).collect());
+                blocks.multiply(other, &output, frozen_rows);
+            }
+        }
+
+        Self {
+            rows: other.rows,
+            cols: other.cols,
+            matrix: rnew,
+        }
+    }
+}
+
+impl IntoOutput for TwoDimVectorMatrix<f32> {
+    type Output = Self;
+
+    fn into_output(self) -> Self {
+        self
+    }
+}
+
+impl IntoOutput for TwoDimVectorMatrix<f64> {
+    type Output = TwoDimVectorMatrix<f32>;
+
+    fn into_output(self) -> TwoDimVectorMatrix<f32> {
+        let matrix = self
             .matrix
             .into_par_iter()
-            .zip(rnew)
-            .update(|data| {
-                let (res_col, rnew_col) = data;
-                for entry in sparse_matrix_reader.iter_entries() {
-                    let elem = rnew_col.get_mut(entry.row as usize).unwrap();
-                    let value = res_col[entry.col as usize];
-                    *elem += value * entry.value;
-                }
-            })
-            .map(|data| data.1)
+            .map(|col| col.into_iter().map(|value| value as f32).collect())
             .collect();
+        TwoDimVectorMatrix {
+            rows: self.rows,
+            cols: self.cols,
+            matrix,
+        }
+    }
+}
+
+fn init_value(col: usize, hsh: u64, fixed_random_value: i64, init_method: InitMethod) -> f32 {
+    let value = ((hash((hsh as i64) + (col as i64) + fixed_random_value) % MAX_HASH_I64) as f32)
+        / MAX_HASH_F32;
+    match init_method {
+        InitMethod::Uniform => value,
+        InitMethod::SparseRandomProjection { density } => {
+            // value is from (-1, 1) so its magnitude decides about sparsity and its sign about sign
+            if value.abs() < density {
+                value.signum() * (1f32 / density).sqrt()
+            } else {
+                0f32
+            }
+        }
+    }
+}
+
+/// Non-zero values of the sparse random projection initialization, kept instead of the mostly
+/// zero matrix until the first multiplication. The values of every row are sorted by column.
+#[derive(Clone)]
+struct SparseInit {
+    rows: usize,
+    cols: usize,
+    /// Start of the values of every row, followed by the end of the last one
+    offsets: Vec<usize>,
+    /// Column and sign (true for negative) of every non-zero value
+    values: Vec<(u16, bool)>,
+    /// Magnitude of the non-zero values
+    scale: f32,
+    /// Rows are scaled to unit length
+    normalized: bool,
+}
 
+impl SparseInit {
+    fn new<T: SparseMatrixReader>(
+        rows: usize,
+        cols: usize,
+        fixed_random_value: i64,
+        density: f32,
+        sparse_matrix_reader: &T,
+    ) -> Self {
+        let init_method = InitMethod::SparseRandomProjection { density };
+        let hashes: Vec<u64> = sparse_matrix_reader
+            .iter_hashes()
+            .map(|hsh| hsh.value)
+            .collect();
+        let row_values: Vec<Vec<(u16, bool)>> = hashes
+            .par_iter()
+            .map(|&hsh| {
+                (0..cols)
+                    .filter_map(|col| {
+                        let value = init_value(col, hsh, fixed_random_value, init_method);
+                        (value != 0f32).then_some((col as u16, value < 0f32))
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut offsets = Vec::with_capacity(rows + 1);
+        let mut values = Vec::with_capacity(row_values.iter().map(Vec::len).sum());
+        offsets.push(0);
+        for row in row_values {
+            values.extend(row);
+            offsets.push(values.len());
+        }
         Self {
-            rows: other.rows,
-            cols: other.cols,
-            matrix: result,
+            rows,
+            cols,
+            offsets,
+            values,
+            scale: (1f32 / density).sqrt(),
+            normalized: false,
+        }
+    }
+
+    #[inline]
+    fn get_value(&self, row: usize, col: usize) -> f32 {
+        let (start, end) = (self.offsets[row], self.offsets[row + 1]);
+        match self.values[start..end].binary_search_by_key(&(col as u16), |&(col, _)| col) {
+            Ok(idx) => {
+                // all values of a row have the same magnitude
+                let magnitude = if self.normalized {
+                    1f32 / ((end - start) as f32).sqrt()
+                } else {
+                    self.scale
+                };
+                if self.values[start + idx].1 {
+                    -magnitude
+                } else {
+                    magnitude
+                }
+            }
+            Err(_) => 0f32,
+        }
+    }
+}
+
+/// Matrix multiplied by the sparse matrix in an iteration, the initial one of sparse random
+/// projection keeps only its non-zero values
+enum InputMatrix<M> {
+    Dense(M),
+    Sparse(SparseInit),
+}
+
+impl<M: MatrixWrapper> InputMatrix<M> {
+    #[inline]
+    fn get_value(&self, row: usize, col: usize) -> M::Value {
+        match self {
+            InputMatrix::Dense(matrix) => matrix.get_value(row, col),
+            InputMatrix::Sparse(init) => M::Value::from_f32(init.get_value(row, col)),
+        }
+    }
+
+    fn multiply<T: SparseMatrixReader + Sync + Send>(
+        &self,
+        sparse_matrix_reader: Arc<T>,
+        frozen_rows: Option<&[bool]>,
+        partitioning: &Partitioning,
+    ) -> M {
+        match self {
+            InputMatrix::Dense(matrix) => {
+                M::multiply(sparse_matrix_reader, matrix, frozen_rows, partitioning)
+            }
+            InputMatrix::Sparse(init) => {
+                M::multiply_sparse(sparse_matrix_reader, init, frozen_rows)
+            }
         }
     }
+
+    /// Copy of the matrix with rows scaled to unit length
+    fn normalized<T: SparseMatrixReader + Sync + Send>(
+        &self,
+        rows: usize,
+        cols: usize,
+        sparse_matrix_reader: Arc<T>,
+    ) -> Self
+    where
+        M: Sync,
+    {
+        match self {
+            InputMatrix::Dense(_) => {
+                let mut matrix = self.to_dense(rows, cols, sparse_matrix_reader);
+                matrix.normalize();
+                InputMatrix::Dense(matrix)
+            }
+            InputMatrix::Sparse(init) => InputMatrix::Sparse(SparseInit {
+                normalized: true,
+                ..init.clone()
+            }),
+        }
+    }
+
+    /// Copy of the values in a dense matrix
+    fn to_dense<T: SparseMatrixReader + Sync + Send>(
+        &self,
+        rows: usize,
+        cols: usize,
+        sparse_matrix_reader: Arc<T>,
+    ) -> M
+    where
+        M: Sync,
+    {
+        let mut matrix = M::zeros(rows, cols, sparse_matrix_reader);
+        matrix.update_columns(|i, column| {
+            for (row, value) in column.iter_mut().enumerate() {
+                *value = self.get_value(row, i);
+            }
+        });
+        matrix
+    }
 }
 
-fn init_value(col: usize, hsh: u64, fixed_random_value: i64) -> f32 {
-    ((hash((hsh as i64) + (col as i64) + fixed_random_value) % MAX_HASH_I64) as f32) / MAX_HASH_F32
+/// Fits feature vectors to the dimension: longer ones are reduced by random projection (signs
+/// derived from the seed like initial vectors), shorter ones are padded with zeros.
+pub fn fit_features(features: Embeddings, dimension: usize, seed: Option<i64>) -> Embeddings {
+    let feature_dimension = features.dimension();
+    if feature_dimension == dimension {
+        return features;
+    }
+    let vectors = if feature_dimension < dimension {
+        let mut vectors = Array2::zeros((features.entities.len(), dimension));
+        vectors
+            .slice_mut(s![.., ..feature_dimension])
+            .assign(&features.vectors);
+        vectors
+    } else {
+        let fixed_random_value = seed.map(hash).unwrap_or(0);
+        let projection = Array2::from_shape_fn((feature_dimension, dimension), |(i, j)| {
+            init_value(j, i as u64, fixed_random_value, InitMethod::Uniform).signum()
+        });
+        features.vectors.dot(&projection)
+    };
+    Embeddings {
+        entities: features.entities,
+        vectors,
+    }
 }
 
 fn hash(num: i64) -> i64 {
@@ -141,42 +717,49 @@ fn hash(num: i64) -> i64 {
     hasher.finish() as i64
 }
 
-fn zero_2d(row: usize, col: usize) -> Vec<Vec<f32>> {
-    let mut res: Vec<Vec<f32>> = Vec::with_capacity(col);
+fn zero_2d<F: Float>(row: usize, col: usize) -> Vec<Vec<F>> {
+    let mut res: Vec<Vec<F>> = Vec::with_capacity(col);
     for _i in 0..col {
-        let col = vec![0f32; row];
+        let col = vec![F::default(); row];
         res.push(col);
     }
     res
 }
 
 /// Memory-mapped file as matrix representation. Every column of the matrix is placed side by side in the file.
-struct MMapMatrix {
+#[cfg(feature = "fs")]
+struct MMapMatrix<F: Float = f32> {
     rows: usize,
     cols: usize,
     file_name: String,
     matrix: MmapMut,
+    _marker: PhantomData<F>,
 }
 
-impl MatrixWrapper for MMapMatrix {
+#[cfg(feature = "fs")]
+impl<F: Float> MatrixWrapper for MMapMatrix<F> {
+    type Value = F;
+
     fn init_with_hashes<T: SparseMatrixReader + Sync + Send>(
         rows: usize,
         cols: usize,
         fixed_random_value: i64,
+        init_method: InitMethod,
         sparse_matrix_reader: Arc<T>,
     ) -> Self {
         let uuid = Uuid::new_v4();
         let file_name = format!("{}_matrix_{}", sparse_matrix_reader.get_id(), uuid);
-        let mut mmap = create_mmap(rows, cols, file_name.as_str());
+        let mut mmap = create_mmap(rows, cols, Self::VALUE_SIZE, file_name.as_str());
 
-        mmap.par_chunks_mut(rows * 4)
+        mmap.par_chunks_mut(rows * Self::VALUE_SIZE)
             .enumerate()
             .for_each(|(i, chunk)| {
                 // i - number of dimension
                 // chunk - column/vector of bytes
                 for (j, hsh) in sparse_matrix_reader.iter_hashes().enumerate() {
-                    let col_value = init_value(i, hsh.value, fixed_random_value);
-                    MMapMatrix::update_column(j, chunk, |value| unsafe { *value = col_value });
+                    let col_value = init_value(i, hsh.value, fixed_random_value, init_method);
+                    let col_value = F::from_f32(col_value);
+                    Self::update_column(j, chunk, |value| unsafe { *value = col_value });
                 }
             });
 
@@ -188,40 +771,62 @@ impl MatrixWrapper for MMapMatrix {
             cols,
             file_name,
             matrix: mmap,
+            _marker: PhantomData,
+        }
+    }
+
+    fn zeros<T: SparseMatrixReader + Sync + Send>(
+        rows: usize,
+        cols: usize,
+        sparse_matrix_reader: Arc<T>,
+    ) -> Self {
+        let uuid = Uuid::new_v4();
+        let file_name = format!("{}_matrix_{}", sparse_matrix_reader.get_id(), uuid);
+        // a new file is filled with zeros
+        let matrix = create_mmap(rows, cols, Self::VALUE_SIZE, file_name.as_str());
+        Self {
+            rows,
+            cols,
+            file_name,
+            matrix,
+            _marker: PhantomData,
         }
     }
 
     #[inline]
-    fn get_value(&self, row: usize, col: usize) -> f32 {
-        let start_idx = ((col * self.rows) + row) * 4;
-        let end_idx = start_idx + 4;
+    fn get_value(&self, row: usize, col: usize) -> F {
+        let start_idx = ((col * self.rows) + row) * Self::VALUE_SIZE;
+        let end_idx = start_idx + Self::VALUE_SIZE;
         let pointer: *const u8 = (&self.matrix[start_idx..end_idx]).as_ptr();
         unsafe {
-            let value = pointer as *const f32;
+            let value = pointer as *const F;
             *value
         }
     }
 
     fn normalize(&mut self) {
         let entities_count = self.rows;
-        let mut row_sum = vec![0f32; entities_count];
+        let mut row_sum = vec![F::default(); entities_count];
 
         for i in 0..(self.cols as usize) {
             for (j, sum) in row_sum.iter_mut().enumerate() {
                 let value = self.get_value(j, i);
-                *sum += value.powi(2)
+                *sum += value * value
             }
         }
 
         let row_sum = Arc::new(row_sum);
         self.matrix
-            .par_chunks_mut(entities_count * 4)
+            .par_chunks_mut(entities_count * Self::VALUE_SIZE)
             .enumerate()
             .for_each(|(_i, chunk)| {
                 // i - number of dimension
                 // chunk - column/vector of bytes
                 for (j, &sum) in row_sum.iter().enumerate() {
-                    MMapMatrix::update_column(j, chunk, |value| unsafe { *value /= sum.sqrt() });
+                    // rows without any value (possible with sparse init) stay zero
+                    if sum > F::default() {
+                        Self::update_column(j, chunk, |value| unsafe { *value /= sum.sqrt() });
+                    }
                 }
             });
 
@@ -230,29 +835,87 @@ impl MatrixWrapper for MMapMatrix {
             .expect("Can't flush memory map modifications to disk");
     }
 
+    fn update_columns<U>(&mut self, func: U)
+    where
+        U: Fn(usize, &mut [F]) + Sync + Send,
+    {
+        let entities_count = self.rows;
+        if entities_count == 0 {
+            return;
+        }
+
+        self.matrix
+            .par_chunks_mut(entities_count * Self::VALUE_SIZE)
+            .enumerate()
+            .for_each(|(i, chunk)| {
+                // i - number of dimension
+                // chunk - column/vector of bytes, the memory map starts at the header length (a
+                // multiple of 64) of the page aligned file, so every column is aligned for the
+                // values
+                let column = unsafe {
+                    std::slice::from_raw_parts_mut(chunk.as_mut_ptr() as *mut F, entities_count)
+                };
+                func(i, column);
+            });
+
+        self.matrix
+            .flush()
+            .expect("Can't flush memory map modifications to disk");
+    }
+
     fn multiply<T: SparseMatrixReader + Sync + Send>(
         sparse_matrix_reader: Arc<T>,
-        other: Self,
+        other: &Self,
+        frozen_rows: Option<&[bool]>,
+        partitioning: &Partitioning,
     ) -> Self {
         let rows = other.rows;
         let cols = other.cols;
 
         let uuid = Uuid::new_v4();
         let file_name = format!("{}_matrix_{}", sparse_matrix_reader.get_id(), uuid);
-        let mut mmap_output = create_mmap(rows, cols, file_name.as_str());
+        let mut mmap_output = create_mmap(rows, cols, Self::VALUE_SIZE, file_name.as_str());
 
-        let input = Arc::new(other);
-        mmap_output
-            .par_chunks_mut(rows * 4)
-            .enumerate()
-            .for_each_with(input, |input, (i, chunk)| {
-                for entry in sparse_matrix_reader.iter_entries() {
-                    let input_value = input.get_value(entry.col as usize, i);
-                    MMapMatrix::update_column(entry.row as usize, chunk, |value| unsafe {
-                        *value += input_value * entry.value
-                    });
-                }
-            });
+        let input = other;
+        let column_size = rows * Self::VALUE_SIZE;
+        match partitioning {
+            Partitioning::DimensionBlocks(block_size) => mmap_output
+                .par_chunks_mut(column_size * block_size)
+                .enumerate()
+                .for_each(|(block, chunk)| {
+                    let first_col = block * block_size;
+                    for entry in sparse_matrix_reader.iter_entries() {
+                        if matches!(frozen_rows, Some(frozen) if frozen[entry.row as usize]) {
+                            continue;
+                        }
+                        let entry_value = F::from_f32(entry.value);
+                        for (k, column) in chunk.chunks_mut(column_size).enumerate() {
+                            let input_value = input.get_value(entry.col as usize, first_col + k);
+                            Self::update_column(entry.row as usize, column, |value| unsafe {
+                                *value += input_value * entry_value
+                            });
+                        }
+                    }
+                    if let Some(frozen_rows) = frozen_rows {
+                        for (row, _) in frozen_rows.iter().enumerate().filter(|(_, &f)| f) {
+                            for (k, column) in chunk.chunks_mut(column_size).enumerate() {
+                                let input_value = input.get_value(row, first_col + k);
+                                Self::update_column(row, column, |value| unsafe {
+                                    *value = input_value
+                                });
+                            }
+                        }
+                    }
+                }),
+            Partitioning::EntityBlocks(blocks) => {
+                // the memory map starts at the header length (a multiple of 64) of the page
+                // aligned file, so every column is properly aligned for the values
+                let start = mmap_output.as_mut_ptr() as *mut F;
+                let output =
+                    OutputColumns((0..cols).map(|i| unsafe { start.add(i * rows) }).collect());
+                blocks.multiply(input, &output, frozen_rows);
+            }
+        }
 
         mmap_output
             .flush()
@@ -263,38 +926,93 @@ impl MatrixWrapper for MMapMatrix {
             cols,
             file_name,
             matrix: mmap_output,
+            _marker: PhantomData,
+        }
+    }
+
+    fn file(&self) -> Option<ColumnMajorFile> {
+        // the file is the layout of f32 numpy output only
+        if Self::VALUE_SIZE != std::mem::size_of::<f32>() {
+            return None;
         }
+        Some(ColumnMajorFile {
+            path: self.file_name.clone(),
+            rows: self.rows,
+            cols: self.cols,
+        })
     }
 }
 
-/// Creates memory-mapped file with allocated number of bytes
-fn create_mmap(rows: usize, cols: usize, file_name: &str) -> MmapMut {
-    let number_of_bytes = (rows * cols * 4) as u64;
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(file_name)
-        .expect("Can't create new set of options for memory mapped file");
-    file.set_len(number_of_bytes).unwrap_or_else(|_| {
-        panic!(
-            "Can't update the size of {} file to {} bytes",
-            file_name, number_of_bytes
+#[cfg(feature = "fs")]
+impl IntoOutput for MMapMatrix<f32> {
+    type Output = Self;
+
+    fn into_output(self) -> Self {
+        self
+    }
+}
+
+#[cfg(feature = "fs")]
+impl IntoOutput for MMapMatrix<f64> {
+    type Output = MMapMatrix<f32>;
+
+    /// Values are converted into a new file, the file of f64 values is removed when dropped
+    fn into_output(self) -> MMapMatrix<f32> {
+        let file_name = format!("{}_f32", self.file_name);
+        let mut output = MMapMatrix {
+            rows: self.rows,
+            cols: self.cols,
+            matrix: create_mmap(self.rows, self.cols, 4, file_name.as_str()),
+            file_name,
+            _marker: PhantomData,
+        };
+        output.update_columns(|i, column| {
+            for (row, value) in column.iter_mut().enumerate() {
+                *value = self.get_value(row, i) as f32;
+            }
+        });
+        output
+    }
+}
+
+/// Creates memory-mapped file with allocated number of bytes (`value_size` bytes per value). The
+/// map starts after the space left for npy header, see `ColumnMajorFile`.
+#[cfg(feature = "fs")]
+fn create_mmap(rows: usize, cols: usize, value_size: usize, file_name: &str) -> MmapMut {
+    let number_of_bytes = (NPY_HEADER_LEN + rows * cols * value_size) as u64;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(file_name)
+        .expect("Can't create new set of options for memory mapped file");
+    file.set_len(number_of_bytes).unwrap_or_else(|_| {
+        panic!(
+            "Can't update the size of {} file to {} bytes",
+            file_name, number_of_bytes
         )
     });
     unsafe {
-        MmapMut::map_mut(&file).unwrap_or_else(|_| {
-            panic!(
-                "Can't create memory mapped file for the underlying file {}",
-                file_name
-            )
-        })
+        MmapOptions::new()
+            .offset(NPY_HEADER_LEN as u64)
+            .map_mut(&file)
+            .unwrap_or_else(|_| {
+                panic!(
+                    "Can't create memory mapped file for the underlying file {}",
+                    file_name
+                )
+            })
     }
 }
 
 /// Used to remove memory-mapped file after processing
-impl Drop for MMapMatrix {
+#[cfg(feature = "fs")]
+impl<F: Float> Drop for MMapMatrix<F> {
     fn drop(&mut self) {
+        // the file is gone if it was moved to the output, see `put_column_major_file`
+        if !Path::new(&self.file_name).exists() {
+            return;
+        }
         fs::remove_file(self.file_name.as_str()).unwrap_or_else(|_| {
             warn!(
                 "File {} can't be removed after work. Remove the file in order to save disk space.",
@@ -304,41 +1022,271 @@ impl Drop for MMapMatrix {
     }
 }
 
-impl MMapMatrix {
+#[cfg(feature = "fs")]
+impl<F: Float> MMapMatrix<F> {
+    /// Bytes of a value
+    const VALUE_SIZE: usize = std::mem::size_of::<F>();
+
     #[inline]
-    fn update_column<F>(col: usize, chunk: &mut [u8], func: F)
+    fn update_column<U>(col: usize, chunk: &mut [u8], func: U)
     where
-        F: Fn(*mut f32),
+        U: Fn(*mut F),
     {
-        let start_idx = col * 4;
-        let end_idx = start_idx + 4;
+        let start_idx = col * Self::VALUE_SIZE;
+        let end_idx = start_idx + Self::VALUE_SIZE;
         let pointer: *mut u8 = (&mut chunk[start_idx..end_idx]).as_mut_ptr();
-        let value = pointer as *mut f32;
+        let value = pointer as *mut F;
         func(value);
     }
 }
 
-/// Calculate embeddings in memory.
+/// Calculate embeddings in memory. Intermediate embeddings are written after every iteration if
+/// `snapshot_persistor` is given.
 pub fn calculate_embeddings<T1, T2>(
     config: Arc<Configuration>,
     sparse_matrix_reader: Arc<T1>,
     entity_mapping_persistor: Arc<T2>,
     embedding_persistor: &mut dyn EmbeddingPersistor,
-) where
+    snapshot_persistor: Option<&SnapshotPersistorFactory>,
+    prior: PriorEmbeddings,
+) -> Result<TrainingSummary, CleoraError>
+where
     T1: SparseMatrixReader + Sync + Send,
     T2: EntityMappingPersistor,
+{
+    match config.precision {
+        Precision::F32 => calculate::<T1, T2, TwoDimVectorMatrix<f32>>(
+            config,
+            sparse_matrix_reader,
+            entity_mapping_persistor,
+            embedding_persistor,
+            snapshot_persistor,
+            prior,
+        ),
+        Precision::F64 => calculate::<T1, T2, TwoDimVectorMatrix<f64>>(
+            config,
+            sparse_matrix_reader,
+            entity_mapping_persistor,
+            embedding_persistor,
+            snapshot_persistor,
+            prior,
+        ),
+    }
+}
+
+/// Calculates embeddings propagated in the matrix of type `M`. The propagated matrix is converted
+/// to f32 values before postprocessing.
+fn calculate<T1, T2, M>(
+    config: Arc<Configuration>,
+    sparse_matrix_reader: Arc<T1>,
+    entity_mapping_persistor: Arc<T2>,
+    embedding_persistor: &mut dyn EmbeddingPersistor,
+    snapshot_persistor: Option<&SnapshotPersistorFactory>,
+    prior: PriorEmbeddings,
+) -> Result<TrainingSummary, CleoraError>
+where
+    T1: SparseMatrixReader + Sync + Send,
+    T2: EntityMappingPersistor,
+    M: IntoOutput + Sync,
+    M::Output: Sync,
 {
     let mult = MatrixMultiplicator::new(config.clone(), sparse_matrix_reader);
-    let init: TwoDimVectorMatrix = mult.initialize();
-    let res = mult.propagate(config.max_number_of_iteration, init);
+    let init = match (prior.features, prior.previous) {
+        (None, None) => mult.input_matrix(),
+        (features, previous) => {
+            let mut init: M = mult.initialize();
+            if let Some(features) = features {
+                mult.init_from_features(&mut init, features, entity_mapping_persistor.as_ref());
+            }
+            if let Some(previous) = previous {
+                mult.continue_from(&mut init, previous, entity_mapping_persistor.as_ref());
+            }
+            InputMatrix::Dense(init)
+        }
+    };
+    let (res, mut summary) =
+        mult.propagate(config.max_number_of_iteration, init, |iteration, res| {
+            match snapshot_persistor {
+                Some(create_persistor) => mult.snapshot(
+                    iteration,
+                    res,
+                    entity_mapping_persistor.as_ref(),
+                    create_persistor(iteration)?.as_mut(),
+                    config.chunk_size,
+                ),
+                None => Ok(()),
+            }
+        })?;
+    let mult: MatrixMultiplicator<T1, M::Output> = mult.with_matrix();
+    let mut res = res.into_output();
+    mult.postprocess(&mut res);
+    if let Some(reference) = prior.reference {
+        mult.align_to(&mut res, reference, entity_mapping_persistor.as_ref());
+    }
+    if config.similarity_summary {
+        mult.similarity_summary(&res);
+    }
+    let stage = profile::Stage::start("persist");
     mult.persist(
         res,
         entity_mapping_persistor,
         embedding_persistor,
         config.chunk_size,
+    )?;
+    stage.finish(
+        Some(mult.sparse_matrix_reader.get_id()),
+        mult.number_of_entities as u64,
+        "rows",
     );
 
-    info!("Finalizing embeddings calculations!")
+    info!("Finalizing embeddings calculations!");
+    // the outputs may miss iterations or rows
+    summary.interrupted = interrupt::interrupted();
+    Ok(summary)
+}
+
+/// Tracks convergence of connected components of the graph. Row change is 1 - cosine similarity of
+/// its embeddings from consecutive iterations. It doesn't drop to zero when embeddings oscillate
+/// (e.g. in bipartite graphs), so component is converged when the changes of its rows stabilize:
+/// their mean difference from the previous iteration is below the tolerance. Rows of converged
+/// components are frozen (not multiplied) since then. Edges never cross components, so frozen
+/// rows don't affect the others.
+struct ComponentConvergence {
+    tolerance: f32,
+    components: Vec<u32>,
+    converged: Vec<bool>,
+    frozen_rows: Vec<bool>,
+    previous_changes: Option<Vec<f32>>,
+}
+
+impl ComponentConvergence {
+    fn new<T: SparseMatrixReader>(sparse_matrix_reader: &T, tolerance: f32) -> Self {
+        let (components, count) = connected_components(sparse_matrix_reader);
+        info!("Tracking convergence of {} connected components.", count);
+        Self {
+            tolerance,
+            frozen_rows: vec![false; components.len()],
+            components,
+            converged: vec![false; count],
+            previous_changes: None,
+        }
+    }
+
+    /// Marks components converged by the last iteration, `row_changes` are changes of every row
+    fn update(&mut self, row_changes: Vec<f32>) {
+        if let Some(previous_changes) = self.previous_changes.as_ref() {
+            let mut sums = vec![0f64; self.converged.len()];
+            let mut counts = vec![0u64; self.converged.len()];
+            for ((&component, &change), &previous) in self
+                .components
+                .iter()
+                .zip(&row_changes)
+                .zip(previous_changes)
+            {
+                sums[component as usize] += (change - previous).abs() as f64;
+                counts[component as usize] += 1;
+            }
+            for (component, converged) in self.converged.iter_mut().enumerate() {
+                if counts[component] > 0 {
+                    let mean_difference = sums[component] / counts[component] as f64;
+                    *converged |= mean_difference < self.tolerance as f64;
+                }
+            }
+            for (frozen, &component) in self.frozen_rows.iter_mut().zip(&self.components) {
+                *frozen = self.converged[component as usize];
+            }
+        }
+        self.previous_changes = Some(row_changes);
+    }
+
+    fn converged_count(&self) -> usize {
+        self.converged.iter().filter(|&&c| c).count()
+    }
+
+    fn all_converged(&self) -> bool {
+        self.converged.iter().all(|&c| c)
+    }
+}
+
+/// Outcome of the propagation
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrainingSummary {
+    /// Number of iterations done
+    pub iterations: u8,
+    /// Propagation was stopped early to meet the deadline or by an interrupt
+    pub partial: bool,
+    /// The run was interrupted, the output may miss iterations (`partial`)
+    pub interrupted: bool,
+    /// Measurements of the done iterations
+    pub iteration_stats: Vec<IterationStats>,
+}
+
+impl TrainingSummary {
+    /// Adds the summary of another propagation of the same output (e.g. of another edge type),
+    /// which ran after this one. Measurements of the same iteration are combined.
+    pub fn merge(&mut self, other: TrainingSummary) {
+        self.iterations = self.iterations.min(other.iterations);
+        self.partial |= other.partial;
+        self.interrupted |= other.interrupted;
+        for (i, stats) in other.iteration_stats.into_iter().enumerate() {
+            match self.iteration_stats.get_mut(i) {
+                Some(merged) => {
+                    merged.seconds += stats.seconds;
+                    // the slowest converging propagation
+                    merged.mean_row_change =
+                        combine(merged.mean_row_change, stats.mean_row_change, f64::max);
+                    merged.p95_row_change =
+                        combine(merged.p95_row_change, stats.p95_row_change, f64::max);
+                    merged.converged_components = combine(
+                        merged.converged_components,
+                        stats.converged_components,
+                        |a, b| a + b,
+                    );
+                }
+                None => self.iteration_stats.push(stats),
+            }
+        }
+    }
+}
+
+fn combine<T>(a: Option<T>, b: Option<T>, f: impl Fn(T, T) -> T) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(f(a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Measurements of a propagation iteration
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IterationStats {
+    /// Time of the multiplication and normalization (without writing the snapshot)
+    pub seconds: f64,
+    /// Mean change of the sampled rows (1 - cosine similarity to the iteration before the
+    /// previous one, which is on the same side of bipartite graphs), unknown for the first one
+    pub mean_row_change: Option<f64>,
+    /// 95th percentile of the changes of the sampled rows
+    pub p95_row_change: Option<f64>,
+    /// Number of converged components, known when convergence is tracked
+    pub converged_components: Option<usize>,
+}
+
+/// Order of entity names, entities without names last
+fn compare_names(a: &Option<Arc<str>>, b: &Option<Arc<str>>) -> std::cmp::Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => a.as_bytes().cmp(b.as_bytes()),
+        (a, b) => b.is_some().cmp(&a.is_some()),
+    }
+}
+
+/// Value below which the given share of the values lies (nearest rank), 0 without values
+fn percentile(values: &[f32], share: f64) -> f64 {
+    if values.is_empty() {
+        return 0f64;
+    }
+    let mut values = values.to_vec();
+    let rank = ((share * values.len() as f64).ceil() as usize).clamp(1, values.len()) - 1;
+    let (_, value, _) = values.select_nth_unstable_by(rank, |a, b| a.total_cmp(b));
+    *value as f64
 }
 
 /// Provides matrix multiplication based on sparse matrix data.
@@ -347,6 +1295,18 @@ struct MatrixMultiplicator<T: SparseMatrixReader + Sync + Send, M: MatrixWrapper
     dimension: usize,
     number_of_entities: usize,
     fixed_random_value: i64,
+    init_method: InitMethod,
+    postprocess: Vec<PostprocessStep>,
+    postprocess_stats: StatsScope,
+    normalize: Normalization,
+    sort_output: Option<SortOutput>,
+    /// Only entities with one of the tags are written, empty writes all
+    output_tags: Vec<String>,
+    convergence_tolerance: Option<f32>,
+    early_stop: Option<f32>,
+    alpha: f32,
+    deadline: Option<Instant>,
+    partitioning: Partitioning,
     sparse_matrix_reader: Arc<T>,
     _marker: PhantomData<M>,
 }
@@ -358,15 +1318,53 @@ where
 {
     fn new(config: Arc<Configuration>, sparse_matrix_reader: Arc<T>) -> Self {
         let rand_value = config.seed.map(hash).unwrap_or(0);
+        let partitioning = Partitioning::new(
+            config.propagation_partitioning,
+            config.embeddings_dimension as usize,
+            sparse_matrix_reader.as_ref(),
+        );
         Self {
             dimension: config.embeddings_dimension as usize,
             number_of_entities: sparse_matrix_reader.get_number_of_entities() as usize,
             fixed_random_value: rand_value,
+            init_method: config.init_method,
+            postprocess: config.postprocess.clone(),
+            postprocess_stats: config.postprocess_stats,
+            normalize: config.normalize,
+            sort_output: config.sort_output,
+            output_tags: config.output_tags.clone(),
+            convergence_tolerance: config.convergence_tolerance,
+            early_stop: config.early_stop,
+            alpha: config.alpha,
+            deadline: config.deadline,
+            partitioning,
             sparse_matrix_reader,
             _marker: PhantomData,
         }
     }
 
+    /// The same multiplicator for matrices of another type
+    fn with_matrix<M2: MatrixWrapper>(self) -> MatrixMultiplicator<T, M2> {
+        MatrixMultiplicator {
+            dimension: self.dimension,
+            number_of_entities: self.number_of_entities,
+            fixed_random_value: self.fixed_random_value,
+            init_method: self.init_method,
+            postprocess: self.postprocess,
+            postprocess_stats: self.postprocess_stats,
+            normalize: self.normalize,
+            sort_output: self.sort_output,
+            output_tags: self.output_tags,
+            convergence_tolerance: self.convergence_tolerance,
+            early_stop: self.early_stop,
+            alpha: self.alpha,
+            deadline: self.deadline,
+            partitioning: self.partitioning,
+            sparse_matrix_reader: self.sparse_matrix_reader,
+            _marker: PhantomData,
+        }
+    }
+
     /// Initialize a matrix
     fn initialize(&self) -> M {
         info!(
@@ -378,6 +1376,7 @@ where
             self.number_of_entities,
             self.dimension,
             self.fixed_random_value,
+            self.init_method,
             self.sparse_matrix_reader.clone(),
         );
 
@@ -388,20 +1387,226 @@ where
         result
     }
 
+    /// Matrix the propagation starts from, only the non-zero values of sparse random projection
+    /// are kept
+    fn input_matrix(&self) -> InputMatrix<M> {
+        match self.init_method {
+            InitMethod::SparseRandomProjection { density } => {
+                info!(
+                    "Start sparse initialization. Dims: {}, entities: {}.",
+                    self.dimension, self.number_of_entities
+                );
+                let init = SparseInit::new(
+                    self.number_of_entities,
+                    self.dimension,
+                    self.fixed_random_value,
+                    density,
+                    self.sparse_matrix_reader.as_ref(),
+                );
+                info!(
+                    "Done sparse initialization. Non-zero values: {} of {}.",
+                    init.values.len(),
+                    self.number_of_entities * self.dimension
+                );
+                InputMatrix::Sparse(init)
+            }
+            InitMethod::Uniform => InputMatrix::Dense(self.initialize()),
+        }
+    }
+
+    /// Rows of the embeddings (by entity name) for every entity of the matrix
+    fn embedding_rows<T1>(
+        &self,
+        embeddings: &Embeddings,
+        entity_mapping_persistor: &T1,
+    ) -> Vec<Option<usize>>
+    where
+        T1: EntityMappingPersistor,
+    {
+        let index = embeddings.index();
+        self.sparse_matrix_reader
+            .iter_hashes()
+            .map(|hash| {
+                entity_mapping_persistor
+                    .get_entity(hash.value)
+                    .and_then(|entity| index.get(&*entity).copied())
+            })
+            .collect()
+    }
+
+    /// Replaces initial vectors of entities found in the features (by entity name, fitted to the
+    /// dimension with `fit_features`), so the features are propagated through the graph. Feature
+    /// vectors are scaled to the norm of the initial vectors they replace, entities without
+    /// features (or with zero vectors) keep their initial vectors.
+    fn init_from_features<T1>(
+        &self,
+        res: &mut M,
+        features: &Embeddings,
+        entity_mapping_persistor: &T1,
+    ) where
+        T1: EntityMappingPersistor,
+        M: Sync,
+    {
+        let feature_rows: Vec<Option<(usize, f32)>> = self
+            .embedding_rows(features, entity_mapping_persistor)
+            .into_iter()
+            .enumerate()
+            .map(|(row, feature_row)| {
+                let feature_row = feature_row?;
+                let feature_vector = features.vectors.row(feature_row);
+                let feature_norm = feature_vector.dot(&feature_vector).sqrt();
+                if feature_norm == 0f32 {
+                    return None;
+                }
+                let norm = (0..self.dimension)
+                    .map(|col| res.get_value(row, col).to_f32().powi(2))
+                    .sum::<f32>()
+                    .sqrt();
+                Some((feature_row, norm / feature_norm))
+            })
+            .collect();
+        let found = feature_rows.iter().filter(|row| row.is_some()).count();
+        res.update_columns(|i, column| {
+            for (value, feature_row) in column.iter_mut().zip(feature_rows.iter()) {
+                if let Some((feature_row, scale)) = feature_row {
+                    *value = M::Value::from_f32(features.vectors[[*feature_row, i]] * scale);
+                }
+            }
+        });
+        info!(
+            "Initialized {} of {} entities from features.",
+            found, self.number_of_entities
+        );
+    }
+
+    /// Replaces initial vectors of entities found in the previous embeddings (by entity name), so
+    /// the propagation continues from them. New entities keep their initial vectors.
+    fn continue_from<T1>(&self, res: &mut M, previous: &Embeddings, entity_mapping_persistor: &T1)
+    where
+        T1: EntityMappingPersistor,
+        M: Sync,
+    {
+        let previous_rows = self.embedding_rows(previous, entity_mapping_persistor);
+        let found = previous_rows.iter().filter(|row| row.is_some()).count();
+        res.update_columns(|i, column| {
+            for (value, previous_row) in column.iter_mut().zip(previous_rows.iter()) {
+                if let Some(previous_row) = previous_row {
+                    *value = M::Value::from_f32(previous.vectors[[*previous_row, i]]);
+                }
+            }
+        });
+        info!(
+            "Continuing from previous embeddings of {} of {} entities, others initialized.",
+            found, self.number_of_entities
+        );
+    }
+
     /// The sparse matrix is multiplied by a freshly initialized matrix M.
     /// Multiplication is done against each column of matrix M in a separate thread.
     /// The obtained columns of the new matrix are subsequently merged into the full matrix.
     /// The matrix is L2-normalized, again in a multithreaded fashion across matrix columns.
     /// Finally, depending on the target iteration number, the matrix is either returned
     /// or fed for next iterations of multiplication against the sparse matrix.
-    fn propagate(&self, max_iter: u8, res: M) -> M {
+    /// `on_iteration` gets every normalized matrix with its iteration number (counted from 1).
+    /// Changes of sampled rows since the iteration before the previous one are measured (vectors
+    /// of bipartite graphs alternate between iterations), propagation stops early once 95% of
+    /// them are below the early stop threshold.
+    fn propagate<F>(
+        &self,
+        max_iter: u8,
+        res: InputMatrix<M>,
+        mut on_iteration: F,
+    ) -> Result<(M, TrainingSummary), CleoraError>
+    where
+        F: FnMut(u8, &M) -> Result<(), CleoraError>,
+        M: Sync,
+    {
         info!("Start propagating. Number of iterations: {}.", max_iter);
 
+        let mut convergence = self.convergence_tolerance.map(|tolerance| {
+            ComponentConvergence::new(self.sparse_matrix_reader.as_ref(), tolerance)
+        });
+
+        // normalized initial vectors (with the feature and previous embeddings) mixed back after
+        // every iteration
+        let initial: Option<InputMatrix<M>> = if self.alpha > 0f32 {
+            Some(res.normalized(
+                self.number_of_entities,
+                self.dimension,
+                self.sparse_matrix_reader.clone(),
+            ))
+        } else {
+            None
+        };
+
+        let mut summary = TrainingSummary {
+            iterations: 0,
+            partial: false,
+            interrupted: false,
+            iteration_stats: Vec::with_capacity(max_iter as usize),
+        };
+        let mut longest_iteration = Duration::default();
+        let step = (self.number_of_entities / CHANGE_SAMPLE_SIZE).max(1);
+        let sampled_rows: Vec<usize> = (0..self.number_of_entities)
+            .step_by(step)
+            .take(CHANGE_SAMPLE_SIZE)
+            .collect();
+        // samples of the last two matrices, the initial one first
+        let mut samples = VecDeque::with_capacity(2);
+        samples.push_back(self.sample_rows(|row, col| res.get_value(row, col), &sampled_rows));
         let mut new_res = res;
         for i in 0..max_iter {
-            let mut next = M::multiply(self.sparse_matrix_reader.clone(), new_res);
+            // the iteration is expected to take as long as the longest one so far, propagation
+            // starting after the deadline (e.g. of a later edge type) doesn't do any
+            if let Some(deadline) = self.deadline {
+                if Instant::now() + longest_iteration > deadline {
+                    warn!(
+                        "Stopping before iter: {} to meet the deadline, {} of {} iterations done.",
+                        i, summary.iterations, max_iter
+                    );
+                    summary.partial = true;
+                    break;
+                }
+            }
+
+            let iteration_start = Instant::now();
+            let stage = profile::Stage::start(format!("iteration {}", i + 1));
+            let frozen_rows = convergence.as_ref().map(|c| c.frozen_rows.as_slice());
+            let mut next = new_res.multiply(
+                self.sparse_matrix_reader.clone(),
+                frozen_rows,
+                &self.partitioning,
+            );
             next.normalize();
-            new_res = next;
+            if let Some(initial) = initial.as_ref() {
+                next.mix_in(initial, self.alpha, frozen_rows);
+                next.normalize();
+            }
+            let sample = self.sample_rows(|row, col| next.get_value(row, col), &sampled_rows);
+            let mut changes = None;
+            if samples.len() == 2 {
+                let before_previous = samples.pop_front().unwrap();
+                let sample_changes = self.sample_changes(&before_previous, &sample);
+                let sum: f64 = sample_changes.iter().map(|&change| change as f64).sum();
+                let mean = sum / sample_changes.len().max(1) as f64;
+                changes = Some((mean, percentile(&sample_changes, 0.95)));
+            }
+            samples.push_back(sample);
+            if let Some(convergence) = convergence.as_mut() {
+                convergence.update(self.row_changes(&new_res, &next));
+            }
+            stage.finish(
+                Some(self.sparse_matrix_reader.get_id()),
+                self.sparse_matrix_reader.get_number_of_entries() as u64,
+                "entries",
+            );
+            summary.iterations = i + 1;
+            summary.iteration_stats.push(IterationStats {
+                seconds: iteration_start.elapsed().as_secs_f64(),
+                mean_row_change: changes.map(|(mean, _)| mean),
+                p95_row_change: changes.map(|(_, p95)| p95),
+                converged_components: convergence.as_ref().map(|c| c.converged_count()),
+            });
 
             info!(
                 "Done iter: {}. Dims: {}, entities: {}, num data points: {}.",
@@ -410,135 +1615,1462 @@ where
                 self.number_of_entities,
                 self.sparse_matrix_reader.get_number_of_entries()
             );
+            if let Some((mean, p95)) = changes {
+                info!(
+                    "Changes of the embeddings in iter: {}. Mean: {:.6}, 95th percentile: {:.6}.",
+                    i, mean, p95
+                );
+            }
+            on_iteration(i + 1, &next)?;
+            new_res = InputMatrix::Dense(next);
+            longest_iteration = longest_iteration.max(iteration_start.elapsed());
+
+            if let Some(convergence) = convergence.as_ref() {
+                info!(
+                    "Converged components: {} of {}, frozen entities: {}.",
+                    convergence.converged_count(),
+                    convergence.converged.len(),
+                    convergence.frozen_rows.iter().filter(|&&f| f).count()
+                );
+                if convergence.all_converged() {
+                    info!("All components converged after iter: {}.", i);
+                    break;
+                }
+            }
+
+            if let (Some(early_stop), Some((_, p95))) = (self.early_stop, changes) {
+                if p95 < early_stop as f64 {
+                    info!(
+                        "Embeddings stabilized after iter: {}, 95th percentile of the changes is below {}.",
+                        i, early_stop
+                    );
+                    break;
+                }
+            }
+
+            if summary.iterations < max_iter && interrupt::interrupted() {
+                warn!(
+                    "Stopping after iter: {} as the run was interrupted, {} of {} iterations done.",
+                    i, summary.iterations, max_iter
+                );
+                summary.partial = true;
+                break;
+            }
         }
 
         info!("Done propagating.");
-        new_res
+        let res = match new_res {
+            InputMatrix::Dense(res) => res,
+            sparse => sparse.to_dense(
+                self.number_of_entities,
+                self.dimension,
+                self.sparse_matrix_reader.clone(),
+            ),
+        };
+        Ok((res, summary))
     }
 
-    /// Saves results to output such as textfile, numpy etc
-    fn persist<T1>(
+    /// Values of the given rows, row after row
+    fn sample_rows<V>(&self, get_value: V, rows: &[usize]) -> Vec<f32>
+    where
+        V: Fn(usize, usize) -> M::Value,
+    {
+        rows.iter()
+            .flat_map(|&row| (0..self.dimension).map(move |j| (row, j)))
+            .map(|(row, j)| get_value(row, j).to_f32())
+            .collect()
+    }
+
+    /// Change of every sampled row: 1 - cosine similarity of its embeddings
+    fn sample_changes(&self, previous: &[f32], next: &[f32]) -> Vec<f32> {
+        previous
+            .chunks(self.dimension.max(1))
+            .zip(next.chunks(self.dimension.max(1)))
+            .map(|(x, y)| {
+                let dot: f32 = x.iter().zip(y).map(|(a, b)| a * b).sum();
+                let norm_a = x.iter().map(|a| a * a).sum::<f32>().sqrt();
+                let norm_b = y.iter().map(|b| b * b).sum::<f32>().sqrt();
+                if norm_a > 0f32 && norm_b > 0f32 {
+                    1f32 - dot / (norm_a * norm_b)
+                } else {
+                    0f32
+                }
+            })
+            .collect()
+    }
+
+    /// Change of every row between iterations: 1 - cosine similarity of its embeddings
+    fn row_changes(&self, previous: &InputMatrix<M>, next: &M) -> Vec<f32>
+    where
+        M: Sync,
+    {
+        (0..self.number_of_entities)
+            .into_par_iter()
+            .map(|row| {
+                let (mut dot, mut norm_a, mut norm_b) = (0f32, 0f32, 0f32);
+                for j in 0..self.dimension {
+                    let (x, y) = (
+                        previous.get_value(row, j).to_f32(),
+                        next.get_value(row, j).to_f32(),
+                    );
+                    dot += x * y;
+                    norm_a += x * x;
+                    norm_b += y * y;
+                }
+                if norm_a > 0f32 && norm_b > 0f32 {
+                    1f32 - dot / (norm_a.sqrt() * norm_b.sqrt())
+                } else {
+                    0f32
+                }
+            })
+            .collect()
+    }
+
+    /// Saves intermediate (not postprocessed) embeddings of the iteration, rows aren't sorted
+    fn snapshot<T1>(
         &self,
-        res: M,
-        entity_mapping_persistor: Arc<T1>,
+        iteration: u8,
+        res: &M,
+        entity_mapping_persistor: &T1,
         embedding_persistor: &mut dyn EmbeddingPersistor,
         chunk_size: usize,
-    ) where
+    ) -> Result<(), CleoraError>
+    where
         T1: EntityMappingPersistor,
     {
-        info!("Start saving embeddings.");
+        let order: Vec<usize> = (0..self.number_of_entities).collect();
+        match self.write(
+            res,
+            &order,
+            entity_mapping_persistor,
+            embedding_persistor,
+            chunk_size,
+        ) {
+            Ok(()) => info!("Saved embeddings after iter: {}.", iteration),
+            // the propagation stops after this iteration and the final embeddings are written
+            Err(CleoraError::Interrupted) => warn!(
+                "Embeddings after iter: {} aren't saved as the run was interrupted.",
+                iteration
+            ),
+            Err(err) => return Err(err),
+        }
+        Ok(())
+    }
 
-        embedding_persistor
-            .put_metadata(self.number_of_entities as u32, self.dimension as u16)
-            .unwrap_or_else(|_| {
-                // if can't write first data to the file, probably further is the same
-                panic!(
-                    "Can't write metadata. Entities: {}. Dimension: {}.",
-                    self.number_of_entities, self.dimension
-                )
+    /// Writes rows of the matrix in the given order. When the run is interrupted during the
+    /// writing, it stops after the current chunk and fails with `CleoraError::Interrupted`
+    /// without finishing the output, so it isn't committed with missing rows (rows of
+    /// propagation stopped by an interrupt are all written).
+    fn write<T1>(
+        &self,
+        res: &M,
+        order: &[usize],
+        entity_mapping_persistor: &T1,
+        embedding_persistor: &mut dyn EmbeddingPersistor,
+        chunk_size: usize,
+    ) -> Result<(), CleoraError>
+    where
+        T1: EntityMappingPersistor,
+    {
+        let interrupted_before = interrupt::interrupted();
+        self.write_until(
+            res,
+            order,
+            entity_mapping_persistor,
+            embedding_persistor,
+            chunk_size,
+            || !interrupted_before && interrupt::interrupted(),
+        )
+    }
+
+    /// Writes rows of the matrix in the given order, stops after the chunk when `interrupted`
+    fn write_until<T1>(
+        &self,
+        res: &M,
+        order: &[usize],
+        entity_mapping_persistor: &T1,
+        embedding_persistor: &mut dyn EmbeddingPersistor,
+        chunk_size: usize,
+        interrupted: impl Fn() -> bool,
+    ) -> Result<(), CleoraError>
+    where
+        T1: EntityMappingPersistor,
+    {
+        let hashes: Vec<_> = self.sparse_matrix_reader.iter_hashes().collect();
+
+        // rows of entities without the output tags are left out of the promised count as well
+        let tagged: Vec<usize>;
+        let mut entity_count = self.number_of_entities;
+        let order = if self.output_tags.is_empty() {
+            order
+        } else {
+            tagged = order
+                .iter()
+                .copied()
+                .filter(|&i| entity_mapping_persistor.has_tag(hashes[i].value, &self.output_tags))
+                .collect();
+            entity_count -= order.len() - tagged.len();
+            &tagged
+        };
+
+        embedding_persistor.put_metadata(entity_count as u32, self.dimension as u16)?;
+
+        // the buffers are reused for every chunk, persistors get them borrowed
+        let mut entities = Vec::with_capacity(chunk_size);
+        let mut occur_counts = Vec::with_capacity(chunk_size);
+        let mut values = Vec::with_capacity(chunk_size * self.dimension);
+        for &i in order {
+            let hash = hashes[i];
+            if let Some(entity_name) = entity_mapping_persistor.get_entity(hash.value) {
+                entities.push(entity_name);
+                occur_counts.push(hash.occurrence);
+                values.extend((0..self.dimension).map(|j| res.get_value(i, j).to_f32()));
+
+                if entities.len() == chunk_size {
+                    embedding_persistor.put_data_batch(EmbeddingBatch::new(
+                        &entities,
+                        &occur_counts,
+                        &values,
+                        self.dimension,
+                    ))?;
+                    entities.clear();
+                    occur_counts.clear();
+                    values.clear();
+                    if interrupted() {
+                        warn!("Stopping writing embeddings as the run was interrupted.");
+                        return Err(CleoraError::Interrupted);
+                    }
+                }
+            }
+        }
+
+        if !entities.is_empty() {
+            embedding_persistor.put_data_batch(EmbeddingBatch::new(
+                &entities,
+                &occur_counts,
+                &values,
+                self.dimension,
+            ))?;
+        }
+        embedding_persistor.finish()
+    }
+}
+
+impl<T, M> MatrixMultiplicator<T, M>
+where
+    T: SparseMatrixReader + Sync + Send,
+    M: MatrixWrapper<Value = f32>,
+{
+    /// Rotates the embeddings onto the reference embeddings (e.g. of the previous run) with the
+    /// orthogonal Procrustes rotation fitted on the entities found in both, so embedding spaces of
+    /// consecutive runs stay comparable. The rotation keeps distances and norms of the embeddings.
+    /// The rotated matrix is computed from a copy of the embeddings kept in memory.
+    fn align_to<T1>(&self, res: &mut M, reference: &Embeddings, entity_mapping_persistor: &T1)
+    where
+        T1: EntityMappingPersistor,
+        M: Sync,
+    {
+        let shared: Vec<(usize, usize)> = self
+            .embedding_rows(reference, entity_mapping_persistor)
+            .into_iter()
+            .enumerate()
+            .filter_map(|(row, reference_row)| reference_row.map(|r| (row, r)))
+            .collect();
+        if shared.is_empty() {
+            warn!("No entities shared with the reference embeddings, embeddings aren't aligned.");
+            return;
+        }
+        if shared.len() < self.dimension {
+            warn!(
+                "Only {} entities shared with the reference embeddings of dimension {}, the alignment is underdetermined.",
+                shared.len(),
+                self.dimension
+            );
+        }
+
+        let source = Array2::from_shape_fn((shared.len(), self.dimension), |(i, col)| {
+            res.get_value(shared[i].0, col)
+        });
+        let target = Array2::from_shape_fn((shared.len(), self.dimension), |(i, col)| {
+            reference.vectors[[shared[i].1, col]]
+        });
+        let rotation = orthogonal_procrustes(source.view(), target.view());
+        let rmse = |rotation: Option<&Array2<f32>>| {
+            let residuals = residuals(source.view(), target.view(), rotation);
+            (residuals.iter().map(|r| r * r).sum::<f32>() / residuals.len() as f32).sqrt()
+        };
+        info!(
+            "Aligning to reference embeddings on {} of {} entities, RMSE {} before and {} after rotation.",
+            shared.len(),
+            self.number_of_entities,
+            rmse(None),
+            rmse(Some(&rotation))
+        );
+
+        let current =
+            Array2::from_shape_fn((self.number_of_entities, self.dimension), |(row, col)| {
+                res.get_value(row, col)
             });
+        res.update_columns(|i, column| {
+            let rotated = current.dot(&rotation.column(i));
+            column.copy_from_slice(rotated.as_slice().unwrap());
+        });
+    }
 
-        // entities which can't be written to the file (error occurs)
-        let mut broken_entities = HashSet::new();
-        let mut chunk: (Vec<String>, Vec<u32>, Vec<Vec<f32>>) = (
-            Vec::new(),
-            Vec::new(),
-            (0..self.dimension)
-                .into_iter()
-                .map(|_x| Vec::new())
+    /// Applies postprocessing steps and the final normalization to the propagated matrix.
+    /// Statistics are computed separately for every entity type (column) unless they are
+    /// configured to be shared.
+    fn postprocess(&self, res: &mut M) {
+        if self.postprocess.is_empty() && self.normalize == Normalization::None {
+            return;
+        }
+
+        let entity_types: Vec<u8> = match self.postprocess_stats {
+            StatsScope::PerEntityType => self
+                .sparse_matrix_reader
+                .iter_hashes()
+                .map(|hash| hash.column_id)
                 .collect(),
-        );
+            StatsScope::Shared => vec![0; self.number_of_entities],
+        };
 
-        let mut entity_names: Vec<String> = Vec::new();
-        //let chunk_size: usize = 1000;
+        for step in self.postprocess.iter() {
+            info!("Start postprocessing: {:?}.", step);
+            match step {
+                PostprocessStep::Standardize => {
+                    res.update_columns(|_, column| standardize(column, &entity_types))
+                }
+                PostprocessStep::Winsorize { p } => {
+                    res.update_columns(|_, column| winsorize(column, &entity_types, *p as f64))
+                }
+            }
+        }
 
-        for (i, hash) in self.sparse_matrix_reader.iter_hashes().enumerate() {
-            let entity_name_opt = entity_mapping_persistor.get_entity(hash.value);
-            if let Some(entity_name) = entity_name_opt {
-                chunk.0.push(entity_name.clone());
-                chunk.1.push(hash.occurrence);
-                entity_names.push(entity_name);
+        match self.normalize {
+            Normalization::None => {}
+            Normalization::L2 => {
+                info!("Start L2 normalization.");
+                res.normalize();
+            }
+            Normalization::ZScore => {
+                info!("Start z-score normalization.");
+                res.update_columns(|_, column| standardize(column, &entity_types));
+            }
+        }
+        info!("Done postprocessing.");
+    }
 
-                //let mut embedding: Vec<f32> = Vec::with_capacity(self.dimension);
-                for j in 0..self.dimension {
-                    let value = res.get_value(i, j);
-                    //embedding.push(value);
-                    chunk.2[j].push(value);
+    /// Logs histograms of cosine similarities of sampled linked (with an edge) and unlinked entity
+    /// pairs of the same types. Embeddings with any structure have linked pairs more similar than
+    /// unlinked ones.
+    fn similarity_summary(&self, res: &M) {
+        let entries = self.sparse_matrix_reader.get_number_of_entries() as usize;
+        if self.number_of_entities < 2 || entries == 0 {
+            return;
+        }
+        let cosine = |a: usize, b: usize| -> f32 {
+            let (mut dot, mut norm_a, mut norm_b) = (0f32, 0f32, 0f32);
+            for j in 0..self.dimension {
+                let (x, y) = (res.get_value(a, j), res.get_value(b, j));
+                dot += x * y;
+                norm_a += x * x;
+                norm_b += y * y;
+            }
+            if norm_a > 0f32 && norm_b > 0f32 {
+                dot / (norm_a.sqrt() * norm_b.sqrt())
+            } else {
+                0f32
+            }
+        };
+
+        // entities of the same type as the linked ones, so the unlinked pairs are comparable
+        let mut entities_by_type: Vec<Vec<u32>> = vec![Vec::new(); 256];
+        for (id, hash) in self.sparse_matrix_reader.iter_hashes().enumerate() {
+            entities_by_type[hash.column_id as usize].push(id as u32);
+        }
+        let column_ids: Vec<u8> = self
+            .sparse_matrix_reader
+            .iter_hashes()
+            .map(|hash| hash.column_id)
+            .collect();
+
+        let mut linked = Histogram::new(SIMILARITY_HISTOGRAM_BINS);
+        let mut unlinked_pairs: FxHashSet<(u32, u32)> = FxHashSet::default();
+        let step = (entries / SIMILARITY_SAMPLE_SIZE).max(1);
+        for (i, entry) in self
+            .sparse_matrix_reader
+            .iter_entries()
+            .step_by(step)
+            .enumerate()
+        {
+            if entry.row == entry.col {
+                continue;
+            }
+            linked.add(cosine(entry.row as usize, entry.col as usize));
+            // the row paired with pseudo-random (deterministic for the seed) entity of the
+            // column type
+            let candidates = &entities_by_type[column_ids[entry.col as usize] as usize];
+            let random = mix(i as u64 ^ self.fixed_random_value as u64);
+            let col = candidates[(random % candidates.len() as u64) as usize];
+            if col != entry.row {
+                unlinked_pairs.insert((entry.row, col));
+            }
+        }
+        for entry in self.sparse_matrix_reader.iter_entries() {
+            unlinked_pairs.remove(&(entry.row, entry.col));
+        }
+        let mut unlinked = Histogram::new(SIMILARITY_HISTOGRAM_BINS);
+        for &(a, b) in unlinked_pairs.iter() {
+            unlinked.add(cosine(a as usize, b as usize));
+        }
+
+        let mut summary = format!(
+            "Cosine similarity of {}. Linked pairs: {}, mean {:.3}. Unlinked pairs: {}, mean {:.3}.\n  bin            linked  unlinked",
+            self.sparse_matrix_reader.get_id(),
+            linked.count(),
+            linked.mean().unwrap_or(0f64),
+            unlinked.count(),
+            unlinked.mean().unwrap_or(0f64)
+        );
+        let (linked_fractions, unlinked_fractions) = (linked.fractions(), unlinked.fractions());
+        for bin in 0..SIMILARITY_HISTOGRAM_BINS {
+            summary.push_str(&format!(
+                "\n  [{:+.1}, {:+.1}) {:>7.1}% {:>8.1}%",
+                linked.bin_start(bin),
+                linked.bin_start(bin + 1),
+                linked_fractions[bin] * 100f64,
+                unlinked_fractions[bin] * 100f64
+            ));
+        }
+        info!("{}", summary);
+
+        if let (Some(linked_mean), Some(unlinked_mean)) = (linked.mean(), unlinked.mean()) {
+            if unlinked.count() >= SIMILARITY_MIN_PAIRS && linked_mean <= unlinked_mean {
+                warn!(
+                    "Linked pairs of {} aren't more similar than unlinked ones, the embeddings may carry no structure.",
+                    self.sparse_matrix_reader.get_id()
+                );
+            }
+        }
+    }
+
+    /// Returns indices of the matrix rows in the order they should be written
+    fn output_order<T1>(&self, res: &M, entity_mapping_persistor: &T1) -> Vec<usize>
+    where
+        T1: EntityMappingPersistor,
+        M: Sync,
+    {
+        match self.sort_output {
+            None => (0..self.number_of_entities).collect(),
+            Some(SortOutput::Entity) | Some(SortOutput::Occurrence) => {
+                // rows of entities without names aren't written, they're left at the end
+                let hashes: Vec<_> = self.sparse_matrix_reader.iter_hashes().collect();
+                let names: Vec<Option<Arc<str>>> = hashes
+                    .iter()
+                    .map(|hash| entity_mapping_persistor.get_entity(hash.value))
+                    .collect();
+                let mut order: Vec<usize> = (0..self.number_of_entities).collect();
+                if self.sort_output == Some(SortOutput::Occurrence) {
+                    order.par_sort_by(|&a, &b| {
+                        hashes[b]
+                            .occurrence
+                            .cmp(&hashes[a].occurrence)
+                            .then_with(|| compare_names(&names[a], &names[b]))
+                    });
+                } else {
+                    order.par_sort_by(|&a, &b| compare_names(&names[a], &names[b]));
                 }
+                order
+            }
+            Some(SortOutput::Cluster { clusters }) => {
+                info!("Start clustering output rows. Clusters: {}.", clusters);
+                let row = |i: usize| -> Vec<f32> {
+                    (0..self.dimension).map(|j| res.get_value(i, j)).collect()
+                };
+                let step = (self.number_of_entities / CLUSTER_SAMPLE_SIZE).max(1);
+                let sample: Vec<Vec<f32>> = (0..self.number_of_entities)
+                    .step_by(step)
+                    .map(row)
+                    .collect();
+                if sample.is_empty() {
+                    return vec![];
+                }
+                let kmeans = KMeans::fit(&sample, clusters, CLUSTER_ITERATIONS);
 
-                if i % chunk_size == 0 {
-                    embedding_persistor
-                        .put_data_chunk(chunk)
-                        .unwrap_or_else(|_| {
-                            entity_names.into_iter().for_each(|e| {
-                                broken_entities.insert(e);
-                            });
-                        });
-
-                    entity_names = Vec::new();
-                    chunk = (
-                        Vec::new(),
-                        Vec::new(),
-                        (0..self.dimension)
-                            .into_iter()
-                            .map(|_x| Vec::new())
-                            .collect(),
-                    );
+                let assignments: Vec<usize> = (0..self.number_of_entities)
+                    .into_par_iter()
+                    .map(|i| kmeans.predict(&row(i)))
+                    .collect();
+                let mut order: Vec<usize> = (0..self.number_of_entities).collect();
+                order.sort_by_key(|&i| assignments[i]);
+                info!("Done clustering output rows.");
+                order
+            }
+        }
+    }
+
+    /// Saves results to output such as textfile, numpy etc
+    fn persist<T1>(
+        &self,
+        res: M,
+        entity_mapping_persistor: Arc<T1>,
+        embedding_persistor: &mut dyn EmbeddingPersistor,
+        chunk_size: usize,
+    ) -> Result<(), CleoraError>
+    where
+        T1: EntityMappingPersistor,
+        M: Sync,
+    {
+        // the file has rows of all entities
+        if self.sort_output.is_none() && self.output_tags.is_empty() {
+            if let Some(file) = res.file() {
+                let entity_mapping_persistor = entity_mapping_persistor.as_ref();
+                if self.put_file(&file, entity_mapping_persistor, embedding_persistor)? {
+                    info!("Embeddings file {} moved to the output.", file.path);
+                    return Ok(());
                 }
-            };
+            }
         }
 
-        embedding_persistor
-            .put_data_chunk(chunk)
-            .unwrap_or_else(|_| {
-                entity_names.into_iter().for_each(|e| {
-                    broken_entities.insert(e);
-                });
-            });
+        let order = self.output_order(&res, entity_mapping_persistor.as_ref());
+        info!("Start saving embeddings.");
+        self.write(
+            &res,
+            &order,
+            entity_mapping_persistor.as_ref(),
+            embedding_persistor,
+            chunk_size,
+        )?;
+        info!("Done saving embeddings.");
+        Ok(())
+    }
 
-        if !broken_entities.is_empty() {
-            log_broken_entities(broken_entities);
+    /// Hands the file of the matrix over to the persistor, so the rows aren't copied. Rows of the
+    /// file are in the order of hashes, so it's possible only if all the entities have names
+    /// (none is transient). Returns `false` if the rows have to be written.
+    fn put_file<T1>(
+        &self,
+        file: &ColumnMajorFile,
+        entity_mapping_persistor: &T1,
+        embedding_persistor: &mut dyn EmbeddingPersistor,
+    ) -> Result<bool, CleoraError>
+    where
+        T1: EntityMappingPersistor,
+    {
+        let mut entities = Vec::with_capacity(self.number_of_entities);
+        let mut occur_counts = Vec::with_capacity(self.number_of_entities);
+        for hash in self.sparse_matrix_reader.iter_hashes() {
+            match entity_mapping_persistor.get_entity(hash.value) {
+                Some(entity_name) => entities.push(entity_name),
+                None => return Ok(false),
+            }
+            occur_counts.push(hash.occurrence);
         }
+        if !embedding_persistor.put_column_major_file(file, entities, occur_counts)? {
+            return Ok(false);
+        }
+        embedding_persistor.finish()?;
+        Ok(true)
+    }
+}
 
-        embedding_persistor
-            .finish()
-            .unwrap_or_else(|_| warn!("Can't finish writing to the file."));
+/// Shifts values to zero mean and scales them to unit variance. Mean and variance are computed
+/// separately for every entity type.
+fn standardize(values: &mut [f32], entity_types: &[u8]) {
+    let mut count = [0u64; 256];
+    let mut sum = [0f64; 256];
+    let mut sum_sq = [0f64; 256];
+    for (&value, &entity_type) in values.iter().zip(entity_types) {
+        let t = entity_type as usize;
+        count[t] += 1;
+        sum[t] += value as f64;
+        sum_sq[t] += (value as f64).powi(2);
+    }
 
-        info!("Done saving embeddings.");
+    let mut mean = [0f32; 256];
+    let mut std_dev = [0f32; 256];
+    for t in 0..256 {
+        if count[t] > 0 {
+            let n = count[t] as f64;
+            let m = sum[t] / n;
+            mean[t] = m as f32;
+            std_dev[t] = (sum_sq[t] / n - m * m).max(0f64).sqrt() as f32;
+        }
+    }
+
+    for (value, &entity_type) in values.iter_mut().zip(entity_types) {
+        let t = entity_type as usize;
+        *value -= mean[t];
+        // constant dimension, nothing to scale
+        if std_dev[t] > 0f32 {
+            *value /= std_dev[t];
+        }
     }
 }
 
-fn log_broken_entities(broken_entities: HashSet<String>) {
-    let num_of_broken_entities = broken_entities.len();
-    let few_broken_entities: HashSet<_> = broken_entities
-        .into_iter()
-        .take(LOGGED_NUMBER_OF_BROKEN_ENTITIES)
+/// Clips values to the `1 - p` and `p` quantiles, estimated with streaming sketches separately
+/// for every entity type.
+fn winsorize(values: &mut [f32], entity_types: &[u8], p: f64) {
+    let mut sketches: Vec<Option<(QuantileSketch, QuantileSketch)>> = vec![None; 256];
+    for (&value, &entity_type) in values.iter().zip(entity_types) {
+        let (lower, upper) = sketches[entity_type as usize]
+            .get_or_insert_with(|| (QuantileSketch::new(1f64 - p), QuantileSketch::new(p)));
+        lower.add(value as f64);
+        upper.add(value as f64);
+    }
+
+    let bounds: Vec<Option<(f32, f32)>> = sketches
+        .iter()
+        .map(|sketch| {
+            sketch.as_ref().and_then(|(lower, upper)| {
+                Some((lower.quantile()? as f32, upper.quantile()? as f32))
+            })
+        })
         .collect();
-    warn!(
-        "Number of entities which can't be written to the file: {}. First {} broken entities: {:?}.",
-        num_of_broken_entities, LOGGED_NUMBER_OF_BROKEN_ENTITIES, few_broken_entities
-    );
+
+    for (value, &entity_type) in values.iter_mut().zip(entity_types) {
+        if let Some((min, max)) = bounds[entity_type as usize] {
+            *value = value.max(min).min(max);
+        }
+    }
 }
 
-/// Calculate embeddings with memory-mapped files.
+/// Calculate embeddings with memory-mapped files. Intermediate embeddings are written after every
+/// iteration if `snapshot_persistor` is given.
+#[cfg(feature = "fs")]
 pub fn calculate_embeddings_mmap<T1, T2>(
     config: Arc<Configuration>,
     sparse_matrix_reader: Arc<T1>,
     entity_mapping_persistor: Arc<T2>,
     embedding_persistor: &mut dyn EmbeddingPersistor,
-) where
+    snapshot_persistor: Option<&SnapshotPersistorFactory>,
+    prior: PriorEmbeddings,
+) -> Result<TrainingSummary, CleoraError>
+where
     T1: SparseMatrixReader + Sync + Send,
     T2: EntityMappingPersistor,
 {
-    let mult = MatrixMultiplicator::new(config.clone(), sparse_matrix_reader);
-    let init: MMapMatrix = mult.initialize();
-    let res = mult.propagate(config.max_number_of_iteration, init);
-    mult.persist(
-        res,
-        entity_mapping_persistor,
-        embedding_persistor,
-        config.chunk_size,
-    );
+    match config.precision {
+        Precision::F32 => calculate::<T1, T2, MMapMatrix<f32>>(
+            config,
+            sparse_matrix_reader,
+            entity_mapping_persistor,
+            embedding_persistor,
+            snapshot_persistor,
+            prior,
+        ),
+        Precision::F64 => calculate::<T1, T2, MMapMatrix<f64>>(
+            config,
+            sparse_matrix_reader,
+            entity_mapping_persistor,
+            embedding_persistor,
+            snapshot_persistor,
+            prior,
+        ),
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    use crate::configuration::{
+        Configuration, EntitiesFormat, InitMethod, Normalization, SelfLoops, SortOutput, StatsScope,
+    };
+    use crate::edge_types::EmbeddingCollector;
+    use crate::embedding::{
+        fit_features, percentile, standardize, winsorize, EntityBlocks, InputMatrix, IntoOutput,
+        IterationStats, MMapMatrix, MatrixMultiplicator, MatrixWrapper, Partitioning, SparseInit,
+        TrainingSummary, TwoDimVectorMatrix,
+    };
+    use crate::error::CleoraError;
+    use crate::io::partial_path;
+    use crate::loader::Embeddings;
+    use crate::persistence::embedding::RawPersistor;
+    use crate::persistence::entity::{EntityMappingPersistor, InMemoryEntityMappingPersistor};
+    use crate::sparse_matrix::{SparseMatrix, SparseMatrixReader};
+    use ndarray::arr2;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn multiply_partitioned<M: MatrixWrapper>(sparse_matrix: Arc<SparseMatrix>) {
+        let rows = sparse_matrix.get_number_of_entities() as usize;
+        let cols = 7;
+        let matrix = M::init_with_hashes(rows, cols, 1, InitMethod::Uniform, sparse_matrix.clone());
+        let frozen_rows: Vec<bool> = (0..rows).map(|row| row % 3 == 0).collect();
+        for frozen_rows in [None, Some(frozen_rows.as_slice())] {
+            let expected = M::multiply(
+                sparse_matrix.clone(),
+                &matrix,
+                frozen_rows,
+                &Partitioning::DimensionBlocks(1),
+            );
+            for partitioning in [
+                Partitioning::DimensionBlocks(3),
+                Partitioning::EntityBlocks(EntityBlocks::new(sparse_matrix.as_ref(), 4)),
+            ] {
+                let result =
+                    M::multiply(sparse_matrix.clone(), &matrix, frozen_rows, &partitioning);
+                for row in 0..rows {
+                    for col in 0..cols {
+                        assert_eq!(expected.get_value(row, col), result.get_value(row, col));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn multiply_with_every_partitioning() {
+        let mut sparse_matrix =
+            SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+        let users = [1, 1, 2, 3, 3, 4, 1];
+        let items = [10, 11, 10, 12, 10, 13, 12];
+        for (user, item) in users.iter().zip(items.iter()) {
+            sparse_matrix.handle_pair(&[1, *user, *item]);
+        }
+        sparse_matrix.finish();
+        let sparse_matrix = Arc::new(sparse_matrix);
+
+        let blocks = EntityBlocks::new(sparse_matrix.as_ref(), 4);
+        let covered: usize = blocks.blocks.iter().map(|(rows, _)| rows.len()).sum();
+        assert_eq!(sparse_matrix.get_number_of_entities() as usize, covered);
+        assert!(blocks.blocks.len() <= 4);
+
+        multiply_partitioned::<TwoDimVectorMatrix>(sparse_matrix.clone());
+        multiply_partitioned::<MMapMatrix>(sparse_matrix.clone());
+        multiply_partitioned::<TwoDimVectorMatrix<f64>>(sparse_matrix.clone());
+        multiply_partitioned::<MMapMatrix<f64>>(sparse_matrix);
+    }
+
+    #[test]
+    fn weigh_columns_against_self_loops() {
+        let embed = |weight: f32, self_loops: SelfLoops| {
+            let mut sparse_matrix =
+                SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+            sparse_matrix.set_weight(weight);
+            sparse_matrix.set_self_loops(self_loops);
+            for (user, item) in [(1, 10), (1, 11), (2, 10), (3, 11)] {
+                sparse_matrix.handle_pair(&[1, user, item]);
+            }
+            sparse_matrix.finish();
+            let sparse_matrix = Arc::new(sparse_matrix);
+            let rows = sparse_matrix.get_number_of_entities() as usize;
+            let matrix = TwoDimVectorMatrix::<f32>::init_with_hashes(
+                rows,
+                4,
+                1,
+                InitMethod::Uniform,
+                sparse_matrix.clone(),
+            );
+            let partitioning = Partitioning::DimensionBlocks(1);
+            let matrix = TwoDimVectorMatrix::multiply(sparse_matrix, &matrix, None, &partitioning);
+            (0..rows)
+                .flat_map(|row| (0..4).map(move |col| (row, col)))
+                .map(|(row, col)| matrix.get_value(row, col))
+                .collect::<Vec<f32>>()
+        };
+
+        let differ =
+            |a: Vec<f32>, b: Vec<f32>| a.iter().zip(b.iter()).any(|(a, b)| (a - b).abs() > 1e-3);
+        // the common factor of the relations cancels out in normalization
+        assert!(!differ(
+            embed(1f32, SelfLoops::Keep),
+            embed(0.3, SelfLoops::Keep)
+        ));
+        assert!(differ(
+            embed(1f32, SelfLoops::Add(1f32)),
+            embed(0.3, SelfLoops::Add(1f32))
+        ));
+    }
+
+    fn multiply_in_double_precision<M: IntoOutput>(sparse_matrix: Arc<SparseMatrix>) {
+        let rows = sparse_matrix.get_number_of_entities() as usize;
+        let cols = 4;
+        let init =
+            || M::init_with_hashes(rows, cols, 1, InitMethod::Uniform, sparse_matrix.clone());
+        let single = TwoDimVectorMatrix::<f32>::init_with_hashes(
+            rows,
+            cols,
+            1,
+            InitMethod::Uniform,
+            sparse_matrix.clone(),
+        );
+        let (mut single, mut double) = (single, init());
+        for _ in 0..3 {
+            let partitioning = Partitioning::DimensionBlocks(1);
+            single =
+                TwoDimVectorMatrix::multiply(sparse_matrix.clone(), &single, None, &partitioning);
+            double = M::multiply(sparse_matrix.clone(), &double, None, &partitioning);
+        }
+        let double = double.into_output();
+        for row in 0..rows {
+            for col in 0..cols {
+                let (expected, found) = (single.get_value(row, col), double.get_value(row, col));
+                assert!((expected - found).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn multiply_in_f64_matrices() {
+        let mut sparse_matrix =
+            SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+        let users = [1, 1, 2, 3, 3, 4, 1];
+        let items = [10, 11, 10, 12, 10, 13, 12];
+        for (user, item) in users.iter().zip(items.iter()) {
+            sparse_matrix.handle_pair(&[1, *user, *item]);
+        }
+        sparse_matrix.finish();
+        let sparse_matrix = Arc::new(sparse_matrix);
+
+        multiply_in_double_precision::<TwoDimVectorMatrix<f64>>(sparse_matrix.clone());
+        multiply_in_double_precision::<MMapMatrix<f64>>(sparse_matrix);
+    }
+
+    fn propagate_sparse_init<M: MatrixWrapper<Value = f32> + Sync>(
+        sparse_matrix: Arc<SparseMatrix>,
+    ) {
+        let init_method = InitMethod::SparseRandomProjection { density: 0.25 };
+        let mut config = Configuration::default(String::new(), vec![]);
+        config.embeddings_dimension = 16;
+        config.init_method = init_method;
+        config.alpha = 0.2;
+        let mult: MatrixMultiplicator<SparseMatrix, M> =
+            MatrixMultiplicator::new(Arc::new(config), sparse_matrix.clone());
+        let rows = sparse_matrix.get_number_of_entities() as usize;
+        let dense = M::init_with_hashes(rows, 16, 0, init_method, sparse_matrix.clone());
+        let frozen_rows: Vec<bool> = (0..rows).map(|row| row % 3 == 0).collect();
+        for frozen_rows in [None, Some(frozen_rows.as_slice())] {
+            let expected = M::multiply(
+                sparse_matrix.clone(),
+                &dense,
+                frozen_rows,
+                &Partitioning::DimensionBlocks(1),
+            );
+            let result = mult.input_matrix().multiply(
+                sparse_matrix.clone(),
+                frozen_rows,
+                &Partitioning::DimensionBlocks(1),
+            );
+            for row in 0..rows {
+                for col in 0..16 {
+                    assert_eq!(expected.get_value(row, col), result.get_value(row, col));
+                }
+            }
+        }
+
+        let (expected, _) = mult
+            .propagate(3, InputMatrix::Dense(dense), |_, _| Ok(()))
+            .unwrap();
+        let (result, _) = mult
+            .propagate(3, mult.input_matrix(), |_, _| Ok(()))
+            .unwrap();
+        for row in 0..rows {
+            for col in 0..16 {
+                assert!((expected.get_value(row, col) - result.get_value(row, col)).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn keep_non_zero_values_of_sparse_init() {
+        let mut sparse_matrix =
+            SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+        let users = [1, 1, 2, 3, 3, 4, 1, 5, 6, 6];
+        let items = [10, 11, 10, 12, 10, 13, 12, 14, 14, 11];
+        for (user, item) in users.iter().zip(items.iter()) {
+            sparse_matrix.handle_pair(&[1, *user, *item]);
+        }
+        sparse_matrix.finish();
+        let sparse_matrix = Arc::new(sparse_matrix);
+        let rows = sparse_matrix.get_number_of_entities() as usize;
+        let (cols, density) = (64, 0.25);
+
+        let init = SparseInit::new(rows, cols, 7, density, sparse_matrix.as_ref());
+        let dense = TwoDimVectorMatrix::<f32>::init_with_hashes(
+            rows,
+            cols,
+            7,
+            InitMethod::SparseRandomProjection { density },
+            sparse_matrix.clone(),
+        );
+        let mut non_zero = 0;
+        for row in 0..rows {
+            for col in 0..cols {
+                let value = dense.get_value(row, col);
+                assert_eq!(value, init.get_value(row, col));
+                if value != 0f32 {
+                    non_zero += 1;
+                }
+            }
+        }
+        assert_eq!(non_zero, init.values.len());
+        assert!(non_zero > 0 && non_zero < rows * cols / 2);
+
+        // normalized rows have unit length
+        let normalized: InputMatrix<TwoDimVectorMatrix> =
+            InputMatrix::Sparse(init).normalized(rows, cols, sparse_matrix.clone());
+        for row in 0..rows {
+            let norm: f32 = (0..cols)
+                .map(|col| normalized.get_value(row, col).powi(2))
+                .sum();
+            assert!((norm - 1f32).abs() < 1e-5);
+        }
+
+        propagate_sparse_init::<TwoDimVectorMatrix>(sparse_matrix.clone());
+        propagate_sparse_init::<MMapMatrix>(sparse_matrix);
+    }
+
+    fn continue_from_previous<M: MatrixWrapper<Value = f32> + Sync>(
+        sparse_matrix: Arc<SparseMatrix>,
+    ) {
+        let mut config = Configuration::default(String::new(), vec![]);
+        config.embeddings_dimension = 2;
+        let mult: MatrixMultiplicator<SparseMatrix, M> =
+            MatrixMultiplicator::new(Arc::new(config), sparse_matrix.clone());
+        let entity_mapping = InMemoryEntityMappingPersistor::default();
+        entity_mapping.put_data(1, String::from("u1"));
+        entity_mapping.put_data(10, String::from("i10"));
+        entity_mapping.put_data(11, String::from("i11"));
+        let previous = Embeddings {
+            entities: vec![
+                String::from("i10"),
+                String::from("gone"),
+                String::from("u1"),
+            ],
+            vectors: arr2(&[[0.5, -0.5], [1.0, 1.0], [0.25, 0.75]]),
+        };
+
+        let initial: M = mult.initialize();
+        let mut res: M = mult.initialize();
+        mult.continue_from(&mut res, &previous, &entity_mapping);
+        for (row, hash) in sparse_matrix.iter_hashes().enumerate() {
+            let expected: Vec<f32> = match hash.value {
+                1 => vec![0.25, 0.75],
+                10 => vec![0.5, -0.5],
+                // new entity keeps the initial vector
+                _ => (0..2).map(|col| initial.get_value(row, col)).collect(),
+            };
+            let found: Vec<f32> = (0..2).map(|col| res.get_value(row, col)).collect();
+            assert_eq!(expected, found);
+        }
+    }
+
+    #[test]
+    fn continue_from_previous_embeddings() {
+        let mut sparse_matrix =
+            SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+        sparse_matrix.handle_pair(&[2, 1, 10]);
+        sparse_matrix.handle_pair(&[2, 1, 11]);
+        sparse_matrix.finish();
+        let sparse_matrix = Arc::new(sparse_matrix);
+
+        continue_from_previous::<TwoDimVectorMatrix>(sparse_matrix.clone());
+        continue_from_previous::<MMapMatrix>(sparse_matrix);
+    }
+
+    #[test]
+    fn write_tagged_entities() {
+        let mut sparse_matrix =
+            SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+        sparse_matrix.handle_pair(&[2, 1, 10]);
+        sparse_matrix.handle_pair(&[2, 1, 11]);
+        sparse_matrix.finish();
+        let mut config = Configuration::default(String::new(), vec![]);
+        config.embeddings_dimension = 2;
+        config.output_tags = vec![String::from("type=product")];
+        let mult: MatrixMultiplicator<SparseMatrix, TwoDimVectorMatrix> =
+            MatrixMultiplicator::new(Arc::new(config), Arc::new(sparse_matrix));
+        let entity_mapping = InMemoryEntityMappingPersistor::default();
+        entity_mapping.put_data(1, String::from("u1"));
+        entity_mapping.put_data(10, String::from("i10"));
+        entity_mapping.put_data(11, String::from("i11"));
+        entity_mapping.put_tag(10, "type=product");
+        entity_mapping.put_tag(1, "type=user");
+
+        let res: TwoDimVectorMatrix = mult.initialize();
+        let mut written = EmbeddingCollector::default();
+        mult.write(&res, &[0, 1, 2], &entity_mapping, &mut written, 1)
+            .unwrap();
+        assert_eq!(
+            vec!["i10"],
+            written.entities.iter().map(|e| &**e).collect::<Vec<_>>()
+        );
+        assert_eq!(2, written.values.len());
+    }
+
+    #[test]
+    fn write_shared_entities() {
+        let mut sparse_matrix =
+            SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+        sparse_matrix.handle_pair(&[2, 1, 10]);
+        sparse_matrix.handle_pair(&[2, 1, 11]);
+        sparse_matrix.finish();
+        let hashes: Vec<u64> = sparse_matrix.iter_hashes().map(|h| h.value).collect();
+        let mut config = Configuration::default(String::new(), vec![]);
+        config.embeddings_dimension = 2;
+        let mult: MatrixMultiplicator<SparseMatrix, TwoDimVectorMatrix> =
+            MatrixMultiplicator::new(Arc::new(config), Arc::new(sparse_matrix));
+        let entity_mapping = InMemoryEntityMappingPersistor::default();
+        entity_mapping.put_data(1, String::from("u1"));
+        entity_mapping.put_data(10, String::from("i10"));
+        entity_mapping.put_data(11, String::from("i11"));
+
+        let res: TwoDimVectorMatrix = mult.initialize();
+        let mut written = EmbeddingCollector::default();
+        mult.write(&res, &[0, 1, 2], &entity_mapping, &mut written, 2)
+            .unwrap();
+        assert_eq!(3, written.entities.len());
+        for (entity, &hash) in written.entities.iter().zip(hashes.iter()) {
+            // the persistor gets the entity of the mapping, not a copy of it
+            let stored = entity_mapping.get_entity(hash).unwrap();
+            assert!(Arc::ptr_eq(&stored, entity));
+        }
+        assert_eq!(Some("u1".into()), entity_mapping.get_entity(1));
+        assert_eq!(Some("i10".into()), entity_mapping.get_entity(10));
+        assert_eq!(Some("i11".into()), entity_mapping.get_entity(11));
+    }
+
+    #[test]
+    fn leave_interrupted_output_uncommitted() {
+        let mut sparse_matrix =
+            SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+        sparse_matrix.handle_pair(&[2, 1, 10]);
+        sparse_matrix.handle_pair(&[2, 1, 11]);
+        sparse_matrix.finish();
+        let mut config = Configuration::default(String::new(), vec![]);
+        config.embeddings_dimension = 2;
+        let mult: MatrixMultiplicator<SparseMatrix, TwoDimVectorMatrix> =
+            MatrixMultiplicator::new(Arc::new(config), Arc::new(sparse_matrix));
+        let entity_mapping = InMemoryEntityMappingPersistor::default();
+        entity_mapping.put_data(1, String::from("u1"));
+        entity_mapping.put_data(10, String::from("i10"));
+        entity_mapping.put_data(11, String::from("i11"));
+
+        let filename = std::env::temp_dir().join("cleora_leave_interrupted_output_uncommitted");
+        let filename = filename.to_str().unwrap().to_string();
+        let array_file_name = format!("{}.bin", filename);
+        let res: TwoDimVectorMatrix = mult.initialize();
+        let mut persistor =
+            RawPersistor::new(filename.clone(), false, EntitiesFormat::Json).unwrap();
+        let interrupted = || true;
+        let result = mult.write_until(
+            &res,
+            &[0, 1, 2],
+            &entity_mapping,
+            &mut persistor,
+            1,
+            interrupted,
+        );
+        drop(persistor);
+        assert!(matches!(result, Err(CleoraError::Interrupted)));
+        assert!(!Path::new(&array_file_name).exists());
+        assert!(Path::new(&partial_path(&array_file_name)).exists());
+        std::fs::remove_file(partial_path(&array_file_name)).unwrap();
+        std::fs::remove_file(partial_path(&format!("{}.entities", filename))).unwrap();
+    }
+
+    #[test]
+    fn sort_output_rows() {
+        let mut sparse_matrix =
+            SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+        let users = [1, 1, 2, 3, 3, 4, 1];
+        let items = [10, 11, 10, 12, 12, 13, 12];
+        for (user, item) in users.iter().zip(items.iter()) {
+            sparse_matrix.handle_pair(&[1, *user, *item]);
+        }
+        sparse_matrix.finish();
+        let sparse_matrix = Arc::new(sparse_matrix);
+        let entity_mapping = InMemoryEntityMappingPersistor::default();
+        for hash in [1, 2, 3, 4, 10, 11, 12] {
+            let prefix = if hash < 10 { "u" } else { "i" };
+            entity_mapping.put_data(hash, format!("{}{}", prefix, hash));
+        }
+        let sorted = |sort_output: SortOutput| -> Vec<String> {
+            let mut config = Configuration::default(String::new(), vec![]);
+            config.embeddings_dimension = 2;
+            config.sort_output = Some(sort_output);
+            let mult: MatrixMultiplicator<SparseMatrix, TwoDimVectorMatrix> =
+                MatrixMultiplicator::new(Arc::new(config), sparse_matrix.clone());
+            let hashes: Vec<u64> = sparse_matrix.iter_hashes().map(|h| h.value).collect();
+            mult.output_order(&mult.initialize(), &entity_mapping)
+                .into_iter()
+                .map(|row| match entity_mapping.get_entity(hashes[row]) {
+                    Some(entity) => entity.to_string(),
+                    None => String::from("?"),
+                })
+                .collect()
+        };
+
+        // entity without name is the last one
+        assert_eq!(
+            vec!["i10", "i11", "i12", "u1", "u2", "u3", "u4", "?"],
+            sorted(SortOutput::Entity)
+        );
+        assert_eq!(
+            vec!["i12", "u1", "i10", "u3", "i11", "u2", "u4", "?"],
+            sorted(SortOutput::Occurrence)
+        );
+    }
+
+    #[test]
+    fn stop_early_when_stable() {
+        let mut sparse_matrix =
+            SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+        let users = [1, 1, 2, 3, 3, 4, 1];
+        let items = [10, 11, 10, 12, 10, 13, 12];
+        for (user, item) in users.iter().zip(items.iter()) {
+            sparse_matrix.handle_pair(&[1, *user, *item]);
+        }
+        sparse_matrix.finish();
+        let sparse_matrix = Arc::new(sparse_matrix);
+        let propagate = |early_stop: Option<f32>| {
+            let mut config = Configuration::default(String::new(), vec![]);
+            config.embeddings_dimension = 8;
+            config.early_stop = early_stop;
+            let mult: MatrixMultiplicator<SparseMatrix, TwoDimVectorMatrix> =
+                MatrixMultiplicator::new(Arc::new(config), sparse_matrix.clone());
+            let (_, summary) = mult
+                .propagate(5, InputMatrix::Dense(mult.initialize()), |_, _| Ok(()))
+                .unwrap();
+            summary
+        };
+
+        let summary = propagate(None);
+        assert_eq!(5, summary.iterations);
+        // the first iteration has no iteration before the previous one to compare with
+        assert_eq!(None, summary.iteration_stats[0].p95_row_change);
+        for stats in summary.iteration_stats[1..].iter() {
+            let (mean, p95) = (
+                stats.mean_row_change.unwrap(),
+                stats.p95_row_change.unwrap(),
+            );
+            assert!((0.0..=2.0).contains(&mean) && (0.0..=2.0).contains(&p95));
+        }
+        // changes are at most 2, the second iteration is the first one measured
+        let summary = propagate(Some(2.5));
+        assert_eq!(2, summary.iterations);
+        assert!(!summary.partial);
+
+        assert_eq!(0.0, percentile(&[], 0.95));
+        let values: Vec<f32> = (1..=100).rev().map(|v| v as f32).collect();
+        assert_eq!(95.0, percentile(&values, 0.95));
+        assert_eq!(100.0, percentile(&values, 1.0));
+    }
+
+    #[test]
+    fn stop_at_deadline() {
+        let mut sparse_matrix =
+            SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+        sparse_matrix.handle_pair(&[1, 1, 10]);
+        sparse_matrix.handle_pair(&[1, 2, 10]);
+        sparse_matrix.finish();
+        let sparse_matrix = Arc::new(sparse_matrix);
+        let propagate = |deadline: Instant| {
+            let mut config = Configuration::default(String::new(), vec![]);
+            config.embeddings_dimension = 8;
+            config.deadline = Some(deadline);
+            let mult: MatrixMultiplicator<SparseMatrix, TwoDimVectorMatrix> =
+                MatrixMultiplicator::new(Arc::new(config), sparse_matrix.clone());
+            let (res, summary) = mult
+                .propagate(3, InputMatrix::Dense(mult.initialize()), |_, _| Ok(()))
+                .unwrap();
+            (mult.initialize(), res, summary)
+        };
+
+        let (_, _, summary) = propagate(Instant::now() + Duration::from_secs(3600));
+        assert_eq!(3, summary.iterations);
+        assert!(!summary.partial);
+
+        // propagation starting after the deadline is skipped, the initial vectors are written
+        let (init, res, summary) = propagate(Instant::now());
+        assert_eq!(0, summary.iterations);
+        assert!(summary.partial);
+        for row in 0..3 {
+            for col in 0..8 {
+                assert_eq!(init.get_value(row, col), res.get_value(row, col));
+            }
+        }
+    }
+
+    #[test]
+    fn init_from_feature_vectors() {
+        let mut sparse_matrix =
+            SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+        sparse_matrix.handle_pair(&[2, 1, 10]);
+        sparse_matrix.handle_pair(&[2, 1, 11]);
+        sparse_matrix.finish();
+        let sparse_matrix = Arc::new(sparse_matrix);
+        let mut config = Configuration::default(String::new(), vec![]);
+        config.embeddings_dimension = 2;
+        let mult: MatrixMultiplicator<SparseMatrix, TwoDimVectorMatrix> =
+            MatrixMultiplicator::new(Arc::new(config), sparse_matrix.clone());
+        let entity_mapping = InMemoryEntityMappingPersistor::default();
+        entity_mapping.put_data(1, String::from("u1"));
+        entity_mapping.put_data(10, String::from("i10"));
+        entity_mapping.put_data(11, String::from("i11"));
+        // one-dimensional features are padded, zero vector isn't used
+        let features = Embeddings {
+            entities: vec![String::from("u1"), String::from("i10")],
+            vectors: arr2(&[[-3.0], [0.0]]),
+        };
+        let features = fit_features(features, 2, None);
+        assert_eq!(arr2(&[[-3.0, 0.0], [0.0, 0.0]]), features.vectors);
+
+        let initial: TwoDimVectorMatrix = mult.initialize();
+        let mut res: TwoDimVectorMatrix = mult.initialize();
+        mult.init_from_features(&mut res, &features, &entity_mapping);
+        for (row, hash) in sparse_matrix.iter_hashes().enumerate() {
+            let initial: Vec<f32> = (0..2).map(|col| initial.get_value(row, col)).collect();
+            let found: Vec<f32> = (0..2).map(|col| res.get_value(row, col)).collect();
+            if hash.value == 1 {
+                // scaled to the norm of the initial vector
+                let norm = initial.iter().map(|v| v * v).sum::<f32>().sqrt();
+                assert_eq!(vec![-norm, 0.0], found);
+            } else {
+                assert_eq!(initial, found);
+            }
+        }
+
+        // with damping, the feature vectors are mixed back after every iteration
+        let mut config = Configuration::default(String::new(), vec![]);
+        config.embeddings_dimension = 2;
+        config.alpha = 1f32;
+        let mult: MatrixMultiplicator<SparseMatrix, TwoDimVectorMatrix> =
+            MatrixMultiplicator::new(Arc::new(config), sparse_matrix.clone());
+        let (res, _) = mult
+            .propagate(2, InputMatrix::Dense(res), |_, _| Ok(()))
+            .unwrap();
+        let row = sparse_matrix
+            .iter_hashes()
+            .position(|h| h.value == 1)
+            .unwrap();
+        assert!((res.get_value(row, 0) + 1f32).abs() < 1e-6);
+        assert_eq!(0f32, res.get_value(row, 1));
+
+        // longer features are projected with the same signs for the seed
+        let features = Embeddings {
+            entities: vec![String::from("u1")],
+            vectors: arr2(&[[1.0, -2.0, 0.5, 4.0]]),
+        };
+        let projected = fit_features(features.clone(), 2, Some(7));
+        assert_eq!((1, 2), projected.vectors.dim());
+        assert_eq!(
+            projected.vectors,
+            fit_features(features, 2, Some(7)).vectors
+        );
+    }
+
+    fn align_to_rotated_reference<M: MatrixWrapper<Value = f32> + Sync>(
+        sparse_matrix: Arc<SparseMatrix>,
+    ) {
+        let mut config = Configuration::default(String::new(), vec![]);
+        config.embeddings_dimension = 2;
+        let mult: MatrixMultiplicator<SparseMatrix, M> =
+            MatrixMultiplicator::new(Arc::new(config), sparse_matrix.clone());
+        let entity_mapping = InMemoryEntityMappingPersistor::default();
+        let names = ["u1", "i10", "i11"];
+        for (hash, name) in [1, 10, 11].iter().zip(names.iter()) {
+            entity_mapping.put_data(*hash, name.to_string());
+        }
+
+        // reference is the embeddings rotated by 90 degrees
+        let mut res: M = mult.initialize();
+        let rotated: Vec<[f32; 2]> = (0..3)
+            .map(|row| [-res.get_value(row, 1), res.get_value(row, 0)])
+            .collect();
+        let reference = Embeddings {
+            entities: sparse_matrix
+                .iter_hashes()
+                .map(|hash| entity_mapping.get_entity(hash.value).unwrap().to_string())
+                .collect(),
+            vectors: arr2(&rotated),
+        };
+        mult.align_to(&mut res, &reference, &entity_mapping);
+        for (row, expected) in rotated.iter().enumerate() {
+            for (col, expected) in expected.iter().enumerate() {
+                assert!((res.get_value(row, col) - expected).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn align_to_reference_embeddings() {
+        let mut sparse_matrix =
+            SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+        sparse_matrix.handle_pair(&[2, 1, 10]);
+        sparse_matrix.handle_pair(&[2, 1, 11]);
+        sparse_matrix.finish();
+        let sparse_matrix = Arc::new(sparse_matrix);
+
+        align_to_rotated_reference::<TwoDimVectorMatrix>(sparse_matrix.clone());
+        align_to_rotated_reference::<MMapMatrix>(sparse_matrix);
+    }
+
+    fn normalize_final<M: MatrixWrapper<Value = f32>>(sparse_matrix: Arc<SparseMatrix>) {
+        let rows = sparse_matrix.get_number_of_entities() as usize;
+        let cols = 4;
+        let normalized = |normalize: Normalization, postprocess_stats: StatsScope| -> M {
+            let mut config = Configuration::default(String::new(), vec![]);
+            config.embeddings_dimension = cols as u16;
+            config.normalize = normalize;
+            config.postprocess_stats = postprocess_stats;
+            let mult: MatrixMultiplicator<SparseMatrix, M> =
+                MatrixMultiplicator::new(Arc::new(config), sparse_matrix.clone());
+            let mut res = mult.initialize();
+            mult.postprocess(&mut res);
+            res
+        };
 
-    info!("Finalizing embeddings calculations!")
+        // not normalized by default
+        let initial: M = normalized(Normalization::None, StatsScope::PerEntityType);
+        let expected: M = MatrixMultiplicator::<SparseMatrix, M>::new(
+            Arc::new(Configuration::default(String::new(), vec![])),
+            sparse_matrix.clone(),
+        )
+        .initialize();
+        for row in 0..rows {
+            for col in 0..cols {
+                assert_eq!(expected.get_value(row, col), initial.get_value(row, col));
+            }
+        }
+
+        // unit length of every embedding
+        let l2: M = normalized(Normalization::L2, StatsScope::PerEntityType);
+        for row in 0..rows {
+            let norm: f32 = (0..cols).map(|col| l2.get_value(row, col).powi(2)).sum();
+            assert!((norm - 1f32).abs() < 1e-5, "{}", norm);
+        }
+
+        // zero mean and unit variance of every dimension
+        let zscore: M = normalized(Normalization::ZScore, StatsScope::Shared);
+        for col in 0..cols {
+            let values: Vec<f32> = (0..rows).map(|row| zscore.get_value(row, col)).collect();
+            let mean = values.iter().sum::<f32>() / rows as f32;
+            let variance = values.iter().map(|value| value.powi(2)).sum::<f32>() / rows as f32;
+            assert!(mean.abs() < 1e-5, "{}", mean);
+            assert!((variance - 1f32).abs() < 1e-4, "{}", variance);
+        }
+
+        // or of every entity type
+        let zscore: M = normalized(Normalization::ZScore, StatsScope::PerEntityType);
+        let entity_types: Vec<u8> = sparse_matrix
+            .iter_hashes()
+            .map(|hash| hash.column_id)
+            .collect();
+        for entity_type in [0, 1] {
+            let rows: Vec<usize> = (0..rows)
+                .filter(|row| entity_types[*row] == entity_type)
+                .collect();
+            for col in 0..cols {
+                let mean = rows
+                    .iter()
+                    .map(|row| zscore.get_value(*row, col))
+                    .sum::<f32>()
+                    / rows.len() as f32;
+                assert!(mean.abs() < 1e-5, "{}", mean);
+            }
+        }
+    }
+
+    #[test]
+    fn normalize_final_embeddings() {
+        let mut sparse_matrix =
+            SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+        sparse_matrix.handle_pair(&[2, 1, 10]);
+        sparse_matrix.handle_pair(&[2, 2, 10]);
+        sparse_matrix.handle_pair(&[2, 3, 11]);
+        sparse_matrix.handle_pair(&[2, 1, 12]);
+        sparse_matrix.finish();
+        let sparse_matrix = Arc::new(sparse_matrix);
+
+        normalize_final::<TwoDimVectorMatrix>(sparse_matrix.clone());
+        normalize_final::<MMapMatrix>(sparse_matrix);
+    }
+
+    #[test]
+    fn standardize_per_entity_type() {
+        let mut values = vec![1.0, 3.0, 10.0, 20.0, 30.0];
+        let entity_types = vec![0, 0, 1, 1, 1];
+        standardize(&mut values, &entity_types);
+
+        let expected = vec![-1.0, 1.0, -1.2247449, 0.0, 1.2247449];
+        for (value, expected) in values.iter().zip(expected) {
+            assert!((value - expected).abs() < 1e-5, "{} != {}", value, expected);
+        }
+    }
+
+    #[test]
+    fn standardize_constant_values() {
+        let mut values = vec![2.0, 2.0, 2.0];
+        standardize(&mut values, &[0, 0, 0]);
+        assert_eq!(vec![0.0, 0.0, 0.0], values);
+    }
+
+    #[test]
+    fn winsorize_per_entity_type() {
+        // outliers at the end of both entity types
+        let mut values: Vec<f32> = (0..1000).map(|i| (i % 10) as f32).collect();
+        values.extend((0..1000).map(|i| -((i % 10) as f32)));
+        values[999] = 1000.0;
+        values[1999] = -1000.0;
+        let entity_types: Vec<u8> = (0..2000).map(|i| (i / 1000) as u8).collect();
+
+        winsorize(&mut values, &entity_types, 0.99);
+
+        // sketches give estimates, the outliers are clipped close to the regular values
+        assert!(values[999] < 100.0);
+        assert!(values[1999] > -100.0);
+        assert_eq!(5.0, values[5]);
+        assert_eq!(-5.0, values[1005]);
+    }
+
+    #[test]
+    fn merge_training_summaries() {
+        let stats = |seconds, converged: Option<usize>| IterationStats {
+            seconds,
+            mean_row_change: converged.map(|c| c as f64 / 10.0),
+            p95_row_change: converged.map(|c| c as f64 / 5.0),
+            converged_components: converged,
+        };
+        let mut summary = TrainingSummary {
+            iterations: 4,
+            partial: false,
+            interrupted: false,
+            iteration_stats: vec![],
+        };
+        summary.merge(TrainingSummary {
+            iterations: 2,
+            partial: false,
+            interrupted: false,
+            iteration_stats: vec![stats(1.0, Some(1)), stats(1.0, Some(2))],
+        });
+        summary.merge(TrainingSummary {
+            iterations: 3,
+            partial: true,
+            interrupted: true,
+            iteration_stats: vec![stats(0.5, Some(3)), stats(0.5, None), stats(2.0, None)],
+        });
+        assert_eq!(2, summary.iterations);
+        assert!(summary.partial);
+        assert!(summary.interrupted);
+        assert_eq!(
+            vec![
+                IterationStats {
+                    seconds: 1.5,
+                    mean_row_change: Some(0.3),
+                    p95_row_change: Some(0.6),
+                    converged_components: Some(4),
+                },
+                IterationStats {
+                    seconds: 1.5,
+                    mean_row_change: Some(0.2),
+                    p95_row_change: Some(0.4),
+                    converged_components: Some(2),
+                },
+                stats(2.0, None),
+            ],
+            summary.iteration_stats
+        );
+    }
 }
@@ -1,7 +1,13 @@
-use crate::configuration::{Column, Configuration};
-use crate::persistence::entity::EntityMappingPersistor;
+use crate::alias::Aliases;
+use crate::cardinality::CardinalityMonitor;
+use crate::configuration::{extract_timestamp, Configuration, HashFunction};
+use crate::error::CleoraError;
+use crate::persistence::entity::{EntityMappingPersistor, InMemoryEntityMappingPersistor};
+use rustc_hash::FxHasher;
 use smallvec::{smallvec, SmallVec};
+use std::fmt;
 use std::hash::Hasher;
+use std::str::{self, Utf8Error};
 use std::sync::Arc;
 use twox_hash::XxHash64;
 
@@ -9,6 +15,49 @@ use twox_hash::XxHash64;
 /// of the vector is placed on Heap.
 pub const SMALL_VECTOR_SIZE: usize = 8;
 
+/// Number of low bits holding the entity id with identity hashing. The column index is placed in
+/// the higher bits, so the same id in different columns gives different keys.
+pub const IDENTITY_ID_BITS: u32 = 56;
+
+/// Row which can't be processed, it's skipped without providing any combinations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidRow {
+    Weight(String),
+    Timestamp(String),
+    Count(String),
+    EdgeType(String),
+    /// Entity which isn't a valid id for identity hashing, with its column
+    EntityId {
+        column: String,
+        value: String,
+    },
+    /// Entity stored in the entity mapping or tag which isn't valid UTF-8, only in raw rows
+    Utf8(Utf8Error),
+}
+
+impl fmt::Display for InvalidRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidRow::Weight(value) => write!(f, "Invalid row weight [{}]", value),
+            InvalidRow::Timestamp(value) => write!(f, "Invalid row timestamp [{}]", value),
+            InvalidRow::Count(value) => write!(f, "Invalid row count [{}]", value),
+            InvalidRow::EdgeType(value) => write!(f, "Unknown edge type [{}]", value),
+            InvalidRow::EntityId { column, value } => write!(
+                f,
+                "Identity hashing requires numeric entity ids lower than 2^{}, got [{}] in column [{}]",
+                IDENTITY_ID_BITS, value, column
+            ),
+            InvalidRow::Utf8(err) => write!(f, "Entity isn't valid UTF-8. {}", err),
+        }
+    }
+}
+
+impl From<Utf8Error> for InvalidRow {
+    fn from(err: Utf8Error) -> Self {
+        InvalidRow::Utf8(err)
+    }
+}
+
 /// Marker for elements in a vector. Let's say that we have `vec![1, 2, 3, 4]`
 /// and `LengthAndOffset { length: 2, offset : 1 }`. Offset points to the second element in the vector
 /// and length tell us how many elements we should take (in that case 2 elements: 2 and 3).
@@ -70,17 +119,64 @@ impl Iterator for CartesianProduct {
     }
 }
 
+/// Uniform sample of the input rows (hyperedges), every row is kept with the given probability.
+/// Decisions come from a SplitMix64 sequence of the seed, so the same input gives the same sample.
+pub struct EdgeSampler {
+    threshold: u64,
+    state: u64,
+    pub seen: u64,
+    pub kept: u64,
+}
+
+impl EdgeSampler {
+    pub fn new(fraction: f64, seed: u64) -> Self {
+        EdgeSampler {
+            threshold: (fraction * u64::MAX as f64) as u64,
+            state: seed,
+            seen: 0,
+            kept: 0,
+        }
+    }
+
+    /// Decides whether the next row is kept
+    pub fn keep(&mut self) -> bool {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^= z >> 31;
+        self.seen += 1;
+        let keep = z <= self.threshold;
+        if keep {
+            self.kept += 1;
+        }
+        keep
+    }
+}
+
 pub struct EntityProcessor<'a, T, F>
 where
     T: EntityMappingPersistor,
     F: FnMut(SmallVec<[u64; SMALL_VECTOR_SIZE]>),
 {
     config: &'a Configuration,
+    hash_function: HashFunction,
+    hash_bits: u32,
     field_hashes: SmallVec<[u64; SMALL_VECTOR_SIZE]>,
+    field_prefixes: Vec<String>,
     not_ignored_columns_count: u16,
     columns_count: u16,
+    weight_column: Option<usize>,
+    edge_type_column: Option<usize>,
+    timestamp_column: Option<usize>,
+    count_column: Option<usize>,
+    /// Tags columns of the entity columns, (entity column, tags column) indexes
+    tag_columns: Vec<(usize, usize)>,
     entity_mapping_persistor: Arc<T>,
     hashes_handler: F,
+    cardinality_monitor: Option<&'a mut CardinalityMonitor>,
+    sampler: Option<&'a mut EdgeSampler>,
+    aliases: Option<&'a Aliases>,
 }
 
 impl<'a, T, F> EntityProcessor<'a, T, F>
@@ -94,10 +190,16 @@ where
         hashes_handler: F,
     ) -> EntityProcessor<'a, T, F> {
         let columns = &config.columns;
+        let hash_function = config.hash_function;
         // hashes for column names are used to differentiate entities with the same name
         // from different columns
-        let field_hashes_vec: Vec<u64> = columns.iter().map(|c| hash(&c.name)).collect();
+        let field_hashes_vec: Vec<u64> = columns
+            .iter()
+            .enumerate()
+            .map(|(i, c)| field_hash(hash_function, i, &c.name))
+            .collect();
         let field_hashes: SmallVec<[u64; SMALL_VECTOR_SIZE]> = SmallVec::from_vec(field_hashes_vec);
+        let field_prefixes = field_prefixes(config);
         let not_ignored_cols = config.not_ignored_columns();
         let mut not_ignored_columns_count = 0;
         let mut reflexive_columns_count = 0;
@@ -109,87 +211,286 @@ where
         }
 
         let columns_count = not_ignored_columns_count + reflexive_columns_count;
+        let column_index = |name: &str| columns.iter().position(|c| c.name == name);
+        let tag_columns = config
+            .entity_tags
+            .iter()
+            .filter_map(|(column, tags)| Some((column_index(column)?, column_index(tags)?)))
+            .collect();
 
         EntityProcessor {
             config,
+            hash_function,
+            hash_bits: config.hash_bits,
             field_hashes,
+            field_prefixes,
             not_ignored_columns_count,
             columns_count,
+            weight_column: columns.iter().position(|c| c.weight),
+            edge_type_column: columns.iter().position(|c| c.edge_type),
+            timestamp_column: columns.iter().position(|c| c.timestamp),
+            count_column: columns.iter().position(|c| c.count),
+            tag_columns,
             entity_mapping_persistor: persistor,
             hashes_handler,
+            cardinality_monitor: None,
+            sampler: None,
+            aliases: None,
+        }
+    }
+
+    /// Feed entity hashes to the monitor, which estimates their cardinality per column.
+    pub fn with_cardinality_monitor(mut self, monitor: &'a mut CardinalityMonitor) -> Self {
+        self.cardinality_monitor = Some(monitor);
+        self
+    }
+
+    /// Checks the memory budget of the monitor every `CHECK_EVERY_N_ROWS` processed rows, see
+    /// `CardinalityMonitor::check_every_n_rows`
+    pub fn check_budget(&mut self) -> Result<(), CleoraError> {
+        match self.cardinality_monitor.as_mut() {
+            Some(monitor) => monitor.check_every_n_rows(),
+            None => Ok(()),
         }
     }
 
+    /// Process only rows kept by the sampler, the others are skipped before parsing their values.
+    pub fn with_sampler(mut self, sampler: &'a mut EdgeSampler) -> Self {
+        self.sampler = Some(sampler);
+        self
+    }
+
+    /// Replace entities of all columns with their canonical IDs before they are hashed.
+    pub fn with_aliases(mut self, aliases: &'a Aliases) -> Self {
+        self.aliases = Some(aliases);
+        self
+    }
+
     /// Every row can create few combinations (cartesian products) which are hashed and provided for sparse matrix creation.
     /// `row` - array of strings such as: ("userId1", "productId1 productId2", "brandId1").
-    pub fn process_row<S: AsRef<str>>(&mut self, row: &[SmallVec<[S; SMALL_VECTOR_SIZE]>]) {
+    /// Rows with invalid weight, count, timestamp or edge type are skipped with the error.
+    pub fn process_row<S: AsRef<str>>(
+        &mut self,
+        row: &[SmallVec<[S; SMALL_VECTOR_SIZE]>],
+    ) -> Result<(), InvalidRow> {
+        self.process_entities(
+            row,
+            |entity| entity.as_ref().as_bytes(),
+            |entity| Ok(entity.as_ref()),
+        )
+    }
+
+    /// Like `process_row`, but entities are raw bytes which are hashed without string
+    /// allocations. Only entities stored in the entity mapping and tags are validated as UTF-8,
+    /// once and before anything is stored, `InvalidRow::Utf8` is returned if one of them isn't
+    /// valid.
+    pub fn process_raw_row<S: AsRef<[u8]>>(
+        &mut self,
+        row: &[SmallVec<[S; SMALL_VECTOR_SIZE]>],
+    ) -> Result<(), InvalidRow> {
+        self.process_entities(
+            row,
+            |entity| entity.as_ref(),
+            |entity| str::from_utf8(entity.as_ref()),
+        )
+    }
+
+    /// Processes the row, entities are hashed as `bytes` and `text` of the stored ones (and of
+    /// tags) is taken before the entity mapping, tags and cardinality monitor are updated, so
+    /// malformed rows leave nothing behind.
+    #[inline(always)]
+    fn process_entities<S, B, X>(
+        &mut self,
+        row: &[SmallVec<[S; SMALL_VECTOR_SIZE]>],
+        bytes: B,
+        text: X,
+    ) -> Result<(), InvalidRow>
+    where
+        B: Fn(&S) -> &[u8],
+        X: Fn(&S) -> Result<&str, Utf8Error>,
+    {
+        if let Some(sampler) = self.sampler.as_mut() {
+            if !sampler.keep() {
+                return Ok(());
+            }
+        }
+        let mut weight = match self.weight_column {
+            Some(column) => {
+                let value = row[column].first().map(&bytes).unwrap_or_default();
+                match str::from_utf8(value).map(|v| v.parse::<f32>()) {
+                    Ok(Ok(weight)) if weight.is_finite() => weight,
+                    _ => {
+                        return Err(InvalidRow::Weight(
+                            String::from_utf8_lossy(value).into_owned(),
+                        ))
+                    }
+                }
+            }
+            None => 1f32,
+        };
+        if let (Some(column), Some(decay)) = (self.timestamp_column, self.config.decay) {
+            let value = row[column].first().map(&bytes).unwrap_or_default();
+            match str::from_utf8(value).map(extract_timestamp) {
+                Ok(Ok(timestamp)) => weight *= decay.factor(timestamp),
+                _ => {
+                    return Err(InvalidRow::Timestamp(
+                        String::from_utf8_lossy(value).into_owned(),
+                    ))
+                }
+            }
+        }
+        let mut count = None;
+        if let Some(column) = self.count_column {
+            let value = row[column].first().map(&bytes).unwrap_or_default();
+            match str::from_utf8(value).map(|v| v.parse::<u32>()) {
+                Ok(Ok(row_count)) => {
+                    weight *= row_count as f32;
+                    count = Some(row_count as u64);
+                }
+                _ => {
+                    return Err(InvalidRow::Count(
+                        String::from_utf8_lossy(value).into_owned(),
+                    ))
+                }
+            }
+        }
+        // nothing to add or subtract
+        if weight == 0f32 {
+            return Ok(());
+        }
+        let edge_type = match self.edge_type_column {
+            Some(column) => {
+                let value = row[column].first().map(&bytes).unwrap_or_default();
+                match self
+                    .config
+                    .edge_types
+                    .iter()
+                    .position(|label| label.as_bytes() == value)
+                {
+                    Some(edge_type) => Some(edge_type as u64),
+                    None => {
+                        return Err(InvalidRow::EdgeType(
+                            String::from_utf8_lossy(value).into_owned(),
+                        ))
+                    }
+                }
+            }
+            None => None,
+        };
+
         let mut hashes: SmallVec<[u64; SMALL_VECTOR_SIZE]> =
             SmallVec::with_capacity(self.not_ignored_columns_count as usize);
+        // column of every hash and its entity, if it's stored in the entity mapping
+        let mut entities: SmallVec<[(usize, Option<&str>); SMALL_VECTOR_SIZE]> =
+            SmallVec::with_capacity(self.not_ignored_columns_count as usize);
         let mut lens_and_offsets: SmallVec<[LengthAndOffset; SMALL_VECTOR_SIZE]> =
             smallvec![LengthAndOffset{ length: 0, offset: 0}; self.columns_count as usize];
         let mut reflexive_count = 0;
         let mut current_offset = 0u32;
 
+        let aliases = self.aliases;
         let mut idx = 0;
         for (i, column_entities) in row.iter().enumerate() {
             let column = &self.config.columns[i];
             if !column.ignored {
-                if column.complex {
-                    for entity in column_entities {
-                        let hash = self.field_hashes[i] ^ hash(entity.as_ref());
-                        hashes.push(hash);
-                        self.update_entity_mapping(entity.as_ref(), hash, column);
-                    }
-                    let length = column_entities.len() as u32;
-                    lens_and_offsets[idx] = LengthAndOffset {
-                        length,
-                        offset: current_offset,
-                    };
-                    if column.reflexive {
-                        // put reflexive column data to the end of the buffers
-                        let reflexive_id =
-                            (self.not_ignored_columns_count + reflexive_count) as usize;
-                        lens_and_offsets[reflexive_id] = LengthAndOffset {
-                            length,
-                            offset: current_offset,
-                        };
-                        reflexive_count += 1;
-                    }
-                    current_offset += length;
+                let column_entities = if column.complex {
+                    &column_entities[..]
                 } else {
-                    let entity = column_entities.get(0).unwrap().as_ref();
-                    let hash = self.field_hashes[i] ^ hash(entity);
-                    hashes.push(hash);
-                    self.update_entity_mapping(entity, hash, column);
-                    let length = 1u32;
-                    lens_and_offsets[idx] = LengthAndOffset {
+                    &column_entities[..1]
+                };
+                let stored = !column.transient && self.hash_function != HashFunction::Identity;
+                for entity in column_entities {
+                    let canonical_id =
+                        aliases.and_then(|aliases| aliases.canonical_id(bytes(entity)));
+                    let entity_bytes = canonical_id.map_or_else(|| bytes(entity), str::as_bytes);
+                    hashes.push(self.entity_hash(i, entity_bytes)?);
+                    let stored_entity = match (stored, canonical_id) {
+                        (false, _) => None,
+                        (true, Some(id)) => Some(id),
+                        (true, None) => Some(text(entity)?),
+                    };
+                    entities.push((i, stored_entity));
+                }
+                let length = column_entities.len() as u32;
+                lens_and_offsets[idx] = LengthAndOffset {
+                    length,
+                    offset: current_offset,
+                };
+                if column.complex && column.reflexive {
+                    // put reflexive column data to the end of the buffers
+                    let reflexive_id = (self.not_ignored_columns_count + reflexive_count) as usize;
+                    lens_and_offsets[reflexive_id] = LengthAndOffset {
                         length,
                         offset: current_offset,
                     };
-                    current_offset += length;
+                    reflexive_count += 1;
                 }
+                current_offset += length;
                 idx += 1;
             }
         }
+        // entity column and tag of every tag value. Tags columns give one tag (the first value)
+        // per row, complex ones all of their values.
+        let mut tags: SmallVec<[(usize, &str); SMALL_VECTOR_SIZE]> = SmallVec::new();
+        for &(column, tags_column) in self.tag_columns.iter() {
+            let values = &row[tags_column];
+            let values = if self.config.columns[tags_column].complex {
+                &values[..]
+            } else {
+                &values[..values.len().min(1)]
+            };
+            for value in values.iter() {
+                let tag = text(value)?;
+                if !tag.is_empty() {
+                    tags.push((column, tag));
+                }
+            }
+        }
+
+        for (&(i, entity), &hash) in entities.iter().zip(hashes.iter()) {
+            if let Some(entity) = entity {
+                let prefix = &self.field_prefixes[i];
+                self.entity_mapping_persistor
+                    .put_entity(hash, prefix, entity);
+            }
+            for &(_, tag) in tags.iter().filter(|(column, _)| *column == i) {
+                self.entity_mapping_persistor.put_tag(hash, tag);
+            }
+            if let Some(monitor) = self.cardinality_monitor.as_mut() {
+                monitor.add(i, hash);
+            }
+        }
 
-        let hash_rows = self.generate_combinations_with_length(hashes, lens_and_offsets);
+        if let Some(monitor) = self.cardinality_monitor.as_mut() {
+            monitor.row_processed();
+        }
+
+        let hash_rows = self.generate_combinations_with_length(
+            hashes,
+            lens_and_offsets,
+            weight,
+            count,
+            edge_type,
+        );
         for hash_row in hash_rows {
             (self.hashes_handler)(hash_row);
         }
+        Ok(())
     }
 
+    /// Hash of the entity of the column, fails for entities which aren't valid ids for identity
+    /// hashing
     #[inline(always)]
-    fn update_entity_mapping(&self, entity: &str, hash: u64, column: &Column) {
-        if !column.transient && !self.entity_mapping_persistor.contains(hash) {
-            let entry = if self.config.prepend_field {
-                let mut entry = column.name.clone();
-                entry.push_str("__");
-                entry.push_str(entity);
-                entry
-            } else {
-                entity.to_string()
-            };
-            self.entity_mapping_persistor.put_data(hash, entry);
+    fn entity_hash(&self, column_idx: usize, entity: &[u8]) -> Result<u64, InvalidRow> {
+        match hash_entity(self.hash_function, entity) {
+            Some(hash) => Ok(narrow_hash(
+                self.field_hashes[column_idx] ^ hash,
+                self.hash_bits,
+            )),
+            None => Err(InvalidRow::EntityId {
+                column: self.config.columns[column_idx].name.clone(),
+                value: String::from_utf8_lossy(entity).into_owned(),
+            }),
         }
     }
 
@@ -204,12 +505,19 @@ where
     /// (userId1, productId1, brandId1), (userId1, productId1, brandId2), (userId1, productId2, brandId1), (userId1, productId2, brandId2)
     /// `hashes` - entity hashes
     /// `lens_and_offsets` - number of entities per column
+    /// `weight` - weight of the input row, packed with the number of combinations
+    /// `count` - multiplicity of the input row (count:: column), appended to every combination
+    /// `edge_type` - index of the edge type of the input row, appended to every combination after
+    /// the count
     /// return entity hashes Cartesian Products. Size of the array (matrix) is equal to number of combinations x number of columns (including reflexive column)
     #[inline(always)]
     fn generate_combinations_with_length(
         &self,
         hashes: SmallVec<[u64; SMALL_VECTOR_SIZE]>,
         lens_and_offsets: SmallVec<[LengthAndOffset; SMALL_VECTOR_SIZE]>,
+        weight: f32,
+        count: Option<u64>,
+        edge_type: Option<u64>,
     ) -> impl Iterator<Item = SmallVec<[u64; SMALL_VECTOR_SIZE]>> {
         let row_length = lens_and_offsets.len();
         let mut total_combinations = 1;
@@ -221,31 +529,233 @@ where
 
         cartesian.map(move |indices| {
             let mut arr: SmallVec<[u64; SMALL_VECTOR_SIZE]> =
-                SmallVec::with_capacity(row_length + 1);
-            arr.push(total_combinations as u64);
+                SmallVec::with_capacity(row_length + 3);
+            arr.push(encode_combinations(total_combinations, weight));
             for i in indices {
                 let value = hashes[i as usize];
                 arr.push(value);
             }
+            arr.extend(count);
+            arr.extend(edge_type);
             arr
         })
     }
 }
 
+/// The first value of every hash row packs the number of combinations generated from the input
+/// row (lower 32 bits) and bits of the f32 weight of the row (upper 32 bits). Zero upper bits
+/// stand for weight 1, so unweighted rows carry just the number of combinations.
+pub fn encode_combinations(count: u32, weight: f32) -> u64 {
+    if weight == 1f32 {
+        count as u64
+    } else {
+        ((weight.to_bits() as u64) << 32) | count as u64
+    }
+}
+
+/// Number of combinations and weight of the row packed by `encode_combinations`
+pub fn decode_combinations(value: u64) -> (u32, f32) {
+    let weight_bits = (value >> 32) as u32;
+    let weight = if weight_bits == 0 {
+        1f32
+    } else {
+        f32::from_bits(weight_bits)
+    };
+    (value as u32, weight)
+}
+
+/// Column names prepended to entities in the output (empty if disabled).
+pub fn field_prefixes(config: &Configuration) -> Vec<String> {
+    config
+        .columns
+        .iter()
+        .map(|c| {
+            if config.prepend_field {
+                format!("{}__", c.name)
+            } else {
+                String::new()
+            }
+        })
+        .collect()
+}
+
+/// Entity replacing the ones cut off by `--max-entities` when unknown bucket is enabled
+pub const UNKNOWN_ENTITY: &str = "<UNK>";
+
+/// Hash of the unknown entity of the column. Identity hashes can't represent it.
+pub fn unknown_entity_hash(hash_function: HashFunction, column_idx: usize, name: &str) -> u64 {
+    let unknown = hash_entity(hash_function, UNKNOWN_ENTITY.as_bytes())
+        .expect("Unknown entity with identity hashing");
+    field_hash(hash_function, column_idx, name) ^ unknown
+}
+
+/// Hash of the column name XOR-ed with hashes of its entities.
+pub fn field_hash(hash_function: HashFunction, column_idx: usize, name: &str) -> u64 {
+    match hash_function {
+        HashFunction::XxHash64 => hash(name),
+        HashFunction::FxHash => fx_hash(name),
+        HashFunction::Identity => (column_idx as u64) << IDENTITY_ID_BITS,
+    }
+}
+
+/// Folds the hash into `hash_bits` bits (32 or 64, see `Configuration::hash_bits`). The high
+/// half is XOR-ed into the low one, so hashes with weak low bits (FxHash) keep their entropy.
+#[inline(always)]
+pub fn narrow_hash(hash: u64, hash_bits: u32) -> u64 {
+    match hash_bits {
+        32 => (hash ^ (hash >> 32)) & u64::from(u32::MAX),
+        _ => hash,
+    }
+}
+
+/// Expected number of colliding pairs among `entities` random hashes of `hash_bits` bits
+/// (birthday bound), to judge whether the observed collisions are the hash width's fault
+pub fn expected_collisions(entities: usize, hash_bits: u32) -> f64 {
+    let entities = entities as f64;
+    entities * (entities - 1f64) / 2f64.powi(hash_bits as i32 + 1)
+}
+
+/// Create entity mapping persistor for the configured hash function. Identity hashes don't need
+/// the mapping, entities are decoded from the hashes.
+pub fn create_entity_mapping_persistor(config: &Configuration) -> InMemoryEntityMappingPersistor {
+    match config.hash_function {
+        HashFunction::Identity => {
+            let prefixes = field_prefixes(config)
+                .into_iter()
+                .zip(config.columns.iter())
+                .map(|(prefix, column)| if column.transient { None } else { Some(prefix) })
+                .collect();
+            InMemoryEntityMappingPersistor::with_identity_hashes(IDENTITY_ID_BITS, prefixes)
+        }
+        HashFunction::XxHash64 | HashFunction::FxHash => InMemoryEntityMappingPersistor::default(),
+    }
+}
+
+/// Entity limits of the columns with hashes of their unknown entities (if enabled). Unknown
+/// entities are added to the entity mapping, so they are written like the other ones.
+pub fn entity_limits(
+    config: &Configuration,
+    persistor: &InMemoryEntityMappingPersistor,
+) -> Vec<(String, usize, Option<u64>)> {
+    let prefixes = field_prefixes(config);
+    config
+        .max_entities
+        .iter()
+        .map(|(column, max_entities)| {
+            let unknown_hash = if config.unknown_bucket {
+                let idx = config
+                    .columns
+                    .iter()
+                    .position(|c| &c.name == column)
+                    .expect("Entity limit of declared column");
+                let hash = narrow_hash(
+                    unknown_entity_hash(config.hash_function, idx, column),
+                    config.hash_bits,
+                );
+                persistor.put_data(hash, format!("{}{}", prefixes[idx], UNKNOWN_ENTITY));
+                Some(hash)
+            } else {
+                None
+            };
+            (column.clone(), *max_entities, unknown_hash)
+        })
+        .collect()
+}
+
+/// Delimiters of the fields of TSV lines and of the values of every column
+pub struct Delimiters {
+    pub field: u8,
+    values: Vec<u8>,
+}
+
+impl Delimiters {
+    pub fn new(config: &Configuration) -> Self {
+        let values = config
+            .columns
+            .iter()
+            .map(|column| {
+                config
+                    .value_delimiters
+                    .iter()
+                    .find(|(name, _)| name == &column.name)
+                    .map_or(b' ', |(_, delimiter)| *delimiter)
+            })
+            .collect();
+        Self {
+            field: config.field_delimiter,
+            values,
+        }
+    }
+
+    /// Delimiter of the values of the column, space for the columns of ragged lines
+    #[inline]
+    pub fn value(&self, column: usize) -> u8 {
+        self.values.get(column).copied().unwrap_or(b' ')
+    }
+}
+
+/// Parse a line of TSV and read its columns into a vector for processing.
+pub fn parse_tsv_line<'a>(
+    line: &'a str,
+    delimiters: &Delimiters,
+) -> Vec<SmallVec<[&'a str; SMALL_VECTOR_SIZE]>> {
+    let values = line.trim().split(delimiters.field as char);
+    values
+        .enumerate()
+        .map(|(i, c)| c.split(delimiters.value(i) as char).collect())
+        .collect()
+}
+
+/// Hash of the entity, `None` if it isn't a valid id for identity hashing (numeric, lower than
+/// 2^`IDENTITY_ID_BITS`)
+#[inline(always)]
+fn hash_entity(hash_function: HashFunction, entity: &[u8]) -> Option<u64> {
+    match hash_function {
+        HashFunction::XxHash64 => Some(hash_bytes(entity)),
+        HashFunction::FxHash => Some(fx_hash_bytes(entity)),
+        HashFunction::Identity => str::from_utf8(entity)
+            .ok()
+            .and_then(|e| e.parse::<u64>().ok())
+            .filter(|id| id >> IDENTITY_ID_BITS == 0),
+    }
+}
+
 #[inline(always)]
 fn hash(entity: &str) -> u64 {
+    hash_bytes(entity.as_bytes())
+}
+
+#[inline(always)]
+fn hash_bytes(entity: &[u8]) -> u64 {
     let mut hasher = XxHash64::default();
-    hasher.write(entity.as_bytes());
+    hasher.write(entity);
+    hasher.finish()
+}
+
+#[inline(always)]
+fn fx_hash(entity: &str) -> u64 {
+    fx_hash_bytes(entity.as_bytes())
+}
+
+#[inline(always)]
+fn fx_hash_bytes(entity: &[u8]) -> u64 {
+    let mut hasher = FxHasher::default();
+    hasher.write(entity);
     hasher.finish()
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::configuration::{Column, Configuration};
+    use crate::alias::Aliases;
+    use crate::configuration::{
+        extract_fields, Column, Configuration, HashFunction, TemporalDecay,
+    };
     use crate::entity::{
-        hash, CartesianProduct, EntityProcessor, LengthAndOffset, SMALL_VECTOR_SIZE,
+        decode_combinations, encode_combinations, expected_collisions, field_prefixes, hash,
+        narrow_hash, CartesianProduct, EdgeSampler, EntityProcessor, InvalidRow, LengthAndOffset,
+        IDENTITY_ID_BITS, SMALL_VECTOR_SIZE,
     };
-    use crate::persistence::entity::InMemoryEntityMappingPersistor;
+    use crate::persistence::entity::{EntityMappingPersistor, InMemoryEntityMappingPersistor};
     use smallvec::{smallvec, SmallVec};
     use std::sync::Arc;
 
@@ -320,7 +830,7 @@ mod tests {
         );
 
         let combinations: Vec<_> = entity_processor
-            .generate_combinations_with_length(hashes, lengths_and_offsets)
+            .generate_combinations_with_length(hashes, lengths_and_offsets, 1f32, None, None)
             .collect();
         assert_eq!(
             &SmallVec::from([total_combinations, 10, 20, 40]),
@@ -358,6 +868,11 @@ mod tests {
                 complex: false,
                 reflexive: false,
                 ignored: true,
+                weight: false,
+                edge_type: false,
+                timestamp: false,
+                count: false,
+                tags: false,
             },
             Column {
                 name: String::from("column_2"),
@@ -365,6 +880,11 @@ mod tests {
                 complex: false,
                 reflexive: false,
                 ignored: false,
+                weight: false,
+                edge_type: false,
+                timestamp: false,
+                count: false,
+                tags: false,
             },
             Column {
                 name: String::from("column_3"),
@@ -372,6 +892,11 @@ mod tests {
                 complex: true,
                 reflexive: true,
                 ignored: false,
+                weight: false,
+                edge_type: false,
+                timestamp: false,
+                count: false,
+                tags: false,
             },
             Column {
                 name: String::from("column_4"),
@@ -379,6 +904,11 @@ mod tests {
                 complex: false,
                 reflexive: false,
                 ignored: false,
+                weight: false,
+                edge_type: false,
+                timestamp: false,
+                count: false,
+                tags: false,
             },
         ];
         // columns configuration: ignored::column_1 transient::column_2 complex::reflexive::column3 column_4
@@ -415,7 +945,7 @@ mod tests {
             smallvec!["ccc", "ddd"],
             smallvec!["eeee"],
         ];
-        entity_processor.process_row(&row);
+        entity_processor.process_row(&row).unwrap();
 
         // first column is ignored, third one is reflexive so the entities go at the end
         // input: "bb", "ccc ddd", "eeee", "ccc ddd"
@@ -438,4 +968,337 @@ mod tests {
             result[3]
         );
     }
+
+    #[test]
+    fn process_row_with_identity_hashes() {
+        let columns = vec![
+            Column {
+                name: String::from("users"),
+                ..Default::default()
+            },
+            Column {
+                name: String::from("items"),
+                ..Default::default()
+            },
+        ];
+        let mut config = Configuration::default(String::from(""), columns);
+        config.hash_function = HashFunction::Identity;
+
+        let prefixes = field_prefixes(&config).into_iter().map(Some).collect();
+        let persistor = Arc::new(InMemoryEntityMappingPersistor::with_identity_hashes(
+            IDENTITY_ID_BITS,
+            prefixes,
+        ));
+        let mut result: Vec<SmallVec<[u64; SMALL_VECTOR_SIZE]>> = Vec::new();
+        let mut entity_processor =
+            EntityProcessor::new(&config, persistor.clone(), |hashes| result.push(hashes));
+
+        entity_processor
+            .process_row(&[smallvec!["5"], smallvec!["5"]])
+            .unwrap();
+        // ids which aren't numeric or don't fit skip the row
+        for id in ["x", "72057594037927936"] {
+            assert_eq!(
+                Err(InvalidRow::EntityId {
+                    column: String::from("items"),
+                    value: String::from(id)
+                }),
+                entity_processor.process_row(&[smallvec!["5"], smallvec![id]])
+            );
+        }
+
+        // the same id in different columns gives different keys
+        let items_hash = (1u64 << IDENTITY_ID_BITS) | 5;
+        let expected: SmallVec<[u64; SMALL_VECTOR_SIZE]> = smallvec![1, 5, items_hash];
+        assert_eq!(vec![expected], result);
+        // nothing stored, entities are decoded from keys
+        assert!(persistor.is_empty());
+        assert_eq!(Some("users__5".into()), persistor.get_entity(5));
+        assert_eq!(Some("items__5".into()), persistor.get_entity(items_hash));
+    }
+
+    #[test]
+    fn process_weighted_rows() {
+        let columns = extract_fields(vec!["users", "items", "weight::w"]).unwrap();
+        let config = Configuration::default(String::from(""), columns);
+        let persistor = Arc::new(InMemoryEntityMappingPersistor::default());
+        let mut result: Vec<SmallVec<[u64; SMALL_VECTOR_SIZE]>> = Vec::new();
+        let mut entity_processor =
+            EntityProcessor::new(&config, persistor.clone(), |hashes| result.push(hashes));
+
+        let mut process = |weight| {
+            entity_processor.process_row(&[smallvec!["u"], smallvec!["i"], smallvec![weight]])
+        };
+        process("1").unwrap();
+        process("-0.5").unwrap();
+        // skipped rows
+        process("0").unwrap();
+        assert_eq!(Err(InvalidRow::Weight(String::from("x"))), process("x"));
+
+        assert_eq!(2, result.len());
+        // weight column gives no entities
+        assert_eq!(3, result[0].len());
+        assert_eq!(1, result[0][0]);
+        assert_eq!((1, -0.5), decode_combinations(result[1][0]));
+        assert_eq!((3, 1f32), decode_combinations(encode_combinations(3, 1f32)));
+    }
+
+    #[test]
+    fn process_sampled_rows() {
+        let columns = extract_fields(vec!["users", "items"]).unwrap();
+        let config = Configuration::default(String::from(""), columns);
+        let persistor = Arc::new(InMemoryEntityMappingPersistor::default());
+        let mut result: Vec<SmallVec<[u64; SMALL_VECTOR_SIZE]>> = Vec::new();
+        let mut sampler = EdgeSampler::new(0.1, 7);
+        let mut entity_processor =
+            EntityProcessor::new(&config, persistor.clone(), |hashes| result.push(hashes))
+                .with_sampler(&mut sampler);
+
+        for i in 0..10000 {
+            let item = i.to_string();
+            entity_processor
+                .process_row(&[smallvec!["u"], smallvec![item.as_str()]])
+                .unwrap();
+        }
+        drop(entity_processor);
+        assert_eq!(10000, sampler.seen);
+        assert_eq!(sampler.kept, result.len() as u64);
+        assert!((900..1100).contains(&sampler.kept));
+        // skipped rows give no entities
+        assert_eq!(1 + result.len(), persistor.len());
+
+        // the same seed gives the same sample
+        let sample = |seed| {
+            let mut sampler = EdgeSampler::new(0.5, seed);
+            (0..64).map(|_| sampler.keep()).collect::<Vec<_>>()
+        };
+        assert_eq!(sample(1), sample(1));
+        assert_ne!(sample(1), sample(2));
+        assert!((0..100).all(|_| EdgeSampler::new(1.0, 3).keep()));
+    }
+
+    #[test]
+    fn process_aliased_rows() {
+        let columns = extract_fields(vec!["users", "complex::items"]).unwrap();
+        let config = Configuration::default(String::from(""), columns);
+        let aliases = Aliases::parse(&b"Alice\talice\ni1-old\ti1\n"[..]).unwrap();
+        let persistor = Arc::new(InMemoryEntityMappingPersistor::default());
+        let mut result: Vec<SmallVec<[u64; SMALL_VECTOR_SIZE]>> = Vec::new();
+        let mut entity_processor =
+            EntityProcessor::new(&config, persistor.clone(), |hashes| result.push(hashes))
+                .with_aliases(&aliases);
+
+        entity_processor
+            .process_row(&[smallvec!["alice"], smallvec!["i1", "i2"]])
+            .unwrap();
+        entity_processor
+            .process_raw_row(&[
+                smallvec![&b"Alice"[..]],
+                smallvec![&b"i1-old"[..], &b"i2"[..]],
+            ])
+            .unwrap();
+        drop(entity_processor);
+        // aliases are the same nodes as their canonical IDs
+        let rows = result.len() / 2;
+        assert_eq!(result[..rows], result[rows..]);
+        assert_eq!(3, persistor.len());
+        assert_eq!(
+            Some("users__alice".into()),
+            persistor.get_entity(result[0][1])
+        );
+    }
+
+    #[test]
+    fn process_rows_with_32_bit_hashes() {
+        let process = |hash_function, hash_bits| {
+            let columns = extract_fields(vec!["users", "items"]).unwrap();
+            let mut config = Configuration::default(String::from(""), columns);
+            config.hash_function = hash_function;
+            config.hash_bits = hash_bits;
+            let persistor = Arc::new(InMemoryEntityMappingPersistor::default());
+            let mut result: Vec<SmallVec<[u64; SMALL_VECTOR_SIZE]>> = Vec::new();
+            let mut entity_processor =
+                EntityProcessor::new(&config, persistor.clone(), |hashes| result.push(hashes));
+            entity_processor
+                .process_row(&[smallvec!["u1"], smallvec!["i1"]])
+                .unwrap();
+            drop(entity_processor);
+            (result.remove(0), persistor)
+        };
+
+        for hash_function in [HashFunction::XxHash64, HashFunction::FxHash] {
+            let (wide, _) = process(hash_function, 64);
+            let (narrow, persistor) = process(hash_function, 32);
+            for (&wide, &narrow) in wide[1..].iter().zip(&narrow[1..]) {
+                assert!(narrow <= u64::from(u32::MAX));
+                assert_eq!(narrow_hash(wide, 32), narrow);
+            }
+            assert_eq!(Some("items__i1".into()), persistor.get_entity(narrow[2]));
+        }
+        assert_eq!(12345, narrow_hash(12345, 64));
+
+        // half a colliding pair expected at 2^16 entities
+        assert!((expected_collisions(1 << 16, 32) - 0.5).abs() < 0.01);
+        assert!(expected_collisions(100_000_000, 64) < 0.001);
+    }
+
+    #[test]
+    fn process_counted_rows() {
+        let columns = extract_fields(vec!["users", "items", "weight::w", "count::n"]).unwrap();
+        let config = Configuration::default(String::from(""), columns);
+        let persistor = Arc::new(InMemoryEntityMappingPersistor::default());
+        let mut result: Vec<SmallVec<[u64; SMALL_VECTOR_SIZE]>> = Vec::new();
+        let mut entity_processor =
+            EntityProcessor::new(&config, persistor.clone(), |hashes| result.push(hashes));
+
+        let row = |weight, count| {
+            [
+                smallvec!["u"],
+                smallvec!["i"],
+                smallvec![weight],
+                smallvec![count],
+            ]
+        };
+        entity_processor.process_row(&row("1", "3")).unwrap();
+        entity_processor.process_row(&row("0.5", "1")).unwrap();
+        // skipped rows
+        entity_processor.process_row(&row("1", "0")).unwrap();
+        assert!(entity_processor.process_row(&row("1", "-2")).is_err());
+        assert_eq!(
+            Err(InvalidRow::Count(String::from("1.5"))),
+            entity_processor.process_row(&row("1", "1.5"))
+        );
+
+        assert_eq!(2, result.len());
+        // the count follows the entities
+        assert_eq!(4, result[0].len());
+        assert_eq!((1, 3f32), decode_combinations(result[0][0]));
+        assert_eq!(3, result[0][3]);
+        assert_eq!((1, 0.5), decode_combinations(result[1][0]));
+        assert_eq!(1, result[1][3]);
+    }
+
+    #[test]
+    fn process_edge_typed_rows() {
+        let columns = extract_fields(vec!["users", "edge_type::kind", "items"]).unwrap();
+        let mut config = Configuration::default(String::from(""), columns);
+        config.edge_types = vec![String::from("viewed"), String::from("bought")];
+        let persistor = Arc::new(InMemoryEntityMappingPersistor::default());
+        let mut result: Vec<SmallVec<[u64; SMALL_VECTOR_SIZE]>> = Vec::new();
+        let mut entity_processor =
+            EntityProcessor::new(&config, persistor.clone(), |hashes| result.push(hashes));
+
+        let mut process = |edge_type| {
+            entity_processor.process_row(&[smallvec!["u"], smallvec![edge_type], smallvec!["i"]])
+        };
+        process("bought").unwrap();
+        process("viewed").unwrap();
+        assert_eq!(
+            Err(InvalidRow::EdgeType(String::from("liked"))),
+            process("liked")
+        );
+
+        assert_eq!(2, result.len());
+        // index of the edge type follows the entities, the edge type column gives no entities
+        assert_eq!(4, result[0].len());
+        assert_eq!(1, result[0][3]);
+        assert_eq!(0, result[1][3]);
+        assert_eq!(result[0][..3], result[1][..3]);
+    }
+
+    #[test]
+    fn process_timestamped_rows() {
+        let columns = extract_fields(vec!["users", "items", "timestamp::ts"]).unwrap();
+        let mut config = Configuration::default(String::from(""), columns);
+        config.decay = Some(TemporalDecay {
+            half_life: 86400f64,
+            reference: 1654041600,
+        });
+        let persistor = Arc::new(InMemoryEntityMappingPersistor::default());
+        let mut result: Vec<SmallVec<[u64; SMALL_VECTOR_SIZE]>> = Vec::new();
+        let mut entity_processor =
+            EntityProcessor::new(&config, persistor.clone(), |hashes| result.push(hashes));
+
+        let mut process = |timestamp| {
+            entity_processor.process_row(&[smallvec!["u"], smallvec!["i"], smallvec![timestamp]])
+        };
+        process("2022-06-01").unwrap();
+        process("1653868800").unwrap();
+        // rows after the reference time aren't boosted
+        process("2022-06-02T12:00:00Z").unwrap();
+        assert_eq!(
+            Err(InvalidRow::Timestamp(String::from("yesterday"))),
+            process("yesterday")
+        );
+
+        assert_eq!(3, result.len());
+        assert_eq!(3, result[0].len());
+        assert_eq!((1, 1f32), decode_combinations(result[0][0]));
+        // two half-lives old
+        assert_eq!((1, 0.25), decode_combinations(result[1][0]));
+        assert_eq!((1, 1f32), decode_combinations(result[2][0]));
+    }
+
+    #[test]
+    fn process_raw_rows() {
+        let columns = extract_fields(vec!["users", "complex::items"]).unwrap();
+        let config = Configuration::default(String::from(""), columns);
+        let persistor = Arc::new(InMemoryEntityMappingPersistor::default());
+        let mut result: Vec<SmallVec<[u64; SMALL_VECTOR_SIZE]>> = Vec::new();
+        let mut entity_processor =
+            EntityProcessor::new(&config, persistor.clone(), |hashes| result.push(hashes));
+
+        entity_processor
+            .process_row(&[smallvec!["u"], smallvec!["i1", "i2"]])
+            .unwrap();
+        let raw_row: [SmallVec<[&[u8]; SMALL_VECTOR_SIZE]>; 2] =
+            [smallvec![&b"u"[..]], smallvec![&b"i1"[..], &b"i2"[..]]];
+        entity_processor.process_raw_row(&raw_row).unwrap();
+        // entities have to be valid UTF-8 to be stored in the mapping, the row is validated
+        // before anything is stored
+        let raw_row: [SmallVec<[&[u8]; SMALL_VECTOR_SIZE]>; 2] =
+            [smallvec![&b"v"[..]], smallvec![&b"i1"[..], &b"\xff"[..]]];
+        assert!(matches!(
+            entity_processor.process_raw_row(&raw_row),
+            Err(InvalidRow::Utf8(_))
+        ));
+        assert_eq!(3, persistor.len());
+
+        assert_eq!(4, result.len());
+        assert_eq!(result[0..2], result[2..4]);
+        assert!(persistor.collisions().is_empty());
+    }
+
+    #[test]
+    fn process_tagged_rows() {
+        let columns =
+            extract_fields(vec!["users", "complex::items", "complex::tags::item_tags"]).unwrap();
+        let mut config = Configuration::default(String::from(""), columns);
+        config.entity_tags = vec![(String::from("items"), String::from("item_tags"))];
+        let persistor = Arc::new(InMemoryEntityMappingPersistor::default());
+        let mut result: Vec<SmallVec<[u64; SMALL_VECTOR_SIZE]>> = Vec::new();
+        let mut entity_processor =
+            EntityProcessor::new(&config, persistor.clone(), |hashes| result.push(hashes));
+
+        entity_processor
+            .process_row(&[
+                smallvec!["u"],
+                smallvec!["i1", "i2"],
+                smallvec!["type=product", "sale"],
+            ])
+            .unwrap();
+        entity_processor
+            .process_row(&[smallvec!["u"], smallvec!["i3"], smallvec![""]])
+            .unwrap();
+
+        // the tags column gives no entities
+        assert_eq!(3, result.len());
+        assert_eq!(3, result[0].len());
+        let (user, i1, i3) = (result[0][1], result[0][2], result[2][2]);
+        let product = [String::from("type=product")];
+        assert!(persistor.has_tag(i1, &product));
+        assert!(persistor.has_tag(result[1][2], &[String::from("sale")]));
+        assert!(!persistor.has_tag(user, &product));
+        assert!(!persistor.has_tag(i3, &product));
+    }
 }
@@ -0,0 +1,243 @@
+use std::io;
+use thiserror::Error;
+
+/// Errors which stop the pipeline. Messages are meant for the user, so they say what to check.
+#[derive(Debug, Error)]
+pub enum CleoraError {
+    #[error("Unable to create file {path}: {source}. Check that the output directory exists and is writable")]
+    CreateFile { path: String, source: io::Error },
+
+    #[error("Output file {path} already exists. Use --overwrite to replace it")]
+    OutputExists { path: String },
+
+    #[error("Output file {path} would be written by several output formats. Put {{format}} into --output-template")]
+    SharedOutput { path: String },
+
+    #[error("Unable to write file {path}: {source}. Check free disk space")]
+    WriteFile { path: String, source: io::Error },
+
+    #[error("Unable to write parquet file {path}: {message}")]
+    Parquet { path: String, message: String },
+
+    #[error("Unable to write npy file {path}: {message}")]
+    Npy { path: String, message: String },
+
+    #[error("Unable to write DuckDB database {path}: {message}")]
+    DuckDb { path: String, message: String },
+
+    #[error("Unable to write SQLite database {path}: {message}")]
+    Sqlite { path: String, message: String },
+
+    #[error("Unable to read file {path}: {source}. Check that the file exists and is readable")]
+    ReadFile { path: String, source: io::Error },
+
+    #[error("Invalid embeddings file {path}: {message}")]
+    InvalidEmbeddings { path: String, message: String },
+
+    #[error("Invalid entity dictionary {path}: {message}")]
+    InvalidDictionary { path: String, message: String },
+
+    #[error("Invalid probes file {path}: {message}")]
+    InvalidProbes { path: String, message: String },
+
+    #[error("Invalid aliases file {path}: {message}")]
+    InvalidAliases { path: String, message: String },
+
+    #[error("Malformed row at line {line} of {path} (--malformed-rows fail): {message}")]
+    MalformedRow {
+        path: String,
+        line: u64,
+        message: String,
+    },
+
+    #[error("Found {count} entity hash collisions (--fail-on-collision is set)")]
+    HashCollisions { count: usize },
+
+    #[error("Found {count} entity hash collisions, more than the budget of {budget} (--collision-budget)")]
+    CollisionBudget { count: usize, budget: usize },
+
+    #[error("Invalid vocabulary file {path}: {message}")]
+    InvalidVocab { path: String, message: String },
+
+    #[error("Can't write Delta table {path}: {message}")]
+    DeltaTable { path: String, message: String },
+
+    #[error("Can't align {path}: {message}")]
+    Alignment { path: String, message: String },
+
+    #[error("Output is locked by another run with the same configuration: {owner} holds {path}. Use --force if that run is gone and the lock is stale")]
+    Locked { path: String, owner: String },
+
+    #[error("Arrow Flight server on {address} failed: {message}")]
+    Flight { address: String, message: String },
+
+    #[error("MLflow request to {uri} failed: {message}. Check the tracking server URI")]
+    Mlflow { uri: String, message: String },
+
+    #[error("Push to the Feast feature server {url} failed: {message}. Check the push source of the feature repository")]
+    Feast { url: String, message: String },
+
+    #[error("S3 request for {path} failed: {message}. Check S3_ENDPOINT_URL and AWS credentials")]
+    S3 { path: String, message: String },
+
+    #[error("{message} (--on-budget-exceeded abort)")]
+    MemoryBudget { message: String },
+
+    #[error("Can't embed in memory: {message}")]
+    Memory { message: String },
+
+    #[error("Run was interrupted while writing embeddings, the output isn't committed")]
+    Interrupted,
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl CleoraError {
+    pub fn create_file(path: &str, source: io::Error) -> Self {
+        CleoraError::CreateFile {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    pub fn write_file(path: &str, source: io::Error) -> Self {
+        CleoraError::WriteFile {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    pub fn read_file(path: &str, source: io::Error) -> Self {
+        CleoraError::ReadFile {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    pub fn invalid_embeddings<E: ToString>(path: &str, error: E) -> Self {
+        CleoraError::InvalidEmbeddings {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn invalid_dictionary<E: ToString>(path: &str, error: E) -> Self {
+        CleoraError::InvalidDictionary {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn invalid_probes<E: ToString>(path: &str, error: E) -> Self {
+        CleoraError::InvalidProbes {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn invalid_aliases<E: ToString>(path: &str, error: E) -> Self {
+        CleoraError::InvalidAliases {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn malformed_row<E: ToString>(path: &str, line: u64, error: E) -> Self {
+        CleoraError::MalformedRow {
+            path: path.to_string(),
+            line,
+            message: error.to_string(),
+        }
+    }
+
+    pub fn invalid_vocab<E: ToString>(path: &str, error: E) -> Self {
+        CleoraError::InvalidVocab {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn delta_table<E: ToString>(path: &str, error: E) -> Self {
+        CleoraError::DeltaTable {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn parquet<E: ToString>(path: &str, error: E) -> Self {
+        CleoraError::Parquet {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn npy<E: ToString>(path: &str, error: E) -> Self {
+        CleoraError::Npy {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn duckdb<E: ToString>(path: &str, error: E) -> Self {
+        CleoraError::DuckDb {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn sqlite<E: ToString>(path: &str, error: E) -> Self {
+        CleoraError::Sqlite {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn flight<E: ToString>(address: &str, error: E) -> Self {
+        CleoraError::Flight {
+            address: address.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn mlflow<E: ToString>(uri: &str, error: E) -> Self {
+        CleoraError::Mlflow {
+            uri: uri.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn feast<E: ToString>(url: &str, error: E) -> Self {
+        CleoraError::Feast {
+            url: url.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn s3<E: ToString>(path: &str, error: E) -> Self {
+        CleoraError::S3 {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn memory<E: ToString>(error: E) -> Self {
+        CleoraError::Memory {
+            message: error.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "fs")]
+impl From<cleora_embeddings::Error> for CleoraError {
+    fn from(error: cleora_embeddings::Error) -> Self {
+        match error {
+            cleora_embeddings::Error::Io { path, source } => CleoraError::ReadFile { path, source },
+            cleora_embeddings::Error::Invalid { path, message } => {
+                CleoraError::InvalidEmbeddings { path, message }
+            }
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, CleoraError>;
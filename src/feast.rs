@@ -0,0 +1,268 @@
+use crate::error::CleoraError;
+use crate::io::create_output;
+use crate::persistence::embedding::{EmbeddingBatch, EmbeddingPersistor, ParquetVectorPersistor};
+use chrono::Utc;
+#[cfg(feature = "feast")]
+use chrono::{DateTime, SecondsFormat};
+use log::info;
+#[cfg(feature = "feast")]
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::io::Write;
+#[cfg(feature = "feast")]
+use std::time::Duration;
+
+/// Writes embeddings as a Feast file source: a parquet file of `entity` (the join key), optional
+/// `occur_count`, `event_timestamp` (time of the run, UTC) and `f0`..`fN` feature columns, see
+/// `ParquetVectorPersistor`. The Python definitions of the entity, the file source, a push source
+/// and the feature view reading them are written next to it (`<output>.py` without `.parquet`),
+/// so the output is registered by copying them into the feature repository and `feast apply`.
+/// With the URL of a feature server, every chunk is also pushed to the online store through the
+/// push source (`POST /push`), so the rows are served without `feast materialize`.
+pub struct FeastPersistor {
+    filename: String,
+    view: String,
+    dimension: u16,
+    parquet: ParquetVectorPersistor,
+    #[cfg(feature = "feast")]
+    push: Option<FeastPush>,
+}
+
+impl FeastPersistor {
+    pub fn new(
+        filename: String,
+        dimension: u16,
+        produce_entity_occurrence_count: bool,
+        push_url: Option<&str>,
+    ) -> Result<Self, CleoraError> {
+        let event_timestamp = Utc::now();
+        let view = feature_view_name(&filename);
+        #[cfg(feature = "feast")]
+        let push = push_url.map(|url| FeastPush::new(url, &view, event_timestamp));
+        #[cfg(not(feature = "feast"))]
+        if let Some(url) = push_url {
+            return Err(CleoraError::feast(
+                url,
+                "cleora is built without the feast feature",
+            ));
+        }
+        let parquet = ParquetVectorPersistor::with_event_timestamp(
+            filename.clone(),
+            dimension,
+            produce_entity_occurrence_count,
+            event_timestamp,
+        )?;
+        Ok(Self {
+            filename,
+            view,
+            dimension,
+            parquet,
+            #[cfg(feature = "feast")]
+            push,
+        })
+    }
+
+    /// Files written for the output: the parquet file source and the definitions
+    pub fn output_files(ofp: &str) -> Vec<String> {
+        vec![ofp.to_string(), definitions_file_name(ofp)]
+    }
+
+    /// Writes the Python definitions of the feature view reading the written file
+    fn write_definitions(&self) -> Result<(), CleoraError> {
+        // feature repositories resolve relative paths against themselves, not the run directory
+        let path = if self.filename.starts_with("s3://") {
+            self.filename.clone()
+        } else {
+            fs::canonicalize(&self.filename)
+                .map_err(|e| CleoraError::read_file(&self.filename, e))?
+                .to_string_lossy()
+                .into_owned()
+        };
+        let definitions = feature_view_definitions(&self.view, &path, self.dimension);
+        let file_name = definitions_file_name(&self.filename);
+        let mut file = create_output(&file_name)?;
+        file.write_all(definitions.as_bytes())
+            .map_err(|e| CleoraError::write_file(&file_name, e))?;
+        file.commit()?;
+        info!("Feast feature view {} defined in {}", self.view, file_name);
+        Ok(())
+    }
+}
+
+impl EmbeddingPersistor for FeastPersistor {
+    fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), CleoraError> {
+        self.parquet.put_metadata(entity_count, dimension)
+    }
+
+    fn put_data(
+        &mut self,
+        entity: &str,
+        occur_count: u32,
+        vector: Vec<f32>,
+    ) -> Result<(), CleoraError> {
+        self.parquet.put_data(entity, occur_count, vector)
+    }
+
+    fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+        #[cfg(feature = "feast")]
+        if let Some(push) = self.push.as_ref() {
+            push.push(&chunk)?;
+        }
+        self.parquet.put_data_batch(chunk)
+    }
+
+    fn finish(&mut self) -> Result<(), CleoraError> {
+        self.parquet.finish()?;
+        self.write_definitions()
+    }
+}
+
+/// Python file of the definitions of the output
+pub fn definitions_file_name(ofp: &str) -> String {
+    format!("{}.py", ofp.strip_suffix(".parquet").unwrap_or(ofp))
+}
+
+/// Name of the feature view (and prefix of the other objects) of the output: its file name
+/// without `.parquet`, as a Python identifier
+fn feature_view_name(ofp: &str) -> String {
+    let stem = ofp.strip_suffix(".parquet").unwrap_or(ofp);
+    let file_name = stem.rsplit('/').next().unwrap_or(stem);
+    let mut name: String = file_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        name.insert(0, '_');
+    }
+    name
+}
+
+/// Definitions of the entity, the file and push sources and the feature view of the `f0`..`fN`
+/// features. The push source wraps the file source, so the view is materialized from the file and
+/// pushed rows go to the online store.
+fn feature_view_definitions(view: &str, path: &str, dimension: u16) -> String {
+    let fields: String = (0..dimension)
+        .map(|i| format!("        Field(name=\"f{}\", dtype=Float32),\n", i))
+        .collect();
+    format!(
+        r#"# Feast definitions of Cleora embeddings, written by cleora {version}.
+# Copy into the feature repository and run `feast apply`.
+from datetime import timedelta
+
+from feast import Entity, FeatureView, Field, FileSource, PushSource, ValueType
+from feast.types import Float32
+
+{view}_entity = Entity(
+    name="{view}_entity",
+    join_keys=["entity"],
+    value_type=ValueType.STRING,
+)
+
+{view}_source = FileSource(
+    name="{view}_source",
+    path="{path}",
+    timestamp_field="event_timestamp",
+)
+
+{view}_push = PushSource(
+    name="{view}_push",
+    batch_source={view}_source,
+)
+
+{view} = FeatureView(
+    name="{view}",
+    entities=[{view}_entity],
+    ttl=timedelta(seconds=0),
+    schema=[
+{fields}    ],
+    online=True,
+    source={view}_push,
+)
+"#,
+        version = env!("CARGO_PKG_VERSION"),
+        view = view,
+        path = path.replace('\\', "\\\\").replace('"', "\\\""),
+        fields = fields
+    )
+}
+
+/// Push source of a feature server the rows are written to the online store through
+#[cfg(feature = "feast")]
+struct FeastPush {
+    url: String,
+    agent: ureq::Agent,
+    source: String,
+    event_timestamp: String,
+}
+
+#[cfg(feature = "feast")]
+impl FeastPush {
+    fn new(url: &str, view: &str, event_timestamp: DateTime<Utc>) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(60))
+            .build();
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            agent,
+            source: format!("{}_push", view),
+            event_timestamp: event_timestamp.to_rfc3339_opts(SecondsFormat::Micros, true),
+        }
+    }
+
+    /// Pushes the rows as a data frame of columns
+    fn push(&self, chunk: &EmbeddingBatch) -> Result<(), CleoraError> {
+        let mut df = Map::new();
+        let entities: Vec<&str> = chunk.entities.iter().map(|e| e.as_ref()).collect();
+        df.insert(String::from("entity"), json!(entities));
+        df.insert(
+            String::from("event_timestamp"),
+            json!(vec![&self.event_timestamp; chunk.len()]),
+        );
+        for (i, column) in chunk.vectors.columns().into_iter().enumerate() {
+            df.insert(format!("f{}", i), json!(column.to_vec()));
+        }
+        let body = json!({
+            "push_source_name": self.source,
+            "df": Value::Object(df),
+            "to": "online",
+        });
+        match self
+            .agent
+            .post(&format!("{}/push", self.url))
+            .send_json(body)
+        {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(code, response)) => {
+                let message = response.into_string().unwrap_or_default();
+                Err(CleoraError::feast(
+                    &self.url,
+                    format!("status {} {}", code, message),
+                ))
+            }
+            Err(err) => Err(CleoraError::feast(&self.url, err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::feast::{definitions_file_name, feature_view_definitions, feature_view_name};
+
+    #[test]
+    fn name_feature_views_of_outputs() {
+        let ofp = "out/emb__users__items_feast.parquet";
+        assert_eq!("out/emb__users__items_feast.py", definitions_file_name(ofp));
+        assert_eq!("emb__users__items_feast", feature_view_name(ofp));
+        assert_eq!(
+            "_2022_emb_v1",
+            feature_view_name("s3://bucket/2022-emb.v1.parquet")
+        );
+
+        let definitions = feature_view_definitions("emb", "/data/emb.parquet", 2);
+        assert!(definitions.contains("path=\"/data/emb.parquet\""));
+        assert!(definitions.contains("PushSource(\n    name=\"emb_push\""));
+        assert!(definitions.contains(
+            "        Field(name=\"f0\", dtype=Float32),\n        Field(name=\"f1\", dtype=Float32),\n    ],"
+        ));
+    }
+}
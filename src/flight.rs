@@ -0,0 +1,483 @@
+use crate::error::CleoraError;
+use crate::persistence::embedding::{EmbeddingBatch, EmbeddingPersistor};
+use arrow2::array::{Array, Float32Array, UInt32Array, Utf8Array};
+use arrow2::chunk::Chunk;
+use arrow2::datatypes::{DataType, Field, Schema};
+use arrow2::io::flight::{serialize_batch, serialize_schema, serialize_schema_to_info};
+use arrow2::io::ipc::write::{default_ipc_fields, WriteOptions};
+use arrow2::io::parquet::read::{infer_schema, read_metadata, FileReader};
+use arrow_format::flight::data::{
+    flight_descriptor, Action, ActionType, Criteria, Empty, FlightData, FlightDescriptor,
+    FlightEndpoint, FlightInfo, HandshakeRequest, HandshakeResponse, PutResult, SchemaResult,
+    Ticket,
+};
+use arrow_format::flight::service::flight_service_server::{FlightService, FlightServiceServer};
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+use log::info;
+use std::fs::File;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tonic::transport::Server;
+use tonic::{Request, Response, Status, Streaming};
+
+/// Tables of the finished outputs, served when the run completes
+static TABLES: Mutex<Vec<FlightTable>> = Mutex::new(Vec::new());
+
+/// Embeddings of an output served as a table
+struct FlightTable {
+    name: String,
+    schema: Schema,
+    rows: usize,
+    data: TableData,
+}
+
+/// Record batches of a table
+enum TableData {
+    /// Chunks written by the run, kept in memory
+    Chunks(Arc<Vec<Chunk<Box<dyn Array>>>>),
+    /// Parquet output of an earlier run, its row groups are read while the table is pulled
+    Parquet(String),
+}
+
+type Batches = Box<dyn Iterator<Item = Result<Chunk<Box<dyn Array>>, Status>> + Send>;
+
+impl FlightTable {
+    fn from_chunks(name: String, schema: Schema, chunks: Vec<Chunk<Box<dyn Array>>>) -> Self {
+        Self {
+            name,
+            schema,
+            rows: chunks.iter().map(|chunk| chunk.len()).sum(),
+            data: TableData::Chunks(Arc::new(chunks)),
+        }
+    }
+
+    /// Table of parquet output, named like the file without its directory and `.parquet`
+    /// extension. Only the metadata is read.
+    fn open_parquet(path: &str) -> Result<Self, CleoraError> {
+        let mut file = File::open(path).map_err(|e| CleoraError::read_file(path, e))?;
+        let metadata =
+            read_metadata(&mut file).map_err(|e| CleoraError::invalid_embeddings(path, e))?;
+        let schema =
+            infer_schema(&metadata).map_err(|e| CleoraError::invalid_embeddings(path, e))?;
+        if !schema.fields.iter().any(|field| field.name == "entity") {
+            return Err(CleoraError::invalid_embeddings(path, "no entity column"));
+        }
+        let name = flight_table_name(path);
+        Ok(Self {
+            name: name.strip_suffix(".parquet").unwrap_or(name).to_string(),
+            schema,
+            rows: metadata.num_rows,
+            data: TableData::Parquet(path.to_string()),
+        })
+    }
+
+    /// Record batches, created one by one as they are sent
+    fn batches(&self) -> Result<Batches, Status> {
+        match &self.data {
+            TableData::Chunks(chunks) => {
+                let chunks = Arc::clone(chunks);
+                Ok(Box::new(
+                    (0..chunks.len()).map(move |i| Ok(chunks[i].clone())),
+                ))
+            }
+            TableData::Parquet(path) => {
+                let mut file = File::open(path).map_err(|e| Status::internal(e.to_string()))?;
+                let metadata =
+                    read_metadata(&mut file).map_err(|e| Status::internal(e.to_string()))?;
+                let reader =
+                    FileReader::new(file, metadata.row_groups, self.schema.clone(), None, None);
+                Ok(Box::new(reader.map(|chunk| {
+                    chunk.map_err(|e| Status::internal(e.to_string()))
+                })))
+            }
+        }
+    }
+}
+
+/// Name of the table served for the output, the file name of the output without its directory
+pub fn flight_table_name(ofp: &str) -> &str {
+    ofp.rsplit('/').next().unwrap_or(ofp)
+}
+
+/// Keeps embeddings in memory as an Arrow table with `entity`, optional `occur_count` and
+/// `f0`..`fN` columns (the columns of parquet output), nothing is written. The table is
+/// registered when finished and served by `serve_tables` after the run.
+pub struct FlightPersistor {
+    name: String,
+    schema: Schema,
+    chunks: Vec<Chunk<Box<dyn Array>>>,
+    produce_entity_occurrence_count: bool,
+}
+
+impl FlightPersistor {
+    pub fn new(ofp: &str, dimension: u16, produce_entity_occurrence_count: bool) -> Self {
+        let mut fields = vec![Field::new("entity", DataType::Utf8, false)];
+        if produce_entity_occurrence_count {
+            fields.push(Field::new("occur_count", DataType::UInt32, false));
+        }
+        fields.extend(
+            (0..dimension).map(|i| Field::new(format!("f{}", i), DataType::Float32, false)),
+        );
+        Self {
+            name: flight_table_name(ofp).to_string(),
+            schema: Schema::from(fields),
+            chunks: Vec::new(),
+            produce_entity_occurrence_count,
+        }
+    }
+}
+
+impl EmbeddingPersistor for FlightPersistor {
+    fn put_metadata(&mut self, _entity_count: u32, _dimension: u16) -> Result<(), CleoraError> {
+        Ok(())
+    }
+
+    fn put_data(
+        &mut self,
+        entity: &str,
+        occur_count: u32,
+        vector: Vec<f32>,
+    ) -> Result<(), CleoraError> {
+        let dimension = vector.len();
+        self.put_data_batch(EmbeddingBatch::new(
+            &[entity.into()],
+            &[occur_count],
+            &vector,
+            dimension,
+        ))
+    }
+
+    fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+        let mut arrays = vec![Utf8Array::<i32>::from_slice(chunk.entities).to_boxed()];
+        if self.produce_entity_occurrence_count {
+            arrays.push(UInt32Array::from_slice(chunk.occur_counts).to_boxed());
+        }
+        for column in chunk.vectors.columns() {
+            arrays.push(Float32Array::from_vec(column.to_vec()).to_boxed());
+        }
+        self.chunks.push(Chunk::new(arrays));
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), CleoraError> {
+        let table = FlightTable::from_chunks(
+            self.name.clone(),
+            self.schema.clone(),
+            std::mem::take(&mut self.chunks),
+        );
+        let mut tables = TABLES.lock().unwrap();
+        // snapshot or output of a previous run with the same name is replaced
+        tables.retain(|t| t.name != table.name);
+        tables.push(table);
+        Ok(())
+    }
+}
+
+/// Serves the tables of the run over Arrow Flight until the process is stopped. Every table is
+/// a flight with the path descriptor of its name, its ticket is the name too, so clients can get
+/// it directly, e.g. `client.do_get(flight.Ticket(b"emb__a__b"))` of pyarrow.
+pub fn serve_tables(address: &str) -> Result<(), CleoraError> {
+    let tables = std::mem::take(&mut *TABLES.lock().unwrap());
+    serve(address, tables)
+}
+
+/// Serves parquet outputs of earlier runs like `serve_tables` serves the outputs of the run
+pub fn serve_parquet_files(address: &str, paths: &[&str]) -> Result<(), CleoraError> {
+    let mut tables: Vec<FlightTable> = Vec::with_capacity(paths.len());
+    for path in paths {
+        let table = FlightTable::open_parquet(path)?;
+        if tables.iter().any(|t| t.name == table.name) {
+            return Err(CleoraError::invalid_embeddings(
+                path,
+                format!("another file is served as table {}", table.name),
+            ));
+        }
+        tables.push(table);
+    }
+    serve(address, tables)
+}
+
+fn serve(address: &str, tables: Vec<FlightTable>) -> Result<(), CleoraError> {
+    let addr: SocketAddr = address
+        .parse()
+        .map_err(|e| CleoraError::flight(address, e))?;
+    for table in tables.iter() {
+        info!(
+            "Serving {} rows of {} over Arrow Flight on {}",
+            table.rows, table.name, address
+        );
+    }
+    let service = FlightServiceServer::new(FlightTables { tables });
+    let runtime = tokio::runtime::Runtime::new().map_err(|e| CleoraError::flight(address, e))?;
+    runtime
+        .block_on(Server::builder().add_service(service).serve(addr))
+        .map_err(|e| CleoraError::flight(address, e))
+}
+
+type FlightStream<T> = stream::Iter<std::vec::IntoIter<Result<T, Status>>>;
+
+/// Read-only Flight service of the tables, uploads and actions aren't supported
+struct FlightTables {
+    tables: Vec<FlightTable>,
+}
+
+impl FlightTables {
+    fn table(&self, name: &[u8]) -> Result<&FlightTable, Status> {
+        self.tables
+            .iter()
+            .find(|table| table.name.as_bytes() == name)
+            .ok_or_else(|| Status::not_found(format!("No table {}", String::from_utf8_lossy(name))))
+    }
+
+    /// Table of the path descriptor
+    fn described_table(&self, descriptor: &FlightDescriptor) -> Result<&FlightTable, Status> {
+        match descriptor.path.as_slice() {
+            [name] => self.table(name.as_bytes()),
+            _ => Err(Status::invalid_argument(
+                "Tables are described by a path of their name",
+            )),
+        }
+    }
+
+    fn flight_info(&self, table: &FlightTable) -> Result<FlightInfo, Status> {
+        let schema = serialize_schema_to_info(&table.schema, None)
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(FlightInfo {
+            schema,
+            flight_descriptor: Some(FlightDescriptor {
+                r#type: flight_descriptor::DescriptorType::Path as i32,
+                cmd: vec![],
+                path: vec![table.name.clone()],
+            }),
+            endpoint: vec![FlightEndpoint {
+                ticket: Some(Ticket {
+                    ticket: table.name.as_bytes().to_vec(),
+                }),
+                location: vec![],
+            }],
+            total_records: table.rows as i64,
+            total_bytes: -1,
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl FlightService for FlightTables {
+    type HandshakeStream = FlightStream<HandshakeResponse>;
+    type ListFlightsStream = FlightStream<FlightInfo>;
+    type DoGetStream = BoxStream<'static, Result<FlightData, Status>>;
+    type DoPutStream = FlightStream<PutResult>;
+    type DoActionStream = FlightStream<arrow_format::flight::data::Result>;
+    type ListActionsStream = FlightStream<ActionType>;
+    type DoExchangeStream = FlightStream<FlightData>;
+
+    async fn handshake(
+        &self,
+        _request: Request<Streaming<HandshakeRequest>>,
+    ) -> Result<Response<Self::HandshakeStream>, Status> {
+        Err(Status::unimplemented("Authentication isn't supported"))
+    }
+
+    async fn list_flights(
+        &self,
+        _request: Request<Criteria>,
+    ) -> Result<Response<Self::ListFlightsStream>, Status> {
+        let flights: Vec<Result<FlightInfo, Status>> = self
+            .tables
+            .iter()
+            .map(|table| self.flight_info(table))
+            .collect();
+        Ok(Response::new(stream::iter(flights)))
+    }
+
+    async fn get_flight_info(
+        &self,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        let table = self.described_table(request.get_ref())?;
+        Ok(Response::new(self.flight_info(table)?))
+    }
+
+    async fn get_schema(
+        &self,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<SchemaResult>, Status> {
+        let table = self.described_table(request.get_ref())?;
+        let schema = serialize_schema_to_info(&table.schema, None)
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(SchemaResult { schema }))
+    }
+
+    async fn do_get(
+        &self,
+        request: Request<Ticket>,
+    ) -> Result<Response<Self::DoGetStream>, Status> {
+        let table = self.table(&request.get_ref().ticket)?;
+        let fields = default_ipc_fields(&table.schema.fields);
+        let schema = serialize_schema(&table.schema, Some(&fields));
+        // batches are serialized when the client pulls them, not all at once
+        let batches = table.batches()?.map(move |chunk| {
+            let options = WriteOptions { compression: None };
+            // columns have no dictionaries
+            let (_, batch) = serialize_batch(&chunk?, &fields, &options)
+                .map_err(|e| Status::internal(e.to_string()))?;
+            Ok(batch)
+        });
+        let messages = std::iter::once(Ok(schema)).chain(batches);
+        Ok(Response::new(stream::iter(messages).boxed()))
+    }
+
+    async fn do_put(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoPutStream>, Status> {
+        Err(Status::unimplemented("Tables are read-only"))
+    }
+
+    async fn do_action(
+        &self,
+        _request: Request<Action>,
+    ) -> Result<Response<Self::DoActionStream>, Status> {
+        Err(Status::unimplemented("Actions aren't supported"))
+    }
+
+    async fn list_actions(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::ListActionsStream>, Status> {
+        Ok(Response::new(stream::iter(vec![])))
+    }
+
+    async fn do_exchange(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoExchangeStream>, Status> {
+        Err(Status::unimplemented("Tables are read-only"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::CleoraError;
+    use crate::flight::{flight_table_name, FlightPersistor, FlightTable, FlightTables, TABLES};
+    use crate::persistence::embedding::{
+        EmbeddingBatch, EmbeddingPersistor, ParquetVectorPersistor,
+    };
+    use arrow_format::flight::data::{FlightDescriptor, Ticket};
+    use arrow_format::flight::service::flight_service_server::FlightService;
+    use futures::StreamExt;
+    use tonic::Request;
+
+    #[test]
+    fn serve_finished_tables() {
+        assert_eq!("emb__a__b", flight_table_name("out/emb__a__b"));
+
+        let mut persistor = FlightPersistor::new("out/cleora_flight__a__b", 2, true);
+        persistor.put_metadata(3, 2).unwrap();
+        persistor.put_data("a", 3, vec![1.0, 0.0]).unwrap();
+        persistor
+            .put_data_batch(EmbeddingBatch::new(
+                &["b".into(), "c".into()],
+                &[2, 1],
+                &[0.0, 1.0, 0.5, 0.5],
+                2,
+            ))
+            .unwrap();
+        persistor.finish().unwrap();
+
+        let tables: Vec<_> = {
+            let mut registered = TABLES.lock().unwrap();
+            let (table, others) = std::mem::take(&mut *registered)
+                .into_iter()
+                .partition(|t| t.name == "cleora_flight__a__b");
+            *registered = others;
+            table
+        };
+        assert_eq!(1, tables.len());
+        assert_eq!(3, tables[0].rows);
+        assert_eq!(4, tables[0].schema.fields.len());
+
+        let service = FlightTables { tables };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let descriptor = FlightDescriptor {
+                r#type: 1,
+                cmd: vec![],
+                path: vec![String::from("cleora_flight__a__b")],
+            };
+            let info = service
+                .get_flight_info(Request::new(descriptor))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(3, info.total_records);
+
+            let ticket = Ticket {
+                ticket: b"cleora_flight__a__b".to_vec(),
+            };
+            let messages: Vec<_> = service
+                .do_get(Request::new(ticket))
+                .await
+                .unwrap()
+                .into_inner()
+                .collect()
+                .await;
+            // schema and a batch of every chunk
+            assert_eq!(3, messages.len());
+
+            let missing = Ticket {
+                ticket: b"missing".to_vec(),
+            };
+            assert!(service.do_get(Request::new(missing)).await.is_err());
+        });
+    }
+
+    #[test]
+    fn serve_parquet_output() {
+        let filename = std::env::temp_dir().join("cleora_flight_serve.parquet");
+        let filename = filename.to_str().unwrap().to_string();
+        let mut persistor = ParquetVectorPersistor::new(filename.clone(), 2, true, false).unwrap();
+        persistor.put_metadata(2, 2).unwrap();
+        persistor
+            .put_data_batch(EmbeddingBatch::new(
+                &["a".into(), "b".into()],
+                &[2, 1],
+                &[0.0, 1.0, 0.5, 0.5],
+                2,
+            ))
+            .unwrap();
+        persistor.finish().unwrap();
+
+        let table = FlightTable::open_parquet(&filename).unwrap();
+        assert_eq!("cleora_flight_serve", table.name);
+        assert_eq!(2, table.rows);
+        assert_eq!(4, table.schema.fields.len());
+        let service = FlightTables {
+            tables: vec![table],
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let messages = runtime.block_on(async {
+            let ticket = Ticket {
+                ticket: b"cleora_flight_serve".to_vec(),
+            };
+            let messages: Vec<_> = service
+                .do_get(Request::new(ticket))
+                .await
+                .unwrap()
+                .into_inner()
+                .collect()
+                .await;
+            messages
+        });
+        // schema and a batch of the row group
+        assert_eq!(2, messages.len());
+        assert!(messages.iter().all(|message| message.is_ok()));
+        std::fs::remove_file(&filename).unwrap();
+
+        assert!(matches!(
+            FlightTable::open_parquet(&filename),
+            Err(CleoraError::ReadFile { .. })
+        ));
+    }
+}
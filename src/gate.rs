@@ -0,0 +1,182 @@
+use crate::error::CleoraError;
+use crate::query::CosineIndex;
+use serde_json::Value;
+use std::fs::File;
+use std::io::BufReader;
+
+/// Known similarity: `similar` entities are expected among the nearest neighbors of `entity`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Probe {
+    pub entity: String,
+    pub similar: Vec<String>,
+}
+
+/// Recall of the probes on one set of embeddings
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeScore {
+    /// Expected entities found among the `k` nearest neighbors
+    pub found: usize,
+    pub expected: usize,
+    /// Probed entities without embedding, all their expected entities count as not found
+    pub missing: usize,
+}
+
+impl ProbeScore {
+    pub fn recall(&self) -> f32 {
+        if self.expected == 0 {
+            return 0f32;
+        }
+        self.found as f32 / self.expected as f32
+    }
+}
+
+/// Reads probes from a JSON file: array of `{"entity": "a", "similar": ["b", "c"]}` objects.
+/// Known-similar pairs can be given as `["a", "b"]` arrays.
+pub fn load_probes(path: &str) -> Result<Vec<Probe>, CleoraError> {
+    let file = File::open(path).map_err(|e| CleoraError::read_file(path, e))?;
+    let json: Value = serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| CleoraError::invalid_probes(path, e))?;
+    let items = json
+        .as_array()
+        .ok_or_else(|| CleoraError::invalid_probes(path, "expected array of probes"))?;
+
+    let mut probes = Vec::with_capacity(items.len());
+    for (i, item) in items.iter().enumerate() {
+        let invalid = || CleoraError::invalid_probes(path, format!("invalid probe {}", i + 1));
+        let probe = match item {
+            Value::Array(pair) => match pair.as_slice() {
+                [Value::String(a), Value::String(b)] => Probe {
+                    entity: a.clone(),
+                    similar: vec![b.clone()],
+                },
+                _ => return Err(invalid()),
+            },
+            Value::Object(object) => {
+                let entity = object
+                    .get("entity")
+                    .and_then(Value::as_str)
+                    .ok_or_else(invalid)?;
+                let similar = object
+                    .get("similar")
+                    .and_then(Value::as_array)
+                    .ok_or_else(invalid)?
+                    .iter()
+                    .map(|s| s.as_str().map(String::from))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(invalid)?;
+                Probe {
+                    entity: entity.to_string(),
+                    similar,
+                }
+            }
+            _ => return Err(invalid()),
+        };
+        probes.push(probe);
+    }
+    Ok(probes)
+}
+
+/// Checks how many expected entities are among the `k` nearest neighbors of the probed ones.
+pub fn score(index: &CosineIndex, probes: &[Probe], k: usize) -> ProbeScore {
+    let mut score = ProbeScore {
+        found: 0,
+        expected: 0,
+        missing: 0,
+    };
+    for probe in probes {
+        score.expected += probe.similar.len();
+        match index.nearest_to_entity(&probe.entity, k) {
+            Some(neighbors) => {
+                score.found += probe
+                    .similar
+                    .iter()
+                    .filter(|s| neighbors.iter().any(|(n, _)| n == s))
+                    .count();
+            }
+            None => score.missing += 1,
+        }
+    }
+    score
+}
+
+/// The candidate passes if its recall is at least `min_recall` of the baseline recall.
+pub fn passes(baseline: &ProbeScore, candidate: &ProbeScore, min_recall: f32) -> bool {
+    candidate.recall() >= baseline.recall() * min_recall
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gate::{load_probes, passes, score, Probe};
+    use crate::loader::Embeddings;
+    use crate::query::CosineIndex;
+    use ndarray::arr2;
+
+    #[test]
+    fn read_probes() {
+        let path = std::env::temp_dir().join("cleora_gate_probes.json");
+        let path = path.to_str().unwrap();
+        std::fs::write(
+            path,
+            r#"[{"entity": "a", "similar": ["b", "c"]}, ["c", "d"]]"#,
+        )
+        .unwrap();
+        let probes = load_probes(path).unwrap();
+        assert_eq!(
+            vec![
+                Probe {
+                    entity: String::from("a"),
+                    similar: vec![String::from("b"), String::from("c")],
+                },
+                Probe {
+                    entity: String::from("c"),
+                    similar: vec![String::from("d")],
+                },
+            ],
+            probes
+        );
+
+        std::fs::write(path, r#"[{"entity": "a"}]"#).unwrap();
+        assert!(load_probes(path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn compare_recall() {
+        let entities: Vec<String> = vec!["a", "b", "c", "d"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let baseline = CosineIndex::new(Embeddings {
+            entities: entities.clone(),
+            vectors: arr2(&[[1.0, 0.0], [0.9, 0.1], [0.0, 1.0], [0.1, 0.9]]),
+        });
+        // b moved away from a
+        let candidate = CosineIndex::new(Embeddings {
+            entities,
+            vectors: arr2(&[[1.0, 0.0], [0.0, -1.0], [0.0, 1.0], [0.1, 0.9]]),
+        });
+        let probes = vec![
+            Probe {
+                entity: String::from("a"),
+                similar: vec![String::from("b")],
+            },
+            Probe {
+                entity: String::from("c"),
+                similar: vec![String::from("d")],
+            },
+            Probe {
+                entity: String::from("x"),
+                similar: vec![String::from("a")],
+            },
+        ];
+
+        let baseline_score = score(&baseline, &probes, 1);
+        let candidate_score = score(&candidate, &probes, 1);
+        assert_eq!(2, baseline_score.found);
+        assert_eq!(1, candidate_score.found);
+        assert_eq!(1, candidate_score.missing);
+        assert!(passes(&baseline_score, &baseline_score, 0.95));
+        assert!(!passes(&baseline_score, &candidate_score, 0.95));
+        assert!(passes(&baseline_score, &candidate_score, 0.4));
+    }
+}
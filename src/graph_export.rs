@@ -0,0 +1,336 @@
+use crate::configuration::Configuration;
+use crate::entity::create_entity_mapping_persistor;
+use crate::error::CleoraError;
+use crate::persistence::entity::{EntityMappingPersistor, InMemoryEntityMappingPersistor};
+use crate::pipeline::build_graphs;
+use crate::sparse_matrix::{SparseMatrix, SparseMatrixReader};
+use log::info;
+use rustc_hash::FxHashSet;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+
+/// Graph file format readable by visualization tools (Gephi, Cytoscape)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GraphFormat {
+    Gexf,
+    GraphMl,
+}
+
+impl GraphFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            GraphFormat::Gexf => "gexf",
+            GraphFormat::GraphMl => "graphml",
+        }
+    }
+}
+
+/// Writer of graph nodes and edges. Every node is written before any edge.
+pub trait GraphWriter {
+    fn put_node(
+        &mut self,
+        id: u32,
+        label: &str,
+        degree: u32,
+        occurrence: u32,
+    ) -> Result<(), io::Error>;
+    fn start_edges(&mut self) -> Result<(), io::Error>;
+    fn put_edge(&mut self, source: u32, target: u32, weight: f32) -> Result<(), io::Error>;
+    fn finish(&mut self) -> Result<(), io::Error>;
+}
+
+/// Builds the graph and writes a view of every sparse matrix for visualization tools. Only
+/// `max_nodes` most frequent entities (and edges between them) are kept for every matrix.
+/// Returns paths of the written files.
+pub fn export_graph(
+    config: Configuration,
+    format: GraphFormat,
+    max_nodes: usize,
+) -> Result<Vec<String>, CleoraError> {
+    let directory = match config.output_dir.as_ref() {
+        Some(out) => format!("{}/", out),
+        None => String::from(""),
+    };
+
+    let entity_mapping_persistor = Arc::new(create_entity_mapping_persistor(&config));
+    let sparse_matrices = build_graphs(&config, entity_mapping_persistor.clone())?;
+
+    let mut file_names = Vec::new();
+    for sparse_matrix in sparse_matrices.iter() {
+        let file_name = format!(
+            "{}{}__{}__{}.{}",
+            directory,
+            config.relation_name,
+            sparse_matrix.col_a_name,
+            sparse_matrix.col_b_name,
+            format.extension()
+        );
+        let file = BufWriter::new(File::create(&file_name)?);
+        let graph_id = sparse_matrix.get_id();
+        let mut writer: Box<dyn GraphWriter> = match format {
+            GraphFormat::Gexf => Box::new(GexfWriter::new(file)?),
+            GraphFormat::GraphMl => Box::new(GraphMlWriter::new(file, &graph_id)?),
+        };
+        write_graph(
+            sparse_matrix,
+            &entity_mapping_persistor,
+            max_nodes,
+            writer.as_mut(),
+        )?;
+        info!("Graph {} written to {}", graph_id, file_name);
+        file_names.push(file_name);
+    }
+    Ok(file_names)
+}
+
+fn write_graph(
+    sparse_matrix: &SparseMatrix,
+    entity_mapping_persistor: &InMemoryEntityMappingPersistor,
+    max_nodes: usize,
+    writer: &mut dyn GraphWriter,
+) -> Result<(), io::Error> {
+    let hashes: Vec<_> = sparse_matrix.iter_hashes().collect();
+    let mut degrees = vec![0u32; hashes.len()];
+    for entry in sparse_matrix.iter_entries() {
+        degrees[entry.row as usize] += 1;
+    }
+
+    // the most frequent entities are kept
+    let mut ids: Vec<u32> = (0..hashes.len() as u32).collect();
+    if ids.len() > max_nodes {
+        ids.sort_by_key(|&id| (std::cmp::Reverse(hashes[id as usize].occurrence), id));
+        ids.truncate(max_nodes);
+        ids.sort_unstable();
+        info!(
+            "Graph {} sampled to {} of {} nodes",
+            sparse_matrix.get_id(),
+            max_nodes,
+            hashes.len()
+        );
+    }
+
+    let mut kept: FxHashSet<u32> = FxHashSet::default();
+    for &id in ids.iter() {
+        let hash = hashes[id as usize];
+        // entities of transient columns have no mapping
+        let label = entity_mapping_persistor
+            .get_entity(hash.value)
+            .unwrap_or_else(|| hash.value.to_string().into());
+        writer.put_node(id, &label, degrees[id as usize], hash.occurrence)?;
+        kept.insert(id);
+    }
+
+    writer.start_edges()?;
+    // every edge is stored in both directions, write it once with the weight before
+    // normalization of the entries
+    for entry in sparse_matrix.iter_entries() {
+        if entry.row <= entry.col && kept.contains(&entry.row) && kept.contains(&entry.col) {
+            let weight = entry.value * sparse_matrix.get_row_sum(entry.row);
+            writer.put_edge(entry.row, entry.col, weight)?;
+        }
+    }
+    writer.finish()
+}
+
+/// Escapes characters which can't be placed in XML attributes and text
+fn escape_xml(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Writes graph in GEXF format (Gephi)
+pub struct GexfWriter<W: Write> {
+    writer: W,
+    edge_count: u64,
+}
+
+impl<W: Write> GexfWriter<W> {
+    pub fn new(mut writer: W) -> Result<Self, io::Error> {
+        writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            writer,
+            r#"<gexf xmlns="http://gexf.net/1.3" version="1.3">"#
+        )?;
+        writeln!(writer, r#"  <graph defaultedgetype="undirected">"#)?;
+        writeln!(writer, r#"    <attributes class="node">"#)?;
+        writeln!(
+            writer,
+            r#"      <attribute id="degree" title="degree" type="integer"/>"#
+        )?;
+        writeln!(
+            writer,
+            r#"      <attribute id="occurrence" title="occurrence" type="integer"/>"#
+        )?;
+        writeln!(writer, r#"    </attributes>"#)?;
+        writeln!(writer, r#"    <nodes>"#)?;
+        Ok(GexfWriter {
+            writer,
+            edge_count: 0,
+        })
+    }
+}
+
+impl<W: Write> GraphWriter for GexfWriter<W> {
+    fn put_node(
+        &mut self,
+        id: u32,
+        label: &str,
+        degree: u32,
+        occurrence: u32,
+    ) -> Result<(), io::Error> {
+        writeln!(
+            self.writer,
+            r#"      <node id="{}" label="{}"><attvalues><attvalue for="degree" value="{}"/><attvalue for="occurrence" value="{}"/></attvalues></node>"#,
+            id,
+            escape_xml(label),
+            degree,
+            occurrence
+        )
+    }
+
+    fn start_edges(&mut self) -> Result<(), io::Error> {
+        writeln!(self.writer, r#"    </nodes>"#)?;
+        writeln!(self.writer, r#"    <edges>"#)
+    }
+
+    fn put_edge(&mut self, source: u32, target: u32, weight: f32) -> Result<(), io::Error> {
+        writeln!(
+            self.writer,
+            r#"      <edge id="{}" source="{}" target="{}" weight="{}"/>"#,
+            self.edge_count, source, target, weight
+        )?;
+        self.edge_count += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), io::Error> {
+        writeln!(self.writer, r#"    </edges>"#)?;
+        writeln!(self.writer, r#"  </graph>"#)?;
+        writeln!(self.writer, r#"</gexf>"#)?;
+        self.writer.flush()
+    }
+}
+
+/// Writes graph in GraphML format (Cytoscape, Gephi, networkx)
+pub struct GraphMlWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> GraphMlWriter<W> {
+    pub fn new(mut writer: W, graph_id: &str) -> Result<Self, io::Error> {
+        writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            writer,
+            r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#
+        )?;
+        writeln!(
+            writer,
+            r#"  <key id="label" for="node" attr.name="label" attr.type="string"/>"#
+        )?;
+        writeln!(
+            writer,
+            r#"  <key id="degree" for="node" attr.name="degree" attr.type="int"/>"#
+        )?;
+        writeln!(
+            writer,
+            r#"  <key id="occurrence" for="node" attr.name="occurrence" attr.type="int"/>"#
+        )?;
+        writeln!(
+            writer,
+            r#"  <key id="weight" for="edge" attr.name="weight" attr.type="double"/>"#
+        )?;
+        writeln!(
+            writer,
+            r#"  <graph id="{}" edgedefault="undirected">"#,
+            escape_xml(graph_id)
+        )?;
+        Ok(GraphMlWriter { writer })
+    }
+}
+
+impl<W: Write> GraphWriter for GraphMlWriter<W> {
+    fn put_node(
+        &mut self,
+        id: u32,
+        label: &str,
+        degree: u32,
+        occurrence: u32,
+    ) -> Result<(), io::Error> {
+        writeln!(
+            self.writer,
+            r#"    <node id="n{}"><data key="label">{}</data><data key="degree">{}</data><data key="occurrence">{}</data></node>"#,
+            id,
+            escape_xml(label),
+            degree,
+            occurrence
+        )
+    }
+
+    fn start_edges(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    fn put_edge(&mut self, source: u32, target: u32, weight: f32) -> Result<(), io::Error> {
+        writeln!(
+            self.writer,
+            r#"    <edge source="n{}" target="n{}"><data key="weight">{}</data></edge>"#,
+            source, target, weight
+        )
+    }
+
+    fn finish(&mut self) -> Result<(), io::Error> {
+        writeln!(self.writer, r#"  </graph>"#)?;
+        writeln!(self.writer, r#"</graphml>"#)?;
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::graph_export::{escape_xml, GexfWriter, GraphMlWriter, GraphWriter};
+
+    fn write_sample(writer: &mut dyn GraphWriter) {
+        writer.put_node(0, "a&b", 1, 2).unwrap();
+        writer.put_node(1, "c", 1, 1).unwrap();
+        writer.start_edges().unwrap();
+        writer.put_edge(0, 1, 0.5).unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn escape_xml_special_characters() {
+        assert_eq!(
+            "&lt;a href=&quot;x&quot;&gt;&amp;&apos;",
+            escape_xml(r#"<a href="x">&'"#)
+        );
+    }
+
+    #[test]
+    fn write_gexf_and_graphml() {
+        let mut buf = Vec::new();
+        write_sample(&mut GexfWriter::new(&mut buf).unwrap());
+        let gexf = String::from_utf8(buf).unwrap();
+        assert!(gexf.contains(r#"<node id="0" label="a&amp;b">"#));
+        assert!(gexf.contains(r#"<edge id="0" source="0" target="1" weight="0.5"/>"#));
+        assert!(gexf.trim_end().ends_with("</gexf>"));
+
+        let mut buf = Vec::new();
+        write_sample(&mut GraphMlWriter::new(&mut buf, "0_1").unwrap());
+        let graphml = String::from_utf8(buf).unwrap();
+        assert!(graphml.contains(r#"<node id="n0"><data key="label">a&amp;b</data>"#));
+        assert!(graphml.contains(r#"<edge source="n0" target="n1"><data key="weight">0.5</data>"#));
+        assert!(graphml.trim_end().ends_with("</graphml>"));
+    }
+}
@@ -0,0 +1,284 @@
+use std::io;
+use std::io::BufRead;
+use std::str;
+
+/// Edge element of a GraphML file
+#[derive(Debug, PartialEq)]
+pub struct GraphMlEdge {
+    /// Line the element starts at
+    pub line: u64,
+    /// Ids of the source and target nodes, or the reason the element is malformed
+    pub nodes: Result<(String, String), String>,
+}
+
+/// Reads the edges of a GraphML file (e.g. written by NetworkX, igraph or Gephi) one by one,
+/// without loading the document. Nodes, keys and data are skipped: only the ids of the nodes are
+/// embedded and nodes without edges don't get embeddings anyway. Graphs are read as undirected,
+/// like every Cleora graph.
+pub struct GraphMlEdges<R> {
+    reader: R,
+    markup: Vec<u8>,
+    text: Vec<u8>,
+    line: u64,
+    bytes: u64,
+}
+
+impl<R: BufRead> GraphMlEdges<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            markup: Vec::new(),
+            text: Vec::new(),
+            line: 1,
+            bytes: 0,
+        }
+    }
+
+    /// Bytes read so far
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Reads up to and including the byte, returns false at the end of the file
+    fn read_until(&mut self, byte: u8, into_markup: bool) -> io::Result<bool> {
+        let buffer = if into_markup {
+            &mut self.markup
+        } else {
+            &mut self.text
+        };
+        let start = buffer.len();
+        let read = self.reader.read_until(byte, buffer)?;
+        self.bytes += read as u64;
+        self.line += buffer[start..].iter().filter(|&&b| b == b'\n').count() as u64;
+        Ok(read > 0 && buffer.last() == Some(&byte))
+    }
+
+    /// Reads the markup following `<` up to its `>`: comments, CDATA and processing instructions
+    /// end at their own terminators, `>` in quoted values (and DOCTYPE subsets) doesn't end tags
+    fn read_markup(&mut self) -> io::Result<()> {
+        self.markup.clear();
+        loop {
+            if !self.read_until(b'>', true)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "unterminated markup",
+                ));
+            }
+            let markup = &self.markup;
+            let complete = if markup.starts_with(b"!--") {
+                markup.len() >= 5 && markup.ends_with(b"-->")
+            } else if markup.starts_with(b"![CDATA[") {
+                markup.ends_with(b"]]>")
+            } else if markup.starts_with(b"?") {
+                markup.ends_with(b"?>")
+            } else {
+                tag_complete(markup)
+            };
+            if complete {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for GraphMlEdges<R> {
+    type Item = io::Result<GraphMlEdge>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.text.clear();
+            match self.read_until(b'<', false) {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(err) => return Some(Err(err)),
+            }
+            let line = self.line;
+            if let Err(err) = self.read_markup() {
+                return Some(Err(err));
+            }
+            if element_name(&self.markup) == Some(b"edge") {
+                let nodes = edge_nodes(&self.markup);
+                return Some(Ok(GraphMlEdge { line, nodes }));
+            }
+        }
+    }
+}
+
+/// Whether the `>` at the end of the tag is outside quoted values and brackets
+fn tag_complete(markup: &[u8]) -> bool {
+    let mut quote = None;
+    let mut depth = 0i32;
+    for &b in &markup[..markup.len() - 1] {
+        match (quote, b) {
+            (Some(q), _) if b == q => quote = None,
+            (Some(_), _) => {}
+            (None, b'"') | (None, b'\'') => quote = Some(b),
+            (None, b'[') => depth += 1,
+            (None, b']') => depth -= 1,
+            _ => {}
+        }
+    }
+    quote.is_none() && depth <= 0
+}
+
+/// Local name (without a namespace prefix) of a start tag, `None` for end tags and other markup
+fn element_name(markup: &[u8]) -> Option<&[u8]> {
+    let end = markup
+        .iter()
+        .position(|b| b.is_ascii_whitespace() || *b == b'/' || *b == b'>')
+        .unwrap_or(markup.len());
+    let name = &markup[..end];
+    if name.is_empty() || !(name[0].is_ascii_alphabetic() || name[0] == b'_') {
+        return None;
+    }
+    Some(match name.iter().rposition(|&b| b == b':') {
+        Some(colon) => &name[colon + 1..],
+        None => name,
+    })
+}
+
+/// Source and target attributes of an edge tag
+fn edge_nodes(markup: &[u8]) -> Result<(String, String), String> {
+    let markup = str::from_utf8(markup).map_err(|err| format!("Invalid UTF-8. {}", err))?;
+    let (mut source, mut target) = (None, None);
+    let mut rest = markup
+        .trim_end_matches('>')
+        .trim_end_matches('/')
+        .trim_start_matches(|c: char| !c.is_whitespace());
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        let (name, tail) = rest
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid attributes of edge [{}]", rest))?;
+        let tail = tail.trim_start();
+        let quote = tail
+            .chars()
+            .next()
+            .filter(|&c| c == '"' || c == '\'')
+            .ok_or_else(|| format!("Unquoted value of attribute {}", name.trim()))?;
+        let (value, tail) = tail[1..]
+            .split_once(quote)
+            .ok_or_else(|| format!("Unterminated value of attribute {}", name.trim()))?;
+        match name.trim() {
+            "source" => source = Some(unescape(value)?),
+            "target" => target = Some(unescape(value)?),
+            _ => {}
+        }
+        rest = tail;
+    }
+    match (source, target) {
+        (Some(source), Some(target)) => Ok((source, target)),
+        _ => Err(String::from("Edge without source or target")),
+    }
+}
+
+/// Replaces the predefined entities and character references of an attribute value
+fn unescape(value: &str) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(amp) = rest.find('&') {
+        unescaped.push_str(&rest[..amp]);
+        let end = rest[amp..]
+            .find(';')
+            .ok_or_else(|| format!("Unterminated reference in [{}]", value))?;
+        let reference = &rest[amp + 1..amp + end];
+        let c = match reference {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match reference.strip_prefix('#') {
+                Some(code) => match code.strip_prefix('x') {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => code.parse().ok(),
+                }
+                .and_then(char::from_u32),
+                None => None,
+            },
+        };
+        let c = c.ok_or_else(|| format!("Unknown reference &{}; in [{}]", reference, value))?;
+        unescaped.push(c);
+        rest = &rest[amp + end + 1..];
+    }
+    unescaped.push_str(rest);
+    Ok(unescaped)
+}
+
+/// Source and target of a line of a SNAP edge list (`FromNodeId ToNodeId`, separated by tabs or
+/// spaces), `None` for blank lines and comments (`#`, or `%` of KONECT and Matrix Market lists).
+/// Further fields (weights, timestamps) are ignored.
+pub fn parse_edge_line(line: &str) -> Result<Option<(&str, &str)>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with('%') {
+        return Ok(None);
+    }
+    let mut fields = line.split_ascii_whitespace();
+    match (fields.next(), fields.next()) {
+        (Some(source), Some(target)) => Ok(Some((source, target))),
+        _ => Err(format!("Edge without target in line [{}]", line)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::graph_import::{parse_edge_line, GraphMlEdge, GraphMlEdges};
+
+    #[test]
+    fn read_graphml_edges() {
+        let graphml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!-- <edge source="commented" target="out"/> -->
+<graphml xmlns="http://graphml.graphdrawing.org/xmlns">
+  <key id="w" for="edge" attr.name="weight" attr.type="double"/>
+  <graph id="G" edgedefault="directed">
+    <node id="a &amp; b"/>
+    <node id="c"/>
+    <edge id="e0" source="a &amp; b" target='c'><data key="w">1.5</data></edge>
+    <edge label="x > y"
+          source="c" target="&#x17C;&#243;&#322;w"/>
+    <edge source="c"/>
+  </graph>
+</graphml>
+"#;
+        let edges: Vec<GraphMlEdge> = GraphMlEdges::new(graphml.as_bytes())
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            vec![
+                GraphMlEdge {
+                    line: 8,
+                    nodes: Ok((String::from("a & b"), String::from("c")))
+                },
+                GraphMlEdge {
+                    line: 9,
+                    nodes: Ok((String::from("c"), String::from("żółw")))
+                },
+                GraphMlEdge {
+                    line: 11,
+                    nodes: Err(String::from("Edge without source or target"))
+                },
+            ],
+            edges
+        );
+
+        let truncated = r#"<graphml><edge source="a" target="b"#;
+        let mut edges = GraphMlEdges::new(truncated.as_bytes());
+        assert!(edges.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn parse_snap_edge_lines() {
+        assert_eq!(Ok(None), parse_edge_line("# FromNodeId\tToNodeId\n"));
+        assert_eq!(Ok(None), parse_edge_line("% sym unweighted\n"));
+        assert_eq!(Ok(None), parse_edge_line("\n"));
+        assert_eq!(Ok(Some(("1", "2"))), parse_edge_line("1\t2\n"));
+        assert_eq!(
+            Ok(Some(("1", "3"))),
+            parse_edge_line("1 3 0.5 1217567877\n")
+        );
+        assert!(parse_edge_line("4\n").is_err());
+    }
+}
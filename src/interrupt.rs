@@ -0,0 +1,32 @@
+#[cfg(feature = "fs")]
+use log::warn;
+#[cfg(feature = "fs")]
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Exit code of interrupted runs, as shells report runs killed by SIGINT
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Traps Ctrl-C (SIGINT) and SIGTERM, so the training stops after the current iteration (or the
+/// writers after the current chunk) and the outputs are finished, instead of being left behind as
+/// half-written files. Another signal exits right away.
+#[cfg(feature = "fs")]
+pub fn handle_interrupts() {
+    let handler = ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            warn!("Interrupted again, exiting without finishing the outputs.");
+            process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        warn!("Interrupted, stopping after the current iteration and finishing the outputs. Interrupt again to exit right away.");
+    });
+    if let Err(err) = handler {
+        warn!("Interrupts can't be handled, they kill the run. {}", err);
+    }
+}
+
+/// The run was interrupted, work should stop at the next point the outputs stay valid
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
@@ -1,14 +1,183 @@
+use crate::configuration::StorageClass;
+use crate::error::CleoraError;
+use log::error;
 use rusoto_core::region::Region;
 use rusoto_core::{ByteStream, RusotoError};
 use rusoto_s3::{
     AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
-    CompletedPart, CreateMultipartUploadRequest, GetObjectError, GetObjectRequest,
-    UploadPartRequest,
+    CompletedPart, CreateMultipartUploadRequest, DeleteObjectRequest, GetObjectError,
+    GetObjectRequest, HeadObjectRequest, PutObjectRequest, UploadPartRequest,
 };
 use rusoto_s3::{S3Client, S3};
+use std::collections::VecDeque;
 use std::env;
+use std::fs;
+use std::fs::File;
+use std::future::Future;
+use std::io;
 use std::io::{Error, Read, Write};
+use std::sync::{Mutex, OnceLock};
 use std::time::Duration;
+use tokio::runtime::{Builder, Runtime};
+use tokio::task::JoinHandle;
+
+/// Suffix of output files which are being written. The file is renamed to its target name when
+/// finished, so partially written outputs never show up under the target name.
+const PARTIAL_FILE_SUFFIX: &str = ".partial";
+
+/// Temporary name of the output file while it's being written. Outputs to S3 which can't be
+/// streamed (memory-mapped arrays, archives) are staged in the temporary directory.
+pub fn partial_path(path: &str) -> String {
+    match path.strip_prefix("s3://") {
+        Some(key) => env::temp_dir()
+            .join(format!(
+                "cleora-{}{}",
+                key.replace('/', "_"),
+                PARTIAL_FILE_SUFFIX
+            ))
+            .to_string_lossy()
+            .into_owned(),
+        None => format!("{}{}", path, PARTIAL_FILE_SUFFIX),
+    }
+}
+
+/// Creates output file under its temporary name. Call `commit_file` once it's fully written.
+pub fn create_partial_file(path: &str) -> Result<File, CleoraError> {
+    let partial_path = partial_path(path);
+    File::create(&partial_path).map_err(|e| CleoraError::create_file(&partial_path, e))
+}
+
+/// Atomically renames fully written output file to its target name. Output to S3 is uploaded
+/// from its staged file, which is removed then.
+pub fn commit_file(path: &str) -> Result<(), CleoraError> {
+    let partial_path = partial_path(path);
+    if !path.starts_with("s3://") {
+        return fs::rename(partial_path, path).map_err(|e| CleoraError::write_file(path, e));
+    }
+
+    let mut staged =
+        File::open(&partial_path).map_err(|e| CleoraError::read_file(&partial_path, e))?;
+    let mut upload = S3File::create(path.to_string())?;
+    // the upload is aborted by the drop if the copy fails
+    io::copy(&mut staged, &mut upload).map_err(|e| CleoraError::write_file(path, e))?;
+    upload.complete()?;
+    fs::remove_file(&partial_path).map_err(|e| CleoraError::write_file(&partial_path, e))
+}
+
+/// Output created by `create_output`
+pub enum OutputFile {
+    /// Local file under its temporary name
+    Local { path: String, file: File },
+    /// S3 object uploaded in parts as it's written, dropping it without the commit aborts the
+    /// upload. Boxed, since it holds the buffered part and the client.
+    S3(Box<S3File>),
+}
+
+impl OutputFile {
+    /// Commits fully written (and flushed) output: renames the local file to its target name or
+    /// completes the upload, so the S3 object shows up.
+    pub fn commit(&mut self) -> Result<(), CleoraError> {
+        match self {
+            OutputFile::Local { path, .. } => commit_file(path),
+            OutputFile::S3(file) => file.complete(),
+        }
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self {
+            OutputFile::Local { file, .. } => file.write(buf),
+            OutputFile::S3(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            OutputFile::Local { file, .. } => file.flush(),
+            OutputFile::S3(file) => file.flush(),
+        }
+    }
+}
+
+/// Creates output written sequentially to its target, a local file or S3 object
+/// (`s3://bucket/key`), so persistors don't have to tell them apart. Call `OutputFile::commit`
+/// once it's fully written.
+pub fn create_output(path: &str) -> Result<OutputFile, CleoraError> {
+    if path.starts_with("s3://") {
+        S3File::create(path.to_string()).map(|file| OutputFile::S3(Box::new(file)))
+    } else {
+        let file = create_partial_file(path)?;
+        Ok(OutputFile::Local {
+            path: path.to_string(),
+            file,
+        })
+    }
+}
+
+/// Parts of a file uploaded at the same time, writes wait for the oldest part when all of them
+/// are in flight
+const S3_CONCURRENT_UPLOADS: usize = 4;
+
+/// Worker threads of the runtime running S3 requests
+const S3_WORKER_THREADS: usize = 4;
+
+/// Storage class of the objects written to S3, see `set_storage_class`
+static STORAGE_CLASS: Mutex<StorageClass> = Mutex::new(StorageClass::Standard);
+
+/// Sets the storage class of the objects written to S3 from now on
+pub fn set_storage_class(storage_class: StorageClass) {
+    *STORAGE_CLASS.lock().unwrap() = storage_class;
+}
+
+/// Storage class of the written objects as named by the S3 API, none for the default (standard)
+/// one, so S3 compatible stores without storage classes accept the requests
+fn storage_class() -> Option<String> {
+    let name = match *STORAGE_CLASS.lock().unwrap() {
+        StorageClass::Standard => return None,
+        StorageClass::IntelligentTiering => "INTELLIGENT_TIERING",
+        StorageClass::StandardIa => "STANDARD_IA",
+        StorageClass::OneZoneIa => "ONEZONE_IA",
+        StorageClass::GlacierIr => "GLACIER_IR",
+    };
+    Some(name.to_string())
+}
+
+/// Runtime of S3 requests shared by all files (and Delta table commits). Files are used from
+/// synchronous code (readers, persistors), which blocks on the requests, only part uploads run in
+/// the background.
+pub(crate) fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .worker_threads(S3_WORKER_THREADS)
+            .thread_name("cleora-s3")
+            .enable_all()
+            .build()
+            .expect("Couldn't start the runtime of S3 requests")
+    })
+}
+
+/// Runs the request on the runtime, fails if it doesn't finish within the timeout
+async fn with_timeout<T, E, F>(path: &str, timeout: Duration, request: F) -> Result<T, CleoraError>
+where
+    F: Future<Output = Result<T, RusotoError<E>>>,
+    E: std::error::Error + 'static,
+{
+    match tokio::time::timeout(timeout, request).await {
+        Ok(result) => result.map_err(|e| CleoraError::s3(path, e)),
+        Err(_) => Err(CleoraError::s3(path, "request timed out")),
+    }
+}
+
+/// Blocks on the request, see `with_timeout`
+fn request<T, E, F>(path: &str, timeout: Duration, request: F) -> Result<T, CleoraError>
+where
+    F: Future<Output = Result<T, RusotoError<E>>>,
+    E: std::error::Error + 'static,
+{
+    runtime().block_on(with_timeout(path, timeout, request))
+}
 
 pub struct S3File {
     bucket_name: String,
@@ -16,75 +185,182 @@ pub struct S3File {
     s3_client: S3Client,
     upload_id: String,
     completed_parts: Vec<CompletedPart>,
+    /// Parts being uploaded, in order of their numbers
+    uploads: VecDeque<JoinHandle<Result<CompletedPart, CleoraError>>>,
     part_number: i64,
     buff: Vec<u8>,
     completed: bool,
     part_size: usize,
 }
 
+/// Uploads which weren't completed (the output failed or wasn't fully written) are aborted, so
+/// truncated objects never show up
 impl Drop for S3File {
     fn drop(&mut self) {
-        self.complete();
+        if !self.completed {
+            if let Err(err) = self.abort_upload() {
+                error!("{}", err);
+            }
+        }
     }
 }
 
 impl S3File {
-    pub fn create(filename: String) -> S3File {
-        let (s3_client, bucket_name, object_key) = S3File::create_client(filename);
+    pub fn create(filename: String) -> Result<S3File, CleoraError> {
+        let (s3_client, bucket_name, object_key) = S3File::create_client(&filename)?;
+        S3File::start_upload(&filename, s3_client, bucket_name, object_key)
+    }
 
+    /// Starts the multipart upload of the object
+    fn start_upload(
+        filename: &str,
+        s3_client: S3Client,
+        bucket_name: String,
+        object_key: String,
+    ) -> Result<S3File, CleoraError> {
         let part_size = 10 * 1024 * 1024;
         let timeout = Duration::from_secs(10);
 
         let completed_parts: Vec<CompletedPart> = Vec::new();
-        let upload_id = &s3_client
-            .create_multipart_upload(CreateMultipartUploadRequest {
+        let upload_id = &request(
+            filename,
+            timeout,
+            s3_client.create_multipart_upload(CreateMultipartUploadRequest {
                 bucket: bucket_name.clone(),
                 key: object_key.clone(),
+                storage_class: storage_class(),
                 //content_type: Some(meta.content_type),
                 //content_disposition: meta.content_disposition,
                 //content_language: meta.content_language,
                 ..Default::default()
-            })
-            .with_timeout(timeout)
-            .sync()
-            .unwrap()
-            .upload_id
-            .expect("no upload ID");
+            }),
+        )?
+        .upload_id
+        .ok_or_else(|| CleoraError::s3(filename, "no upload ID"))?;
 
         let buff = Vec::new();
 
-        S3File {
+        Ok(S3File {
             bucket_name,
             object_key,
             s3_client,
             upload_id: upload_id.to_string(),
             completed_parts,
+            uploads: VecDeque::new(),
             part_number: 0,
             buff,
             completed: false,
             part_size,
-        }
+        })
     }
 
-    pub fn open(
-        filename: String,
-    ) -> Result<impl std::io::Read + Send, RusotoError<GetObjectError>> {
-        let (s3_client, bucket_name, object_key) = S3File::create_client(filename);
+    pub fn open(filename: String) -> Result<impl std::io::Read + Send, CleoraError> {
+        let (s3_client, bucket_name, object_key) = S3File::create_client(&filename)?;
 
         let data_timeout = Duration::from_secs(300);
 
-        s3_client
-            .get_object(GetObjectRequest {
+        request(
+            &filename,
+            data_timeout,
+            s3_client.get_object(GetObjectRequest {
                 bucket: bucket_name.clone(),
                 key: object_key.clone(),
                 ..Default::default()
-            })
-            .with_timeout(data_timeout)
-            .sync()
-            .map(|output| output.body.unwrap().into_blocking_read())
+            }),
+        )?
+        .body
+        .map(|body| body.into_blocking_read())
+        .ok_or_else(|| CleoraError::s3(&filename, "no object body"))
     }
 
-    fn create_client(filename: String) -> (S3Client, String, String) {
+    /// Reads the whole (small) object, `None` if there is no such object
+    pub fn read_if_exists(filename: &str) -> Result<Option<Vec<u8>>, CleoraError> {
+        let (s3_client, bucket_name, object_key) = S3File::create_client(filename)?;
+        let timeout = Duration::from_secs(10);
+
+        let get_object = s3_client.get_object(GetObjectRequest {
+            bucket: bucket_name,
+            key: object_key,
+            ..Default::default()
+        });
+        let result = runtime().block_on(async { tokio::time::timeout(timeout, get_object).await });
+        let body = match result {
+            Ok(Ok(output)) => output.body,
+            Ok(Err(RusotoError::Service(GetObjectError::NoSuchKey(_)))) => return Ok(None),
+            Ok(Err(err)) => return Err(CleoraError::s3(filename, err)),
+            Err(_) => return Err(CleoraError::s3(filename, "request timed out")),
+        };
+        let mut data = Vec::new();
+        if let Some(body) = body {
+            body.into_blocking_read()
+                .read_to_end(&mut data)
+                .map_err(|e| CleoraError::s3(filename, e))?;
+        }
+        Ok(Some(data))
+    }
+
+    /// Writes the whole (small) object in a single request
+    pub fn put(filename: &str, data: Vec<u8>) -> Result<(), CleoraError> {
+        let (s3_client, bucket_name, object_key) = S3File::create_client(filename)?;
+        let timeout = Duration::from_secs(10);
+
+        request(
+            filename,
+            timeout,
+            s3_client.put_object(PutObjectRequest {
+                bucket: bucket_name,
+                key: object_key,
+                body: Some(ByteStream::from(data)),
+                storage_class: storage_class(),
+                ..Default::default()
+            }),
+        )?;
+        Ok(())
+    }
+
+    /// Size of the object in bytes
+    #[allow(clippy::needless_update)]
+    pub fn size(filename: &str) -> Result<u64, CleoraError> {
+        let (s3_client, bucket_name, object_key) = S3File::create_client(filename)?;
+        let timeout = Duration::from_secs(10);
+
+        request(
+            filename,
+            timeout,
+            s3_client.head_object(HeadObjectRequest {
+                bucket: bucket_name,
+                key: object_key,
+                ..Default::default()
+            }),
+        )?
+        .content_length
+        .map(|length| length as u64)
+        .ok_or_else(|| CleoraError::s3(filename, "no content length"))
+    }
+
+    #[allow(clippy::needless_update)]
+    pub fn delete(filename: &str) -> Result<(), CleoraError> {
+        let (s3_client, bucket_name, object_key) = S3File::create_client(filename)?;
+        let timeout = Duration::from_secs(10);
+
+        request(
+            filename,
+            timeout,
+            s3_client.delete_object(DeleteObjectRequest {
+                bucket: bucket_name,
+                key: object_key,
+                ..Default::default()
+            }),
+        )?;
+        Ok(())
+    }
+
+    /// Path of the object, used in error messages
+    fn path(&self) -> String {
+        format!("s3://{}/{}", self.bucket_name, self.object_key)
+    }
+
+    fn create_client(filename: &str) -> Result<(S3Client, String, String), CleoraError> {
         let region = match env::var("S3_ENDPOINT_URL") {
             Ok(endpoint) => Region::Custom {
                 name: "custom".to_string(),
@@ -93,51 +369,95 @@ impl S3File {
             Err(_) => Region::default(),
         };
 
-        let path: Vec<&str> = filename.strip_prefix("s3://").unwrap().split("/").collect();
+        let path: Vec<&str> = filename
+            .strip_prefix("s3://")
+            .ok_or_else(|| CleoraError::s3(filename, "path must start with s3://"))?
+            .split("/")
+            .collect();
         let bucket_name: String = path[0].to_string();
         let object_key: String = path[1..].join("/");
 
         let s3_client = S3Client::new(region);
 
-
-        (s3_client, bucket_name, object_key)
+        Ok((s3_client, bucket_name, object_key))
     }
 
-    fn write_buff(&mut self) {
+    /// Starts upload of the buffered part in the background, waits for the oldest part first if
+    /// all uploads are in flight
+    fn write_buff(&mut self) -> Result<(), CleoraError> {
         if self.buff.len() == 0 {
-            return;
+            return Ok(());
+        }
+        if self.uploads.len() >= S3_CONCURRENT_UPLOADS {
+            self.wait_for_upload()?;
         }
 
-        let buff = self.buff.to_owned();
+        let buff = std::mem::take(&mut self.buff);
         let data_timeout = Duration::from_secs(300);
-
-        let result = self
-            .s3_client
-            .upload_part(UploadPartRequest {
-                body: Some(ByteStream::from(buff)),
-                bucket: self.bucket_name.clone(),
-                key: self.object_key.clone(),
-                part_number: self.part_number as i64,
-                upload_id: self.upload_id.clone(),
-                ..Default::default()
+        let path = self.path();
+        let part_number = self.part_number;
+        let s3_client = self.s3_client.clone();
+        let upload_part = UploadPartRequest {
+            body: Some(ByteStream::from(buff)),
+            bucket: self.bucket_name.clone(),
+            key: self.object_key.clone(),
+            part_number,
+            upload_id: self.upload_id.clone(),
+            ..Default::default()
+        };
+        let upload = runtime().spawn(async move {
+            let result =
+                with_timeout(&path, data_timeout, s3_client.upload_part(upload_part)).await?;
+            Ok(CompletedPart {
+                e_tag: result.e_tag,
+                part_number: Some(part_number),
             })
-            .with_timeout(data_timeout)
-            .sync()
-            .unwrap();
-
-        self.completed_parts.push(CompletedPart {
-            e_tag: result.e_tag,
-            part_number: Some(self.part_number as i64),
         });
+        self.uploads.push_back(upload);
 
         self.part_number += 1;
-        self.buff = Vec::new();
+        Ok(())
     }
 
-    pub fn complete(&mut self) {
-        if !self.completed {
-            self.write_buff();
-            let timeout = Duration::from_secs(10);
+    /// Waits for upload of the oldest part in flight
+    fn wait_for_upload(&mut self) -> Result<(), CleoraError> {
+        if let Some(upload) = self.uploads.pop_front() {
+            let part = runtime()
+                .block_on(upload)
+                .map_err(|e| CleoraError::s3(&self.path(), e))??;
+            self.completed_parts.push(part);
+        }
+        Ok(())
+    }
+
+    /// Uploads the rest of the object and completes the upload, so the object shows up. The
+    /// upload is aborted if it fails.
+    pub fn complete(&mut self) -> Result<(), CleoraError> {
+        if self.completed {
+            return Ok(());
+        }
+        if let Err(err) = self.complete_upload() {
+            // parts uploaded so far are kept (and billed) until the upload is aborted
+            if let Err(abort_err) = self.abort_upload() {
+                error!("{}", abort_err);
+            }
+            return Err(err);
+        }
+        self.completed = true;
+        Ok(())
+    }
+
+    // requests of rusoto have more optional fields than the ones set
+    #[allow(clippy::needless_update)]
+    fn complete_upload(&mut self) -> Result<(), CleoraError> {
+        self.write_buff()?;
+        while !self.uploads.is_empty() {
+            self.wait_for_upload()?;
+        }
+        let timeout = Duration::from_secs(10);
+        request(
+            &self.path(),
+            timeout,
             self.s3_client
                 .complete_multipart_upload(CompleteMultipartUploadRequest {
                     bucket: self.bucket_name.clone(),
@@ -147,27 +467,32 @@ impl S3File {
                         parts: Some(self.completed_parts.clone()),
                     }),
                     ..Default::default()
-                })
-                .with_timeout(timeout)
-                .sync()
-                .unwrap();
-            self.completed = true;
-        }
+                }),
+        )?;
+        Ok(())
     }
 
-    pub fn abort_upload(&mut self) {
-        let timeout = Duration::from_secs(10);
-        self.s3_client
-            .abort_multipart_upload(AbortMultipartUploadRequest {
-                bucket: self.bucket_name.clone(),
-                key: self.object_key.clone(),
-                upload_id: self.upload_id.clone(),
-                ..Default::default()
-            })
-            .with_timeout(timeout)
-            .sync()
-            .unwrap();
+    /// Aborts the upload, uploaded parts are removed and the object doesn't show up
+    #[allow(clippy::needless_update)]
+    pub fn abort_upload(&mut self) -> Result<(), CleoraError> {
+        // don't retry in drop if the abort fails
         self.completed = true;
+        for upload in self.uploads.drain(..) {
+            upload.abort();
+        }
+        let timeout = Duration::from_secs(10);
+        request(
+            &self.path(),
+            timeout,
+            self.s3_client
+                .abort_multipart_upload(AbortMultipartUploadRequest {
+                    bucket: self.bucket_name.clone(),
+                    key: self.object_key.clone(),
+                    upload_id: self.upload_id.clone(),
+                    ..Default::default()
+                }),
+        )?;
+        Ok(())
     }
 }
 
@@ -176,7 +501,7 @@ impl Write for S3File {
         self.buff.extend_from_slice(buf);
 
         if self.buff.len() > self.part_size {
-            self.write_buff();
+            self.write_buff().map_err(|e| Error::other(e.to_string()))?;
         }
 
         Ok(buf.len())
@@ -197,11 +522,11 @@ fn open_write_read_test() {
     env::set_var("AWS_ACCESS_KEY_ID", "minioadmin");
     env::set_var("AWS_SECRET_ACCESS_KEY", "minioadmin");
 
-    let mut f = S3File::create("s3://input/hello.txt".to_string());
+    let mut f = S3File::create("s3://input/hello.txt".to_string()).unwrap();
 
-    f.write(b"hello world\n");
-    f.write(b"hello world");
-    f.complete();
+    f.write_all(b"hello world\n").unwrap();
+    f.write_all(b"hello world").unwrap();
+    f.complete().unwrap();
 
     let mut file1 = S3File::open("s3://input/hello.txt".to_string()).unwrap();
     let mut data: Vec<u8> = Vec::new();
@@ -215,3 +540,206 @@ fn open_write_read_test() {
 
     assert_eq!(line, "hello world\n");
 }
+
+#[test]
+fn commit_partial_file_test() {
+    let path = env::temp_dir().join("cleora_commit_partial_file_test.out");
+    let path = path.to_str().unwrap();
+    let _ = fs::remove_file(path);
+
+    let mut file = create_partial_file(path).unwrap();
+    file.write_all(b"hello world").unwrap();
+    assert!(!std::path::Path::new(path).exists());
+
+    commit_file(path).unwrap();
+    assert!(!std::path::Path::new(&partial_path(path)).exists());
+    assert_eq!(fs::read(path).unwrap(), b"hello world");
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn create_and_commit_output_test() {
+    let path = env::temp_dir().join("cleora_create_and_commit_output_test.out");
+    let path = path.to_str().unwrap();
+    let _ = fs::remove_file(path);
+
+    let mut output = create_output(path).unwrap();
+    output.write_all(b"hello world").unwrap();
+    output.flush().unwrap();
+    assert!(!std::path::Path::new(path).exists());
+
+    output.commit().unwrap();
+    assert!(!std::path::Path::new(&partial_path(path)).exists());
+    assert_eq!(fs::read(path).unwrap(), b"hello world");
+    fs::remove_file(path).unwrap();
+
+    // outputs to S3 which can't be streamed are staged locally
+    let staged = partial_path("s3://bucket/embeddings/emb.npy");
+    assert!(staged.starts_with(env::temp_dir().to_str().unwrap()));
+    assert!(staged.ends_with("cleora-bucket_embeddings_emb.npy.partial"));
+}
+
+/// State of `MockS3` shared with the test
+#[cfg(test)]
+type Shared<T> = std::sync::Arc<std::sync::Mutex<T>>;
+
+/// S3 answering the requests of multipart uploads without a server, records them in order
+#[cfg(test)]
+struct MockS3 {
+    requests: Shared<Vec<String>>,
+    /// Number of the part whose upload fails
+    failing_part: Option<i64>,
+    /// Parts being uploaded and the most parts uploaded at once, every upload takes a while
+    uploads: Shared<(usize, usize)>,
+}
+
+#[cfg(test)]
+impl rusoto_core::DispatchSignedRequest for MockS3 {
+    fn dispatch(
+        &self,
+        request: rusoto_core::signature::SignedRequest,
+        _timeout: Option<Duration>,
+    ) -> rusoto_core::request::DispatchSignedRequestFuture {
+        let part: Option<i64> = request
+            .params
+            .get("partNumber")
+            .cloned()
+            .flatten()
+            .map(|part| part.parse().unwrap());
+        let (name, status, body) = match (request.method(), part) {
+            ("POST", _) if request.params.contains_key("uploads") => (
+                String::from("create"),
+                200,
+                "<InitiateMultipartUploadResult><Bucket>bucket</Bucket><Key>key</Key>\
+                 <UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+            ),
+            ("PUT", Some(part)) if Some(part) == self.failing_part => {
+                (format!("part {}", part), 500, "")
+            }
+            ("PUT", Some(part)) => (format!("part {}", part), 200, ""),
+            ("POST", _) => (
+                String::from("complete"),
+                200,
+                "<CompleteMultipartUploadResult><Bucket>bucket</Bucket><Key>key</Key>\
+                 </CompleteMultipartUploadResult>",
+            ),
+            ("DELETE", _) => (String::from("abort"), 204, ""),
+            (method, _) => (String::from(method), 400, ""),
+        };
+        self.requests.lock().unwrap().push(name);
+        let mut headers = http::HeaderMap::<String>::default();
+        if let Some(part) = part {
+            headers.insert("etag", format!("\"{}\"", part));
+        }
+        let uploads = self.uploads.clone();
+        Box::pin(async move {
+            if part.is_some() {
+                {
+                    let mut uploads = uploads.lock().unwrap();
+                    uploads.0 += 1;
+                    uploads.1 = uploads.1.max(uploads.0);
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                uploads.lock().unwrap().0 -= 1;
+            }
+            Ok(rusoto_core::request::HttpResponse {
+                status: http::StatusCode::from_u16(status).unwrap(),
+                body: ByteStream::from(body.as_bytes().to_vec()),
+                headers,
+            })
+        })
+    }
+}
+
+/// Upload of `s3://bucket/key` to `MockS3`, with the requests it got and the most parts it
+/// uploaded at once
+#[cfg(test)]
+fn mock_upload(failing_part: Option<i64>) -> (S3File, Shared<Vec<String>>, Shared<(usize, usize)>) {
+    let requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let uploads = std::sync::Arc::new(std::sync::Mutex::new((0, 0)));
+    let s3 = MockS3 {
+        requests: requests.clone(),
+        failing_part,
+        uploads: uploads.clone(),
+    };
+    let s3_client = S3Client::new_with(
+        s3,
+        rusoto_core::credential::StaticProvider::new_minimal(
+            String::from("access key"),
+            String::from("secret key"),
+        ),
+        Region::Custom {
+            name: String::from("mock"),
+            endpoint: String::from("http://localhost"),
+        },
+    );
+    let file = S3File::start_upload(
+        "s3://bucket/key",
+        s3_client,
+        String::from("bucket"),
+        String::from("key"),
+    )
+    .unwrap();
+    (file, requests, uploads)
+}
+
+#[test]
+fn abort_unfinished_upload_test() {
+    let (mut file, requests, _) = mock_upload(None);
+    file.write_all(b"hello world").unwrap();
+    drop(file);
+    assert_eq!(vec!["create", "abort"], *requests.lock().unwrap());
+
+    let (mut file, requests, _) = mock_upload(None);
+    file.write_all(b"hello world").unwrap();
+    file.complete().unwrap();
+    drop(file);
+    assert_eq!(
+        vec!["create", "part 0", "complete"],
+        *requests.lock().unwrap()
+    );
+}
+
+#[test]
+fn abort_failed_upload_test() {
+    let (mut file, requests, _) = mock_upload(Some(1));
+    file.part_size = 4;
+    file.write_all(b"hello world").unwrap();
+    file.write_all(b"!").unwrap();
+    assert!(file.complete().is_err());
+    drop(file);
+    let requests = requests.lock().unwrap();
+    assert_eq!(Some("abort"), requests.last().map(|r| r.as_str()));
+    assert!(!requests.iter().any(|r| r == "complete"));
+}
+
+#[test]
+fn upload_parts_concurrently_test() {
+    let (mut file, requests, uploads) = mock_upload(None);
+    file.part_size = 4;
+    for i in 0..10 {
+        file.write_all(format!("part{}", i).as_bytes()).unwrap();
+    }
+    file.write_all(b"!").unwrap();
+    file.complete().unwrap();
+
+    // parts are uploaded in the background, at most S3_CONCURRENT_UPLOADS of them at once
+    let (in_flight, most) = *uploads.lock().unwrap();
+    assert_eq!(0, in_flight);
+    assert!(most > 1 && most <= S3_CONCURRENT_UPLOADS, "{}", most);
+    // but completed in order of their numbers
+    let parts: Vec<(Option<i64>, Option<String>)> = file
+        .completed_parts
+        .iter()
+        .map(|part| (part.part_number, part.e_tag.clone()))
+        .collect();
+    let expected: Vec<(Option<i64>, Option<String>)> = (0..11)
+        .map(|part| (Some(part), Some(format!("\"{}\"", part))))
+        .collect();
+    assert_eq!(expected, parts);
+    drop(file);
+    let requests = requests.lock().unwrap();
+    assert_eq!(Some("create"), requests.first().map(|r| r.as_str()));
+    assert_eq!(Some("complete"), requests.last().map(|r| r.as_str()));
+    assert_eq!(13, requests.len());
+}
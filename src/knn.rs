@@ -0,0 +1,268 @@
+use crate::configuration::{KnnGraph, KnnMethod};
+use crate::edge_types::EmbeddingCollector;
+use crate::error::CleoraError;
+use crate::io::create_output;
+use crate::pca::starting_value;
+use ndarray::{Array2, ArrayView2, Axis};
+use rayon::prelude::*;
+use std::cmp::Ordering;
+use std::io::{BufWriter, Write};
+
+/// Max number of embeddings whose neighbors `KnnMethod::Auto` searches exactly
+pub const KNN_EXACT_MAX_ENTITIES: usize = 20_000;
+/// Hash tables of the approximate search, the embeddings are sorted by another signature in each
+const KNN_TABLES: usize = 8;
+/// Random hyperplanes giving the signature of the embedding in a hash table
+const KNN_SIGNATURE_BITS: usize = 16;
+/// Embeddings before and after the embedding in the sorted order of a hash table which are
+/// compared with it (at least `k`)
+const KNN_WINDOW: usize = 32;
+/// Rounds of the approximate search comparing embeddings with neighbors of their neighbors
+const KNN_REFINEMENTS: usize = 4;
+
+/// Whether the neighbors of that many embeddings are searched exactly
+pub fn is_exact(method: KnnMethod, entities: usize) -> bool {
+    match method {
+        KnnMethod::Auto => entities <= KNN_EXACT_MAX_ENTITIES,
+        KnnMethod::Exact => true,
+        KnnMethod::Approximate => false,
+    }
+}
+
+/// Finds `k` nearest neighbors (by cosine similarity) of every row, the most similar first.
+/// Exact search compares every pair of rows. Approximate search sorts the rows by signatures of
+/// random hyperplanes (SimHash) in several hash tables and compares rows with their
+/// neighborhoods in the sorted orders, where similar rows are likely to meet. The neighbors are
+/// then refined by comparing rows with neighbors (and reverse neighbors) of their neighbors, as in
+/// NN-descent. Hyperplanes are deterministic, so the result is the same in every run.
+pub fn nearest_neighbors(values: ArrayView2<f32>, k: usize, exact: bool) -> Vec<Vec<(u32, f32)>> {
+    let mut vectors = values.to_owned();
+    for mut row in vectors.axis_iter_mut(Axis(0)) {
+        let norm = row.dot(&row).sqrt();
+        // zero vectors stay zero, they are similar to nothing
+        if norm > 0f32 {
+            row /= norm;
+        }
+    }
+    let rows = vectors.nrows();
+    if exact {
+        return (0..rows)
+            .into_par_iter()
+            .map(|row| most_similar(&vectors, row, 0..rows, k))
+            .collect();
+    }
+
+    let bits = KNN_TABLES * KNN_SIGNATURE_BITS;
+    let planes = Array2::from_shape_fn((vectors.ncols(), bits), |(i, j)| {
+        starting_value((i * bits + j) as u64) as f32
+    });
+    let projected = vectors.dot(&planes);
+    let orders: Vec<Vec<u32>> = (0..KNN_TABLES)
+        .into_par_iter()
+        .map(|table| {
+            let columns = table * KNN_SIGNATURE_BITS..(table + 1) * KNN_SIGNATURE_BITS;
+            let signatures: Vec<u32> = projected
+                .rows()
+                .into_iter()
+                .map(|row| {
+                    columns.clone().fold(0u32, |signature, column| {
+                        (signature << 1) | (row[column] > 0f32) as u32
+                    })
+                })
+                .collect();
+            let mut order: Vec<u32> = (0..rows as u32).collect();
+            order.sort_by_key(|&row| (signatures[row as usize], row));
+            order
+        })
+        .collect();
+    let positions: Vec<Vec<u32>> = orders
+        .iter()
+        .map(|order| {
+            let mut positions = vec![0u32; rows];
+            for (position, &row) in order.iter().enumerate() {
+                positions[row as usize] = position as u32;
+            }
+            positions
+        })
+        .collect();
+
+    let window = KNN_WINDOW.max(k);
+    let mut neighbors: Vec<Vec<(u32, f32)>> = (0..rows)
+        .into_par_iter()
+        .map(|row| {
+            let mut candidates = Vec::with_capacity(KNN_TABLES * (2 * window + 1));
+            for (order, positions) in orders.iter().zip(positions.iter()) {
+                let position = positions[row] as usize;
+                let start = position.saturating_sub(window);
+                let end = (position + window + 1).min(rows);
+                candidates.extend(order[start..end].iter().map(|&other| other as usize));
+            }
+            candidates.sort_unstable();
+            candidates.dedup();
+            most_similar(&vectors, row, candidates.into_iter(), k)
+        })
+        .collect();
+
+    for _ in 0..KNN_REFINEMENTS {
+        // reverse neighbors are capped at 2k, so popular rows don't get too many candidates
+        let mut linked: Vec<Vec<u32>> = neighbors
+            .iter()
+            .map(|row_neighbors| row_neighbors.iter().map(|n| n.0).collect())
+            .collect();
+        for (row, row_neighbors) in neighbors.iter().enumerate() {
+            for &(neighbor, _) in row_neighbors {
+                if linked[neighbor as usize].len() < 2 * k {
+                    linked[neighbor as usize].push(row as u32);
+                }
+            }
+        }
+        neighbors = (0..rows)
+            .into_par_iter()
+            .map(|row| {
+                let mut candidates: Vec<usize> = Vec::new();
+                for &other in linked[row].iter() {
+                    candidates.push(other as usize);
+                    candidates.extend(linked[other as usize].iter().map(|&o| o as usize));
+                }
+                candidates.sort_unstable();
+                candidates.dedup();
+                most_similar(&vectors, row, candidates.into_iter(), k)
+            })
+            .collect();
+    }
+    neighbors
+}
+
+/// `k` candidates most similar to the row (excluding itself), ties are broken by the row number
+fn most_similar(
+    vectors: &Array2<f32>,
+    row: usize,
+    candidates: impl Iterator<Item = usize>,
+    k: usize,
+) -> Vec<(u32, f32)> {
+    let vector = vectors.row(row);
+    let mut similarities: Vec<(u32, f32)> = candidates
+        .filter(|&other| other != row)
+        .map(|other| (other as u32, vectors.row(other).dot(&vector)))
+        .collect();
+    let by_similarity = |a: &(u32, f32), b: &(u32, f32)| {
+        b.1.partial_cmp(&a.1)
+            .unwrap_or(Ordering::Equal)
+            .then(a.0.cmp(&b.0))
+    };
+    if k < similarities.len() {
+        similarities.select_nth_unstable_by(k, by_similarity);
+        similarities.truncate(k);
+    }
+    similarities.sort_by(by_similarity);
+    similarities
+}
+
+/// Writes the k-nearest-neighbor graph of the embeddings as `entity<TAB>neighbor<TAB>similarity`
+/// lines, neighbors of every entity from the most similar. Edges are directed, entities similar
+/// to each other are written in both directions. Returns the number of written edges.
+pub fn write_graph(
+    filename: &str,
+    embeddings: &EmbeddingCollector,
+    graph: KnnGraph,
+) -> Result<usize, CleoraError> {
+    let values =
+        ArrayView2::from_shape((embeddings.len(), embeddings.dimension), &embeddings.values)
+            .expect("Row-major embeddings");
+    let exact = is_exact(graph.method, embeddings.len());
+    let neighbors = nearest_neighbors(values, graph.k, exact);
+
+    let mut writer = BufWriter::new(create_output(filename)?);
+    let mut edges = 0;
+    for (entity, neighbors) in embeddings.entities.iter().zip(neighbors.iter()) {
+        for &(neighbor, similarity) in neighbors {
+            writeln!(
+                writer,
+                "{}\t{}\t{}",
+                entity, embeddings.entities[neighbor as usize], similarity
+            )
+            .map_err(|e| CleoraError::write_file(filename, e))?;
+        }
+        edges += neighbors.len();
+    }
+    writer
+        .flush()
+        .map_err(|e| CleoraError::write_file(filename, e))?;
+    writer.get_mut().commit()?;
+    Ok(edges)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::{KnnGraph, KnnMethod};
+    use crate::edge_types::EmbeddingCollector;
+    use crate::knn::{is_exact, nearest_neighbors, write_graph, KNN_EXACT_MAX_ENTITIES};
+    use crate::pca::starting_value;
+    use ndarray::{arr2, Array2};
+
+    #[test]
+    fn exact_neighbors() {
+        let values = arr2(&[[1.0, 0.0], [10.0, 1.0], [0.0, 1.0], [-1.0, 0.0], [0.0, 0.0]]);
+        let neighbors = nearest_neighbors(values.view(), 2, true);
+        assert_eq!(5, neighbors.len());
+        let rows: Vec<u32> = neighbors[0].iter().map(|n| n.0).collect();
+        assert_eq!(vec![1, 2], rows);
+        assert!((neighbors[0][0].1 - 10.0 / 101f32.sqrt()).abs() < 1e-6);
+        let rows: Vec<u32> = neighbors[3].iter().map(|n| n.0).collect();
+        assert_eq!(vec![2, 4], rows);
+
+        // fewer neighbors than k
+        let neighbors = nearest_neighbors(values.view(), 10, true);
+        assert!(neighbors.iter().all(|n| n.len() == 4));
+    }
+
+    #[test]
+    fn approximate_neighbors() {
+        // clusters of near-duplicate vectors around random centers
+        let (clusters, size, dimension) = (200, 5, 16);
+        let values = Array2::from_shape_fn((clusters * size, dimension), |(i, j)| {
+            let center = starting_value(((i / size) * dimension + j) as u64) as f32;
+            center + 0.01 * starting_value((1_000_000 + i * dimension + j) as u64) as f32
+        });
+        let exact = nearest_neighbors(values.view(), size - 1, true);
+        let approximate = nearest_neighbors(values.view(), size - 1, false);
+        assert!(approximate
+            .iter()
+            .zip(exact.iter())
+            .all(|(a, e)| a.len() == e.len()));
+        let found = approximate
+            .iter()
+            .zip(exact.iter())
+            .map(|(a, e)| a.iter().filter(|n| e.contains(n)).count())
+            .sum::<usize>();
+        assert!(found as f32 >= 0.95 * (clusters * size * (size - 1)) as f32);
+
+        assert!(is_exact(KnnMethod::Auto, KNN_EXACT_MAX_ENTITIES));
+        assert!(!is_exact(KnnMethod::Auto, KNN_EXACT_MAX_ENTITIES + 1));
+        assert!(!is_exact(KnnMethod::Approximate, 10));
+    }
+
+    #[test]
+    fn write_edge_list() {
+        let embeddings = EmbeddingCollector {
+            entities: vec!["a".into(), "b".into(), "c".into()],
+            occur_counts: vec![1, 1, 1],
+            values: vec![1.0, 0.0, 1.0, 1.0, 0.0, 1.0],
+            dimension: 2,
+        };
+        let path = std::env::temp_dir().join("cleora_knn_graph.tsv");
+        let path = path.to_str().unwrap();
+        let graph = KnnGraph {
+            k: 1,
+            method: KnnMethod::Auto,
+        };
+        assert_eq!(3, write_graph(path, &embeddings, graph).unwrap());
+        let written = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(3, lines.len());
+        assert!(lines[0].starts_with("a\tb\t0.70710"));
+        assert!(lines[1].starts_with("b\ta\t0.70710"));
+        assert!(lines[2].starts_with("c\tb\t0.70710"));
+    }
+}
@@ -1,19 +1,96 @@
+pub mod alias;
+pub mod alignment;
+pub mod cardinality;
+pub mod clustering;
+#[cfg(feature = "fs")]
+pub mod compare;
 pub mod configuration;
+#[cfg(feature = "fs")]
+pub mod cost;
+#[cfg(feature = "fs")]
+pub mod debug;
+#[cfg(feature = "delta")]
+pub mod delta;
+#[cfg(feature = "fs")]
+pub mod dictionary;
+#[cfg(feature = "fs")]
+pub mod dry_run;
+#[cfg(feature = "duckdb")]
+pub mod duckdb_output;
+pub mod edge_types;
 pub mod embedding;
 pub mod entity;
+pub mod error;
+#[cfg(feature = "fs")]
+pub mod feast;
+#[cfg(feature = "flight")]
+pub mod flight;
+#[cfg(feature = "fs")]
+pub mod gate;
+#[cfg(feature = "fs")]
+pub mod graph_export;
+#[cfg(feature = "fs")]
+pub mod graph_import;
+pub mod interrupt;
+#[cfg(feature = "fs")]
+pub mod io;
+#[cfg(feature = "fs")]
+pub mod knn;
+pub mod loader;
+#[cfg(feature = "fs")]
+pub mod lock;
+#[cfg(feature = "fs")]
+pub mod manifest;
+#[cfg(feature = "fs")]
+pub mod matrix_export;
+pub mod memory;
+#[cfg(feature = "mlflow")]
+pub mod mlflow;
+#[cfg(feature = "fs")]
+pub mod onnx;
+#[cfg(feature = "fs")]
+pub mod pca;
 pub mod persistence;
+#[cfg(feature = "fs")]
 pub mod pipeline;
+pub mod profile;
+#[cfg(feature = "fs")]
+pub mod projector;
+#[cfg(feature = "fs")]
+pub mod query;
+#[cfg(feature = "fs")]
+pub mod resources;
+pub mod sketch;
 pub mod sparse_matrix;
-pub mod io;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_output;
+#[cfg(feature = "fs")]
+pub mod stitch;
+#[cfg(feature = "fs")]
+pub mod tags;
+#[cfg(feature = "fs")]
+pub mod vocab;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "python")]
 use pyo3::prelude::*;
 
 //pub use configuration;
 pub use configuration::Configuration;
+pub use configuration::InitMethod;
 pub use configuration::OutputFormat;
+#[cfg(feature = "python")]
+use configuration::{EdgeTypeCombination, MetadataColumn};
+#[cfg(feature = "python")]
 use persistence::entity::InMemoryEntityMappingPersistor;
+#[cfg(feature = "python")]
 use pipeline::{build_graphs, train};
+#[cfg(feature = "python")]
 use std::sync::Arc;
+#[cfg(feature = "python")]
+use std::time::Instant;
 
+#[cfg(feature = "python")]
 #[pyfunction]
 fn run(
     input: Vec<String>,
@@ -29,11 +106,15 @@ fn run(
     output_format: &str,
     relation_name: String,
     chunk_size: usize,
+    output_metadata: Option<&str>,
 ) -> PyResult<String> {
+    let now = Instant::now();
     let file_type = match type_name {
         Some(type_name) => match type_name {
             "tsv" => configuration::FileType::Tsv,
             "json" => configuration::FileType::Json,
+            "graphml" => configuration::FileType::GraphMl,
+            "snap" => configuration::FileType::Snap,
             _ => panic!("Invalid file type {}", type_name),
         },
         None => configuration::FileType::Tsv,
@@ -47,16 +128,37 @@ fn run(
         },
         Err(msg) => panic!("Parsing problem. Message: {}", msg),
     };
+    if file_type.is_graph() && columns.len() != 2 {
+        panic!(
+            "Graph inputs have two columns, the source and target of the edges, got {}",
+            columns.len()
+        );
+    }
 
     let output_format_type = match output_format {
         "textfile" => OutputFormat::TextFile,
         "numpy" => OutputFormat::Numpy,
         "parquet" => OutputFormat::Parquet,
+        "raw" => OutputFormat::Raw,
+        "compact" => OutputFormat::Compact,
+        "npz" => OutputFormat::Npz,
+        "onnx" => OutputFormat::Onnx,
+        "projector" => OutputFormat::Projector(None),
+        "delta" => OutputFormat::Delta(configuration::DeltaMode::Append),
+        "feast" => OutputFormat::Feast(None),
         _ => panic!("unsupported output format"),
     };
 
+    let metadata_columns = match output_metadata {
+        Some(output_metadata) => match configuration::extract_metadata_columns(output_metadata) {
+            Ok(metadata_columns) => metadata_columns,
+            Err(msg) => panic!("Invalid output metadata. Message: {}", msg),
+        },
+        None => vec![MetadataColumn::OccurCount, MetadataColumn::Datetime],
+    };
+
     let config = Configuration {
-        produce_entity_occurrence_count: true,
+        produce_entity_occurrence_count: metadata_columns.contains(&MetadataColumn::OccurCount),
         embeddings_dimension: dimension,
         max_number_of_iteration: max_iter,
         seed,
@@ -65,24 +167,96 @@ fn run(
         in_memory_embedding_calculation,
         input,
         file_type,
+        field_delimiter: b'\t',
+        value_delimiters: vec![],
+        aliases: None,
         output_dir,
         output_format: output_format_type,
+        extra_output_formats: vec![],
         relation_name,
         columns,
         chunk_size,
+        init_method: InitMethod::Uniform,
+        entity_mapping_format: None,
+        fail_on_collision: false,
+        collision_budget: None,
+        postprocess: vec![],
+        postprocess_stats: configuration::StatsScope::PerEntityType,
+        normalize: configuration::Normalization::None,
+        reduce_dimension: None,
+        knn_graph: None,
+        hash_function: configuration::HashFunction::XxHash64,
+        hash_bits: 64,
+        // python callers rerun into the same directory, keep replacing the outputs
+        overwrite: true,
+        sort_output: None,
+        output_template: None,
+        memory_budget: None,
+        on_budget_exceeded: configuration::BudgetPolicy::Abort,
+        auto_pairs: false,
+        excluded_pairs: vec![],
+        relations: None,
+        edge_types: vec![],
+        edge_type_combination: EdgeTypeCombination::Concat,
+        decay: None,
+        feature_init: None,
+        matrix_export: None,
+        align_to: None,
+        precision: configuration::Precision::F32,
+        dedupe_edges: false,
+        sample_edges: None,
+        save_vocab: None,
+        load_vocab: None,
+        mlflow_uri: None,
+        min_entity_count: 1,
+        max_entities: vec![],
+        unknown_bucket: false,
+        entity_tags: vec![],
+        output_tags: vec![],
+        snapshot_iterations: false,
+        convergence_tolerance: None,
+        early_stop: None,
+        kernel: configuration::PropagationKernel::Markov,
+        alpha: 0f32,
+        column_weights: vec![],
+        self_loops: configuration::SelfLoops::Keep,
+        deadline: None,
+        fast_parse: false,
+        malformed_rows: configuration::MalformedRows::Log(100),
+        propagation_partitioning: configuration::PropagationPartitioning::Auto,
+        dry_run: false,
+        profile: false,
+        input_checksums: true,
+        similarity_summary: true,
+        continue_from: None,
+        force: false,
+        storage_class: configuration::StorageClass::Standard,
+        estimate_cost: None,
+        entities_format: configuration::EntitiesFormat::Json,
+        output_datetime: metadata_columns.contains(&MetadataColumn::Datetime),
+        write_queue_size: 4,
+        threads: 0,
     };
 
     let in_memory_entity_mapping_persistor = InMemoryEntityMappingPersistor::default();
     let in_memory_entity_mapping_persistor = Arc::new(in_memory_entity_mapping_persistor);
 
-    let sparse_matrices = build_graphs(&config, in_memory_entity_mapping_persistor.clone());
+    let sparse_matrices = build_graphs(&config, in_memory_entity_mapping_persistor.clone())
+        .map_err(|err| pyo3::exceptions::PyIOError::new_err(err.to_string()))?;
 
-    train(config, in_memory_entity_mapping_persistor, sparse_matrices);
+    train(
+        config,
+        in_memory_entity_mapping_persistor,
+        sparse_matrices,
+        now,
+    )
+    .map_err(|err| pyo3::exceptions::PyIOError::new_err(err.to_string()))?;
 
     Ok("OK".to_string())
 }
 
 /// A Python module implemented in Rust.
+#[cfg(feature = "python")]
 #[pymodule]
 fn cleora(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(run, m)?)?;
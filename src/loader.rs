@@ -0,0 +1,93 @@
+#[cfg(feature = "fs")]
+use crate::error::CleoraError;
+#[cfg(feature = "fs")]
+use cleora_embeddings::EmbeddingSet;
+use ndarray::Array2;
+use rustc_hash::FxHashMap;
+
+/// Embeddings read back from an output file. Row `i` of `vectors` is the embedding of `entities[i]`.
+#[derive(Debug, Clone)]
+pub struct Embeddings {
+    pub entities: Vec<String>,
+    pub vectors: Array2<f32>,
+}
+
+impl Embeddings {
+    pub fn dimension(&self) -> usize {
+        self.vectors.ncols()
+    }
+
+    /// Entity to row index mapping
+    pub fn index(&self) -> FxHashMap<&str, usize> {
+        self.entities
+            .iter()
+            .enumerate()
+            .map(|(i, entity)| (entity.as_str(), i))
+            .collect()
+    }
+}
+
+/// Loads embeddings written by one of the persistors, see `EmbeddingSet::open` for the
+/// recognized formats.
+#[cfg(feature = "fs")]
+pub fn load_embeddings(path: &str) -> Result<Embeddings, CleoraError> {
+    let embeddings = EmbeddingSet::open(path)?;
+    let shape = (embeddings.len(), embeddings.dimension());
+    let (entities, values) = embeddings.into_parts();
+    let vectors = Array2::from_shape_vec(shape, values)
+        .map_err(|e| CleoraError::invalid_embeddings(path, e))?;
+    Ok(Embeddings { entities, vectors })
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    use crate::configuration::EntitiesFormat;
+    use crate::loader::load_embeddings;
+    use crate::persistence::embedding::{
+        EmbeddingPersistor, NpyPersistor, TextFileVectorPersistor,
+    };
+
+    fn write(persistor: &mut dyn EmbeddingPersistor) {
+        persistor.put_metadata(3, 2).unwrap();
+        persistor.put_data("a", 4, vec![0.5, -1.0]).unwrap();
+        persistor.put_data("b", 1, vec![0.25, 2.0]).unwrap();
+        // the third row belongs to a transient entity and is never written
+        persistor.finish().unwrap();
+    }
+
+    #[test]
+    fn load_text_and_npy_outputs() {
+        let dir = std::env::temp_dir();
+        for produce_entity_occurrence_count in [true, false] {
+            let text = dir.join(format!(
+                "cleora_load_{}.out",
+                produce_entity_occurrence_count
+            ));
+            let text = text.to_str().unwrap().to_string();
+            let _ = std::fs::remove_file(&text);
+            write(
+                &mut TextFileVectorPersistor::new(text.clone(), produce_entity_occurrence_count)
+                    .unwrap(),
+            );
+            let embeddings = load_embeddings(&text).unwrap();
+            assert_eq!(vec!["a", "b"], embeddings.entities);
+            assert_eq!(
+                vec![0.5, -1.0, 0.25, 2.0],
+                embeddings.vectors.into_raw_vec()
+            );
+            std::fs::remove_file(&text).unwrap();
+        }
+
+        let npy = dir.join("cleora_load_npy.out");
+        let npy = npy.to_str().unwrap().to_string();
+        write(&mut NpyPersistor::new(npy.clone(), false, EntitiesFormat::Json).unwrap());
+        let embeddings = load_embeddings(&format!("{}.npy", npy)).unwrap();
+        assert_eq!(vec!["a", "b"], embeddings.entities);
+        assert_eq!(
+            vec![0.5, -1.0, 0.25, 2.0],
+            embeddings.vectors.into_raw_vec()
+        );
+        std::fs::remove_file(format!("{}.npy", npy)).unwrap();
+        std::fs::remove_file(format!("{}.entities", npy)).unwrap();
+    }
+}
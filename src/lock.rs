@@ -0,0 +1,182 @@
+use crate::error::CleoraError;
+use crate::io::S3File;
+use chrono::Utc;
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::process;
+use uuid::Uuid;
+
+/// Advisory lock of the output location, so two runs of the same configuration (e.g. started by
+/// two schedulers) don't interleave their writes. It's a lock file (S3 object for S3 outputs)
+/// describing the owner, removed when the lock is dropped. Runs which are killed leave a stale
+/// lock behind, it's cleared with `force`.
+///
+/// Local lock files are created atomically. S3 has no atomic create, so the object is read back
+/// after it's written and the run whose token is found wins; runs started within the same moment
+/// may still both proceed.
+#[derive(Debug)]
+pub struct RunLock {
+    path: String,
+    token: String,
+}
+
+impl RunLock {
+    pub fn acquire(path: String, force: bool) -> Result<Self, CleoraError> {
+        let token = Uuid::new_v4().to_string();
+        let owner = json!({
+            "host": hostname(),
+            "pid": process::id(),
+            "started": Utc::now().to_rfc3339(),
+            "token": token,
+        });
+        let contents = serde_json::to_vec_pretty(&owner).expect("Lock owner is valid JSON");
+
+        if path.starts_with("s3://") {
+            if let Some(existing) = S3File::read_if_exists(&path)? {
+                check_stale(&path, &existing, force)?;
+            }
+            S3File::put(&path, contents)?;
+            let written = S3File::read_if_exists(&path)?.unwrap_or_default();
+            if lock_token(&written).as_deref() != Some(token.as_str()) {
+                return Err(locked(&path, &written));
+            }
+        } else {
+            let mut file = match create_new(&path) {
+                Ok(file) => file,
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                    let existing = fs::read(&path).unwrap_or_default();
+                    check_stale(&path, &existing, force)?;
+                    fs::remove_file(&path).map_err(|e| CleoraError::write_file(&path, e))?;
+                    create_new(&path).map_err(|e| match e.kind() {
+                        // another run took the cleared lock first
+                        ErrorKind::AlreadyExists => {
+                            locked(&path, &fs::read(&path).unwrap_or_default())
+                        }
+                        _ => CleoraError::create_file(&path, e),
+                    })?
+                }
+                Err(err) => return Err(CleoraError::create_file(&path, err)),
+            };
+            file.write_all(&contents)
+                .map_err(|e| CleoraError::write_file(&path, e))?;
+        }
+
+        info!("Acquired run lock {}.", path);
+        Ok(Self { path, token })
+    }
+
+    /// Removes the lock unless it was taken over (`--force`) by another run
+    fn release(&self) -> Result<(), CleoraError> {
+        let current = if self.path.starts_with("s3://") {
+            S3File::read_if_exists(&self.path)?
+        } else {
+            fs::read(&self.path).ok()
+        };
+        match current {
+            Some(current) if lock_token(&current).as_deref() == Some(self.token.as_str()) => {
+                if self.path.starts_with("s3://") {
+                    S3File::delete(&self.path)
+                } else {
+                    fs::remove_file(&self.path).map_err(|e| CleoraError::write_file(&self.path, e))
+                }
+            }
+            _ => {
+                warn!("Run lock {} was taken over by another run.", self.path);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        if let Err(err) = self.release() {
+            error!("Can't release run lock. {}", err);
+        }
+    }
+}
+
+fn create_new(path: &str) -> std::io::Result<fs::File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+/// Fails on the existing lock, unless it's forced to be cleared
+fn check_stale(path: &str, existing: &[u8], force: bool) -> Result<(), CleoraError> {
+    if !force {
+        return Err(locked(path, existing));
+    }
+    warn!(
+        "Clearing run lock {} held by {}.",
+        path,
+        describe_owner(existing)
+    );
+    Ok(())
+}
+
+fn locked(path: &str, existing: &[u8]) -> CleoraError {
+    CleoraError::Locked {
+        path: path.to_string(),
+        owner: describe_owner(existing),
+    }
+}
+
+fn lock_token(contents: &[u8]) -> Option<String> {
+    let owner: Value = serde_json::from_slice(contents).ok()?;
+    owner["token"].as_str().map(String::from)
+}
+
+/// Owner of the lock for the messages, e.g. `pid 42 on worker-1 since 2022-06-01T10:00:00+00:00`
+fn describe_owner(contents: &[u8]) -> String {
+    match serde_json::from_slice::<Value>(contents) {
+        Ok(owner) => format!(
+            "pid {} on {} since {}",
+            owner["pid"],
+            owner["host"].as_str().unwrap_or("unknown host"),
+            owner["started"].as_str().unwrap_or("unknown time")
+        ),
+        Err(_) => String::from("unknown run"),
+    }
+}
+
+fn hostname() -> String {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| String::from("unknown host"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::CleoraError;
+    use crate::lock::RunLock;
+    use std::path::Path;
+
+    #[test]
+    fn lock_output_location() {
+        let path = std::env::temp_dir().join("cleora_run_lock_test.lock");
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+
+        let lock = RunLock::acquire(path.clone(), false).unwrap();
+        assert!(matches!(
+            RunLock::acquire(path.clone(), false),
+            Err(CleoraError::Locked { .. })
+        ));
+        drop(lock);
+        assert!(!Path::new(&path).exists());
+
+        // stale lock of a killed run
+        std::fs::write(&path, "{\"pid\": 1, \"token\": \"stale\"}").unwrap();
+        assert!(RunLock::acquire(path.clone(), false).is_err());
+        let lock = RunLock::acquire(path.clone(), true).unwrap();
+        assert!(Path::new(&path).exists());
+        drop(lock);
+        assert!(!Path::new(&path).exists());
+    }
+}
@@ -1,24 +1,80 @@
+pub mod alias;
+pub mod alignment;
+pub mod cardinality;
+pub mod clustering;
+pub mod compare;
 pub mod configuration;
-pub mod pipeline;
-pub mod persistence;
+pub mod cost;
+pub mod debug;
+#[cfg(feature = "delta")]
+pub mod delta;
+pub mod dictionary;
+pub mod dry_run;
+#[cfg(feature = "duckdb")]
+pub mod duckdb_output;
+pub mod edge_types;
 pub mod embedding;
 pub mod entity;
+pub mod error;
+pub mod feast;
+#[cfg(feature = "flight")]
+pub mod flight;
+pub mod gate;
+pub mod graph_export;
+pub mod graph_import;
+pub mod interrupt;
 pub mod io;
+pub mod knn;
+pub mod loader;
+pub mod lock;
+pub mod manifest;
+pub mod matrix_export;
+#[cfg(feature = "mlflow")]
+pub mod mlflow;
+pub mod onnx;
+pub mod pca;
+pub mod persistence;
+pub mod pipeline;
+pub mod profile;
+pub mod projector;
+pub mod query;
+pub mod resources;
+pub mod sketch;
 pub mod sparse_matrix;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_output;
+pub mod stitch;
+pub mod tags;
+pub mod vocab;
 use std::time::Instant;
 
-use clap::{crate_authors, crate_description, crate_name, crate_version, Arg, Command};
+use chrono::Utc;
+use clap::{crate_authors, crate_description, crate_name, crate_version, Arg, ArgMatches, Command};
 use configuration::Configuration;
-use configuration::OutputFormat;
-use persistence::entity::InMemoryEntityMappingPersistor;
-use pipeline::{build_graphs, train};
+use configuration::{
+    BudgetPolicy, CostPrices, EdgeTypeCombination, EntitiesFormat, HashFunction, KnnGraph,
+    KnnMethod, MalformedRows, MetadataColumn, Normalization, OutputFormat, PropagationKernel,
+    PropagationPartitioning, SelfLoops, StatsScope, TemporalDecay,
+};
+use entity::create_entity_mapping_persistor;
 use env_logger::Env;
+use error::CleoraError;
+use lock::RunLock;
+use pipeline::{
+    build_graphs, check_outputs, check_previous_outputs, lock_file_name, profile_file_name, train,
+};
+use resources::ResourceLimits;
+use std::ffi::OsString;
 use std::fs;
+use std::process;
 use std::sync::Arc;
 
 #[macro_use]
 extern crate log;
 
+#[global_allocator]
+static ALLOCATOR: profile::CountingAllocator = profile::CountingAllocator;
+
 fn main() {
     let env = Env::default()
         .filter_or("MY_LOG_LEVEL", "info")
@@ -27,123 +83,1118 @@ fn main() {
 
     let now = Instant::now();
 
-    let matches = Command::new(crate_name!())
+    let run_config = read_run_config(std::env::args().skip(1)).unwrap_or_else(|msg| {
+        error!("Invalid config file. {}", msg);
+        process::exit(1)
+    });
+    let command = Command::new(crate_name!())
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
-        .arg(
-            Arg::new("inputs")
-                .multiple_values(true)
-                .help("Input files paths")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::new("input")
-                .short('i')
-                .long("input")
-                .help("Deprecated. Use positional args for input files")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::new("file-type")
-                .short('t')
-                .long("type")
-                .possible_values(&["tsv", "json"])
-                .help("Input file type")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::new("output-dir")
-                .short('o')
-                .long("output-dir")
-                .help("Output directory for files with embeddings")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::new("dimension")
-                .short('d')
-                .long("dimension")
-                .required(true)
-                .help("Embedding dimension size")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::new("number-of-iterations")
-                .short('n')
-                .long("number-of-iterations")
-                .required(true)
-                .help("Max number of iterations")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::new("seed")
-                .short('s')
-                .long("seed")
-                .help("Seed (integer) for embedding initialization")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::new("columns")
-                .short('c')
-                .long("columns")
-                .required(true)
-                .help(
-                    "Column names (max 12), with modifiers: [transient::, reflexive::, complex::]",
-                )
-                .takes_value(true),
-        )
-        .arg(
-            Arg::new("relation-name")
-                .short('r')
-                .long("relation-name")
-                .default_value("emb")
-                .help("Name of the relation, for output filename generation")
-                .takes_value(true),
+        .subcommand_negates_reqs(true)
+        .args(pipeline_args())
+        .subcommand(
+            Command::new("debug-dump")
+                .about("Write a compact snapshot of internal structures for bug reports")
+                .args(pipeline_args())
+                .arg(
+                    Arg::new("stage")
+                        .long("stage")
+                        .required(true)
+                        .help("Stage of the snapshot. One of: post-parse|post-matrix|post-iteration-N")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("sample-size")
+                        .long("sample-size")
+                        .default_value("20")
+                        .help("Number of sample rows written for every structure")
+                        .takes_value(true),
+                ),
         )
-        .arg(
-            Arg::new("prepend-field-name")
-                .short('p')
-                .long("prepend-field-name")
-                .possible_values(&["0", "1"])
-                .default_value("0")
-                .help("Prepend field name to entity in output")
-                .takes_value(true),
+        .subcommand(
+            Command::new("export-graph")
+                .about("Write the constructed graph for visualization tools such as Gephi or Cytoscape")
+                .args(pipeline_args())
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .possible_values(&["gexf", "graphml"])
+                        .default_value("gexf")
+                        .help("Graph file format")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("max-nodes")
+                        .long("max-nodes")
+                        .default_value("100000")
+                        .help("Max number of nodes in every graph, the most frequent entities are kept")
+                        .takes_value(true),
+                ),
         )
-        .arg(
-            Arg::new("log-every-n")
-                .short('l')
-                .long("log-every-n")
-                .default_value("10000")
-                .help("Log output every N lines")
-                .takes_value(true),
+        .subcommand(
+            Command::new("stitch")
+                .about("Align embeddings of disjoint entity partitions on their shared (anchor) entities and write one embedding space")
+                .arg(
+                    Arg::new("partitions")
+                        .required(true)
+                        .multiple_values(true)
+                        .min_values(2)
+                        .help("Embedding files of the partitions (text, .npy, .npz or .bin). The first one is the reference space"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .required(true)
+                        .help("Output embedding file, the residuals report is written next to it")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("output-format")
+                        .short('f')
+                        .long("output-format")
+                        .possible_values(&["textfile", "numpy", "parquet", "raw", "compact", "npz"])
+                        .default_value("textfile")
+                        .help("Output format")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("min-anchors")
+                        .long("min-anchors")
+                        .help("Min number of anchor entities of every partition, defaults to the dimension")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("overwrite")
+                        .long("overwrite")
+                        .help("Replace existing output files"),
+                ),
         )
-        .arg(
-            Arg::new("in-memory-embedding-calculation")
-                .short('e')
-                .long("in-memory-embedding-calculation")
-                .possible_values(&["0", "1"])
-                .default_value("1")
-                .help("Calculate embeddings in memory or with memory-mapped files")
-                .takes_value(true),
+        .subcommand(
+            Command::new("query")
+                .about("Print entities most similar (cosine similarity) to the given ones")
+                .arg(
+                    Arg::new("embeddings")
+                        .long("embeddings")
+                        .required(true)
+                        .help("Embedding file (text, .npy, .npz, .bin or .parquet)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("entity")
+                        .long("entity")
+                        .required_unless_present("hash")
+                        .multiple_occurrences(true)
+                        .help("Queried entity, can be repeated")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("hash")
+                        .long("hash")
+                        .multiple_occurrences(true)
+                        .requires("entity-mapping")
+                        .help("Hash of the queried entity, resolved with --entity-mapping, can be repeated")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("entity-mapping")
+                        .long("entity-mapping")
+                        .help("Entity mapping written with --entity-mapping-format dict")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("tags")
+                        .long("tags")
+                        .requires("tag")
+                        .help("Entity tags file written with --entity-tags")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("tag")
+                        .long("tag")
+                        .multiple_occurrences(true)
+                        .requires("tags")
+                        .help("Return only neighbors with the tag (from --tags), can be repeated")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("k")
+                        .short('k')
+                        .long("k")
+                        .default_value("10")
+                        .help("Number of similar entities")
+                        .takes_value(true),
+                ),
         )
-        .arg(
-            Arg::new("output-format")
-                .short('f')
-                .help("Output format. One of: textfile|numpy")
-                .possible_values(&["textfile", "numpy"])
-                .default_value("textfile")
-                .takes_value(true),
+        .subcommand(
+            Command::new("gate")
+                .about("Compare candidate embeddings with the baseline on known-similar entities, exit with error if the candidate is worse")
+                .arg(
+                    Arg::new("probes")
+                        .long("probes")
+                        .required(true)
+                        .help("JSON array of probes: {\"entity\": \"a\", \"similar\": [\"b\", \"c\"]} objects or [\"a\", \"b\"] pairs")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("baseline")
+                        .long("baseline")
+                        .required(true)
+                        .help("Baseline embedding file (text, .npy, .npz, .bin or .parquet)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("candidate")
+                        .long("candidate")
+                        .required(true)
+                        .help("Candidate embedding file (text, .npy, .npz, .bin or .parquet)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("min-recall")
+                        .long("min-recall")
+                        .default_value("0.95")
+                        .help("Minimal candidate recall relative to the baseline recall")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("k")
+                        .short('k')
+                        .long("k")
+                        .default_value("10")
+                        .help("Number of nearest neighbors searched for the similar entities")
+                        .takes_value(true),
+                ),
         )
-        .arg(
-            Arg::new("chunk-size")
-                .help("Chunk size of output write")
-                .default_value("3000")
-                .takes_value(true),
+        .subcommand(
+            Command::new("compare")
+                .about("Compare two embedding outputs: overlap of the entities, cosine distances of their vectors and overlap of their nearest neighbors")
+                .arg(
+                    Arg::new("a")
+                        .required(true)
+                        .help("First embedding file (text, .npy, .npz, .bin or .parquet)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("b")
+                        .required(true)
+                        .help("Second embedding file (text, .npy, .npz, .bin or .parquet)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("k")
+                        .short('k')
+                        .long("k")
+                        .default_value("10")
+                        .help("Number of nearest neighbors compared")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("sample-size")
+                        .long("sample-size")
+                        .default_value("1000")
+                        .help("Max number of common entities whose nearest neighbors are compared, every search scans all entities")
+                        .takes_value(true),
+                ),
         )
+        .subcommand(
+            Command::new("serve")
+                .about("Serve parquet outputs of earlier runs over Arrow Flight, like --output-format flight serves the outputs of a run")
+                .arg(
+                    Arg::new("outputs")
+                        .required(true)
+                        .multiple_values(true)
+                        .help("Parquet output files, served as tables named like the files without their directory and .parquet extension"),
+                )
+                .arg(
+                    Arg::new("flight-address")
+                        .long("flight-address")
+                        .default_value("127.0.0.1:8815")
+                        .help("Address the outputs are served on, e.g. 0.0.0.0:8815 to accept remote clients")
+                        .takes_value(true),
+                ),
+        );
+    let matches = parse_args(command, std::env::args_os().collect(), &run_config)
+        .unwrap_or_else(|err| err.exit());
+
+    if let Some(("debug-dump", sub_matches)) = matches.subcommand() {
+        let config = parse_configuration(sub_matches);
+        io::set_storage_class(config.storage_class);
+        let stage = match debug::extract_stage(sub_matches.value_of("stage").unwrap()) {
+            Ok(stage) => stage,
+            Err(msg) => panic!("Invalid debug dump stage. Message: {}", msg),
+        };
+        let sample_size: usize = sub_matches
+            .value_of("sample-size")
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        info!("Starting debug dump...");
+        let dump_path = debug::dump(config, stage, sample_size).unwrap_or_else(|err| {
+            error!("Can't write debug dump. {}", err);
+            process::exit(1)
+        });
+        info!(
+            "Debug dump written to {} in {} sec",
+            dump_path,
+            now.elapsed().as_secs()
+        );
+        return;
+    }
+
+    if let Some(("export-graph", sub_matches)) = matches.subcommand() {
+        let config = parse_configuration(sub_matches);
+        io::set_storage_class(config.storage_class);
+        let format = match sub_matches.value_of("format").unwrap() {
+            "gexf" => graph_export::GraphFormat::Gexf,
+            "graphml" => graph_export::GraphFormat::GraphMl,
+            _ => panic!("unsupported graph format"),
+        };
+        let max_nodes: usize = sub_matches.value_of("max-nodes").unwrap().parse().unwrap();
+
+        info!("Starting graph export...");
+        let file_names =
+            graph_export::export_graph(config, format, max_nodes).unwrap_or_else(|err| {
+                error!("Can't export graph. {}", err);
+                process::exit(1)
+            });
+        info!(
+            "Graph written to {:?} in {} sec",
+            file_names,
+            now.elapsed().as_secs()
+        );
+        return;
+    }
 
-        .get_matches();
+    if let Some(("stitch", sub_matches)) = matches.subcommand() {
+        let paths: Vec<String> = sub_matches
+            .values_of("partitions")
+            .unwrap()
+            .map(|p| p.to_string())
+            .collect();
+        let output = sub_matches.value_of("output").unwrap().to_string();
+        let output_format = match sub_matches.value_of("output-format").unwrap() {
+            "textfile" => OutputFormat::TextFile,
+            "numpy" => OutputFormat::Numpy,
+            "parquet" => OutputFormat::Parquet,
+            "raw" => OutputFormat::Raw,
+            "compact" => OutputFormat::Compact,
+            "npz" => OutputFormat::Npz,
+            _ => panic!("unsupported output format"),
+        };
+        let min_anchors: Option<usize> = sub_matches
+            .value_of("min-anchors")
+            .map(|v| v.parse().unwrap());
+
+        if !sub_matches.is_present("overwrite") {
+            let mut files = pipeline::embedding_output_files(&output_format, &output, false);
+            files.push(stitch::report_file_name(&output));
+            if let Err(err) = pipeline::check_absent(files) {
+                error!("{}", err);
+                process::exit(1);
+            }
+        }
+
+        info!("Starting stitching...");
+        if let Err(err) = stitch::stitch(&paths, output, &output_format, min_anchors) {
+            error!("Stitching failed. {}", err);
+            process::exit(1);
+        }
+        info!("Finished stitching in {} sec", now.elapsed().as_secs());
+        return;
+    }
+
+    if let Some(("query", sub_matches)) = matches.subcommand() {
+        let path = sub_matches.value_of("embeddings").unwrap();
+        let k: usize = sub_matches.value_of("k").unwrap().parse().unwrap();
+        let embeddings = loader::load_embeddings(path).unwrap_or_else(|err| {
+            error!("Can't load embeddings. {}", err);
+            process::exit(1)
+        });
+        let mut index = query::CosineIndex::new(embeddings);
+        info!(
+            "Loaded {} embeddings in {} sec",
+            index.len(),
+            now.elapsed().as_secs()
+        );
+        if let Some(tags_path) = sub_matches.value_of("tags") {
+            let tags = tags::EntityTags::load(tags_path).unwrap_or_else(|err| {
+                error!("Can't load entity tags. {}", err);
+                process::exit(1)
+            });
+            let wanted: Vec<String> = sub_matches
+                .values_of("tag")
+                .unwrap()
+                .map(String::from)
+                .collect();
+            index.restrict_neighbors(|entity| tags.has_any(entity, &wanted));
+            info!(
+                "Returning {} neighbors tagged with {}",
+                index.neighbors_len(),
+                wanted.join(", ")
+            );
+        }
 
+        let mut entities: Vec<String> = sub_matches
+            .values_of("entity")
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or_default();
+        let mut missing = false;
+        if let Some(hashes) = sub_matches.values_of("hash") {
+            let mapping_path = sub_matches.value_of("entity-mapping").unwrap();
+            let mapping = dictionary::EntityDictionary::open(mapping_path).unwrap_or_else(|err| {
+                error!("Can't open entity mapping. {}", err);
+                process::exit(1)
+            });
+            for hash in hashes {
+                let hash: u64 = hash.parse().expect("Invalid hash");
+                match mapping.get(hash) {
+                    Ok(Some(entity)) => entities.push(entity),
+                    Ok(None) => {
+                        error!("Hash {} not found in {}", hash, mapping_path);
+                        missing = true;
+                    }
+                    Err(err) => {
+                        error!("Can't read entity mapping. {}", err);
+                        process::exit(1);
+                    }
+                }
+            }
+        }
+
+        for entity in &entities {
+            match index.nearest_to_entity(entity, k) {
+                Some(neighbors) => {
+                    println!("{}", entity);
+                    for (rank, (neighbor, similarity)) in neighbors.iter().enumerate() {
+                        println!("{}\t{}\t{}", rank + 1, neighbor, similarity);
+                    }
+                }
+                None => {
+                    error!("Entity {} not found in {}", entity, path);
+                    missing = true;
+                }
+            }
+        }
+        if missing {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(("gate", sub_matches)) = matches.subcommand() {
+        let k: usize = sub_matches.value_of("k").unwrap().parse().unwrap();
+        let min_recall: f32 = sub_matches.value_of("min-recall").unwrap().parse().unwrap();
+        let probes =
+            gate::load_probes(sub_matches.value_of("probes").unwrap()).unwrap_or_else(|err| {
+                error!("Can't load probes. {}", err);
+                process::exit(1)
+            });
+
+        let mut scores = Vec::new();
+        for name in ["baseline", "candidate"] {
+            let path = sub_matches.value_of(name).unwrap();
+            let embeddings = loader::load_embeddings(path).unwrap_or_else(|err| {
+                error!("Can't load {} embeddings. {}", name, err);
+                process::exit(1)
+            });
+            let score = gate::score(&query::CosineIndex::new(embeddings), &probes, k);
+            println!(
+                "{}\trecall@{}\t{}\tfound\t{}/{}\tmissing entities\t{}",
+                name,
+                k,
+                score.recall(),
+                score.found,
+                score.expected,
+                score.missing
+            );
+            scores.push(score);
+        }
+
+        if gate::passes(&scores[0], &scores[1], min_recall) {
+            info!("Candidate passed the gate.");
+        } else {
+            error!(
+                "Candidate recall {} is below {} of the baseline recall {}.",
+                scores[1].recall(),
+                min_recall,
+                scores[0].recall()
+            );
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(("serve", sub_matches)) = matches.subcommand() {
+        let address = sub_matches.value_of("flight-address").unwrap();
+        let outputs: Vec<&str> = sub_matches.values_of("outputs").unwrap().collect();
+        #[cfg(feature = "flight")]
+        if let Err(err) = flight::serve_parquet_files(address, &outputs) {
+            error!("{}", err);
+            process::exit(1);
+        }
+        #[cfg(not(feature = "flight"))]
+        {
+            error!(
+                "Can't serve {} on {}, serving requires cleora built with the flight feature",
+                outputs.join(", "),
+                address
+            );
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(("compare", sub_matches)) = matches.subcommand() {
+        let k: usize = sub_matches.value_of("k").unwrap().parse().unwrap();
+        let sample_size: usize = sub_matches
+            .value_of("sample-size")
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut indexes = Vec::new();
+        for name in ["a", "b"] {
+            let path = sub_matches.value_of(name).unwrap();
+            let embeddings = loader::load_embeddings(path).unwrap_or_else(|err| {
+                error!("Can't load embeddings {}. {}", path, err);
+                process::exit(1)
+            });
+            indexes.push(query::CosineIndex::new(embeddings));
+        }
+
+        let comparison = compare::compare(&indexes[0], &indexes[1], k, sample_size);
+        println!(
+            "entities\ta\t{}\tb\t{}\tcommon\t{}\tonly a\t{}\tonly b\t{}",
+            comparison.entities_a,
+            comparison.entities_b,
+            comparison.common,
+            comparison.entities_a - comparison.common,
+            comparison.entities_b - comparison.common
+        );
+        match &comparison.distances {
+            Some(distances) => println!(
+                "cosine distance\tmean\t{}\tp50\t{}\tp90\t{}\tp99\t{}\tmax\t{}",
+                distances.mean(),
+                distances.quantile(0.5),
+                distances.quantile(0.9),
+                distances.quantile(0.99),
+                distances.quantile(1.0)
+            ),
+            None => println!("cosine distance\tdimensions differ"),
+        }
+        let neighborhoods = &comparison.neighborhoods;
+        println!(
+            "jaccard@{}\tmean\t{}\tp1\t{}\tp10\t{}\tp50\t{}\tmin\t{}\tsampled\t{}",
+            k,
+            neighborhoods.mean(),
+            neighborhoods.quantile(0.01),
+            neighborhoods.quantile(0.1),
+            neighborhoods.quantile(0.5),
+            neighborhoods.quantile(0.0),
+            neighborhoods.len()
+        );
+        return;
+    }
+
+    let config = parse_configuration(&matches);
+    dbg!(&config);
+    io::set_storage_class(config.storage_class);
+
+    if config.dry_run {
+        info!("Starting dry run...");
+        let report = dry_run::dry_run(&config).unwrap_or_else(|err| {
+            error!("Can't read inputs. {}", err);
+            process::exit(1)
+        });
+        println!("{}", report);
+        if let Some(prices) = config.estimate_cost {
+            match cost::estimate_cost(&config, &report, prices) {
+                Ok(cost) => println!("{}", cost),
+                Err(err) => {
+                    error!("Can't estimate cost. {}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        info!("Finished dry run in {} sec", now.elapsed().as_secs());
+        return;
+    }
+
+    if let Err(err) = check_outputs(&config).and_then(|_| check_previous_outputs(&config)) {
+        error!("{}", err);
+        process::exit(1);
+    }
+    // held until the end of the run
+    let lock = RunLock::acquire(lock_file_name(&config), config.force).unwrap_or_else(|err| {
+        error!("{}", err);
+        process::exit(1)
+    });
+
+    let profile_file = config.profile.then(|| profile_file_name(&config));
+    if profile_file.is_some() {
+        profile::enable();
+    }
+
+    info!("Starting calculation...");
+    let in_memory_entity_mapping_persistor = create_entity_mapping_persistor(&config);
+    let in_memory_entity_mapping_persistor = Arc::new(in_memory_entity_mapping_persistor);
+
+    let sparse_matrices = match config.load_vocab.as_ref() {
+        Some(path) => vocab::load_vocab(path, &config, &in_memory_entity_mapping_persistor)
+            .map_err(|err| format!("Can't load vocabulary. {}", err)),
+        None => build_graphs(&config, in_memory_entity_mapping_persistor.clone())
+            .map_err(|err| format!("Can't build graphs. {}", err)),
+    };
+    let sparse_matrices = match sparse_matrices {
+        Ok(sparse_matrices) => sparse_matrices,
+        Err(message) => {
+            error!("{}", message);
+            drop(lock);
+            process::exit(1);
+        }
+    };
+    info!(
+        "Finished Sparse Matrices calculation in {} sec",
+        now.elapsed().as_secs()
+    );
+    if let Some(path) = config.save_vocab.as_ref() {
+        if let Err(err) = vocab::save_vocab(
+            path,
+            &config,
+            &in_memory_entity_mapping_persistor,
+            &sparse_matrices,
+        ) {
+            error!("Can't save vocabulary. {}", err);
+            drop(lock);
+            process::exit(1);
+        }
+    }
+
+    #[cfg(feature = "mlflow")]
+    let mlflow_run = match config.mlflow_uri.as_ref() {
+        Some(uri) => match mlflow::MlflowRun::start(uri, &config) {
+            Ok(run) => Some(run),
+            Err(err) => {
+                error!("Can't start MLflow run. {}", err);
+                drop(lock);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    #[cfg(feature = "flight")]
+    let flight_address = config.output_formats().find_map(|format| match format {
+        OutputFormat::Flight(address) => Some(address.clone()),
+        _ => None,
+    });
+    // interrupts before the training leave nothing behind, the outputs aren't created yet
+    interrupt::handle_interrupts();
+    let result = train(
+        config,
+        in_memory_entity_mapping_persistor,
+        sparse_matrices,
+        now,
+    );
+    #[cfg(feature = "mlflow")]
+    if let Some(run) = mlflow_run {
+        run.end(&result);
+    }
+    if let Err(err) = result {
+        error!("Training failed. {}", err);
+        // exit doesn't run destructors
+        drop(lock);
+        match err {
+            CleoraError::Interrupted => process::exit(interrupt::INTERRUPTED_EXIT_CODE),
+            _ => process::exit(1),
+        }
+    }
+    if let Some(profile_file) = profile_file {
+        if let Err(err) = profile::write_report(&profile_file, now) {
+            error!("Can't write profile report. {}", err);
+        }
+    }
+    drop(lock);
+    if interrupt::interrupted() {
+        warn!(
+            "Run was interrupted after {} sec. Outputs are finished, but may miss iterations (see their .meta.json), resume with --continue-from the output directory and --overwrite.",
+            now.elapsed().as_secs()
+        );
+        process::exit(interrupt::INTERRUPTED_EXIT_CODE);
+    }
+    info!("Finished in {} sec", now.elapsed().as_secs());
+
+    #[cfg(feature = "flight")]
+    if let Some(address) = flight_address {
+        if let Err(err) = flight::serve_tables(&address) {
+            error!("{}", err);
+            process::exit(1);
+        }
+    }
+}
+
+/// Args describing the input, the graph and the embedding process. Shared by the main command
+/// and subcommands which need to build the graph.
+fn pipeline_args<'a>() -> Vec<Arg<'a>> {
+    vec![
+        Arg::new("config")
+            .long("config")
+            .help("Run configuration file (TOML, YAML or JSON) with options named like the long flags, e.g. dimension: 128. Flags given on the command line override the file")
+            .takes_value(true),
+        Arg::new("inputs")
+            .multiple_values(true)
+            .help("Input files paths")
+            .takes_value(true),
+        Arg::new("input")
+            .short('i')
+            .long("input")
+            .help("Deprecated. Use positional args for input files")
+            .takes_value(true),
+        Arg::new("file-type")
+            .short('t')
+            .long("type")
+            .possible_values(&["tsv", "json", "graphml", "snap"])
+            .help("Input file type: tsv, json or a graph, GraphML or a SNAP edge list (rows of the source and target of every edge, columns \"source target\" by default)")
+            .takes_value(true),
+        Arg::new("field-delimiter")
+            .long("field-delimiter")
+            .default_value("tab")
+            .help("Separator of the fields of TSV input: a single ASCII character (e.g. , or |), tab or space")
+            .takes_value(true),
+        Arg::new("value-delimiters")
+            .long("value-delimiters")
+            .help("Separators of the values of complex columns in TSV input, e.g. \"tags:| brands:,\". Values of other complex columns are separated by spaces")
+            .takes_value(true),
+        Arg::new("aliases")
+            .long("aliases")
+            .help("File of alias<TAB>canonical lines, entities of all columns are replaced with their canonical IDs while the input is parsed")
+            .takes_value(true),
+        Arg::new("output-dir")
+            .short('o')
+            .long("output-dir")
+            .help("Output directory for files with embeddings")
+            .takes_value(true),
+        Arg::new("dimension")
+            .short('d')
+            .long("dimension")
+            .required_unless_present("config")
+            .help("Embedding dimension size")
+            .takes_value(true),
+        Arg::new("number-of-iterations")
+            .short('n')
+            .long("number-of-iterations")
+            .required_unless_present("config")
+            .help("Max number of iterations")
+            .takes_value(true),
+        Arg::new("seed")
+            .short('s')
+            .long("seed")
+            .help("Seed (integer) for embedding initialization")
+            .takes_value(true),
+        Arg::new("columns")
+            .short('c')
+            .long("columns")
+            .required_unless_present_any(&["config", "file-type"])
+            .help("Column names (max 12), with modifiers: [transient::, reflexive::, complex::, weight::, edge_type::, timestamp::, count::, tags::]. Graph inputs (--type graphml or snap) have two columns, \"source target\" by default")
+            .takes_value(true),
+        Arg::new("output-columns")
+            .long("output-columns")
+            .help("Columns which get output files, e.g. user,product. Embeddings of the other columns are computed but not written (like transient ones) and pairs without an output column aren't trained")
+            .takes_value(true),
+        Arg::new("relation-name")
+            .short('r')
+            .long("relation-name")
+            .default_value("emb")
+            .help("Name of the relation, for output filename generation")
+            .takes_value(true),
+        Arg::new("prepend-field-name")
+            .short('p')
+            .long("prepend-field-name")
+            .possible_values(&["0", "1"])
+            .default_value("0")
+            .help("Prepend field name to entity in output")
+            .takes_value(true),
+        Arg::new("log-every-n")
+            .short('l')
+            .long("log-every-n")
+            .default_value("10000")
+            .help("Log output every N lines")
+            .takes_value(true),
+        Arg::new("in-memory-embedding-calculation")
+            .short('e')
+            .long("in-memory-embedding-calculation")
+            .possible_values(&["0", "1"])
+            .default_value("1")
+            .help("Calculate embeddings in memory or with memory-mapped files")
+            .takes_value(true),
+        Arg::new("output-format")
+            .short('f')
+            .long("output-format")
+            .multiple_occurrences(true)
+            .help("Output format, can be repeated to write the embeddings of one propagation in several formats. One of: textfile|parquet|numpy|raw|compact|npz|onnx|projector|delta|duckdb|sqlite|flight|feast")
+            .possible_values(&[
+                "textfile",
+                "parquet",
+                "numpy",
+                "raw",
+                "compact",
+                "npz",
+                "onnx",
+                "projector",
+                "delta",
+                "duckdb",
+                "sqlite",
+                "flight",
+                "feast",
+            ])
+            .default_value("textfile")
+            .takes_value(true),
+        Arg::new("projector-points")
+            .long("projector-points")
+            .help("Points of projector output, sampled uniformly from the entities (the same ones in every run). Defaults to all of them")
+            .takes_value(true),
+        Arg::new("delta-mode")
+            .long("delta-mode")
+            .possible_values(&["append", "overwrite"])
+            .default_value("append")
+            .help("How delta output is committed to an existing table: rows added (columns of another dimension merged into the schema) or replaced with the schema")
+            .takes_value(true),
+        Arg::new("duckdb-layout")
+            .long("duckdb-layout")
+            .possible_values(&["columns", "array"])
+            .default_value("columns")
+            .help("Vector columns of duckdb output: f0..fN float columns or a single embedding column of FLOAT[] lists")
+            .takes_value(true),
+        Arg::new("flight-address")
+            .long("flight-address")
+            .default_value("127.0.0.1:8815")
+            .help("Address flight output is served on when the run completes, e.g. 0.0.0.0:8815 to accept remote clients")
+            .takes_value(true),
+        Arg::new("feast-push-url")
+            .long("feast-push-url")
+            .help("Feast feature server (e.g. http://localhost:6566) the rows of feast output are pushed to, into the online store. The feature view definitions written with the output have to be applied first")
+            .takes_value(true),
+        Arg::new("output-metadata")
+            .long("output-metadata")
+            .default_value("occur_count,datetime")
+            .help("Comma separated metadata columns written with the embeddings: occur_count (all formats), datetime (time of the run, parquet and delta only) or none")
+            .takes_value(true),
+        Arg::new("entities-format")
+            .long("entities-format")
+            .possible_values(&["json", "lines", "binary"])
+            .default_value("json")
+            .help("Format of the .entities file of numpy, raw and onnx outputs: JSON list, entity per line (names can't contain new lines) or length-prefixed binary, the last two are faster to parse for many entities")
+            .takes_value(true),
+        Arg::new("chunk-size")
+            .last(true)
+            .help("Chunk size of output write, given after -- (the input paths take all other positional args). Defaults to 3000 rows, fewer when the memory limit of the container (cgroup v2) is low")
+            .takes_value(true),
+        Arg::new("write-queue")
+            .long("write-queue")
+            .help("Chunks queued for the background writer thread of every output, which encodes, compresses and uploads them while the next chunks are computed. 0 writes on the training thread")
+            .default_value("4")
+            .takes_value(true),
+        Arg::new("threads")
+            .long("threads")
+            .help("Threads parsing the input and propagating the embeddings. Defaults to the CPU limit of the container (cgroup v2), rounded up, or to all cores of the host")
+            .takes_value(true),
+        Arg::new("init")
+            .long("init")
+            .default_value("uniform")
+            .help("Embedding initialization. One of: uniform|sparse-rp[:density=D]")
+            .takes_value(true),
+        Arg::new("entity-mapping-format")
+            .long("entity-mapping-format")
+            .possible_values(&["tsv", "parquet", "dict"])
+            .help("Write hash to entity mapping in the given format at the end of the run. dict is a compressed, memory-mappable dictionary")
+            .takes_value(true),
+        Arg::new("fail-on-collision")
+            .long("fail-on-collision")
+            .help("Abort when two different entities map to the same hash"),
+        Arg::new("collision-budget")
+            .long("collision-budget")
+            .help("Abort when more than this many hashes are shared by different entities")
+            .takes_value(true),
+        Arg::new("overwrite")
+            .long("overwrite")
+            .help("Replace existing output files"),
+        Arg::new("postprocess")
+            .long("postprocess")
+            .multiple_occurrences(true)
+            .help("Postprocessing step applied to embeddings before writing, can be repeated. One of: standardize|winsorize[:p=P]")
+            .takes_value(true),
+        Arg::new("postprocess-stats")
+            .long("postprocess-stats")
+            .possible_values(&["per-type", "shared"])
+            .default_value("per-type")
+            .help("Compute postprocessing statistics per entity type (column) or shared by all entities")
+            .takes_value(true),
+        Arg::new("normalize")
+            .long("normalize")
+            .possible_values(&["none", "l2", "zscore"])
+            .default_value("none")
+            .help("Scaling of the final embeddings, applied after postprocessing steps")
+            .takes_value(true),
+        Arg::new("reduce-dimension")
+            .long("reduce-dimension")
+            .help("Also write embeddings reduced to this dimension with PCA fitted on the final embeddings, with the fitted projection (<output>.pca.npz)")
+            .takes_value(true),
+        Arg::new("knn-graph")
+            .long("knn-graph")
+            .help("Also write graph of k nearest neighbors (by cosine similarity) of every entity, given k, as an edge list with similarities (<output>.knn.tsv)")
+            .takes_value(true),
+        Arg::new("knn-method")
+            .long("knn-method")
+            .requires("knn-graph")
+            .possible_values(&["auto", "exact", "approximate"])
+            .default_value("auto")
+            .help("Search of the nearest neighbors for --knn-graph, auto is exact for up to 20000 entities and approximate for more")
+            .takes_value(true),
+        Arg::new("hash")
+            .long("hash")
+            .possible_values(&["xxhash64", "fxhash", "identity"])
+            .default_value("xxhash64")
+            .help("Entity hash function. Identity requires numeric entity ids and keeps no hash to entity mapping")
+            .takes_value(true),
+        Arg::new("hash-bits")
+            .long("hash-bits")
+            .possible_values(&["32", "64"])
+            .default_value("64")
+            .help("Width of the entity hashes. 32-bit hashes make the sparse matrix indices smaller but collide more often, the number of collisions is logged after parsing")
+            .takes_value(true),
+        Arg::new("sort-output")
+            .long("sort-output")
+            .help("Order of the output rows, the same in every run with entity or occurrence. One of: cluster[:k=K], entity, occurrence (most frequent first)")
+            .takes_value(true),
+        Arg::new("output-template")
+            .long("output-template")
+            .help("Embedding file name template with placeholders: {relation}, {column}, {dim}, {timestamp}, {format}. Defaults to {relation}__{column}.out ({relation}__{column}_{timestamp}.parquet for parquet)")
+            .takes_value(true),
+        Arg::new("memory-budget")
+            .long("memory-budget")
+            .help("Memory the run may use, e.g. 512M or 16G. Memory needed for the estimated number of unique entities is checked against it during ingestion")
+            .takes_value(true),
+        Arg::new("on-budget-exceeded")
+            .long("on-budget-exceeded")
+            .possible_values(&["abort", "warn"])
+            .default_value("abort")
+            .help("Abort the run or only warn when the estimated memory usage exceeds --memory-budget")
+            .takes_value(true),
+        Arg::new("auto-pairs")
+            .long("auto-pairs")
+            .help("Train only the column pairs whose graphs have some structure, the others (without relations, with a single entity in a column or related one to one) are skipped once the graphs are built. Pairs of two transient columns, ignored columns and --exclude-pair pairs aren't built"),
+        Arg::new("exclude-pair")
+            .long("exclude-pair")
+            .multiple_occurrences(true)
+            .requires("auto-pairs")
+            .help("Column pair not trained in --auto-pairs mode, e.g. users:brands (users:users for reflexive column), can be repeated")
+            .takes_value(true),
+        Arg::new("relations")
+            .long("relations")
+            .help("Train and persist only the given column pairs, e.g. users<->products,products<->brands (users<->users for reflexive column)")
+            .takes_value(true),
+        Arg::new("edge-types")
+            .long("edge-types")
+            .help("Labels of the edge_type column, e.g. viewed,bought. Edges of every type are trained separately, rows of other types are skipped")
+            .takes_value(true),
+        Arg::new("edge-type-combination")
+            .long("edge-type-combination")
+            .possible_values(&["concat", "merge"])
+            .default_value("concat")
+            .help("Concatenate embeddings of the edge types (dimension times number of types) or take their mean")
+            .takes_value(true),
+        Arg::new("half-life")
+            .long("half-life")
+            .help("Age halving the weight of the row, e.g. 30d, given by the timestamp column (unix seconds, RFC 3339 time or date)")
+            .takes_value(true),
+        Arg::new("decay-reference")
+            .long("decay-reference")
+            .requires("half-life")
+            .help("Time the age of the rows is measured to, e.g. 2022-06-01, start of the run by default")
+            .takes_value(true),
+        Arg::new("feature-init")
+            .long("feature-init")
+            .help("Embeddings file (any output format) with feature vectors of the entities used as their initial vectors, projected or padded to the dimension")
+            .takes_value(true),
+        Arg::new("export-matrix")
+            .long("export-matrix")
+            .possible_values(&["mtx", "npz"])
+            .help("Write the sparse matrices (as they are propagated) with their entities next to the embeddings. mtx is Matrix Market, npz holds CSR arrays for scipy")
+            .takes_value(true),
+        Arg::new("min-entity-count")
+            .long("min-entity-count")
+            .default_value("1")
+            .help("Remove entities occurring fewer times before training, they get no embeddings")
+            .takes_value(true),
+        Arg::new("max-entities")
+            .long("max-entities")
+            .multiple_occurrences(true)
+            .help("Keep only K most frequent entities of the column, e.g. session_id=100000, can be repeated")
+            .takes_value(true),
+        Arg::new("unknown-bucket")
+            .long("unknown-bucket")
+            .requires("max-entities")
+            .help("Merge entities cut off by --max-entities into one <UNK> entity of the column instead of removing them"),
+        Arg::new("entity-tags")
+            .long("entity-tags")
+            .multiple_occurrences(true)
+            .help("Tags column of the entity column, e.g. items=item_tags: tags of the row are attached to its items, can be repeated")
+            .takes_value(true),
+        Arg::new("output-tag")
+            .long("output-tag")
+            .multiple_occurrences(true)
+            .requires("entity-tags")
+            .help("Write embeddings only of entities with the tag (e.g. type=product), all entities are still propagated, can be repeated to write entities with any of the tags")
+            .takes_value(true),
+        Arg::new("snapshot-iterations")
+            .long("snapshot-iterations")
+            .help("Write intermediate embeddings after every iteration, file names get .iterN suffix"),
+        Arg::new("adaptive-iterations")
+            .long("adaptive-iterations")
+            .help("Stop iterating connected components once changes of their embeddings between iterations stabilize (mean difference below the tolerance, e.g. 0.001). --number-of-iterations stays the limit")
+            .takes_value(true),
+        Arg::new("early-stop")
+            .long("early-stop")
+            .help("Stop iterating once 95% of the embeddings change less than the threshold (1 - cosine similarity to two iterations before, e.g. 0.001). --number-of-iterations stays the limit")
+            .takes_value(true),
+        Arg::new("kernel")
+            .long("kernel")
+            .possible_values(&["markov", "symmetric"])
+            .default_value("markov")
+            .help("Graph normalization used for propagation: markov (D^-1 A) or symmetric (D^-1/2 A D^-1/2)")
+            .takes_value(true),
+        Arg::new("alpha")
+            .long("alpha")
+            .default_value("0")
+            .help("Weight of the initial vectors mixed back after every iteration (PageRank-style damping), from [0, 1)")
+            .takes_value(true),
+        Arg::new("column-weights")
+            .long("column-weights")
+            .help("Weights scaling edges of the columns, e.g. \"user:1.0 brand:0.3\". Edge between two columns weighs the product of their weights, missing columns weigh 1. Self-loops aren't weighted, so with --self-loops add the weight sets how much entities take from their neighbors against keeping their own embeddings")
+            .takes_value(true),
+        Arg::new("self-loops")
+            .long("self-loops")
+            .possible_values(&["keep", "drop", "add"])
+            .default_value("keep")
+            .help("Self-loops (entity paired with itself, e.g. in reflexive columns): keep them as they come from the input, drop them or add one of --self-loop-weight to every entity instead")
+            .takes_value(true),
+        Arg::new("self-loop-weight")
+            .long("self-loop-weight")
+            .default_value("1")
+            .help("Weight of the self-loops added with --self-loops add, not scaled by --column-weights")
+            .takes_value(true),
+        Arg::new("dedupe-edges")
+            .long("dedupe-edges")
+            .help("Count repeated edges once, the first occurrence gives the value of the edge. Pre-aggregated multiplicity of the rows can be given by count:: column instead"),
+        Arg::new("save-vocab")
+            .long("save-vocab")
+            .help("Save the vocabulary (entity mapping with occurrence counts) and the built graph to the file, so repeated runs over the same input can skip reading it with --load-vocab")
+            .takes_value(true),
+        Arg::new("load-vocab")
+            .long("load-vocab")
+            .conflicts_with("save-vocab")
+            .help("Load the vocabulary saved with --save-vocab instead of reading the input, e.g. for dimension and iteration sweeps. Graph options (columns, hashing, pairs, weights, filters) have to be the same")
+            .takes_value(true),
+        Arg::new("mlflow-uri")
+            .long("mlflow-uri")
+            .help("MLflow tracking server (e.g. http://localhost:5000) the run parameters, per-iteration metrics and output files are logged to")
+            .takes_value(true),
+        Arg::new("sample-edges")
+            .long("sample-edges")
+            .help("Fraction of the input rows (hyperedges) uniformly sampled during parsing, e.g. 0.1 for quick experiments on a subgraph. The sample is given by --seed")
+            .takes_value(true),
+        Arg::new("deadline")
+            .long("deadline")
+            .help("Time limit of the run, e.g. 90m or 2h. Propagation stops early if the next iteration wouldn't finish in time, embeddings get <output>.meta.json with the number of iterations done and partial flag")
+            .takes_value(true),
+        Arg::new("fast-parse")
+            .long("fast-parse")
+            .help("Parse TSV input as raw bytes: memchr field splitting and hashing without UTF-8 validation, lines it can't handle go through the regular parser"),
+        Arg::new("malformed-rows")
+            .long("malformed-rows")
+            .possible_values(&["skip", "log", "fail"])
+            .default_value("log")
+            .help("Handling of malformed input rows (wrong number of columns, invalid UTF-8, JSON, weights, counts, timestamps or edge types): skipped silently, skipped with the first --malformed-rows-logged of them logged with line numbers, or the run fails on the first one. The number of skipped rows is reported after reading")
+            .takes_value(true),
+        Arg::new("malformed-rows-logged")
+            .long("malformed-rows-logged")
+            .default_value("100")
+            .help("Number of malformed rows logged with --malformed-rows log")
+            .takes_value(true),
+        Arg::new("propagation-partitioning")
+            .long("propagation-partitioning")
+            .possible_values(&["auto", "dimensions", "entities"])
+            .default_value("auto")
+            .help("Split of the propagation between threads: blocks of dimensions of all entities, ranges of entities with all dimensions or auto (dimensions if there are at least as many as threads)")
+            .takes_value(true),
+        Arg::new("dry-run")
+            .long("dry-run")
+            .help("Only read the input and print graph statistics: entities per column, hyperedges, edges, estimated matrix entries and projected memory of the run"),
+        Arg::new("profile")
+            .long("profile")
+            .help("Record wall time, heap allocation peak and throughput of every stage (parse, hash, build, each iteration, persist), print them as JSON report and write it to <relation>__profile.json in the output directory"),
+        Arg::new("no-input-checksums")
+            .long("no-input-checksums")
+            .help("Don't checksum the inputs for the output manifests, which reads every local input once more. Their checksums are null then"),
+        Arg::new("no-similarity-summary")
+            .long("no-similarity-summary")
+            .help("Don't log the histograms of cosine similarities of linked and unlinked entity pairs after training, which samples pairs of every graph"),
+        Arg::new("continue-from")
+            .long("continue-from")
+            .help("Output directory of a previous run with the same output template (e.g. numpy outputs). Its embeddings replace the initial vectors of known entities and --number-of-iterations more iterations are done. Use the same output directory with --overwrite to update the embeddings in place")
+            .takes_value(true),
+        Arg::new("precision")
+            .long("precision")
+            .possible_values(&["f32", "f64"])
+            .default_value("f32")
+            .help("Type of the values embeddings are propagated in. f64 accumulates less rounding error over many iterations, with twice the memory of the matrices; embeddings are written as f32")
+            .takes_value(true),
+        Arg::new("align-to")
+            .long("align-to")
+            .help("Output directory of a previous run with the same output template. New embeddings are rotated (orthogonal Procrustes on the shared entities) onto its embeddings, so embedding spaces of the runs stay comparable")
+            .takes_value(true),
+        Arg::new("force")
+            .long("force")
+            .help("Clear the lock of the output location (<relation>__run.lock) left by another run. Use it only if that run is gone, e.g. killed"),
+        Arg::new("storage-class")
+            .long("storage-class")
+            .possible_values(&["standard", "intelligent-tiering", "standard-ia", "one-zone-ia", "glacier-ir"])
+            .default_value("standard")
+            .help("S3 storage class of the outputs (and other objects) written by the run")
+            .takes_value(true),
+        Arg::new("estimate-cost")
+            .long("estimate-cost")
+            .help("Dry run (see --dry-run) with the cost estimate of S3 requests, transfer and storage for the outputs stored in the storage class of --storage-class. S3 list prices of us-east-1 are used unless overridden with --cost-prices"),
+        Arg::new("cost-prices")
+            .long("cost-prices")
+            .requires("estimate-cost")
+            .help("Prices (USD) of the cost estimate overriding the list prices, e.g. for another region: storage=0.0125 put=0.01 get=0.001 transfer=0.02. Storage is per GB-month, requests per 1000, transfer of S3 input and output per GB (zero by default, for runs in the region of the bucket)")
+            .takes_value(true),
+    ]
+    .into_iter()
+    .map(|arg| {
+        if arg.is_takes_value_set() {
+            arg
+        } else {
+            // flags can be turned off, e.g. --overwrite=false for overwrite: true of the
+            // run configuration file
+            arg.takes_value(true)
+                .min_values(0)
+                .max_values(1)
+                .require_equals(true)
+                .default_missing_value("true")
+                .possible_values(&["true", "false"])
+        }
+    })
+    .collect()
+}
+
+/// Build pipeline configuration from parsed args.
+fn parse_configuration(matches: &ArgMatches) -> Configuration {
     info!("Reading args...");
+    let matches = RunArgs::new(matches);
 
     let input: Vec<String> = {
         let named_arg = matches.value_of("input");
@@ -165,19 +1216,25 @@ fn main() {
         Some(type_name) => match type_name {
             "tsv" => configuration::FileType::Tsv,
             "json" => configuration::FileType::Json,
+            "graphml" => configuration::FileType::GraphMl,
+            "snap" => configuration::FileType::Snap,
             _ => panic!("Invalid file type {}", type_name),
         },
         None => configuration::FileType::Tsv,
     };
     let output_dir = matches.value_of("output-dir").map(|s| s.to_string());
-    // try to create output directory for files with embeddings
-    if let Some(output_dir) = output_dir.as_ref() {
+    // try to create output directory for files with embeddings, S3 prefixes don't need it
+    if let Some(output_dir) = output_dir.as_ref().filter(|dir| !dir.starts_with("s3://")) {
         fs::create_dir_all(output_dir).expect("Can't create output directory");
     }
-    let dimension: u16 = matches.value_of("dimension").unwrap().parse().unwrap();
+    let dimension: u16 = matches
+        .value_of("dimension")
+        .expect("Missing embedding dimension")
+        .parse()
+        .unwrap();
     let max_iter: u8 = matches
         .value_of("number-of-iterations")
-        .unwrap()
+        .expect("Missing number of iterations")
         .parse()
         .unwrap();
     let seed: Option<i64> = matches.value_of("seed").map(|s| s.parse().unwrap());
@@ -200,7 +1257,11 @@ fn main() {
         value == 1
     };
     let columns = {
-        let cols_str = matches.value_of("columns").unwrap();
+        let cols_str = match matches.value_of("columns") {
+            Some(cols_str) => cols_str,
+            None if file_type.is_graph() => configuration::GRAPH_COLUMNS,
+            None => panic!("Missing columns"),
+        };
         let cols_str_separated: Vec<&str> = cols_str.split(' ').collect();
         match configuration::extract_fields(cols_str_separated) {
             Ok(cols) => match configuration::validate_fields(cols) {
@@ -210,18 +1271,487 @@ fn main() {
             Err(msg) => panic!("Parsing problem. Message: {}", msg),
         }
     };
+    if file_type.is_graph() && columns.len() != 2 {
+        panic!(
+            "Graph inputs have two columns, the source and target of the edges, got {}",
+            columns.len()
+        );
+    }
+    let columns = match matches.value_of("output-columns") {
+        None => columns,
+        Some(output_columns) => {
+            match configuration::apply_output_columns(output_columns, columns) {
+                Ok(columns) => columns,
+                Err(msg) => panic!("Invalid output columns. Message: {}", msg),
+            }
+        }
+    };
 
-    let output_format = match matches.value_of("output-format").unwrap() {
+    let delta_mode = match matches.value_of("delta-mode").unwrap() {
+        "append" => configuration::DeltaMode::Append,
+        "overwrite" => configuration::DeltaMode::Overwrite,
+        _ => panic!("unsupported delta mode"),
+    };
+    let duckdb_layout = match matches.value_of("duckdb-layout").unwrap() {
+        "columns" => configuration::DuckDbLayout::Columns,
+        "array" => configuration::DuckDbLayout::Array,
+        _ => panic!("unsupported duckdb layout"),
+    };
+    let feast_push_url = match matches.value_of("feast-push-url") {
+        Some(url) if cfg!(feature = "feast") => Some(url.to_string()),
+        Some(_) => panic!("pushing feast output requires cleora built with the feast feature"),
+        None => None,
+    };
+    let output_format_names = matches.values_of("output-format").unwrap();
+    for (i, name) in output_format_names.iter().enumerate() {
+        if output_format_names[..i].contains(name) {
+            panic!("Output format {} is given more than once", name);
+        }
+    }
+    let parse_output_format = |name: &str| match name {
         "textfile" => OutputFormat::TextFile,
+        "parquet" => OutputFormat::Parquet,
         "numpy" => OutputFormat::Numpy,
+        "raw" => OutputFormat::Raw,
+        "compact" => OutputFormat::Compact,
+        "npz" => OutputFormat::Npz,
+        "onnx" => OutputFormat::Onnx,
+        "projector" => OutputFormat::Projector(
+            matches
+                .value_of("projector-points")
+                .map(|points| points.parse().unwrap()),
+        ),
+        "delta" if cfg!(feature = "delta") => OutputFormat::Delta(delta_mode),
+        "delta" => panic!("delta output requires cleora built with the delta feature"),
+        "duckdb" if cfg!(feature = "duckdb") => OutputFormat::DuckDb(duckdb_layout),
+        "duckdb" => panic!("duckdb output requires cleora built with the duckdb feature"),
+        "sqlite" if cfg!(feature = "sqlite") => OutputFormat::Sqlite,
+        "sqlite" => panic!("sqlite output requires cleora built with the sqlite feature"),
+        "flight" if cfg!(feature = "flight") => {
+            OutputFormat::Flight(matches.value_of("flight-address").unwrap().to_string())
+        }
+        "flight" => panic!("flight output requires cleora built with the flight feature"),
+        "feast" => OutputFormat::Feast(feast_push_url.clone()),
         _ => panic!("unsupported output format"),
     };
+    let mut extra_output_formats: Vec<OutputFormat> = output_format_names
+        .into_iter()
+        .map(parse_output_format)
+        .collect();
+    let output_format = extra_output_formats.remove(0);
+
+    let output_metadata = matches.value_of("output-metadata").unwrap();
+    let metadata_columns = match configuration::extract_metadata_columns(output_metadata) {
+        Ok(metadata_columns) => metadata_columns,
+        Err(msg) => panic!("Invalid output metadata. Message: {}", msg),
+    };
+    let produce_entity_occurrence_count = metadata_columns.contains(&MetadataColumn::OccurCount);
+    let output_datetime = metadata_columns.contains(&MetadataColumn::Datetime);
+
+    let entities_format = match matches.value_of("entities-format").unwrap() {
+        "json" => EntitiesFormat::Json,
+        "lines" => EntitiesFormat::Lines,
+        "binary" => EntitiesFormat::Binary,
+        _ => panic!("unsupported entities format"),
+    };
+
+    let write_queue_size: usize = matches.value_of("write-queue").unwrap().parse().unwrap();
+    let limits = ResourceLimits::detect();
+    if let Some(cpus) = limits.cpus {
+        info!("CPU limit of the container: {}", cpus);
+    }
+    if let Some(memory) = limits.memory {
+        info!("Memory limit of the container: {} MiB", memory >> 20);
+    }
+    let chunk_size: usize = match matches.value_of("chunk-size") {
+        Some(chunk_size) => chunk_size.parse().unwrap(),
+        None => limits.chunk_size(dimension, write_queue_size),
+    };
+    let threads: usize = match matches.value_of("threads") {
+        Some(threads) => threads.parse().unwrap(),
+        None => limits.threads(),
+    };
+    if threads == 0 {
+        panic!("Number of threads must be positive");
+    }
+    info!("Using {} threads, chunk size {}", threads, chunk_size);
+    // the pool is used from the first parsed input on
+    resources::init_thread_pool(threads);
+
+    let init_method = match configuration::extract_init_method(matches.value_of("init").unwrap()) {
+        Ok(init_method) => init_method,
+        Err(msg) => panic!("Invalid init method. Message: {}", msg),
+    };
+
+    let entity_mapping_format =
+        matches
+            .value_of("entity-mapping-format")
+            .map(|format| match format {
+                "tsv" => configuration::EntityMappingFormat::Tsv,
+                "parquet" => configuration::EntityMappingFormat::Parquet,
+                "dict" => configuration::EntityMappingFormat::Dictionary,
+                _ => panic!("unsupported entity mapping format"),
+            });
+
+    let fail_on_collision = matches.is_present("fail-on-collision");
+    let collision_budget: Option<usize> = matches
+        .value_of("collision-budget")
+        .map(|budget| budget.parse().unwrap());
+    let overwrite = matches.is_present("overwrite");
+
+    let postprocess = match matches.values_of("postprocess") {
+        None => vec![],
+        Some(values) => values
+            .into_iter()
+            .map(|step| match configuration::extract_postprocess_step(step) {
+                Ok(step) => step,
+                Err(msg) => panic!("Invalid postprocessing step. Message: {}", msg),
+            })
+            .collect(),
+    };
 
+    let postprocess_stats = match matches.value_of("postprocess-stats").unwrap() {
+        "per-type" => StatsScope::PerEntityType,
+        "shared" => StatsScope::Shared,
+        _ => panic!("unsupported postprocess stats scope"),
+    };
+
+    let normalize = match matches.value_of("normalize").unwrap() {
+        "none" => Normalization::None,
+        "l2" => Normalization::L2,
+        "zscore" => Normalization::ZScore,
+        _ => panic!("unsupported normalization"),
+    };
+    let reduce_dimension: Option<u16> = matches
+        .value_of("reduce-dimension")
+        .map(|reduced| reduced.parse().unwrap());
+    if let Some(reduced) = reduce_dimension {
+        if reduced == 0 || reduced >= dimension {
+            panic!(
+                "Reduced dimension has to be between 1 and the embedding dimension {}, got: {}",
+                dimension, reduced
+            );
+        }
+    }
 
-    let chunk_size: usize = matches.value_of("chunk-size").unwrap().parse().unwrap();
+    let knn_graph = matches.value_of("knn-graph").map(|k| {
+        let k = match k.parse::<usize>() {
+            Ok(k) if k > 0 => k,
+            _ => panic!(
+                "Invalid k-NN graph. Message: k has to be a positive number, got: {}",
+                k
+            ),
+        };
+        let method = match matches.value_of("knn-method").unwrap() {
+            "auto" => KnnMethod::Auto,
+            "exact" => KnnMethod::Exact,
+            "approximate" => KnnMethod::Approximate,
+            _ => panic!("unsupported k-NN method"),
+        };
+        KnnGraph { k, method }
+    });
+
+    let hash_function = match matches.value_of("hash").unwrap() {
+        "xxhash64" => HashFunction::XxHash64,
+        "fxhash" => HashFunction::FxHash,
+        "identity" => HashFunction::Identity,
+        _ => panic!("unsupported hash function"),
+    };
+    let hash_bits: u32 = matches.value_of("hash-bits").unwrap().parse().unwrap();
+    if hash_bits == 32 && hash_function == HashFunction::Identity {
+        panic!("32-bit hashes can't be used with identity hashing");
+    }
+
+    let output_template = matches.value_of("output-template").map(|template| {
+        match configuration::validate_output_template(template) {
+            Ok(template) => template,
+            Err(msg) => panic!("Invalid output template. Message: {}", msg),
+        }
+    });
+
+    let sort_output = matches.value_of("sort-output").map(|order| {
+        match configuration::extract_sort_output(order) {
+            Ok(order) => order,
+            Err(msg) => panic!("Invalid output order. Message: {}", msg),
+        }
+    });
+
+    let memory_budget = matches.value_of("memory-budget").map(|size| {
+        match configuration::extract_memory_size(size) {
+            Ok(size) => size,
+            Err(msg) => panic!("Invalid memory budget. Message: {}", msg),
+        }
+    });
+
+    let on_budget_exceeded = match matches.value_of("on-budget-exceeded").unwrap() {
+        "abort" => BudgetPolicy::Abort,
+        "warn" => BudgetPolicy::Warn,
+        _ => panic!("unsupported budget policy"),
+    };
+
+    let auto_pairs = matches.is_present("auto-pairs");
+    let excluded_pairs = match matches.values_of("exclude-pair") {
+        None => vec![],
+        Some(values) => values
+            .into_iter()
+            .map(
+                |pair| match configuration::extract_column_pair(pair, &columns) {
+                    Ok(pair) => pair,
+                    Err(msg) => panic!("Invalid excluded pair. Message: {}", msg),
+                },
+            )
+            .collect(),
+    };
+    let relations = matches.value_of("relations").map(|relations| {
+        match configuration::extract_relations(relations, &columns) {
+            Ok(relations) => relations,
+            Err(msg) => panic!("Invalid relations. Message: {}", msg),
+        }
+    });
+
+    let min_entity_count: u32 = matches
+        .value_of("min-entity-count")
+        .unwrap()
+        .parse()
+        .unwrap();
 
-    let config = Configuration {
-        produce_entity_occurrence_count: true,
+    let max_entities = match matches.values_of("max-entities") {
+        None => vec![],
+        Some(values) => values
+            .into_iter()
+            .map(
+                |limit| match configuration::extract_max_entities(limit, &columns) {
+                    Ok(limit) => limit,
+                    Err(msg) => panic!("Invalid entity limit. Message: {}", msg),
+                },
+            )
+            .collect(),
+    };
+    let unknown_bucket = matches.is_present("unknown-bucket");
+    let entity_tags: Vec<(String, String)> = match matches.values_of("entity-tags") {
+        None => vec![],
+        Some(values) => values
+            .into_iter()
+            .map(
+                |value| match configuration::extract_entity_tags(value, &columns) {
+                    Ok(entity_tags) => entity_tags,
+                    Err(msg) => panic!("Invalid entity tags. Message: {}", msg),
+                },
+            )
+            .collect(),
+    };
+    if let Some(column) = columns
+        .iter()
+        .find(|c| c.tags && !entity_tags.iter().any(|(_, tags)| *tags == c.name))
+    {
+        panic!(
+            "Tags column {} isn't attached to any column, see --entity-tags",
+            column.name
+        );
+    }
+    let output_tags: Vec<String> = matches
+        .values_of("output-tag")
+        .map(|values| values.into_iter().map(String::from).collect())
+        .unwrap_or_default();
+    if unknown_bucket && hash_function == HashFunction::Identity {
+        panic!("Unknown bucket can't be used with identity hashing");
+    }
+    let snapshot_iterations = matches.is_present("snapshot-iterations");
+    let convergence_tolerance: Option<f32> = matches
+        .value_of("adaptive-iterations")
+        .map(|t| t.parse().unwrap());
+    let early_stop: Option<f32> = matches
+        .value_of("early-stop")
+        .map(|threshold| threshold.parse().unwrap());
+    if let Some(threshold) = early_stop {
+        if threshold <= 0f32 {
+            panic!(
+                "Early stop threshold has to be a positive number, got: {}",
+                threshold
+            );
+        }
+    }
+    let kernel = match matches.value_of("kernel").unwrap() {
+        "markov" => PropagationKernel::Markov,
+        "symmetric" => PropagationKernel::Symmetric,
+        kernel => panic!("unsupported propagation kernel {}", kernel),
+    };
+    let alpha: f32 = matches.value_of("alpha").unwrap().parse().unwrap();
+    if !(0f32..1f32).contains(&alpha) {
+        panic!("Alpha must be from [0, 1) range, got: {}", alpha);
+    }
+    let field_delimiter =
+        match configuration::extract_delimiter(matches.value_of("field-delimiter").unwrap()) {
+            Ok(delimiter) => delimiter,
+            Err(msg) => panic!("Invalid field delimiter. Message: {}", msg),
+        };
+    let value_delimiters = match matches.value_of("value-delimiters") {
+        None => vec![],
+        Some(delimiters) => match configuration::extract_value_delimiters(delimiters, &columns) {
+            Ok(delimiters) => delimiters,
+            Err(msg) => panic!("Invalid value delimiters. Message: {}", msg),
+        },
+    };
+    if let Some((name, _)) = value_delimiters
+        .iter()
+        .find(|(_, delimiter)| *delimiter == field_delimiter)
+    {
+        panic!("Value delimiter of column {} is the field delimiter", name);
+    }
+    let aliases = matches.value_of("aliases").map(|path| path.to_string());
+    let column_weights = match matches.value_of("column-weights") {
+        None => vec![],
+        Some(weights) => match configuration::extract_column_weights(weights, &columns) {
+            Ok(weights) => weights,
+            Err(msg) => panic!("Invalid column weights. Message: {}", msg),
+        },
+    };
+    let self_loops = match matches.value_of("self-loops").unwrap() {
+        "keep" => SelfLoops::Keep,
+        "drop" => SelfLoops::Drop,
+        "add" => {
+            let weight: f32 = matches
+                .value_of("self-loop-weight")
+                .unwrap()
+                .parse()
+                .unwrap();
+            if !(weight.is_finite() && weight > 0f32) {
+                panic!("Self-loop weight must be positive, got: {}", weight);
+            }
+            SelfLoops::Add(weight)
+        }
+        self_loops => panic!("unsupported self-loops handling {}", self_loops),
+    };
+    if !column_weights.is_empty() && !matches!(self_loops, SelfLoops::Add(_)) {
+        warn!("Column weights count only against self-loops, use them with --self-loops add");
+    }
+    let dedupe_edges = matches.is_present("dedupe-edges");
+    let save_vocab = matches.value_of("save-vocab").map(|path| path.to_string());
+    let load_vocab = matches.value_of("load-vocab").map(|path| path.to_string());
+    let mlflow_uri = match matches.value_of("mlflow-uri") {
+        Some(uri) if cfg!(feature = "mlflow") => Some(uri.trim_end_matches('/').to_string()),
+        Some(_) => panic!("mlflow logging requires cleora built with the mlflow feature"),
+        None => None,
+    };
+    let sample_edges = matches.value_of("sample-edges").map(|fraction| {
+        let fraction: f64 = fraction
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid sample edges fraction: {}", fraction));
+        if !(fraction > 0f64 && fraction <= 1f64) {
+            panic!("Sample edges fraction must be in (0, 1], got: {}", fraction);
+        }
+        fraction
+    });
+    let deadline =
+        matches.value_of("deadline").map(|duration| {
+            match configuration::extract_duration(duration) {
+                Ok(duration) => Instant::now() + duration,
+                Err(msg) => panic!("Invalid deadline. Message: {}", msg),
+            }
+        });
+    let fast_parse = matches.is_present("fast-parse");
+    let malformed_rows =
+        match matches.value_of("malformed-rows").unwrap() {
+            "skip" => MalformedRows::Skip,
+            "log" => {
+                let logged = matches.value_of("malformed-rows-logged").unwrap();
+                MalformedRows::Log(logged.parse().unwrap_or_else(|_| {
+                    panic!("Invalid number of logged malformed rows: {}", logged)
+                }))
+            }
+            "fail" => MalformedRows::Fail,
+            malformed_rows => panic!("unsupported malformed rows handling {}", malformed_rows),
+        };
+    let propagation_partitioning = match matches.value_of("propagation-partitioning").unwrap() {
+        "auto" => PropagationPartitioning::Auto,
+        "dimensions" => PropagationPartitioning::Dimensions,
+        "entities" => PropagationPartitioning::Entities,
+        partitioning => panic!("unsupported propagation partitioning {}", partitioning),
+    };
+    let dry_run = matches.is_present("dry-run") || matches.is_present("estimate-cost");
+    let profile = matches.is_present("profile");
+    let input_checksums = !matches.is_present("no-input-checksums");
+    let similarity_summary = !matches.is_present("no-similarity-summary");
+    let continue_from = matches.value_of("continue-from").map(|dir| dir.to_string());
+    let align_to = matches.value_of("align-to").map(|dir| dir.to_string());
+    let precision = match matches.value_of("precision").unwrap() {
+        "f32" => configuration::Precision::F32,
+        "f64" => configuration::Precision::F64,
+        _ => panic!("unsupported precision"),
+    };
+    let edge_types = match matches.value_of("edge-types") {
+        Some(edge_types) => match configuration::extract_edge_types(edge_types, &columns) {
+            Ok(edge_types) => edge_types,
+            Err(msg) => panic!("Invalid edge types. Message: {}", msg),
+        },
+        None if columns.iter().any(|c| c.edge_type) => {
+            panic!("Edge type column requires --edge-types")
+        }
+        None => vec![],
+    };
+    let edge_type_combination = match matches.value_of("edge-type-combination").unwrap() {
+        "concat" => EdgeTypeCombination::Concat,
+        "merge" => EdgeTypeCombination::Merge,
+        combination => panic!("unsupported edge type combination {}", combination),
+    };
+    let decay = matches.value_of("half-life").map(|half_life| {
+        let half_life = match configuration::extract_duration(half_life) {
+            Ok(half_life) if !half_life.is_zero() => half_life,
+            Ok(_) => panic!("Invalid half-life. Message: Half-life must be positive"),
+            Err(msg) => panic!("Invalid half-life. Message: {}", msg),
+        };
+        let reference = match matches.value_of("decay-reference") {
+            Some(reference) => match configuration::extract_timestamp(reference) {
+                Ok(reference) => reference,
+                Err(msg) => panic!("Invalid decay reference. Message: {}", msg),
+            },
+            None => Utc::now().timestamp(),
+        };
+        TemporalDecay {
+            half_life: half_life.as_secs_f64(),
+            reference,
+        }
+    });
+    let feature_init = matches
+        .value_of("feature-init")
+        .map(|path| path.to_string());
+    let matrix_export = matches
+        .value_of("export-matrix")
+        .map(|format| match format {
+            "mtx" => configuration::MatrixFormat::MatrixMarket,
+            "npz" => configuration::MatrixFormat::Npz,
+            _ => panic!("unsupported matrix format"),
+        });
+    if decay.is_some() != columns.iter().any(|c| c.timestamp) {
+        panic!("Timestamp column and --half-life have to be given together");
+    }
+    if !edge_types.is_empty() {
+        if snapshot_iterations || continue_from.is_some() || align_to.is_some() {
+            panic!("Edge types can't be used with --snapshot-iterations, --continue-from or --align-to");
+        }
+        if edge_type_combination == EdgeTypeCombination::Concat
+            && dimension as usize * edge_types.len() > u16::MAX as usize
+        {
+            panic!("Concatenated embeddings of the edge types are too long");
+        }
+    }
+    let force = matches.is_present("force");
+    let storage_class =
+        match configuration::extract_storage_class(matches.value_of("storage-class").unwrap()) {
+            Ok(storage_class) => storage_class,
+            Err(msg) => panic!("Invalid storage class. Message: {}", msg),
+        };
+    let estimate_cost = matches.is_present("estimate-cost").then(|| {
+        let prices = CostPrices::list_prices(storage_class);
+        let overrides = matches.value_of("cost-prices").unwrap_or("");
+        match configuration::extract_cost_prices(prices, overrides) {
+            Ok(prices) => prices,
+            Err(msg) => panic!("Invalid cost prices. Message: {}", msg),
+        }
+    });
+
+    Configuration {
+        produce_entity_occurrence_count,
         embeddings_dimension: dimension,
         max_number_of_iteration: max_iter,
         seed,
@@ -230,24 +1760,361 @@ fn main() {
         in_memory_embedding_calculation,
         input,
         file_type,
+        field_delimiter,
+        value_delimiters,
+        aliases,
         output_dir,
         output_format,
+        extra_output_formats,
         relation_name: relation_name.to_string(),
         columns,
         chunk_size,
+        init_method,
+        entity_mapping_format,
+        fail_on_collision,
+        collision_budget,
+        postprocess,
+        postprocess_stats,
+        normalize,
+        reduce_dimension,
+        knn_graph,
+        hash_function,
+        hash_bits,
+        overwrite,
+        sort_output,
+        output_template,
+        memory_budget,
+        on_budget_exceeded,
+        auto_pairs,
+        excluded_pairs,
+        relations,
+        edge_types,
+        edge_type_combination,
+        decay,
+        feature_init,
+        matrix_export,
+        align_to,
+        precision,
+        dedupe_edges,
+        sample_edges,
+        save_vocab,
+        load_vocab,
+        mlflow_uri,
+        min_entity_count,
+        max_entities,
+        unknown_bucket,
+        entity_tags,
+        output_tags,
+        snapshot_iterations,
+        convergence_tolerance,
+        early_stop,
+        kernel,
+        alpha,
+        column_weights,
+        self_loops,
+        deadline,
+        fast_parse,
+        malformed_rows,
+        propagation_partitioning,
+        dry_run,
+        profile,
+        input_checksums,
+        similarity_summary,
+        continue_from,
+        force,
+        storage_class,
+        estimate_cost,
+        entities_format,
+        output_datetime,
+        write_queue_size,
+        threads,
+    }
+}
+
+/// Options of the run configuration file given by `--config` (which is read before the args are
+/// parsed) by their arg ids, empty without the file
+fn read_run_config<I: IntoIterator<Item = String>>(
+    args: I,
+) -> Result<Vec<(String, Vec<String>)>, String> {
+    let mut args = args.into_iter();
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            path = args.next();
+        } else if let Some(value) = arg.strip_prefix("--config=") {
+            path = Some(value.to_string());
+        }
+    }
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(vec![]),
     };
-    dbg!(&config);
+    let pipeline_args = pipeline_args();
+    configuration::read_run_config(&path)?
+        .into_iter()
+        .map(|(name, values)| {
+            // options are named like the long flags (which mostly match arg ids)
+            pipeline_args
+                .iter()
+                .find(|arg| arg.get_id() == name || arg.get_long() == Some(name.as_str()))
+                .filter(|arg| arg.get_id() != "config")
+                .map(|arg| (arg.get_id().to_string(), values))
+                .ok_or_else(|| format!("Unknown option {} in config file {}", name, path))
+        })
+        .collect()
+}
 
-    info!("Starting calculation...");
-    let in_memory_entity_mapping_persistor = InMemoryEntityMappingPersistor::default();
-    let in_memory_entity_mapping_persistor = Arc::new(in_memory_entity_mapping_persistor);
+/// Parses the args with the options of the run configuration file which aren't given on the
+/// command line. They're added to the args, so their values are checked the same way.
+fn parse_args(
+    command: Command,
+    mut args: Vec<OsString>,
+    run_config: &[(String, Vec<String>)],
+) -> clap::Result<ArgMatches> {
+    let matches = command.clone().try_get_matches_from(&args)?;
+    let pipeline_matches = match matches.subcommand() {
+        // subcommands without pipeline args (e.g. reading embeddings) don't train, the run
+        // configuration isn't theirs
+        Some((name, _)) if !has_pipeline_args(&command, name) => return Ok(matches),
+        Some((_, sub_matches)) => sub_matches,
+        None => &matches,
+    };
+    let pipeline_args = pipeline_args();
+    let mut file_args = Vec::new();
+    let mut last_args = Vec::new();
+    for (id, values) in run_config {
+        if pipeline_matches.occurrences_of(id) > 0 {
+            continue;
+        }
+        let arg = pipeline_args
+            .iter()
+            .find(|arg| arg.get_id() == id)
+            .expect("Arg of the run config option");
+        match arg.get_long() {
+            // chunk size, after all other args
+            None if arg.is_last_set() => last_args.extend(values.iter().cloned()),
+            // input files
+            None => file_args.extend(values.iter().cloned()),
+            Some(long) if values.len() == 1 || arg.is_multiple_occurrences_set() => {
+                file_args.extend(values.iter().map(|value| format!("--{}={}", long, value)))
+            }
+            Some(long) => {
+                file_args.push(format!("--{}", long));
+                file_args.extend(values.iter().cloned());
+            }
+        }
+    }
+    if file_args.is_empty() && last_args.is_empty() {
+        return Ok(matches);
+    }
+    // options go before the -- of the command line, the chunk size after it
+    let tail = match args.iter().position(|arg| arg == "--") {
+        Some(separator) => args.split_off(separator),
+        None if last_args.is_empty() => vec![],
+        None => vec![OsString::from("--")],
+    };
+    command.try_get_matches_from(
+        args.into_iter()
+            .chain(file_args.into_iter().map(OsString::from))
+            .chain(tail)
+            .chain(last_args.into_iter().map(OsString::from)),
+    )
+}
 
-    let sparse_matrices = build_graphs(&config, in_memory_entity_mapping_persistor.clone());
-    info!(
-        "Finished Sparse Matrices calculation in {} sec",
-        now.elapsed().as_secs()
-    );
+/// Whether the subcommand builds the graph, so it takes the pipeline args (see `pipeline_args`)
+fn has_pipeline_args(command: &Command, subcommand: &str) -> bool {
+    command.find_subcommand(subcommand).map_or(false, |sub| {
+        sub.get_arguments().any(|arg| arg.get_id() == "config")
+    })
+}
 
-    train(config, in_memory_entity_mapping_persistor, sparse_matrices);
-    info!("Finished in {} sec", now.elapsed().as_secs());
+/// Parsed pipeline args (see `parse_args`)
+struct RunArgs<'a> {
+    matches: &'a ArgMatches,
+}
+
+impl<'a> RunArgs<'a> {
+    fn new(matches: &'a ArgMatches) -> Self {
+        RunArgs { matches }
+    }
+
+    fn value_of(&self, id: &str) -> Option<&str> {
+        self.matches.value_of(id)
+    }
+
+    fn values_of(&self, id: &str) -> Option<Vec<&str>> {
+        self.matches.values_of(id).map(|values| values.collect())
+    }
+
+    /// Flags turned off (e.g. `--overwrite=false`) aren't present
+    fn is_present(&self, id: &str) -> bool {
+        self.matches.is_present(id) && self.matches.value_of(id) != Some("false")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse_args, pipeline_args, read_run_config, RunArgs};
+    use clap::{Arg, ArgMatches, Command};
+
+    fn parse(run_config: &[(String, Vec<String>)], args: &[&str]) -> clap::Result<ArgMatches> {
+        let command = Command::new("cleora").args(pipeline_args());
+        let args = std::iter::once(&"cleora")
+            .chain(args)
+            .map(|arg| arg.into())
+            .collect();
+        parse_args(command, args, run_config)
+    }
+
+    #[test]
+    fn merge_run_config_into_args() {
+        let path = std::env::temp_dir().join("cleora_run_config.json");
+        std::fs::write(
+            &path,
+            r#"{"dimension": 8, "type": "json", "output_format": ["textfile", "numpy"], "overwrite": true}"#,
+        )
+        .unwrap();
+        let config = format!("--config={}", path.to_str().unwrap());
+        let run_config = read_run_config(vec![String::from("-n"), config.clone()]).unwrap();
+        let mut ids: Vec<&str> = run_config.iter().map(|(id, _)| id.as_str()).collect();
+        ids.sort_unstable();
+        assert_eq!(
+            vec!["dimension", "file-type", "output-format", "overwrite"],
+            ids
+        );
+
+        // options of the file which are not on the command line are added
+        let matches = parse(&run_config, &[&config, "-n", "2"]).unwrap();
+        let args = RunArgs::new(&matches);
+        assert_eq!(Some("8"), args.value_of("dimension"));
+        assert_eq!(Some("json"), args.value_of("file-type"));
+        assert_eq!(
+            Some(vec!["textfile", "numpy"]),
+            args.values_of("output-format")
+        );
+        assert!(args.is_present("overwrite"));
+        assert!(!args.is_present("force"));
+
+        // the command line overrides them, flags are turned off by false
+        let matches = parse(
+            &run_config,
+            &[
+                &config,
+                "-d",
+                "16",
+                "-f",
+                "parquet",
+                "--overwrite=false",
+                "--force",
+            ],
+        )
+        .unwrap();
+        let args = RunArgs::new(&matches);
+        assert_eq!(Some("16"), args.value_of("dimension"));
+        assert_eq!(Some(vec!["parquet"]), args.values_of("output-format"));
+        assert!(!args.is_present("overwrite"));
+        assert!(args.is_present("force"));
+
+        // values of the file are validated
+        std::fs::write(&path, r#"{"type": "csv"}"#).unwrap();
+        let run_config = read_run_config(vec![config.clone()]).unwrap();
+        assert!(parse(&run_config, &[&config]).is_err());
+        std::fs::write(&path, r#"{"dimensions": 8}"#).unwrap();
+        assert!(read_run_config(vec![config]).is_err());
+        std::fs::remove_file(path).unwrap();
+
+        assert!(read_run_config(vec![String::from("-d"), String::from("8")])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn run_config_of_training_only() {
+        let command = || {
+            Command::new("cleora")
+                .subcommand_negates_reqs(true)
+                .args(pipeline_args())
+                .subcommand(Command::new("debug-dump").args(pipeline_args()))
+                .subcommand(Command::new("query").arg(Arg::new("k").long("k").takes_value(true)))
+        };
+        let run_config = vec![(String::from("dimension"), vec![String::from("8")])];
+        let args = |args: &[&str]| args.iter().map(|arg| arg.into()).collect();
+
+        // subcommands building the graph get the options of the file
+        let matches = parse_args(
+            command(),
+            args(&[
+                "cleora",
+                "debug-dump",
+                "--config=run.json",
+                "-c",
+                "a b",
+                "a.tsv",
+            ]),
+            &run_config,
+        )
+        .unwrap();
+        let (_, sub_matches) = matches.subcommand().unwrap();
+        assert_eq!(Some("8"), RunArgs::new(sub_matches).value_of("dimension"));
+
+        // subcommands reading embeddings don't
+        let matches = parse_args(
+            command(),
+            args(&["cleora", "query", "--k", "5"]),
+            &run_config,
+        )
+        .unwrap();
+        let (name, sub_matches) = matches.subcommand().unwrap();
+        assert_eq!("query", name);
+        assert_eq!(Some("5"), sub_matches.value_of("k"));
+    }
+
+    #[test]
+    fn chunk_size_after_inputs() {
+        let matches = parse(&[], &["-c", "a b", "-n", "2", "-d", "8", "a.tsv", "b.tsv"]).unwrap();
+        assert_eq!(
+            Some(vec!["a.tsv", "b.tsv"]),
+            RunArgs::new(&matches).values_of("inputs")
+        );
+        assert_eq!(None, matches.value_of("chunk-size"));
+
+        let matches = parse(
+            &[],
+            &[
+                "-c", "a b", "-n", "2", "-d", "8", "a.tsv", "b.tsv", "--", "1000",
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            Some(vec!["a.tsv", "b.tsv"]),
+            RunArgs::new(&matches).values_of("inputs")
+        );
+        assert_eq!(Some("1000"), matches.value_of("chunk-size"));
+
+        // the chunk size of the run config goes after the options and inputs
+        let run_config = vec![
+            (String::from("chunk-size"), vec![String::from("500")]),
+            (String::from("inputs"), vec![String::from("c.tsv")]),
+            (String::from("file-type"), vec![String::from("json")]),
+        ];
+        for args in [
+            vec!["-c", "a b", "-n", "2", "-d", "8", "a.tsv"],
+            vec!["-c", "a b", "-n", "2", "-d", "8", "a.tsv", "--"],
+        ] {
+            let matches = parse(&run_config, &args).unwrap();
+            let args = RunArgs::new(&matches);
+            assert_eq!(Some(vec!["a.tsv"]), args.values_of("inputs"));
+            assert_eq!(Some("json"), args.value_of("file-type"));
+            assert_eq!(Some("500"), args.value_of("chunk-size"));
+        }
+        let matches = parse(
+            &run_config,
+            &["-c", "a b", "-n", "2", "-d", "8", "-t", "tsv", "--", "1000"],
+        )
+        .unwrap();
+        let args = RunArgs::new(&matches);
+        assert_eq!(Some(vec!["c.tsv"]), args.values_of("inputs"));
+        assert_eq!(Some("tsv"), args.value_of("file-type"));
+        assert_eq!(Some("1000"), args.value_of("chunk-size"));
+    }
 }
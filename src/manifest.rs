@@ -0,0 +1,261 @@
+use crate::configuration::Configuration;
+use crate::error::CleoraError;
+use crate::io::create_output;
+use crate::pipeline::TrainedOutput;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+use std::fs;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io;
+use std::io::Write;
+use twox_hash::XxHash64;
+
+/// Version of the manifest schema. Fields are only added within a version, it's raised when a
+/// field is renamed, removed or changes its meaning.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Commit of the sources the binary was built from (see `build.rs`), builds outside of a git
+/// checkout have none
+const GIT_COMMIT: Option<&str> = option_env!("CLEORA_GIT_COMMIT");
+
+/// Manifest describing the output, written next to it
+pub fn manifest_file_name(ofp: &str) -> String {
+    format!("{}.manifest.json", ofp)
+}
+
+/// Input file of the run with its size and XXH64 checksum (seed 0, hex). Objects on S3 aren't
+/// downloaded again for the checksum, they have neither. Local files aren't read without the
+/// checksum, only their size is known.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputFile {
+    pub path: String,
+    pub bytes: Option<u64>,
+    pub xxh64: Option<String>,
+}
+
+impl InputFile {
+    pub fn read(path: &str, checksum: bool) -> Result<Self, CleoraError> {
+        if path.starts_with("s3://") {
+            return Ok(Self {
+                path: path.to_string(),
+                bytes: None,
+                xxh64: None,
+            });
+        }
+        if !checksum {
+            let metadata = fs::metadata(path).map_err(|e| CleoraError::read_file(path, e))?;
+            return Ok(Self {
+                path: path.to_string(),
+                bytes: Some(metadata.len()),
+                xxh64: None,
+            });
+        }
+        let mut file = File::open(path).map_err(|e| CleoraError::read_file(path, e))?;
+        let mut checksum = Checksum {
+            hasher: XxHash64::with_seed(0),
+            bytes: 0,
+        };
+        io::copy(&mut file, &mut checksum).map_err(|e| CleoraError::read_file(path, e))?;
+        Ok(Self {
+            path: path.to_string(),
+            bytes: Some(checksum.bytes),
+            xxh64: Some(format!("{:016x}", checksum.hasher.finish())),
+        })
+    }
+}
+
+/// Hashes the bytes written to it
+struct Checksum {
+    hasher: XxHash64,
+    bytes: u64,
+}
+
+impl Write for Checksum {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.write(buf);
+        self.bytes += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The run the outputs were trained in, shared by their manifests
+#[derive(Debug, Clone)]
+pub struct RunInfo {
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub inputs: Vec<InputFile>,
+    /// Entity mapping file written by the run
+    pub entity_mapping: Option<String>,
+}
+
+/// Manifest of the trained output: its files and shape, the options it was trained with, the
+/// inputs and the version of cleora, so the output can be told apart from others and reproduced.
+pub fn manifest(config: &Configuration, output: &TrainedOutput, run: &RunInfo) -> Value {
+    let columns: Vec<String> = config.columns.iter().map(|column| column.spec()).collect();
+    let seconds = (run.finished - run.started).num_milliseconds() as f64 / 1000f64;
+    json!({
+        "manifest_version": MANIFEST_VERSION,
+        "cleora": {
+            "version": env!("CARGO_PKG_VERSION"),
+            "git_commit": GIT_COMMIT,
+        },
+        "output": {
+            "name": output.name,
+            "files": output.files,
+            "format": output.formats[0],
+            "formats": output.formats,
+            "dimension": output.dimension,
+            "entities": output.entities,
+            "entity_mapping": run.entity_mapping,
+        },
+        "config": {
+            "relation_name": config.relation_name,
+            "columns": columns.join(" "),
+            "dimension": config.embeddings_dimension,
+            "number_of_iterations": config.max_number_of_iteration,
+            "seed": config.seed,
+            "init_method": format!("{:?}", config.init_method),
+            "kernel": format!("{:?}", config.kernel),
+            "alpha": config.alpha,
+            "hash": format!("{:?}", config.hash_function),
+            "hash_bits": config.hash_bits,
+            "edge_types": config.edge_types,
+            "normalize": format!("{:?}", config.normalize),
+            "postprocess": format!("{:?}", config.postprocess),
+            "reduce_dimension": config.reduce_dimension,
+            "early_stop": config.early_stop,
+            "knn_graph": config.knn_graph.map(|graph| graph.k),
+            "precision": format!("{:?}", config.precision),
+        },
+        "training": {
+            "iterations": output.summary.iterations,
+            "partial": output.summary.partial,
+            "interrupted": output.summary.interrupted,
+            "row_changes": output
+                .summary
+                .iteration_stats
+                .iter()
+                .map(|stats| {
+                    json!({
+                        "mean": stats.mean_row_change,
+                        "p95": stats.p95_row_change,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        },
+        "run": {
+            "started": run.started.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "finished": run.finished.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "seconds": seconds,
+        },
+        "inputs": run
+            .inputs
+            .iter()
+            .map(|input| {
+                json!({
+                    "path": input.path,
+                    "bytes": input.bytes,
+                    "xxh64": input.xxh64,
+                })
+            })
+            .collect::<Vec<_>>(),
+    })
+}
+
+pub fn write_manifest(filename: &str, manifest: &Value) -> Result<(), CleoraError> {
+    let mut file = create_output(filename)?;
+    serde_json::to_writer_pretty(&mut file, manifest)
+        .map_err(io::Error::from)
+        .and_then(|_| file.flush())
+        .map_err(|e| CleoraError::write_file(filename, e))?;
+    file.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::Configuration;
+    use crate::embedding::{IterationStats, TrainingSummary};
+    use crate::manifest::{manifest, InputFile, RunInfo, MANIFEST_VERSION};
+    use crate::pipeline::TrainedOutput;
+    use chrono::{DateTime, Duration, Utc};
+    use std::hash::Hasher;
+    use twox_hash::XxHash64;
+
+    #[test]
+    fn checksum_input_files() {
+        let path = std::env::temp_dir().join("cleora_manifest_input.tsv");
+        std::fs::write(&path, b"a\tb\n").unwrap();
+        let input = InputFile::read(path.to_str().unwrap(), true).unwrap();
+        let unread = InputFile::read(path.to_str().unwrap(), false).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Some(4), input.bytes);
+        let mut hasher = XxHash64::with_seed(0);
+        hasher.write(b"a\tb\n");
+        assert_eq!(Some(format!("{:016x}", hasher.finish())), input.xxh64);
+        assert_eq!((Some(4), None), (unread.bytes, unread.xxh64));
+
+        let s3 = InputFile::read("s3://bucket/input.tsv", true).unwrap();
+        assert_eq!((None, None), (s3.bytes, s3.xxh64));
+        assert!(InputFile::read("missing_manifest_input.tsv", true).is_err());
+    }
+
+    #[test]
+    fn describe_output() {
+        let config = Configuration {
+            seed: Some(7),
+            ..Configuration::default(String::from("input.tsv"), vec![])
+        };
+        let output = TrainedOutput {
+            name: String::from("emb__users__items.out"),
+            formats: vec!["textfile", "numpy"],
+            files: vec![String::from("emb__users__items.out")],
+            dimension: 128,
+            entities: 42,
+            summary: TrainingSummary {
+                iterations: 3,
+                partial: true,
+                interrupted: false,
+                iteration_stats: vec![IterationStats {
+                    seconds: 1.0,
+                    mean_row_change: Some(0.25),
+                    p95_row_change: Some(0.5),
+                    converged_components: None,
+                }],
+            },
+        };
+        let started = DateTime::parse_from_rfc3339("2024-01-02T03:04:05Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let run = RunInfo {
+            started,
+            finished: started + Duration::milliseconds(2500),
+            inputs: vec![InputFile {
+                path: String::from("input.tsv"),
+                bytes: Some(10),
+                xxh64: Some(String::from("00000000000000ff")),
+            }],
+            entity_mapping: None,
+        };
+
+        let manifest = manifest(&config, &output, &run);
+        assert_eq!(MANIFEST_VERSION, manifest["manifest_version"]);
+        assert_eq!(env!("CARGO_PKG_VERSION"), manifest["cleora"]["version"]);
+        assert_eq!("textfile", manifest["output"]["format"]);
+        assert_eq!("numpy", manifest["output"]["formats"][1]);
+        assert_eq!(42, manifest["output"]["entities"]);
+        assert_eq!(128, manifest["output"]["dimension"]);
+        assert_eq!(7, manifest["config"]["seed"]);
+        assert_eq!(3, manifest["training"]["iterations"]);
+        assert_eq!(true, manifest["training"]["partial"]);
+        assert_eq!(0.5, manifest["training"]["row_changes"][0]["p95"]);
+        assert_eq!("2024-01-02T03:04:05.000Z", manifest["run"]["started"]);
+        assert_eq!(2.5, manifest["run"]["seconds"]);
+        assert_eq!("00000000000000ff", manifest["inputs"][0]["xxh64"]);
+        assert!(manifest["output"]["entity_mapping"].is_null());
+    }
+}
@@ -0,0 +1,284 @@
+use crate::configuration::MatrixFormat;
+use crate::error::CleoraError;
+use crate::io::{commit_file, create_partial_file};
+use crate::persistence::embedding::{npy_header, unicode_width, write_unicode};
+use crate::persistence::entity::{EntityMappingPersistor, InMemoryEntityMappingPersistor};
+use crate::sparse_matrix::{SparseMatrix, SparseMatrixReader};
+use log::info;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// Base name of the matrix exported next to the embedding file `ofp`, matrices of the edge types
+/// are told apart by their labels
+pub fn matrix_file_name(ofp: &str, edge_type: Option<&str>) -> String {
+    match edge_type {
+        Some(label) => format!("{}.{}.matrix", ofp, label),
+        None => format!("{}.matrix", ofp),
+    }
+}
+
+/// Files written for the matrix with given base name
+pub fn matrix_output_files(format: MatrixFormat, base: &str) -> Vec<String> {
+    match format {
+        MatrixFormat::MatrixMarket => vec![format!("{}.mtx", base), format!("{}.entities", base)],
+        MatrixFormat::Npz => vec![format!("{}.npz", base)],
+    }
+}
+
+/// Writes the sparse matrix with the values it's propagated with (normalized by the kernel). Row
+/// and column `i` of the matrix is the entity `i`, entities of transient columns (without the
+/// mapping) are given by their hashes.
+pub fn export_matrix(
+    sparse_matrix: &SparseMatrix,
+    entity_mapping_persistor: &InMemoryEntityMappingPersistor,
+    format: MatrixFormat,
+    base: &str,
+) -> Result<(), CleoraError> {
+    let entities: Vec<Arc<str>> = sparse_matrix
+        .iter_hashes()
+        .map(|hash| {
+            entity_mapping_persistor
+                .get_entity(hash.value)
+                .unwrap_or_else(|| hash.value.to_string().into())
+        })
+        .collect();
+    match format {
+        MatrixFormat::MatrixMarket => write_matrix_market(sparse_matrix, &entities, base)?,
+        MatrixFormat::Npz => write_csr_npz(sparse_matrix, &entities, base)?,
+    }
+    info!(
+        "Sparse matrix {} exported to {}",
+        sparse_matrix.get_id(),
+        base
+    );
+    Ok(())
+}
+
+/// Writes `.mtx` coordinate file (1-based entries) and `.entities` file with entity per line
+fn write_matrix_market(
+    sparse_matrix: &SparseMatrix,
+    entities: &[Arc<str>],
+    base: &str,
+) -> Result<(), CleoraError> {
+    let filename = format!("{}.mtx", base);
+    let mut writer = BufWriter::new(create_partial_file(&filename)?);
+    let mut write = || -> Result<(), std::io::Error> {
+        writeln!(writer, "%%MatrixMarket matrix coordinate real general")?;
+        writeln!(
+            writer,
+            "% sparse matrix {}, entities of the rows and columns in {}.entities",
+            sparse_matrix.get_id(),
+            base
+        )?;
+        writeln!(
+            writer,
+            "{} {} {}",
+            entities.len(),
+            entities.len(),
+            sparse_matrix.get_number_of_entries()
+        )?;
+        for entry in sparse_matrix.iter_entries() {
+            writeln!(
+                writer,
+                "{} {} {}",
+                entry.row + 1,
+                entry.col + 1,
+                entry.value
+            )?;
+        }
+        writer.flush()
+    };
+    write().map_err(|e| CleoraError::write_file(&filename, e))?;
+    drop(writer);
+    commit_file(&filename)?;
+
+    let filename = format!("{}.entities", base);
+    let mut writer = BufWriter::new(create_partial_file(&filename)?);
+    entities
+        .iter()
+        .try_for_each(|entity| writeln!(writer, "{}", entity))
+        .and_then(|_| writer.flush())
+        .map_err(|e| CleoraError::write_file(&filename, e))?;
+    drop(writer);
+    commit_file(&filename)
+}
+
+/// Writes `.npz` archive of `scipy.sparse.save_npz` layout (`data`, `indices`, `indptr`, `format`
+/// and `shape` arrays of CSR matrix) with `entities` array added
+fn write_csr_npz(
+    sparse_matrix: &SparseMatrix,
+    entities: &[Arc<str>],
+    base: &str,
+) -> Result<(), CleoraError> {
+    let filename = format!("{}.npz", base);
+    let rows = entities.len();
+
+    // entries are in the order they were found, counted and placed by rows
+    let mut indptr = vec![0i64; rows + 1];
+    for entry in sparse_matrix.iter_entries() {
+        indptr[entry.row as usize + 1] += 1;
+    }
+    for row in 0..rows {
+        indptr[row + 1] += indptr[row];
+    }
+    let entries = sparse_matrix.get_number_of_entries() as usize;
+    let mut next: Vec<i64> = indptr[..rows].to_vec();
+    let mut indices = vec![0i64; entries];
+    let mut data = vec![0f32; entries];
+    for entry in sparse_matrix.iter_entries() {
+        let position = next[entry.row as usize] as usize;
+        indices[position] = entry.col as i64;
+        data[position] = entry.value;
+        next[entry.row as usize] += 1;
+    }
+
+    let mut archive = NpzWriter::create(&filename)?;
+    archive.put_array(
+        "data",
+        "<f4",
+        &[entries],
+        &to_le_bytes(&data, f32::to_le_bytes),
+    )?;
+    archive.put_array(
+        "indices",
+        "<i8",
+        &[entries],
+        &to_le_bytes(&indices, i64::to_le_bytes),
+    )?;
+    archive.put_array(
+        "indptr",
+        "<i8",
+        &[rows + 1],
+        &to_le_bytes(&indptr, i64::to_le_bytes),
+    )?;
+    archive.put_array("format", "|S3", &[], b"csr")?;
+    archive.put_array(
+        "shape",
+        "<i8",
+        &[2],
+        &to_le_bytes(&[rows as i64, rows as i64], i64::to_le_bytes),
+    )?;
+    let width = unicode_width(entities);
+    archive.start_array("entities", &format!("<U{}", width), &[rows])?;
+    write_unicode(&mut archive.archive, entities, width)
+        .map_err(|e| CleoraError::write_file(&filename, e))?;
+    archive.finish()
+}
+
+pub(crate) fn to_le_bytes<T: Copy, const N: usize>(
+    values: &[T],
+    to_bytes: fn(T) -> [u8; N],
+) -> Vec<u8> {
+    values.iter().flat_map(|&v| to_bytes(v)).collect()
+}
+
+/// Arrays of the `.npz` archive written one after another
+pub(crate) struct NpzWriter<'a> {
+    filename: &'a str,
+    archive: ZipWriter<BufWriter<File>>,
+}
+
+impl<'a> NpzWriter<'a> {
+    /// Starts the archive as a partial file, it's moved to `filename` by `finish`
+    pub(crate) fn create(filename: &'a str) -> Result<Self, CleoraError> {
+        Ok(Self {
+            filename,
+            archive: ZipWriter::new(BufWriter::new(create_partial_file(filename)?)),
+        })
+    }
+
+    fn start_array(&mut self, name: &str, descr: &str, shape: &[usize]) -> Result<(), CleoraError> {
+        let options = FileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .large_file(true);
+        self.archive
+            .start_file(format!("{}.npy", name), options)
+            .map_err(|e| CleoraError::npy(self.filename, e))?;
+        self.archive
+            .write_all(&npy_header(descr, false, shape))
+            .map_err(|e| CleoraError::write_file(self.filename, e))
+    }
+
+    pub(crate) fn put_array(
+        &mut self,
+        name: &str,
+        descr: &str,
+        shape: &[usize],
+        values: &[u8],
+    ) -> Result<(), CleoraError> {
+        self.start_array(name, descr, shape)?;
+        self.archive
+            .write_all(values)
+            .map_err(|e| CleoraError::write_file(self.filename, e))
+    }
+
+    pub(crate) fn finish(mut self) -> Result<(), CleoraError> {
+        self.archive
+            .finish()
+            .map_err(|e| CleoraError::npy(self.filename, e))?
+            .flush()
+            .map_err(|e| CleoraError::write_file(self.filename, e))?;
+        commit_file(self.filename)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::MatrixFormat;
+    use crate::matrix_export::{export_matrix, matrix_file_name, matrix_output_files};
+    use crate::persistence::entity::{EntityMappingPersistor, InMemoryEntityMappingPersistor};
+    use crate::sparse_matrix::SparseMatrix;
+    use std::convert::TryInto;
+    use std::io::Read;
+
+    #[test]
+    fn export_matrix_market_and_csr() {
+        let mut sm = SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+        sm.handle_pair(&[1, 10, 20]);
+        sm.handle_pair(&[1, 10, 30]);
+        sm.finish();
+        let entity_mapping = InMemoryEntityMappingPersistor::default();
+        entity_mapping.put_data(10, String::from("u1"));
+        entity_mapping.put_data(20, String::from("i1"));
+
+        let ofp = std::env::temp_dir().join("cleora_matrix_export.out");
+        let base = matrix_file_name(ofp.to_str().unwrap(), None);
+        export_matrix(&sm, &entity_mapping, MatrixFormat::MatrixMarket, &base).unwrap();
+        let files = matrix_output_files(MatrixFormat::MatrixMarket, &base);
+        let mtx = std::fs::read_to_string(&files[0]).unwrap();
+        let lines: Vec<&str> = mtx.lines().collect();
+        assert_eq!("%%MatrixMarket matrix coordinate real general", lines[0]);
+        assert_eq!("3 3 4", lines[2]);
+        // u1 has two items, both entries of its row are halved
+        assert!(lines.contains(&"1 2 0.5"));
+        assert!(lines.contains(&"3 1 1"));
+        // the transient entity is given by its hash
+        let entities = std::fs::read_to_string(&files[1]).unwrap();
+        assert_eq!("u1\ni1\n30\n", entities);
+        for file in files {
+            std::fs::remove_file(file).unwrap();
+        }
+
+        export_matrix(&sm, &entity_mapping, MatrixFormat::Npz, &base).unwrap();
+        let files = matrix_output_files(MatrixFormat::Npz, &base);
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(&files[0]).unwrap()).unwrap();
+        for name in ["data", "indices", "indptr", "format", "shape", "entities"] {
+            assert!(archive.by_name(&format!("{}.npy", name)).is_ok());
+        }
+        let mut indptr = Vec::new();
+        archive
+            .by_name("indptr.npy")
+            .unwrap()
+            .read_to_end(&mut indptr)
+            .unwrap();
+        let indptr: Vec<i64> = indptr[indptr.len() - 32..]
+            .chunks_exact(8)
+            .map(|b| i64::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(vec![0, 2, 3, 4], indptr);
+        std::fs::remove_file(&files[0]).unwrap();
+    }
+}
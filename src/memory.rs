@@ -0,0 +1,181 @@
+use crate::configuration::{Configuration, FileType, MalformedRows};
+use crate::edge_types::EmbeddingCollector;
+use crate::embedding::{calculate_embeddings, PriorEmbeddings, TrainingSummary};
+use crate::entity::{
+    create_entity_mapping_persistor, entity_limits, parse_tsv_line, Delimiters, EntityProcessor,
+    SMALL_VECTOR_SIZE,
+};
+use crate::error::CleoraError;
+use crate::persistence::entity::InMemoryEntityMappingPersistor;
+use crate::sparse_matrix::{configured_sparse_matrices, select_column_pairs};
+use log::warn;
+use smallvec::SmallVec;
+use std::sync::Arc;
+
+/// Embeddings of a pair of columns trained in memory
+#[derive(Debug)]
+pub struct MemoryEmbeddings {
+    /// Columns of the pair, e.g. `users__items`
+    pub name: String,
+    pub embeddings: EmbeddingCollector,
+    pub summary: TrainingSummary,
+}
+
+/// Trains embeddings of the TSV rows without reading or writing any file, so small graphs can be
+/// embedded where there is no file system (the WASM build). Rows are parsed like the lines of
+/// `--type tsv` input, inputs and outputs of the configuration aren't used and neither are the
+/// options reading files (aliases, prior embeddings, vocabularies). Edge types and JSON rows
+/// aren't supported.
+pub fn embed_rows<'a>(
+    config: Configuration,
+    rows: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<MemoryEmbeddings>, CleoraError> {
+    if !matches!(config.file_type, FileType::Tsv) {
+        return Err(CleoraError::memory("only TSV rows are parsed"));
+    }
+    if !config.edge_types.is_empty() {
+        return Err(CleoraError::memory("edge types aren't supported"));
+    }
+
+    let persistor = Arc::new(create_entity_mapping_persistor(&config));
+    let mut sparse_matrices = configured_sparse_matrices(&config);
+    for sparse_matrix in sparse_matrices.iter_mut() {
+        sparse_matrix.configure(&config);
+    }
+    let rows: Vec<&str> = rows.into_iter().collect();
+    let limits = entity_limits(&config, &persistor);
+    if config.min_entity_count > 1 || !limits.is_empty() {
+        // the entities are counted first, so the matrices are built without the ones left out
+        read_rows(&config, &persistor, &rows, |hashes| {
+            for sparse_matrix in sparse_matrices.iter_mut() {
+                sparse_matrix.count_pair(&hashes);
+            }
+        })?;
+        for sparse_matrix in sparse_matrices.iter_mut() {
+            sparse_matrix.set_limits(config.min_entity_count, &limits);
+        }
+    }
+    read_rows(&config, &persistor, &rows, |hashes| {
+        for sparse_matrix in sparse_matrices.iter_mut() {
+            sparse_matrix.handle_pair(&hashes);
+        }
+    })?;
+
+    for sparse_matrix in sparse_matrices.iter_mut() {
+        sparse_matrix.finish();
+    }
+    if config.auto_pairs {
+        sparse_matrices = select_column_pairs(sparse_matrices);
+    }
+
+    let config = Arc::new(config);
+    sparse_matrices
+        .into_iter()
+        .map(|sparse_matrix| {
+            let name = format!("{}__{}", sparse_matrix.col_a_name, sparse_matrix.col_b_name);
+            let mut embeddings = EmbeddingCollector::default();
+            let summary = calculate_embeddings(
+                config.clone(),
+                Arc::new(sparse_matrix),
+                persistor.clone(),
+                &mut embeddings,
+                None,
+                PriorEmbeddings::default(),
+            )?;
+            Ok(MemoryEmbeddings {
+                name,
+                embeddings,
+                summary,
+            })
+        })
+        .collect()
+}
+
+/// Parses and hashes the rows, passing the combinations to the handler. Malformed rows are
+/// handled by the configured policy.
+fn read_rows<F>(
+    config: &Configuration,
+    persistor: &Arc<InMemoryEntityMappingPersistor>,
+    rows: &[&str],
+    hashes_handler: F,
+) -> Result<(), CleoraError>
+where
+    F: FnMut(SmallVec<[u64; SMALL_VECTOR_SIZE]>),
+{
+    let delimiters = Delimiters::new(config);
+    let config_col_num = config.columns.len();
+    let mut entity_processor = EntityProcessor::new(config, persistor.clone(), hashes_handler);
+    let mut malformed = 0usize;
+    for (line_number, line) in rows.iter().enumerate() {
+        let row = parse_tsv_line(line, &delimiters);
+        let processed = if row.len() == config_col_num {
+            entity_processor
+                .process_row(&row)
+                .map_err(|err| err.to_string())
+        } else {
+            Err(format!(
+                "Wrong number of columns (expected: {}, provided: {}) in line [{}]",
+                config_col_num,
+                row.len(),
+                line.trim_end()
+            ))
+        };
+        if let Err(reason) = processed {
+            malformed += 1;
+            match config.malformed_rows {
+                MalformedRows::Skip => {}
+                MalformedRows::Log(logged) => {
+                    if malformed <= logged {
+                        warn!("Malformed row {}, skipped. {}", line_number + 1, reason);
+                    }
+                }
+                MalformedRows::Fail => {
+                    return Err(CleoraError::memory(format!(
+                        "malformed row {}. {}",
+                        line_number + 1,
+                        reason
+                    )))
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::{extract_fields, Configuration, FileType, MalformedRows};
+    use crate::memory::embed_rows;
+
+    fn config(malformed_rows: MalformedRows) -> Configuration {
+        let columns = extract_fields(vec!["transient::users", "complex::items"]).unwrap();
+        Configuration {
+            embeddings_dimension: 8,
+            max_number_of_iteration: 2,
+            malformed_rows,
+            ..Configuration::default(String::new(), columns)
+        }
+    }
+
+    #[test]
+    fn embed_rows_in_memory() {
+        let rows = ["u1\ti1 i2", "u2\ti2 i3", "u3\ttoo\tmany", "u1\ti3"];
+        let outputs = embed_rows(config(MalformedRows::Skip), rows.iter().copied()).unwrap();
+        assert_eq!(1, outputs.len());
+        let output = &outputs[0];
+        assert_eq!("users__items", output.name);
+        assert_eq!(2, output.summary.iterations);
+        assert_eq!(8, output.embeddings.dimension);
+        // transient users aren't embedded
+        assert_eq!(3, output.embeddings.len());
+        assert_eq!(24, output.embeddings.values.len());
+        assert!(output.embeddings.values.iter().all(|v| v.is_finite()));
+
+        assert!(embed_rows(config(MalformedRows::Fail), rows.iter().copied()).is_err());
+        let json = Configuration {
+            file_type: FileType::Json,
+            ..config(MalformedRows::Skip)
+        };
+        assert!(embed_rows(json, rows.iter().copied()).is_err());
+    }
+}
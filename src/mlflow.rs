@@ -0,0 +1,355 @@
+use crate::configuration::Configuration;
+use crate::error::CleoraError;
+use crate::io::create_output;
+use crate::pipeline::TrainedOutput;
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// REST API of the tracking server
+const MLFLOW_API: &str = "api/2.0/mlflow";
+
+/// Artifacts API of the tracking server proxying the artifact store (`mlflow-artifacts:` roots)
+const MLFLOW_ARTIFACTS_API: &str = "api/2.0/mlflow-artifacts/artifacts";
+
+/// Limits of the logged values, longer ones are truncated and batches split
+const MAX_BATCH_METRICS: usize = 1000;
+const MAX_PARAM_LENGTH: usize = 500;
+const MAX_TAG_LENGTH: usize = 5000;
+
+/// Run of an MLflow tracking server the embedding run is logged to, so it shows up with the model
+/// training runs. Parameters of the configuration are logged when it starts, per-iteration
+/// metrics of every output (`<output>/iteration_seconds`, `<output>/mean_row_change`,
+/// `<output>/p95_row_change` and with the convergence tolerance `<output>/converged_components`,
+/// stepped by the iteration) and
+/// the output files as artifacts when it ends. The run is created in the experiment of
+/// `MLFLOW_EXPERIMENT_ID`, the default experiment without it.
+pub struct MlflowRun {
+    uri: String,
+    agent: ureq::Agent,
+    run_id: String,
+    artifact_uri: String,
+}
+
+impl MlflowRun {
+    /// Creates the run and logs parameters of the configuration. Fails if the server can't be
+    /// reached, so a misconfigured run doesn't train without tracking.
+    pub fn start(uri: &str, config: &Configuration) -> Result<Self, CleoraError> {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(60))
+            .build();
+        let experiment_id =
+            std::env::var("MLFLOW_EXPERIMENT_ID").unwrap_or_else(|_| String::from("0"));
+        let response = post(
+            &agent,
+            uri,
+            "runs/create",
+            json!({
+                "experiment_id": experiment_id,
+                "run_name": config.relation_name,
+                "start_time": now_millis(),
+                "tags": [
+                    {"key": "mlflow.source.name", "value": "cleora"},
+                    {"key": "cleora.version", "value": env!("CARGO_PKG_VERSION")},
+                ],
+            }),
+        )?;
+        let info = &response["run"]["info"];
+        let run_id = info["run_id"]
+            .as_str()
+            .ok_or_else(|| CleoraError::mlflow(uri, "no run id in the created run"))?
+            .to_string();
+        let run = MlflowRun {
+            uri: uri.to_string(),
+            agent,
+            run_id,
+            artifact_uri: info["artifact_uri"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+        };
+        run.log_batch(json!({ "params": params(config) }))?;
+        info!(
+            "Logging to MLflow run {} of experiment {} on {}",
+            run.run_id, experiment_id, uri
+        );
+        Ok(run)
+    }
+
+    /// Logs the trained outputs and finishes the run, as failed if the training failed. The
+    /// embeddings are written already, so logging errors are only warned about.
+    pub fn end(&self, result: &Result<Vec<TrainedOutput>, CleoraError>) {
+        let status = match result {
+            Ok(outputs) => {
+                if let Err(err) = self.log_outputs(outputs) {
+                    warn!("Can't log the outputs to MLflow. {}", err);
+                }
+                "FINISHED"
+            }
+            Err(_) => "FAILED",
+        };
+        let update = self.post(
+            "runs/update",
+            json!({
+                "run_id": self.run_id,
+                "status": status,
+                "end_time": now_millis(),
+            }),
+        );
+        if let Err(err) = update {
+            warn!("Can't finish the MLflow run. {}", err);
+        }
+    }
+
+    fn log_outputs(&self, outputs: &[TrainedOutput]) -> Result<(), CleoraError> {
+        for metrics in metrics(outputs, now_millis()).chunks(MAX_BATCH_METRICS) {
+            self.log_batch(json!({ "metrics": metrics }))?;
+        }
+        let files: Vec<&str> = outputs
+            .iter()
+            .flat_map(|output| output.files.iter().map(|file| file.as_str()))
+            .collect();
+        // files which aren't uploaded (on S3, Delta table directories) can be found by the tag
+        let tag = truncate(&files.join("\n"), MAX_TAG_LENGTH);
+        self.log_batch(json!({ "tags": [{"key": "cleora.outputs", "value": tag}] }))?;
+        for file in files {
+            if Path::new(file).is_file() {
+                self.upload_artifact(file)?;
+            } else {
+                warn!(
+                    "Output {} isn't a local file, it isn't logged as artifact",
+                    file
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Uploads the file to the root of the run artifacts, through the tracking server or directly
+    /// to the artifact store (local or S3) it gave
+    fn upload_artifact(&self, file: &str) -> Result<(), CleoraError> {
+        let name = Path::new(file)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| file.to_string());
+        let source = File::open(file).map_err(|e| CleoraError::read_file(file, e))?;
+        match artifact_root(&self.artifact_uri) {
+            ArtifactRoot::Proxied(path) => {
+                let url = format!("{}/{}/{}/{}", self.uri, MLFLOW_ARTIFACTS_API, path, name);
+                let length = source
+                    .metadata()
+                    .map_err(|e| CleoraError::read_file(file, e))?
+                    .len();
+                // sent with the length, the server doesn't have to support chunked uploads
+                self.agent
+                    .put(&url)
+                    .set("Content-Length", &length.to_string())
+                    .send(source)
+                    .map_err(|e| request_error(&self.uri, e))?;
+            }
+            ArtifactRoot::Location(root) => {
+                let target = format!("{}/{}", root, name);
+                let mut output = create_output(&target)?;
+                io::copy(&mut &source, &mut output)
+                    .map_err(|e| CleoraError::write_file(&target, e))?;
+                output.commit()?;
+            }
+        }
+        info!("Output {} logged as MLflow artifact {}", file, name);
+        Ok(())
+    }
+
+    fn log_batch(&self, mut batch: Value) -> Result<(), CleoraError> {
+        batch["run_id"] = json!(self.run_id);
+        self.post("runs/log-batch", batch).map(|_| ())
+    }
+
+    fn post(&self, endpoint: &str, body: Value) -> Result<Value, CleoraError> {
+        post(&self.agent, &self.uri, endpoint, body)
+    }
+}
+
+fn post(agent: &ureq::Agent, uri: &str, endpoint: &str, body: Value) -> Result<Value, CleoraError> {
+    agent
+        .post(&format!("{}/{}/{}", uri, MLFLOW_API, endpoint))
+        .send_json(body)
+        .map_err(|e| request_error(uri, e))?
+        .into_json()
+        .map_err(|e| CleoraError::mlflow(uri, e))
+}
+
+/// Error of the request, with the message the server responded with
+fn request_error(uri: &str, error: ureq::Error) -> CleoraError {
+    match error {
+        ureq::Error::Status(code, response) => {
+            let message = response.into_string().unwrap_or_default();
+            CleoraError::mlflow(uri, format!("status {} {}", code, message))
+        }
+        error => CleoraError::mlflow(uri, error),
+    }
+}
+
+/// Parameters of the run, the options the embeddings are compared by
+fn params(config: &Configuration) -> Vec<Value> {
+    let columns: Vec<String> = config.columns.iter().map(|column| column.spec()).collect();
+    let params = [
+        ("columns", columns.join(" ")),
+        ("dimension", config.embeddings_dimension.to_string()),
+        ("iterations", config.max_number_of_iteration.to_string()),
+        ("relation_name", config.relation_name.clone()),
+        ("input", config.input.join(" ")),
+        ("output_format", format!("{:?}", config.output_format)),
+    ];
+    params
+        .iter()
+        .map(|(key, value)| json!({"key": key, "value": truncate(value, MAX_PARAM_LENGTH)}))
+        .collect()
+}
+
+/// Per-iteration metrics of the outputs, keyed by the output file name
+fn metrics(outputs: &[TrainedOutput], timestamp: u64) -> Vec<Value> {
+    let mut metrics = Vec::new();
+    for output in outputs {
+        let name = metric_name(&output.name);
+        let mut push = |key: &str, value: f64, step: usize| {
+            metrics.push(json!({
+                "key": format!("{}/{}", name, key),
+                "value": value,
+                "timestamp": timestamp,
+                "step": step,
+            }))
+        };
+        push("iterations", output.summary.iterations as f64, 0);
+        for (i, stats) in output.summary.iteration_stats.iter().enumerate() {
+            push("iteration_seconds", stats.seconds, i + 1);
+            if let Some(change) = stats.mean_row_change {
+                push("mean_row_change", change, i + 1);
+            }
+            if let Some(change) = stats.p95_row_change {
+                push("p95_row_change", change, i + 1);
+            }
+            if let Some(converged) = stats.converged_components {
+                push("converged_components", converged as f64, i + 1);
+            }
+        }
+    }
+    metrics
+}
+
+/// File name of the output with the characters MLflow doesn't allow in metric names replaced
+fn metric_name(ofp: &str) -> String {
+    ofp.rsplit('/')
+        .next()
+        .unwrap_or(ofp)
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '.' | ' ' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+enum ArtifactRoot {
+    /// Path of the artifacts served by the tracking server
+    Proxied(String),
+    /// Local directory or S3 location of the artifacts
+    Location(String),
+}
+
+fn artifact_root(artifact_uri: &str) -> ArtifactRoot {
+    match artifact_uri.strip_prefix("mlflow-artifacts:") {
+        // `mlflow-artifacts://host:port/path` names the server, the path is all it's needed for
+        Some(path) if path.starts_with("//") => {
+            let path = path[2..].split_once('/').map(|(_, p)| p).unwrap_or("");
+            ArtifactRoot::Proxied(path.trim_matches('/').to_string())
+        }
+        Some(path) => ArtifactRoot::Proxied(path.trim_matches('/').to_string()),
+        None => {
+            let location = artifact_uri.strip_prefix("file://").unwrap_or(artifact_uri);
+            ArtifactRoot::Location(location.trim_end_matches('/').to_string())
+        }
+    }
+}
+
+fn truncate(value: &str, max_length: usize) -> String {
+    value.chars().take(max_length).collect()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::embedding::{IterationStats, TrainingSummary};
+    use crate::mlflow::{artifact_root, metric_name, metrics, ArtifactRoot};
+    use crate::pipeline::TrainedOutput;
+
+    #[test]
+    fn log_iteration_metrics() {
+        let stats = |seconds, converged: Option<usize>| IterationStats {
+            seconds,
+            mean_row_change: converged.map(|_| 0.25),
+            p95_row_change: converged.map(|_| 0.5),
+            converged_components: converged,
+        };
+        let outputs = vec![TrainedOutput {
+            name: String::from("out/emb__a__b:2022"),
+            formats: vec!["textfile"],
+            files: vec![],
+            dimension: 2,
+            entities: 0,
+            summary: TrainingSummary {
+                iterations: 2,
+                partial: false,
+                interrupted: false,
+                iteration_stats: vec![stats(1.5, None), stats(0.5, Some(3))],
+            },
+        }];
+        let metrics = metrics(&outputs, 1000);
+        let keys: Vec<(&str, u64)> = metrics
+            .iter()
+            .map(|m| (m["key"].as_str().unwrap(), m["step"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("emb__a__b_2022/iterations", 0),
+                ("emb__a__b_2022/iteration_seconds", 1),
+                ("emb__a__b_2022/iteration_seconds", 2),
+                ("emb__a__b_2022/mean_row_change", 2),
+                ("emb__a__b_2022/p95_row_change", 2),
+                ("emb__a__b_2022/converged_components", 2),
+            ],
+            keys
+        );
+        assert_eq!(3.0, metrics[5]["value"].as_f64().unwrap());
+        assert_eq!("emb", metric_name("emb"));
+    }
+
+    #[test]
+    fn resolve_artifact_root() {
+        let proxied = |uri| match artifact_root(uri) {
+            ArtifactRoot::Proxied(path) => Some(path),
+            ArtifactRoot::Location(_) => None,
+        };
+        assert_eq!(
+            Some(String::from("1/abc/artifacts")),
+            proxied("mlflow-artifacts:/1/abc/artifacts")
+        );
+        assert_eq!(
+            Some(String::from("1/abc/artifacts")),
+            proxied("mlflow-artifacts://tracking:5000/1/abc/artifacts/")
+        );
+        assert_eq!(None, proxied("s3://bucket/1/abc/artifacts"));
+        match artifact_root("file:///mlruns/1/abc/artifacts/") {
+            ArtifactRoot::Location(location) => assert_eq!("/mlruns/1/abc/artifacts", location),
+            ArtifactRoot::Proxied(_) => panic!("local artifact root"),
+        }
+    }
+}
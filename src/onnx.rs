@@ -0,0 +1,443 @@
+use crate::configuration::EntitiesFormat;
+use crate::error::CleoraError;
+use crate::io::{create_output, OutputFile};
+use crate::persistence::embedding::{
+    write_occurences, EmbeddingBatch, EmbeddingPersistor, EntitiesWriter,
+};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Name of the graph input with indices of the entities (int64 `[N]`)
+pub const ONNX_INPUT: &str = "index";
+
+/// Name of the graph output with their embeddings (float `[N, dimension]`)
+pub const ONNX_OUTPUT: &str = "embedding";
+
+/// Name of the initializer holding the embedding matrix
+pub const ONNX_EMBEDDINGS: &str = "embeddings";
+
+/// IR version of the model, supported by onnxruntime 1.6 and newer
+const ONNX_IR_VERSION: u64 = 7;
+
+/// Version of the default operator set of the model
+const ONNX_OPSET_VERSION: u64 = 13;
+
+/// Protobuf messages can't exceed 2GB, bigger matrices are written to the `.onnx.data` file next
+/// to the model (leaving room for the rest of the model)
+const ONNX_INLINE_LIMIT: u64 = (1 << 31) - (1 << 20);
+
+/// `TensorProto.DataType` values
+const ONNX_FLOAT: u64 = 1;
+const ONNX_INT64: u64 = 7;
+
+/// Protobuf wire types
+const WIRE_VARINT: u32 = 0;
+const WIRE_LEN: u32 = 2;
+
+/// Protobuf message encoded field by field, in the order of the calls
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(mut self, field: u32, value: u64) -> Self {
+        put_key(&mut self.0, field, WIRE_VARINT);
+        put_varint(&mut self.0, value);
+        self
+    }
+
+    fn bytes(mut self, field: u32, value: &[u8]) -> Self {
+        put_key(&mut self.0, field, WIRE_LEN);
+        put_varint(&mut self.0, value.len() as u64);
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    fn string(self, field: u32, value: &str) -> Self {
+        self.bytes(field, value.as_bytes())
+    }
+
+    fn message(self, field: u32, value: Message) -> Self {
+        self.bytes(field, &value.0)
+    }
+
+    /// Starts the length-delimited field whose `len` bytes are appended afterwards
+    fn open(mut self, field: u32, len: u64) -> Self {
+        put_key(&mut self.0, field, WIRE_LEN);
+        put_varint(&mut self.0, len);
+        self
+    }
+
+    fn len(&self) -> u64 {
+        self.0.len() as u64
+    }
+}
+
+fn put_key(buf: &mut Vec<u8>, field: u32, wire_type: u32) {
+    put_varint(buf, ((field << 3) | wire_type) as u64);
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Bytes of the key and length of the length-delimited field
+fn prefix_len(field: u32, len: u64) -> u64 {
+    Message::default().open(field, len).len()
+}
+
+/// Dimension of the tensor shape
+enum Dim<'a> {
+    Value(u64),
+    Param(&'a str),
+}
+
+/// `ValueInfoProto` of the tensor input or output
+fn value_info(name: &str, elem_type: u64, dims: &[Dim]) -> Message {
+    let shape = dims.iter().fold(Message::default(), |shape, dim| {
+        let dim = match dim {
+            Dim::Value(value) => Message::default().varint(1, *value),
+            Dim::Param(param) => Message::default().string(2, param),
+        };
+        shape.message(1, dim)
+    });
+    let tensor_type = Message::default().varint(1, elem_type).message(2, shape);
+    Message::default()
+        .string(1, name)
+        .message(2, Message::default().message(1, tensor_type))
+}
+
+/// Model of the lookup up to the raw data of the embedding matrix (`TensorProto.raw_data`), which
+/// is written right after it, or the whole model if the matrix is in the external data file.
+fn model_prefix(entity_count: u64, dimension: u64, external_data: Option<&str>) -> Vec<u8> {
+    let gather = Message::default()
+        .string(1, ONNX_EMBEDDINGS)
+        .string(1, ONNX_INPUT)
+        .string(2, ONNX_OUTPUT)
+        .string(3, "lookup")
+        .string(4, "Gather")
+        .message(
+            5,
+            // AttributeProto of type INT
+            Message::default()
+                .string(1, "axis")
+                .varint(3, 0)
+                .varint(20, 2),
+        );
+    let graph = Message::default()
+        .message(1, gather)
+        .string(2, "cleora")
+        .message(11, value_info(ONNX_INPUT, ONNX_INT64, &[Dim::Param("N")]))
+        .message(
+            12,
+            value_info(
+                ONNX_OUTPUT,
+                ONNX_FLOAT,
+                &[Dim::Param("N"), Dim::Value(dimension)],
+            ),
+        );
+    let data_len = entity_count * dimension * 4;
+    let tensor = Message::default()
+        .varint(1, entity_count)
+        .varint(1, dimension)
+        .varint(2, ONNX_FLOAT)
+        .string(8, ONNX_EMBEDDINGS);
+    let (tensor, streamed_len) = match external_data {
+        Some(location) => {
+            let entry = |key: &str, value: &str| Message::default().string(1, key).string(2, value);
+            let tensor = tensor
+                .message(13, entry("location", location))
+                .message(13, entry("offset", "0"))
+                .message(13, entry("length", &data_len.to_string()))
+                .varint(14, 1);
+            (tensor, 0)
+        }
+        None => (tensor.open(9, data_len), data_len),
+    };
+
+    let tensor_len = tensor.len() + streamed_len;
+    let graph_len = graph.len() + prefix_len(5, tensor_len) + tensor_len;
+    let model = Message::default()
+        .varint(1, ONNX_IR_VERSION)
+        .string(2, "cleora")
+        .string(3, env!("CARGO_PKG_VERSION"))
+        .message(
+            8,
+            Message::default()
+                .string(1, "")
+                .varint(2, ONNX_OPSET_VERSION),
+        )
+        .open(7, graph_len);
+    let mut prefix = model.0;
+    prefix.extend(graph.0);
+    // the initializer is the last field of the graph, so its data ends the file
+    prefix.extend(Message::default().open(5, tensor_len).0);
+    prefix.extend(tensor.0);
+    prefix
+}
+
+/// Writes embeddings as an ONNX model of a single `Gather` over the embedding matrix, so serving
+/// stacks which only run ONNX models can look them up without custom loaders. The model takes
+/// int64 indices of the entities (`index`, `[N]`) and returns their rows (`embedding`,
+/// `[N, dimension]`). Indices are the positions of the entities in the `.entities` sidecar (see
+/// `EntitiesWriter`), occurrences are written next to it as npy. Rows are streamed into the
+/// matrix, rows of transient entities (without the name) are left as zeros at the end like in
+/// numpy output. Matrices bigger than the 2GB limit of protobuf are written to the
+/// `.onnx.data` file next to the model, as ONNX external data.
+pub struct OnnxPersistor {
+    model_file_name: String,
+    model_buf: BufWriter<OutputFile>,
+    data_file_name: String,
+    data_buf: Option<BufWriter<OutputFile>>,
+    entities: EntitiesWriter,
+    occurences: Vec<u32>,
+    occurences_buf: Option<BufWriter<OutputFile>>,
+    inline_limit: u64,
+    entity_count: usize,
+    dimension: usize,
+    rows: usize,
+}
+
+impl OnnxPersistor {
+    pub fn new(
+        filename: String,
+        produce_entity_occurrence_count: bool,
+        entities_format: EntitiesFormat,
+    ) -> Result<Self, CleoraError> {
+        let entities = EntitiesWriter::new(format!("{}.entities", &filename), entities_format)?;
+
+        let occurences_buf = if produce_entity_occurrence_count {
+            Some(BufWriter::new(create_output(&format!(
+                "{}.occurences",
+                &filename
+            ))?))
+        } else {
+            None
+        };
+
+        let model_file_name = format!("{}.onnx", &filename);
+        let model_buf = BufWriter::new(create_output(&model_file_name)?);
+
+        Ok(Self {
+            data_file_name: format!("{}.data", &model_file_name),
+            model_file_name,
+            model_buf,
+            data_buf: None,
+            entities,
+            occurences: vec![],
+            occurences_buf,
+            inline_limit: ONNX_INLINE_LIMIT,
+            entity_count: 0,
+            dimension: 0,
+            rows: 0,
+        })
+    }
+
+    /// Files written for the embedding with given (`.out`) file name. The external data file of
+    /// big matrices isn't known upfront.
+    pub fn output_files(filename: &str, produce_entity_occurrence_count: bool) -> Vec<String> {
+        let mut files = vec![
+            format!("{}.onnx", filename),
+            format!("{}.entities", filename),
+        ];
+        if produce_entity_occurrence_count {
+            files.push(format!("{}.occurences", filename));
+        }
+        files
+    }
+
+    /// Writes the rows to the model or its external data file
+    fn write_data(&mut self, bytes: &[u8]) -> Result<(), CleoraError> {
+        let (buf, filename) = match self.data_buf.as_mut() {
+            Some(data_buf) => (data_buf, &self.data_file_name),
+            None => (&mut self.model_buf, &self.model_file_name),
+        };
+        buf.write_all(bytes)
+            .map_err(|e| CleoraError::write_file(filename, e))
+    }
+}
+
+impl EmbeddingPersistor for OnnxPersistor {
+    fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), CleoraError> {
+        self.entity_count = entity_count as usize;
+        self.dimension = dimension as usize;
+
+        let data_len = entity_count as u64 * dimension as u64 * 4;
+        let external_data = if data_len > self.inline_limit {
+            self.data_buf = Some(BufWriter::new(create_output(&self.data_file_name)?));
+            // resolved relative to the model
+            Path::new(&self.data_file_name)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.to_string())
+        } else {
+            None
+        };
+        let prefix = model_prefix(
+            entity_count as u64,
+            dimension as u64,
+            external_data.as_deref(),
+        );
+        self.model_buf
+            .write_all(&prefix)
+            .map_err(|e| CleoraError::write_file(&self.model_file_name, e))
+    }
+
+    fn put_data(
+        &mut self,
+        entity: &str,
+        occur_count: u32,
+        vector: Vec<f32>,
+    ) -> Result<(), CleoraError> {
+        let row: Vec<u8> = vector.iter().flat_map(|v| v.to_le_bytes()).collect();
+        self.write_data(&row)?;
+        self.entities.put(entity)?;
+        self.occurences.push(occur_count);
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+        // rows of the chunk are contiguous, so they are written at once
+        let bytes: Vec<u8> = chunk.vectors.iter().flat_map(|v| v.to_le_bytes()).collect();
+        self.write_data(&bytes)?;
+        for entity in chunk.entities.iter() {
+            self.entities.put(entity)?;
+        }
+        self.rows += chunk.entities.len();
+        self.occurences.extend_from_slice(chunk.occur_counts);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), CleoraError> {
+        // the model promised rows of all entities
+        let missing_rows = self.entity_count.saturating_sub(self.rows);
+        self.write_data(&vec![0u8; missing_rows * self.dimension * 4])?;
+
+        if let Some(data_buf) = self.data_buf.as_mut() {
+            let data_file_name = &self.data_file_name;
+            data_buf
+                .flush()
+                .map_err(|e| CleoraError::write_file(data_file_name, e))?;
+            data_buf.get_mut().commit()?;
+        }
+        self.model_buf
+            .flush()
+            .map_err(|e| CleoraError::write_file(&self.model_file_name, e))?;
+        self.entities.finish()?;
+        write_occurences(
+            &self.model_file_name,
+            &self.occurences,
+            self.occurences_buf.as_mut(),
+        )?;
+        self.model_buf.get_mut().commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::EntitiesFormat;
+    use crate::onnx::OnnxPersistor;
+    use crate::persistence::embedding::{EmbeddingBatch, EmbeddingPersistor};
+
+    /// Field number, varint value (or 0) and bytes (or empty) of the protobuf fields
+    fn fields(mut bytes: &[u8]) -> Vec<(u32, u64, &[u8])> {
+        fn varint(bytes: &mut &[u8]) -> u64 {
+            let mut value = 0;
+            let mut shift = 0;
+            loop {
+                let byte = bytes[0];
+                *bytes = &bytes[1..];
+                value |= ((byte & 0x7f) as u64) << shift;
+                if byte < 0x80 {
+                    return value;
+                }
+                shift += 7;
+            }
+        }
+
+        let mut fields = vec![];
+        while !bytes.is_empty() {
+            let key = varint(&mut bytes);
+            match key & 7 {
+                0 => fields.push(((key >> 3) as u32, varint(&mut bytes), &[][..])),
+                2 => {
+                    let len = varint(&mut bytes) as usize;
+                    fields.push(((key >> 3) as u32, 0, &bytes[..len]));
+                    bytes = &bytes[len..];
+                }
+                wire_type => panic!("unexpected wire type {}", wire_type),
+            }
+        }
+        fields
+    }
+
+    fn field(bytes: &[u8], number: u32) -> Vec<(u64, &[u8])> {
+        fields(bytes)
+            .into_iter()
+            .filter(|(n, _, _)| *n == number)
+            .map(|(_, value, bytes)| (value, bytes))
+            .collect()
+    }
+
+    fn write(filename: &str, inline_limit: u64) -> Vec<u8> {
+        let mut persistor =
+            OnnxPersistor::new(filename.to_string(), true, EntitiesFormat::Lines).unwrap();
+        persistor.inline_limit = inline_limit;
+        persistor.put_metadata(3, 2).unwrap();
+        persistor.put_data("a", 3, vec![1.0, -0.5]).unwrap();
+        persistor
+            .put_data_batch(EmbeddingBatch::new(&["b".into()], &[2], &[0.0, 1.0], 2))
+            .unwrap();
+        persistor.finish().unwrap();
+        std::fs::read(format!("{}.onnx", filename)).unwrap()
+    }
+
+    #[test]
+    fn write_onnx_model() {
+        let filename = std::env::temp_dir().join("cleora_onnx_output");
+        let filename = filename.to_str().unwrap();
+        let model = write(filename, u64::MAX);
+        let expected: Vec<u8> = [1.0f32, -0.5, 0.0, 1.0, 0.0, 0.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+
+        assert_eq!(vec![(7, &[][..])], field(&model, 1));
+        let graph = field(&model, 7)[0].1;
+        let node = field(graph, 1)[0].1;
+        assert_eq!(b"Gather", field(node, 4)[0].1);
+        assert_eq!(
+            vec![&b"embeddings"[..], &b"index"[..]],
+            field(node, 1).iter().map(|f| f.1).collect::<Vec<_>>()
+        );
+        let tensor = field(graph, 5)[0].1;
+        assert_eq!(
+            vec![3, 2],
+            field(tensor, 1).iter().map(|f| f.0).collect::<Vec<_>>()
+        );
+        assert_eq!(&expected[..], field(tensor, 9)[0].1);
+        assert_eq!(
+            "a\nb\n",
+            std::fs::read_to_string(format!("{}.entities", filename)).unwrap()
+        );
+
+        // the matrix moves to the external data file
+        let model = write(filename, 0);
+        let graph = field(&model, 7)[0].1;
+        let tensor = field(graph, 5)[0].1;
+        assert!(field(tensor, 9).is_empty());
+        assert_eq!(vec![(1, &[][..])], field(tensor, 14));
+        let location = field(tensor, 13)[0].1;
+        assert_eq!(b"cleora_onnx_output.onnx.data", field(location, 2)[0].1);
+        let data_file_name = format!("{}.onnx.data", filename);
+        assert_eq!(expected, std::fs::read(&data_file_name).unwrap());
+
+        for extension in ["onnx", "onnx.data", "entities", "occurences"] {
+            std::fs::remove_file(format!("{}.{}", filename, extension)).unwrap();
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use crate::error::CleoraError;
+use crate::matrix_export::{to_le_bytes, NpzWriter};
+use ndarray::{Array1, Array2, ArrayView2, Axis};
+
+/// Extra vectors iterated along the components, so the last components converge as fast as the
+/// first ones
+const PCA_OVERSAMPLING: usize = 10;
+const MAX_SUBSPACE_ITERATIONS: usize = 100;
+/// Relative change of the variances of the components at which the iteration stops
+const SUBSPACE_TOLERANCE: f64 = 1e-9;
+const MAX_JACOBI_SWEEPS: usize = 50;
+
+/// Projection of the embeddings onto their principal components (PCA), which keeps as much of
+/// their variance as `components` dimensions can
+#[derive(Debug, Clone)]
+pub struct Projection {
+    /// Mean of the embeddings, subtracted before projecting
+    pub mean: Array1<f32>,
+    /// Principal axes as columns (`dimension x components`), by decreasing variance
+    pub components: Array2<f32>,
+    /// Variance of the embeddings along every axis
+    pub explained_variance: Vec<f32>,
+    /// Sum of the variances of all dimensions of the embeddings
+    pub total_variance: f32,
+}
+
+impl Projection {
+    /// Fits the projection on at most `sample_size` rows, evenly spaced in the embeddings. The
+    /// components are found with subspace iteration on the covariance matrix from deterministic
+    /// starting vectors, so the result is the same in every run. Signs of the components are
+    /// chosen to make their largest coordinate positive.
+    pub fn fit(values: ArrayView2<f32>, components: usize, sample_size: usize) -> Self {
+        let (rows, dimension) = values.dim();
+        let components = components.min(dimension);
+        let step = (rows / sample_size.max(1)).max(1);
+        let sample: Vec<usize> = (0..rows).step_by(step).take(sample_size).collect();
+        let mut centered = Array2::from_shape_fn((sample.len(), dimension), |(i, j)| {
+            values[[sample[i], j]] as f64
+        });
+        let mean = centered
+            .mean_axis(Axis(0))
+            .unwrap_or_else(|| Array1::zeros(dimension));
+        centered -= &mean;
+        let covariance = centered.t().dot(&centered) / (sample.len().max(2) - 1) as f64;
+
+        let (variances, mut axes) = top_eigenvectors(&covariance, components);
+        for mut axis in axes.columns_mut() {
+            let largest = axis
+                .iter()
+                .copied()
+                .max_by(|a, b| a.abs().partial_cmp(&b.abs()).unwrap())
+                .unwrap_or_default();
+            if largest < 0f64 {
+                axis.mapv_inplace(|v| -v);
+            }
+        }
+
+        Self {
+            mean: mean.mapv(|v| v as f32),
+            components: axes.mapv(|v| v as f32),
+            explained_variance: variances.iter().map(|&v| v.max(0f64) as f32).collect(),
+            total_variance: covariance.diag().sum() as f32,
+        }
+    }
+
+    /// Share of the variance of the embeddings kept by the components
+    pub fn explained_ratio(&self) -> f32 {
+        if self.total_variance > 0f32 {
+            self.explained_variance.iter().sum::<f32>() / self.total_variance
+        } else {
+            1f32
+        }
+    }
+
+    /// Projects the rows of the embeddings
+    pub fn transform(&self, values: ArrayView2<f32>) -> Array2<f32> {
+        (&values - &self.mean).dot(&self.components)
+    }
+
+    /// Writes `.npz` archive with `mean`, `components` and `explained_variance` arrays, so other
+    /// embeddings of the same space are projected with `(x - mean) @ components`
+    pub fn write(&self, filename: &str) -> Result<(), CleoraError> {
+        let (dimension, components) = self.components.dim();
+        let mut archive = NpzWriter::create(filename)?;
+        archive.put_array(
+            "mean",
+            "<f4",
+            &[dimension],
+            &to_le_bytes(&self.mean.to_vec(), f32::to_le_bytes),
+        )?;
+        let values: Vec<f32> = self.components.iter().copied().collect();
+        archive.put_array(
+            "components",
+            "<f4",
+            &[dimension, components],
+            &to_le_bytes(&values, f32::to_le_bytes),
+        )?;
+        archive.put_array(
+            "explained_variance",
+            "<f4",
+            &[components],
+            &to_le_bytes(&self.explained_variance, f32::to_le_bytes),
+        )?;
+        archive.finish()
+    }
+}
+
+/// Largest `count` eigenvalues (in decreasing order) of the symmetric matrix and their
+/// eigenvectors as columns. Orthonormal basis of the dominant subspace is iterated until the
+/// variances along it settle, then it's rotated onto the eigenvectors (Rayleigh-Ritz).
+fn top_eigenvectors(matrix: &Array2<f64>, count: usize) -> (Vec<f64>, Array2<f64>) {
+    let dimension = matrix.nrows();
+    let size = (count + PCA_OVERSAMPLING).min(dimension);
+    let mut basis = Array2::from_shape_fn((dimension, size), |(i, j)| {
+        starting_value((i * size + j) as u64)
+    });
+    orthonormalize(&mut basis);
+
+    let mut variances = vec![0f64; count];
+    for _ in 0..MAX_SUBSPACE_ITERATIONS {
+        let mut next = matrix.dot(&basis);
+        let current: Vec<f64> = (0..count)
+            .map(|j| basis.column(j).dot(&next.column(j)))
+            .collect();
+        orthonormalize(&mut next);
+        basis = next;
+        let scale = current.iter().fold(0f64, |max, v| max.max(v.abs()));
+        let settled = current
+            .iter()
+            .zip(variances.iter())
+            .all(|(v, previous)| (v - previous).abs() <= SUBSPACE_TOLERANCE * scale);
+        variances = current;
+        if settled || size == dimension {
+            break;
+        }
+    }
+
+    let projected = basis.t().dot(matrix).dot(&basis);
+    let (values, vectors) = symmetric_eigen(projected);
+    let mut order: Vec<usize> = (0..size).collect();
+    order.sort_by(|&a, &b| values[b].partial_cmp(&values[a]).unwrap());
+    let vectors = basis.dot(&vectors);
+    let mut axes = Array2::zeros((dimension, count));
+    for (j, &i) in order.iter().take(count).enumerate() {
+        axes.column_mut(j).assign(&vectors.column(i));
+    }
+    (order.iter().take(count).map(|&i| values[i]).collect(), axes)
+}
+
+/// Gram-Schmidt orthonormalization of the columns (done twice for accuracy). Columns dependent on
+/// the previous ones are replaced, so the basis stays complete for rank deficient matrices.
+fn orthonormalize(basis: &mut Array2<f64>) {
+    let columns = basis.ncols();
+    for j in 0..columns {
+        for attempt in 0..2 {
+            let initial = basis.column(j).dot(&basis.column(j)).sqrt();
+            for _ in 0..2 {
+                for i in 0..j {
+                    let previous = basis.column(i).to_owned();
+                    let overlap = previous.dot(&basis.column(j));
+                    basis.column_mut(j).scaled_add(-overlap, &previous);
+                }
+            }
+            let norm = basis.column(j).dot(&basis.column(j)).sqrt();
+            if norm > 1e-8 * initial || attempt == 1 {
+                basis.column_mut(j).mapv_inplace(|v| v / norm.max(1e-300));
+                break;
+            }
+            let rows = basis.nrows();
+            for (i, v) in basis.column_mut(j).iter_mut().enumerate() {
+                *v = starting_value(((columns + j) * rows + i) as u64);
+            }
+        }
+    }
+}
+
+/// Deterministic pseudo-random value in [-1, 1) (SplitMix64)
+pub fn starting_value(index: u64) -> f64 {
+    let mut z = index.wrapping_add(1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 52) as f64 - 1f64
+}
+
+/// Eigenvalues and eigenvectors (columns) of the symmetric matrix, cyclic Jacobi rotations
+fn symmetric_eigen(mut matrix: Array2<f64>) -> (Vec<f64>, Array2<f64>) {
+    let size = matrix.nrows();
+    let mut vectors = Array2::<f64>::eye(size);
+    let total = matrix.iter().map(|v| v * v).sum::<f64>();
+    for _ in 0..MAX_JACOBI_SWEEPS {
+        let off_diagonal = total - matrix.diag().iter().map(|v| v * v).sum::<f64>();
+        if off_diagonal <= 1e-24 * total {
+            break;
+        }
+        for p in 0..size {
+            for q in p + 1..size {
+                let apq = matrix[[p, q]];
+                if apq == 0f64 {
+                    continue;
+                }
+                let theta = (matrix[[q, q]] - matrix[[p, p]]) / (2f64 * apq);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1f64).sqrt());
+                let c = 1f64 / (t * t + 1f64).sqrt();
+                let s = t * c;
+                rotate(&mut matrix, p, q, c, s);
+                for k in 0..size {
+                    let (apk, aqk) = (matrix[[p, k]], matrix[[q, k]]);
+                    matrix[[p, k]] = c * apk - s * aqk;
+                    matrix[[q, k]] = s * apk + c * aqk;
+                }
+                rotate(&mut vectors, p, q, c, s);
+            }
+        }
+    }
+    (matrix.diag().to_vec(), vectors)
+}
+
+/// Applies the rotation to the columns `p` and `q` of the matrix
+fn rotate(matrix: &mut Array2<f64>, p: usize, q: usize, c: f64, s: f64) {
+    for k in 0..matrix.nrows() {
+        let (akp, akq) = (matrix[[k, p]], matrix[[k, q]]);
+        matrix[[k, p]] = c * akp - s * akq;
+        matrix[[k, q]] = s * akp + c * akq;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pca::{symmetric_eigen, Projection};
+    use ndarray::{arr1, arr2, Array2};
+
+    #[test]
+    fn eigen_decomposition() {
+        let (values, vectors) =
+            symmetric_eigen(arr2(&[[2.0, 1.0, 0.0], [1.0, 2.0, 0.0], [0.0, 0.0, 5.0]]));
+        let mut sorted = values.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for (value, expected) in sorted.iter().zip([1.0, 3.0, 5.0]) {
+            assert!((value - expected).abs() < 1e-9);
+        }
+        let largest = values.iter().position(|&v| (v - 5.0).abs() < 1e-9).unwrap();
+        assert!((vectors[[2, largest]].abs() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn fit_principal_components() {
+        let u = arr1(&[0.6f32, 0.8, 0.0, 0.0, 0.0, 0.0]);
+        let v = arr1(&[0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0]);
+        let mean = arr1(&[1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let coordinates: Vec<(f32, f32)> = (0..100)
+            .map(|i| ((i as f32 - 49.5) / 10.0, [0.5, -0.5, -0.5, 0.5][i % 4]))
+            .collect();
+        let values = Array2::from_shape_fn((100, 6), |(i, j)| {
+            mean[j] + coordinates[i].0 * u[j] + coordinates[i].1 * v[j]
+        });
+
+        let projection = Projection::fit(values.view(), 2, 1000);
+        assert_eq!((6, 2), projection.components.dim());
+        assert!((projection.components.column(0).dot(&u) - 1.0).abs() < 1e-4);
+        assert!((projection.components.column(1).dot(&v) - 1.0).abs() < 1e-4);
+        assert!(projection.explained_variance[0] > projection.explained_variance[1]);
+        assert!((projection.explained_ratio() - 1.0).abs() < 1e-4);
+
+        let reduced = projection.transform(values.view());
+        for (row, &(a, b)) in reduced.outer_iter().zip(coordinates.iter()) {
+            assert!((row[0] - a).abs() < 1e-3);
+            assert!((row[1] - b).abs() < 1e-3);
+        }
+
+        // sampled rows and fewer rows than dimensions give orthonormal components too
+        let sampled = Projection::fit(values.view(), 2, 10);
+        assert!((sampled.components.column(0).dot(&u) - 1.0).abs() < 1e-2);
+        let few = Projection::fit(values.slice(ndarray::s![..2, ..]), 4, 10);
+        let gram = few.components.t().dot(&few.components);
+        assert!((&gram - &Array2::<f32>::eye(4))
+            .iter()
+            .all(|v| v.abs() < 1e-4));
+    }
+}
@@ -1,175 +1,683 @@
 pub mod entity {
-    use rustc_hash::FxHashMap;
-    use std::sync::RwLock;
+    #[cfg(feature = "fs")]
+    use crate::dictionary::write_dictionary;
+    use crate::error::CleoraError;
+    #[cfg(feature = "fs")]
+    use crate::io::{create_output, OutputFile};
+    #[cfg(feature = "fs")]
+    use arrow2::{
+        array::{Array as ArrowArray, UInt64Array, Utf8Array},
+        chunk::Chunk,
+        datatypes::{DataType, Field, Schema},
+        io::parquet::write::{
+            transverse, CompressionOptions, Encoding, FileWriter, RowGroupIterator, Version,
+            WriteOptions,
+        },
+    };
+    use rustc_hash::{FxHashMap, FxHashSet};
+    use smallvec::SmallVec;
+    use std::collections::hash_map;
+    #[cfg(feature = "fs")]
+    use std::io::{BufWriter, Write};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, RwLock};
 
     pub trait EntityMappingPersistor {
-        fn get_entity(&self, hash: u64) -> Option<String>;
+        /// Entity stored for the hash, shared with the mapping so reading it doesn't copy it
+        fn get_entity(&self, hash: u64) -> Option<Arc<str>>;
+        /// Stores entity for the hash. Different entity for already stored hash is a collision,
+        /// the first entity is kept.
         fn put_data(&self, hash: u64, entity: String);
-        fn contains(&self, hash: u64) -> bool;
+        /// Stores `prefix` + `entity` for the hash with a single lookup, or records a collision if
+        /// a different entity is stored for it. The prefix is passed separately, so the entity is
+        /// copied only when it's stored.
+        fn put_entity(&self, hash: u64, prefix: &str, entity: &str);
+        /// Attaches the tag to the entity of the hash, tags already attached are skipped
+        fn put_tag(&self, hash: u64, tag: &str);
+        /// Whether the entity of the hash has any of the tags
+        fn has_tag(&self, hash: u64, tags: &[String]) -> bool;
     }
 
+    /// Hash collision - two different entities with the same hash
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Collision {
+        pub hash: u64,
+        /// Entity kept in the mapping
+        pub stored_entity: String,
+        /// Entity merged with the stored one
+        pub colliding_entity: String,
+    }
+
+    /// The entity mapping is split into `2^ENTITY_MAPPING_SHARD_BITS` separately locked shards, so
+    /// threads parsing the input don't wait for each other's writes
+    const ENTITY_MAPPING_SHARD_BITS: u32 = 6;
+
+    type EntityShard = RwLock<FxHashMap<u64, Arc<str>>>;
+
+    /// Ids of the tags (see `TagNames`) attached to the entities of a shard
+    type TagShard = RwLock<FxHashMap<u64, SmallVec<[u32; 4]>>>;
+
+    /// Distinct tags, entities refer to them by their index
     #[derive(Debug, Default)]
+    struct TagNames {
+        ids: FxHashMap<Arc<str>, u32>,
+        names: Vec<Arc<str>>,
+    }
+
+    /// Entities are kept as `Arc<str>` (exactly sized, without spare capacity), so they are handed
+    /// out to the embedding batches by reference count instead of being copied for every row
+    #[derive(Debug)]
     pub struct InMemoryEntityMappingPersistor {
-        entity_mappings: RwLock<FxHashMap<u64, String>>,
+        entity_mappings: Vec<EntityShard>,
+        /// Tags of the entities, sharded like the entities
+        entity_tags: Vec<TagShard>,
+        tag_names: RwLock<TagNames>,
+        collisions: RwLock<FxHashMap<u64, FxHashSet<String>>>,
+        identity_hashes: Option<IdentityHashes>,
     }
 
-    impl EntityMappingPersistor for InMemoryEntityMappingPersistor {
-        fn get_entity(&self, hash: u64) -> Option<String> {
-            let entity_mappings_read = self.entity_mappings.read().unwrap();
-            entity_mappings_read.get(&hash).map(|s| s.to_string())
+    impl Default for InMemoryEntityMappingPersistor {
+        fn default() -> Self {
+            InMemoryEntityMappingPersistor {
+                entity_mappings: (0..1 << ENTITY_MAPPING_SHARD_BITS)
+                    .map(|_| RwLock::default())
+                    .collect(),
+                entity_tags: (0..1 << ENTITY_MAPPING_SHARD_BITS)
+                    .map(|_| RwLock::default())
+                    .collect(),
+                tag_names: RwLock::default(),
+                collisions: RwLock::default(),
+                identity_hashes: None,
+            }
         }
+    }
 
-        fn put_data(&self, hash: u64, entity: String) {
-            let mut entity_mappings_write = self.entity_mappings.write().unwrap();
-            entity_mappings_write.insert(hash, entity);
+    /// Layout of identity hashes: column index in the high bits, numeric entity id in the low bits
+    #[derive(Debug)]
+    struct IdentityHashes {
+        id_bits: u32,
+        /// Prefix of entities for every column, `None` for columns without output (transient)
+        prefixes: Vec<Option<String>>,
+    }
+
+    impl InMemoryEntityMappingPersistor {
+        /// Persistor for identity hashes. Entities are decoded from hashes instead of being stored.
+        pub fn with_identity_hashes(id_bits: u32, prefixes: Vec<Option<String>>) -> Self {
+            InMemoryEntityMappingPersistor {
+                identity_hashes: Some(IdentityHashes { id_bits, prefixes }),
+                ..Default::default()
+            }
         }
 
-        fn contains(&self, hash: u64) -> bool {
-            let entity_mappings_read = self.entity_mappings.read().unwrap();
-            entity_mappings_read.contains_key(&hash)
+        /// Shard of the hash, picked by the high bits of the hash multiplied by an odd constant.
+        /// Every bit of the hash counts (32-bit hashes have no high bits of their own) and the low
+        /// bits the shard's map places keys by stay spread.
+        fn shard_index(hash: u64) -> usize {
+            let mixed = hash.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            (mixed >> (64 - ENTITY_MAPPING_SHARD_BITS)) as usize
         }
-    }
-}
 
-pub mod embedding {
-    use crate::io::S3File;
-    use crate::persistence::embedding::memmap::OwnedMmapArrayViewMut;
+        fn shard(&self, hash: u64) -> &EntityShard {
+            &self.entity_mappings[Self::shard_index(hash)]
+        }
 
-    use ndarray::{s, Array};
-    use ndarray_npy::write_zeroed_npy;
-    use std::fs::File;
-    use std::io;
-    use std::io::{BufWriter, Error, ErrorKind, Write};
+        fn tag_shard(&self, hash: u64) -> &TagShard {
+            &self.entity_tags[Self::shard_index(hash)]
+        }
 
-    use arrow2::{
-        array::{Array as ArrowArray, Float32Array, UInt32Array, Utf8Array},
-        chunk::Chunk,
-        datatypes::{DataType, Field, Schema},
-        error::Result as ArrowResult,
-        io::parquet::write::{
-            transverse, CompressionOptions, Encoding, FileWriter, RowGroupIterator, Version,
-            WriteOptions,
-        },
-    };
-    use chrono::prelude::*;
+        /// Id of the tag, the tag is added if it's new
+        fn tag_id(&self, tag: &str) -> u32 {
+            if let Some(&id) = self.tag_names.read().unwrap().ids.get(tag) {
+                return id;
+            }
+            let mut tag_names_write = self.tag_names.write().unwrap();
+            let TagNames { ids, names } = &mut *tag_names_write;
+            *ids.entry(Arc::from(tag)).or_insert_with_key(|tag| {
+                names.push(Arc::clone(tag));
+                (names.len() - 1) as u32
+            })
+        }
 
-    pub trait EmbeddingPersistor {
-        fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), io::Error>;
+        /// Calls the function with every tagged entity hash and its tags
+        pub fn for_each_tagged<F>(&self, mut f: F) -> Result<(), CleoraError>
+        where
+            F: FnMut(u64, Vec<Arc<str>>) -> Result<(), CleoraError>,
+        {
+            let tag_names_read = self.tag_names.read().unwrap();
+            for shard in self.entity_tags.iter() {
+                let shard_read = shard.read().unwrap();
+                for (&hash, ids) in shard_read.iter() {
+                    let tags = ids
+                        .iter()
+                        .map(|&id| Arc::clone(&tag_names_read.names[id as usize]))
+                        .collect();
+                    f(hash, tags)?;
+                }
+            }
+            Ok(())
+        }
 
-        fn put_data(
-            &mut self,
-            entity: &str,
-            occur_count: u32,
-            vector: Vec<f32>,
-        ) -> Result<(), io::Error>;
+        /// Returns number of stored entities
+        pub fn len(&self) -> usize {
+            self.entity_mappings
+                .iter()
+                .map(|shard| shard.read().unwrap().len())
+                .sum()
+        }
 
-        fn put_data_chunk(
-            &mut self,
-            chunk: (Vec<String>, Vec<u32>, Vec<Vec<f32>>),
-        ) -> Result<(), io::Error>;
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        /// Returns up to `n` arbitrary (hash, entity) pairs
+        pub fn sample(&self, n: usize) -> Vec<(u64, Arc<str>)> {
+            let mut sample = Vec::with_capacity(n.min(self.len()));
+            for shard in self.entity_mappings.iter() {
+                if sample.len() == n {
+                    break;
+                }
+                let shard_read = shard.read().unwrap();
+                sample.extend(
+                    shard_read
+                        .iter()
+                        .take(n - sample.len())
+                        .map(|(&hash, entity)| (hash, Arc::clone(entity))),
+                );
+            }
+            sample
+        }
+
+        /// Returns every detected collision (distinct colliding entities)
+        pub fn collisions(&self) -> Vec<Collision> {
+            let collisions_read = self.collisions.read().unwrap();
+            let mut collisions = Vec::new();
+            for (hash, colliding_entities) in collisions_read.iter() {
+                let stored_entity = self
+                    .shard(*hash)
+                    .read()
+                    .unwrap()
+                    .get(hash)
+                    .map(|entity| entity.to_string())
+                    .unwrap_or_default();
+                for colliding_entity in colliding_entities {
+                    collisions.push(Collision {
+                        hash: *hash,
+                        stored_entity: stored_entity.clone(),
+                        colliding_entity: colliding_entity.clone(),
+                    });
+                }
+            }
+            collisions
+        }
+
+        fn record_collision(&self, hash: u64, entity: &str) {
+            {
+                let collisions_read = self.collisions.read().unwrap();
+                if let Some(colliding_entities) = collisions_read.get(&hash) {
+                    if colliding_entities.contains(entity) {
+                        return;
+                    }
+                }
+            }
+            let mut collisions_write = self.collisions.write().unwrap();
+            collisions_write
+                .entry(hash)
+                .or_default()
+                .insert(entity.to_string());
+        }
 
-        fn finish(&mut self) -> Result<(), io::Error>;
+        /// Writes every (hash, entity) pair with the provided writer
+        pub fn write_all(&self, writer: &mut dyn EntityMappingWriter) -> Result<(), CleoraError> {
+            for shard in self.entity_mappings.iter() {
+                let shard_read = shard.read().unwrap();
+                for (&hash, entity) in shard_read.iter() {
+                    writer.put_data(hash, entity)?;
+                }
+            }
+            writer.finish()
+        }
     }
 
-    pub struct TextFileVectorPersistor {
-        buf_writer: BufWriter<File>,
-        produce_entity_occurrence_count: bool,
+    impl EntityMappingPersistor for InMemoryEntityMappingPersistor {
+        fn get_entity(&self, hash: u64) -> Option<Arc<str>> {
+            if let Some(identity_hashes) = self.identity_hashes.as_ref() {
+                let column_idx = (hash >> identity_hashes.id_bits) as usize;
+                let id = hash & ((1u64 << identity_hashes.id_bits) - 1);
+                let prefix = identity_hashes.prefixes.get(column_idx)?.as_ref()?;
+                return Some(format!("{}{}", prefix, id).into());
+            }
+            let entity_mappings_read = self.shard(hash).read().unwrap();
+            entity_mappings_read.get(&hash).cloned()
+        }
+
+        fn put_data(&self, hash: u64, entity: String) {
+            let mut entity_mappings_write = self.shard(hash).write().unwrap();
+            match entity_mappings_write.entry(hash) {
+                hash_map::Entry::Vacant(entry) => {
+                    entry.insert(entity.into());
+                }
+                hash_map::Entry::Occupied(entry) => {
+                    if **entry.get() != *entity {
+                        drop(entity_mappings_write);
+                        self.record_collision(hash, &entity);
+                    }
+                }
+            }
+        }
+
+        fn put_entity(&self, hash: u64, prefix: &str, entity: &str) {
+            let mut entity_mappings_write = self.shard(hash).write().unwrap();
+            match entity_mappings_write.entry(hash) {
+                hash_map::Entry::Vacant(entry) => {
+                    let mut stored = String::with_capacity(prefix.len() + entity.len());
+                    stored.push_str(prefix);
+                    stored.push_str(entity);
+                    entry.insert(stored.into());
+                }
+                hash_map::Entry::Occupied(entry) => {
+                    let stored = entry.get();
+                    if stored.len() != prefix.len() + entity.len()
+                        || !stored.starts_with(prefix)
+                        || !stored.ends_with(entity)
+                    {
+                        drop(entity_mappings_write);
+                        let mut colliding_entity = prefix.to_string();
+                        colliding_entity.push_str(entity);
+                        self.record_collision(hash, &colliding_entity);
+                    }
+                }
+            }
+        }
+
+        fn put_tag(&self, hash: u64, tag: &str) {
+            let id = self.tag_id(tag);
+            let shard = self.tag_shard(hash);
+            if let Some(ids) = shard.read().unwrap().get(&hash) {
+                if ids.contains(&id) {
+                    return;
+                }
+            }
+            let mut shard_write = shard.write().unwrap();
+            let ids = shard_write.entry(hash).or_default();
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+
+        fn has_tag(&self, hash: u64, tags: &[String]) -> bool {
+            let shard_read = self.tag_shard(hash).read().unwrap();
+            let ids = match shard_read.get(&hash) {
+                Some(ids) => ids,
+                None => return false,
+            };
+            let tag_names_read = self.tag_names.read().unwrap();
+            ids.iter().any(|&id| {
+                tags.iter()
+                    .any(|tag| *tag_names_read.names[id as usize] == **tag)
+            })
+        }
     }
 
-    impl TextFileVectorPersistor {
-        pub fn new(filename: String, produce_entity_occurrence_count: bool) -> Self {
-            let msg = format!("Unable to create file: {}", filename);
-            let file = File::create(filename).expect(&msg);
-            TextFileVectorPersistor {
-                buf_writer: BufWriter::new(file),
-                produce_entity_occurrence_count,
+    /// Entity mapping which doesn't keep the entities, for reading inputs only to count them (dry
+    /// runs). Lengths of the first distinct entities are sampled, so the size of entity names can
+    /// be estimated without memory growing with the graph.
+    #[derive(Debug)]
+    pub struct CountingEntityMappingPersistor {
+        sample_size: usize,
+        /// Lengths of the sampled entities
+        lengths: RwLock<FxHashMap<u64, usize>>,
+        full: AtomicBool,
+    }
+
+    impl CountingEntityMappingPersistor {
+        pub fn new(sample_size: usize) -> Self {
+            CountingEntityMappingPersistor {
+                sample_size,
+                lengths: RwLock::default(),
+                full: AtomicBool::new(sample_size == 0),
+            }
+        }
+
+        /// Number of sampled entities
+        pub fn sampled(&self) -> usize {
+            self.lengths.read().unwrap().len()
+        }
+
+        /// Mean length in bytes of the sampled entities, `None` if nothing was sampled
+        pub fn mean_entity_bytes(&self) -> Option<f64> {
+            let lengths = self.lengths.read().unwrap();
+            if lengths.is_empty() {
+                return None;
             }
+            let bytes: usize = lengths.values().sum();
+            Some(bytes as f64 / lengths.len() as f64)
         }
     }
 
-    impl EmbeddingPersistor for TextFileVectorPersistor {
-        fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), io::Error> {
-            write!(&mut self.buf_writer, "{} {}", entity_count, dimension)?;
-            Ok(())
+    impl EntityMappingPersistor for CountingEntityMappingPersistor {
+        fn get_entity(&self, _hash: u64) -> Option<Arc<str>> {
+            None
         }
 
-        fn put_data(
-            &mut self,
-            entity: &str,
-            occur_count: u32,
-            vector: Vec<f32>,
-        ) -> Result<(), io::Error> {
-            self.buf_writer.write_all(b"\n")?;
-            self.buf_writer.write_all(entity.as_bytes())?;
+        fn put_data(&self, hash: u64, entity: String) {
+            let mut lengths_write = self.lengths.write().unwrap();
+            if lengths_write.len() < self.sample_size {
+                lengths_write.entry(hash).or_insert(entity.len());
+            }
+            if lengths_write.len() >= self.sample_size {
+                self.full.store(true, Ordering::Relaxed);
+            }
+        }
 
-            if self.produce_entity_occurrence_count {
-                write!(&mut self.buf_writer, " {}", occur_count)?;
+        /// Once the sample is full nothing is looked up for the entities
+        fn put_entity(&self, hash: u64, prefix: &str, entity: &str) {
+            if self.full.load(Ordering::Relaxed) {
+                return;
+            }
+            let mut lengths_write = self.lengths.write().unwrap();
+            let sampled = lengths_write.len();
+            if let hash_map::Entry::Vacant(entry) = lengths_write.entry(hash) {
+                if sampled < self.sample_size {
+                    entry.insert(prefix.len() + entity.len());
+                    if sampled + 1 >= self.sample_size {
+                        self.full.store(true, Ordering::Relaxed);
+                    }
+                }
             }
+        }
 
-            for &v in &vector {
-                self.buf_writer.write_all(b" ")?;
-                let mut buf = ryu::Buffer::new(); // cheap op
-                self.buf_writer.write_all(buf.format_finite(v).as_bytes())?;
+        fn put_tag(&self, _hash: u64, _tag: &str) {}
+
+        fn has_tag(&self, _hash: u64, _tags: &[String]) -> bool {
+            false
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use crate::persistence::entity::{
+            Collision, CountingEntityMappingPersistor, EntityMappingPersistor,
+            InMemoryEntityMappingPersistor,
+        };
+
+        #[test]
+        fn detect_collisions() {
+            let persistor = InMemoryEntityMappingPersistor::default();
+            persistor.put_entity(1, "col__", "a");
+            persistor.put_data(2, String::from("col__b"));
+
+            // the same entities are not collisions
+            persistor.put_data(1, String::from("col__a"));
+            persistor.put_entity(2, "col__", "b");
+            assert!(persistor.collisions().is_empty());
+
+            persistor.put_data(1, String::from("col__c"));
+            persistor.put_entity(1, "col__", "c");
+            persistor.put_entity(2, "col__", "bb");
+            let mut collisions = persistor.collisions();
+            collisions.sort_by_key(|c| c.hash);
+            assert_eq!(
+                vec![
+                    Collision {
+                        hash: 1,
+                        stored_entity: String::from("col__a"),
+                        colliding_entity: String::from("col__c"),
+                    },
+                    Collision {
+                        hash: 2,
+                        stored_entity: String::from("col__b"),
+                        colliding_entity: String::from("col__bb"),
+                    },
+                ],
+                collisions
+            );
+            assert_eq!(Some("col__a".into()), persistor.get_entity(1));
+        }
+
+        #[test]
+        fn sample_entity_lengths() {
+            let persistor = CountingEntityMappingPersistor::new(2);
+            assert_eq!(None, persistor.mean_entity_bytes());
+            persistor.put_entity(1, "col__", "a");
+            persistor.put_entity(1, "col__", "a");
+            assert_eq!(1, persistor.sampled());
+            persistor.put_entity(2, "col__", "bbb");
+            // the sample is full, other entities aren't stored
+            persistor.put_entity(3, "col__", "cccccc");
+            assert_eq!(2, persistor.sampled());
+            assert_eq!(Some(7.0), persistor.mean_entity_bytes());
+            assert_eq!(None, persistor.get_entity(1));
+        }
+
+        #[test]
+        fn put_from_threads() {
+            let persistor = InMemoryEntityMappingPersistor::default();
+            std::thread::scope(|scope| {
+                for thread in 0..8u64 {
+                    let persistor = &persistor;
+                    scope.spawn(move || {
+                        // every hash is put by two threads
+                        for hash in (thread / 2 * 1000)..(thread / 2 * 1000 + 1000) {
+                            let entity = format!("{}", hash);
+                            persistor.put_entity(hash << 32, "e", &entity);
+                        }
+                    });
+                }
+            });
+            assert_eq!(4000, persistor.len());
+            assert!(persistor.collisions().is_empty());
+            assert_eq!(Some("e3999".into()), persistor.get_entity(3999 << 32));
+            assert_eq!(None, persistor.get_entity(4000 << 32));
+            assert_eq!(10, persistor.sample(10).len());
+            assert_eq!(4000, persistor.sample(5000).len());
+        }
+
+        #[test]
+        fn tag_entities() {
+            let persistor = InMemoryEntityMappingPersistor::default();
+            persistor.put_data(1, String::from("items__a"));
+            persistor.put_tag(1, "type=product");
+            persistor.put_tag(1, "color=red");
+            persistor.put_tag(1, "type=product");
+            persistor.put_tag(2, "type=product");
+
+            let tags = [String::from("type=product")];
+            assert!(persistor.has_tag(1, &tags));
+            assert!(persistor.has_tag(2, &tags));
+            assert!(!persistor.has_tag(3, &tags));
+            assert!(!persistor.has_tag(1, &[String::from("type=user")]));
+
+            let mut tagged = Vec::new();
+            persistor
+                .for_each_tagged(|hash, tags| {
+                    tagged.push((hash, tags.iter().map(|t| t.to_string()).collect::<Vec<_>>()));
+                    Ok(())
+                })
+                .unwrap();
+            tagged.sort();
+            assert_eq!(
+                vec![
+                    (
+                        1,
+                        vec![String::from("type=product"), String::from("color=red")]
+                    ),
+                    (2, vec![String::from("type=product")]),
+                ],
+                tagged
+            );
+        }
+
+        fn mapped_persistor() -> InMemoryEntityMappingPersistor {
+            let persistor = InMemoryEntityMappingPersistor::default();
+            persistor.put_entity(1, "users__", "a");
+            persistor.put_entity(2, "users__", "b");
+            persistor.put_entity(u64::MAX, "items__", "ą c");
+            persistor
+        }
+
+        fn mapping_of(persistor: &InMemoryEntityMappingPersistor) -> Vec<(u64, String)> {
+            let mut mapping: Vec<(u64, String)> = [1, 2, u64::MAX]
+                .iter()
+                .map(|&hash| (hash, persistor.get_entity(hash).unwrap().to_string()))
+                .collect();
+            mapping.sort();
+            mapping
+        }
+
+        #[test]
+        #[cfg(feature = "fs")]
+        fn tsv_mapping_round_trip() {
+            use crate::persistence::entity::TsvEntityMappingWriter;
+
+            let filename = std::env::temp_dir().join("cleora_tsv_mapping_round_trip.tsv");
+            let filename = filename.to_str().unwrap().to_string();
+            let persistor = mapped_persistor();
+            let mut writer = TsvEntityMappingWriter::new(filename.clone()).unwrap();
+            persistor.write_all(&mut writer).unwrap();
+
+            let mut mapping: Vec<(u64, String)> = std::fs::read_to_string(&filename)
+                .unwrap()
+                .lines()
+                .map(|line| {
+                    let (hash, entity) = line.split_once('\t').unwrap();
+                    (hash.parse().unwrap(), entity.to_string())
+                })
+                .collect();
+            mapping.sort();
+            assert_eq!(mapping_of(&persistor), mapping);
+            std::fs::remove_file(filename).unwrap();
+        }
+
+        #[test]
+        #[cfg(feature = "fs")]
+        fn parquet_mapping_round_trip() {
+            use crate::persistence::entity::ParquetEntityMappingWriter;
+            use arrow2::array::{UInt64Array, Utf8Array};
+            use arrow2::io::parquet::read::{infer_schema, read_metadata, FileReader};
+
+            let filename = std::env::temp_dir().join("cleora_parquet_mapping_round_trip.parquet");
+            let filename = filename.to_str().unwrap().to_string();
+            let persistor = mapped_persistor();
+            // chunks of two rows, so the mapping is split into row groups
+            let mut writer = ParquetEntityMappingWriter::new(filename.clone(), 2).unwrap();
+            persistor.write_all(&mut writer).unwrap();
+
+            let mut file = std::fs::File::open(&filename).unwrap();
+            let metadata = read_metadata(&mut file).unwrap();
+            let schema = infer_schema(&metadata).unwrap();
+            let names: Vec<&str> = schema.fields.iter().map(|f| f.name.as_str()).collect();
+            assert_eq!(vec!["hash", "entity"], names);
+            let mut mapping = Vec::new();
+            for chunk in FileReader::new(file, metadata.row_groups, schema, None, None) {
+                let chunk = chunk.unwrap();
+                let arrays = chunk.arrays();
+                let hashes = arrays[0].as_any().downcast_ref::<UInt64Array>().unwrap();
+                let entities = arrays[1].as_any().downcast_ref::<Utf8Array<i32>>().unwrap();
+                for row in 0..entities.len() {
+                    mapping.push((hashes.value(row), entities.value(row).to_string()));
+                }
             }
+            mapping.sort();
+            assert_eq!(mapping_of(&persistor), mapping);
+            std::fs::remove_file(filename).unwrap();
+        }
+    }
 
-            Ok(())
+    /// Output of the hash to entity mapping
+    pub trait EntityMappingWriter {
+        fn put_data(&mut self, hash: u64, entity: &str) -> Result<(), CleoraError>;
+
+        fn finish(&mut self) -> Result<(), CleoraError>;
+    }
+
+    /// Writes mapping as `hash<TAB>entity` lines
+    #[cfg(feature = "fs")]
+    pub struct TsvEntityMappingWriter {
+        filename: String,
+        buf_writer: BufWriter<OutputFile>,
+    }
+
+    #[cfg(feature = "fs")]
+    impl TsvEntityMappingWriter {
+        pub fn new(filename: String) -> Result<Self, CleoraError> {
+            let file = create_output(&filename)?;
+            Ok(TsvEntityMappingWriter {
+                filename,
+                buf_writer: BufWriter::new(file),
+            })
         }
+    }
 
-        fn put_data_chunk(
-            &mut self,
-            chunk: (Vec<String>, Vec<u32>, Vec<Vec<f32>>),
-        ) -> Result<(), io::Error> {
-            let entities = chunk.0;
-            let occur_counts = chunk.1;
-            let vectors = &chunk.2;
+    #[cfg(feature = "fs")]
+    impl EntityMappingWriter for TsvEntityMappingWriter {
+        fn put_data(&mut self, hash: u64, entity: &str) -> Result<(), CleoraError> {
+            writeln!(&mut self.buf_writer, "{}\t{}", hash, entity)
+                .map_err(|e| CleoraError::write_file(&self.filename, e))
+        }
 
-            for i in 0..entities.len() {
-                let entity = &entities[i];
-                let occur_count = &occur_counts[i];
-                let mut vector: Vec<f32> = Vec::new();
+        fn finish(&mut self) -> Result<(), CleoraError> {
+            self.buf_writer
+                .flush()
+                .map_err(|e| CleoraError::write_file(&self.filename, e))?;
+            self.buf_writer.get_mut().commit()
+        }
+    }
 
-                vectors.into_iter().for_each(|x| vector.push(x[i]));
-                self.put_data(entity.as_str(), *occur_count, vector)
-                    .unwrap();
+    /// Writes mapping as compressed dictionary, see `write_dictionary`. Entries have to be sorted
+    /// by entity, so they are kept in memory until the end.
+    #[cfg(feature = "fs")]
+    pub struct DictionaryEntityMappingWriter {
+        filename: String,
+        entries: Vec<(u64, String)>,
+    }
+
+    #[cfg(feature = "fs")]
+    impl DictionaryEntityMappingWriter {
+        pub fn new(filename: String) -> Self {
+            DictionaryEntityMappingWriter {
+                filename,
+                entries: Vec::new(),
             }
+        }
+    }
 
+    #[cfg(feature = "fs")]
+    impl EntityMappingWriter for DictionaryEntityMappingWriter {
+        fn put_data(&mut self, hash: u64, entity: &str) -> Result<(), CleoraError> {
+            self.entries.push((hash, entity.to_string()));
             Ok(())
         }
 
-        fn finish(&mut self) -> Result<(), io::Error> {
-            self.buf_writer.write_all(b"\n")?;
-            Ok(())
+        fn finish(&mut self) -> Result<(), CleoraError> {
+            let mut buf_writer = BufWriter::new(create_output(&self.filename)?);
+            write_dictionary(&mut buf_writer, &mut self.entries)
+                .and_then(|_| buf_writer.flush())
+                .map_err(|e| CleoraError::write_file(&self.filename, e))?;
+            buf_writer.get_mut().commit()
         }
     }
 
-    pub struct ParquetVectorPersistor {
+    /// Writes mapping as parquet file with `hash` and `entity` columns
+    #[cfg(feature = "fs")]
+    pub struct ParquetEntityMappingWriter {
+        filename: String,
         schema: Schema,
         options: WriteOptions,
         encodings: Vec<Vec<Encoding>>,
-        writer: FileWriter<Box<dyn Write>>,
-        timestamp: String,
+        /// Taken by `finish`, which commits the output
+        writer: Option<FileWriter<OutputFile>>,
+        chunk_size: usize,
+        hashes: Vec<Option<u64>>,
+        entities: Vec<Option<String>>,
     }
 
-    impl ParquetVectorPersistor {
-        pub fn new(
-            filename: String,
-            dimension: u16,
-        ) -> Self {
-            let mut fields: Vec<Field> = vec![
+    #[cfg(feature = "fs")]
+    impl ParquetEntityMappingWriter {
+        pub fn new(filename: String, chunk_size: usize) -> Result<Self, CleoraError> {
+            let schema = Schema::from(vec![
+                Field::new("hash", DataType::UInt64, false),
                 Field::new("entity", DataType::Utf8, false),
-                Field::new("occur_count", DataType::UInt32, false),
-                Field::new("datetime", DataType::Utf8, false),
-                //Field::new("datetime", DataType::Timestamp(TimeUnit::Second, None), false),
-            ];
-            (0..dimension).into_iter().for_each(|x| {
-                fields.push(Field::new(
-                    format!("f{}", x).as_str(),
-                    DataType::Float32,
-                    false,
-                ))
-            });
-
-            let schema = Schema::from(fields);
+            ]);
 
             let options = WriteOptions {
                 write_statistics: false,
@@ -183,106 +691,616 @@ pub mod embedding {
                 .map(|f| transverse(&f.data_type, |_| Encoding::Plain))
                 .collect();
 
-            // Create a new empty file
-            let now = Utc::now();
-            let f = now.format("%Y%m%dT%H%M%S").to_string();
-            let file_name = filename.replace(".out", &format!("_{}.parquet", f));
-            let file: Box<dyn Write> = if file_name.starts_with("s3://") {
-                Box::new(S3File::create(file_name))
-            } else {
-                Box::new(File::create(file_name).unwrap())
-            };
-
-            let writer = FileWriter::try_new(file, schema.clone(), options.clone()).unwrap();
+            let file = create_output(&filename)?;
+            let writer = FileWriter::try_new(file, schema.clone(), options)
+                .map_err(|e| CleoraError::parquet(&filename, e))?;
 
-            let utc: String = now.format("%F %X").to_string();
-
-            ParquetVectorPersistor {
+            Ok(ParquetEntityMappingWriter {
+                filename,
                 schema,
                 options,
                 encodings,
-                writer,
-                timestamp: utc,
-            }
+                writer: Some(writer),
+                chunk_size,
+                hashes: Vec::with_capacity(chunk_size),
+                entities: Vec::with_capacity(chunk_size),
+            })
         }
 
-        fn write_chunks(&mut self, chunk: Chunk<Box<dyn ArrowArray>>) -> ArrowResult<()> {
-            let iter = vec![Ok(chunk)];
+        fn write_chunk(&mut self) -> Result<(), CleoraError> {
+            if self.hashes.is_empty() {
+                return Ok(());
+            }
+
+            let hashes = std::mem::take(&mut self.hashes);
+            let entities = std::mem::take(&mut self.entities);
+            let chunk: Chunk<Box<dyn ArrowArray>> = Chunk::new(vec![
+                UInt64Array::from(hashes).to_boxed(),
+                Utf8Array::<i32>::from(entities).to_boxed(),
+            ]);
 
             let row_groups = RowGroupIterator::try_new(
-                iter.into_iter(),
+                vec![Ok(chunk)].into_iter(),
                 &self.schema,
                 self.options,
                 self.encodings.clone(),
-            )?;
+            )
+            .map_err(|e| CleoraError::parquet(&self.filename, e))?;
 
+            let filename = &self.filename;
+            let writer = self
+                .writer
+                .as_mut()
+                .expect("Entities written after the parquet file is finished");
             for group in row_groups {
-                self.writer.write(group?)?;
+                let group = group.map_err(|e| CleoraError::parquet(filename, e))?;
+                writer
+                    .write(group)
+                    .map_err(|e| CleoraError::parquet(filename, e))?;
             }
-
             Ok(())
         }
     }
 
-    impl EmbeddingPersistor for ParquetVectorPersistor {
-        fn put_metadata(&mut self, _entity_count: u32, _dimension: u16) -> Result<(), io::Error> {
+    /// Writes the footer of the parquet file and commits the output the writer owns
+    #[cfg(feature = "fs")]
+    pub(crate) fn finish_parquet(
+        filename: &str,
+        writer: Option<FileWriter<OutputFile>>,
+    ) -> Result<(), CleoraError> {
+        let mut writer = match writer {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        writer
+            .end(None)
+            .map_err(|e| CleoraError::parquet(filename, e))?;
+        writer.into_inner().commit()
+    }
+
+    #[cfg(feature = "fs")]
+    impl EntityMappingWriter for ParquetEntityMappingWriter {
+        fn put_data(&mut self, hash: u64, entity: &str) -> Result<(), CleoraError> {
+            self.hashes.push(Some(hash));
+            self.entities.push(Some(entity.to_string()));
+            if self.hashes.len() >= self.chunk_size {
+                self.write_chunk()?;
+            }
             Ok(())
         }
 
+        fn finish(&mut self) -> Result<(), CleoraError> {
+            self.write_chunk()?;
+            finish_parquet(&self.filename, self.writer.take())
+        }
+    }
+}
+
+pub mod embedding {
+    #[cfg(feature = "fs")]
+    use crate::configuration::EntitiesFormat;
+    use crate::error::CleoraError;
+    #[cfg(feature = "fs")]
+    use crate::io::{commit_file, create_output, create_partial_file, partial_path, OutputFile};
+    #[cfg(feature = "fs")]
+    use crate::persistence::embedding::memmap::OwnedMmapArrayViewMut;
+    #[cfg(feature = "fs")]
+    use crate::persistence::entity::finish_parquet;
+
+    #[cfg(feature = "fs")]
+    use ndarray::{s, Array};
+    use ndarray::{Array2, ArrayView1, ArrayView2};
+    #[cfg(feature = "fs")]
+    use ndarray_npy::write_zeroed_npy;
+    #[cfg(feature = "fs")]
+    use std::fs;
+    #[cfg(feature = "fs")]
+    use std::fs::{File, OpenOptions};
+    #[cfg(feature = "fs")]
+    use std::io;
+    #[cfg(feature = "fs")]
+    use std::io::{BufWriter, Write};
+    #[cfg(feature = "fs")]
+    use std::sync::mpsc::{self, SyncSender};
+    use std::sync::Arc;
+    #[cfg(feature = "fs")]
+    use std::thread::{self, JoinHandle};
+    #[cfg(feature = "fs")]
+    use zip::write::FileOptions;
+    #[cfg(feature = "fs")]
+    use zip::{CompressionMethod, ZipWriter};
+
+    #[cfg(feature = "fs")]
+    use arrow2::{
+        array::{Array as ArrowArray, Float32Array, Int64Array, UInt32Array, Utf8Array},
+        chunk::Chunk,
+        datatypes::{DataType, Field, Schema, TimeUnit},
+        io::parquet::write::{
+            transverse, CompressionOptions, Encoding, FileWriter, RowGroupIterator, Version,
+            WriteOptions,
+        },
+    };
+    #[cfg(feature = "fs")]
+    use chrono::prelude::*;
+
+    pub trait EmbeddingPersistor {
+        fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), CleoraError>;
+
         fn put_data(
             &mut self,
-            _entity: &str,
-            _occur_count: u32,
-            _vector: Vec<f32>,
-        ) -> Result<(), io::Error> {
-            Ok(())
-        }
+            entity: &str,
+            occur_count: u32,
+            vector: Vec<f32>,
+        ) -> Result<(), CleoraError>;
+
+        /// Writes consecutive rows, see `EmbeddingBatch`
+        fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError>;
 
+        /// Writes consecutive rows given as entities, their occurrence counts and column-major
+        /// vectors (`vectors[c][i]` is value `c` of the embedding of `entities[i]`)
+        #[deprecated(
+            note = "use put_data_batch, tuples are converted by `OwnedEmbeddingBatch::from(chunk)`"
+        )]
         fn put_data_chunk(
             &mut self,
             chunk: (Vec<String>, Vec<u32>, Vec<Vec<f32>>),
-        ) -> Result<(), io::Error> {
-            let entities: Vec<Option<String>> = chunk.0.into_iter().map(|x| Some(x)).collect();
-            let occur_counts: Vec<Option<u32>> = chunk.1.into_iter().map(|x| Some(x)).collect();
+        ) -> Result<(), CleoraError> {
+            self.put_data_batch(OwnedEmbeddingBatch::from(chunk).view())
+        }
 
-            let timestamps: Vec<Option<String>> = (0..entities.len())
-                .into_iter()
-                .map(|_x| Some(self.timestamp.clone()))
-                .collect();
+        /// Takes the whole embeddings over as the column-major file they were computed in, instead
+        /// of getting their rows, `entities` and `occur_counts` are of the rows in order. Called
+        /// before `put_metadata`. Returns `false` without writing anything if the persistor can't
+        /// use the file, the rows are written then.
+        fn put_column_major_file(
+            &mut self,
+            _file: &ColumnMajorFile,
+            _entities: Vec<Arc<str>>,
+            _occur_counts: Vec<u32>,
+        ) -> Result<bool, CleoraError> {
+            Ok(false)
+        }
 
-            let mut chunk_array = vec![
-                Utf8Array::<i32>::from(entities).to_boxed(),
-                UInt32Array::from(occur_counts).to_boxed(),
-                Utf8Array::<i32>::from(timestamps).to_boxed(),
-            ];
+        fn finish(&mut self) -> Result<(), CleoraError>;
+    }
 
-            chunk.2.into_iter().for_each(|x| {
-                chunk_array.push(
-                    Float32Array::from(
-                        x.into_iter().map(|e| Some(e)).collect::<Vec<Option<f32>>>(),
-                    )
-                    .to_boxed(),
-                )
+    /// Length of the npy header of two dimensional arrays, see `npy_header`
+    pub const NPY_HEADER_LEN: usize = 128;
+
+    /// File of the memory-mapped matrix: f32 values column after column, so value of row `r` and
+    /// column `c` is at `NPY_HEADER_LEN + 4 * (c * rows + r)`. The header space is left for the
+    /// npy header of Fortran order array, the file becomes numpy output without copying the rows.
+    #[derive(Debug, Clone)]
+    pub struct ColumnMajorFile {
+        pub path: String,
+        pub rows: usize,
+        pub cols: usize,
+    }
+
+    /// Consecutive rows of the embeddings written at once. Vectors are a row-major matrix, row `i`
+    /// is the embedding of `entities[i]`, so rows (or columns) are written as contiguous slices.
+    /// Borrows the buffers of the writer, persistors which keep the rows copy them (see
+    /// `to_owned`).
+    #[derive(Clone, Copy)]
+    pub struct EmbeddingBatch<'a> {
+        pub entities: &'a [Arc<str>],
+        pub occur_counts: &'a [u32],
+        pub vectors: ArrayView2<'a, f32>,
+    }
+
+    impl<'a> EmbeddingBatch<'a> {
+        /// Views row-major `values`, `dimension` values per entity
+        pub fn new(
+            entities: &'a [Arc<str>],
+            occur_counts: &'a [u32],
+            values: &'a [f32],
+            dimension: usize,
+        ) -> Self {
+            let vectors = ArrayView2::from_shape((entities.len(), dimension), values)
+                .expect("Batch values should have a row for every entity");
+            Self {
+                entities,
+                occur_counts,
+                vectors,
+            }
+        }
+
+        pub fn len(&self) -> usize {
+            self.entities.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.entities.is_empty()
+        }
+
+        pub fn rows(&self) -> impl Iterator<Item = (&str, u32, ArrayView1<'_, f32>)> {
+            self.entities
+                .iter()
+                .zip(self.occur_counts.iter())
+                .zip(self.vectors.outer_iter())
+                .map(|((entity, &occur_count), vector)| (&**entity, occur_count, vector))
+        }
+
+        /// Copies the rows, entities are shared
+        pub fn to_owned(&self) -> OwnedEmbeddingBatch {
+            OwnedEmbeddingBatch {
+                entities: self.entities.to_vec(),
+                occur_counts: self.occur_counts.to_vec(),
+                vectors: self.vectors.to_owned(),
+            }
+        }
+    }
+
+    /// Rows of `EmbeddingBatch` kept by their owner, e.g. sent to another thread
+    #[derive(Clone)]
+    pub struct OwnedEmbeddingBatch {
+        pub entities: Vec<Arc<str>>,
+        pub occur_counts: Vec<u32>,
+        pub vectors: Array2<f32>,
+    }
+
+    impl OwnedEmbeddingBatch {
+        pub fn view(&self) -> EmbeddingBatch<'_> {
+            EmbeddingBatch {
+                entities: &self.entities,
+                occur_counts: &self.occur_counts,
+                vectors: self.vectors.view(),
+            }
+        }
+    }
+
+    /// Rows of the deprecated `put_data_chunk` tuple: entities, their occurrence counts and
+    /// column-major vectors (`vectors[c][i]` is value `c` of the embedding of `entities[i]`)
+    impl From<(Vec<String>, Vec<u32>, Vec<Vec<f32>>)> for OwnedEmbeddingBatch {
+        fn from(chunk: (Vec<String>, Vec<u32>, Vec<Vec<f32>>)) -> Self {
+            let (entities, occur_counts, vectors) = chunk;
+            let dimension = vectors.len();
+            let mut values = Vec::with_capacity(entities.len() * dimension);
+            for i in 0..entities.len() {
+                values.extend(vectors.iter().map(|column| column[i]));
+            }
+            let vectors = Array2::from_shape_vec((entities.len(), dimension), values)
+                .expect("Chunk vectors should have a value for every entity");
+            Self {
+                entities: entities.into_iter().map(Arc::from).collect(),
+                occur_counts,
+                vectors,
+            }
+        }
+    }
+
+    #[deprecated(note = "renamed to EmbeddingBatch")]
+    pub type EmbeddingChunk<'a> = EmbeddingBatch<'a>;
+
+    #[cfg(feature = "fs")]
+    pub struct TextFileVectorPersistor {
+        filename: String,
+        buf_writer: BufWriter<OutputFile>,
+        produce_entity_occurrence_count: bool,
+    }
+
+    #[cfg(feature = "fs")]
+    impl TextFileVectorPersistor {
+        pub fn new(
+            filename: String,
+            produce_entity_occurrence_count: bool,
+        ) -> Result<Self, CleoraError> {
+            let file = create_output(&filename)?;
+            Ok(TextFileVectorPersistor {
+                filename,
+                buf_writer: BufWriter::new(file),
+                produce_entity_occurrence_count,
+            })
+        }
+
+        fn write_vector(
+            &mut self,
+            entity: &str,
+            occur_count: u32,
+            vector: ArrayView1<f32>,
+        ) -> Result<(), io::Error> {
+            self.buf_writer.write_all(b"\n")?;
+            self.buf_writer.write_all(entity.as_bytes())?;
+
+            if self.produce_entity_occurrence_count {
+                write!(&mut self.buf_writer, " {}", occur_count)?;
+            }
+
+            for &v in vector.iter() {
+                self.buf_writer.write_all(b" ")?;
+                let mut buf = ryu::Buffer::new(); // cheap op
+                self.buf_writer.write_all(buf.format_finite(v).as_bytes())?;
+            }
+
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    impl EmbeddingPersistor for TextFileVectorPersistor {
+        fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), CleoraError> {
+            write!(&mut self.buf_writer, "{} {}", entity_count, dimension)
+                .map_err(|e| CleoraError::write_file(&self.filename, e))
+        }
+
+        fn put_data(
+            &mut self,
+            entity: &str,
+            occur_count: u32,
+            vector: Vec<f32>,
+        ) -> Result<(), CleoraError> {
+            self.write_vector(entity, occur_count, ArrayView1::from(&vector))
+                .map_err(|e| CleoraError::write_file(&self.filename, e))
+        }
+
+        fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+            for (entity, occur_count, vector) in chunk.rows() {
+                self.write_vector(entity, occur_count, vector)
+                    .map_err(|e| CleoraError::write_file(&self.filename, e))?;
+            }
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), CleoraError> {
+            self.buf_writer
+                .write_all(b"\n")
+                .and_then(|_| self.buf_writer.flush())
+                .map_err(|e| CleoraError::write_file(&self.filename, e))?;
+            self.buf_writer.get_mut().commit()
+        }
+    }
+
+    /// Writes embeddings as `entity` column, optional `occur_count` and `datetime` (time of the
+    /// run) metadata columns and `f0`..`fN` columns of the vector values
+    #[cfg(feature = "fs")]
+    pub struct ParquetVectorPersistor {
+        filename: String,
+        schema: Schema,
+        options: WriteOptions,
+        encodings: Vec<Vec<Encoding>>,
+        /// Taken by `finish`, which commits the output
+        writer: Option<FileWriter<OutputFile>>,
+        produce_entity_occurrence_count: bool,
+        /// `UInt32`, or `Int64` for readers without unsigned integers
+        occur_count_type: DataType,
+        timestamp: Option<RunTimestamp>,
+    }
+
+    /// Time of the run written in every row
+    #[cfg(feature = "fs")]
+    enum RunTimestamp {
+        /// `datetime` text, e.g. `2022-10-01 12:00:00`
+        Datetime(String),
+        /// `event_timestamp` in microseconds since the epoch (UTC)
+        Event(i64),
+    }
+
+    #[cfg(feature = "fs")]
+    impl ParquetVectorPersistor {
+        pub fn new(
+            filename: String,
+            dimension: u16,
+            produce_entity_occurrence_count: bool,
+            output_datetime: bool,
+        ) -> Result<Self, CleoraError> {
+            let timestamp = if output_datetime {
+                Some(RunTimestamp::Datetime(
+                    Utc::now().format("%F %X").to_string(),
+                ))
+            } else {
+                None
+            };
+            Self::create(
+                filename,
+                dimension,
+                produce_entity_occurrence_count,
+                DataType::UInt32,
+                timestamp,
+            )
+        }
+
+        /// Like `new`, with `occur_count` written as signed 64-bit integers, for formats without
+        /// unsigned integers (like Delta Lake)
+        pub fn with_signed_counts(
+            filename: String,
+            dimension: u16,
+            produce_entity_occurrence_count: bool,
+            output_datetime: bool,
+        ) -> Result<Self, CleoraError> {
+            let timestamp = if output_datetime {
+                Some(RunTimestamp::Datetime(
+                    Utc::now().format("%F %X").to_string(),
+                ))
+            } else {
+                None
+            };
+            Self::create(
+                filename,
+                dimension,
+                produce_entity_occurrence_count,
+                DataType::Int64,
+                timestamp,
+            )
+        }
+
+        /// Like `new`, with an `event_timestamp` column (UTC timestamp of the run, in
+        /// microseconds) instead of `datetime`, as Feast file sources need
+        pub fn with_event_timestamp(
+            filename: String,
+            dimension: u16,
+            produce_entity_occurrence_count: bool,
+            event_timestamp: DateTime<Utc>,
+        ) -> Result<Self, CleoraError> {
+            let timestamp = RunTimestamp::Event(event_timestamp.timestamp_micros());
+            Self::create(
+                filename,
+                dimension,
+                produce_entity_occurrence_count,
+                DataType::UInt32,
+                Some(timestamp),
+            )
+        }
+
+        fn create(
+            filename: String,
+            dimension: u16,
+            produce_entity_occurrence_count: bool,
+            occur_count_type: DataType,
+            timestamp: Option<RunTimestamp>,
+        ) -> Result<Self, CleoraError> {
+            let mut fields: Vec<Field> = vec![Field::new("entity", DataType::Utf8, false)];
+            if produce_entity_occurrence_count {
+                fields.push(Field::new("occur_count", occur_count_type.clone(), false));
+            }
+            match timestamp {
+                Some(RunTimestamp::Datetime(_)) => {
+                    fields.push(Field::new("datetime", DataType::Utf8, false));
+                    //Field::new("datetime", DataType::Timestamp(TimeUnit::Second, None), false),
+                }
+                Some(RunTimestamp::Event(_)) => {
+                    fields.push(Field::new("event_timestamp", event_timestamp_type(), false))
+                }
+                None => {}
+            }
+            (0..dimension).into_iter().for_each(|x| {
+                fields.push(Field::new(
+                    format!("f{}", x).as_str(),
+                    DataType::Float32,
+                    false,
+                ))
             });
 
-            let chunk = Chunk::new(chunk_array);
-            self.write_chunks(chunk).unwrap();
+            let schema = Schema::from(fields);
+
+            let options = WriteOptions {
+                write_statistics: false,
+                compression: CompressionOptions::Snappy,
+                version: Version::V2,
+            };
+
+            let encodings = schema
+                .fields
+                .iter()
+                .map(|f| transverse(&f.data_type, |_| Encoding::Plain))
+                .collect();
+
+            // Create a new empty file
+            let file = create_output(&filename)?;
+
+            let writer = FileWriter::try_new(file, schema.clone(), options)
+                .map_err(|e| CleoraError::parquet(&filename, e))?;
+
+            Ok(ParquetVectorPersistor {
+                filename,
+                schema,
+                options,
+                encodings,
+                writer: Some(writer),
+                produce_entity_occurrence_count,
+                occur_count_type,
+                timestamp,
+            })
+        }
+
+        /// Columns of the written file
+        pub fn fields(&self) -> &[Field] {
+            &self.schema.fields
+        }
+
+        fn write_chunks(&mut self, chunk: Chunk<Box<dyn ArrowArray>>) -> Result<(), CleoraError> {
+            let iter = vec![Ok(chunk)];
+
+            let row_groups = RowGroupIterator::try_new(
+                iter.into_iter(),
+                &self.schema,
+                self.options,
+                self.encodings.clone(),
+            )
+            .map_err(|e| CleoraError::parquet(&self.filename, e))?;
+
+            let filename = &self.filename;
+            let writer = self
+                .writer
+                .as_mut()
+                .expect("Embeddings written after the parquet file is finished");
+            for group in row_groups {
+                let group = group.map_err(|e| CleoraError::parquet(filename, e))?;
+                writer
+                    .write(group)
+                    .map_err(|e| CleoraError::parquet(filename, e))?;
+            }
 
             Ok(())
         }
+    }
 
-        fn finish(&mut self) -> Result<(), io::Error> {
-            let _size = self.writer.end(None).unwrap();
+    #[cfg(feature = "fs")]
+    fn event_timestamp_type() -> DataType {
+        DataType::Timestamp(TimeUnit::Microsecond, Some(String::from("UTC")))
+    }
+
+    #[cfg(feature = "fs")]
+    impl EmbeddingPersistor for ParquetVectorPersistor {
+        fn put_metadata(&mut self, _entity_count: u32, _dimension: u16) -> Result<(), CleoraError> {
             Ok(())
         }
+
+        fn put_data(
+            &mut self,
+            _entity: &str,
+            _occur_count: u32,
+            _vector: Vec<f32>,
+        ) -> Result<(), CleoraError> {
+            Ok(())
+        }
+
+        fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+            let rows = chunk.len();
+            let mut chunk_array = vec![Utf8Array::<i32>::from_slice(chunk.entities).to_boxed()];
+
+            if self.produce_entity_occurrence_count {
+                chunk_array.push(match self.occur_count_type {
+                    DataType::Int64 => {
+                        let counts = chunk.occur_counts.iter().map(|&c| c as i64).collect();
+                        Int64Array::from_vec(counts).to_boxed()
+                    }
+                    _ => UInt32Array::from_slice(chunk.occur_counts).to_boxed(),
+                });
+            }
+            match self.timestamp.as_ref() {
+                Some(RunTimestamp::Datetime(timestamp)) => {
+                    let timestamps: Vec<Option<String>> =
+                        (0..rows).map(|_x| Some(timestamp.clone())).collect();
+                    chunk_array.push(Utf8Array::<i32>::from(timestamps).to_boxed());
+                }
+                Some(RunTimestamp::Event(micros)) => chunk_array.push(
+                    Int64Array::from_vec(vec![*micros; rows])
+                        .to(event_timestamp_type())
+                        .to_boxed(),
+                ),
+                None => {}
+            }
+
+            for column in chunk.vectors.columns() {
+                chunk_array.push(Float32Array::from_vec(column.to_vec()).to_boxed());
+            }
+
+            let chunk = Chunk::new(chunk_array);
+            self.write_chunks(chunk)
+        }
+
+        fn finish(&mut self) -> Result<(), CleoraError> {
+            finish_parquet(&self.filename, self.writer.take())
+        }
     }
 
+    #[cfg(feature = "fs")]
     mod memmap {
         use memmap::MmapMut;
         use ndarray::ArrayViewMut2;
         use std::fs::OpenOptions;
         use std::io;
-        use std::io::{Error, ErrorKind};
+        use std::io::Error;
         use std::ptr::drop_in_place;
 
         pub struct OwnedMmapArrayViewMut {
@@ -301,7 +1319,7 @@ pub mod embedding {
                 let mmap_ptr: *mut MmapMut = mmap as *mut _;
 
                 let mmap_data = ArrayViewMut2::<'static, f32>::view_mut_npy(mmap)
-                    .map_err(|_| Error::new(ErrorKind::Other, "Mmap view error"))?;
+                    .map_err(|_| Error::other("Mmap view error"))?;
 
                 Ok(Self {
                     mmap_ptr,
@@ -337,59 +1355,73 @@ pub mod embedding {
         }
     }
 
+    #[cfg(feature = "fs")]
     pub struct NpyPersistor {
-        entities: Vec<String>,
+        rows: usize,
+        entities: EntitiesWriter,
         occurences: Vec<u32>,
         array_file_name: String,
         array_file: File,
         array_write_context: Option<OwnedMmapArrayViewMut>,
-        occurences_buf: Option<BufWriter<File>>,
-        entities_buf: BufWriter<File>,
+        occurences_buf: Option<BufWriter<OutputFile>>,
     }
 
+    #[cfg(feature = "fs")]
     impl NpyPersistor {
-        pub fn new(filename: String, produce_entity_occurrence_count: bool) -> Self {
-            let entities_filename = format!("{}.entities", &filename);
-            let entities_buf = BufWriter::new(
-                File::create(&entities_filename)
-                    .unwrap_or_else(|_| panic!("Unable to create file: {}", &entities_filename)),
-            );
+        pub fn new(
+            filename: String,
+            produce_entity_occurrence_count: bool,
+            entities_format: EntitiesFormat,
+        ) -> Result<Self, CleoraError> {
+            let entities = EntitiesWriter::new(format!("{}.entities", &filename), entities_format)?;
 
             let occurences_filename = format!("{}.occurences", &filename);
             let occurences_buf = if produce_entity_occurrence_count {
-                Some(BufWriter::new(
-                    File::create(&occurences_filename).unwrap_or_else(|_| {
-                        panic!("Unable to create file: {}", &occurences_filename)
-                    }),
-                ))
+                Some(BufWriter::new(create_output(&occurences_filename)?))
             } else {
                 None
             };
 
+            // the array is memory-mapped, so outputs to S3 are staged locally
             let array_file_name = format!("{}.npy", &filename);
-            let array_file = File::create(&array_file_name)
-                .unwrap_or_else(|_| panic!("Unable to create file: {}", &array_file_name));
+            let array_file = create_partial_file(&array_file_name)?;
 
-            Self {
-                entities: vec![],
+            Ok(Self {
+                rows: 0,
+                entities,
                 occurences: vec![],
                 array_file_name,
                 array_file,
                 array_write_context: None,
                 occurences_buf,
-                entities_buf,
+            })
+        }
+
+        /// Files written for the embedding with given (`.out`) file name
+        pub fn output_files(filename: &str, produce_entity_occurrence_count: bool) -> Vec<String> {
+            let mut files = vec![
+                format!("{}.npy", filename),
+                format!("{}.entities", filename),
+            ];
+            if produce_entity_occurrence_count {
+                files.push(format!("{}.occurences", filename));
             }
+            files
         }
     }
 
+    #[cfg(feature = "fs")]
     impl EmbeddingPersistor for NpyPersistor {
-        fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), io::Error> {
+        fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), CleoraError> {
             write_zeroed_npy::<f32, _>(
                 &self.array_file,
                 [entity_count as usize, dimension as usize],
             )
-            .map_err(|_| Error::new(ErrorKind::Other, "Write zeroed npy error"))?;
-            self.array_write_context = Some(OwnedMmapArrayViewMut::new(&self.array_file_name)?);
+            .map_err(|e| CleoraError::npy(&self.array_file_name, e))?;
+            let array_write_context =
+                OwnedMmapArrayViewMut::new(&partial_path(&self.array_file_name))
+                    .map_err(|e| CleoraError::npy(&self.array_file_name, e))?;
+            self.array_write_context = Some(array_write_context);
             Ok(())
         }
 
@@ -398,7 +1430,7 @@ pub mod embedding {
             entity: &str,
             occur_count: u32,
             vector: Vec<f32>,
-        ) -> Result<(), io::Error> {
+        ) -> Result<(), CleoraError> {
             let array = &mut self
                 .array_write_context
                 .as_mut()
@@ -406,50 +1438,1291 @@ pub mod embedding {
                 .data_view();
 
             array
-                .slice_mut(s![self.entities.len(), ..])
+                .slice_mut(s![self.rows, ..])
                 .assign(&Array::from(vector));
-            self.entities.push(entity.to_owned());
+            self.rows += 1;
+            self.entities.put(entity)?;
             self.occurences.push(occur_count);
             Ok(())
         }
 
-        fn put_data_chunk(
+        fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+            let array = &mut self
+                .array_write_context
+                .as_mut()
+                .expect("Should be defined. Was put_metadata not called?")
+                .data_view();
+
+            array
+                .slice_mut(s![self.rows..self.rows + chunk.len(), ..])
+                .assign(&chunk.vectors);
+            self.rows += chunk.len();
+            for entity in chunk.entities.iter() {
+                self.entities.put(entity)?;
+            }
+            self.occurences.extend_from_slice(chunk.occur_counts);
+            Ok(())
+        }
+
+        fn put_column_major_file(
             &mut self,
-            chunk: (Vec<String>, Vec<u32>, Vec<Vec<f32>>),
-        ) -> Result<(), io::Error> {
-            let entities = chunk.0;
-            let occur_counts = chunk.1;
-            let vectors = &chunk.2;
+            file: &ColumnMajorFile,
+            entities: Vec<Arc<str>>,
+            occur_counts: Vec<u32>,
+        ) -> Result<bool, CleoraError> {
+            let array_path = partial_path(&self.array_file_name);
+            // files are moved only within the file system
+            if fs::rename(&file.path, &array_path).is_err() {
+                return Ok(false);
+            }
+            let header = npy_header("<f4", true, &[file.rows, file.cols]);
+            assert_eq!(NPY_HEADER_LEN, header.len());
+            OpenOptions::new()
+                .write(true)
+                .open(&array_path)
+                .and_then(|mut array| array.write_all(&header))
+                .map_err(|e| CleoraError::write_file(&array_path, e))?;
+
+            self.rows = entities.len();
+            for entity in entities.iter() {
+                self.entities.put(entity)?;
+            }
+            self.occurences = occur_counts;
+            Ok(true)
+        }
 
-            for i in 0..entities.len() {
-                let entity = &entities[i];
-                let occur_count = &occur_counts[i];
-                let mut vector: Vec<f32> = Vec::new();
+        fn finish(&mut self) -> Result<(), CleoraError> {
+            self.entities.finish()?;
+            write_occurences(
+                &self.array_file_name,
+                &self.occurences,
+                self.occurences_buf.as_mut(),
+            )?;
 
-                vectors.into_iter().for_each(|x| vector.push(x[i]));
-                self.put_data(entity.as_str(), *occur_count, vector)
-                    .unwrap();
+            // unmap the array before it's renamed (or uploaded)
+            self.array_write_context = None;
+            commit_file(&self.array_file_name)
+        }
+    }
+
+    /// Magic bytes starting the binary entities file
+    pub const ENTITIES_MAGIC: &[u8; 8] = b"CLEORAEN";
+
+    /// Version of the binary entities file layout
+    pub const ENTITIES_VERSION: u32 = 1;
+
+    /// Streams entities of numpy, raw and onnx outputs into the `.entities` sidecar in the order of
+    /// the array rows, so names aren't held in memory until the output is finished. Binary file
+    /// starts with the magic `CLEORAEN` and u32 version, followed by u32 byte length and UTF-8 bytes
+    /// of every entity (integers little-endian).
+    #[cfg(feature = "fs")]
+    pub(crate) struct EntitiesWriter {
+        filename: String,
+        buf: BufWriter<OutputFile>,
+        format: EntitiesFormat,
+        count: usize,
+    }
+
+    #[cfg(feature = "fs")]
+    impl EntitiesWriter {
+        pub(crate) fn new(filename: String, format: EntitiesFormat) -> Result<Self, CleoraError> {
+            let buf = BufWriter::new(create_output(&filename)?);
+            let mut writer = Self {
+                filename,
+                buf,
+                format,
+                count: 0,
+            };
+            if format == EntitiesFormat::Binary {
+                writer.write(ENTITIES_MAGIC)?;
+                writer.write(&ENTITIES_VERSION.to_le_bytes())?;
+            }
+            Ok(writer)
+        }
+
+        pub(crate) fn put(&mut self, entity: &str) -> Result<(), CleoraError> {
+            match self.format {
+                // the same layout as the pretty-printed list
+                EntitiesFormat::Json => {
+                    let separator: &[u8] = if self.count == 0 { b"[\n  " } else { b",\n  " };
+                    self.write(separator)?;
+                    serde_json::to_writer(&mut self.buf, entity)
+                        .map_err(|e| CleoraError::write_file(&self.filename, e.into()))?;
+                }
+                EntitiesFormat::Lines => {
+                    if entity.contains('\n') {
+                        return Err(CleoraError::invalid_embeddings(
+                            &self.filename,
+                            format!(
+                                "entity {:?} contains a new line, use json or binary entities format",
+                                entity
+                            ),
+                        ));
+                    }
+                    self.write(entity.as_bytes())?;
+                    self.write(b"\n")?;
+                }
+                EntitiesFormat::Binary => {
+                    self.write(&(entity.len() as u32).to_le_bytes())?;
+                    self.write(entity.as_bytes())?;
+                }
+            }
+            self.count += 1;
+            Ok(())
+        }
+
+        pub(crate) fn finish(&mut self) -> Result<(), CleoraError> {
+            if self.format == EntitiesFormat::Json {
+                let end: &[u8] = if self.count == 0 { b"[]" } else { b"\n]" };
+                self.write(end)?;
+            }
+            self.buf
+                .flush()
+                .map_err(|e| CleoraError::write_file(&self.filename, e))?;
+            self.buf.get_mut().commit()
+        }
+
+        fn write(&mut self, bytes: &[u8]) -> Result<(), CleoraError> {
+            self.buf
+                .write_all(bytes)
+                .map_err(|e| CleoraError::write_file(&self.filename, e))
+        }
+    }
+
+    /// Writes occurrences as npy array, next to the array of embeddings
+    #[cfg(feature = "fs")]
+    pub(crate) fn write_occurences(
+        filename: &str,
+        occurences: &[u32],
+        occurences_buf: Option<&mut BufWriter<OutputFile>>,
+    ) -> Result<(), CleoraError> {
+        use ndarray_npy::WriteNpyExt;
+
+        if let Some(occurences_buf) = occurences_buf {
+            let occur = ndarray::ArrayView1::from(occurences);
+            occur
+                .write_npy(&mut *occurences_buf)
+                .map_err(|e| CleoraError::npy(filename, e))?;
+            occurences_buf
+                .flush()
+                .map_err(|e| CleoraError::write_file(filename, e))?;
+            occurences_buf.get_mut().commit()?;
+        }
+        Ok(())
+    }
+
+    /// Names of the arrays in the `.npz` archive, as `numpy.load` lists them
+    pub const NPZ_EMBEDDINGS: &str = "embeddings";
+    pub const NPZ_ENTITIES: &str = "entities";
+    pub const NPZ_OCCURRENCES: &str = "occurrences";
+
+    /// Writes embeddings, entities and occurrences as arrays of a single (uncompressed) `.npz`
+    /// archive, so they can't get separated when moved around. Rows are streamed into the archive,
+    /// entities are a fixed-width unicode array (`<U`), padded to the longest name. Like in numpy
+    /// output, rows of transient entities (without the name) are left as zeros at the end.
+    #[cfg(feature = "fs")]
+    pub struct NpzPersistor {
+        filename: String,
+        archive: ZipWriter<BufWriter<File>>,
+        entities: Vec<Arc<str>>,
+        occurences: Vec<u32>,
+        produce_entity_occurrence_count: bool,
+        entity_count: usize,
+        dimension: usize,
+    }
+
+    #[cfg(feature = "fs")]
+    impl NpzPersistor {
+        pub fn new(
+            filename: String,
+            produce_entity_occurrence_count: bool,
+        ) -> Result<Self, CleoraError> {
+            // the archive is seeked, so outputs to S3 are staged locally
+            let filename = format!("{}.npz", filename);
+            let archive = ZipWriter::new(BufWriter::new(create_partial_file(&filename)?));
+            Ok(Self {
+                filename,
+                archive,
+                entities: vec![],
+                occurences: vec![],
+                produce_entity_occurrence_count,
+                entity_count: 0,
+                dimension: 0,
+            })
+        }
+
+        /// Files written for the embedding with given (`.out`) file name
+        pub fn output_files(filename: &str) -> Vec<String> {
+            vec![format!("{}.npz", filename)]
+        }
+
+        /// Starts the next array of the archive with its npy header
+        fn start_array(
+            &mut self,
+            name: &str,
+            descr: &str,
+            shape: &[usize],
+        ) -> Result<(), CleoraError> {
+            let options = FileOptions::default()
+                .compression_method(CompressionMethod::Stored)
+                .large_file(true);
+            self.archive
+                .start_file(format!("{}.npy", name), options)
+                .map_err(|e| CleoraError::npy(&self.filename, e))?;
+            self.write(&npy_header(descr, false, shape))
+        }
+
+        fn write(&mut self, bytes: &[u8]) -> Result<(), CleoraError> {
+            self.archive
+                .write_all(bytes)
+                .map_err(|e| CleoraError::write_file(&self.filename, e))
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    impl EmbeddingPersistor for NpzPersistor {
+        fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), CleoraError> {
+            self.entity_count = entity_count as usize;
+            self.dimension = dimension as usize;
+            self.start_array(NPZ_EMBEDDINGS, "<f4", &[self.entity_count, self.dimension])
+        }
+
+        fn put_data(
+            &mut self,
+            entity: &str,
+            occur_count: u32,
+            vector: Vec<f32>,
+        ) -> Result<(), CleoraError> {
+            let row: Vec<u8> = vector.iter().flat_map(|v| v.to_le_bytes()).collect();
+            self.write(&row)?;
+            self.entities.push(entity.into());
+            self.occurences.push(occur_count);
+            Ok(())
+        }
+
+        fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+            // rows of the chunk are contiguous, so they are written at once
+            let bytes: Vec<u8> = chunk.vectors.iter().flat_map(|v| v.to_le_bytes()).collect();
+            self.write(&bytes)?;
+            self.entities.extend_from_slice(chunk.entities);
+            self.occurences.extend_from_slice(chunk.occur_counts);
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), CleoraError> {
+            // the header promised rows of all entities
+            let missing_rows = self.entity_count.saturating_sub(self.entities.len());
+            self.write(&vec![0u8; missing_rows * self.dimension * 4])?;
+
+            let entities = std::mem::take(&mut self.entities);
+            let width = unicode_width(&entities);
+            self.start_array(NPZ_ENTITIES, &format!("<U{}", width), &[entities.len()])?;
+            write_unicode(&mut self.archive, &entities, width)
+                .map_err(|e| CleoraError::write_file(&self.filename, e))?;
+
+            if self.produce_entity_occurrence_count {
+                let occurences = std::mem::take(&mut self.occurences);
+                self.start_array(NPZ_OCCURRENCES, "<u4", &[occurences.len()])?;
+                let bytes: Vec<u8> = occurences.iter().flat_map(|o| o.to_le_bytes()).collect();
+                self.write(&bytes)?;
+            }
+
+            self.archive
+                .finish()
+                .map_err(|e| CleoraError::npy(&self.filename, e))?
+                .flush()
+                .map_err(|e| CleoraError::write_file(&self.filename, e))?;
+            commit_file(&self.filename)
+        }
+    }
+
+    /// Header of the npy (version 1.0) array of little-endian values in C order, padded so the
+    /// values start at a multiple of 64 bytes
+    #[cfg(feature = "fs")]
+    pub fn npy_header(descr: &str, fortran_order: bool, shape: &[usize]) -> Vec<u8> {
+        let shape = match shape {
+            [len] => format!("({},)", len),
+            _ => format!(
+                "({})",
+                shape
+                    .iter()
+                    .map(|d| d.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        let mut dict = format!(
+            "{{'descr': '{}', 'fortran_order': {}, 'shape': {}, }}",
+            descr,
+            if fortran_order { "True" } else { "False" },
+            shape
+        );
+        // magic, version and header length take 10 bytes, the header ends with a new line
+        let padding = 63 - (10 + dict.len()) % 64;
+        dict.push_str(&" ".repeat(padding));
+        dict.push('\n');
+
+        let mut header = b"\x93NUMPY\x01\x00".to_vec();
+        header.extend_from_slice(&(dict.len() as u16).to_le_bytes());
+        header.extend_from_slice(dict.as_bytes());
+        header
+    }
+
+    /// Width of the fixed-width unicode (`<U`) array of the strings, the longest string
+    #[cfg(feature = "fs")]
+    pub fn unicode_width<S: AsRef<str>>(values: &[S]) -> usize {
+        values
+            .iter()
+            .map(|value| value.as_ref().chars().count())
+            .max()
+            .unwrap_or(0)
+            .max(1)
+    }
+
+    /// Writes values of the unicode array: UTF-32 code points, padded with zeros to the width
+    #[cfg(feature = "fs")]
+    pub fn write_unicode<W: Write, S: AsRef<str>>(
+        writer: &mut W,
+        values: &[S],
+        width: usize,
+    ) -> Result<(), io::Error> {
+        let mut buf = Vec::with_capacity(width * 4);
+        for value in values.iter() {
+            buf.clear();
+            for c in value.as_ref().chars() {
+                buf.extend_from_slice(&(c as u32).to_le_bytes());
             }
+            buf.resize(width * 4, 0);
+            writer.write_all(&buf)?;
+        }
+        Ok(())
+    }
+
+    /// Magic bytes starting the raw embeddings file
+    pub const RAW_MAGIC: &[u8; 8] = b"CLEORAEM";
+
+    /// Version of the raw embeddings file layout
+    pub const RAW_VERSION: u32 = 1;
+
+    /// Size of the raw file header and alignment of every row, in bytes
+    pub const RAW_ALIGNMENT: usize = 64;
+
+    /// Byte order marker stored in the header, vectors are always written little-endian
+    #[cfg(feature = "fs")]
+    const RAW_LITTLE_ENDIAN: u8 = 0;
+
+    /// Writes embeddings as packed f32 rows, which can be memory-mapped by serving code without
+    /// parsing. The file starts with a 64-byte header (all integers little-endian):
+    ///
+    /// | offset | type    | field                                |
+    /// |--------|---------|--------------------------------------|
+    /// | 0      | [u8; 8] | magic `CLEORAEM`                     |
+    /// | 8      | u32     | version                              |
+    /// | 12     | u8      | byte order of the values, 0 = little |
+    /// | 16     | u32     | dimension                            |
+    /// | 20     | u32     | row stride in bytes                  |
+    /// | 24     | u64     | number of rows                       |
+    ///
+    /// Every row is padded with zeros to a multiple of 64 bytes, rows of transient entities (without
+    /// the name) are zeros left at the end. Entities (see `EntitiesWriter`) and occurrences (npy)
+    /// are written next to it, the same way as for numpy output.
+    #[cfg(feature = "fs")]
+    pub struct RawPersistor {
+        entities: EntitiesWriter,
+        occurences: Vec<u32>,
+        array_file_name: String,
+        array_buf: BufWriter<OutputFile>,
+        rows: u64,
+        written_rows: u64,
+        row_stride: usize,
+        row_padding: Vec<u8>,
+        occurences_buf: Option<BufWriter<OutputFile>>,
+    }
 
+    #[cfg(feature = "fs")]
+    impl RawPersistor {
+        pub fn new(
+            filename: String,
+            produce_entity_occurrence_count: bool,
+            entities_format: EntitiesFormat,
+        ) -> Result<Self, CleoraError> {
+            let entities = EntitiesWriter::new(format!("{}.entities", &filename), entities_format)?;
+
+            let occurences_filename = format!("{}.occurences", &filename);
+            let occurences_buf = if produce_entity_occurrence_count {
+                Some(BufWriter::new(create_output(&occurences_filename)?))
+            } else {
+                None
+            };
+
+            let array_file_name = format!("{}.bin", &filename);
+            let array_buf = BufWriter::new(create_output(&array_file_name)?);
+
+            Ok(Self {
+                entities,
+                occurences: vec![],
+                array_file_name,
+                array_buf,
+                rows: 0,
+                written_rows: 0,
+                row_stride: 0,
+                row_padding: vec![],
+                occurences_buf,
+            })
+        }
+
+        /// Files written for the embedding with given (`.out`) file name
+        pub fn output_files(filename: &str, produce_entity_occurrence_count: bool) -> Vec<String> {
+            let mut files = vec![
+                format!("{}.bin", filename),
+                format!("{}.entities", filename),
+            ];
+            if produce_entity_occurrence_count {
+                files.push(format!("{}.occurences", filename));
+            }
+            files
+        }
+
+        fn write_row(
+            &mut self,
+            entity: &str,
+            occur_count: u32,
+            vector: ArrayView1<f32>,
+        ) -> Result<(), CleoraError> {
+            let mut row = Vec::with_capacity(vector.len() * 4 + self.row_padding.len());
+            for v in vector.iter() {
+                row.extend_from_slice(&v.to_le_bytes());
+            }
+            row.extend_from_slice(&self.row_padding);
+            self.array_buf
+                .write_all(&row)
+                .map_err(|e| CleoraError::write_file(&self.array_file_name, e))?;
+            self.written_rows += 1;
+            self.entities.put(entity)?;
+            self.occurences.push(occur_count);
             Ok(())
         }
+    }
+
+    /// Length of the row in bytes, including padding
+    #[cfg(feature = "fs")]
+    fn raw_row_stride(dimension: usize) -> usize {
+        let row_len = dimension * std::mem::size_of::<f32>();
+        row_len.div_ceil(RAW_ALIGNMENT) * RAW_ALIGNMENT
+    }
 
-        fn finish(&mut self) -> Result<(), io::Error> {
-            use ndarray_npy::WriteNpyExt;
+    #[cfg(feature = "fs")]
+    impl EmbeddingPersistor for RawPersistor {
+        fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), CleoraError> {
+            let row_stride = raw_row_stride(dimension as usize);
+            self.row_stride = row_stride;
+            self.row_padding = vec![0u8; row_stride - dimension as usize * 4];
+            self.rows = entity_count as u64;
+
+            let mut header = [0u8; RAW_ALIGNMENT];
+            header[0..8].copy_from_slice(RAW_MAGIC);
+            header[8..12].copy_from_slice(&RAW_VERSION.to_le_bytes());
+            header[12] = RAW_LITTLE_ENDIAN;
+            header[16..20].copy_from_slice(&(dimension as u32).to_le_bytes());
+            header[20..24].copy_from_slice(&(row_stride as u32).to_le_bytes());
+            header[24..32].copy_from_slice(&(entity_count as u64).to_le_bytes());
+            self.array_buf
+                .write_all(&header)
+                .map_err(|e| CleoraError::write_file(&self.array_file_name, e))
+        }
 
-            serde_json::to_writer_pretty(&mut self.entities_buf, &self.entities)?;
+        fn put_data(
+            &mut self,
+            entity: &str,
+            occur_count: u32,
+            vector: Vec<f32>,
+        ) -> Result<(), CleoraError> {
+            self.write_row(entity, occur_count, ArrayView1::from(&vector))
+        }
 
-            if let Some(occurences_buf) = self.occurences_buf.as_mut() {
-                let occur = ndarray::ArrayView1::from(&self.occurences);
-                occur.write_npy(occurences_buf).map_err(|e| {
-                    Error::new(
-                        ErrorKind::Other,
-                        format!("Could not save occurences: {}", e),
-                    )
-                })?;
+        fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+            for (entity, occur_count, vector) in chunk.rows() {
+                self.write_row(entity, occur_count, vector)?;
             }
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), CleoraError> {
+            // rows of transient entities, promised by the header but never written
+            let missing = self.rows.saturating_sub(self.written_rows) as usize;
+            self.array_buf
+                .write_all(&vec![0u8; missing * self.row_stride])
+                .and_then(|_| self.array_buf.flush())
+                .map_err(|e| CleoraError::write_file(&self.array_file_name, e))?;
+            self.entities.finish()?;
+            write_occurences(
+                &self.array_file_name,
+                &self.occurences,
+                self.occurences_buf.as_mut(),
+            )?;
+            self.array_buf.get_mut().commit()
+        }
+    }
 
+    /// Magic bytes starting the compact embeddings file
+    pub const COMPACT_MAGIC: &[u8; 8] = b"CLEORACB";
+
+    /// Version of the compact embeddings file layout
+    pub const COMPACT_VERSION: u32 = 1;
+
+    /// Flag of the compact file header set when occurrence counts are written
+    pub const COMPACT_OCCURRENCES: u8 = 1;
+
+    /// Writes embeddings, entities and occurrences into a single binary file, so services can
+    /// memory-map it without Arrow or npy readers. The file starts with a 64-byte header (all
+    /// integers little-endian):
+    ///
+    /// | offset | type    | field                                   |
+    /// |--------|---------|-----------------------------------------|
+    /// | 0      | [u8; 8] | magic `CLEORACB`                        |
+    /// | 8      | u32     | version                                 |
+    /// | 12     | u8      | byte order of the values, 0 = little    |
+    /// | 13     | u8      | flags, 1 = occurrences are written      |
+    /// | 16     | u32     | dimension                               |
+    /// | 20     | u32     | row stride in bytes                     |
+    /// | 24     | u64     | number of rows                          |
+    ///
+    /// Rows follow like in raw output (f32 values padded with zeros to a multiple of 64 bytes).
+    /// The entity table starts right after them, at `64 + rows * stride`: u64 number of entities,
+    /// u32 occurrence count of every entity (if flagged) and u32 byte length and UTF-8 bytes of
+    /// every entity. Entity `i` is the embedding in row `i`, rows of transient entities (without
+    /// the name) are zeros left at the end. The entity table is kept in memory until the rows are
+    /// written.
+    #[cfg(feature = "fs")]
+    pub struct CompactPersistor {
+        file_name: String,
+        buf: BufWriter<OutputFile>,
+        rows: u64,
+        written_rows: u64,
+        row_stride: usize,
+        row_padding: Vec<u8>,
+        produce_entity_occurrence_count: bool,
+        occurences: Vec<u32>,
+        entities: Vec<u8>,
+    }
+
+    #[cfg(feature = "fs")]
+    impl CompactPersistor {
+        pub fn new(
+            filename: String,
+            produce_entity_occurrence_count: bool,
+        ) -> Result<Self, CleoraError> {
+            let file_name = format!("{}.cemb", &filename);
+            let buf = BufWriter::new(create_output(&file_name)?);
+            Ok(Self {
+                file_name,
+                buf,
+                rows: 0,
+                written_rows: 0,
+                row_stride: 0,
+                row_padding: vec![],
+                produce_entity_occurrence_count,
+                occurences: vec![],
+                entities: vec![],
+            })
+        }
+
+        /// Files written for the embedding with given (`.out`) file name
+        pub fn output_files(filename: &str) -> Vec<String> {
+            vec![format!("{}.cemb", filename)]
+        }
+
+        fn write_row(
+            &mut self,
+            entity: &str,
+            occur_count: u32,
+            vector: ArrayView1<f32>,
+        ) -> Result<(), CleoraError> {
+            let mut row = Vec::with_capacity(vector.len() * 4 + self.row_padding.len());
+            for v in vector.iter() {
+                row.extend_from_slice(&v.to_le_bytes());
+            }
+            row.extend_from_slice(&self.row_padding);
+            self.buf
+                .write_all(&row)
+                .map_err(|e| CleoraError::write_file(&self.file_name, e))?;
+            self.written_rows += 1;
+            self.entities
+                .extend_from_slice(&(entity.len() as u32).to_le_bytes());
+            self.entities.extend_from_slice(entity.as_bytes());
+            self.occurences.push(occur_count);
             Ok(())
         }
     }
+
+    #[cfg(feature = "fs")]
+    impl EmbeddingPersistor for CompactPersistor {
+        fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), CleoraError> {
+            let row_stride = raw_row_stride(dimension as usize);
+            self.row_stride = row_stride;
+            self.row_padding = vec![0u8; row_stride - dimension as usize * 4];
+            self.rows = entity_count as u64;
+
+            let mut header = [0u8; RAW_ALIGNMENT];
+            header[0..8].copy_from_slice(COMPACT_MAGIC);
+            header[8..12].copy_from_slice(&COMPACT_VERSION.to_le_bytes());
+            header[12] = RAW_LITTLE_ENDIAN;
+            if self.produce_entity_occurrence_count {
+                header[13] = COMPACT_OCCURRENCES;
+            }
+            header[16..20].copy_from_slice(&(dimension as u32).to_le_bytes());
+            header[20..24].copy_from_slice(&(row_stride as u32).to_le_bytes());
+            header[24..32].copy_from_slice(&self.rows.to_le_bytes());
+            self.buf
+                .write_all(&header)
+                .map_err(|e| CleoraError::write_file(&self.file_name, e))
+        }
+
+        fn put_data(
+            &mut self,
+            entity: &str,
+            occur_count: u32,
+            vector: Vec<f32>,
+        ) -> Result<(), CleoraError> {
+            self.write_row(entity, occur_count, ArrayView1::from(&vector))
+        }
+
+        fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+            for (entity, occur_count, vector) in chunk.rows() {
+                self.write_row(entity, occur_count, vector)?;
+            }
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), CleoraError> {
+            // rows of transient entities, promised by the header but never written
+            let missing = self.rows.saturating_sub(self.written_rows) as usize;
+            let mut table = vec![0u8; missing * self.row_stride];
+            table.extend_from_slice(&(self.occurences.len() as u64).to_le_bytes());
+            if self.produce_entity_occurrence_count {
+                for occur_count in self.occurences.iter() {
+                    table.extend_from_slice(&occur_count.to_le_bytes());
+                }
+            }
+            table.extend_from_slice(&self.entities);
+            self.buf
+                .write_all(&table)
+                .and_then(|_| self.buf.flush())
+                .map_err(|e| CleoraError::write_file(&self.file_name, e))?;
+            self.buf.get_mut().commit()
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    enum WriteRequest {
+        Metadata(u32, u16),
+        Data(String, u32, Vec<f32>),
+        Chunk(OwnedEmbeddingBatch),
+        ColumnMajorFile(ColumnMajorFile, Vec<Arc<str>>, Vec<u32>, mpsc::Sender<bool>),
+        Finish,
+    }
+
+    /// Runs the persistor on its own thread, fed by a bounded queue of chunks, so encoding,
+    /// compression and uploads of the output overlap with computing the next chunks. The queue
+    /// blocks the compute thread when the writer falls behind. Errors of the writer are returned
+    /// by the next call after they happen (or by `finish`).
+    #[cfg(feature = "fs")]
+    pub struct BackgroundPersistor {
+        sender: Option<SyncSender<WriteRequest>>,
+        handle: Option<JoinHandle<Result<(), CleoraError>>>,
+    }
+
+    #[cfg(feature = "fs")]
+    impl BackgroundPersistor {
+        /// Creates the persistor on the writer thread, so it doesn't have to be `Send` (e.g. the
+        /// memory map of numpy output)
+        pub fn spawn<F>(queue_size: usize, create_persistor: F) -> Result<Self, CleoraError>
+        where
+            F: FnOnce() -> Result<Box<dyn EmbeddingPersistor>, CleoraError> + Send + 'static,
+        {
+            let (sender, receiver) = mpsc::sync_channel(queue_size);
+            let (created_sender, created_receiver) = mpsc::channel();
+            let handle = thread::spawn(move || -> Result<(), CleoraError> {
+                let mut persistor = match create_persistor() {
+                    Ok(persistor) => persistor,
+                    Err(err) => {
+                        let _ = created_sender.send(Err(err));
+                        return Ok(());
+                    }
+                };
+                let _ = created_sender.send(Ok(()));
+                for request in receiver {
+                    match request {
+                        WriteRequest::Metadata(entity_count, dimension) => {
+                            persistor.put_metadata(entity_count, dimension)?
+                        }
+                        WriteRequest::Data(entity, occur_count, vector) => {
+                            persistor.put_data(&entity, occur_count, vector)?
+                        }
+                        WriteRequest::Chunk(chunk) => persistor.put_data_batch(chunk.view())?,
+                        WriteRequest::ColumnMajorFile(file, entities, occur_counts, taken) => {
+                            let _ = taken.send(persistor.put_column_major_file(
+                                &file,
+                                entities,
+                                occur_counts,
+                            )?);
+                        }
+                        WriteRequest::Finish => return persistor.finish(),
+                    }
+                }
+                // dropped without finishing, the output is left partial
+                Ok(())
+            });
+            created_receiver
+                .recv()
+                .expect("Writer thread should report the created persistor")?;
+            Ok(Self {
+                sender: Some(sender),
+                handle: Some(handle),
+            })
+        }
+
+        fn send(&mut self, request: WriteRequest) -> Result<(), CleoraError> {
+            let sender = self.sender.as_ref().expect("Writer is already finished");
+            match sender.send(request) {
+                Ok(()) => Ok(()),
+                // the writer stopped on an error
+                Err(_) => self.join(),
+            }
+        }
+
+        fn join(&mut self) -> Result<(), CleoraError> {
+            self.sender = None;
+            match self.handle.take() {
+                Some(handle) => handle.join().expect("Couldn't join on the writer thread"),
+                None => Ok(()),
+            }
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    impl EmbeddingPersistor for BackgroundPersistor {
+        fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), CleoraError> {
+            self.send(WriteRequest::Metadata(entity_count, dimension))
+        }
+
+        fn put_data(
+            &mut self,
+            entity: &str,
+            occur_count: u32,
+            vector: Vec<f32>,
+        ) -> Result<(), CleoraError> {
+            self.send(WriteRequest::Data(entity.to_owned(), occur_count, vector))
+        }
+
+        /// The rows are copied for the writer thread, the buffers of the batch are reused
+        fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+            self.send(WriteRequest::Chunk(chunk.to_owned()))
+        }
+
+        fn put_column_major_file(
+            &mut self,
+            file: &ColumnMajorFile,
+            entities: Vec<Arc<str>>,
+            occur_counts: Vec<u32>,
+        ) -> Result<bool, CleoraError> {
+            let (sender, taken) = mpsc::channel();
+            self.send(WriteRequest::ColumnMajorFile(
+                file.clone(),
+                entities,
+                occur_counts,
+                sender,
+            ))?;
+            match taken.recv() {
+                Ok(taken) => Ok(taken),
+                // the writer stopped on an error
+                Err(_) => self.join().map(|_| false),
+            }
+        }
+
+        fn finish(&mut self) -> Result<(), CleoraError> {
+            self.send(WriteRequest::Finish)?;
+            self.join()
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    impl Drop for BackgroundPersistor {
+        fn drop(&mut self) {
+            // the run failed before finishing, errors of the writer don't matter anymore
+            let _ = self.join();
+        }
+    }
+
+    /// Writes the same embeddings with several persistors (e.g. numpy and parquet outputs of one
+    /// propagation), every chunk is passed to all of them. Column-major files aren't taken over,
+    /// only one of the persistors could take them.
+    #[cfg(feature = "fs")]
+    pub struct FanOutPersistor {
+        persistors: Vec<Box<dyn EmbeddingPersistor>>,
+    }
+
+    #[cfg(feature = "fs")]
+    impl FanOutPersistor {
+        pub fn new(persistors: Vec<Box<dyn EmbeddingPersistor>>) -> Self {
+            Self { persistors }
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    impl EmbeddingPersistor for FanOutPersistor {
+        fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), CleoraError> {
+            for persistor in self.persistors.iter_mut() {
+                persistor.put_metadata(entity_count, dimension)?;
+            }
+            Ok(())
+        }
+
+        fn put_data(
+            &mut self,
+            entity: &str,
+            occur_count: u32,
+            vector: Vec<f32>,
+        ) -> Result<(), CleoraError> {
+            if let Some((last, others)) = self.persistors.split_last_mut() {
+                for persistor in others {
+                    persistor.put_data(entity, occur_count, vector.clone())?;
+                }
+                last.put_data(entity, occur_count, vector)?;
+            }
+            Ok(())
+        }
+
+        fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+            for persistor in self.persistors.iter_mut() {
+                persistor.put_data_batch(chunk)?;
+            }
+            Ok(())
+        }
+
+        /// Finishes every persistor, also after an error of another one, so their outputs aren't
+        /// left partial. Returns the first error.
+        fn finish(&mut self) -> Result<(), CleoraError> {
+            let mut result = Ok(());
+            for persistor in self.persistors.iter_mut() {
+                let finished = persistor.finish();
+                if result.is_ok() {
+                    result = finished;
+                }
+            }
+            result
+        }
+    }
+
+    #[cfg(all(test, feature = "fs"))]
+    mod tests {
+        use crate::configuration::EntitiesFormat;
+        use crate::edge_types::EmbeddingCollector;
+        use crate::error::CleoraError;
+        use crate::persistence::embedding::{
+            npy_header, raw_row_stride, BackgroundPersistor, ColumnMajorFile, CompactPersistor,
+            EmbeddingBatch, EmbeddingPersistor, FanOutPersistor, NpyPersistor, NpzPersistor,
+            OwnedEmbeddingBatch, ParquetVectorPersistor, RawPersistor, TextFileVectorPersistor,
+            NPY_HEADER_LEN, RAW_ALIGNMENT,
+        };
+        use cleora_embeddings::EmbeddingSet;
+        use std::sync::Arc;
+
+        #[test]
+        #[allow(deprecated)]
+        fn put_column_major_chunk() {
+            let mut collector = EmbeddingCollector::default();
+            collector.put_metadata(2, 3).unwrap();
+            let vectors = vec![vec![0.5, 0.0], vec![-1.0, 1.5], vec![2.0, -0.25]];
+            collector
+                .put_data_chunk((
+                    vec![String::from("a"), String::from("b")],
+                    vec![4, 1],
+                    vectors,
+                ))
+                .unwrap();
+            let entities: Vec<&str> = collector.entities.iter().map(|e| &**e).collect();
+            assert_eq!(vec!["a", "b"], entities);
+            assert_eq!(vec![4, 1], collector.occur_counts);
+            assert_eq!(vec![0.5, -1.0, 2.0, 0.0, 1.5, -0.25], collector.values);
+
+            let batch =
+                OwnedEmbeddingBatch::from((vec![String::from("c")], vec![2], vec![vec![1.0]]));
+            assert_eq!(1, batch.view().len());
+            assert_eq!(vec![1.0], batch.vectors.row(0).to_vec());
+        }
+
+        #[test]
+        fn view_batch_buffers() {
+            let entities: Vec<Arc<str>> = vec!["a".into(), "b".into()];
+            let occur_counts = [3, 1];
+            let values = [0.5, -1.0, 0.0, 1.5];
+            let batch = EmbeddingBatch::new(&entities, &occur_counts, &values, 2);
+            // the rows aren't copied
+            assert_eq!(values.as_ptr(), batch.vectors.as_ptr());
+            assert!(std::ptr::eq(entities.as_slice(), batch.entities));
+            let rows: Vec<(&str, u32, Vec<f32>)> = batch
+                .rows()
+                .map(|(entity, occur_count, vector)| (entity, occur_count, vector.to_vec()))
+                .collect();
+            assert_eq!(
+                vec![("a", 3, vec![0.5, -1.0]), ("b", 1, vec![0.0, 1.5])],
+                rows
+            );
+
+            // owned copies share the entities
+            let owned = batch.to_owned();
+            assert!(Arc::ptr_eq(&entities[1], &owned.entities[1]));
+            assert_eq!(batch.vectors, owned.view().vectors);
+            assert_eq!(&occur_counts[..], owned.view().occur_counts);
+        }
+
+        #[test]
+        fn raw_round_trip() {
+            let filename = std::env::temp_dir().join("cleora_raw_round_trip.out");
+            let filename = filename.to_str().unwrap().to_string();
+            let mut persistor =
+                RawPersistor::new(filename.clone(), true, EntitiesFormat::Json).unwrap();
+            // the third entity is transient, its row is left as zeros
+            persistor.put_metadata(3, 3).unwrap();
+            persistor.put_data("a", 1, vec![0.5, -1.0, 2.0]).unwrap();
+            persistor.put_data("b", 1, vec![0.0, 1.5, -0.25]).unwrap();
+            persistor.finish().unwrap();
+
+            let array_file_name = format!("{}.bin", filename);
+            let file_len = std::fs::metadata(&array_file_name).unwrap().len() as usize;
+            assert_eq!(RAW_ALIGNMENT * 4, file_len);
+
+            let embeddings = EmbeddingSet::open(&array_file_name).unwrap();
+            assert_eq!(3, embeddings.dimension());
+            assert_eq!(Some(&[0.0f32, 1.5, -0.25][..]), embeddings.get("b"));
+            assert_eq!(Some(&[1u32, 1][..]), embeddings.occurrences());
+            assert_eq!(vec![0.5, -1.0, 2.0, 0.0, 1.5, -0.25], embeddings.values());
+
+            std::fs::remove_file(array_file_name).unwrap();
+            std::fs::remove_file(format!("{}.entities", filename)).unwrap();
+            std::fs::remove_file(format!("{}.occurences", filename)).unwrap();
+        }
+
+        #[test]
+        fn compact_round_trip() {
+            let filename = std::env::temp_dir().join("cleora_compact_round_trip.out");
+            let filename = filename.to_str().unwrap().to_string();
+            let mut persistor = CompactPersistor::new(filename.clone(), true).unwrap();
+            // the third entity is transient, its row isn't written
+            persistor.put_metadata(3, 3).unwrap();
+            persistor.put_data("a", 4, vec![0.5, -1.0, 2.0]).unwrap();
+            persistor
+                .put_data_batch(EmbeddingBatch::new(
+                    &["żółw".into()],
+                    &[1],
+                    &[0.0, 1.5, -0.25],
+                    3,
+                ))
+                .unwrap();
+            persistor.finish().unwrap();
+
+            let file_name = format!("{}.cemb", filename);
+            let file_len = std::fs::metadata(&file_name).unwrap().len() as usize;
+            let table_len = 8 + 2 * 4 + 4 + 1 + 4 + "żółw".len();
+            assert_eq!(RAW_ALIGNMENT * 4 + table_len, file_len);
+
+            let embeddings = EmbeddingSet::open(&file_name).unwrap();
+            assert_eq!(vec!["a", "żółw"], embeddings.entities());
+            assert_eq!(Some(&[0.0f32, 1.5, -0.25][..]), embeddings.get("żółw"));
+            assert_eq!(Some(&[4u32, 1][..]), embeddings.occurrences());
+
+            let mut persistor = CompactPersistor::new(filename, false).unwrap();
+            persistor.put_metadata(1, 3).unwrap();
+            persistor.put_data("a", 4, vec![0.5, -1.0, 2.0]).unwrap();
+            persistor.finish().unwrap();
+            let embeddings = EmbeddingSet::open(&file_name).unwrap();
+            assert_eq!(None, embeddings.occurrences());
+            assert_eq!(Some(&[0.5f32, -1.0, 2.0][..]), embeddings.get("a"));
+            std::fs::remove_file(file_name).unwrap();
+        }
+
+        #[test]
+        fn write_chunks() {
+            let filename = std::env::temp_dir().join("cleora_write_chunks.out");
+            let filename = filename.to_str().unwrap().to_string();
+            let entities: Vec<Arc<str>> = vec!["a".into(), "b".into(), "c".into()];
+            let occur_counts = [3, 1, 2];
+            let values = [0.5, -1.0, 0.0, 1.5, 2.0, 0.25];
+            let chunks = [
+                EmbeddingBatch::new(&entities[..2], &occur_counts[..2], &values[..4], 2),
+                EmbeddingBatch::new(&entities[2..], &occur_counts[2..], &values[4..], 2),
+            ];
+
+            let mut persistor =
+                NpyPersistor::new(filename.clone(), true, EntitiesFormat::Json).unwrap();
+            persistor.put_metadata(3, 2).unwrap();
+            for chunk in chunks {
+                persistor.put_data_batch(chunk).unwrap();
+            }
+            persistor.finish().unwrap();
+            let embeddings = EmbeddingSet::open(&filename).unwrap();
+            assert_eq!(vec!["a", "b", "c"], embeddings.entities());
+            assert_eq!(Some(&[3u32, 1, 2][..]), embeddings.occurrences());
+            assert_eq!(vec![0.5, -1.0, 0.0, 1.5, 2.0, 0.25], embeddings.values());
+            for extension in ["npy", "entities", "occurences"] {
+                std::fs::remove_file(format!("{}.{}", filename, extension)).unwrap();
+            }
+
+            let mut persistor = TextFileVectorPersistor::new(filename.clone(), true).unwrap();
+            persistor.put_metadata(3, 2).unwrap();
+            for chunk in chunks {
+                persistor.put_data_batch(chunk).unwrap();
+            }
+            persistor.finish().unwrap();
+            assert_eq!(
+                "3 2\na 3 0.5 -1.0\nb 1 0.0 1.5\nc 2 2.0 0.25\n",
+                std::fs::read_to_string(&filename).unwrap()
+            );
+            std::fs::remove_file(filename).unwrap();
+        }
+
+        #[test]
+        fn write_in_background() {
+            let filename = std::env::temp_dir().join("cleora_write_in_background.out");
+            let filename = filename.to_str().unwrap().to_string();
+            let output = filename.clone();
+            let mut persistor = BackgroundPersistor::spawn(1, move || {
+                Ok(Box::new(TextFileVectorPersistor::new(output, false)?))
+            })
+            .unwrap();
+            persistor.put_metadata(3, 1).unwrap();
+            persistor.put_data("a", 1, vec![0.5]).unwrap();
+            for entity in ["b", "c"] {
+                persistor
+                    .put_data_batch(EmbeddingBatch::new(&[entity.into()], &[1], &[1.0], 1))
+                    .unwrap();
+            }
+            persistor.finish().unwrap();
+            assert_eq!(
+                "3 1\na 0.5\nb 1.0\nc 1.0\n",
+                std::fs::read_to_string(&filename).unwrap()
+            );
+            std::fs::remove_file(&filename).unwrap();
+
+            // the error of the writer is returned to the compute thread
+            let output = filename.clone();
+            let mut persistor = BackgroundPersistor::spawn(1, move || {
+                Ok(Box::new(RawPersistor::new(
+                    output,
+                    false,
+                    EntitiesFormat::Lines,
+                )?))
+            })
+            .unwrap();
+            persistor.put_metadata(1, 1).unwrap();
+            let result = persistor
+                .put_data("a\nb", 1, vec![0.5])
+                .and_then(|_| persistor.finish());
+            assert!(result.is_err());
+            std::fs::remove_file(format!("{}.bin.partial", filename)).unwrap();
+            std::fs::remove_file(format!("{}.entities.partial", filename)).unwrap();
+
+            let missing_dir = std::env::temp_dir().join("cleora_missing_dir/embeddings.out");
+            let missing_dir = missing_dir.to_str().unwrap().to_string();
+            assert!(BackgroundPersistor::spawn(1, move || {
+                Ok(Box::new(TextFileVectorPersistor::new(missing_dir, false)?))
+            })
+            .is_err());
+        }
+
+        #[test]
+        fn fan_out_chunks() {
+            let filename = std::env::temp_dir().join("cleora_fan_out_chunks.out");
+            let filename = filename.to_str().unwrap().to_string();
+            let mut persistor = FanOutPersistor::new(vec![
+                Box::new(TextFileVectorPersistor::new(filename.clone(), false).unwrap()),
+                Box::new(NpyPersistor::new(filename.clone(), false, EntitiesFormat::Json).unwrap()),
+            ]);
+            persistor.put_metadata(2, 2).unwrap();
+            persistor.put_data("a", 1, vec![0.5, -1.0]).unwrap();
+            persistor
+                .put_data_batch(EmbeddingBatch::new(&["b".into()], &[1], &[2.0, 0.25], 2))
+                .unwrap();
+            persistor.finish().unwrap();
+
+            assert_eq!(
+                "2 2\na 0.5 -1.0\nb 2.0 0.25\n",
+                std::fs::read_to_string(&filename).unwrap()
+            );
+            let embeddings = EmbeddingSet::open(format!("{}.npy", filename)).unwrap();
+            assert_eq!(vec!["a", "b"], embeddings.entities());
+            assert_eq!(vec![0.5, -1.0, 2.0, 0.25], embeddings.values());
+            std::fs::remove_file(&filename).unwrap();
+            for extension in ["npy", "entities"] {
+                std::fs::remove_file(format!("{}.{}", filename, extension)).unwrap();
+            }
+        }
+
+        #[test]
+        fn move_column_major_file() {
+            let dir = std::env::temp_dir();
+            let matrix = dir.join("cleora_column_major_matrix");
+            let matrix = matrix.to_str().unwrap().to_string();
+            let mut bytes = vec![0u8; NPY_HEADER_LEN];
+            for value in [0.5f32, -1.0, 2.0, 0.0, 1.5, -0.25] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            std::fs::write(&matrix, bytes).unwrap();
+            let file = ColumnMajorFile {
+                path: matrix.clone(),
+                rows: 3,
+                cols: 2,
+            };
+
+            let filename = dir.join("cleora_column_major_output.out");
+            let filename = filename.to_str().unwrap().to_string();
+            let mut persistor =
+                NpyPersistor::new(filename.clone(), true, EntitiesFormat::Json).unwrap();
+            let entities = vec!["a".into(), "b".into(), "c".into()];
+            assert!(persistor
+                .put_column_major_file(&file, entities, vec![1, 2, 3])
+                .unwrap());
+            persistor.finish().unwrap();
+            assert!(!std::path::Path::new(&matrix).exists());
+
+            let embeddings = EmbeddingSet::open(&filename).unwrap();
+            assert_eq!(Some(&[-1.0f32, 1.5][..]), embeddings.get("b"));
+            assert_eq!(vec![0.5, 0.0, -1.0, 1.5, 2.0, -0.25], embeddings.values());
+            assert_eq!(Some(&[1u32, 2, 3][..]), embeddings.occurrences());
+            for extension in ["npy", "entities", "occurences"] {
+                std::fs::remove_file(format!("{}.{}", filename, extension)).unwrap();
+            }
+
+            // other persistors write the rows
+            let mut persistor = TextFileVectorPersistor::new(filename.clone(), false).unwrap();
+            let taken = persistor.put_column_major_file(&file, vec![], vec![]);
+            assert!(!taken.unwrap());
+            std::fs::remove_file(format!("{}.partial", filename)).unwrap();
+        }
+
+        #[test]
+        fn parquet_metadata_columns() {
+            let filename = std::env::temp_dir().join("cleora_parquet_metadata.parquet");
+            let filename = filename.to_str().unwrap().to_string();
+            let column_names = |persistor: &ParquetVectorPersistor| -> Vec<String> {
+                let fields = persistor.schema.fields.iter();
+                fields.map(|f| f.name.clone()).collect()
+            };
+
+            let persistor = ParquetVectorPersistor::new(filename.clone(), 2, true, true).unwrap();
+            assert_eq!(
+                vec!["entity", "occur_count", "datetime", "f0", "f1"],
+                column_names(&persistor)
+            );
+
+            let mut persistor =
+                ParquetVectorPersistor::new(filename.clone(), 2, false, false).unwrap();
+            assert_eq!(vec!["entity", "f0", "f1"], column_names(&persistor));
+            persistor
+                .put_data_batch(EmbeddingBatch::new(
+                    &["a".into(), "b".into()],
+                    &[1, 2],
+                    &[0.5, -1.0, 1.0, 0.0],
+                    2,
+                ))
+                .unwrap();
+            persistor.finish().unwrap();
+            std::fs::remove_file(filename).unwrap();
+        }
+
+        #[test]
+        fn entities_formats() {
+            let filename = std::env::temp_dir().join("cleora_entities_formats.out");
+            let filename = filename.to_str().unwrap().to_string();
+            let entities_file_name = format!("{}.entities", filename);
+            for format in [
+                EntitiesFormat::Json,
+                EntitiesFormat::Lines,
+                EntitiesFormat::Binary,
+            ] {
+                let mut persistor = RawPersistor::new(filename.clone(), false, format).unwrap();
+                persistor.put_metadata(2, 1).unwrap();
+                persistor.put_data("a b", 1, vec![0.5]).unwrap();
+                persistor.put_data("żółw", 1, vec![-1.0]).unwrap();
+                persistor.finish().unwrap();
+
+                let embeddings = EmbeddingSet::open(format!("{}.bin", filename)).unwrap();
+                assert_eq!(vec!["a b", "żółw"], embeddings.entities());
+                assert_eq!(Some(&[-1.0f32][..]), embeddings.get("żółw"));
+                if format == EntitiesFormat::Json {
+                    let json = serde_json::to_string_pretty(&["a b", "żółw"]).unwrap();
+                    assert_eq!(json, std::fs::read_to_string(&entities_file_name).unwrap());
+                }
+            }
+
+            let mut persistor =
+                RawPersistor::new(filename.clone(), false, EntitiesFormat::Lines).unwrap();
+            persistor.put_metadata(1, 1).unwrap();
+            assert!(persistor.put_data("a\nb", 1, vec![0.5]).is_err());
+            std::fs::remove_file(format!("{}.bin.partial", filename)).unwrap();
+            std::fs::remove_file(format!("{}.partial", entities_file_name)).unwrap();
+            std::fs::remove_file(format!("{}.bin", filename)).unwrap();
+            std::fs::remove_file(entities_file_name).unwrap();
+        }
+
+        #[test]
+        fn npz_round_trip() {
+            let filename = std::env::temp_dir().join("cleora_npz_round_trip.out");
+            let filename = filename.to_str().unwrap().to_string();
+            let mut persistor = NpzPersistor::new(filename.clone(), true).unwrap();
+            // the third entity is transient, its row is left as zeros
+            persistor.put_metadata(3, 2).unwrap();
+            persistor.put_data("a", 2, vec![0.5, -1.0]).unwrap();
+            persistor.put_data("żółw", 1, vec![0.0, 1.5]).unwrap();
+            persistor.finish().unwrap();
+
+            let archive_file_name = format!("{}.npz", filename);
+            let embeddings = EmbeddingSet::open(&filename).unwrap();
+            assert_eq!(2, embeddings.len());
+            assert_eq!(Some(&[0.0f32, 1.5][..]), embeddings.get("żółw"));
+            assert_eq!(Some(&[2u32, 1][..]), embeddings.occurrences());
+            assert_eq!(vec![0.5, -1.0, 0.0, 1.5], embeddings.values());
+            std::fs::remove_file(archive_file_name).unwrap();
+        }
+
+        #[test]
+        fn pad_npy_header() {
+            let header = npy_header("<f4", false, &[3, 2]);
+            assert_eq!(128, header.len());
+            assert_eq!(b"\x93NUMPY\x01\x00", &header[..8]);
+            assert_eq!(118, u16::from_le_bytes([header[8], header[9]]));
+            let dict = String::from_utf8_lossy(&header[10..]);
+            assert!(dict.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (3, 2), }"));
+            assert!(dict.ends_with(" \n"));
+            assert!(String::from_utf8_lossy(&npy_header("<u4", false, &[3])).contains("(3,)"));
+        }
+
+        #[test]
+        fn align_raw_rows() {
+            assert_eq!(64, raw_row_stride(1));
+            assert_eq!(64, raw_row_stride(16));
+            assert_eq!(128, raw_row_stride(17));
+        }
+
+        #[test]
+        fn missing_output_directory() {
+            let dir = std::env::temp_dir().join("cleora_missing_output_directory");
+            let filename = dir.join("emb.out").to_str().unwrap().to_string();
+            let result = TextFileVectorPersistor::new(filename.clone(), true);
+            // the file is created under a partial name first
+            let dir = dir.to_str().unwrap();
+            assert!(
+                matches!(result, Err(CleoraError::CreateFile { path, .. }) if path.starts_with(dir))
+            );
+            let result = ParquetVectorPersistor::new(filename.clone(), 2, true, false);
+            assert!(matches!(result, Err(CleoraError::CreateFile { .. })));
+            let error = NpyPersistor::new(filename, true, EntitiesFormat::Json)
+                .err()
+                .unwrap();
+            assert!(error
+                .to_string()
+                .contains("Check that the output directory exists"));
+        }
+    }
 }
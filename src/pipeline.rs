@@ -1,78 +1,207 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::io::Read;
-use crate::configuration::{Column, Configuration, FileType, OutputFormat};
-use crate::embedding::{calculate_embeddings, calculate_embeddings_mmap};
-use crate::entity::{EntityProcessor, SMALL_VECTOR_SIZE};
-use crate::io::S3File;
+use crate::alias::Aliases;
+use crate::cardinality::CardinalityMonitor;
+use crate::configuration::{
+    Column, Configuration, EntitiesFormat, EntityMappingFormat, FileType, MalformedRows,
+    Normalization, OutputFormat, DEFAULT_DELTA_OUTPUT_TEMPLATE, DEFAULT_DUCKDB_OUTPUT_TEMPLATE,
+    DEFAULT_FEAST_OUTPUT_TEMPLATE, DEFAULT_FLIGHT_OUTPUT_TEMPLATE, DEFAULT_OUTPUT_TEMPLATE,
+    DEFAULT_PARQUET_OUTPUT_TEMPLATE, DEFAULT_SQLITE_OUTPUT_TEMPLATE,
+};
+#[cfg(feature = "delta")]
+use crate::delta::DeltaPersistor;
+#[cfg(feature = "duckdb")]
+use crate::duckdb_output::DuckDbPersistor;
+use crate::edge_types::{self, EmbeddingCollector};
+use crate::embedding::{
+    calculate_embeddings, calculate_embeddings_mmap, fit_features, PriorEmbeddings,
+    SnapshotPersistorFactory, TrainingSummary,
+};
+use crate::entity::{
+    entity_limits, expected_collisions, parse_tsv_line, Delimiters, EdgeSampler, EntityProcessor,
+    SMALL_VECTOR_SIZE,
+};
+use crate::error::CleoraError;
+use crate::feast::FeastPersistor;
+#[cfg(feature = "flight")]
+use crate::flight::FlightPersistor;
+use crate::graph_import::{parse_edge_line, GraphMlEdges};
+use crate::interrupt;
+use crate::io::{create_output, S3File};
+use crate::knn;
+use crate::loader::{load_embeddings, Embeddings};
+use crate::manifest::{self, manifest_file_name, InputFile, RunInfo};
+use crate::matrix_export::{export_matrix, matrix_file_name, matrix_output_files};
+use crate::onnx::OnnxPersistor;
+use crate::pca::Projection;
 use crate::persistence::embedding::{
-    EmbeddingPersistor, NpyPersistor, ParquetVectorPersistor, TextFileVectorPersistor,
+    BackgroundPersistor, ColumnMajorFile, CompactPersistor, EmbeddingBatch, EmbeddingPersistor,
+    FanOutPersistor, NpyPersistor, NpzPersistor, ParquetVectorPersistor, RawPersistor,
+    TextFileVectorPersistor,
+};
+use crate::persistence::entity::{
+    DictionaryEntityMappingWriter, EntityMappingPersistor, EntityMappingWriter,
+    InMemoryEntityMappingPersistor, ParquetEntityMappingWriter, TsvEntityMappingWriter,
+};
+use crate::profile::{self, StageReport};
+use crate::projector::ProjectorPersistor;
+use crate::sparse_matrix::{
+    configured_sparse_matrices, select_column_pairs, SparseMatrix, SparseMatrixReader,
 };
-use crate::persistence::entity::InMemoryEntityMappingPersistor;
-use crate::sparse_matrix::{create_sparse_matrices, SparseMatrix};
+#[cfg(feature = "sqlite")]
+use crate::sqlite_output::SqlitePersistor;
+use crate::tags;
 use bus::Bus;
+use chrono::Utc;
 use log::{error, info, warn};
+use memchr::{memchr, memchr_iter};
+use memmap::Mmap;
+use ndarray::{s, ArrayView2};
+use rayon::prelude::*;
 use simdjson_rust::dom;
 use smallvec::{smallvec, SmallVec};
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::io::{BufRead, BufReader};
+use std::iter;
+use std::ops::Range;
+use std::path::Path;
+use std::str;
+use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// Sparse matrices of every edge type for every column pair, one after another for the column
+/// pair. Matrices are left as they are if there are no edge types.
+fn edge_type_sparse_matrices(
+    config: &Configuration,
+    sparse_matrices: Vec<SparseMatrix>,
+) -> Vec<SparseMatrix> {
+    if config.edge_types.is_empty() {
+        return sparse_matrices;
+    }
+    let mut edge_type_matrices = Vec::new();
+    for sparse_matrix in sparse_matrices {
+        for (index, label) in config.edge_types.iter().enumerate() {
+            let mut edge_type_matrix = SparseMatrix::new(
+                sparse_matrix.col_a_id,
+                sparse_matrix.col_a_name.clone(),
+                sparse_matrix.col_b_id,
+                sparse_matrix.col_b_name.clone(),
+            );
+            edge_type_matrix.set_edge_type(index as u64, label.clone());
+            edge_type_matrices.push(edge_type_matrix);
+        }
+    }
+    edge_type_matrices
+}
 
 /// Create SparseMatrix'es based on columns config. Every SparseMatrix operates in separate
 /// thread. EntityProcessor reads data in main thread and broadcast cartesian products
-/// to SparseMatrix'es.
+/// to SparseMatrix'es. With entity limits (`--min-entity-count`, `--max-entities`) the inputs are
+/// read twice: the first pass only counts the entities, so the matrices are built without the
+/// entities left out by the limits. Fails on malformed rows with `--malformed-rows fail`.
 pub fn build_graphs(
     config: &Configuration,
     in_memory_entity_mapping_persistor: Arc<InMemoryEntityMappingPersistor>,
-) -> Vec<SparseMatrix> {
-    let sparse_matrices = create_sparse_matrices(&config.columns);
+) -> Result<Vec<SparseMatrix>, CleoraError> {
+    let sparse_matrices = configured_sparse_matrices(config);
     dbg!(&sparse_matrices);
+    let mut sparse_matrices = edge_type_sparse_matrices(config, sparse_matrices);
+    for sparse_matrix in sparse_matrices.iter_mut() {
+        sparse_matrix.configure(config);
+    }
+
+    let min_entity_count = config.min_entity_count;
+    let entity_limits = entity_limits(config, &in_memory_entity_mapping_persistor);
+    let counting = min_entity_count > 1 || !entity_limits.is_empty();
+    let mut cardinality_monitor = CardinalityMonitor::new(config);
+    if counting {
+        let (counted, count) = handle_inputs(
+            config,
+            &in_memory_entity_mapping_persistor,
+            sparse_matrices,
+            cardinality_monitor.as_mut(),
+            SparseMatrix::count_pair,
+            move |sparse_matrix| sparse_matrix.set_limits(min_entity_count, &entity_limits),
+        )?;
+        count.stop();
+        sparse_matrices = counted;
+    }
+
+    let (sparse_matrices, build) = handle_inputs(
+        config,
+        &in_memory_entity_mapping_persistor,
+        sparse_matrices,
+        if counting {
+            None
+        } else {
+            cardinality_monitor.as_mut()
+        },
+        SparseMatrix::handle_pair,
+        SparseMatrix::finish,
+    )?;
+    let sparse_matrices = if config.auto_pairs {
+        select_column_pairs(sparse_matrices)
+    } else {
+        sparse_matrices
+    };
+    let entries = sparse_matrices
+        .iter()
+        .map(|sparse_matrix| sparse_matrix.get_number_of_entries() as u64)
+        .sum();
+    build.finish(None, entries, "entries");
+
+    report_collisions(config, &in_memory_entity_mapping_persistor)?;
 
+    Ok(sparse_matrices)
+}
+
+/// Reads the inputs once and passes the combinations to the matrices, every one of them handles
+/// them (`handle`) and is finished (`finish`) in its own thread. The cardinality monitor is
+/// checked when the inputs are read. Returns the matrices with the build stage, started when the
+/// inputs are read.
+fn handle_inputs<F>(
+    config: &Configuration,
+    in_memory_entity_mapping_persistor: &Arc<InMemoryEntityMappingPersistor>,
+    sparse_matrices: Vec<SparseMatrix>,
+    mut cardinality_monitor: Option<&mut CardinalityMonitor>,
+    handle: fn(&mut SparseMatrix, &[u64]),
+    finish: F,
+) -> Result<(Vec<SparseMatrix>, profile::Stage), CleoraError>
+where
+    F: Fn(&mut SparseMatrix) + Clone + Send + 'static,
+{
     let mut bus: Bus<SmallVec<[u64; SMALL_VECTOR_SIZE]>> = Bus::new(128);
     let mut sparse_matrix_threads = Vec::new();
     for mut sparse_matrix in sparse_matrices {
         let rx = bus.add_rx();
+        let finish = finish.clone();
         let handle = thread::spawn(move || {
             for received in rx {
-                sparse_matrix.handle_pair(&received);
+                handle(&mut sparse_matrix, &received);
             }
-            sparse_matrix.finish();
+            finish(&mut sparse_matrix);
             sparse_matrix
         });
         sparse_matrix_threads.push(handle);
     }
 
-    for input in config.input.iter() {
-        let mut entity_processor = EntityProcessor::new(
-            config,
-            in_memory_entity_mapping_persistor.clone(),
-            |hashes| {
-                bus.broadcast(hashes);
-            },
-        );
+    read_inputs(
+        config,
+        in_memory_entity_mapping_persistor.clone(),
+        cardinality_monitor.as_deref_mut(),
+        |hashes| {
+            bus.broadcast(hashes);
+        },
+    )?;
 
-        match &config.file_type {
-            FileType::Json => {
-                let mut parser = dom::Parser::default();
-                read_file(input, config.log_every_n as u64, move |line| {
-                    let row = parse_json_line(line, &mut parser, &config.columns);
-                    entity_processor.process_row(&row);
-                });
-            }
-            FileType::Tsv => {
-                let config_col_num = config.columns.len();
-                read_file(input, config.log_every_n as u64, move |line| {
-                    let row = parse_tsv_line(line);
-                    let line_col_num = row.len();
-                    if line_col_num == config_col_num {
-                        entity_processor.process_row(&row);
-                    } else {
-                        warn!("Wrong number of columns (expected: {}, provided: {}). The line [{}] is skipped.", config_col_num, line_col_num, line);
-                    }
-                });
-            }
-        }
+    if let Some(monitor) = cardinality_monitor {
+        monitor.check()?;
+        monitor.report();
     }
 
+    let build = profile::Stage::start("build");
     drop(bus);
 
     let mut sparse_matrices = vec![];
@@ -82,33 +211,586 @@ pub fn build_graphs(
             .expect("Couldn't join on the associated thread");
         sparse_matrices.push(sparse_matrix);
     }
-
-    sparse_matrices
+    Ok((sparse_matrices, build))
 }
 
-/// Read file line by line. Pass every valid line to handler for parsing.
-fn read_file<F>(filepath: &str, log_every: u64, mut line_handler: F)
+/// Reads all inputs and passes hashes of every combination (see `EntityProcessor`) to the handler.
+/// Local files are memory-mapped and parsed in parallel (see `InputReader::read_in_parallel`),
+/// S3 files and sampled inputs are read line by line. Malformed rows are handled by the
+/// configured policy (see `MalformedRows`), the first one fails the reading with the fail policy.
+/// Profiled as parse (reading and splitting the rows) and hash (hashing the entities and passing
+/// the combinations on) stages.
+pub fn read_inputs<T, F>(
+    config: &Configuration,
+    persistor: Arc<T>,
+    mut cardinality_monitor: Option<&mut CardinalityMonitor>,
+    mut hashes_handler: F,
+) -> Result<(), CleoraError>
 where
-    F: FnMut(&str),
+    T: EntityMappingPersistor + Send + Sync,
+    F: FnMut(SmallVec<[u64; SMALL_VECTOR_SIZE]>),
 {
-    let input_file: Box<dyn Read> = if filepath.starts_with("s3://") {
-        Box::new(S3File::open(filepath.to_string()).unwrap())
+    let mut sampler = config
+        .sample_edges
+        .map(|fraction| EdgeSampler::new(fraction, config.seed.unwrap_or_default() as u64));
+    let aliases = match config.aliases.as_deref() {
+        Some(path) => {
+            let aliases = Aliases::load(path)?;
+            info!("Loaded {} aliases from {}", aliases.len(), path);
+            Some(aliases)
+        }
+        None => None,
+    };
+    let parse = profile::Stage::start("parse");
+    let mut reader = InputReader::new(config, aliases.as_ref(), persistor, PARSE_CHUNK_SIZE);
+    let (mut lines, mut bytes) = (0, 0);
+    for input in config.input.iter() {
+        // the sample depends on the order of the rows, so sampled inputs are read sequentially,
+        // like GraphML, which isn't split into lines
+        let graphml = matches!(config.file_type, FileType::GraphMl);
+        let sequential = input.starts_with("s3://") || sampler.is_some() || graphml;
+        let (input_lines, input_bytes) = if sequential {
+            let mut entity_processor =
+                EntityProcessor::new(config, reader.persistor.clone(), &mut hashes_handler);
+            if let Some(monitor) = cardinality_monitor.as_deref_mut() {
+                entity_processor = entity_processor.with_cardinality_monitor(monitor);
+            }
+            if let Some(sampler) = sampler.as_mut() {
+                entity_processor = entity_processor.with_sampler(sampler);
+            }
+            if let Some(aliases) = reader.aliases {
+                entity_processor = entity_processor.with_aliases(aliases);
+            }
+            if graphml {
+                reader.read_graphml(input, entity_processor)?
+            } else {
+                reader.read_sequentially(input, entity_processor)?
+            }
+        } else {
+            reader.read_in_parallel(
+                input,
+                cardinality_monitor.as_deref_mut(),
+                &mut hashes_handler,
+            )?
+        };
+        lines += input_lines;
+        bytes += input_bytes;
+    }
+    if let Some(sampler) = sampler {
+        info!("Sampled {} of {} input rows", sampler.kept, sampler.seen);
+    }
+    if reader.malformed.count > 0 {
+        warn!("Skipped {} malformed input rows", reader.malformed.count);
+    }
+    if let Some((name, elapsed, peak_bytes)) = parse.stop() {
+        let hash = reader.hash.elapsed;
+        profile::record(StageReport {
+            name,
+            graph: None,
+            seconds: elapsed.saturating_sub(hash).as_secs_f64(),
+            peak_bytes,
+            items: bytes,
+            unit: "bytes",
+        });
+        profile::record(StageReport {
+            name: String::from("hash"),
+            graph: None,
+            seconds: hash.as_secs_f64(),
+            peak_bytes,
+            items: lines,
+            unit: "rows",
+        });
+    }
+    Ok(())
+}
+
+/// Size of the byte ranges memory-mapped inputs are split into for parsing in parallel
+const PARSE_CHUNK_SIZE: usize = 4 << 20;
+
+/// Reads the inputs with the malformed rows and hashing time of all of them
+struct InputReader<'a, T: EntityMappingPersistor> {
+    config: &'a Configuration,
+    delimiters: Delimiters,
+    aliases: Option<&'a Aliases>,
+    persistor: Arc<T>,
+    malformed: MalformedRowsReport,
+    hash: profile::Timer,
+    chunk_size: usize,
+}
+
+impl<'a, T: EntityMappingPersistor + Send + Sync> InputReader<'a, T> {
+    fn new(
+        config: &'a Configuration,
+        aliases: Option<&'a Aliases>,
+        persistor: Arc<T>,
+        chunk_size: usize,
+    ) -> Self {
+        Self {
+            config,
+            delimiters: Delimiters::new(config),
+            aliases,
+            persistor,
+            malformed: MalformedRowsReport::new(config.malformed_rows),
+            hash: profile::Timer::default(),
+            chunk_size,
+        }
+    }
+
+    /// Reads the input line by line into the entity processor, returns the number of lines and
+    /// bytes read
+    fn read_sequentially<F>(
+        &mut self,
+        input: &str,
+        mut entity_processor: EntityProcessor<T, F>,
+    ) -> Result<(u64, u64), CleoraError>
+    where
+        F: FnMut(SmallVec<[u64; SMALL_VECTOR_SIZE]>),
+    {
+        let mut parser = LineParser::new(self.config, &self.delimiters);
+        let hash = &mut self.hash;
+        let malformed = &mut self.malformed;
+        let log_every = self.config.log_every_n as u64;
+        read_raw_lines(input, log_every, |line, line_number| {
+            if let Err(reason) = parser.process(&mut entity_processor, line, hash) {
+                malformed.add(input, line_number, &reason)?;
+            }
+            entity_processor.check_budget()
+        })
+    }
+
+    /// Reads the edges of the GraphML input into the entity processor as rows of their source and
+    /// target, returns the number of edges and bytes read
+    fn read_graphml<F>(
+        &mut self,
+        input: &str,
+        mut entity_processor: EntityProcessor<T, F>,
+    ) -> Result<(u64, u64), CleoraError>
+    where
+        F: FnMut(SmallVec<[u64; SMALL_VECTOR_SIZE]>),
+    {
+        let hash = &mut self.hash;
+        let log_every = self.config.log_every_n as u64;
+        let mut edges = GraphMlEdges::new(BufReader::new(open_input(input)));
+        let mut count = 0u64;
+        for edge in edges.by_ref() {
+            let edge = edge.unwrap_or_else(|e| panic!("Can't read file {}. Error: {}", input, e));
+            let processed = edge.nodes.and_then(|(source, target)| {
+                let row: [SmallVec<[String; SMALL_VECTOR_SIZE]>; 2] =
+                    [smallvec![source], smallvec![target]];
+                hash.time(|| entity_processor.process_row(&row))
+                    .map_err(|err| err.to_string())
+            });
+            if let Err(reason) = processed {
+                self.malformed.add(input, edge.line, &reason)?;
+            }
+            entity_processor.check_budget()?;
+            count += 1;
+            if count.is_multiple_of(log_every) {
+                info!("Number of edges processed: {}", count);
+            }
+        }
+        Ok((count, edges.bytes()))
+    }
+
+    /// Memory-maps the local input and splits it into byte ranges (of the chunk size, ending at
+    /// new lines), which are parsed and hashed in parallel by their own entity processors. The
+    /// combinations of the ranges are passed to the handler in the order of the input, so the
+    /// graphs are the same as with reading line by line. Returns the number of lines and bytes
+    /// read.
+    fn read_in_parallel<F>(
+        &mut self,
+        input: &str,
+        mut cardinality_monitor: Option<&mut CardinalityMonitor>,
+        hashes_handler: &mut F,
+    ) -> Result<(u64, u64), CleoraError>
+    where
+        F: FnMut(SmallVec<[u64; SMALL_VECTOR_SIZE]>),
+    {
+        let file =
+            File::open(input).unwrap_or_else(|e| panic!("Can't open file {}. Error: {}", input, e));
+        let size = file
+            .metadata()
+            .unwrap_or_else(|e| panic!("Can't read file {}. Error: {}", input, e))
+            .len();
+        // empty files can't be mapped
+        if size == 0 {
+            return Ok((0, 0));
+        }
+        let mmap = unsafe { Mmap::map(&file) }
+            .unwrap_or_else(|e| panic!("Can't map file {}. Error: {}", input, e));
+
+        let start = profile::enabled().then(Instant::now);
+        let ranges = chunk_ranges(&mmap, self.chunk_size);
+        let batch_size = rayon::current_num_threads();
+        let fork = cardinality_monitor.as_deref().map(CardinalityMonitor::fork);
+        let (config, delimiters, persistor) = (self.config, &self.delimiters, &self.persistor);
+        let aliases = self.aliases;
+        let malformed = &mut self.malformed;
+        let log_every = config.log_every_n as u64;
+        let (mut lines, mut hashing, mut working) = (0u64, Duration::ZERO, Duration::ZERO);
+        thread::scope(|scope| -> Result<(), CleoraError> {
+            // a batch of ranges is parsed while the previous one is passed on
+            let (sender, receiver) = mpsc::sync_channel(1);
+            scope.spawn(move || {
+                for batch in ranges.chunks(batch_size) {
+                    let parsed: Vec<ParsedChunk> = batch
+                        .par_iter()
+                        .map(|range| {
+                            let monitor = fork.as_ref().map(CardinalityMonitor::fork);
+                            parse_chunk(
+                                config,
+                                delimiters,
+                                aliases,
+                                persistor,
+                                &mmap[range.clone()],
+                                monitor,
+                            )
+                        })
+                        .collect();
+                    // the reading failed (malformed row with the fail policy)
+                    if sender.send(parsed).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            for parsed in receiver {
+                for chunk in parsed {
+                    for (line_number, reason) in chunk.malformed.iter() {
+                        malformed.add(input, lines + line_number, reason)?;
+                    }
+                    for combination in chunk.combinations() {
+                        hashes_handler(combination);
+                    }
+                    if let (Some(monitor), Some(chunk_monitor)) =
+                        (cardinality_monitor.as_deref_mut(), chunk.monitor.as_ref())
+                    {
+                        monitor.merge(chunk_monitor)?;
+                    }
+                    if (lines + chunk.lines) / log_every > lines / log_every {
+                        info!("Number of lines processed: {}", lines + chunk.lines);
+                    }
+                    lines += chunk.lines;
+                    hashing += chunk.hash;
+                    working += chunk.elapsed;
+                }
+            }
+            Ok(())
+        })?;
+
+        // hashing is interleaved with parsing in every range, its share of the wall time is
+        // reported
+        if let Some(start) = start {
+            if !working.is_zero() {
+                let share = hashing.as_secs_f64() / working.as_secs_f64();
+                self.hash.elapsed += start.elapsed().mul_f64(share);
+            }
+        }
+        Ok((lines, size))
+    }
+}
+
+/// Parses lines of the inputs into rows for the entity processor
+struct LineParser<'a> {
+    config: &'a Configuration,
+    delimiters: &'a Delimiters,
+    json: Option<dom::Parser>,
+}
+
+impl<'a> LineParser<'a> {
+    fn new(config: &'a Configuration, delimiters: &'a Delimiters) -> Self {
+        Self {
+            config,
+            delimiters,
+            json: None,
+        }
+    }
+
+    /// Processes the line (new line characters included), returns the reason if it's malformed
+    fn process<T, F>(
+        &mut self,
+        entity_processor: &mut EntityProcessor<T, F>,
+        line: &[u8],
+        hash: &mut profile::Timer,
+    ) -> Result<(), String>
+    where
+        T: EntityMappingPersistor,
+        F: FnMut(SmallVec<[u64; SMALL_VECTOR_SIZE]>),
+    {
+        let config_col_num = self.config.columns.len();
+        match &self.config.file_type {
+            FileType::Json => {
+                let line = str::from_utf8(line).map_err(|err| format!("Invalid UTF-8. {}", err))?;
+                let parser = self.json.get_or_insert_with(dom::Parser::default);
+                let row = parse_json_line(line, parser, &self.config.columns)?;
+                hash.time(|| entity_processor.process_row(&row))
+                    .map_err(|err| err.to_string())
+            }
+            FileType::Tsv if self.config.fast_parse => {
+                // only entities stored in the entity mapping (and tags) are validated as UTF-8,
+                // by the entity processor before anything of the row is stored
+                let row = parse_tsv_line_bytes(line, self.delimiters);
+                if row.len() == config_col_num {
+                    return hash
+                        .time(|| entity_processor.process_raw_row(&row))
+                        .map_err(|err| err.to_string());
+                }
+                // lines the fast path can't split go through the regular parser
+                let line = str::from_utf8(line).map_err(|err| format!("Invalid UTF-8. {}", err))?;
+                process_tsv_line(
+                    entity_processor,
+                    line,
+                    config_col_num,
+                    hash,
+                    self.delimiters,
+                )
+            }
+            FileType::Tsv => {
+                let line = str::from_utf8(line).map_err(|err| format!("Invalid UTF-8. {}", err))?;
+                process_tsv_line(
+                    entity_processor,
+                    line,
+                    config_col_num,
+                    hash,
+                    self.delimiters,
+                )
+            }
+            FileType::Snap => {
+                let line = str::from_utf8(line).map_err(|err| format!("Invalid UTF-8. {}", err))?;
+                match parse_edge_line(line)? {
+                    Some((source, target)) => {
+                        let row: [SmallVec<[&str; SMALL_VECTOR_SIZE]>; 2] =
+                            [smallvec![source], smallvec![target]];
+                        hash.time(|| entity_processor.process_row(&row))
+                            .map_err(|err| err.to_string())
+                    }
+                    None => Ok(()),
+                }
+            }
+            FileType::GraphMl => unreachable!("GraphML inputs aren't read by lines"),
+        }
+    }
+}
+
+/// Combinations, malformed lines and cardinality sketches of a byte range of the input
+struct ParsedChunk {
+    /// Hashes of all combinations, one after another
+    hashes: Vec<u64>,
+    /// End of every combination in the hashes
+    ends: Vec<usize>,
+    /// Line numbers (from 1, in the range) and reasons of the malformed lines
+    malformed: Vec<(u64, String)>,
+    lines: u64,
+    monitor: Option<CardinalityMonitor>,
+    /// Time of hashing and of the whole parsing, measured only while profiling
+    hash: Duration,
+    elapsed: Duration,
+}
+
+impl ParsedChunk {
+    fn combinations(&self) -> impl Iterator<Item = SmallVec<[u64; SMALL_VECTOR_SIZE]>> + '_ {
+        let starts = iter::once(0).chain(self.ends.iter().copied());
+        starts
+            .zip(self.ends.iter())
+            .map(move |(start, &end)| SmallVec::from_slice(&self.hashes[start..end]))
+    }
+}
+
+/// Parses and hashes the lines of a byte range of the input with its own entity processor
+fn parse_chunk<T: EntityMappingPersistor>(
+    config: &Configuration,
+    delimiters: &Delimiters,
+    aliases: Option<&Aliases>,
+    persistor: &Arc<T>,
+    data: &[u8],
+    mut monitor: Option<CardinalityMonitor>,
+) -> ParsedChunk {
+    let start = profile::enabled().then(Instant::now);
+    let (mut hashes, mut ends) = (Vec::new(), Vec::new());
+    let mut malformed = Vec::new();
+    let mut hash = profile::Timer::default();
+    let mut lines = 0;
+    {
+        let mut entity_processor = EntityProcessor::new(
+            config,
+            persistor.clone(),
+            |combination: SmallVec<[u64; SMALL_VECTOR_SIZE]>| {
+                hashes.extend_from_slice(&combination);
+                ends.push(hashes.len());
+            },
+        );
+        if let Some(monitor) = monitor.as_mut() {
+            entity_processor = entity_processor.with_cardinality_monitor(monitor);
+        }
+        if let Some(aliases) = aliases {
+            entity_processor = entity_processor.with_aliases(aliases);
+        }
+        let mut parser = LineParser::new(config, delimiters);
+        let mut rest = data;
+        while !rest.is_empty() {
+            let end = memchr(b'\n', rest).map_or(rest.len(), |i| i + 1);
+            let (line, tail) = rest.split_at(end);
+            rest = tail;
+            lines += 1;
+            if let Err(reason) = parser.process(&mut entity_processor, line, &mut hash) {
+                malformed.push((lines, reason));
+            }
+        }
+    }
+    ParsedChunk {
+        hashes,
+        ends,
+        malformed,
+        lines,
+        monitor,
+        hash: hash.elapsed,
+        elapsed: start.map(|start| start.elapsed()).unwrap_or_default(),
+    }
+}
+
+/// Splits the data into ranges of at least the size (except the last one), ending after new
+/// lines
+fn chunk_ranges(data: &[u8], size: usize) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let boundary = start + size.max(1) - 1;
+        let end = match data.get(boundary..).and_then(|rest| memchr(b'\n', rest)) {
+            Some(i) => boundary + i + 1,
+            None => data.len(),
+        };
+        ranges.push(start..end);
+        start = end;
+    }
+    ranges
+}
+
+/// Malformed input rows, which are skipped, logged or fail the run by the configured policy
+struct MalformedRowsReport {
+    policy: MalformedRows,
+    count: u64,
+}
+
+impl MalformedRowsReport {
+    fn new(policy: MalformedRows) -> Self {
+        Self { policy, count: 0 }
+    }
+
+    /// Handles the malformed row at the line number (counted from 1) of the input, fails with the
+    /// fail policy
+    fn add(&mut self, input: &str, line_number: u64, reason: &str) -> Result<(), CleoraError> {
+        self.count += 1;
+        match self.policy {
+            MalformedRows::Skip => {}
+            MalformedRows::Log(logged) => {
+                if self.count <= logged as u64 {
+                    warn!(
+                        "Malformed row at line {} of {}, skipped. {}",
+                        line_number, input, reason
+                    );
+                }
+                if self.count == logged as u64 {
+                    warn!("Further malformed rows aren't logged, their number is reported after reading.");
+                }
+            }
+            MalformedRows::Fail => {
+                return Err(CleoraError::malformed_row(input, line_number, reason));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Max number of collisions printed in logs
+const LOGGED_NUMBER_OF_COLLISIONS: usize = 10;
+
+/// Log entity hash collisions found during parsing. Colliding entities share one embedding.
+/// With 32-bit hashes the number of collisions is always logged, next to the number expected for
+/// random hashes of that many entities. Fails on collisions with `--fail-on-collision` or on more
+/// collisions than `--collision-budget`.
+fn report_collisions(
+    config: &Configuration,
+    persistor: &InMemoryEntityMappingPersistor,
+) -> Result<(), CleoraError> {
+    let collisions = persistor.collisions();
+    if config.hash_bits < 64 {
+        info!(
+            "{}-bit hashes of {} entities: {} collisions found, ~{:.1} expected",
+            config.hash_bits,
+            persistor.len(),
+            collisions.len(),
+            expected_collisions(persistor.len(), config.hash_bits)
+        );
+    }
+    if collisions.is_empty() {
+        return Ok(());
+    }
+
+    warn!(
+        "Found {} entity hash collisions. Colliding entities share the same embedding.",
+        collisions.len()
+    );
+    for collision in collisions.iter().take(LOGGED_NUMBER_OF_COLLISIONS) {
+        warn!(
+            "Hash {}: entity [{}] collides with [{}]",
+            collision.hash, collision.colliding_entity, collision.stored_entity
+        );
+    }
+
+    if config.fail_on_collision {
+        return Err(CleoraError::HashCollisions {
+            count: collisions.len(),
+        });
+    }
+    if let Some(budget) = config.collision_budget {
+        if collisions.len() > budget {
+            return Err(CleoraError::CollisionBudget {
+                count: collisions.len(),
+                budget,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Opens the local or S3 input
+fn open_input(filepath: &str) -> Box<dyn Read> {
+    if filepath.starts_with("s3://") {
+        Box::new(
+            S3File::open(filepath.to_string())
+                .unwrap_or_else(|e| panic!("Can't open file {}. Error: {}", filepath, e)),
+        )
     } else {
         Box::new(File::open(filepath).expect("Can't open file"))
-    };
-    let mut buffered = BufReader::new(input_file);
+    }
+}
+
+/// Reads lines of the file as bytes (new line characters included), without UTF-8 validation.
+/// The handler gets every line with its number (counted from 1), its error stops the reading.
+/// Returns the number of lines and bytes read.
+fn read_raw_lines<F>(
+    filepath: &str,
+    log_every: u64,
+    mut line_handler: F,
+) -> Result<(u64, u64), CleoraError>
+where
+    F: FnMut(&[u8], u64) -> Result<(), CleoraError>,
+{
+    let mut buffered = BufReader::new(open_input(filepath));
 
     let mut line_number = 1u64;
-    let mut line = String::new();
+    let mut bytes = 0u64;
+    let mut line = Vec::new();
     loop {
-        match buffered.read_line(&mut line) {
+        match buffered.read_until(b'\n', &mut line) {
             Ok(bytes_read) => {
                 // EOF
                 if bytes_read == 0 {
                     break;
                 }
 
-                line_handler(&line);
+                bytes += bytes_read as u64;
+                line_handler(&line, line_number)?;
             }
             Err(err) => {
                 error!("Can't read line number: {}. Error: {}.", line_number, err);
@@ -118,12 +800,13 @@ where
         // clear to reuse the buffer
         line.clear();
 
-        if line_number % log_every == 0 {
+        if line_number.is_multiple_of(log_every) {
             info!("Number of lines processed: {}", line_number);
         }
 
         line_number += 1;
     }
+    Ok((line_number - 1, bytes))
 }
 
 /// Parse a line of JSON and read its columns into a vector for processing.
@@ -131,102 +814,1653 @@ fn parse_json_line(
     line: &str,
     parser: &mut dom::Parser,
     columns: &[Column],
-) -> Vec<SmallVec<[String; SMALL_VECTOR_SIZE]>> {
-    let parsed = parser.parse(line).unwrap();
+) -> Result<Vec<SmallVec<[String; SMALL_VECTOR_SIZE]>>, String> {
+    let parsed = parser
+        .parse(line)
+        .map_err(|err| format!("Invalid JSON. {:?}", err))?;
     columns
         .iter()
         .map(|c| {
+            let elem = parsed
+                .at_key(&c.name)
+                .map_err(|_| format!("Missing column [{}]", c.name))?;
             if !c.complex {
-                let elem = parsed.at_key(&c.name).unwrap();
                 let value = match elem.get_type() {
                     dom::element::ElementType::String => elem.get_string().unwrap(),
                     _ => elem.minify(),
                 };
-                smallvec![value]
+                Ok(smallvec![value])
             } else {
-                parsed
-                    .at_key(&c.name)
-                    .unwrap()
-                    .get_array()
-                    .expect("Values for complex columns must be arrays")
+                let values = elem.get_array().map_err(|_| {
+                    format!("Values of complex column [{}] must be an array", c.name)
+                })?;
+                Ok(values
                     .into_iter()
                     .map(|v| match v.get_type() {
                         dom::element::ElementType::String => v.get_string().unwrap(),
                         _ => v.minify(),
                     })
-                    .collect()
+                    .collect())
             }
         })
         .collect()
 }
 
-/// Parse a line of TSV and read its columns into a vector for processing.
-fn parse_tsv_line(line: &str) -> Vec<SmallVec<[&str; SMALL_VECTOR_SIZE]>> {
-    let values = line.trim().split('\t');
-    values.map(|c| c.split(' ').collect()).collect()
+/// Process a line of TSV, returns the reason of malformed lines (e.g. with wrong number of
+/// columns), which are skipped.
+fn process_tsv_line<T, F>(
+    entity_processor: &mut EntityProcessor<T, F>,
+    line: &str,
+    config_col_num: usize,
+    hash: &mut profile::Timer,
+    delimiters: &Delimiters,
+) -> Result<(), String>
+where
+    T: EntityMappingPersistor,
+    F: FnMut(SmallVec<[u64; SMALL_VECTOR_SIZE]>),
+{
+    let row = parse_tsv_line(line, delimiters);
+    let line_col_num = row.len();
+    if line_col_num != config_col_num {
+        return Err(format!(
+            "Wrong number of columns (expected: {}, provided: {}) in line [{}]",
+            config_col_num,
+            line_col_num,
+            line.trim_end()
+        ));
+    }
+    hash.time(|| entity_processor.process_row(&row))
+        .map_err(|err| err.to_string())
+}
+
+/// Like `parse_tsv_line`, but splits raw bytes with memchr, without UTF-8 validation or
+/// allocating strings. Only ASCII whitespace is trimmed.
+fn parse_tsv_line_bytes<'a>(
+    line: &'a [u8],
+    delimiters: &Delimiters,
+) -> SmallVec<[SmallVec<[&'a [u8]; SMALL_VECTOR_SIZE]>; SMALL_VECTOR_SIZE]> {
+    let start = line
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(line.len());
+    let end = line
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(start, |end| end + 1);
+    split_bytes(&line[start..end], delimiters.field)
+        .enumerate()
+        .map(|(i, c)| split_bytes(c, delimiters.value(i)).collect())
+        .collect()
+}
+
+/// Splits bytes on the separator like `str::split`
+fn split_bytes(bytes: &[u8], separator: u8) -> impl Iterator<Item = &[u8]> {
+    let mut start = 0;
+    memchr_iter(separator, bytes)
+        .chain(iter::once(bytes.len()))
+        .map(move |end| {
+            let field = &bytes[start..end];
+            start = end + 1;
+            field
+        })
+}
+
+fn output_directory(config: &Configuration) -> String {
+    match config.output_dir.as_ref() {
+        Some(out) => format!("{}/", out),
+        None => String::from(""),
+    }
+}
+
+/// Timestamp used in output file names, taken once so all outputs of the run share it
+fn output_timestamp() -> String {
+    Utc::now().format("%Y%m%dT%H%M%S").to_string()
+}
+
+/// Name of the embedding file of the sparse matrix rendered from the output template. Persistors
+/// writing several files (numpy, raw) derive their names from it.
+fn output_file_name(
+    config: &Configuration,
+    output_format: &OutputFormat,
+    col_a_name: &str,
+    col_b_name: &str,
+    timestamp: &str,
+) -> String {
+    format!(
+        "{}{}",
+        output_directory(config),
+        output_name(config, output_format, col_a_name, col_b_name, timestamp)
+    )
+}
+
+/// Embedding file names of the sparse matrix in every output format of the run, in the order of
+/// `Configuration::output_formats`
+fn output_file_names(
+    config: &Configuration,
+    col_a_name: &str,
+    col_b_name: &str,
+    timestamp: &str,
+) -> Vec<String> {
+    config
+        .output_formats()
+        .map(|format| output_file_name(config, format, col_a_name, col_b_name, timestamp))
+        .collect()
+}
+
+/// Names of the output formats of the run, as in the output template
+fn output_format_names(config: &Configuration) -> Vec<&'static str> {
+    config
+        .output_formats()
+        .map(|format| output_template(config, format).1)
+        .collect()
+}
+
+/// Embedding file name rendered from the output template, without the output directory
+fn output_name(
+    config: &Configuration,
+    output_format: &OutputFormat,
+    col_a_name: &str,
+    col_b_name: &str,
+    timestamp: &str,
+) -> String {
+    let (template, format) = output_template(config, output_format);
+    template
+        .replace("{relation}", &config.relation_name)
+        .replace("{column}", &format!("{}__{}", col_a_name, col_b_name))
+        .replace("{dim}", &config.embeddings_dimension.to_string())
+        .replace("{timestamp}", timestamp)
+        .replace("{format}", format)
+}
+
+/// Output template of the output format and its name
+fn output_template<'a>(
+    config: &'a Configuration,
+    output_format: &OutputFormat,
+) -> (&'a str, &'static str) {
+    let (template, format) = match output_format {
+        OutputFormat::TextFile => (DEFAULT_OUTPUT_TEMPLATE, "textfile"),
+        OutputFormat::Parquet => (DEFAULT_PARQUET_OUTPUT_TEMPLATE, "parquet"),
+        OutputFormat::Numpy => (DEFAULT_OUTPUT_TEMPLATE, "numpy"),
+        OutputFormat::Raw => (DEFAULT_OUTPUT_TEMPLATE, "raw"),
+        OutputFormat::Compact => (DEFAULT_OUTPUT_TEMPLATE, "compact"),
+        OutputFormat::Npz => (DEFAULT_OUTPUT_TEMPLATE, "npz"),
+        OutputFormat::Onnx => (DEFAULT_OUTPUT_TEMPLATE, "onnx"),
+        OutputFormat::Projector(_) => (DEFAULT_OUTPUT_TEMPLATE, "projector"),
+        OutputFormat::Delta(_) => (DEFAULT_DELTA_OUTPUT_TEMPLATE, "delta"),
+        OutputFormat::DuckDb(_) => (DEFAULT_DUCKDB_OUTPUT_TEMPLATE, "duckdb"),
+        OutputFormat::Sqlite => (DEFAULT_SQLITE_OUTPUT_TEMPLATE, "sqlite"),
+        OutputFormat::Flight(_) => (DEFAULT_FLIGHT_OUTPUT_TEMPLATE, "flight"),
+        OutputFormat::Feast(_) => (DEFAULT_FEAST_OUTPUT_TEMPLATE, "feast"),
+    };
+    let template = config.output_template.as_deref().unwrap_or(template);
+    (template, format)
+}
+
+/// Embedding file of the sparse matrix written by the previous run (with the same output template
+/// and the first output format) to the given directory
+fn previous_output_file_name(
+    config: &Configuration,
+    directory: &str,
+    col_a_name: &str,
+    col_b_name: &str,
+) -> String {
+    format!(
+        "{}/{}",
+        directory.trim_end_matches('/'),
+        output_name(config, &config.output_format, col_a_name, col_b_name, "")
+    )
+}
+
+/// Checks that embeddings of the previous run exist for every sparse matrix when training is
+/// continued (`--continue-from`) or aligned (`--align-to`). Their names can't be known if they
+/// contain the timestamp.
+pub fn check_previous_outputs(config: &Configuration) -> Result<(), CleoraError> {
+    for directory in config.continue_from.iter().chain(config.align_to.iter()) {
+        let (template, _) = output_template(config, &config.output_format);
+        if template.contains("{timestamp}") {
+            return Err(CleoraError::invalid_embeddings(
+                directory,
+                "outputs named with {timestamp} can't be read by the next run, set --output-template without it",
+            ));
+        }
+        for sparse_matrix in configured_sparse_matrices(config) {
+            let path = previous_output_file_name(
+                config,
+                directory,
+                &sparse_matrix.col_a_name,
+                &sparse_matrix.col_b_name,
+            );
+            // numpy and npz outputs are opened by the output name
+            File::open(&path)
+                .or_else(|_| File::open(format!("{}.npy", path)))
+                .or_else(|_| File::open(format!("{}.npz", path)))
+                .map_err(|e| CleoraError::read_file(&path, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Loads feature vectors of the entities (`--feature-init`) fitted to the dimension
+fn load_features(config: &Configuration) -> Result<Option<Embeddings>, CleoraError> {
+    let path = match config.feature_init.as_ref() {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let features = load_embeddings(path)?;
+    info!(
+        "Loaded features of {} entities, dimension {}.",
+        features.entities.len(),
+        features.dimension()
+    );
+    Ok(Some(fit_features(
+        features,
+        config.embeddings_dimension as usize,
+        config.seed,
+    )))
+}
+
+/// Loads embeddings of the previous run to continue the training from or align to
+fn load_previous_embeddings(
+    config: &Configuration,
+    directory: &str,
+    col_a_name: &str,
+    col_b_name: &str,
+) -> Result<Embeddings, CleoraError> {
+    let path = previous_output_file_name(config, directory, col_a_name, col_b_name);
+    let embeddings = load_embeddings(&path)?;
+    if embeddings.dimension() != config.embeddings_dimension as usize {
+        return Err(CleoraError::invalid_embeddings(
+            &path,
+            format!(
+                "dimension {} differs from the configured dimension {}",
+                embeddings.dimension(),
+                config.embeddings_dimension
+            ),
+        ));
+    }
+    info!(
+        "Loaded previous embeddings of {}__{} from {}, {} entities.",
+        col_a_name,
+        col_b_name,
+        path,
+        embeddings.entities.len()
+    );
+    Ok(embeddings)
+}
+
+/// Name of the intermediate embedding file written after the iteration, see `infixed_file_name`
+fn snapshot_file_name(ofp: &str, iteration: u8) -> String {
+    infixed_file_name(ofp, &format!("iter{}", iteration))
+}
+
+/// Name of the embedding file reduced to given dimension by `--reduce-dimension`
+fn reduced_file_name(ofp: &str, dimension: u16) -> String {
+    infixed_file_name(ofp, &format!("pca{}", dimension))
+}
+
+/// Name of the projection fitted by `--reduce-dimension`, written next to the embeddings
+fn projection_file_name(ofp: &str) -> String {
+    format!("{}.pca.npz", ofp)
+}
+
+/// Name of the k-nearest-neighbor graph of `--knn-graph`, written next to the embeddings
+fn knn_graph_file_name(ofp: &str) -> String {
+    format!("{}.knn.tsv", ofp)
+}
+
+/// `.infix` is inserted before the extension of the file name (appended if there is none)
+fn infixed_file_name(ofp: &str, infix: &str) -> String {
+    let name_start = ofp.rfind('/').map(|i| i + 1).unwrap_or(0);
+    match ofp[name_start..].rfind('.') {
+        Some(dot) => {
+            let dot = name_start + dot;
+            format!("{}.{}{}", &ofp[..dot], infix, &ofp[dot..])
+        }
+        None => format!("{}.{}", ofp, infix),
+    }
+}
+
+/// Name of the training metadata file written next to the embeddings
+fn metadata_file_name(ofp: &str) -> String {
+    format!("{}.meta.json", ofp)
+}
+
+/// Writes training metadata of the embeddings: number of iterations done, whether propagation
+/// was stopped early by the deadline or an interrupt (`partial`) and whether the run was
+/// interrupted.
+fn write_training_metadata(
+    ofp: &str,
+    max_iterations: u8,
+    summary: &TrainingSummary,
+) -> Result<(), CleoraError> {
+    let filename = metadata_file_name(ofp);
+    let metadata = serde_json::json!({
+        "iterations": summary.iterations,
+        "max_iterations": max_iterations,
+        "partial": summary.partial,
+        "interrupted": summary.interrupted,
+    });
+    let mut file = create_output(&filename)?;
+    serde_json::to_writer_pretty(&mut file, &metadata)
+        .map_err(std::io::Error::from)
+        .and_then(|_| file.flush())
+        .map_err(|e| CleoraError::write_file(&filename, e))?;
+    file.commit()
+}
+
+fn entity_mapping_file_name(config: &Configuration, format: EntityMappingFormat) -> String {
+    let extension = match format {
+        EntityMappingFormat::Tsv => "tsv",
+        EntityMappingFormat::Parquet => "parquet",
+        EntityMappingFormat::Dictionary => "dict",
+    };
+    format!(
+        "{}{}__entity_mapping.{}",
+        output_directory(config),
+        config.relation_name,
+        extension
+    )
+}
+
+/// Tags of the entities, written when entities are tagged (see `Configuration::entity_tags`)
+pub fn entity_tags_file_name(config: &Configuration) -> String {
+    format!(
+        "{}{}__entity_tags.tsv",
+        output_directory(config),
+        config.relation_name
+    )
+}
+
+/// Lock of the output location held during the run, see `RunLock`
+pub fn lock_file_name(config: &Configuration) -> String {
+    format!(
+        "{}{}__run.lock",
+        output_directory(config),
+        config.relation_name
+    )
+}
+
+/// Report of the run profiled with `--profile`, see `profile::write_report`
+pub fn profile_file_name(config: &Configuration) -> String {
+    format!(
+        "{}{}__profile.json",
+        output_directory(config),
+        config.relation_name
+    )
 }
 
-/// Train SparseMatrix'es (graphs) in separated threads.
+/// Checks that the outputs don't exist yet, unless overwriting is allowed, and that output formats
+/// don't write the same files. Called before the graph is built, so the user doesn't wait for the
+/// failure. Names with `{timestamp}` can't collide and S3 objects aren't checked. Delta tables are
+/// meant to be written again, so they aren't checked either.
+pub fn check_outputs(config: &Configuration) -> Result<(), CleoraError> {
+    let timestamp = output_timestamp();
+    let mut files = Vec::new();
+    let mut tables = Vec::new();
+    for sparse_matrix in configured_sparse_matrices(config) {
+        let ofps = output_file_names(
+            config,
+            sparse_matrix.col_a_name.as_str(),
+            sparse_matrix.col_b_name.as_str(),
+            &timestamp,
+        );
+        for (format, ofp) in config.output_formats().zip(ofps.iter()) {
+            let embedding_files = |ofp: &str| {
+                embedding_output_files(format, ofp, config.produce_entity_occurrence_count)
+            };
+            let mut written = embedding_files(ofp);
+            if config.snapshot_iterations {
+                for iteration in 1..=config.max_number_of_iteration {
+                    written.extend(embedding_files(&snapshot_file_name(ofp, iteration)));
+                }
+            }
+            if let Some(dimension) = config.reduce_dimension {
+                written.extend(embedding_files(&reduced_file_name(ofp, dimension)));
+            }
+            match format {
+                OutputFormat::Delta(_) => tables.extend(written),
+                _ => files.extend(written),
+            }
+        }
+
+        let ofp = &ofps[0];
+        if config
+            .output_formats()
+            .any(|format| !matches!(format, OutputFormat::Flight(_)))
+        {
+            files.push(manifest_file_name(ofp));
+        }
+        if config.deadline.is_some() {
+            files.push(metadata_file_name(ofp));
+        }
+        if let Some(format) = config.matrix_export {
+            if config.edge_types.is_empty() {
+                files.extend(matrix_output_files(format, &matrix_file_name(ofp, None)));
+            }
+            for label in config.edge_types.iter() {
+                let base = matrix_file_name(ofp, Some(label));
+                files.extend(matrix_output_files(format, &base));
+            }
+        }
+        if config.reduce_dimension.is_some() {
+            files.push(projection_file_name(ofp));
+        }
+        if config.knn_graph.is_some() {
+            files.push(knn_graph_file_name(ofp));
+        }
+    }
+    if let Some(entity_mapping_format) = config.entity_mapping_format {
+        files.push(entity_mapping_file_name(config, entity_mapping_format));
+    }
+    if !config.entity_tags.is_empty() {
+        files.push(entity_tags_file_name(config));
+    }
+    files.extend(config.save_vocab.clone());
+
+    let mut written = HashSet::new();
+    if let Some(path) = files
+        .iter()
+        .chain(tables.iter())
+        .find(|f| !written.insert(*f))
+    {
+        return Err(CleoraError::SharedOutput { path: path.clone() });
+    }
+    if config.overwrite {
+        return Ok(());
+    }
+    check_absent(files)
+}
+
+/// Files written by the embedding persistor for given (templated) file name
+pub fn embedding_output_files(
+    output_format: &OutputFormat,
+    ofp: &str,
+    produce_entity_occurrence_count: bool,
+) -> Vec<String> {
+    match output_format {
+        OutputFormat::TextFile
+        | OutputFormat::Parquet
+        | OutputFormat::Delta(_)
+        | OutputFormat::DuckDb(_)
+        | OutputFormat::Sqlite => vec![ofp.to_string()],
+        OutputFormat::Numpy => NpyPersistor::output_files(ofp, produce_entity_occurrence_count),
+        OutputFormat::Raw => RawPersistor::output_files(ofp, produce_entity_occurrence_count),
+        OutputFormat::Compact => CompactPersistor::output_files(ofp),
+        OutputFormat::Npz => NpzPersistor::output_files(ofp),
+        OutputFormat::Onnx => OnnxPersistor::output_files(ofp, produce_entity_occurrence_count),
+        OutputFormat::Projector(_) => ProjectorPersistor::output_files(ofp),
+        OutputFormat::Flight(_) => vec![],
+        OutputFormat::Feast(_) => FeastPersistor::output_files(ofp),
+    }
+}
+
+/// Fails with the first of the files which already exists. S3 objects aren't checked.
+pub fn check_absent(files: Vec<String>) -> Result<(), CleoraError> {
+    match files
+        .into_iter()
+        .find(|f| !f.starts_with("s3://") && Path::new(f).exists())
+    {
+        Some(path) => Err(CleoraError::OutputExists { path }),
+        None => Ok(()),
+    }
+}
+
+pub fn create_embedding_persistor(
+    output_format: &OutputFormat,
+    ofp: String,
+    dimension: u16,
+    produce_entity_occurrence_count: bool,
+    entities_format: EntitiesFormat,
+    output_datetime: bool,
+) -> Result<Box<dyn EmbeddingPersistor>, CleoraError> {
+    let persistor: Box<dyn EmbeddingPersistor> = match output_format {
+        OutputFormat::TextFile => Box::new(TextFileVectorPersistor::new(
+            ofp,
+            produce_entity_occurrence_count,
+        )?),
+        OutputFormat::Parquet => Box::new(ParquetVectorPersistor::new(
+            ofp,
+            dimension,
+            produce_entity_occurrence_count,
+            output_datetime,
+        )?),
+        OutputFormat::Numpy => Box::new(NpyPersistor::new(
+            ofp,
+            produce_entity_occurrence_count,
+            entities_format,
+        )?),
+        OutputFormat::Raw => Box::new(RawPersistor::new(
+            ofp,
+            produce_entity_occurrence_count,
+            entities_format,
+        )?),
+        OutputFormat::Compact => {
+            Box::new(CompactPersistor::new(ofp, produce_entity_occurrence_count)?)
+        }
+        OutputFormat::Npz => Box::new(NpzPersistor::new(ofp, produce_entity_occurrence_count)?),
+        OutputFormat::Onnx => Box::new(OnnxPersistor::new(
+            ofp,
+            produce_entity_occurrence_count,
+            entities_format,
+        )?),
+        OutputFormat::Projector(points) => Box::new(ProjectorPersistor::new(
+            ofp,
+            produce_entity_occurrence_count,
+            *points,
+        )?),
+        #[cfg(feature = "delta")]
+        OutputFormat::Delta(mode) => Box::new(DeltaPersistor::new(
+            ofp,
+            dimension,
+            produce_entity_occurrence_count,
+            output_datetime,
+            *mode,
+        )?),
+        #[cfg(not(feature = "delta"))]
+        OutputFormat::Delta(_) => {
+            return Err(CleoraError::delta_table(
+                &ofp,
+                "cleora is built without the delta feature",
+            ))
+        }
+        #[cfg(feature = "duckdb")]
+        OutputFormat::DuckDb(layout) => Box::new(DuckDbPersistor::new(
+            ofp,
+            produce_entity_occurrence_count,
+            *layout,
+        )?),
+        #[cfg(not(feature = "duckdb"))]
+        OutputFormat::DuckDb(_) => {
+            return Err(CleoraError::duckdb(
+                &ofp,
+                "cleora is built without the duckdb feature",
+            ))
+        }
+        #[cfg(feature = "sqlite")]
+        OutputFormat::Sqlite => {
+            Box::new(SqlitePersistor::new(ofp, produce_entity_occurrence_count)?)
+        }
+        #[cfg(not(feature = "sqlite"))]
+        OutputFormat::Sqlite => {
+            return Err(CleoraError::sqlite(
+                &ofp,
+                "cleora is built without the sqlite feature",
+            ))
+        }
+        #[cfg(feature = "flight")]
+        OutputFormat::Flight(_) => Box::new(FlightPersistor::new(
+            &ofp,
+            dimension,
+            produce_entity_occurrence_count,
+        )),
+        #[cfg(not(feature = "flight"))]
+        OutputFormat::Flight(address) => {
+            return Err(CleoraError::flight(
+                address,
+                "cleora is built without the flight feature",
+            ))
+        }
+        OutputFormat::Feast(push_url) => Box::new(FeastPersistor::new(
+            ofp,
+            dimension,
+            produce_entity_occurrence_count,
+            push_url.as_deref(),
+        )?),
+    };
+    Ok(persistor)
+}
+
+/// Persistor of the configured output formats writing to `ofps` (a file name for every format,
+/// see `output_file_names`). Every format writes on its own background thread fed by a queue of
+/// `write_queue_size` chunks (on the calling thread if it's 0).
+fn create_output_persistor(
+    config: &Arc<Configuration>,
+    ofps: Vec<String>,
+    dimension: u16,
+) -> Result<Box<dyn EmbeddingPersistor>, CleoraError> {
+    let mut persistors = Vec::with_capacity(ofps.len());
+    for (i, ofp) in ofps.into_iter().enumerate() {
+        let persistor = if config.write_queue_size == 0 {
+            create_embedding_persistor(
+                config.output_formats().nth(i).unwrap(),
+                ofp,
+                dimension,
+                config.produce_entity_occurrence_count,
+                config.entities_format,
+                config.output_datetime,
+            )?
+        } else {
+            let config = config.clone();
+            Box::new(BackgroundPersistor::spawn(
+                config.write_queue_size,
+                move || {
+                    create_embedding_persistor(
+                        config.output_formats().nth(i).unwrap(),
+                        ofp,
+                        dimension,
+                        config.produce_entity_occurrence_count,
+                        config.entities_format,
+                        config.output_datetime,
+                    )
+                },
+            )?)
+        };
+        persistors.push(persistor);
+    }
+    if persistors.len() == 1 {
+        return Ok(persistors.pop().unwrap());
+    }
+    Ok(Box::new(FanOutPersistor::new(persistors)))
+}
+
+/// Embeddings output trained by the run
+#[derive(Debug)]
+pub struct TrainedOutput {
+    /// Templated output name of the first output format
+    pub name: String,
+    /// Names of the output formats the embeddings are written in, as in the output template
+    pub formats: Vec<&'static str>,
+    /// Files of the embeddings in every format, the training metadata and the manifest
+    pub files: Vec<String>,
+    pub dimension: u16,
+    /// Number of embeddings written
+    pub entities: usize,
+    pub summary: TrainingSummary,
+}
+
+/// Train SparseMatrix'es (graphs) in separated threads, matrices of the edge types of a column
+/// pair in the same thread. Embeddings of a thread are written in all output formats at once.
+/// Returns the first error of any thread, trained outputs otherwise.
+/// Every output gets a manifest (see `manifest::manifest`) when all of them are written, the
+/// duration of the run in it is counted from `run_start`.
 pub fn train(
     config: Configuration,
     in_memory_entity_mapping_persistor: Arc<InMemoryEntityMappingPersistor>,
     sparse_matrices: Vec<SparseMatrix>,
-) {
+    run_start: Instant,
+) -> Result<Vec<TrainedOutput>, CleoraError> {
     let config = Arc::new(config);
+    let features = load_features(&config)?.map(Arc::new);
+    let timestamp = output_timestamp();
+    // inputs are checksummed for the manifests while the training runs
+    let inputs = config.input.clone();
+    let input_checksums = config.input_checksums;
+    let checksum_thread = thread::spawn(move || {
+        inputs
+            .iter()
+            .map(|input| InputFile::read(input, input_checksums))
+            .collect::<Result<Vec<_>, _>>()
+    });
     let mut embedding_threads = Vec::new();
-    for sparse_matrix in sparse_matrices {
-        let sparse_matrix = Arc::new(sparse_matrix);
+    for sparse_matrices in group_by_column_pair(sparse_matrices) {
         let config = config.clone();
+        let features = features.clone();
+        let timestamp = timestamp.clone();
         let in_memory_entity_mapping_persistor = in_memory_entity_mapping_persistor.clone();
-        let handle = thread::spawn(move || {
-            let directory = match config.output_dir.as_ref() {
-                Some(out) => format!("{}/", out.clone()),
-                None => String::from(""),
-            };
-            let ofp = format!(
-                "{}{}__{}__{}.out",
-                directory,
-                config.relation_name,
-                sparse_matrix.col_a_name.as_str(),
-                sparse_matrix.col_b_name.as_str()
+        let handle = thread::spawn(move || -> Result<TrainedOutput, CleoraError> {
+            let ofps = output_file_names(
+                &config,
+                sparse_matrices[0].col_a_name.as_str(),
+                sparse_matrices[0].col_b_name.as_str(),
+                &timestamp,
             );
-
-            let mut persistor: Box<dyn EmbeddingPersistor> = match &config.output_format {
-                OutputFormat::TextFile => Box::new(TextFileVectorPersistor::new(
-                    ofp,
-                    config.produce_entity_occurrence_count,
-                )),
-                OutputFormat::Parquet => Box::new(ParquetVectorPersistor::new(
-                    ofp,
-                    config.embeddings_dimension,
-                )),
-                OutputFormat::Numpy => Box::new(NpyPersistor::new(
-                    ofp,
-                    config.produce_entity_occurrence_count,
-                )),
-            };
-            if config.in_memory_embedding_calculation {
-                calculate_embeddings(
-                    config.clone(),
-                    sparse_matrix.clone(),
+            let mut output = if config.edge_types.is_empty() {
+                let sparse_matrix = sparse_matrices.into_iter().next().unwrap();
+                train_sparse_matrix(
+                    &config,
+                    sparse_matrix,
                     in_memory_entity_mapping_persistor,
-                    persistor.as_mut(),
-                );
+                    features.as_deref(),
+                    &ofps,
+                )?
             } else {
-                calculate_embeddings_mmap(
-                    config.clone(),
-                    sparse_matrix.clone(),
+                train_edge_types(
+                    &config,
+                    sparse_matrices,
                     in_memory_entity_mapping_persistor,
-                    persistor.as_mut(),
-                );
+                    features.as_deref(),
+                    &ofps,
+                )?
+            };
+            for (format, ofp) in config.output_formats().zip(ofps.iter()) {
+                output.files.extend(embedding_output_files(
+                    format,
+                    ofp,
+                    config.produce_entity_occurrence_count,
+                ));
+            }
+            // interrupted runs are resumed from the outputs, which updates their metadata
+            let (ofp, summary) = (&output.name, &output.summary);
+            if config.deadline.is_some() || config.continue_from.is_some() || summary.interrupted {
+                write_training_metadata(ofp, config.max_number_of_iteration, summary)?;
+                output.files.push(metadata_file_name(ofp));
             }
+            Ok(output)
         });
         embedding_threads.push(handle);
     }
 
+    // wait for every thread, so other outputs are finished before the error is reported
+    let mut result = Ok(());
+    let mut outputs = Vec::with_capacity(embedding_threads.len());
     for join_handle in embedding_threads {
-        join_handle
+        let thread_result = join_handle
             .join()
             .expect("Couldn't join on the associated thread");
+        match thread_result {
+            Ok(output) => outputs.push(output),
+            Err(err) if result.is_ok() => result = Err(err),
+            Err(err) => error!("{}", err),
+        }
+    }
+    result?;
+
+    let entity_mapping = match config.entity_mapping_format {
+        Some(entity_mapping_format) => {
+            persist_entity_mapping(
+                &config,
+                entity_mapping_format,
+                &in_memory_entity_mapping_persistor,
+            )?;
+            Some(entity_mapping_file_name(&config, entity_mapping_format))
+        }
+        None => None,
+    };
+    if !config.entity_tags.is_empty() {
+        let filename = entity_tags_file_name(&config);
+        tags::write_entity_tags(&filename, &in_memory_entity_mapping_persistor)?;
+        info!("Entity tags saved to {}", filename);
+    }
+
+    let inputs = checksum_thread
+        .join()
+        .expect("Couldn't join on the associated thread")?;
+    let finished = Utc::now();
+    let elapsed = chrono::Duration::from_std(run_start.elapsed())
+        .unwrap_or_else(|_| chrono::Duration::zero());
+    let run = RunInfo {
+        started: finished - elapsed,
+        finished,
+        inputs,
+        entity_mapping,
+    };
+    for output in outputs.iter_mut() {
+        if output.files.is_empty() {
+            // served outputs (Arrow Flight) have no files to describe
+            continue;
+        }
+        let filename = manifest_file_name(&output.name);
+        let manifest = manifest::manifest(&config, output, &run);
+        manifest::write_manifest(&filename, &manifest)?;
+        output.files.push(filename);
+    }
+    Ok(outputs)
+}
+
+/// Groups sparse matrices of the same column pair (of different edge types), keeping their order
+fn group_by_column_pair(sparse_matrices: Vec<SparseMatrix>) -> Vec<Vec<SparseMatrix>> {
+    let mut groups: Vec<Vec<SparseMatrix>> = Vec::new();
+    for sparse_matrix in sparse_matrices {
+        match groups.last_mut() {
+            Some(group)
+                if group[0].col_a_name == sparse_matrix.col_a_name
+                    && group[0].col_b_name == sparse_matrix.col_b_name =>
+            {
+                group.push(sparse_matrix)
+            }
+            _ => groups.push(vec![sparse_matrix]),
+        }
+    }
+    groups
+}
+
+/// Trains the sparse matrix and writes its embeddings (and snapshots) to `ofps`, see
+/// `output_file_names`. Files of the returned output are left for the caller.
+fn train_sparse_matrix(
+    config: &Arc<Configuration>,
+    sparse_matrix: SparseMatrix,
+    in_memory_entity_mapping_persistor: Arc<InMemoryEntityMappingPersistor>,
+    features: Option<&Embeddings>,
+    ofps: &[String],
+) -> Result<TrainedOutput, CleoraError> {
+    let dimension = config.embeddings_dimension;
+    let create_snapshot_persistor = |iteration: u8| {
+        let snapshots = ofps
+            .iter()
+            .map(|ofp| snapshot_file_name(ofp, iteration))
+            .collect();
+        create_output_persistor(config, snapshots, dimension)
+    };
+    let snapshot_persistor: Option<&SnapshotPersistorFactory> = if config.snapshot_iterations {
+        Some(&create_snapshot_persistor)
+    } else {
+        None
+    };
+
+    let previous = match config.continue_from.as_ref() {
+        Some(directory) => Some(load_previous_embeddings(
+            config,
+            directory,
+            &sparse_matrix.col_a_name,
+            &sparse_matrix.col_b_name,
+        )?),
+        None => None,
+    };
+    let reference = match config.align_to.as_ref() {
+        Some(directory) => Some(load_previous_embeddings(
+            config,
+            directory,
+            &sparse_matrix.col_a_name,
+            &sparse_matrix.col_b_name,
+        )?),
+        None => None,
+    };
+
+    if let Some(format) = config.matrix_export {
+        let base = matrix_file_name(&ofps[0], None);
+        export_matrix(
+            &sparse_matrix,
+            &in_memory_entity_mapping_persistor,
+            format,
+            &base,
+        )?;
+    }
+
+    let mut persistor = create_output_persistor(config, ofps.to_vec(), dimension)?;
+    let prior = PriorEmbeddings {
+        features,
+        previous: previous.as_ref(),
+        reference: reference.as_ref(),
+    };
+    // the reduction and the k-NN graph need all of the embeddings, a copy of them is kept in
+    // memory
+    let collect = config.reduce_dimension.is_some() || config.knn_graph.is_some();
+    let mut counting = CountingPersistor {
+        inner: persistor.as_mut(),
+        rows: 0,
+        collected: if collect {
+            Some(EmbeddingCollector::default())
+        } else {
+            None
+        },
+    };
+    let summary = calculate(
+        config,
+        sparse_matrix,
+        in_memory_entity_mapping_persistor,
+        &mut counting,
+        snapshot_persistor,
+        prior,
+    )?;
+    if let Some(collected) = counting.collected.as_ref() {
+        write_reduced(config, collected, ofps)?;
+        write_knn_graph(config, collected, &ofps[0])?;
+    }
+    Ok(TrainedOutput {
+        name: ofps[0].clone(),
+        formats: output_format_names(config),
+        files: vec![],
+        dimension,
+        entities: counting.rows,
+        summary,
+    })
+}
+
+/// Trains the sparse matrices of the edge types of a column pair one by one and writes their
+/// combined embeddings to `ofps`. Embeddings of the edge types are kept in memory until all of
+/// them are trained. Files of the returned output are left for the caller.
+fn train_edge_types(
+    config: &Arc<Configuration>,
+    sparse_matrices: Vec<SparseMatrix>,
+    in_memory_entity_mapping_persistor: Arc<InMemoryEntityMappingPersistor>,
+    features: Option<&Embeddings>,
+    ofps: &[String],
+) -> Result<TrainedOutput, CleoraError> {
+    let mut summary = TrainingSummary {
+        iterations: config.max_number_of_iteration,
+        partial: false,
+        interrupted: false,
+        iteration_stats: vec![],
+    };
+    let mut edge_types = Vec::with_capacity(sparse_matrices.len());
+    for sparse_matrix in sparse_matrices {
+        if let Some((_, label)) = &sparse_matrix.edge_type {
+            info!(
+                "Training {} - {} edges of type {}.",
+                sparse_matrix.col_a_name, sparse_matrix.col_b_name, label
+            );
+            if let Some(format) = config.matrix_export {
+                let base = matrix_file_name(&ofps[0], Some(label));
+                export_matrix(
+                    &sparse_matrix,
+                    &in_memory_entity_mapping_persistor,
+                    format,
+                    &base,
+                )?;
+            }
+        }
+        let mut collector = EmbeddingCollector::default();
+        let edge_type_summary = calculate(
+            config,
+            sparse_matrix,
+            in_memory_entity_mapping_persistor.clone(),
+            &mut collector,
+            None,
+            PriorEmbeddings {
+                features,
+                ..PriorEmbeddings::default()
+            },
+        )?;
+        summary.merge(edge_type_summary);
+        edge_types.push(collector);
+    }
+
+    let mut embeddings = edge_types::combine(
+        edge_types,
+        config.edge_type_combination,
+        config.embeddings_dimension as usize,
+    );
+    if let Some(sort_output) = config.sort_output {
+        embeddings.sort(sort_output);
+    }
+    write_reduced(config, &embeddings, ofps)?;
+    write_knn_graph(config, &embeddings, &ofps[0])?;
+    let (entities, dimension) = (embeddings.len(), embeddings.dimension as u16);
+    let mut persistor = create_output_persistor(config, ofps.to_vec(), dimension)?;
+    edge_types::write(embeddings, persistor.as_mut(), config.chunk_size)?;
+    Ok(TrainedOutput {
+        name: ofps[0].clone(),
+        formats: output_format_names(config),
+        files: vec![],
+        dimension,
+        entities,
+        summary,
+    })
+}
+
+/// Writes the embeddings with the inner persistor, counts them and keeps them for
+/// `write_reduced` and `write_knn_graph` if collecting
+struct CountingPersistor<'a> {
+    inner: &'a mut dyn EmbeddingPersistor,
+    rows: usize,
+    collected: Option<EmbeddingCollector>,
+}
+
+impl EmbeddingPersistor for CountingPersistor<'_> {
+    fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), CleoraError> {
+        if let Some(collected) = self.collected.as_mut() {
+            collected.put_metadata(entity_count, dimension)?;
+        }
+        self.inner.put_metadata(entity_count, dimension)
+    }
+
+    fn put_data(
+        &mut self,
+        entity: &str,
+        occur_count: u32,
+        vector: Vec<f32>,
+    ) -> Result<(), CleoraError> {
+        if let Some(collected) = self.collected.as_mut() {
+            collected.put_data(entity, occur_count, vector.clone())?;
+        }
+        self.rows += 1;
+        self.inner.put_data(entity, occur_count, vector)
+    }
+
+    fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+        if let Some(collected) = self.collected.as_mut() {
+            collected.put_data_batch(chunk)?;
+        }
+        self.rows += chunk.entities.len();
+        self.inner.put_data_batch(chunk)
+    }
+
+    fn put_column_major_file(
+        &mut self,
+        file: &ColumnMajorFile,
+        entities: Vec<Arc<str>>,
+        occur_counts: Vec<u32>,
+    ) -> Result<bool, CleoraError> {
+        // the rows are needed to collect the embeddings
+        if self.collected.is_some() {
+            return Ok(false);
+        }
+        let rows = entities.len();
+        let taken = self
+            .inner
+            .put_column_major_file(file, entities, occur_counts)?;
+        if taken {
+            self.rows += rows;
+        }
+        Ok(taken)
+    }
+
+    fn finish(&mut self) -> Result<(), CleoraError> {
+        self.inner.finish()
+    }
+}
+
+/// Max number of rows the projection of `--reduce-dimension` is fitted on
+const PCA_SAMPLE_SIZE: usize = 100_000;
+
+/// Fits PCA on the final embeddings and writes them reduced to `--reduce-dimension` (see
+/// `reduced_file_name`) in every output format, with the fitted projection next to the output of
+/// the first one. Reduced embeddings are L2-normalized again if the full ones are.
+fn write_reduced(
+    config: &Arc<Configuration>,
+    embeddings: &EmbeddingCollector,
+    ofps: &[String],
+) -> Result<(), CleoraError> {
+    let dimension = match config.reduce_dimension {
+        Some(dimension) => dimension,
+        None => return Ok(()),
+    };
+    if interrupt::interrupted() {
+        warn!("Embeddings aren't reduced as the run was interrupted.");
+        return Ok(());
+    }
+    let values =
+        ArrayView2::from_shape((embeddings.len(), embeddings.dimension), &embeddings.values)
+            .expect("Row-major embeddings");
+    let projection = Projection::fit(values, dimension as usize, PCA_SAMPLE_SIZE);
+    info!(
+        "Reduced embeddings to {} dimensions with PCA, keeping {:.1}% of the variance.",
+        dimension,
+        100f32 * projection.explained_ratio()
+    );
+    projection.write(&projection_file_name(&ofps[0]))?;
+
+    let reduced = ofps
+        .iter()
+        .map(|ofp| reduced_file_name(ofp, dimension))
+        .collect();
+    let mut persistor = create_output_persistor(config, reduced, dimension)?;
+    persistor.put_metadata(embeddings.len() as u32, dimension)?;
+    let chunk_size = config.chunk_size.max(1);
+    for start in (0..embeddings.len()).step_by(chunk_size) {
+        let end = (start + chunk_size).min(embeddings.len());
+        let mut vectors = projection.transform(values.slice(s![start..end, ..]));
+        if config.normalize == Normalization::L2 {
+            for mut row in vectors.rows_mut() {
+                let norm = row.dot(&row).sqrt();
+                if norm > 0f32 {
+                    row /= norm;
+                }
+            }
+        }
+        persistor.put_data_batch(EmbeddingBatch {
+            entities: &embeddings.entities[start..end],
+            occur_counts: &embeddings.occur_counts[start..end],
+            vectors: vectors.view(),
+        })?;
+    }
+    persistor.finish()
+}
+
+/// Writes the k-nearest-neighbor graph of the final embeddings of `--knn-graph` next to the output
+/// of the first output format, see `knn::write_graph`
+fn write_knn_graph(
+    config: &Arc<Configuration>,
+    embeddings: &EmbeddingCollector,
+    ofp: &str,
+) -> Result<(), CleoraError> {
+    let graph = match config.knn_graph {
+        Some(graph) => graph,
+        None => return Ok(()),
+    };
+    if interrupt::interrupted() {
+        warn!("k-NN graph isn't written as the run was interrupted.");
+        return Ok(());
+    }
+    let search = if knn::is_exact(graph.method, embeddings.len()) {
+        "exact"
+    } else {
+        "approximate"
+    };
+    let filename = knn_graph_file_name(ofp);
+    let edges = knn::write_graph(&filename, embeddings, graph)?;
+    info!(
+        "k-NN graph with {} edges ({} search) saved to {}",
+        edges, search, filename
+    );
+    Ok(())
+}
+
+/// Calculates embeddings of the sparse matrix in memory or in memory-mapped files
+fn calculate(
+    config: &Arc<Configuration>,
+    sparse_matrix: SparseMatrix,
+    in_memory_entity_mapping_persistor: Arc<InMemoryEntityMappingPersistor>,
+    persistor: &mut dyn EmbeddingPersistor,
+    snapshot_persistor: Option<&SnapshotPersistorFactory>,
+    prior: PriorEmbeddings,
+) -> Result<TrainingSummary, CleoraError> {
+    let sparse_matrix = Arc::new(sparse_matrix);
+    if config.in_memory_embedding_calculation {
+        calculate_embeddings(
+            config.clone(),
+            sparse_matrix,
+            in_memory_entity_mapping_persistor,
+            persistor,
+            snapshot_persistor,
+            prior,
+        )
+    } else {
+        calculate_embeddings_mmap(
+            config.clone(),
+            sparse_matrix,
+            in_memory_entity_mapping_persistor,
+            persistor,
+            snapshot_persistor,
+            prior,
+        )
+    }
+}
+
+/// Write hash to entity mapping, so embeddings can be joined back with hashes (sparse matrix rows).
+fn persist_entity_mapping(
+    config: &Configuration,
+    entity_mapping_format: EntityMappingFormat,
+    in_memory_entity_mapping_persistor: &InMemoryEntityMappingPersistor,
+) -> Result<(), CleoraError> {
+    let filename = entity_mapping_file_name(config, entity_mapping_format);
+    let mut writer: Box<dyn EntityMappingWriter> = match entity_mapping_format {
+        EntityMappingFormat::Tsv => Box::new(TsvEntityMappingWriter::new(filename)?),
+        EntityMappingFormat::Parquet => Box::new(ParquetEntityMappingWriter::new(
+            filename,
+            config.chunk_size,
+        )?),
+        EntityMappingFormat::Dictionary => Box::new(DictionaryEntityMappingWriter::new(filename)),
+    };
+
+    info!("Start saving entity mapping.");
+    in_memory_entity_mapping_persistor.write_all(writer.as_mut())?;
+    info!("Done saving entity mapping.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cardinality::CardinalityMonitor;
+    use crate::configuration::{
+        extract_delimiter, extract_fields, extract_relations, extract_value_delimiters,
+        validate_output_template, Configuration, MalformedRows, Normalization, OutputFormat,
+    };
+    use crate::edge_types::EmbeddingCollector;
+    use crate::embedding::TrainingSummary;
+    use crate::entity::{EntityProcessor, SMALL_VECTOR_SIZE};
+    use crate::error::CleoraError;
+    use crate::persistence::embedding::EmbeddingPersistor;
+    use crate::persistence::entity::{EntityMappingPersistor, InMemoryEntityMappingPersistor};
+    use crate::pipeline::{
+        check_outputs, chunk_ranges, configured_sparse_matrices, output_file_name,
+        output_file_names, parse_tsv_line, parse_tsv_line_bytes, projection_file_name, read_inputs,
+        reduced_file_name, report_collisions, snapshot_file_name, write_reduced,
+        write_training_metadata, Delimiters, InputReader,
+    };
+    use smallvec::SmallVec;
+    use std::fs::File;
+    use std::sync::Arc;
+
+    #[test]
+    fn parse_tsv_line_as_bytes() {
+        let columns = extract_fields(vec!["users", "complex::products", "brands"]).unwrap();
+        let mut config = Configuration::default(String::from(""), columns);
+        let delimiters = Delimiters::new(&config);
+        for line in ["u1\tp1 p2\tb1\n", "  u1\t\tp1  p2\r\n", "u1", "", "\n"] {
+            let expected: Vec<Vec<&[u8]>> = parse_tsv_line(line, &delimiters)
+                .iter()
+                .map(|c| c.iter().map(|e| e.as_bytes()).collect())
+                .collect();
+            let parsed: Vec<Vec<&[u8]>> = parse_tsv_line_bytes(line.as_bytes(), &delimiters)
+                .iter()
+                .map(|c| c.to_vec())
+                .collect();
+            assert_eq!(expected, parsed);
+        }
+
+        config.field_delimiter = b',';
+        config.value_delimiters = vec![(String::from("products"), b'|')];
+        let delimiters = Delimiters::new(&config);
+        let line = "u 1,p1|p 2,b1\n";
+        let expected: Vec<Vec<&str>> = vec![vec!["u", "1"], vec!["p1", "p 2"], vec!["b1"]];
+        let parsed: Vec<Vec<&str>> = parse_tsv_line(line, &delimiters)
+            .iter()
+            .map(|c| c.to_vec())
+            .collect();
+        assert_eq!(expected, parsed);
+        let parsed: Vec<Vec<&[u8]>> = parse_tsv_line_bytes(line.as_bytes(), &delimiters)
+            .iter()
+            .map(|c| c.to_vec())
+            .collect();
+        let expected: Vec<Vec<&[u8]>> = expected
+            .iter()
+            .map(|c| c.iter().map(|e| e.as_bytes()).collect())
+            .collect();
+        assert_eq!(expected, parsed);
+    }
+
+    #[test]
+    fn render_output_file_names() {
+        let mut config = Configuration::default(String::from("unused"), vec![]);
+        config.relation_name = String::from("emb");
+        config.embeddings_dimension = 64;
+        assert_eq!(
+            "emb__a__b.out",
+            output_file_name(&config, &config.output_format, "a", "b", "20220901T120000")
+        );
+
+        config.output_format = OutputFormat::Parquet;
+        config.output_dir = Some(String::from("out"));
+        assert_eq!(
+            "out/emb__a__b_20220901T120000.parquet",
+            output_file_name(&config, &config.output_format, "a", "b", "20220901T120000")
+        );
+
+        config.output_template = Some(String::from("{column}_{dim}.{format}"));
+        assert_eq!(
+            "out/a__b_64.parquet",
+            output_file_name(&config, &config.output_format, "a", "b", "20220901T120000")
+        );
+
+        // every format renders its own name
+        config.extra_output_formats = vec![OutputFormat::Numpy];
+        assert_eq!(
+            vec!["out/a__b_64.parquet", "out/a__b_64.numpy"],
+            output_file_names(&config, "a", "b", "20220901T120000")
+        );
+    }
+
+    #[test]
+    fn check_files_shared_by_output_formats() {
+        let columns = extract_fields(vec!["users", "items"]).unwrap();
+        let mut config = Configuration::default(String::from("unused"), columns);
+        config.output_dir = Some(String::from("cleora_missing_dir"));
+        config.output_format = OutputFormat::Numpy;
+        config.extra_output_formats = vec![OutputFormat::TextFile];
+        assert!(check_outputs(&config).is_ok());
+
+        // both formats write .out without {format} in the template
+        config.extra_output_formats = vec![OutputFormat::Parquet];
+        config.output_template = Some(String::from("{relation}__{column}.out"));
+        assert!(check_outputs(&config).is_ok());
+        config.output_format = OutputFormat::TextFile;
+        assert!(matches!(
+            check_outputs(&config),
+            Err(CleoraError::SharedOutput { .. })
+        ));
+        // overwriting doesn't allow it either
+        config.overwrite = true;
+        assert!(check_outputs(&config).is_err());
+    }
+
+    #[test]
+    fn render_snapshot_file_names() {
+        assert_eq!(
+            "emb__a__b.iter1.out",
+            snapshot_file_name("emb__a__b.out", 1)
+        );
+        assert_eq!(
+            "out.d/emb__a__b.iter12",
+            snapshot_file_name("out.d/emb__a__b", 12)
+        );
+        assert_eq!(
+            "emb__a__b.pca64.out",
+            reduced_file_name("emb__a__b.out", 64)
+        );
+    }
+
+    #[test]
+    fn write_reduced_embeddings() {
+        let ofp = std::env::temp_dir().join("cleora_reduced_test.out");
+        let ofp = ofp.to_str().unwrap();
+        let mut config = Configuration::default(String::from(""), vec![]);
+        config.reduce_dimension = Some(1);
+        config.normalize = Normalization::L2;
+        let mut embeddings = EmbeddingCollector::default();
+        embeddings.put_metadata(3, 3).unwrap();
+        for (entity, scale) in [("a", 1f32), ("b", 2f32), ("c", -3f32)] {
+            embeddings
+                .put_data(entity, 1, vec![scale, 2f32 * scale, 0f32])
+                .unwrap();
+        }
+        write_reduced(&Arc::new(config), &embeddings, &[ofp.to_string()]).unwrap();
+
+        let reduced = std::fs::read_to_string(reduced_file_name(ofp, 1)).unwrap();
+        let lines: Vec<&str> = reduced.lines().collect();
+        assert_eq!("3 1", lines[0]);
+        // normalized to unit length, entities on the opposite side of the mean get -1
+        let values: Vec<f32> = lines[1..]
+            .iter()
+            .map(|l| l.rsplit(' ').next().unwrap().parse().unwrap())
+            .collect();
+        for (value, expected) in values.iter().zip([1f32, 1f32, -1f32]) {
+            assert!((value - expected).abs() < 1e-5);
+        }
+        assert!(zip::ZipArchive::new(File::open(projection_file_name(ofp)).unwrap()).is_ok());
+        std::fs::remove_file(reduced_file_name(ofp, 1)).unwrap();
+        std::fs::remove_file(projection_file_name(ofp)).unwrap();
+    }
+
+    #[test]
+    fn write_metadata_of_partial_training() {
+        let ofp = std::env::temp_dir().join("cleora_metadata_test.out");
+        let ofp = ofp.to_str().unwrap();
+        let summary = TrainingSummary {
+            iterations: 2,
+            partial: true,
+            interrupted: true,
+            iteration_stats: vec![],
+        };
+        write_training_metadata(ofp, 4, &summary).unwrap();
+
+        let path = format!("{}.meta.json", ofp);
+        let metadata: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "iterations": 2,
+                "max_iterations": 4,
+                "partial": true,
+                "interrupted": true,
+            }),
+            metadata
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn read_malformed_input(
+        name: &str,
+        malformed_rows: MalformedRows,
+        fast_parse: bool,
+    ) -> Result<usize, CleoraError> {
+        let input = std::env::temp_dir().join(name);
+        std::fs::write(
+            &input,
+            b"u1\ti1\t1\nu2\ti2\nu3\ti3\tx\n\xff\ti4\t1\nu4\ti4\t2\n",
+        )
+        .unwrap();
+        let columns = extract_fields(vec!["users", "items", "weight::w"]).unwrap();
+        let mut config = Configuration::default(input.to_str().unwrap().to_string(), columns);
+        config.malformed_rows = malformed_rows;
+        config.fast_parse = fast_parse;
+        let persistor = Arc::new(InMemoryEntityMappingPersistor::default());
+        let mut rows = 0;
+        let read = read_inputs(&config, persistor, None, |_| rows += 1);
+        std::fs::remove_file(input).unwrap();
+        read.map(|_| rows)
+    }
+
+    #[test]
+    fn skip_malformed_rows() {
+        // ragged row, invalid weight and invalid UTF-8 are skipped
+        let rows = read_malformed_input("cleora_malformed_skip.tsv", MalformedRows::Skip, false);
+        assert_eq!(2, rows.unwrap());
+        let rows = read_malformed_input("cleora_malformed_log.tsv", MalformedRows::Log(1), true);
+        assert_eq!(2, rows.unwrap());
+    }
+
+    #[test]
+    fn fail_on_malformed_row() {
+        for fast_parse in [false, true] {
+            let read =
+                read_malformed_input("cleora_malformed_fail.tsv", MalformedRows::Fail, fast_parse);
+            match read {
+                Err(CleoraError::MalformedRow { line, .. }) => assert_eq!(2, line),
+                other => panic!("Expected the malformed row error, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn fail_on_invalid_aliases() {
+        let input = std::env::temp_dir().join("cleora_invalid_aliases_input.tsv");
+        std::fs::write(&input, b"u1\ti1\n").unwrap();
+        let aliases = std::env::temp_dir().join("cleora_invalid_aliases.tsv");
+        std::fs::write(&aliases, b"u1\n").unwrap();
+        let columns = extract_fields(vec!["users", "items"]).unwrap();
+        let mut config = Configuration::default(input.to_str().unwrap().to_string(), columns);
+
+        config.aliases = Some(String::from("/nonexistent/cleora_aliases.tsv"));
+        let persistor = Arc::new(InMemoryEntityMappingPersistor::default());
+        let read = read_inputs(&config, persistor, None, |_| {});
+        assert!(matches!(read, Err(CleoraError::ReadFile { .. })));
+
+        config.aliases = Some(aliases.to_str().unwrap().to_string());
+        let persistor = Arc::new(InMemoryEntityMappingPersistor::default());
+        let read = read_inputs(&config, persistor, None, |_| {});
+        assert!(matches!(read, Err(CleoraError::InvalidAliases { .. })));
+        std::fs::remove_file(input).unwrap();
+        std::fs::remove_file(aliases).unwrap();
+    }
+
+    #[test]
+    fn fail_on_collisions() {
+        let columns = extract_fields(vec!["users", "items"]).unwrap();
+        let mut config = Configuration::default(String::from(""), columns);
+        let persistor = InMemoryEntityMappingPersistor::default();
+        persistor.put_data(1, String::from("users__a"));
+        persistor.put_data(2, String::from("users__b"));
+        assert!(report_collisions(&config, &persistor).is_ok());
+        config.fail_on_collision = true;
+        assert!(report_collisions(&config, &persistor).is_ok());
+
+        persistor.put_data(1, String::from("users__c"));
+        config.fail_on_collision = false;
+        assert!(report_collisions(&config, &persistor).is_ok());
+        config.fail_on_collision = true;
+        assert!(matches!(
+            report_collisions(&config, &persistor),
+            Err(CleoraError::HashCollisions { count: 1 })
+        ));
+
+        config.fail_on_collision = false;
+        config.collision_budget = Some(1);
+        assert!(report_collisions(&config, &persistor).is_ok());
+        persistor.put_data(2, String::from("users__d"));
+        assert!(matches!(
+            report_collisions(&config, &persistor),
+            Err(CleoraError::CollisionBudget {
+                count: 2,
+                budget: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn fast_parse_validates_stored_entities_only() {
+        // invalid UTF-8 in an ignored and a transient column, then in a stored one
+        let data = b"u1\t\xff\t\xfe\ti1\nu2\tx\ty\t\xff\n";
+        let read = |fast_parse: bool| {
+            let input = std::env::temp_dir().join(format!("cleora_fast_utf8_{}.tsv", fast_parse));
+            std::fs::write(&input, data).unwrap();
+            let columns =
+                extract_fields(vec!["users", "ignore::a", "transient::b", "items"]).unwrap();
+            let mut config = Configuration::default(input.to_str().unwrap().to_string(), columns);
+            config.fast_parse = fast_parse;
+            let persistor = Arc::new(InMemoryEntityMappingPersistor::default());
+            let mut rows = 0;
+            read_inputs(&config, persistor.clone(), None, |_| rows += 1).unwrap();
+            std::fs::remove_file(input).unwrap();
+            (rows, persistor)
+        };
+
+        // the regular parser validates whole lines
+        let (rows, persistor) = read(false);
+        assert_eq!(0, rows);
+        assert_eq!(0, persistor.len());
+        // the fast parser validates only entities stored in the entity mapping, the row with
+        // invalid stored entity leaves nothing behind
+        let (rows, persistor) = read(true);
+        assert!(rows > 0);
+        assert_eq!(2, persistor.len());
+        let mut entities: Vec<String> = persistor
+            .sample(usize::MAX)
+            .into_iter()
+            .map(|(_, entity)| entity.to_string())
+            .collect();
+        entities.sort();
+        assert_eq!(vec!["items__i1", "users__u1"], entities);
+    }
+
+    #[test]
+    fn read_delimited_input() {
+        assert_eq!(Ok(b'\t'), extract_delimiter("tab"));
+        assert_eq!(Ok(b'\t'), extract_delimiter("\\t"));
+        assert_eq!(Ok(b' '), extract_delimiter("space"));
+        assert_eq!(Ok(b','), extract_delimiter(","));
+        assert!(extract_delimiter("").is_err());
+        assert!(extract_delimiter(";;").is_err());
+        assert!(extract_delimiter("\n").is_err());
+        assert!(extract_delimiter("ą").is_err());
+
+        let fields = vec!["users", "complex::items", "complex::tags"];
+        let columns = extract_fields(fields.clone()).unwrap();
+        assert_eq!(
+            Ok(vec![
+                (String::from("items"), b'|'),
+                (String::from("tags"), b' ')
+            ]),
+            extract_value_delimiters("items:| tags:space", &columns)
+        );
+        assert!(extract_value_delimiters("items", &columns).is_err());
+        assert!(extract_value_delimiters("users:|", &columns).is_err());
+        assert!(extract_value_delimiters("brands:|", &columns).is_err());
+        assert!(extract_value_delimiters("items:| items:,", &columns).is_err());
+
+        // the same rows separated by tabs and spaces or by the configured delimiters
+        let read = |name: &str, data: &str, delimiters: Option<&str>, fast_parse: bool| {
+            let input = std::env::temp_dir().join(name);
+            std::fs::write(&input, data).unwrap();
+            let columns = extract_fields(fields.clone()).unwrap();
+            let mut config = Configuration::default(input.to_str().unwrap().to_string(), columns);
+            if let Some(delimiters) = delimiters {
+                config.field_delimiter = b',';
+                config.value_delimiters =
+                    extract_value_delimiters(delimiters, &config.columns).unwrap();
+            }
+            config.fast_parse = fast_parse;
+            let persistor = Arc::new(InMemoryEntityMappingPersistor::default());
+            let mut rows = vec![];
+            read_inputs(&config, persistor, None, |row| rows.push(row)).unwrap();
+            std::fs::remove_file(input).unwrap();
+            rows
+        };
+        let expected = read(
+            "cleora_delimited.tsv",
+            "u1\ti1 i2\tt1 t2\nu2\ti3\tt3\n",
+            None,
+            false,
+        );
+        assert_eq!(5, expected.len());
+        for fast_parse in [false, true] {
+            let rows = read(
+                "cleora_delimited.csv",
+                "u1,i1|i2,t1 t2\nu2,i3,t3\n",
+                Some("items:|"),
+                fast_parse,
+            );
+            assert_eq!(expected, rows);
+        }
+    }
+
+    #[test]
+    fn split_input_into_chunks() {
+        let data = b"a\tb\nccc\td\n\ne\tf";
+        assert_eq!(vec![0..4, 4..10, 10..11, 11..14], chunk_ranges(data, 1));
+        assert_eq!(vec![0..10, 10..14], chunk_ranges(data, 5));
+        assert_eq!(vec![0..14], chunk_ranges(data, 100));
+        assert!(chunk_ranges(b"", 5).is_empty());
+    }
+
+    #[test]
+    fn read_input_in_parallel_chunks() {
+        let input = std::env::temp_dir().join("cleora_parallel_input.tsv");
+        let mut data = String::new();
+        for i in 0..200 {
+            data.push_str(&format!("u{}\ti{} i{}\n", i % 17, i % 23, i % 5));
+        }
+        // malformed row and last line without new line
+        data.push_str("u1\nu2\ti2");
+        std::fs::write(&input, &data).unwrap();
+        let input = input.to_str().unwrap();
+        let columns = extract_fields(vec!["users", "complex::items"]).unwrap();
+        let config = Configuration::default(input.to_string(), columns);
+
+        let read = |chunk_size: Option<usize>| {
+            let persistor = Arc::new(InMemoryEntityMappingPersistor::default());
+            let mut reader =
+                InputReader::new(&config, None, persistor.clone(), chunk_size.unwrap_or(1));
+            let mut monitor = CardinalityMonitor::with_budget(&config, u64::MAX);
+            let mut combinations = vec![];
+            let mut handler =
+                |combination: SmallVec<[u64; SMALL_VECTOR_SIZE]>| combinations.push(combination);
+            let read = match chunk_size {
+                Some(_) => reader
+                    .read_in_parallel(input, Some(&mut monitor), &mut handler)
+                    .unwrap(),
+                None => {
+                    let entity_processor = EntityProcessor::new(&config, persistor, &mut handler)
+                        .with_cardinality_monitor(&mut monitor);
+                    reader.read_sequentially(input, entity_processor).unwrap()
+                }
+            };
+            let entities = monitor.estimated_entities(1);
+            (read, combinations, reader.malformed.count, entities)
+        };
+        let sequential = read(None);
+        assert_eq!((202, data.len() as u64), sequential.0);
+        assert_eq!(1, sequential.2);
+        assert_eq!(sequential, read(Some(7)));
+        assert_eq!(sequential, read(Some(1 << 20)));
+        std::fs::remove_file(input).unwrap();
+    }
+
+    #[test]
+    fn validate_output_templates() {
+        assert!(validate_output_template("{relation}/{column}_{timestamp}.{format}").is_ok());
+        assert!(validate_output_template("{relation}.out").is_err());
+        assert!(validate_output_template("{column}_{date}.out").is_err());
+        assert!(validate_output_template("{column}_{dim").is_err());
+    }
+
+    #[test]
+    fn train_selected_relations() {
+        let columns = extract_fields(vec![
+            "transient::users",
+            "products",
+            "reflexive::complex::brands",
+        ])
+        .unwrap();
+        let mut config = Configuration::default(String::from("input.tsv"), columns);
+        let pairs = |config: &Configuration| -> Vec<(u8, String, u8, String)> {
+            configured_sparse_matrices(config)
+                .into_iter()
+                .map(|m| (m.col_a_id, m.col_a_name, m.col_b_id, m.col_b_name))
+                .collect()
+        };
+        assert_eq!(4, pairs(&config).len());
+
+        let relations =
+            extract_relations("brands<->products, brands<->brands", &config.columns).unwrap();
+        config.relations = Some(relations);
+        assert_eq!(
+            vec![
+                (1, String::from("products"), 2, String::from("brands")),
+                (2, String::from("brands"), 3, String::from("brands")),
+            ],
+            pairs(&config)
+        );
+
+        let columns = &config.columns;
+        assert!(extract_relations("users:products", columns).is_err());
+        assert!(extract_relations("users<->sellers", columns).is_err());
+        assert!(extract_relations("products<->products", columns).is_err());
+        assert_eq!(
+            1,
+            extract_relations("users<->brands,brands<->users", columns)
+                .unwrap()
+                .len()
+        );
     }
 }
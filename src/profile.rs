@@ -0,0 +1,247 @@
+#[cfg(feature = "fs")]
+use crate::error::CleoraError;
+#[cfg(feature = "fs")]
+use crate::io::create_output;
+use serde_json::{json, Value};
+use std::alloc::{GlobalAlloc, Layout, System};
+#[cfg(feature = "fs")]
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Heap bytes allocated since profiling was enabled, less the freed ones. Frees of earlier
+/// allocations make it an offset, the peaks are relative to the heap of the enabled run.
+static ALLOCATED: AtomicIsize = AtomicIsize::new(0);
+/// Highest `ALLOCATED` since the first of the open stages started
+static PEAK: AtomicIsize = AtomicIsize::new(0);
+static OPEN_STAGES: AtomicUsize = AtomicUsize::new(0);
+/// Stages of the run in the order they finished
+static STAGES: Mutex<Vec<StageReport>> = Mutex::new(Vec::new());
+
+/// System allocator counting the heap in use while profiling, for the allocation peaks of the
+/// stages. It has to be installed as the global allocator of the binary, without it the peaks are
+/// reported as 0.
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            count(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            count(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        count(-(layout.size() as isize));
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            count(new_size as isize - layout.size() as isize);
+        }
+        new_ptr
+    }
+}
+
+#[inline]
+fn count(bytes: isize) {
+    if ENABLED.load(Ordering::Relaxed) {
+        let allocated = ALLOCATED.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if bytes > 0 {
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Starts recording the stages of the run
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Measurements of a finished stage
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageReport {
+    pub name: String,
+    /// Sparse matrix (see `SparseMatrixReader::get_id`) of the training stages
+    pub graph: Option<String>,
+    pub seconds: f64,
+    /// Highest heap in use during the stage. Stages running at the same time (e.g. training of
+    /// different column pairs) share it.
+    pub peak_bytes: u64,
+    /// Amount of work done, in `unit`s
+    pub items: u64,
+    pub unit: &'static str,
+}
+
+impl StageReport {
+    /// Items processed per second
+    pub fn throughput(&self) -> f64 {
+        if self.seconds > 0f64 {
+            self.items as f64 / self.seconds
+        } else {
+            0f64
+        }
+    }
+}
+
+/// Records the stage (if profiling is enabled)
+pub fn record(report: StageReport) {
+    if enabled() {
+        STAGES.lock().unwrap().push(report);
+    }
+}
+
+/// Wall time and allocation peak of a stage, from `start` until it's finished. Nothing is
+/// measured if profiling isn't enabled.
+pub struct Stage {
+    name: String,
+    start: Option<Instant>,
+}
+
+impl Stage {
+    pub fn start(name: impl Into<String>) -> Self {
+        let start = enabled().then(|| {
+            if OPEN_STAGES.fetch_add(1, Ordering::SeqCst) == 0 {
+                PEAK.store(ALLOCATED.load(Ordering::SeqCst), Ordering::SeqCst);
+            }
+            Instant::now()
+        });
+        Self {
+            name: name.into(),
+            start,
+        }
+    }
+
+    /// Stops the measurement, returns its name, wall time and allocation peak
+    pub fn stop(self) -> Option<(String, Duration, u64)> {
+        let start = self.start?;
+        let elapsed = start.elapsed();
+        let peak = PEAK.load(Ordering::SeqCst).max(0) as u64;
+        OPEN_STAGES.fetch_sub(1, Ordering::SeqCst);
+        Some((self.name, elapsed, peak))
+    }
+
+    /// Records the stage with the amount of work done in it
+    pub fn finish(self, graph: Option<String>, items: u64, unit: &'static str) {
+        if let Some((name, elapsed, peak_bytes)) = self.stop() {
+            record(StageReport {
+                name,
+                graph,
+                seconds: elapsed.as_secs_f64(),
+                peak_bytes,
+                items,
+                unit,
+            });
+        }
+    }
+}
+
+/// Sums the time of calls interleaved with other work, e.g. hashing of parsed rows. Calls aren't
+/// timed if profiling isn't enabled.
+#[derive(Debug, Default)]
+pub struct Timer {
+    pub elapsed: Duration,
+}
+
+impl Timer {
+    #[inline]
+    pub fn time<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        if !enabled() {
+            return f();
+        }
+        let start = Instant::now();
+        let result = f();
+        self.elapsed += start.elapsed();
+        result
+    }
+}
+
+/// Report of the recorded stages: wall time, allocation peak and throughput of every stage with
+/// totals of the run
+pub fn report(stages: &[StageReport], seconds: f64) -> Value {
+    let peak_bytes = stages.iter().map(|stage| stage.peak_bytes).max();
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "seconds": seconds,
+        "peak_bytes": peak_bytes.unwrap_or_default(),
+        "stages": stages
+            .iter()
+            .map(|stage| {
+                json!({
+                    "stage": stage.name,
+                    "graph": stage.graph,
+                    "seconds": stage.seconds,
+                    "peak_bytes": stage.peak_bytes,
+                    "items": stage.items,
+                    "unit": stage.unit,
+                    "throughput": stage.throughput(),
+                })
+            })
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// Prints the report of the stages recorded so far and writes it to the file
+#[cfg(feature = "fs")]
+pub fn write_report(filename: &str, run_start: Instant) -> Result<(), CleoraError> {
+    let stages = STAGES.lock().unwrap();
+    let report = report(&stages, run_start.elapsed().as_secs_f64());
+    let report = serde_json::to_string_pretty(&report).unwrap();
+    println!("{}", report);
+    let mut file = create_output(filename)?;
+    file.write_all(report.as_bytes())
+        .and_then(|_| file.flush())
+        .map_err(|e| CleoraError::write_file(filename, e))?;
+    file.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::profile::{report, StageReport};
+
+    #[test]
+    fn profile_report() {
+        let stage = |name: &str, seconds, peak_bytes, items, unit| StageReport {
+            name: name.to_string(),
+            graph: None,
+            seconds,
+            peak_bytes,
+            items,
+            unit,
+        };
+        let stages = vec![
+            stage("parse", 2.0, 1000, 4000, "bytes"),
+            stage("hash", 0.5, 1000, 100, "rows"),
+            stage("build", 0.0, 3000, 50, "entries"),
+        ];
+        let report = report(&stages, 3.0);
+        assert_eq!(3000, report["peak_bytes"]);
+        assert_eq!(3.0, report["seconds"]);
+        let stages = report["stages"].as_array().unwrap();
+        assert_eq!(3, stages.len());
+        assert_eq!("parse", stages[0]["stage"]);
+        assert_eq!(2000.0, stages[0]["throughput"]);
+        assert_eq!(200.0, stages[1]["throughput"]);
+        assert_eq!("rows", stages[1]["unit"]);
+        // stages too short to measure have no throughput
+        assert_eq!(0.0, stages[2]["throughput"]);
+        assert!(stages[2]["graph"].is_null());
+    }
+}
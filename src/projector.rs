@@ -0,0 +1,246 @@
+use crate::error::CleoraError;
+use crate::io::{create_output, OutputFile};
+use crate::persistence::embedding::{EmbeddingBatch, EmbeddingPersistor};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::hash::Hasher;
+use std::io::{BufWriter, Write};
+use twox_hash::XxHash64;
+
+/// Row kept in the sample, ordered by the hash of the entity
+struct SampledRow {
+    hash: u64,
+    row: usize,
+    entity: String,
+    occur_count: u32,
+    vector: Vec<f32>,
+}
+
+impl PartialEq for SampledRow {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SampledRow {}
+
+impl PartialOrd for SampledRow {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SampledRow {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.hash, self.row).cmp(&(other.hash, other.row))
+    }
+}
+
+/// Writes embeddings as `vectors.tsv` (tab separated values, row per entity) and `metadata.tsv`
+/// (entity names, with `occurrences` column and header row when they're produced) files, which
+/// can be loaded into the TensorBoard Embedding Projector as they are. Tabs and new lines in
+/// the names are replaced with spaces.
+///
+/// The Projector gets slow with many points, so rows can be subsampled to the given number of
+/// points: the entities with the lowest hashes of their names are kept (in the order of the
+/// rows), so the sample is uniform and doesn't depend on the order the rows are computed in.
+pub struct ProjectorPersistor {
+    vectors_file_name: String,
+    vectors_buf: BufWriter<OutputFile>,
+    metadata_file_name: String,
+    metadata_buf: BufWriter<OutputFile>,
+    produce_entity_occurrence_count: bool,
+    points: Option<usize>,
+    sample: BinaryHeap<SampledRow>,
+    rows: usize,
+}
+
+impl ProjectorPersistor {
+    pub fn new(
+        filename: String,
+        produce_entity_occurrence_count: bool,
+        points: Option<usize>,
+    ) -> Result<Self, CleoraError> {
+        let vectors_file_name = format!("{}.vectors.tsv", &filename);
+        let vectors_buf = BufWriter::new(create_output(&vectors_file_name)?);
+        let metadata_file_name = format!("{}.metadata.tsv", &filename);
+        let metadata_buf = BufWriter::new(create_output(&metadata_file_name)?);
+        Ok(Self {
+            vectors_file_name,
+            vectors_buf,
+            metadata_file_name,
+            metadata_buf,
+            produce_entity_occurrence_count,
+            points,
+            sample: BinaryHeap::new(),
+            rows: 0,
+        })
+    }
+
+    /// Files written for the embedding with given (`.out`) file name
+    pub fn output_files(filename: &str) -> Vec<String> {
+        vec![
+            format!("{}.vectors.tsv", filename),
+            format!("{}.metadata.tsv", filename),
+        ]
+    }
+
+    fn put_row<'a>(
+        &mut self,
+        entity: &str,
+        occur_count: u32,
+        vector: impl IntoIterator<Item = &'a f32>,
+    ) -> Result<(), CleoraError> {
+        let row = self.rows;
+        self.rows += 1;
+        let points = match self.points {
+            Some(points) => points,
+            None => return self.write_row(entity, occur_count, vector),
+        };
+
+        let mut hasher = XxHash64::default();
+        hasher.write(entity.as_bytes());
+        let hash = hasher.finish();
+        if self.sample.len() == points {
+            match self.sample.peek() {
+                Some(highest) if hash < highest.hash => {
+                    self.sample.pop();
+                }
+                _ => return Ok(()),
+            }
+        }
+        self.sample.push(SampledRow {
+            hash,
+            row,
+            entity: entity.to_string(),
+            occur_count,
+            vector: vector.into_iter().copied().collect(),
+        });
+        Ok(())
+    }
+
+    fn write_row<'a>(
+        &mut self,
+        entity: &str,
+        occur_count: u32,
+        vector: impl IntoIterator<Item = &'a f32>,
+    ) -> Result<(), CleoraError> {
+        let mut line = String::new();
+        for (i, &v) in vector.into_iter().enumerate() {
+            if i > 0 {
+                line.push('\t');
+            }
+            let mut buf = ryu::Buffer::new(); // cheap op
+            line.push_str(buf.format_finite(v));
+        }
+        line.push('\n');
+        self.vectors_buf
+            .write_all(line.as_bytes())
+            .map_err(|e| CleoraError::write_file(&self.vectors_file_name, e))?;
+
+        let mut line = entity.replace(['\t', '\n', '\r'], " ");
+        if self.produce_entity_occurrence_count {
+            line.push('\t');
+            line.push_str(&occur_count.to_string());
+        }
+        line.push('\n');
+        self.metadata_buf
+            .write_all(line.as_bytes())
+            .map_err(|e| CleoraError::write_file(&self.metadata_file_name, e))
+    }
+}
+
+impl EmbeddingPersistor for ProjectorPersistor {
+    fn put_metadata(&mut self, _entity_count: u32, _dimension: u16) -> Result<(), CleoraError> {
+        // the header row is expected only with more than one column
+        if self.produce_entity_occurrence_count {
+            self.metadata_buf
+                .write_all(b"entity\toccurrences\n")
+                .map_err(|e| CleoraError::write_file(&self.metadata_file_name, e))?;
+        }
+        Ok(())
+    }
+
+    fn put_data(
+        &mut self,
+        entity: &str,
+        occur_count: u32,
+        vector: Vec<f32>,
+    ) -> Result<(), CleoraError> {
+        self.put_row(entity, occur_count, &vector)
+    }
+
+    fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+        for (entity, occur_count, vector) in chunk.rows() {
+            self.put_row(entity, occur_count, vector)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), CleoraError> {
+        let mut sample = std::mem::take(&mut self.sample).into_vec();
+        sample.sort_unstable_by_key(|sampled| sampled.row);
+        for sampled in sample {
+            self.write_row(&sampled.entity, sampled.occur_count, &sampled.vector)?;
+        }
+
+        self.vectors_buf
+            .flush()
+            .map_err(|e| CleoraError::write_file(&self.vectors_file_name, e))?;
+        self.metadata_buf
+            .flush()
+            .map_err(|e| CleoraError::write_file(&self.metadata_file_name, e))?;
+        self.vectors_buf.get_mut().commit()?;
+        self.metadata_buf.get_mut().commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::persistence::embedding::{EmbeddingBatch, EmbeddingPersistor};
+    use crate::projector::ProjectorPersistor;
+
+    fn write(filename: &str, points: Option<usize>) -> (String, String) {
+        let mut persistor = ProjectorPersistor::new(filename.to_string(), true, points).unwrap();
+        persistor.put_metadata(3, 2).unwrap();
+        persistor.put_data("a\tb", 3, vec![1.0, -0.5]).unwrap();
+        persistor
+            .put_data_batch(EmbeddingBatch::new(
+                &["c".into(), "d".into()],
+                &[2, 1],
+                &[0.0, 1.0, 0.5, 0.25],
+                2,
+            ))
+            .unwrap();
+        persistor.finish().unwrap();
+        let vectors_file_name = format!("{}.vectors.tsv", filename);
+        let metadata_file_name = format!("{}.metadata.tsv", filename);
+        let files = (
+            std::fs::read_to_string(&vectors_file_name).unwrap(),
+            std::fs::read_to_string(&metadata_file_name).unwrap(),
+        );
+        std::fs::remove_file(vectors_file_name).unwrap();
+        std::fs::remove_file(metadata_file_name).unwrap();
+        files
+    }
+
+    #[test]
+    fn write_projector_files() {
+        let filename = std::env::temp_dir().join("cleora_projector_output");
+        let filename = filename.to_str().unwrap();
+        let (vectors, metadata) = write(filename, None);
+        assert_eq!("1.0\t-0.5\n0.0\t1.0\n0.5\t0.25\n", vectors);
+        assert_eq!("entity\toccurrences\na b\t3\nc\t2\nd\t1\n", metadata);
+
+        // sampled rows keep their order
+        let (vectors, metadata) = write(filename, Some(2));
+        assert_eq!(2, vectors.lines().count());
+        let entities: Vec<&str> = metadata.lines().skip(1).collect();
+        assert_eq!(2, entities.len());
+        let mut all = vec!["a b\t3", "c\t2", "d\t1"];
+        all.retain(|entity| entities.contains(entity));
+        assert_eq!(all, entities);
+        let (_, metadata) = write(filename, Some(3));
+        assert_eq!(4, metadata.lines().count());
+    }
+}
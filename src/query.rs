@@ -0,0 +1,177 @@
+use crate::loader::Embeddings;
+use ndarray::{Array2, ArrayView1, Axis};
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+use std::cmp::Ordering;
+
+/// Exact cosine similarity index. Vectors are L2-normalized up front, so every query is a single
+/// (parallel) scan of dot products.
+pub struct CosineIndex {
+    entities: Vec<String>,
+    positions: FxHashMap<String, usize>,
+    vectors: Array2<f32>,
+    /// Rows returned as neighbors, all if `None`
+    candidates: Option<Vec<usize>>,
+}
+
+impl CosineIndex {
+    pub fn new(embeddings: Embeddings) -> Self {
+        let mut vectors = embeddings.vectors;
+        for mut row in vectors.axis_iter_mut(Axis(0)) {
+            let norm = row.dot(&row).sqrt();
+            // zero vectors stay zero, they are similar to nothing
+            if norm > 0f32 {
+                row /= norm;
+            }
+        }
+        let positions = embeddings
+            .entities
+            .iter()
+            .enumerate()
+            .map(|(i, entity)| (entity.clone(), i))
+            .collect();
+        Self {
+            entities: embeddings.entities,
+            positions,
+            vectors,
+            candidates: None,
+        }
+    }
+
+    /// Returns only entities kept by the predicate as neighbors. Other entities can still be
+    /// queried.
+    pub fn restrict_neighbors(&mut self, keep: impl Fn(&str) -> bool) {
+        let candidates = self
+            .entities
+            .iter()
+            .enumerate()
+            .filter(|(_, entity)| keep(entity))
+            .map(|(i, _)| i)
+            .collect();
+        self.candidates = Some(candidates);
+    }
+
+    /// Number of entities returned as neighbors
+    pub fn neighbors_len(&self) -> usize {
+        self.candidates
+            .as_ref()
+            .map_or(self.entities.len(), |candidates| candidates.len())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.vectors.ncols()
+    }
+
+    /// Indexed entities, in the order of the embedding rows
+    pub fn entities(&self) -> &[String] {
+        &self.entities
+    }
+
+    /// L2-normalized vector of the entity, `None` if the entity isn't indexed
+    pub fn vector(&self, entity: &str) -> Option<ArrayView1<'_, f32>> {
+        let &position = self.positions.get(entity)?;
+        Some(self.vectors.row(position))
+    }
+
+    /// Returns `k` entities most similar to the given one (excluding itself), `None` if the entity
+    /// isn't indexed.
+    pub fn nearest_to_entity(&self, entity: &str, k: usize) -> Option<Vec<(&str, f32)>> {
+        let &position = self.positions.get(entity)?;
+        let neighbors = self
+            .nearest(self.vectors.row(position), k + 1)
+            .into_iter()
+            .filter(|&(e, _)| e != entity)
+            .take(k)
+            .collect();
+        Some(neighbors)
+    }
+
+    /// Returns `k` entities with the highest cosine similarity to the vector, the most similar
+    /// first.
+    pub fn nearest(&self, vector: ArrayView1<f32>, k: usize) -> Vec<(&str, f32)> {
+        let norm = vector.dot(&vector).sqrt();
+        let norm = if norm > 0f32 { norm } else { 1f32 };
+
+        let similarity = |i: usize| (i, self.vectors.row(i).dot(&vector) / norm);
+        let mut similarities: Vec<(usize, f32)> = match self.candidates.as_ref() {
+            Some(candidates) => candidates.par_iter().map(|&i| similarity(i)).collect(),
+            None => (0..self.entities.len())
+                .into_par_iter()
+                .map(similarity)
+                .collect(),
+        };
+        let by_similarity =
+            |a: &(usize, f32), b: &(usize, f32)| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal);
+        if k < similarities.len() {
+            similarities.select_nth_unstable_by(k, by_similarity);
+            similarities.truncate(k);
+        }
+        similarities.sort_by(by_similarity);
+
+        similarities
+            .into_iter()
+            .map(|(i, similarity)| (self.entities[i].as_str(), similarity))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loader::Embeddings;
+    use crate::query::CosineIndex;
+    use ndarray::arr2;
+
+    #[test]
+    fn find_nearest_entities() {
+        let index = CosineIndex::new(Embeddings {
+            entities: vec!["a", "b", "c", "d"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            vectors: arr2(&[[1.0, 0.0], [10.0, 1.0], [0.0, 1.0], [-1.0, 0.0]]),
+        });
+
+        let neighbors = index.nearest_to_entity("a", 2).unwrap();
+        assert_eq!(
+            vec!["b", "c"],
+            neighbors.iter().map(|n| n.0).collect::<Vec<_>>()
+        );
+        assert!((neighbors[1].1 - 0.0).abs() < 1e-6);
+
+        assert_eq!(3, index.nearest_to_entity("a", 10).unwrap().len());
+        assert!(index.nearest_to_entity("x", 2).is_none());
+    }
+
+    #[test]
+    fn restrict_neighbors() {
+        let mut index = CosineIndex::new(Embeddings {
+            entities: vec!["a", "b", "c", "d"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            vectors: arr2(&[[1.0, 0.0], [10.0, 1.0], [0.0, 1.0], [-1.0, 0.0]]),
+        });
+        index.restrict_neighbors(|entity| entity != "b");
+        assert_eq!(3, index.neighbors_len());
+
+        // entities left out are still queried
+        let neighbors = index.nearest_to_entity("b", 2).unwrap();
+        assert_eq!(
+            vec!["a", "c"],
+            neighbors.iter().map(|n| n.0).collect::<Vec<_>>()
+        );
+        let neighbors = index.nearest_to_entity("a", 2).unwrap();
+        assert_eq!(
+            vec!["c", "d"],
+            neighbors.iter().map(|n| n.0).collect::<Vec<_>>()
+        );
+    }
+}
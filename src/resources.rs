@@ -0,0 +1,165 @@
+use log::warn;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// Mount point of the unified (v2) cgroup hierarchy
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Rows of the output chunks without a memory limit
+pub const DEFAULT_CHUNK_SIZE: usize = 3000;
+
+/// Rows of the output chunks, however low the memory limit is
+const MIN_CHUNK_SIZE: usize = 100;
+
+/// Part of the memory limit the output chunks of a writer may take
+const CHUNK_MEMORY_SHARE: u64 = 64;
+
+/// CPU and memory limits of the container (cgroup v2) the process runs in
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ResourceLimits {
+    /// CPUs the cgroup may use (quota per period), e.g. 4 or 0.5
+    pub cpus: Option<f64>,
+    /// Memory the cgroup may use, in bytes
+    pub memory: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Reads the limits of the cgroup of the process and of its ancestors, the lowest of them
+    /// applies. There are none outside of a container or with cgroup v1.
+    pub fn detect() -> Self {
+        match fs::read_to_string("/proc/self/cgroup") {
+            Ok(cgroups) => match unified_cgroup(&cgroups) {
+                Some(cgroup) => Self::read(Path::new(CGROUP_ROOT), cgroup),
+                None => Self::default(),
+            },
+            Err(_) => Self::default(),
+        }
+    }
+
+    /// Reads `cpu.max` and `memory.max` of the cgroup (path relative to the root of the hierarchy)
+    /// and of its ancestors up to the root. Cgroups missing in the mount (e.g. a container sees
+    /// only its own cgroup, as the root) have the limits of the ancestors found in it.
+    fn read(root: &Path, cgroup: &str) -> Self {
+        let mut limits = Self::default();
+        let mut dir: PathBuf = root.join(cgroup.trim_start_matches('/'));
+        loop {
+            if let Some(cpus) = read_limit(&dir.join("cpu.max"), parse_cpu_max) {
+                limits.cpus = Some(limits.cpus.map_or(cpus, |limit| limit.min(cpus)));
+            }
+            if let Some(memory) = read_limit(&dir.join("memory.max"), parse_memory_max) {
+                limits.memory = Some(limits.memory.map_or(memory, |limit| limit.min(memory)));
+            }
+            if dir == root || !dir.pop() || !dir.starts_with(root) {
+                return limits;
+            }
+        }
+    }
+
+    /// Threads matching the CPU limit (rounded up), at most the available parallelism of the host
+    pub fn threads(&self) -> usize {
+        let available = thread::available_parallelism().map_or(1, |threads| threads.get());
+        match self.cpus {
+            Some(cpus) => (cpus.ceil() as usize).clamp(1, available),
+            None => available,
+        }
+    }
+
+    /// Rows of the output chunks: `DEFAULT_CHUNK_SIZE`, fewer when the chunks queued for the
+    /// writer of an output (and the ones being computed and written) would take more than 1/64 of
+    /// the memory limit
+    pub fn chunk_size(&self, dimension: u16, write_queue_size: usize) -> usize {
+        match self.memory {
+            Some(memory) => {
+                let row_bytes = dimension.max(1) as u64 * 4 * (write_queue_size as u64 + 2);
+                let rows = memory / CHUNK_MEMORY_SHARE / row_bytes;
+                (rows.min(DEFAULT_CHUNK_SIZE as u64) as usize).max(MIN_CHUNK_SIZE)
+            }
+            None => DEFAULT_CHUNK_SIZE,
+        }
+    }
+}
+
+/// Path of the process in the unified hierarchy, the `0::<path>` line of `/proc/self/cgroup`
+fn unified_cgroup(cgroups: &str) -> Option<&str> {
+    cgroups.lines().find_map(|line| line.strip_prefix("0::"))
+}
+
+fn read_limit<T>(path: &Path, parse: fn(&str) -> Option<T>) -> Option<T> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|value| parse(value.trim()))
+}
+
+/// CPUs of `cpu.max` (`$MAX $PERIOD`), `None` for `max`
+fn parse_cpu_max(value: &str) -> Option<f64> {
+    let mut parts = value.split_whitespace();
+    let quota: f64 = parts.next()?.parse().ok()?;
+    let period: f64 = parts.next().map_or(Some(100_000f64), |p| p.parse().ok())?;
+    (quota > 0f64 && period > 0f64).then(|| quota / period)
+}
+
+/// Bytes of `memory.max`, `None` for `max`
+fn parse_memory_max(value: &str) -> Option<u64> {
+    value.parse().ok()
+}
+
+/// Sets the number of threads of the global rayon pool, which parses the input and propagates
+/// the embeddings. It has to be called before the pool is used, later calls are ignored.
+pub fn init_thread_pool(threads: usize) {
+    if let Err(err) = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()
+    {
+        warn!("Can't set the number of threads. {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::resources::{
+        parse_cpu_max, parse_memory_max, unified_cgroup, ResourceLimits, DEFAULT_CHUNK_SIZE,
+    };
+
+    #[test]
+    fn parse_cgroup_files() {
+        assert_eq!(
+            Some("/kubepods/pod1"),
+            unified_cgroup("0::/kubepods/pod1\n")
+        );
+        assert_eq!(None, unified_cgroup("12:cpu,cpuacct:/docker/1\n"));
+        assert_eq!(Some(4f64), parse_cpu_max("400000 100000"));
+        assert_eq!(Some(0.5f64), parse_cpu_max("50000 100000"));
+        assert_eq!(None, parse_cpu_max("max 100000"));
+        assert_eq!(Some(1 << 30), parse_memory_max("1073741824"));
+        assert_eq!(None, parse_memory_max("max"));
+    }
+
+    #[test]
+    fn read_lowest_limits_of_ancestors() {
+        let root = std::env::temp_dir().join("cleora_cgroup_limits");
+        let pod = root.join("kubepods").join("pod1");
+        std::fs::create_dir_all(&pod).unwrap();
+        std::fs::write(root.join("kubepods").join("cpu.max"), "200000 100000\n").unwrap();
+        std::fs::write(root.join("kubepods").join("memory.max"), "max\n").unwrap();
+        std::fs::write(pod.join("cpu.max"), "max 100000\n").unwrap();
+        std::fs::write(pod.join("memory.max"), "536870912\n").unwrap();
+
+        let limits = ResourceLimits::read(&root, "/kubepods/pod1");
+        // a cgroup missing in the mount has the limits of its ancestors
+        let namespaced = ResourceLimits::read(&root, "/kubepods/pod1/other");
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(Some(2f64), limits.cpus);
+        assert_eq!(Some(512 << 20), limits.memory);
+        assert_eq!(limits, namespaced);
+        assert!(limits.threads() >= 1 && limits.threads() <= 2);
+
+        assert_eq!(2730, limits.chunk_size(128, 4));
+        assert_eq!(DEFAULT_CHUNK_SIZE, limits.chunk_size(8, 4));
+        assert_eq!(100, limits.chunk_size(u16::MAX, 4));
+        assert_eq!(
+            DEFAULT_CHUNK_SIZE,
+            ResourceLimits::default().chunk_size(1024, 4)
+        );
+    }
+}
@@ -0,0 +1,321 @@
+/// Streaming estimate of a single quantile with constant memory, based on the P-square algorithm
+/// (R. Jain, I. Chlamtac, "The P² algorithm for dynamic calculation of quantiles and histograms
+/// without storing observations"). Five markers track the minimum, the maximum, the quantile and
+/// two quantiles in between. Their heights are adjusted with piecewise-parabolic interpolation
+/// when the new value arrives.
+#[derive(Debug, Clone)]
+pub struct QuantileSketch {
+    /// Quantile to estimate, from [0, 1] range
+    p: f64,
+
+    /// Number of observed values
+    count: usize,
+
+    /// Marker heights (values)
+    heights: [f64; 5],
+
+    /// Actual marker positions
+    positions: [f64; 5],
+
+    /// Desired marker positions
+    desired: [f64; 5],
+
+    /// Increments of desired marker positions for every new value
+    increments: [f64; 5],
+}
+
+impl QuantileSketch {
+    pub fn new(p: f64) -> Self {
+        Self {
+            p,
+            count: 0,
+            heights: [0f64; 5],
+            positions: [1f64, 2f64, 3f64, 4f64, 5f64],
+            desired: [
+                1f64,
+                1f64 + 2f64 * p,
+                1f64 + 4f64 * p,
+                3f64 + 2f64 * p,
+                5f64,
+            ],
+            increments: [0f64, p / 2f64, p, (1f64 + p) / 2f64, 1f64],
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        // the first five values initialize the markers
+        if self.count < 5 {
+            self.heights[self.count] = value;
+            self.count += 1;
+            if self.count == 5 {
+                self.heights.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            }
+            return;
+        }
+        self.count += 1;
+
+        // cell in which the value falls, extreme markers are moved if needed
+        let k = if value < self.heights[0] {
+            self.heights[0] = value;
+            0
+        } else if value >= self.heights[4] {
+            self.heights[4] = value;
+            3
+        } else {
+            (1..5).find(|&i| value < self.heights[i]).unwrap() - 1
+        };
+
+        for position in self.positions[(k + 1)..].iter_mut() {
+            *position += 1f64;
+        }
+        for (desired, increment) in self.desired.iter_mut().zip(self.increments.iter()) {
+            *desired += increment;
+        }
+
+        // adjust heights of the middle markers if they are off their desired positions
+        for i in 1..4 {
+            let d = self.desired[i] - self.positions[i];
+            if (d >= 1f64 && self.positions[i + 1] - self.positions[i] > 1f64)
+                || (d <= -1f64 && self.positions[i - 1] - self.positions[i] < -1f64)
+            {
+                let sign = d.signum();
+                let height = self.parabolic(i, sign);
+                self.heights[i] = if self.heights[i - 1] < height && height < self.heights[i + 1] {
+                    height
+                } else {
+                    self.linear(i, sign)
+                };
+                self.positions[i] += sign;
+            }
+        }
+    }
+
+    /// Returns estimated quantile, `None` if no value was observed.
+    pub fn quantile(&self) -> Option<f64> {
+        match self.count {
+            0 => None,
+            count if count < 5 => {
+                let mut values = self.heights[..count].to_vec();
+                values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                let idx = (self.p * (count - 1) as f64).round() as usize;
+                Some(values[idx])
+            }
+            _ => Some(self.heights[2]),
+        }
+    }
+
+    fn parabolic(&self, i: usize, sign: f64) -> f64 {
+        let (h, n) = (&self.heights, &self.positions);
+        h[i] + sign / (n[i + 1] - n[i - 1])
+            * ((n[i] - n[i - 1] + sign) * (h[i + 1] - h[i]) / (n[i + 1] - n[i])
+                + (n[i + 1] - n[i] - sign) * (h[i] - h[i - 1]) / (n[i] - n[i - 1]))
+    }
+
+    fn linear(&self, i: usize, sign: f64) -> f64 {
+        let (h, n) = (&self.heights, &self.positions);
+        let j = if sign > 0f64 { i + 1 } else { i - 1 };
+        h[i] + sign * (h[j] - h[i]) / (n[j] - n[i])
+    }
+}
+
+/// Cardinality (number of distinct values) estimate with constant memory, based on the HyperLogLog
+/// algorithm (P. Flajolet, É. Fusy, O. Gandouet, F. Meunier, "HyperLogLog: the analysis of a
+/// near-optimal cardinality estimation algorithm"). Values are added as 64-bit hashes. The first
+/// `precision` bits select a register which keeps the maximum position of the first set bit in
+/// the remaining bits. The standard error is `1.04 / sqrt(2^precision)`.
+#[derive(Debug, Clone)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn new(precision: u8) -> Self {
+        assert!(
+            (4..=18).contains(&precision),
+            "HyperLogLog precision must be in [4, 18] range"
+        );
+        Self {
+            precision,
+            registers: vec![0u8; 1 << precision],
+        }
+    }
+
+    pub fn add_hash(&mut self, hash: u64) {
+        // entity hashes aren't always uniform (e.g. identity hashes), so the bits are mixed first
+        let hash = mix(hash);
+        let index = (hash >> (64 - self.precision)) as usize;
+        let rest = hash << self.precision;
+        let rank = (rest.leading_zeros() as u8).min(64 - self.precision) + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    /// Adds values of the other sketch, so the estimate is the cardinality of the union.
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        assert_eq!(
+            self.precision, other.precision,
+            "Sketches of different precision"
+        );
+        for (register, &other) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *register = (*register).max(other);
+        }
+    }
+
+    pub fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213f64 / (1f64 + 1.079f64 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|&r| 1f64 / (1u64 << r) as f64)
+            .sum();
+        let estimate = alpha * m * m / sum;
+
+        // small cardinalities are estimated better with linear counting of empty registers
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if estimate <= 2.5f64 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            estimate
+        }
+    }
+}
+
+/// Histogram of values from [-1, 1] range (e.g. cosine similarities) with equal width bins.
+/// Values out of the range are counted in the first or the last bin.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn new(bins: usize) -> Self {
+        assert!(bins > 0, "Histogram needs at least one bin");
+        Self {
+            counts: vec![0; bins],
+            sum: 0f64,
+        }
+    }
+
+    pub fn add(&mut self, value: f32) {
+        let bins = self.counts.len();
+        let bin = ((value + 1f32) / 2f32 * bins as f32).floor().max(0f32) as usize;
+        self.counts[bin.min(bins - 1)] += 1;
+        self.sum += value as f64;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        match self.count() {
+            0 => None,
+            count => Some(self.sum / count as f64),
+        }
+    }
+
+    /// Lower bound of the bin
+    pub fn bin_start(&self, bin: usize) -> f32 {
+        bin as f32 * 2f32 / self.counts.len() as f32 - 1f32
+    }
+
+    /// Fractions of the values falling into every bin
+    pub fn fractions(&self) -> Vec<f64> {
+        let count = self.count().max(1) as f64;
+        self.counts.iter().map(|&c| c as f64 / count).collect()
+    }
+}
+
+/// Finalizer of SplitMix64 generator, spreads every input bit over the whole output
+pub fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sketch::{Histogram, HyperLogLog, QuantileSketch};
+
+    #[test]
+    fn estimate_quantiles_of_shuffled_sequence() {
+        let mut lower = QuantileSketch::new(0.01);
+        let mut median = QuantileSketch::new(0.5);
+        let mut upper = QuantileSketch::new(0.99);
+        // permutation of 0..10007 (prime), so the values don't come sorted
+        for i in 0..10007u64 {
+            let value = ((i * 7919) % 10007) as f64;
+            lower.add(value);
+            median.add(value);
+            upper.add(value);
+        }
+
+        assert!((lower.quantile().unwrap() - 100f64).abs() < 50f64);
+        assert!((median.quantile().unwrap() - 5003f64).abs() < 100f64);
+        assert!((upper.quantile().unwrap() - 9906f64).abs() < 50f64);
+    }
+
+    #[test]
+    fn estimate_quantiles_of_few_values() {
+        let mut sketch = QuantileSketch::new(1.0);
+        assert_eq!(None, sketch.quantile());
+
+        sketch.add(3.0);
+        sketch.add(1.0);
+        sketch.add(2.0);
+        assert_eq!(Some(3.0), sketch.quantile());
+    }
+
+    #[test]
+    fn estimate_cardinality() {
+        let mut sketch = HyperLogLog::new(14);
+        assert_eq!(0f64, sketch.estimate());
+
+        // sequential values (as identity hashes), every one added twice
+        for i in 0..200_000u64 {
+            sketch.add_hash(i);
+            sketch.add_hash(i);
+        }
+        assert!((sketch.estimate() / 200_000f64 - 1f64).abs() < 0.03);
+
+        let mut small = HyperLogLog::new(14);
+        for i in 0..100u64 {
+            small.add_hash(i << 56);
+        }
+        assert!((small.estimate() - 100f64).abs() < 2f64);
+    }
+
+    #[test]
+    fn merge_sketches() {
+        let mut first = HyperLogLog::new(12);
+        let mut second = HyperLogLog::new(12);
+        for i in 0..30_000u64 {
+            first.add_hash(i);
+            second.add_hash(i + 20_000);
+        }
+        first.merge(&second);
+        assert!((first.estimate() / 50_000f64 - 1f64).abs() < 0.05);
+    }
+
+    #[test]
+    fn count_values_in_bins() {
+        let mut histogram = Histogram::new(4);
+        assert_eq!(None, histogram.mean());
+        for value in [-1.0, -0.6, 0.0, 0.2, 0.99, 1.0, 1.5] {
+            histogram.add(value);
+        }
+        assert_eq!(7, histogram.count());
+        assert_eq!(-0.5, histogram.bin_start(1));
+        let counts: Vec<f64> = histogram
+            .fractions()
+            .iter()
+            .map(|f| (f * 7f64).round())
+            .collect();
+        assert_eq!(vec![2.0, 0.0, 2.0, 3.0], counts);
+        assert!((histogram.mean().unwrap() - 2.09 / 7f64).abs() < 1e-6);
+    }
+}
@@ -1,9 +1,20 @@
-use crate::configuration::Column;
-use log::info;
+use crate::configuration::{Column, Configuration, PropagationKernel, SelfLoops};
+use crate::entity::decode_combinations;
+#[cfg(feature = "fs")]
+use crate::vocab::{read_str, read_u32, read_u64, write_str, write_u32, write_u64};
+use log::{info, warn};
 use rustc_hash::FxHashMap;
-use std::collections::hash_map;
+use std::cmp::Reverse;
+use std::convert::TryFrom;
+#[cfg(feature = "fs")]
+use std::io;
+#[cfg(feature = "fs")]
+use std::io::{Read, Write};
 use std::mem;
 
+/// Entries at or below this value are treated as fully subtracted (allows for rounding errors)
+const CANCELLED_ENTRY_VALUE: f32 = 1e-6;
+
 /// Creates combinations of column pairs as sparse matrices.
 /// Let's say that we have such columns configuration: complex::a reflexive::complex::b c. This is provided
 /// as `&[Column]` after parsing the config.
@@ -11,16 +22,20 @@ use std::mem;
 /// - transient - the field is virtual - it is considered during embedding process, no entity is written for the column,
 /// - complex   - the field is composite, containing multiple entity identifiers separated by space,
 /// - reflexive - the field is reflexive, which means that it interacts with itself, additional output file is written for every such field.
+///
 /// We create sparse matrix for every columns relations (based on column modifiers).
 /// For our example we have:
 /// - sparse matrix for column a and b,
 /// - sparse matrix for column a and c,
 /// - sparse matrix for column b and c,
 /// - sparse matrix for column b and b (reflexive column).
+///
 /// Apart from column names in sparse matrix we provide indices for incoming data. We have 3 columns such as a, b and c
 /// but column b is reflexive so we need to include this column. The result is: (a, b, c, b).
 /// The rule is that every reflexive column is append with the order of occurrence to the end of constructed array.
+/// Ignored columns (including the weight column) are left out of the incoming data, so they're skipped.
 pub fn create_sparse_matrices(cols: &[Column]) -> Vec<SparseMatrix> {
+    let cols: Vec<&Column> = cols.iter().filter(|c| !c.ignored).collect();
     let mut sparse_matrices: Vec<SparseMatrix> = Vec::new();
     let num_fields = cols.len();
     let mut reflexive_count = 0;
@@ -45,6 +60,137 @@ pub fn create_sparse_matrices(cols: &[Column]) -> Vec<SparseMatrix> {
     sparse_matrices
 }
 
+/// Discovers sensible column pairs (auto pairs mode). Like `create_sparse_matrices`, but columns
+/// marked with `ignore` are skipped and the excluded pairs (column names in any order, `(a, a)`
+/// excludes reflexive matrix of `a`) aren't created. Indices point to the positions in the
+/// incoming data, where ignored columns are left out.
+pub fn discover_sparse_matrices(
+    cols: &[Column],
+    excluded: &[(String, String)],
+) -> Vec<SparseMatrix> {
+    let is_excluded = |a: &str, b: &str| {
+        excluded
+            .iter()
+            .any(|(x, y)| (x == a && y == b) || (x == b && y == a))
+    };
+    let cols: Vec<&Column> = cols.iter().filter(|c| !c.ignored).collect();
+    let mut sparse_matrices: Vec<SparseMatrix> = Vec::new();
+    let num_fields = cols.len();
+    let mut reflexive_count = 0;
+
+    for i in 0..num_fields {
+        for j in i..num_fields {
+            let col_i = cols[i];
+            let col_j = cols[j];
+            if i < j && !(col_i.transient && col_j.transient) {
+                if !is_excluded(&col_i.name, &col_j.name) {
+                    let sm =
+                        SparseMatrix::new(i as u8, col_i.name.clone(), j as u8, col_j.name.clone());
+                    sparse_matrices.push(sm);
+                }
+            } else if i == j && col_i.reflexive {
+                // the position is taken even if the matrix is excluded
+                let new_j = num_fields + reflexive_count;
+                reflexive_count += 1;
+                if !is_excluded(&col_i.name, &col_i.name) {
+                    let sm = SparseMatrix::new(
+                        i as u8,
+                        col_i.name.clone(),
+                        new_j as u8,
+                        col_j.name.clone(),
+                    );
+                    sparse_matrices.push(sm);
+                }
+            }
+        }
+    }
+    sparse_matrices
+}
+
+/// Sparse matrices of the trained column pairs: all of them or the discovered ones in auto pairs
+/// mode, narrowed down to the selected relations.
+pub fn configured_sparse_matrices(config: &Configuration) -> Vec<SparseMatrix> {
+    let mut sparse_matrices = if config.auto_pairs {
+        discover_sparse_matrices(&config.columns, &config.excluded_pairs)
+    } else {
+        create_sparse_matrices(&config.columns)
+    };
+    if let Some(relations) = &config.relations {
+        // indices are kept, they point to the positions in the incoming data
+        let is_selected = |a: &str, b: &str| {
+            relations
+                .iter()
+                .any(|(x, y)| (x == a && y == b) || (x == b && y == a))
+        };
+        sparse_matrices.retain(|m| is_selected(&m.col_a_name, &m.col_b_name));
+        for (a, b) in relations {
+            let found = sparse_matrices.iter().any(|m| {
+                (m.col_a_name == *a && m.col_b_name == *b)
+                    || (m.col_a_name == *b && m.col_b_name == *a)
+            });
+            if !found {
+                warn!("Relation {} - {} is excluded, it isn't trained.", a, b);
+            }
+        }
+    }
+    sparse_matrices
+}
+
+/// Column pairs worth training in auto pairs mode, checked once the matrices are finished: pairs
+/// whose graph (of any edge type) has some structure, see `SparseMatrix::structure_issue`. Kept
+/// pairs are logged as discovered, skipped ones with the reason.
+pub fn select_column_pairs(sparse_matrices: Vec<SparseMatrix>) -> Vec<SparseMatrix> {
+    let same_pair = |a: &SparseMatrix, b: &SparseMatrix| {
+        a.col_a_name == b.col_a_name && a.col_b_name == b.col_b_name
+    };
+    // the first matrix of every pair with the issues of all matrices of the pair
+    let mut pairs: Vec<(&SparseMatrix, Vec<Option<String>>)> = Vec::new();
+    for sparse_matrix in sparse_matrices.iter() {
+        let issue = sparse_matrix.structure_issue();
+        match pairs
+            .iter_mut()
+            .find(|(first, _)| same_pair(first, sparse_matrix))
+        {
+            Some((_, issues)) => issues.push(issue),
+            None => pairs.push((sparse_matrix, vec![issue])),
+        }
+    }
+    let mut selected = Vec::new();
+    for (first, issues) in pairs {
+        if issues.iter().any(Option::is_none) {
+            info!(
+                "Discovered column pair: {} - {}",
+                first.col_a_name, first.col_b_name
+            );
+            selected.push((first.col_a_name.clone(), first.col_b_name.clone()));
+        } else {
+            info!(
+                "Skipped column pair: {} - {}, {}.",
+                first.col_a_name,
+                first.col_b_name,
+                issues[0].as_deref().unwrap_or_default()
+            );
+        }
+    }
+    if selected.is_empty() {
+        warn!("None of the column pairs has any structure, nothing is trained.");
+    }
+    sparse_matrices
+        .into_iter()
+        .filter(|m| selected.contains(&(m.col_a_name.clone(), m.col_b_name.clone())))
+        .collect()
+}
+
+/// Weight of the column, 1 if not configured
+fn column_weight(config: &Configuration, column: &str) -> f32 {
+    config
+        .column_weights
+        .iter()
+        .find(|(name, _)| name == column)
+        .map(|(_, weight)| *weight)
+        .unwrap_or(1f32)
+}
+
 /// Represents graph based on incoming data.
 /// It follows the sparse matrix coordinate format (COO). Its purpose is to save space by holding only
 /// the coordinates and values of nonzero entities.
@@ -62,11 +208,18 @@ pub struct SparseMatrix {
     /// Second column name
     pub col_b_name: String,
 
+    /// Index (as found at the end of the combinations) and label of the edge type, only
+    /// combinations of the edge type are handled. `None` handles all of them
+    pub edge_type: Option<(u64, String)>,
+
+    /// Both columns hold the same entity type (reflexive column)
+    reflexive: bool,
+
     /// Counts every occurrence of entity relationships from first and second column
     edge_count: u32,
 
     /// Maps entity hash to the id in such a way that each new hash gets another id (id + 1)
-    hash_2_id: FxHashMap<u64, u32>,
+    hash_2_id: HashIndex,
 
     /// Maps id to hash value and occurrence
     id_2_hash: Vec<Hash>,
@@ -79,6 +232,31 @@ pub struct SparseMatrix {
 
     /// Coordinates and values of nonzero entities
     entries: Vec<Entry>,
+
+    /// How entries are normalized by `finish`
+    kernel: PropagationKernel,
+
+    /// Weight of every relationship between two entities, product of the column weights
+    weight: f32,
+
+    /// Handling of self-loops
+    self_loops: SelfLoops,
+
+    /// Repeated edges are skipped, see `set_dedupe`
+    dedupe: bool,
+
+    /// Number of skipped repeated edges
+    duplicate_count: u32,
+
+    /// Combinations carry the multiplicity of their row, see `set_counted`
+    counted: bool,
+
+    /// Occurrences and column ids of the entities counted by the counting pass, see `count_pair`
+    counts: FxHashMap<u64, (u32, u8)>,
+
+    /// Entities left out of the handled pairs by the limits (see `set_limits`): removed ones map
+    /// to `None`, capped ones to the unknown entity of their column (if any)
+    filtered: FxHashMap<u64, Option<u64>>,
 }
 
 /// Hash data
@@ -89,13 +267,73 @@ pub struct Hash {
 
     /// Number of hash occurrences
     pub occurrence: u32,
+
+    /// Column (entity type) the hash comes from
+    pub column_id: u8,
 }
 
 impl Hash {
-    fn new(value: u64) -> Self {
+    fn new(value: u64, column_id: u8, occurrence: u32) -> Self {
         Self {
             value,
-            occurrence: 1,
+            occurrence,
+            column_id,
+        }
+    }
+}
+
+/// Map of entity hashes to ids. 32-bit hashes (see `SparseMatrix::set_narrow_hashes`) are kept
+/// as `u32` keys, which takes 8 instead of 12 bytes per entity.
+#[derive(Debug)]
+enum HashIndex {
+    Wide(FxHashMap<u64, u32>),
+    Narrow(FxHashMap<u32, u32>),
+}
+
+impl HashIndex {
+    fn with_capacity(narrow: bool, capacity: usize) -> Self {
+        let hasher = Default::default();
+        if narrow {
+            HashIndex::Narrow(FxHashMap::with_capacity_and_hasher(capacity, hasher))
+        } else {
+            HashIndex::Wide(FxHashMap::with_capacity_and_hasher(capacity, hasher))
+        }
+    }
+
+    #[inline]
+    fn narrow_key(hash: u64) -> u32 {
+        u32::try_from(hash).expect("Hash wider than 32 bits in 32-bit hash mode")
+    }
+
+    #[inline]
+    fn get(&self, hash: u64) -> Option<u32> {
+        match self {
+            HashIndex::Wide(map) => map.get(&hash).copied(),
+            HashIndex::Narrow(map) => map.get(&Self::narrow_key(hash)).copied(),
+        }
+    }
+
+    /// Id of the hash, `new_id` is given to a new one
+    #[inline]
+    fn get_or_insert(&mut self, hash: u64, new_id: u32) -> u32 {
+        match self {
+            HashIndex::Wide(map) => *map.entry(hash).or_insert(new_id),
+            HashIndex::Narrow(map) => *map.entry(Self::narrow_key(hash)).or_insert(new_id),
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    fn insert(&mut self, hash: u64, id: u32) {
+        match self {
+            HashIndex::Wide(map) => map.insert(hash, id),
+            HashIndex::Narrow(map) => map.insert(Self::narrow_key(hash), id),
+        };
+    }
+
+    fn mem_size(&self) -> usize {
+        match self {
+            HashIndex::Wide(map) => map.capacity() * 12,
+            HashIndex::Narrow(map) => map.capacity() * 8,
         }
     }
 }
@@ -144,20 +382,85 @@ impl<T: Copy> Iterator for CopyIter<'_, T> {
 
 impl SparseMatrix {
     pub fn new(col_a_id: u8, col_a_name: String, col_b_id: u8, col_b_name: String) -> Self {
+        let reflexive = col_a_name == col_b_name;
         Self {
             col_a_id,
             col_a_name,
             col_b_id,
             col_b_name,
+            edge_type: None,
+            reflexive,
             edge_count: 0,
-            hash_2_id: FxHashMap::default(),
+            hash_2_id: HashIndex::with_capacity(false, 0),
             id_2_hash: Vec::new(),
             row_sum: Vec::new(),
             pair_index: FxHashMap::default(),
             entries: Vec::new(),
+            kernel: PropagationKernel::Markov,
+            weight: 1f32,
+            self_loops: SelfLoops::Keep,
+            dedupe: false,
+            duplicate_count: 0,
+            counted: false,
+            counts: FxHashMap::default(),
+            filtered: FxHashMap::default(),
         }
     }
 
+    /// Restricts the matrix to the edges of the given type, has to be called before handling pairs
+    pub fn set_edge_type(&mut self, index: u64, label: String) {
+        self.edge_type = Some((index, label));
+    }
+
+    /// Sets weight of the relationships (by default 1), has to be called before handling pairs.
+    /// Self-loops aren't weighted, so the weight sets how much entities take from their neighbors
+    /// against keeping their own embeddings.
+    pub fn set_weight(&mut self, weight: f32) {
+        self.weight = weight;
+    }
+
+    /// Sets handling of the self-loops, has to be called before handling pairs
+    pub fn set_self_loops(&mut self, self_loops: SelfLoops) {
+        self.self_loops = self_loops;
+    }
+
+    /// Counts every edge once: edges already found (in any row) are skipped, so the first
+    /// occurrence gives the value of the entry and occurrences of the entities aren't counted
+    /// again. Has to be called before handling pairs
+    pub fn set_dedupe(&mut self, dedupe: bool) {
+        self.dedupe = dedupe;
+    }
+
+    /// Combinations carry the multiplicity of their row (count:: column) before the edge type, the
+    /// entities of the pair occur that many times. Has to be called before handling pairs
+    pub fn set_counted(&mut self, counted: bool) {
+        self.counted = counted;
+    }
+
+    /// Keys the hash to id index with 32-bit hashes (see `Configuration::hash_bits`), has to be
+    /// called before handling pairs
+    pub fn set_narrow_hashes(&mut self, narrow: bool) {
+        self.hash_2_id = HashIndex::with_capacity(narrow, 0);
+    }
+
+    /// Sets normalization of the entries, has to be called before `finish`
+    pub fn set_kernel(&mut self, kernel: PropagationKernel) {
+        self.kernel = kernel;
+    }
+
+    /// Applies the configured kernel, self-loops, deduplication, hash width and weight of the
+    /// columns, has to be called before handling pairs
+    pub fn configure(&mut self, config: &Configuration) {
+        self.set_kernel(config.kernel);
+        self.set_self_loops(config.self_loops);
+        self.set_dedupe(config.dedupe_edges);
+        self.set_counted(config.columns.iter().any(|c| c.count));
+        self.set_narrow_hashes(config.hash_bits == 32);
+        self.set_weight(
+            column_weight(config, &self.col_a_name) * column_weight(config, &self.col_b_name),
+        );
+    }
+
     /// Handles hashes for one combination of incoming data. Let's say that input row looks like:
     /// userId1   | productId1, productId2  | brandId1, brandId2
     /// Note! To simplify explanation there is no any reflexive column so the result is:
@@ -169,15 +472,54 @@ impl SparseMatrix {
     /// `col_a_id` and `col_b_id` (to corresponding columns) in order to read interesting hashes
     /// from provided slice. For one input row we actually call this function 4 times.
     pub fn handle_pair(&mut self, hashes: &[u64]) {
+        if let Some((edge_type, _)) = &self.edge_type {
+            if hashes.last() != Some(edge_type) {
+                return;
+            }
+        }
         let a = self.col_a_id;
         let b = self.col_b_id;
+        let (count, row_weight) = decode_combinations(hashes[0]);
+        let occurrences = self.row_count(hashes);
         self.add_pair_symmetric(
             hashes[(a + 1) as usize],
             hashes[(b + 1) as usize],
-            hashes[0],
+            count,
+            row_weight,
+            occurrences,
         );
     }
 
+    /// Multiplicity of the row of the combination, 1 for combinations without it
+    fn row_count(&self, hashes: &[u64]) -> u32 {
+        if !self.counted {
+            return 1;
+        }
+        let position = hashes.len() - 1 - self.edge_type.is_some() as usize;
+        hashes[position] as u32
+    }
+
+    /// Counts the occurrences of the entities of one combination like `handle_pair` does, without
+    /// adding any entry. Entity limits (see `set_limits`) are set by the counts of the counting pass,
+    /// which reads the whole input before the pairs are handled.
+    pub fn count_pair(&mut self, hashes: &[u64]) {
+        if let Some((edge_type, _)) = &self.edge_type {
+            if hashes.last() != Some(edge_type) {
+                return;
+            }
+        }
+        let b_column_id = if self.reflexive {
+            self.col_a_id
+        } else {
+            self.col_b_id
+        };
+        let a_hash = hashes[(self.col_a_id + 1) as usize];
+        let b_hash = hashes[(self.col_b_id + 1) as usize];
+        let occurrences = self.row_count(hashes);
+        self.counts.entry(a_hash).or_insert((0, self.col_a_id)).0 += occurrences;
+        self.counts.entry(b_hash).or_insert((0, b_column_id)).0 += occurrences;
+    }
+
     /// It creates sparse matrix for two columns in the incoming data.
     /// Let's say that we have such columns:
     /// customers | products                | brands
@@ -194,11 +536,45 @@ impl SparseMatrix {
     /// `a_hash` - hash of a entity for a column A
     /// `b_hash` - hash of a entity for a column B
     /// `count` - total number of combinations in a row
-    fn add_pair_symmetric(&mut self, a_hash: u64, b_hash: u64, count: u64) {
-        let a = self.update_hash_and_get_id(a_hash);
-        let b = self.update_hash_and_get_id(b_hash);
+    /// `row_weight` - weight of the input row, negative weight subtracts the relation
+    /// `occurrences` - multiplicity of the input row, occurrences of the entities it adds
+    fn add_pair_symmetric(
+        &mut self,
+        a_hash: u64,
+        b_hash: u64,
+        count: u32,
+        row_weight: f32,
+        occurrences: u32,
+    ) {
+        let a_column_id = self.col_a_id;
+        let b_column_id = if self.reflexive {
+            self.col_a_id
+        } else {
+            self.col_b_id
+        };
+        let (a_hash, b_hash) = match (self.kept_hash(a_hash), self.kept_hash(b_hash)) {
+            (Some(a_hash), Some(b_hash)) => (a_hash, b_hash),
+            // the remaining entity keeps its occurrence, like without the limits
+            (Some(a_hash), None) => return self.add_entity(a_hash, a_column_id, occurrences),
+            (None, Some(b_hash)) => return self.add_entity(b_hash, b_column_id, occurrences),
+            (None, None) => return,
+        };
+        if self.dedupe && self.has_pair(a_hash, b_hash) {
+            self.duplicate_count += 1;
+            return;
+        }
+        let a = self.update_hash_and_get_id(a_hash, a_column_id, occurrences);
+        let b = self.update_hash_and_get_id(b_hash, b_column_id, occurrences);
+
+        if a == b && self.self_loops != SelfLoops::Keep {
+            // the entity still counts as seen, its row sum has to exist for the ids that follow
+            self.update_row_sum(a, 0f32);
+            return;
+        }
 
-        let value = 1f32 / (count as f32);
+        // rows are normalized, so the column weights only count against the (unweighted) self-loops
+        let weight = if a == b { 1f32 } else { self.weight };
+        let value = weight * row_weight / (count as f32);
 
         self.edge_count += 1;
 
@@ -209,20 +585,38 @@ impl SparseMatrix {
         self.update_row_sum(b, value);
     }
 
-    fn update_hash_and_get_id(&mut self, hash: u64) -> u32 {
-        match self.hash_2_id.entry(hash) {
-            hash_map::Entry::Vacant(entry) => {
-                let id = self.id_2_hash.len() as u32;
-                entry.insert(id);
-                self.id_2_hash.push(Hash::new(hash));
-                id
-            }
-            hash_map::Entry::Occupied(entry) => {
-                let id = *entry.get();
-                self.id_2_hash[id as usize].occurrence += 1;
-                id
-            }
+    /// Whether the edge between entities of given hashes has its entry
+    fn has_pair(&self, a_hash: u64, b_hash: u64) -> bool {
+        match (self.hash_2_id.get(a_hash), self.hash_2_id.get(b_hash)) {
+            (Some(a), Some(b)) => self.pair_index.contains_key(&Self::magic_pair(a, b)),
+            _ => false,
+        }
+    }
+
+    /// Hash the entity is handled as (see `set_limits`), `None` if it's removed
+    fn kept_hash(&self, hash: u64) -> Option<u64> {
+        match self.filtered.get(&hash) {
+            Some(replacement) => *replacement,
+            None => Some(hash),
+        }
+    }
+
+    /// Counts occurrences of the entity without any entry
+    fn add_entity(&mut self, hash: u64, column_id: u8, occurrences: u32) {
+        let id = self.update_hash_and_get_id(hash, column_id, occurrences);
+        // its row sum has to exist for the ids that follow
+        self.update_row_sum(id, 0f32);
+    }
+
+    fn update_hash_and_get_id(&mut self, hash: u64, column_id: u8, occurrences: u32) -> u32 {
+        let new_id = self.id_2_hash.len() as u32;
+        let id = self.hash_2_id.get_or_insert(hash, new_id);
+        if id == new_id {
+            self.id_2_hash.push(Hash::new(hash, column_id, occurrences));
+        } else {
+            self.id_2_hash[id as usize].occurrence += occurrences;
         }
+        id
     }
 
     fn add_or_update_entry(&mut self, x: u32, y: u32, val: f32) {
@@ -263,15 +657,101 @@ impl SparseMatrix {
         };
     }
 
+    /// Returns sum of the row values before normalization
+    pub fn get_row_sum(&self, id: u32) -> f32 {
+        self.row_sum[id as usize]
+    }
+
+    /// Returns total number of handled entity relationships
+    pub fn get_number_of_edges(&self) -> u32 {
+        self.edge_count
+    }
+
+    /// Sets the entity limits by the occurrences of the counting pass (see `count_pair`), has to
+    /// be called after it, before handling pairs. Entities occurring fewer than `min_occurrence`
+    /// times are removed, so long tail entities don't get (poor) embeddings. Then the columns are
+    /// capped at their limits (see `entity_limits`): only `max_entities` most frequent entities
+    /// of the column are kept (ties broken by hash), the rest is removed or merged into one entity
+    /// with the unknown hash, which takes over their entries and occurrences. Pairs of the removed
+    /// entities aren't added, the other entity of such a pair only counts the occurrence.
+    pub fn set_limits(
+        &mut self,
+        min_occurrence: u32,
+        entity_limits: &[(String, usize, Option<u64>)],
+    ) {
+        let counts = mem::take(&mut self.counts);
+        let mut kept = Vec::with_capacity(counts.len());
+        for (hash, (occurrence, column_id)) in counts {
+            if occurrence < min_occurrence {
+                self.filtered.insert(hash, None);
+            } else {
+                kept.push((hash, occurrence, column_id));
+            }
+        }
+        if !self.filtered.is_empty() {
+            info!(
+                "Removed {} entities occurring fewer than {} times",
+                self.filtered.len(),
+                min_occurrence
+            );
+        }
+
+        for (column, max_entities, unknown_hash) in entity_limits {
+            let column_id = if *column == self.col_a_name {
+                self.col_a_id
+            } else if *column == self.col_b_name {
+                self.col_b_id
+            } else {
+                continue;
+            };
+            let mut column_entities: Vec<(u64, u32)> = kept
+                .iter()
+                .filter(|(_, _, id)| *id == column_id)
+                .map(|&(hash, occurrence, _)| (hash, occurrence))
+                .collect();
+            if column_entities.len() <= *max_entities {
+                continue;
+            }
+            column_entities.sort_unstable_by_key(|&(hash, occurrence)| (Reverse(occurrence), hash));
+            for &(hash, _) in &column_entities[*max_entities..] {
+                self.filtered.insert(hash, *unknown_hash);
+            }
+            info!(
+                "Capped column {} to {} most frequent entities, {} {}",
+                column,
+                max_entities,
+                column_entities.len() - max_entities,
+                if unknown_hash.is_some() {
+                    "merged into unknown entity"
+                } else {
+                    "removed"
+                }
+            );
+        }
+    }
+
     /// Normalization and other tasks after sparse matrix construction.
     pub fn finish(&mut self) {
+        if let SelfLoops::Add(weight) = self.self_loops {
+            for id in 0..self.id_2_hash.len() as u32 {
+                self.add_or_update_entry(id, id, weight);
+                self.update_row_sum(id, weight);
+            }
+        }
+        self.remove_cancelled_entries();
         self.normalize();
 
         info!("Number of entities: {}", self.get_number_of_entities());
         info!("Number of edges: {}", self.edge_count);
+        if self.dedupe {
+            info!(
+                "Number of skipped duplicate edges: {}",
+                self.duplicate_count
+            );
+        }
         info!("Number of entries: {}", self.get_number_of_entries());
 
-        let hash_2_id_mem_size = self.hash_2_id.capacity() * 12;
+        let hash_2_id_mem_size = self.hash_2_id.mem_size();
         let hash_mem_size = mem::size_of::<Hash>();
         let id_2_hash_mem_size = self.id_2_hash.capacity() * hash_mem_size;
         let row_sum_mem_size = self.row_sum.capacity() * 4;
@@ -292,17 +772,176 @@ impl SparseMatrix {
         );
     }
 
-    /// Normalize entries by dividing every entry value by row sum
+    /// Why the graph of the column pair has no structure worth embedding, `None` if it has:
+    /// there are no relations, one of the columns has a single entity (every entity relates to it,
+    /// so all of them get the same embedding) or entities are related one to one (every entity
+    /// gets the embedding of its only neighbor). Self-loops don't count.
+    pub fn structure_issue(&self) -> Option<String> {
+        let mut neighbors = vec![0u32; self.id_2_hash.len()];
+        for entry in self.entries.iter().filter(|entry| entry.row != entry.col) {
+            neighbors[entry.row as usize] += 1;
+        }
+        if neighbors.iter().all(|&count| count == 0) {
+            return Some(String::from("no relations"));
+        }
+        if !self.reflexive {
+            for (column_id, name) in [
+                (self.col_a_id, &self.col_a_name),
+                (self.col_b_id, &self.col_b_name),
+            ] {
+                let related = self
+                    .id_2_hash
+                    .iter()
+                    .zip(neighbors.iter())
+                    .filter(|(hash, &count)| hash.column_id == column_id && count > 0)
+                    .count();
+                if related == 1 {
+                    return Some(format!("a single related entity in column {}", name));
+                }
+            }
+        }
+        if neighbors.iter().all(|&count| count <= 1) {
+            return Some(String::from("entities related one to one"));
+        }
+        None
+    }
+
+    /// Writes the finished matrix: its columns, entities and normalized entries
+    #[cfg(feature = "fs")]
+    pub fn write_finished<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        writer.write_all(&[self.col_a_id, self.col_b_id])?;
+        write_str(writer, &self.col_a_name)?;
+        write_str(writer, &self.col_b_name)?;
+        match &self.edge_type {
+            Some((index, label)) => {
+                writer.write_all(&[1])?;
+                write_u64(writer, *index)?;
+                write_str(writer, label)?;
+            }
+            None => writer.write_all(&[0])?,
+        }
+        write_u32(writer, self.edge_count)?;
+        write_u32(writer, self.id_2_hash.len() as u32)?;
+        for (hash, row_sum) in self.id_2_hash.iter().zip(self.row_sum.iter()) {
+            write_u64(writer, hash.value)?;
+            write_u32(writer, hash.occurrence)?;
+            writer.write_all(&[hash.column_id])?;
+            writer.write_all(&row_sum.to_le_bytes())?;
+        }
+        write_u32(writer, self.entries.len() as u32)?;
+        for entry in self.entries.iter() {
+            write_u32(writer, entry.row)?;
+            write_u32(writer, entry.col)?;
+            writer.write_all(&entry.value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Reads the matrix written by `write_finished`. It's ready for training, but no pairs can be
+    /// handled anymore.
+    #[cfg(feature = "fs")]
+    pub fn read_finished<R: Read>(reader: &mut R) -> Result<SparseMatrix, io::Error> {
+        let mut ids = [0u8; 2];
+        reader.read_exact(&mut ids)?;
+        let mut sparse_matrix =
+            SparseMatrix::new(ids[0], read_str(reader)?, ids[1], read_str(reader)?);
+        let mut flag = [0u8; 1];
+        reader.read_exact(&mut flag)?;
+        if flag[0] == 1 {
+            let index = read_u64(reader)?;
+            sparse_matrix.set_edge_type(index, read_str(reader)?);
+        }
+        sparse_matrix.edge_count = read_u32(reader)?;
+        let entities = read_u32(reader)?;
+        for id in 0..entities {
+            let value = read_u64(reader)?;
+            let occurrence = read_u32(reader)?;
+            let mut column_id = [0u8; 1];
+            reader.read_exact(&mut column_id)?;
+            let mut row_sum = [0u8; 4];
+            reader.read_exact(&mut row_sum)?;
+            sparse_matrix.hash_2_id.insert(value, id);
+            sparse_matrix.id_2_hash.push(Hash {
+                value,
+                occurrence,
+                column_id: column_id[0],
+            });
+            sparse_matrix.row_sum.push(f32::from_le_bytes(row_sum));
+        }
+        let entries = read_u32(reader)?;
+        sparse_matrix.entries.reserve(entries as usize);
+        for _ in 0..entries {
+            let row = read_u32(reader)?;
+            let col = read_u32(reader)?;
+            let mut value = [0u8; 4];
+            reader.read_exact(&mut value)?;
+            if row >= entities || col >= entities {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("entry ({}, {}) out of {} entities", row, col, entities),
+                ));
+            }
+            sparse_matrix.entries.push(Entry {
+                row,
+                col,
+                value: f32::from_le_bytes(value),
+            });
+        }
+        Ok(sparse_matrix)
+    }
+
+    /// Removes entries whose relations were subtracted by negatively weighted rows (the value
+    /// dropped to zero or below). Lookups and row sums are rebuilt from the remaining entries.
+    fn remove_cancelled_entries(&mut self) {
+        if self
+            .entries
+            .iter()
+            .all(|entry| entry.value > CANCELLED_ENTRY_VALUE)
+        {
+            return;
+        }
+        let entries = mem::take(&mut self.entries);
+        let entries_count = entries.len();
+        self.pair_index = FxHashMap::default();
+        self.row_sum = vec![0f32; self.id_2_hash.len()];
+        for entry in entries {
+            if entry.value > CANCELLED_ENTRY_VALUE {
+                self.add_or_update_entry(entry.row, entry.col, entry.value);
+                self.row_sum[entry.row as usize] += entry.value;
+            }
+        }
+        info!(
+            "Removed {} entries cancelled by negative weights",
+            entries_count - self.entries.len()
+        );
+    }
+
+    /// Normalize entries by dividing every entry value by row sum (markov kernel) or by square
+    /// roots of row sums of its row and column (symmetric kernel)
     fn normalize(&mut self) {
-        for entry in self.entries.iter_mut() {
-            entry.value /= self.row_sum[entry.row as usize];
+        match self.kernel {
+            PropagationKernel::Markov => {
+                for entry in self.entries.iter_mut() {
+                    entry.value /= self.row_sum[entry.row as usize];
+                }
+            }
+            PropagationKernel::Symmetric => {
+                for entry in self.entries.iter_mut() {
+                    let degrees =
+                        self.row_sum[entry.row as usize] * self.row_sum[entry.col as usize];
+                    entry.value /= degrees.sqrt();
+                }
+            }
         }
     }
 }
 
 impl SparseMatrixReader for SparseMatrix {
     fn get_id(&self) -> String {
-        format!("{}_{}", self.col_a_id, self.col_b_id)
+        match &self.edge_type {
+            Some((_, label)) => format!("{}_{}_{}", self.col_a_id, self.col_b_id, label),
+            None => format!("{}_{}", self.col_a_id, self.col_b_id),
+        }
     }
 
     fn get_number_of_entities(&self) -> u32 {
@@ -324,10 +963,51 @@ impl SparseMatrixReader for SparseMatrix {
     }
 }
 
+/// Labels entities with their connected components (entries are edges). Returns the component of
+/// every entity and the number of components.
+pub fn connected_components<T: SparseMatrixReader + ?Sized>(reader: &T) -> (Vec<u32>, usize) {
+    let entities = reader.get_number_of_entities() as usize;
+    let mut parents: Vec<u32> = (0..entities as u32).collect();
+    for entry in reader.iter_entries() {
+        let (a, b) = (
+            find_root(&mut parents, entry.row),
+            find_root(&mut parents, entry.col),
+        );
+        if a != b {
+            parents[a.max(b) as usize] = a.min(b);
+        }
+    }
+
+    let mut labels: FxHashMap<u32, u32> = FxHashMap::default();
+    let components = (0..entities as u32)
+        .map(|id| {
+            let root = find_root(&mut parents, id);
+            let next_label = labels.len() as u32;
+            *labels.entry(root).or_insert(next_label)
+        })
+        .collect();
+    (components, labels.len())
+}
+
+/// Union-find root of the entity, paths are halved on the way
+fn find_root(parents: &mut [u32], mut id: u32) -> u32 {
+    while parents[id as usize] != id {
+        parents[id as usize] = parents[parents[id as usize] as usize];
+        id = parents[id as usize];
+    }
+    id
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::configuration::Column;
-    use crate::sparse_matrix::{create_sparse_matrices, Entry, SparseMatrix, SparseMatrixReader};
+    use crate::configuration::{
+        apply_output_columns, extract_fields, Column, PropagationKernel, SelfLoops,
+    };
+    use crate::entity::encode_combinations;
+    use crate::sparse_matrix::{
+        connected_components, create_sparse_matrices, discover_sparse_matrices,
+        select_column_pairs, Entry, SparseMatrix, SparseMatrixReader,
+    };
     use rustc_hash::FxHasher;
     use std::collections::{HashMap, HashSet};
     use std::hash::Hasher;
@@ -408,7 +1088,23 @@ mod tests {
             .iter()
             .cloned()
             .collect();
-        assert_eq!(expected_sparse_matrices, sparse_matrices)
+        assert_eq!(expected_sparse_matrices, sparse_matrices);
+
+        // weight column gives no entities
+        columns.insert(
+            0,
+            Column {
+                name: String::from("w"),
+                ignored: true,
+                weight: true,
+                ..Default::default()
+            },
+        );
+        let sparse_matrices = create_sparse_matrices(&columns);
+        assert_eq!(
+            expected_sparse_matrices,
+            map_to_ids_and_names(&sparse_matrices)
+        )
     }
 
     #[test]
@@ -451,6 +1147,402 @@ mod tests {
         assert_eq!(expected_sparse_matrices, sparse_matrices)
     }
 
+    #[test]
+    fn create_sparse_matrices_if_output_columns_provided() {
+        let columns = || {
+            extract_fields(vec![
+                "a",
+                "complex::reflexive::b",
+                "c",
+                "complex::reflexive::d",
+            ])
+            .unwrap()
+        };
+        let output_columns = apply_output_columns("a,d", columns()).unwrap();
+        let sparse_matrices = create_sparse_matrices(&output_columns);
+        let sparse_matrices: HashSet<_> = map_to_ids_and_names(&sparse_matrices);
+        // pairs without output columns aren't created, b loses the reflexive modifier
+        assert!(!output_columns[1].reflexive);
+        let expected_sparse_matrices: HashSet<_> = [
+            (0, "a", 1, "b"),
+            (0, "a", 2, "c"),
+            (0, "a", 3, "d"),
+            (1, "b", 3, "d"),
+            (2, "c", 3, "d"),
+            (3, "d", 4, "d"),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        assert_eq!(expected_sparse_matrices, sparse_matrices);
+
+        assert!(apply_output_columns("a,x", columns()).is_err());
+        assert!(apply_output_columns("", columns()).is_err());
+    }
+
+    #[test]
+    fn discover_sparse_matrices_without_ignored_and_excluded_pairs() {
+        let columns = [
+            Column {
+                name: String::from("a"),
+                ..Default::default()
+            },
+            Column {
+                name: String::from("b"),
+                ignored: true,
+                ..Default::default()
+            },
+            Column {
+                name: String::from("c"),
+                complex: true,
+                reflexive: true,
+                ..Default::default()
+            },
+            Column {
+                name: String::from("d"),
+                complex: true,
+                reflexive: true,
+                ..Default::default()
+            },
+        ];
+        let excluded = [
+            (String::from("d"), String::from("a")),
+            (String::from("c"), String::from("c")),
+        ];
+        let sparse_matrices = discover_sparse_matrices(&columns, &excluded);
+        let sparse_matrices: HashSet<_> = map_to_ids_and_names(&sparse_matrices);
+        // ignored column is left out of the incoming data: (a, c, d, c, d)
+        let expected_sparse_matrices: HashSet<_> =
+            [(0, "a", 1, "c"), (1, "c", 2, "d"), (2, "d", 4, "d")]
+                .iter()
+                .cloned()
+                .collect();
+        assert_eq!(expected_sparse_matrices, sparse_matrices)
+    }
+
+    #[test]
+    fn select_column_pairs_with_structure() {
+        let build = |col_b: &str, pairs: &[(&str, &str)]| {
+            let mut sm = SparseMatrix::new(0u8, String::from("users"), 1u8, col_b.to_string());
+            for (a, b) in pairs {
+                sm.handle_pair(&[1, hash(a), hash(b)]);
+            }
+            sm.finish();
+            sm
+        };
+        let items = build("items", &[("u1", "i1"), ("u1", "i2"), ("u2", "i1")]);
+        let countries = build("countries", &[("u1", "c1"), ("u2", "c1"), ("u3", "c1")]);
+        let ids = build("ids", &[("u1", "d1"), ("u2", "d2"), ("u1", "d1")]);
+        let empty = build("tags", &[]);
+        assert_eq!(None, items.structure_issue());
+        assert_eq!(
+            Some(String::from("a single related entity in column countries")),
+            countries.structure_issue()
+        );
+        assert_eq!(
+            Some(String::from("entities related one to one")),
+            ids.structure_issue()
+        );
+        assert_eq!(Some(String::from("no relations")), empty.structure_issue());
+
+        // a pair is kept if the graph of any of its edge types has some structure
+        let mut bought = build("ids", &[("u1", "d1"), ("u1", "d2"), ("u2", "d1")]);
+        bought.set_edge_type(1, String::from("bought"));
+        let selected = select_column_pairs(vec![items, countries, ids, bought, empty]);
+        let selected: Vec<&str> = selected.iter().map(|m| m.col_b_name.as_str()).collect();
+        assert_eq!(vec!["items", "ids", "ids"], selected);
+    }
+
+    #[test]
+    fn normalize_symmetrically() {
+        let mut sm = SparseMatrix::new(0u8, String::from("col_0"), 1u8, String::from("col_1"));
+        sm.set_kernel(PropagationKernel::Symmetric);
+        sm.handle_pair(&[1, hash("u1"), hash("p1")]);
+        sm.handle_pair(&[1, hash("u1"), hash("p2")]);
+        sm.finish();
+
+        // u1 has row sum 2, products 1
+        assert_eq!(4, sm.get_number_of_entries());
+        for entry in sm.iter_entries() {
+            assert!((entry.value - 1f32 / 2f32.sqrt()).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn handle_pairs_of_edge_type() {
+        let mut sm = SparseMatrix::new(0u8, String::from("col_0"), 1u8, String::from("col_1"));
+        sm.set_edge_type(1, String::from("bought"));
+        sm.handle_pair(&[1, hash("u1"), hash("p1"), 0]);
+        sm.handle_pair(&[1, hash("u1"), hash("p2"), 1]);
+        sm.finish();
+
+        assert_eq!(2, sm.get_number_of_entities());
+        assert_eq!(2, sm.get_number_of_entries());
+        assert_eq!("0_1_bought", sm.get_id());
+    }
+
+    #[test]
+    fn handle_duplicate_pairs() {
+        let pairs = [
+            [1, hash("u1"), hash("p1")],
+            [1, hash("u1"), hash("p1")],
+            [2, hash("u2"), hash("p1")],
+            [2, hash("p1"), hash("u2")],
+        ];
+        let build = |dedupe: bool| {
+            let mut sm = SparseMatrix::new(0u8, String::from("col_0"), 1u8, String::from("col_1"));
+            sm.set_dedupe(dedupe);
+            for pair in &pairs {
+                sm.handle_pair(pair);
+            }
+            sm.finish();
+            sm
+        };
+
+        let sm = build(false);
+        assert_eq!(4, sm.get_number_of_edges());
+        assert_eq!(4, sm.iter_hashes().map(|h| h.occurrence).max().unwrap());
+
+        // the edge in the opposite direction is the same edge
+        let sm = build(true);
+        assert_eq!(2, sm.get_number_of_edges());
+        assert_eq!(4, sm.get_number_of_entries());
+        assert_eq!(2, sm.iter_hashes().map(|h| h.occurrence).max().unwrap());
+        let u2 = sm
+            .iter_hashes()
+            .position(|h| h.value == hash("u2"))
+            .unwrap();
+        assert_eq!(0.5, sm.get_row_sum(u2 as u32));
+    }
+
+    #[test]
+    fn handle_pairs_with_narrow_hashes() {
+        let pairs = [[1, 1, 2], [1, 1, 3], [1, u64::from(u32::MAX), 2]];
+        let build = |narrow: bool| {
+            let mut sm = SparseMatrix::new(0u8, String::from("col_0"), 1u8, String::from("col_1"));
+            sm.set_narrow_hashes(narrow);
+            sm.set_dedupe(true);
+            for pair in &pairs {
+                sm.handle_pair(pair);
+            }
+            sm.finish();
+            sm
+        };
+
+        let wide = build(false);
+        let narrow = build(true);
+        assert_eq!(4, narrow.get_number_of_entities());
+        assert_eq!(
+            wide.iter_entries().collect::<Vec<_>>(),
+            narrow.iter_entries().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            wide.iter_hashes().map(|h| h.value).collect::<Vec<_>>(),
+            narrow.iter_hashes().map(|h| h.value).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn handle_self_loops() {
+        let pairs = [
+            [3, hash("a"), hash("a")],
+            [3, hash("a"), hash("b")],
+            [3, hash("b"), hash("a")],
+            [1, hash("c"), hash("c")],
+        ];
+        let self_loop_values = |self_loops: SelfLoops| {
+            let mut sm = SparseMatrix::new(0u8, String::from("col_0"), 1u8, String::from("col_0"));
+            sm.set_self_loops(self_loops);
+            for pair in &pairs {
+                sm.handle_pair(pair);
+            }
+            sm.finish();
+            assert_eq!(3, sm.get_number_of_entities());
+            let mut values: Vec<(u32, f32)> = sm
+                .iter_entries()
+                .filter(|e| e.row == e.col)
+                .map(|e| (e.row, e.value))
+                .collect();
+            values.sort_by_key(|&(row, _)| row);
+            values
+        };
+
+        // a: self-loop 2 * 1/3 (both directions land on the same entry) and 2/3 to b
+        let values = self_loop_values(SelfLoops::Keep);
+        assert_eq!(2, values.len());
+        assert!((values[0].1 - 0.5).abs() < 1e-6);
+        assert!((values[1].1 - 1f32).abs() < 1e-6);
+        // c has no other relationships, its row is empty
+        assert!(self_loop_values(SelfLoops::Drop).is_empty());
+        // a and b: self-loop 1 and 2/3 to each other
+        let values = self_loop_values(SelfLoops::Add(1f32));
+        assert_eq!(3, values.len());
+        assert!((values[0].1 - 0.6).abs() < 1e-6);
+        assert!((values[2].1 - 1f32).abs() < 1e-6);
+    }
+
+    #[test]
+    fn subtract_negatively_weighted_rows() {
+        let mut sm = SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+        let pairs = [
+            [encode_combinations(1, 1f32), hash("u1"), hash("i1")],
+            [encode_combinations(1, 2f32), hash("u1"), hash("i2")],
+            [encode_combinations(1, 1f32), hash("u2"), hash("i1")],
+            // u2 unfollows i1, u1 partially
+            [encode_combinations(1, -1f32), hash("u2"), hash("i1")],
+            [encode_combinations(1, -1f32), hash("u1"), hash("i2")],
+        ];
+        for pair in &pairs {
+            sm.handle_pair(pair);
+        }
+        sm.finish();
+
+        assert_eq!(4, sm.get_number_of_entities());
+        assert_eq!(4, sm.get_number_of_entries());
+        let hash_2_id: HashMap<u64, u32> = sm
+            .iter_hashes()
+            .enumerate()
+            .map(|(id, hash)| (hash.value, id as u32))
+            .collect();
+        let u1 = hash_2_id[&hash("u1")];
+        let u2 = hash_2_id[&hash("u2")];
+        assert!(sm.iter_entries().all(|e| e.row != u2 && e.col != u2));
+        // both items of u1 have the same weight left
+        for entry in sm.iter_entries().filter(|e| e.row == u1) {
+            assert!((entry.value - 0.5).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn count_occurrences_of_counted_rows() {
+        let mut sm = SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+        sm.set_counted(true);
+        sm.set_edge_type(1, String::from("bought"));
+        let pairs = [
+            [encode_combinations(1, 3f32), hash("u1"), hash("i1"), 3, 1],
+            [encode_combinations(1, 1f32), hash("u1"), hash("i2"), 1, 1],
+            [encode_combinations(1, 2f32), hash("u2"), hash("i1"), 2, 1],
+            // other edge type
+            [encode_combinations(1, 5f32), hash("u2"), hash("i2"), 5, 0],
+        ];
+        for pair in &pairs {
+            sm.handle_pair(pair);
+        }
+        sm.finish();
+
+        let occurrences: HashMap<u64, u32> = sm
+            .iter_hashes()
+            .map(|hash| (hash.value, hash.occurrence))
+            .collect();
+        assert_eq!(4, occurrences[&hash("u1")]);
+        assert_eq!(2, occurrences[&hash("u2")]);
+        assert_eq!(5, occurrences[&hash("i1")]);
+        assert_eq!(1, occurrences[&hash("i2")]);
+    }
+
+    #[test]
+    fn label_connected_components() {
+        let mut sm = SparseMatrix::new(0u8, String::from("col_0"), 1u8, String::from("col_1"));
+        sm.handle_pair(&[1, hash("u1"), hash("p1")]);
+        sm.handle_pair(&[1, hash("u2"), hash("p1")]);
+        sm.handle_pair(&[1, hash("u3"), hash("p2")]);
+        sm.handle_pair(&[1, hash("u2"), hash("p3")]);
+        sm.finish();
+
+        let (components, count) = connected_components(&sm);
+        assert_eq!(2, count);
+        let component = |entity: &str| {
+            let id = sm
+                .iter_hashes()
+                .position(|h| h.value == hash(entity))
+                .unwrap();
+            components[id]
+        };
+        assert_eq!(component("u1"), component("p3"));
+        assert_eq!(component("u3"), component("p2"));
+        assert_ne!(component("u1"), component("u3"));
+    }
+
+    #[test]
+    fn prune_rare_entities() {
+        let mut sm = SparseMatrix::new(0u8, String::from("col_0"), 1u8, String::from("col_1"));
+        let pairs = [
+            [1, hash("u1"), hash("p1")],
+            [1, hash("u1"), hash("p2")],
+            [2, hash("u2"), hash("p1")],
+            [2, hash("u2"), hash("p3")],
+        ];
+        for pair in pairs.iter() {
+            sm.count_pair(pair);
+        }
+        sm.set_limits(2, &[]);
+        for pair in pairs.iter() {
+            sm.handle_pair(pair);
+        }
+        sm.finish();
+
+        let hashes: HashSet<u64> = sm.iter_hashes().map(|h| h.value).collect();
+        let expected_hashes: HashSet<u64> = [hash("u1"), hash("u2"), hash("p1")]
+            .iter()
+            .cloned()
+            .collect();
+        assert_eq!(expected_hashes, hashes);
+        // only u1 - p1 and u2 - p1 edges are left, every row is normalized
+        assert_eq!(4, sm.get_number_of_entries());
+        // occurrences with the removed entities still count
+        assert!(sm.iter_hashes().all(|h| h.occurrence == 2));
+        let p1 = sm
+            .iter_hashes()
+            .position(|h| h.value == hash("p1"))
+            .unwrap() as u32;
+        let mut p1_row_sum = 0f32;
+        for entry in sm.iter_entries() {
+            if entry.row == p1 {
+                p1_row_sum += entry.value;
+            } else {
+                assert!((entry.value - 1f32).abs() < 1e-6);
+            }
+        }
+        assert!((p1_row_sum - 1f32).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cap_column_entities() {
+        let pairs = [
+            ("u1", "p1"),
+            ("u1", "p2"),
+            ("u2", "p1"),
+            ("u2", "p3"),
+            ("u3", "p1"),
+        ];
+        let build = |unknown_hash: Option<u64>| {
+            let mut sm = SparseMatrix::new(0u8, String::from("col_0"), 1u8, String::from("col_1"));
+            for (u, p) in pairs.iter() {
+                sm.count_pair(&[1, hash(u), hash(p)]);
+            }
+            sm.set_limits(1, &[(String::from("col_1"), 1, unknown_hash)]);
+            for (u, p) in pairs.iter() {
+                sm.handle_pair(&[1, hash(u), hash(p)]);
+            }
+            sm.finish();
+            sm
+        };
+
+        // p1 is the most frequent, p2 and p3 are removed with their entries
+        let sm = build(None);
+        assert_eq!(4, sm.get_number_of_entities());
+        assert_eq!(6, sm.get_number_of_entries());
+
+        // p2 and p3 become one unknown entity
+        let sm = build(Some(42));
+        assert_eq!(5, sm.get_number_of_entities());
+        let unknown = sm.iter_hashes().find(|h| h.value == 42).unwrap();
+        assert_eq!(2, unknown.occurrence);
+        assert_eq!(1, unknown.column_id);
+        // u1 - p2 and u2 - p3 edges become u1 - unknown and u2 - unknown
+        assert_eq!(10, sm.get_number_of_entries());
+    }
+
     #[test]
     fn create_sparse_matrix_for_undirected_graph() {
         let mut sm = SparseMatrix::new(0u8, String::from("col_0"), 1u8, String::from("col_1"));
@@ -0,0 +1,189 @@
+use crate::error::CleoraError;
+use crate::io::{commit_file, partial_path};
+use crate::persistence::embedding::{EmbeddingBatch, EmbeddingPersistor};
+use rusqlite::{params, Connection};
+use std::fs;
+
+/// Table of the embeddings in the database
+pub const SQLITE_TABLE: &str = "embeddings";
+
+/// Key-value table describing the vectors, so readers don't have to assume their layout
+pub const SQLITE_METADATA_TABLE: &str = "metadata";
+
+/// Writes embeddings into a compact SQLite database, for services and edge devices which can't
+/// read npy or parquet. The `embeddings` table has `entity TEXT PRIMARY KEY`, `vector BLOB` of
+/// packed little-endian f32 values and `occur_count INTEGER` (null without occurrences), without
+/// row ids. The `metadata` table holds `dimension`, `dtype` and `count`. Rows of a chunk are
+/// inserted in a transaction, the database is vacuumed when finished and written under its
+/// temporary name (staged locally for S3 outputs) until then.
+pub struct SqlitePersistor {
+    filename: String,
+    connection: Option<Connection>,
+    produce_entity_occurrence_count: bool,
+    dimension: u16,
+    count: u64,
+}
+
+impl SqlitePersistor {
+    pub fn new(
+        filename: String,
+        produce_entity_occurrence_count: bool,
+    ) -> Result<Self, CleoraError> {
+        let path = partial_path(&filename);
+        // leftovers of a failed run would be opened as the database
+        let _ = fs::remove_file(&path);
+        let connection = Connection::open(&path).map_err(|e| CleoraError::sqlite(&filename, e))?;
+        // the database isn't visible until it's committed, so it doesn't need the journal
+        connection
+            .execute_batch(&format!(
+                "PRAGMA journal_mode = OFF; PRAGMA synchronous = OFF;
+                CREATE TABLE {} (entity TEXT PRIMARY KEY, vector BLOB NOT NULL, occur_count INTEGER) WITHOUT ROWID;
+                CREATE TABLE {} (key TEXT PRIMARY KEY, value TEXT NOT NULL) WITHOUT ROWID;",
+                SQLITE_TABLE, SQLITE_METADATA_TABLE
+            ))
+            .map_err(|e| CleoraError::sqlite(&filename, e))?;
+        Ok(Self {
+            filename,
+            connection: Some(connection),
+            produce_entity_occurrence_count,
+            dimension: 0,
+            count: 0,
+        })
+    }
+
+    fn connection(&mut self) -> &mut Connection {
+        self.connection
+            .as_mut()
+            .expect("Should be defined. Was finish called already?")
+    }
+
+    fn put_metadata_value(&mut self, key: &str, value: String) -> Result<(), CleoraError> {
+        let filename = self.filename.clone();
+        self.connection()
+            .execute(
+                &format!(
+                    "INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)",
+                    SQLITE_METADATA_TABLE
+                ),
+                params![key, value],
+            )
+            .map(|_| ())
+            .map_err(|e| CleoraError::sqlite(&filename, e))
+    }
+}
+
+impl EmbeddingPersistor for SqlitePersistor {
+    fn put_metadata(&mut self, _entity_count: u32, dimension: u16) -> Result<(), CleoraError> {
+        self.dimension = dimension;
+        self.put_metadata_value("dimension", dimension.to_string())?;
+        self.put_metadata_value("dtype", String::from("<f4"))
+    }
+
+    fn put_data(
+        &mut self,
+        entity: &str,
+        occur_count: u32,
+        vector: Vec<f32>,
+    ) -> Result<(), CleoraError> {
+        let dimension = vector.len();
+        self.put_data_batch(EmbeddingBatch::new(
+            &[entity.into()],
+            &[occur_count],
+            &vector,
+            dimension,
+        ))
+    }
+
+    fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+        let filename = self.filename.clone();
+        let produce_entity_occurrence_count = self.produce_entity_occurrence_count;
+        let sqlite_error = |e| CleoraError::sqlite(&filename, e);
+
+        let transaction = self.connection().transaction().map_err(sqlite_error)?;
+        {
+            let mut statement = transaction
+                .prepare_cached(&format!(
+                    "INSERT INTO {} (entity, vector, occur_count) VALUES (?1, ?2, ?3)",
+                    SQLITE_TABLE
+                ))
+                .map_err(sqlite_error)?;
+            for (entity, occur_count, vector) in chunk.rows() {
+                let vector: Vec<u8> = vector.iter().flat_map(|v| v.to_le_bytes()).collect();
+                let occur_count = Some(occur_count).filter(|_| produce_entity_occurrence_count);
+                statement
+                    .execute(params![entity, vector, occur_count])
+                    .map_err(sqlite_error)?;
+            }
+        }
+        transaction.commit().map_err(sqlite_error)?;
+        self.count += chunk.len() as u64;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), CleoraError> {
+        self.put_metadata_value("count", self.count.to_string())?;
+        let filename = self.filename.clone();
+        // pages of the table are rewritten in the order of the keys, without free space
+        self.connection()
+            .execute_batch("VACUUM;")
+            .map_err(|e| CleoraError::sqlite(&filename, e))?;
+        if let Some(connection) = self.connection.take() {
+            connection
+                .close()
+                .map_err(|(_, e)| CleoraError::sqlite(&filename, e))?;
+        }
+        commit_file(&self.filename)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::persistence::embedding::{EmbeddingBatch, EmbeddingPersistor};
+    use crate::sqlite_output::SqlitePersistor;
+    use rusqlite::Connection;
+
+    #[test]
+    fn write_sqlite_database() {
+        let filename = std::env::temp_dir().join("cleora_sqlite_output.sqlite");
+        let filename = filename.to_str().unwrap();
+        let _ = std::fs::remove_file(filename);
+
+        let mut persistor = SqlitePersistor::new(filename.to_string(), true).unwrap();
+        persistor.put_metadata(3, 2).unwrap();
+        persistor.put_data("a", 3, vec![1.0, -0.5]).unwrap();
+        persistor
+            .put_data_batch(EmbeddingBatch::new(
+                &["b".into(), "c".into()],
+                &[2, 1],
+                &[0.0, 1.0, 0.5, 0.5],
+                2,
+            ))
+            .unwrap();
+        persistor.finish().unwrap();
+
+        let connection = Connection::open(filename).unwrap();
+        let (vector, occur_count): (Vec<u8>, u32) = connection
+            .query_row(
+                "SELECT vector, occur_count FROM embeddings WHERE entity = 'a'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        let expected: Vec<u8> = [1.0f32, -0.5]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        assert_eq!(expected, vector);
+        assert_eq!(3, occur_count);
+        let count: String = connection
+            .query_row(
+                "SELECT value FROM metadata WHERE key = 'count'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!("3", count);
+        connection.close().unwrap();
+        std::fs::remove_file(filename).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+use crate::alignment::{orthogonal_procrustes, residuals};
+use crate::configuration::{EntitiesFormat, OutputFormat};
+use crate::error::CleoraError;
+use crate::io::{commit_file, create_partial_file};
+use crate::loader::{load_embeddings, Embeddings};
+use crate::pipeline::create_embedding_persistor;
+use log::{info, warn};
+use ndarray::Axis;
+use rustc_hash::FxHashMap;
+use std::io::{BufWriter, Write};
+
+/// Alignment summary of a single partition
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionReport {
+    pub path: String,
+    /// Entities shared with the partitions stitched before
+    pub anchors: usize,
+    /// Entities added to the unified space
+    pub new_entities: usize,
+    /// Root mean square distance of the anchors to the unified space, before and after alignment
+    pub rmse_before: f32,
+    pub rmse_after: f32,
+    pub max_residual: f32,
+}
+
+/// Path of the residuals report written next to the stitched embeddings
+pub fn report_file_name(output: &str) -> String {
+    format!("{}.residuals.tsv", output)
+}
+
+/// Stitches embeddings of disjoint entity partitions into one space and writes it to `output`
+/// with the residuals report. The first partition is the reference space, every next one is
+/// rotated onto the entities stitched so far (anchors) which it shares. Anchors keep their
+/// reference vectors. Every partition needs at least `min_anchors` anchors, dimension by default.
+pub fn stitch(
+    paths: &[String],
+    output: String,
+    output_format: &OutputFormat,
+    min_anchors: Option<usize>,
+) -> Result<Vec<PartitionReport>, CleoraError> {
+    let mut partitions = Vec::with_capacity(paths.len());
+    for path in paths {
+        info!("Loading partition {}.", path);
+        partitions.push((path.clone(), load_embeddings(path)?));
+    }
+    let (unified, reports) = stitch_partitions(partitions, min_anchors)?;
+
+    let mut persistor = create_embedding_persistor(
+        output_format,
+        output.clone(),
+        unified.dimension() as u16,
+        false,
+        EntitiesFormat::Json,
+        true,
+    )?;
+    persistor.put_metadata(unified.entities.len() as u32, unified.dimension() as u16)?;
+    for (entity, vector) in unified.entities.iter().zip(unified.vectors.outer_iter()) {
+        persistor.put_data(entity, 0, vector.to_vec())?;
+    }
+    persistor.finish()?;
+
+    write_report(&report_file_name(&output), &reports)?;
+    Ok(reports)
+}
+
+fn stitch_partitions(
+    partitions: Vec<(String, Embeddings)>,
+    min_anchors: Option<usize>,
+) -> Result<(Embeddings, Vec<PartitionReport>), CleoraError> {
+    let mut partitions = partitions.into_iter();
+    let (reference_path, reference) = partitions.next().expect("No partitions to stitch");
+    let dimension = reference.dimension();
+    let min_anchors = min_anchors.unwrap_or(dimension);
+
+    let mut reports = vec![PartitionReport {
+        path: reference_path,
+        anchors: 0,
+        new_entities: reference.entities.len(),
+        rmse_before: 0f32,
+        rmse_after: 0f32,
+        max_residual: 0f32,
+    }];
+    let mut entities = reference.entities;
+    let mut vectors = reference.vectors;
+    let mut index: FxHashMap<String, usize> = entities
+        .iter()
+        .enumerate()
+        .map(|(i, entity)| (entity.clone(), i))
+        .collect();
+
+    for (path, partition) in partitions {
+        if partition.dimension() != dimension {
+            return Err(CleoraError::Alignment {
+                path,
+                message: format!(
+                    "dimension {} differs from the reference dimension {}",
+                    partition.dimension(),
+                    dimension
+                ),
+            });
+        }
+
+        let (anchor_rows, unified_rows): (Vec<usize>, Vec<usize>) = partition
+            .entities
+            .iter()
+            .enumerate()
+            .filter_map(|(row, entity)| index.get(entity).map(|&unified_row| (row, unified_row)))
+            .unzip();
+        if anchor_rows.is_empty() || anchor_rows.len() < min_anchors {
+            return Err(CleoraError::Alignment {
+                path,
+                message: format!(
+                    "{} anchor entities shared with the previous partitions, at least {} required",
+                    anchor_rows.len(),
+                    min_anchors.max(1)
+                ),
+            });
+        }
+        if anchor_rows.len() < dimension {
+            warn!(
+                "Partition {} has only {} anchors for dimension {}, the alignment is underdetermined",
+                path,
+                anchor_rows.len(),
+                dimension
+            );
+        }
+
+        let source = partition.vectors.select(Axis(0), &anchor_rows);
+        let target = vectors.select(Axis(0), &unified_rows);
+        let rotation = orthogonal_procrustes(source.view(), target.view());
+        let before = residuals(source.view(), target.view(), None);
+        let after = residuals(source.view(), target.view(), Some(&rotation));
+
+        let rotated = partition.vectors.dot(&rotation);
+        let mut new_entities = 0;
+        for (row, entity) in partition.entities.into_iter().enumerate() {
+            if index.contains_key(&entity) {
+                continue;
+            }
+            vectors
+                .push_row(rotated.row(row))
+                .expect("Rows of the same dimension");
+            index.insert(entity.clone(), entities.len());
+            entities.push(entity);
+            new_entities += 1;
+        }
+
+        let report = PartitionReport {
+            path,
+            anchors: anchor_rows.len(),
+            new_entities,
+            rmse_before: rmse(&before),
+            rmse_after: rmse(&after),
+            max_residual: after.iter().cloned().fold(0f32, f32::max),
+        };
+        info!(
+            "Partition {} aligned on {} anchors. RMSE before: {}, after: {}, max residual: {}.",
+            report.path, report.anchors, report.rmse_before, report.rmse_after, report.max_residual
+        );
+        reports.push(report);
+    }
+
+    Ok((Embeddings { entities, vectors }, reports))
+}
+
+fn rmse(residuals: &[f32]) -> f32 {
+    let sum: f32 = residuals.iter().map(|r| r * r).sum();
+    (sum / residuals.len() as f32).sqrt()
+}
+
+fn write_report(path: &str, reports: &[PartitionReport]) -> Result<(), CleoraError> {
+    let mut writer = BufWriter::new(create_partial_file(path)?);
+    let mut write = || -> std::io::Result<()> {
+        writeln!(
+            writer,
+            "partition\tanchors\tnew_entities\trmse_before\trmse_after\tmax_residual"
+        )?;
+        for r in reports {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                r.path, r.anchors, r.new_entities, r.rmse_before, r.rmse_after, r.max_residual
+            )?;
+        }
+        writer.flush()
+    };
+    write().map_err(|e| CleoraError::write_file(path, e))?;
+    commit_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loader::Embeddings;
+    use crate::stitch::stitch_partitions;
+    use ndarray::arr2;
+
+    fn embeddings(entities: &[&str], vectors: ndarray::Array2<f32>) -> Embeddings {
+        Embeddings {
+            entities: entities.iter().map(|e| e.to_string()).collect(),
+            vectors,
+        }
+    }
+
+    #[test]
+    fn stitch_rotated_partition() {
+        let reference = embeddings(
+            &["a", "b", "c"],
+            arr2(&[[1.0, 0.0], [0.0, 1.0], [0.6, 0.8]]),
+        );
+        // the same space rotated by 90 degrees, with new entity `d`
+        let partition = embeddings(
+            &["b", "a", "d"],
+            arr2(&[[-1.0, 0.0], [0.0, 1.0], [-0.8, 0.6]]),
+        );
+
+        let (unified, reports) = stitch_partitions(
+            vec![
+                (String::from("reference"), reference),
+                (String::from("partition"), partition),
+            ],
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(vec!["a", "b", "c", "d"], unified.entities);
+        assert!((unified.vectors[[3, 0]] - 0.6).abs() < 1e-4);
+        assert!((unified.vectors[[3, 1]] - 0.8).abs() < 1e-4);
+        assert_eq!(2, reports[1].anchors);
+        assert_eq!(1, reports[1].new_entities);
+        assert!(reports[1].rmse_before > 1.0);
+        assert!(reports[1].rmse_after < 1e-4);
+    }
+
+    #[test]
+    fn require_anchors() {
+        let reference = embeddings(&["a"], arr2(&[[1.0, 0.0]]));
+        let partition = embeddings(&["a", "b"], arr2(&[[0.0, 1.0], [1.0, 0.0]]));
+        let result = stitch_partitions(
+            vec![
+                (String::from("reference"), reference),
+                (String::from("partition"), partition),
+            ],
+            None,
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use crate::error::CleoraError;
+use crate::io::create_output;
+use crate::persistence::entity::{EntityMappingPersistor, InMemoryEntityMappingPersistor};
+use rustc_hash::FxHashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+/// Writes tags of the entities as `entity<TAB>tag<TAB>tag...` lines, entities are named like in
+/// the embedding outputs
+pub fn write_entity_tags(
+    filename: &str,
+    persistor: &InMemoryEntityMappingPersistor,
+) -> Result<(), CleoraError> {
+    let mut writer = BufWriter::new(create_output(filename)?);
+    persistor.for_each_tagged(|hash, tags| {
+        let entity = match persistor.get_entity(hash) {
+            Some(entity) => entity,
+            None => return Ok(()),
+        };
+        writer
+            .write_all(entity.as_bytes())
+            .and_then(|_| tags.iter().try_for_each(|tag| write!(writer, "\t{}", tag)))
+            .and_then(|_| writer.write_all(b"\n"))
+            .map_err(|e| CleoraError::write_file(filename, e))
+    })?;
+    writer
+        .flush()
+        .map_err(|e| CleoraError::write_file(filename, e))?;
+    writer.get_mut().commit()
+}
+
+/// Tags of the entities, read from the file written with `write_entity_tags`
+#[derive(Debug, Default)]
+pub struct EntityTags {
+    tags: FxHashMap<String, Vec<String>>,
+}
+
+impl EntityTags {
+    pub fn load(path: &str) -> Result<Self, CleoraError> {
+        let file = File::open(path).map_err(|e| CleoraError::read_file(path, e))?;
+        let mut tags: FxHashMap<String, Vec<String>> = FxHashMap::default();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| CleoraError::read_file(path, e))?;
+            let mut values = line.split('\t');
+            if let Some(entity) = values.next().filter(|entity| !entity.is_empty()) {
+                tags.entry(entity.to_string())
+                    .or_default()
+                    .extend(values.map(String::from));
+            }
+        }
+        Ok(Self { tags })
+    }
+
+    /// Whether the entity has any of the tags
+    pub fn has_any(&self, entity: &str, tags: &[String]) -> bool {
+        match self.tags.get(entity) {
+            Some(entity_tags) => entity_tags.iter().any(|tag| tags.contains(tag)),
+            None => false,
+        }
+    }
+
+    /// Number of tagged entities
+    pub fn len(&self) -> usize {
+        self.tags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::persistence::entity::{EntityMappingPersistor, InMemoryEntityMappingPersistor};
+    use crate::tags::{write_entity_tags, EntityTags};
+
+    #[test]
+    fn write_and_load_entity_tags() {
+        let persistor = InMemoryEntityMappingPersistor::default();
+        persistor.put_data(1, String::from("items__a"));
+        persistor.put_data(2, String::from("items__b"));
+        persistor.put_tag(1, "type=product");
+        persistor.put_tag(1, "sale");
+        // entities without names aren't written
+        persistor.put_tag(3, "type=product");
+
+        let path = std::env::temp_dir().join("cleora_entity_tags.tsv");
+        let path = path.to_str().unwrap();
+        write_entity_tags(path, &persistor).unwrap();
+        let tags = EntityTags::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(1, tags.len());
+        assert!(tags.has_any("items__a", &[String::from("sale")]));
+        assert!(tags.has_any(
+            "items__a",
+            &[String::from("type=user"), String::from("type=product")]
+        ));
+        assert!(!tags.has_any("items__a", &[String::from("type=user")]));
+        assert!(!tags.has_any("items__b", &[String::from("sale")]));
+    }
+}
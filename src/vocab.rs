@@ -0,0 +1,299 @@
+use crate::configuration::Configuration;
+use crate::error::CleoraError;
+use crate::io::{commit_file, create_partial_file};
+use crate::manifest::InputFile;
+use crate::persistence::entity::{
+    EntityMappingPersistor, EntityMappingWriter, InMemoryEntityMappingPersistor,
+};
+use crate::sparse_matrix::SparseMatrix;
+use log::info;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+const VOCAB_MAGIC: &[u8; 8] = b"CLEORAVC";
+const VOCAB_VERSION: u32 = 2;
+
+/// Options the graph is built with. Vocabulary saved with other options can't be reused, the
+/// embedding options (dimension, iterations, outputs) are free to change.
+fn graph_options(config: &Configuration) -> String {
+    let sample_seed = config.sample_edges.map(|_| config.seed.unwrap_or_default());
+    format!(
+        "{:?} {:?} {} {} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {} {:?} {} {} {:?} {:?} {} {:?}",
+        config.columns,
+        config.hash_function,
+        config.hash_bits,
+        config.auto_pairs,
+        config.excluded_pairs,
+        config.relations,
+        config.edge_types,
+        config.kernel,
+        config.self_loops,
+        config.column_weights,
+        config.decay,
+        config.min_entity_count,
+        config.max_entities,
+        config.unknown_bucket,
+        config.dedupe_edges,
+        config.sample_edges,
+        sample_seed,
+        config.prepend_field,
+        config.aliases,
+    )
+}
+
+/// Saves the entity mapping and the built sparse matrices (entities with their occurrence
+/// counts and normalized entries), so the next run over the same input can skip reading it. The
+/// inputs are saved with their checksums, see `input_checksum`.
+pub fn save_vocab(
+    path: &str,
+    config: &Configuration,
+    entity_mapping_persistor: &InMemoryEntityMappingPersistor,
+    sparse_matrices: &[SparseMatrix],
+) -> Result<(), CleoraError> {
+    let checksums = config
+        .input
+        .iter()
+        .map(|input| input_checksum(input))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut writer = BufWriter::new(create_partial_file(path)?);
+    let mut write = || -> Result<(), io::Error> {
+        writer.write_all(VOCAB_MAGIC)?;
+        write_u32(&mut writer, VOCAB_VERSION)?;
+        write_str(&mut writer, &graph_options(config))?;
+        write_u32(&mut writer, config.input.len() as u32)?;
+        for (input, checksum) in config.input.iter().zip(checksums.iter()) {
+            write_str(&mut writer, input)?;
+            write_str(&mut writer, checksum)?;
+        }
+        Ok(())
+    };
+    write().map_err(|e| CleoraError::write_file(path, e))?;
+
+    write_u64(&mut writer, entity_mapping_persistor.len() as u64)
+        .map_err(|e| CleoraError::write_file(path, e))?;
+    entity_mapping_persistor.write_all(&mut VocabEntityWriter {
+        path,
+        writer: &mut writer,
+    })?;
+
+    let mut write = || -> Result<(), io::Error> {
+        write_u32(&mut writer, sparse_matrices.len() as u32)?;
+        for sparse_matrix in sparse_matrices {
+            sparse_matrix.write_finished(&mut writer)?;
+        }
+        writer.flush()
+    };
+    write().map_err(|e| CleoraError::write_file(path, e))?;
+    drop(writer);
+    commit_file(path)?;
+    info!(
+        "Vocabulary of {} entities and {} sparse matrices saved to {}",
+        entity_mapping_persistor.len(),
+        sparse_matrices.len(),
+        path
+    );
+    Ok(())
+}
+
+/// Loads the vocabulary saved by `save_vocab` instead of building the graph. Entities are put
+/// into the entity mapping. The vocabulary has to be saved with the same graph options from the
+/// same inputs, which didn't change since (by their checksums).
+pub fn load_vocab(
+    path: &str,
+    config: &Configuration,
+    entity_mapping_persistor: &InMemoryEntityMappingPersistor,
+) -> Result<Vec<SparseMatrix>, CleoraError> {
+    let file = File::open(path).map_err(|e| CleoraError::read_file(path, e))?;
+    let mut reader = BufReader::new(file);
+    let invalid = |e: io::Error| CleoraError::invalid_vocab(path, e);
+
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic).map_err(invalid)?;
+    if &magic != VOCAB_MAGIC {
+        return Err(CleoraError::invalid_vocab(path, "not a vocabulary file"));
+    }
+    let version = read_u32(&mut reader).map_err(invalid)?;
+    if version != VOCAB_VERSION {
+        return Err(CleoraError::invalid_vocab(
+            path,
+            format!("unsupported version {}", version),
+        ));
+    }
+    if read_str(&mut reader).map_err(invalid)? != graph_options(config) {
+        return Err(CleoraError::invalid_vocab(
+            path,
+            "saved with other graph options (columns, hashing, pairs, weights or filters), save it again with the current ones",
+        ));
+    }
+    let inputs = read_u32(&mut reader).map_err(invalid)?;
+    let mut saved_inputs = Vec::with_capacity(inputs as usize);
+    for _ in 0..inputs {
+        let input = read_str(&mut reader).map_err(invalid)?;
+        let checksum = read_str(&mut reader).map_err(invalid)?;
+        saved_inputs.push((input, checksum));
+    }
+    if saved_inputs.len() != config.input.len() {
+        return Err(CleoraError::invalid_vocab(
+            path,
+            format!(
+                "saved from {} inputs, the run reads {}",
+                saved_inputs.len(),
+                config.input.len()
+            ),
+        ));
+    }
+    for input in config.input.iter() {
+        match saved_inputs.iter().find(|(saved, _)| saved == input) {
+            Some((_, checksum)) if *checksum == input_checksum(input)? => {}
+            Some(_) => {
+                return Err(CleoraError::invalid_vocab(
+                    path,
+                    format!("input {} changed since it was saved, save it again", input),
+                ))
+            }
+            None => {
+                return Err(CleoraError::invalid_vocab(
+                    path,
+                    format!("input {} isn't one of the inputs it was saved from", input),
+                ))
+            }
+        }
+    }
+
+    let entities = read_u64(&mut reader).map_err(invalid)?;
+    for _ in 0..entities {
+        let hash = read_u64(&mut reader).map_err(invalid)?;
+        let entity = read_str(&mut reader).map_err(invalid)?;
+        entity_mapping_persistor.put_data(hash, entity);
+    }
+    let matrices = read_u32(&mut reader).map_err(invalid)?;
+    let sparse_matrices = (0..matrices)
+        .map(|_| SparseMatrix::read_finished(&mut reader))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    info!(
+        "Vocabulary of {} entities and {} sparse matrices loaded from {}",
+        entities,
+        sparse_matrices.len(),
+        path
+    );
+    Ok(sparse_matrices)
+}
+
+/// XXH64 checksum of the local input file (see `InputFile`), empty for S3 inputs, which aren't
+/// downloaded for it
+fn input_checksum(input: &str) -> Result<String, CleoraError> {
+    Ok(InputFile::read(input, true)?.xxh64.unwrap_or_default())
+}
+
+struct VocabEntityWriter<'a, W: Write> {
+    path: &'a str,
+    writer: &'a mut W,
+}
+
+impl<W: Write> EntityMappingWriter for VocabEntityWriter<'_, W> {
+    fn put_data(&mut self, hash: u64, entity: &str) -> Result<(), CleoraError> {
+        write_u64(self.writer, hash)
+            .and_then(|_| write_str(self.writer, entity))
+            .map_err(|e| CleoraError::write_file(self.path, e))
+    }
+
+    fn finish(&mut self) -> Result<(), CleoraError> {
+        Ok(())
+    }
+}
+
+pub(crate) fn write_u32<W: Write>(writer: &mut W, value: u32) -> Result<(), io::Error> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_u64<W: Write>(writer: &mut W, value: u64) -> Result<(), io::Error> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_str<W: Write>(writer: &mut W, value: &str) -> Result<(), io::Error> {
+    write_u32(writer, value.len() as u32)?;
+    writer.write_all(value.as_bytes())
+}
+
+pub(crate) fn read_u32<R: Read>(reader: &mut R) -> Result<u32, io::Error> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_u64<R: Read>(reader: &mut R) -> Result<u64, io::Error> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_str<R: Read>(reader: &mut R) -> Result<String, io::Error> {
+    let len = read_u32(reader)?;
+    let mut bytes = vec![0u8; len as usize];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::{extract_fields, Configuration};
+    use crate::persistence::entity::{EntityMappingPersistor, InMemoryEntityMappingPersistor};
+    use crate::sparse_matrix::{SparseMatrix, SparseMatrixReader};
+    use crate::vocab::{load_vocab, save_vocab};
+
+    #[test]
+    fn save_and_load_vocab() {
+        let input = std::env::temp_dir().join("cleora_vocab_input.tsv");
+        std::fs::write(&input, "u1\ti1\n").unwrap();
+        let columns = extract_fields(vec!["users", "complex::items"]).unwrap();
+        let mut config = Configuration::default(input.to_str().unwrap().to_string(), columns);
+        let mut sm = SparseMatrix::new(0u8, String::from("users"), 1u8, String::from("items"));
+        sm.set_edge_type(1, String::from("bought"));
+        sm.handle_pair(&[2, 10, 20, 1]);
+        sm.handle_pair(&[2, 10, 30, 1]);
+        sm.handle_pair(&[1, 11, 30, 1]);
+        sm.finish();
+        let entity_mapping = InMemoryEntityMappingPersistor::default();
+        entity_mapping.put_data(10, String::from("u1"));
+        entity_mapping.put_data(20, String::from("i1"));
+
+        let path = std::env::temp_dir().join("cleora_vocab.bin");
+        let path = path.to_str().unwrap();
+        save_vocab(path, &config, &entity_mapping, std::slice::from_ref(&sm)).unwrap();
+
+        let loaded_mapping = InMemoryEntityMappingPersistor::default();
+        let loaded = load_vocab(path, &config, &loaded_mapping).unwrap();
+        assert_eq!(1, loaded.len());
+        assert_eq!(sm.get_id(), loaded[0].get_id());
+        assert_eq!(sm.get_number_of_edges(), loaded[0].get_number_of_edges());
+        assert_eq!(
+            sm.iter_entries().collect::<Vec<_>>(),
+            loaded[0].iter_entries().collect::<Vec<_>>()
+        );
+        let hashes = |sm: &SparseMatrix| {
+            sm.iter_hashes()
+                .map(|h| (h.value, h.occurrence, h.column_id))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(hashes(&sm), hashes(&loaded[0]));
+        assert_eq!(Some("i1".into()), loaded_mapping.get_entity(20));
+        assert_eq!(2, loaded_mapping.len());
+
+        // the graph would be built from other rows
+        std::fs::write(&input, "u1\ti2\n").unwrap();
+        assert!(load_vocab(path, &config, &loaded_mapping).is_err());
+        std::fs::write(&input, "u1\ti1\n").unwrap();
+        assert!(load_vocab(path, &config, &loaded_mapping).is_ok());
+        config.input.push(String::from("other.tsv"));
+        assert!(load_vocab(path, &config, &loaded_mapping).is_err());
+        config.input.pop();
+
+        // the graph would be built differently
+        config.min_entity_count = 2;
+        assert!(load_vocab(path, &config, &loaded_mapping).is_err());
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(input).unwrap();
+    }
+}
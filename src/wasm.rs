@@ -0,0 +1,63 @@
+use crate::configuration::{extract_fields, validate_fields, Configuration};
+use crate::memory::{embed_rows, MemoryEmbeddings};
+use wasm_bindgen::prelude::*;
+
+/// Trains embeddings of the TSV rows in `input` (one per line, blank lines are skipped) for the
+/// columns given like `--columns`, e.g. `"users complex::items"`. Malformed rows are skipped.
+#[wasm_bindgen]
+pub fn embed(
+    input: &str,
+    columns: &str,
+    dimension: u16,
+    iterations: u8,
+) -> Result<WasmEmbeddings, JsValue> {
+    let columns = extract_fields(columns.split_whitespace().collect())
+        .and_then(validate_fields)
+        .map_err(|message| JsValue::from_str(&message))?;
+    let config = Configuration {
+        embeddings_dimension: dimension,
+        max_number_of_iteration: iterations,
+        ..Configuration::default(String::new(), columns)
+    };
+    let rows = input.lines().filter(|line| !line.trim().is_empty());
+    let outputs = embed_rows(config, rows).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    Ok(WasmEmbeddings { outputs })
+}
+
+/// Embeddings of every pair of columns, in the order of the outputs of the CLI
+#[wasm_bindgen]
+pub struct WasmEmbeddings {
+    outputs: Vec<MemoryEmbeddings>,
+}
+
+#[wasm_bindgen]
+impl WasmEmbeddings {
+    /// Number of column pairs
+    pub fn count(&self) -> usize {
+        self.outputs.len()
+    }
+
+    /// Name of the column pair, e.g. `users__items`
+    pub fn name(&self, output: usize) -> String {
+        self.outputs[output].name.clone()
+    }
+
+    pub fn dimension(&self, output: usize) -> usize {
+        self.outputs[output].embeddings.dimension
+    }
+
+    /// Embedded entities, row `i` of the values is the embedding of entity `i`
+    pub fn entities(&self, output: usize) -> Box<[JsValue]> {
+        self.outputs[output]
+            .embeddings
+            .entities
+            .iter()
+            .map(|entity| JsValue::from_str(entity))
+            .collect()
+    }
+
+    /// Row-major embeddings (`Float32Array` in JavaScript)
+    pub fn values(&self, output: usize) -> Vec<f32> {
+        self.outputs[output].embeddings.values.clone()
+    }
+}
@@ -1,10 +1,14 @@
-use cleora::configuration::{Column, Configuration, FileType, OutputFormat};
-use cleora::embedding::{calculate_embeddings, calculate_embeddings_mmap};
-use cleora::persistence::embedding::EmbeddingPersistor;
+use cleora::configuration::{
+    BudgetPolicy, Column, Configuration, EdgeTypeCombination, EntitiesFormat, FileType,
+    HashFunction, InitMethod, MalformedRows, Normalization, OutputFormat, Precision,
+    PropagationKernel, PropagationPartitioning, SelfLoops, StatsScope, StorageClass,
+};
+use cleora::embedding::{calculate_embeddings, calculate_embeddings_mmap, PriorEmbeddings};
+use cleora::error::CleoraError;
+use cleora::persistence::embedding::{EmbeddingBatch, EmbeddingPersistor};
 use cleora::persistence::entity::InMemoryEntityMappingPersistor;
 use cleora::pipeline::build_graphs;
 use insta::assert_debug_snapshot;
-use std::io;
 use std::sync::Arc;
 
 /// This test performs work for sample case and saves snapshot file.
@@ -26,7 +30,8 @@ fn test_build_graphs_and_create_embeddings() {
     let in_memory_entity_mapping_persistor = Arc::new(in_memory_entity_mapping_persistor);
 
     // build sparse matrices
-    let sparse_matrices = build_graphs(&config, in_memory_entity_mapping_persistor.clone());
+    let sparse_matrices =
+        build_graphs(&config, in_memory_entity_mapping_persistor.clone()).unwrap();
 
     let config = Arc::new(config);
 
@@ -45,7 +50,10 @@ fn test_build_graphs_and_create_embeddings() {
             sparse_matrix.clone(),
             in_memory_entity_mapping_persistor.clone(),
             &mut in_memory_embedding_persistor,
-        );
+            None,
+            PriorEmbeddings::default(),
+        )
+        .unwrap();
         assert_debug_snapshot!(snapshot_name.clone(), in_memory_embedding_persistor);
 
         let mut in_memory_embedding_persistor = InMemoryEmbeddingPersistor::default();
@@ -55,7 +63,10 @@ fn test_build_graphs_and_create_embeddings() {
             sparse_matrix.clone(),
             in_memory_entity_mapping_persistor.clone(),
             &mut in_memory_embedding_persistor,
-        );
+            None,
+            PriorEmbeddings::default(),
+        )
+        .unwrap();
         assert_debug_snapshot!(snapshot_name, in_memory_embedding_persistor);
     }
 }
@@ -89,10 +100,74 @@ fn prepare_config() -> Configuration {
         in_memory_embedding_calculation: true,
         input: vec!["files/samples/edgelist_1.tsv".to_string()],
         file_type: FileType::Tsv,
+        field_delimiter: b'\t',
+        value_delimiters: vec![],
+        aliases: None,
         output_format: OutputFormat::TextFile,
+        extra_output_formats: vec![],
         output_dir: None,
         relation_name: "r1".to_string(),
         columns,
+        chunk_size: 3000,
+        init_method: InitMethod::Uniform,
+        entity_mapping_format: None,
+        fail_on_collision: false,
+        collision_budget: None,
+        postprocess: vec![],
+        postprocess_stats: StatsScope::PerEntityType,
+        normalize: Normalization::None,
+        reduce_dimension: None,
+        knn_graph: None,
+        hash_function: HashFunction::XxHash64,
+        hash_bits: 64,
+        overwrite: true,
+        sort_output: None,
+        output_template: None,
+        memory_budget: None,
+        on_budget_exceeded: BudgetPolicy::Abort,
+        auto_pairs: false,
+        excluded_pairs: vec![],
+        relations: None,
+        edge_types: vec![],
+        edge_type_combination: EdgeTypeCombination::Concat,
+        decay: None,
+        feature_init: None,
+        matrix_export: None,
+        align_to: None,
+        precision: Precision::F32,
+        dedupe_edges: false,
+        sample_edges: None,
+        save_vocab: None,
+        load_vocab: None,
+        mlflow_uri: None,
+        min_entity_count: 1,
+        max_entities: vec![],
+        unknown_bucket: false,
+        entity_tags: vec![],
+        output_tags: vec![],
+        snapshot_iterations: false,
+        convergence_tolerance: None,
+        early_stop: None,
+        kernel: PropagationKernel::Markov,
+        alpha: 0f32,
+        column_weights: vec![],
+        self_loops: SelfLoops::Keep,
+        deadline: None,
+        fast_parse: false,
+        malformed_rows: MalformedRows::Log(100),
+        propagation_partitioning: PropagationPartitioning::Auto,
+        dry_run: false,
+        profile: false,
+        input_checksums: true,
+        similarity_summary: true,
+        continue_from: None,
+        force: false,
+        storage_class: StorageClass::Standard,
+        estimate_cost: None,
+        entities_format: EntitiesFormat::Json,
+        output_datetime: true,
+        write_queue_size: 4,
+        threads: 0,
     };
     config
 }
@@ -112,7 +187,7 @@ struct InMemoryEntity {
 }
 
 impl EmbeddingPersistor for InMemoryEmbeddingPersistor {
-    fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), io::Error> {
+    fn put_metadata(&mut self, entity_count: u32, dimension: u16) -> Result<(), CleoraError> {
         self.entity_count = entity_count;
         self.dimenstion = dimension;
         Ok(())
@@ -122,7 +197,7 @@ impl EmbeddingPersistor for InMemoryEmbeddingPersistor {
         entity: &str,
         occur_count: u32,
         vector: Vec<f32>,
-    ) -> Result<(), io::Error> {
+    ) -> Result<(), CleoraError> {
         let entity = entity.to_string();
         self.entities.push(InMemoryEntity {
             entity,
@@ -131,7 +206,13 @@ impl EmbeddingPersistor for InMemoryEmbeddingPersistor {
         });
         Ok(())
     }
-    fn finish(&mut self) -> Result<(), io::Error> {
+    fn put_data_batch(&mut self, chunk: EmbeddingBatch) -> Result<(), CleoraError> {
+        for (entity, occur_count, vector) in chunk.rows() {
+            self.put_data(entity, occur_count, vector.to_vec())?;
+        }
+        Ok(())
+    }
+    fn finish(&mut self) -> Result<(), CleoraError> {
         Ok(())
     }
 }